@@ -0,0 +1,622 @@
+//! Log de acceso en JSON, una línea por petición terminada.
+//!
+//! Con `--access-log <ruta>` (o `-` para la salida estándar) cada petición
+//! escribe al terminar una línea con el cliente, el método, el destino, el
+//! esquema, el código de respuesta, los bytes en cada sentido y la duración
+//! ([`AccessEntry`]). Una petición HTTP termina cuando se ha entregado su
+//! body (o el cliente se fue); un `CONNECT` que abre túnel, cuando el túnel
+//! se cierra, para contar sus bytes. Un `CONNECT` rechazado se registra con
+//! su código al responder.
+//!
+//! El camino de las peticiones solo deja la línea en el buffer de su worker
+//! ([`crate::batch`]): un hilo propio las recoge en lotes, cuando se juntan
+//! [`BATCH`] o cada [`FLUSH_INTERVAL`], y escribe cada lote con una llamada
+//! vectorizada, así que ni un disco lento ni una ráfaga frenan a nadie. Si
+//! se acumulan más de [`QUEUE`], las líneas que no caben se descartan y se
+//! cuentan en `GET /api/stats`. Al soltar el log se escribe todo lo
+//! pendiente. Con `SIGHUP` el archivo se vuelve a abrir en la misma ruta,
+//! para logrotate.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{JoinHandle, Thread};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use futures_util::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, CONTENT_LENGTH};
+use hyper::{Body, Method, Request, Response};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::{error, info};
+
+use crate::ai_router::RoutedTo;
+use crate::batch::{self, Batcher};
+use crate::log_throttle::LogThrottle;
+use crate::privacy::Sanitizer;
+use crate::settings::AccessLogTarget;
+use crate::token_usage::{TokenCount, UsageSlot};
+
+/// Líneas en espera del hilo de escritura como mucho.
+pub const QUEUE: usize = 8192;
+
+/// Líneas que despiertan al hilo de escritura antes de su intervalo.
+pub const BATCH: usize = 256;
+
+/// Lo más que espera una línea antes de escribirse.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Una línea del log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessEntry {
+    /// Milisegundos desde la época Unix al terminar la petición.
+    pub at_ms: u64,
+    /// Dirección del cliente, según el perfil de logs.
+    pub client: String,
+    pub method: String,
+    /// Host de destino; vacío si la petición no lo trae.
+    pub host: String,
+    pub port: Option<u16>,
+    /// `http` o `https` según la URI; `null` en un `CONNECT`.
+    pub scheme: Option<String>,
+    /// `null` si la conexión falló antes de responder. En un `CONNECT`, el
+    /// código con el que se contestó.
+    pub status: Option<u16>,
+    /// Bytes del cliente hacia el destino (body o túnel).
+    pub bytes_up: u64,
+    /// Bytes del destino hacia el cliente (body o túnel).
+    pub bytes_down: u64,
+    pub duration_ms: f64,
+    /// Motivo con el que la API de administración cerró el túnel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub termination: Option<String>,
+    /// Tokens de la respuesta, en las peticiones del router IA.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<TokenCount>,
+    /// Backend del router IA que dio la respuesta.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+}
+
+/// Líneas escritas y descartadas, tal como las muestra `GET /api/stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AccessLogStats {
+    pub written: u64,
+    pub dropped: u64,
+}
+
+/// Lo que el hilo de escritura hace tras escribir lo pendiente.
+enum Control {
+    Reopen(oneshot::Sender<()>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Lotes hacia el hilo que escribe el log.
+pub struct AccessLog {
+    lines: Arc<Batcher>,
+    controls: mpsc::Sender<Control>,
+    writer: Thread,
+    handle: Mutex<Option<JoinHandle<()>>>,
+    target: AccessLogTarget,
+    written: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    /// Abre el destino y arranca el hilo de escritura.
+    pub fn open(target: AccessLogTarget) -> anyhow::Result<Self> {
+        let out = Output::open(&target)?;
+        let writer = Arc::new(OnceLock::<Thread>::new());
+        let wake = writer.clone();
+        let lines = Arc::new(Batcher::new(QUEUE, BATCH, move || {
+            if let Some(thread) = wake.get() {
+                thread.unpark();
+            }
+        }));
+        let (controls, receiver) = mpsc::channel();
+        let written = Arc::new(AtomicU64::new(0));
+        let (queue, counter) = (lines.clone(), written.clone());
+        let handle = std::thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || write_batches(out, &queue, receiver, &counter))
+            .context("No se pudo arrancar el hilo del log de acceso")?;
+        let _ = writer.set(handle.thread().clone());
+        Ok(Self {
+            lines,
+            controls,
+            writer: handle.thread().clone(),
+            handle: Mutex::new(Some(handle)),
+            target,
+            written,
+        })
+    }
+
+    pub fn target(&self) -> &AccessLogTarget {
+        &self.target
+    }
+
+    /// Encola la línea sin esperar; si la cola está llena, se descarta.
+    pub fn record(&self, entry: &AccessEntry) {
+        let mut line = serde_json::to_string(entry).expect("entrada serializable");
+        line.push('\n');
+        self.lines.push(line.into());
+    }
+
+    /// Vuelve a abrir el archivo en su ruta tras escribir lo encolado.
+    pub async fn reopen(&self) {
+        self.control(Control::Reopen).await;
+    }
+
+    /// Espera a que lo encolado hasta ahora esté escrito.
+    pub async fn flush(&self) {
+        self.control(Control::Flush).await;
+    }
+
+    async fn control(&self, control: impl FnOnce(oneshot::Sender<()>) -> Control) {
+        let (done, applied) = oneshot::channel();
+        if self.controls.send(control(done)).is_ok() {
+            self.writer.unpark();
+            let _ = applied.await;
+        }
+    }
+
+    pub fn stats(&self) -> AccessLogStats {
+        AccessLogStats {
+            written: self.written.load(Ordering::Relaxed),
+            dropped: self.lines.dropped(),
+        }
+    }
+
+    /// Línea de un túnel que se acaba de cerrar.
+    pub fn record_tunnel(&self, tunnel: &TunnelEntry<'_>, sanitizer: &Sanitizer) {
+        self.record(&AccessEntry {
+            at_ms: now_ms(),
+            client: sanitizer.addr(tunnel.client),
+            method: Method::CONNECT.to_string(),
+            host: tunnel.host.to_string(),
+            port: Some(tunnel.port),
+            scheme: None,
+            status: Some(200),
+            bytes_up: tunnel.bytes_up,
+            bytes_down: tunnel.bytes_down,
+            duration_ms: tunnel.duration.as_secs_f64() * 1000.0,
+            termination: tunnel.termination.map(str::to_string),
+            tokens: None,
+            upstream: None,
+        });
+    }
+
+    /// Empieza la entrada de `req`; cuenta el body que sube si lo hay.
+    pub fn start(
+        self: &Arc<Self>,
+        req: &mut Request<Body>,
+        client: SocketAddr,
+        sanitizer: &Sanitizer,
+    ) -> PendingEntry {
+        let uri = req.uri();
+        let connect = req.method() == Method::CONNECT;
+        let scheme = uri.scheme_str().filter(|_| !connect).map(str::to_string);
+        let port = uri.port_u16().or(match uri.scheme_str() {
+            Some("https") => Some(443),
+            Some("http") => Some(80),
+            _ => None,
+        });
+        let entry = AccessEntry {
+            at_ms: 0,
+            client: sanitizer.addr(client),
+            method: req.method().to_string(),
+            host: uri.host().unwrap_or_default().to_string(),
+            port,
+            scheme,
+            status: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            duration_ms: 0.0,
+            termination: None,
+            tokens: None,
+            upstream: None,
+        };
+        let up = Arc::new(AtomicU64::new(0));
+        if !req.body().is_end_stream() {
+            let counter = up.clone();
+            let body = std::mem::take(req.body_mut()).inspect_ok(move |chunk| {
+                counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            });
+            *req.body_mut() = Body::wrap_stream(body);
+        }
+        PendingEntry {
+            log: self.clone(),
+            started: Instant::now(),
+            connect,
+            entry,
+            up,
+            usage: None,
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    /// Escribe lo pendiente antes de soltar el destino.
+    fn drop(&mut self) {
+        self.lines.close();
+        let handle = self.handle.get_mut().ok().and_then(Option::take);
+        if let Some(handle) = handle {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Entrada de una petición en curso.
+pub struct PendingEntry {
+    log: Arc<AccessLog>,
+    started: Instant,
+    connect: bool,
+    entry: AccessEntry,
+    up: Arc<AtomicU64>,
+    usage: Option<UsageSlot>,
+}
+
+impl PendingEntry {
+    /// La petición tiene respuesta (o falló): la línea sale cuando termina
+    /// el body. Un túnel abierto escribe la suya al cerrarse.
+    pub fn finish(mut self, response: Option<&mut Response<Body>>) {
+        let Some(response) = response else {
+            return self.write(0);
+        };
+        self.entry.status = Some(response.status().as_u16());
+        self.usage = response.extensions().get::<UsageSlot>().cloned();
+        self.entry.upstream = response
+            .extensions()
+            .get::<RoutedTo>()
+            .map(|routed| routed.0.clone());
+        if self.connect && response.status().is_success() {
+            return;
+        }
+        let body = response.body();
+        if body.is_end_stream() {
+            return self.write(0);
+        }
+        // Rewrapping the body drops its size hint.
+        if let Some(len) = body.size_hint().exact() {
+            response
+                .headers_mut()
+                .entry(CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(len));
+        }
+        let mut delivered = Delivered {
+            pending: Some(self),
+            bytes: 0,
+        };
+        let body =
+            std::mem::take(response.body_mut()).inspect_ok(move |chunk| delivered.add(chunk.len()));
+        *response.body_mut() = Body::wrap_stream(body);
+    }
+
+    fn write(mut self, bytes_down: u64) {
+        self.entry.bytes_up = self.up.load(Ordering::Relaxed);
+        self.entry.bytes_down = bytes_down;
+        self.entry.tokens = self.usage.as_ref().and_then(UsageSlot::get);
+        self.entry.duration_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        self.entry.at_ms = now_ms();
+        self.log.record(&self.entry);
+    }
+}
+
+/// Túnel cerrado, con los bytes que copió en cada sentido.
+pub struct TunnelEntry<'a> {
+    pub client: SocketAddr,
+    pub host: &'a str,
+    pub port: u16,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration: Duration,
+    pub termination: Option<&'a str>,
+}
+
+/// Cuenta lo entregado al cliente y escribe la línea al soltar el body.
+struct Delivered {
+    pending: Option<PendingEntry>,
+    bytes: u64,
+}
+
+impl Delivered {
+    fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for Delivered {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            pending.write(self.bytes);
+        }
+    }
+}
+
+/// Vuelve a abrir el log con cada `SIGHUP`.
+pub async fn reopen_on_hangup(log: Arc<AccessLog>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            error!("No se pudo instalar el manejador de SIGHUP del log de acceso");
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP: se vuelve a abrir el log de acceso");
+            log.reopen().await;
+        }
+    }
+    #[cfg(not(unix))]
+    drop(log);
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+struct Output {
+    path: Option<PathBuf>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl Output {
+    fn open(target: &AccessLogTarget) -> anyhow::Result<Self> {
+        let (path, writer): (_, Box<dyn Write + Send>) = match target {
+            AccessLogTarget::Stdout => (None, Box::new(io::stdout())),
+            AccessLogTarget::File(path) => (Some(path.clone()), Box::new(append(path)?)),
+        };
+        Ok(Self { path, writer })
+    }
+
+    fn reopen(&mut self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = append(path)?;
+        self.writer.flush()?;
+        self.writer = Box::new(file);
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("No se pudo abrir el log de acceso {}", path.display()))
+}
+
+fn write_batches(
+    mut out: Output,
+    lines: &Batcher,
+    controls: mpsc::Receiver<Control>,
+    written: &AtomicU64,
+) {
+    let failures = LogThrottle::new(Duration::from_secs(10));
+    let fail = |e: &dyn std::fmt::Display| {
+        if let Some(suppressed) = failures.should_log() {
+            error!(error = %e, suppressed, "Error escribiendo el log de acceso");
+        }
+    };
+    let write = |out: &mut Output| {
+        let batch = lines.take();
+        if batch.is_empty() {
+            return;
+        }
+        match batch::write_lines(&mut out.writer, &batch).and_then(|()| out.writer.flush()) {
+            Ok(()) => {
+                written.fetch_add(batch.len() as u64, Ordering::Relaxed);
+            }
+            Err(e) => fail(&e),
+        }
+    };
+    loop {
+        // Closed only once the log is being dropped: nothing else arrives.
+        let closing = lines.is_closed();
+        loop {
+            match controls.try_recv() {
+                Ok(Control::Reopen(done)) => {
+                    write(&mut out);
+                    if let Err(e) = out.reopen() {
+                        fail(&e);
+                    }
+                    let _ = done.send(());
+                }
+                Ok(Control::Flush(done)) => {
+                    write(&mut out);
+                    let _ = done.send(());
+                }
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+            }
+        }
+        write(&mut out);
+        if closing {
+            return;
+        }
+        std::thread::park_timeout(FLUSH_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tunnel(host: &str) -> TunnelEntry<'_> {
+        TunnelEntry {
+            client: "10.0.0.1:4000".parse().unwrap(),
+            host,
+            port: 443,
+            bytes_up: 10,
+            bytes_down: 20,
+            duration: Duration::from_millis(5),
+            termination: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reopen_moves_later_lines_to_the_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let rotated = dir.path().join("access.log.1");
+        let log = AccessLog::open(AccessLogTarget::File(path.clone())).unwrap();
+        let sanitizer = Sanitizer::default();
+
+        log.record_tunnel(&tunnel("antes.test"), &sanitizer);
+        log.flush().await;
+        std::fs::rename(&path, &rotated).unwrap();
+        // Until the reopen the rotated file keeps receiving lines.
+        log.record_tunnel(&tunnel("rotado.test"), &sanitizer);
+        log.reopen().await;
+        log.record_tunnel(&tunnel("despues.test"), &sanitizer);
+        log.flush().await;
+
+        let hosts = |path: &Path| -> Vec<String> {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+                    entry["host"].as_str().unwrap().to_string()
+                })
+                .collect()
+        };
+        assert_eq!(hosts(&rotated), ["antes.test", "rotado.test"]);
+        assert_eq!(hosts(&path), ["despues.test"]);
+        assert_eq!(
+            log.stats(),
+            AccessLogStats {
+                written: 3,
+                dropped: 0
+            }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_dropping_the_log_writes_every_buffered_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = Arc::new(AccessLog::open(AccessLogTarget::File(path.clone())).unwrap());
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let sanitizer = Sanitizer::default();
+                    for _ in 0..250 {
+                        log.record_tunnel(&tunnel("ráfaga.test"), &sanitizer);
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let stats = log.stats();
+        assert_eq!(stats.dropped, 0);
+        // No flush: the last reference going away writes what is left.
+        drop(log);
+        let lines = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(lines.lines().count(), 2000);
+    }
+
+    /// Registra líneas desde varios workers a la vez, con el log actual y
+    /// con una cola de un envío por línea (como antes de los lotes):
+    /// `cargo test --release bench_access_log_burst -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[ignore]
+    async fn bench_access_log_burst() {
+        const TASKS: usize = 8;
+        const LINES: usize = 100_000;
+        let dir = tempfile::tempdir().unwrap();
+
+        async fn burst(record: Arc<dyn Fn() + Send + Sync>) -> Duration {
+            let started = Instant::now();
+            let tasks: Vec<_> = (0..TASKS)
+                .map(|_| {
+                    let record = record.clone();
+                    tokio::spawn(async move {
+                        for n in 0..LINES {
+                            record();
+                            if n % 64 == 0 {
+                                tokio::task::yield_now().await;
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            started.elapsed()
+        }
+
+        let log =
+            Arc::new(AccessLog::open(AccessLogTarget::File(dir.path().join("lotes"))).unwrap());
+        let recorder = log.clone();
+        let sanitizer = Sanitizer::default();
+        let batched = burst(Arc::new(move || {
+            recorder.record_tunnel(&tunnel("bench.test"), &sanitizer)
+        }))
+        .await;
+        log.flush().await;
+        let stats = log.stats();
+
+        let (queue, mut receiver) = tokio::sync::mpsc::channel::<String>(QUEUE);
+        let file = append(&dir.path().join("canal")).unwrap();
+        let writer = std::thread::spawn(move || {
+            let mut out = io::BufWriter::new(file);
+            while let Some(line) = receiver.blocking_recv() {
+                out.write_all(line.as_bytes()).unwrap();
+            }
+        });
+        let baseline_dropped = Arc::new(AtomicU64::new(0));
+        let dropped = baseline_dropped.clone();
+        let sanitizer = Sanitizer::default();
+        let channel = burst(Arc::new(move || {
+            let entry = AccessEntry {
+                at_ms: now_ms(),
+                client: sanitizer.addr("10.0.0.1:4000".parse().unwrap()),
+                method: Method::CONNECT.to_string(),
+                host: "bench.test".into(),
+                port: Some(443),
+                scheme: None,
+                status: Some(200),
+                bytes_up: 10,
+                bytes_down: 20,
+                duration_ms: 5.0,
+                termination: None,
+                tokens: None,
+                upstream: None,
+            };
+            let mut line = serde_json::to_string(&entry).unwrap();
+            line.push('\n');
+            if queue.try_send(line).is_err() {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }))
+        .await;
+        writer.join().unwrap();
+
+        let total = (TASKS * LINES) as f64;
+        println!(
+            "lotes: {:.0} líneas/s, {} descartadas",
+            total / batched.as_secs_f64(),
+            stats.dropped
+        );
+        println!(
+            "un envío por línea: {:.0} líneas/s, {} descartadas",
+            total / channel.as_secs_f64(),
+            baseline_dropped.load(Ordering::Relaxed)
+        );
+    }
+}
@@ -0,0 +1,1441 @@
+//! Listener de administración: API JSON en un puerto separado del proxy para
+//! que su tráfico nunca se interprete como una petición a reenviar.
+//!
+//! Quien embebe el proxy puede agregar endpoints propios con
+//! [`AdminApi::router`]. Comparten el listener, el token de administración y
+//! las convenciones de error JSON (`{"error": "..."}`, ver [`json_error`]).
+//! Todo `/api`, `/healthz` y `/readyz` quedan reservados a los endpoints del
+//! proxy, así que los propios nunca tapan uno existente ni uno futuro; los
+//! prefijos que se solapan se rechazan al construir la API.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures_util::future::BoxFuture;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tracing::{debug, error, info};
+
+use crate::archive::{percent_decode, InterceptQuery};
+use crate::canary::CanaryError;
+use crate::capture::{read_bundle, RecordedBundle, Replayed};
+use crate::concurrency::ConcurrencyLimit;
+use crate::connection::Protocol;
+use crate::connections::ConnectionSnapshot;
+use crate::effective::{EffectiveSettings, Explanation, RequestFacts};
+use crate::error::ProxyError;
+use crate::events::Event;
+use crate::header_limits::HeaderLimit;
+use crate::host_filter::HostRule;
+use crate::overload::ShedReason;
+use crate::policy_export::{self, Format};
+use crate::policy_sim::{Policies, Target};
+use crate::proxy::ProxyContext;
+use crate::response_cache::StaleReason;
+use crate::settings::{ClientClass, Feature, ProxySettings};
+use crate::shutdown::ShutdownTrigger;
+use crate::stats::DayStats;
+use crate::tunnels::TunnelSnapshot;
+
+/// Prefijo de los endpoints del proxy.
+const RESERVED: &str = "/api";
+
+/// Sondas de los balanceadores, fuera de `/api` y sin token.
+const PROBES: [&str; 2] = ["/healthz", "/readyz"];
+
+/// Endpoint propio. Estabilidad: la forma
+/// `Fn(Request<Body>, AdminView) -> impl Future<Output = Response<Body>>`
+/// no cambia dentro de una versión mayor; [`AdminView`] puede ganar métodos
+/// pero los existentes se mantienen. Lo que devuelva el handler se entrega
+/// tal cual, sin cabeceras añadidas.
+type Handler =
+    Arc<dyn Fn(Request<Body>, AdminView) -> BoxFuture<'static, Response<Body>> + Send + Sync>;
+
+/// Endpoints propios agregados a la API de administración.
+#[derive(Clone, Default)]
+pub struct AdminApi {
+    routes: Vec<(String, Handler)>,
+}
+
+impl AdminApi {
+    pub fn router() -> AdminRouter {
+        AdminRouter::default()
+    }
+
+    fn find(&self, path: &str) -> Option<&Handler> {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| under(path, prefix))
+            .map(|(_, handler)| handler)
+    }
+}
+
+#[derive(Default)]
+pub struct AdminRouter {
+    routes: Vec<(String, Handler)>,
+}
+
+impl AdminRouter {
+    /// Atiende `prefix` y todo lo que cuelga de él (`/admin/tenant-sync`
+    /// cubre también `/admin/tenant-sync/42`), con cualquier método.
+    pub fn route<F, Fut>(mut self, prefix: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Request<Body>, AdminView) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response<Body>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |req, view| Box::pin(handler(req, view)));
+        self.routes.push((prefix.into(), handler));
+        self
+    }
+
+    /// Falla si un prefijo no empieza por `/`, se solapa con `/api` o con
+    /// las sondas, o se solapa con otro.
+    pub fn build(self) -> anyhow::Result<AdminApi> {
+        let mut routes: Vec<(String, Handler)> = Vec::with_capacity(self.routes.len());
+        for (prefix, handler) in self.routes {
+            let normalized = prefix.trim_end_matches('/');
+            anyhow::ensure!(
+                prefix.starts_with('/') && !normalized.is_empty(),
+                "el prefijo de administración `{prefix}` debe empezar por `/` y no ser la raíz"
+            );
+            anyhow::ensure!(
+                !overlaps(normalized, RESERVED),
+                "el prefijo de administración `{prefix}` choca con los endpoints del proxy en {RESERVED}"
+            );
+            if let Some(probe) = PROBES.iter().find(|probe| overlaps(normalized, probe)) {
+                anyhow::bail!(
+                    "el prefijo de administración `{prefix}` choca con la sonda del proxy {probe}"
+                );
+            }
+            if let Some((other, _)) = routes.iter().find(|(other, _)| overlaps(normalized, other)) {
+                anyhow::bail!("el prefijo de administración `{prefix}` choca con `{other}`");
+            }
+            routes.push((normalized.to_string(), handler));
+        }
+        Ok(AdminApi { routes })
+    }
+}
+
+/// Si `path` es `prefix` o cuelga de él.
+fn under(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn overlaps(a: &str, b: &str) -> bool {
+    under(a, b) || under(b, a)
+}
+
+/// Vista de solo lectura del proxy para los endpoints propios.
+#[derive(Clone)]
+pub struct AdminView {
+    ctx: ProxyContext,
+    settings: Arc<ProxySettings>,
+}
+
+impl AdminView {
+    /// Configuración con la que arrancó el proxy.
+    pub fn settings(&self) -> &ProxySettings {
+        &self.settings
+    }
+
+    /// Estadísticas de los días `[from, to)` contados desde la época, si
+    /// están activadas.
+    pub fn stats(&self, from: u64, to: u64) -> Option<DayStats> {
+        self.ctx.stats().map(|stats| stats.range(from, to))
+    }
+
+    pub fn tunnels(&self) -> Vec<TunnelSnapshot> {
+        self.ctx.tunnels().list()
+    }
+
+    /// Conexiones aceptadas por protocolo desde el arranque.
+    pub fn protocols(&self, protocol: Protocol) -> u64 {
+        self.ctx.metrics().protocols(protocol)
+    }
+}
+
+/// API de administración completa: autenticación, endpoints del proxy y
+/// endpoints propios.
+pub struct AdminService {
+    view: AdminView,
+    api: AdminApi,
+}
+
+impl AdminService {
+    pub fn new(ctx: ProxyContext, settings: Arc<ProxySettings>, api: AdminApi) -> Self {
+        Self {
+            view: AdminView { ctx, settings },
+            api,
+        }
+    }
+
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        // Load balancers probe these without credentials; only the built-in
+        // handlers answer them.
+        let probe = req.method() == Method::GET && PROBES.contains(&req.uri().path());
+        if let Some(token) = self.view.settings.admin_token().filter(|_| !probe) {
+            let presented = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "));
+            if !presented.is_some_and(|presented| token.matches(presented)) {
+                let mut response = json_error(
+                    StatusCode::UNAUTHORIZED,
+                    "se requiere el token de administración",
+                );
+                response.headers_mut().insert(
+                    WWW_AUTHENTICATE,
+                    hyper::header::HeaderValue::from_static("Bearer realm=\"proxy-admin\""),
+                );
+                return response;
+            }
+        }
+        match self.api.find(req.uri().path()) {
+            Some(handler) => handler(req, self.view.clone()).await,
+            None => match handle(self.view.ctx.current(), req).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            },
+        }
+    }
+}
+
+/// Dirección desde la que se llamó a la API de administración; [`serve`] la
+/// pone en cada petición para dejar constancia de quién cerró qué.
+#[derive(Debug, Clone, Copy)]
+pub struct AdminClient(pub SocketAddr);
+
+/// Atiende la API de administración hasta que el listener falle.
+pub async fn serve(listener: TcpListener, admin: Arc<AdminService>) -> anyhow::Result<()> {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(error = %e, "Error aceptando conexión de administración");
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                continue;
+            }
+        };
+        let admin = admin.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |mut req: Request<Body>| {
+                let admin = admin.clone();
+                req.extensions_mut().insert(AdminClient(remote_addr));
+                async move { Ok::<_, Infallible>(admin.handle(req).await) }
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                debug!(%remote_addr, error = %e, "Conexión de administración terminó con error");
+            }
+        });
+    }
+}
+
+pub(crate) async fn handle(
+    ctx: ProxyContext,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["api", "captures"]) => list_captures(&ctx).await,
+        (&Method::GET, ["api", "captures", id]) => get_capture(&ctx, id).await,
+        (&Method::GET, ["api", "captures", id, "curl"]) => capture_curl(&ctx, id, &req).await,
+        (&Method::POST, ["api", "captures", id, "replay"]) => replay_capture(&ctx, id, &req).await,
+        (&Method::GET, ["api", "intercepts"]) => search_intercepts(&ctx, &req),
+        (&Method::GET, ["api", "intercepts", id]) => get_intercept(&ctx, id).await,
+        (&Method::GET, ["api", "reports", "preview"]) => preview_report(&ctx),
+        (&Method::POST, ["api", "reports"]) => generate_report(&ctx).await,
+        #[cfg(feature = "transcoding")]
+        (&Method::GET, ["api", "data-saver"]) => data_saver_savings(&ctx),
+        #[cfg(feature = "upnp")]
+        (&Method::GET, ["api", "port-mapping"]) => port_mapping(&ctx),
+        (&Method::GET, ["api", "rollouts"]) => rollout_exposures(&ctx),
+        (&Method::GET, ["api", "error-pages"]) => error_pages(&ctx),
+        (&Method::GET, ["api", "config"]) => match ctx.file_config() {
+            Some(config) => json_response(StatusCode::OK, config.clone()),
+            None => json_error(
+                StatusCode::NOT_FOUND,
+                "el proxy arrancó sin archivo de configuración",
+            ),
+        },
+        (&Method::GET, ["api", "redirect-maps"]) => redirect_maps(&ctx),
+        (&Method::GET, ["api", "policy", "export"]) => export_policy(&ctx, &req),
+        (&Method::POST, ["api", "policy", "simulate"]) => simulate_policy(&ctx, req).await,
+        (&Method::GET, ["api", "explain"]) => explain(&ctx, &req),
+        (&Method::GET, ["api", "stats"]) => stats(&ctx),
+        (&Method::GET, ["api", "upstream-tls"]) => upstream_tls(&ctx),
+        (&Method::DELETE, ["api", "tunnels", id]) => terminate_tunnel(&ctx, id, &req),
+        (&Method::DELETE, ["api", "clients", ip, "tunnels"]) => {
+            terminate_client_tunnels(&ctx, ip, &req)
+        }
+        (&Method::GET, ["api", "tunnels"]) => {
+            json_response(StatusCode::OK, json!(ctx.tunnels().list()))
+        }
+        (&Method::DELETE, ["api", "connections", id]) => terminate_connection(&ctx, id, &req),
+        (&Method::DELETE, ["api", "clients", ip, "connections"]) => {
+            terminate_client_connections(&ctx, ip, &req)
+        }
+        (&Method::GET, ["api", "connections"]) => {
+            json_response(StatusCode::OK, json!(ctx.connections().list()))
+        }
+        (&Method::GET, ["healthz"]) => liveness(&ctx),
+        (&Method::GET, ["readyz"]) => readiness(&ctx),
+        (&Method::GET, ["api", "drain"]) => drain_status(&ctx, StatusCode::OK),
+        (&Method::POST, ["api", "drain"]) => {
+            ctx.drain().start();
+            drain_status(&ctx, StatusCode::ACCEPTED)
+        }
+        (&Method::DELETE, ["api", "drain"]) => {
+            if ctx.drain().cancel() {
+                drain_status(&ctx, StatusCode::OK)
+            } else {
+                json_error(StatusCode::CONFLICT, "no hay ningún drenaje en curso")
+            }
+        }
+        (&Method::POST, ["api", "shutdown"]) => {
+            ctx.shutdown().trigger(ShutdownTrigger::Admin);
+            json_response(
+                StatusCode::ACCEPTED,
+                json!({ "draining": ctx.shutdown().active() }),
+            )
+        }
+        (&Method::GET, ["api", "jobs"]) => {
+            let jobs = ctx.jobs();
+            json_response(
+                StatusCode::OK,
+                json!({
+                    "in_flight": jobs.in_flight(),
+                    "busy": jobs.is_busy(),
+                    "queues": jobs.list(),
+                }),
+            )
+        }
+        (&Method::POST, ["api", "jobs", name, "pause"]) => pause_queue(&ctx, name, true),
+        (&Method::POST, ["api", "jobs", name, "resume"]) => pause_queue(&ctx, name, false),
+        (&Method::GET, ["api", "tickets"]) => list_tickets(&ctx),
+        (&Method::POST, ["api", "tickets"]) => mint_ticket(&ctx, req).await,
+        (&Method::DELETE, ["api", "tickets", id]) => revoke_ticket(&ctx, id),
+        (&Method::GET, ["api", "canary"]) => canary_status(&ctx, StatusCode::OK),
+        (&Method::PUT, ["api", "canary"]) => set_canary_weight(&ctx, req).await,
+        (&Method::POST, ["api", "canary", "reset"]) => reset_canary(&ctx),
+        (&Method::GET, ["api", "blocklist"]) => list_blocklist(&ctx),
+        (&Method::POST, ["api", "blocklist"]) => add_to_blocklist(&ctx, req).await,
+        // A CIDR rule spans two segments.
+        (&Method::DELETE, ["api", "blocklist", rule @ ..]) if !rule.is_empty() => {
+            remove_from_blocklist(&ctx, &rule.join("/"))
+        }
+        (&Method::GET, ["api", "bans"]) => list_bans(&ctx),
+        (&Method::DELETE, ["api", "bans", ip]) => revoke_ban(&ctx, ip),
+        (&Method::GET, ["api", "debug", "resolve"]) => resolve_debug(&ctx, &req).await,
+        (&Method::GET, ["api", "debug", "egress"]) => egress_debug(&ctx, &req),
+        (&Method::GET, ["api", "debug", "signing"]) => signing_debug(&ctx, &req),
+        (&Method::GET, ["api", "debug", "connect-scores"]) => {
+            json_response(StatusCode::OK, json!(ctx.dialer().scores()))
+        }
+        _ => json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "ruta desconocida" }),
+        ),
+    };
+    Ok(response)
+}
+
+async fn list_captures(ctx: &ProxyContext) -> Response<Body> {
+    let Some(store) = ctx.capture() else {
+        return capture_disabled();
+    };
+    match store.list().await {
+        Ok(entries) => json_response(StatusCode::OK, json!(entries)),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn get_capture(ctx: &ProxyContext, id: &str) -> Response<Body> {
+    let Some(store) = ctx.capture() else {
+        return capture_disabled();
+    };
+    match store.get(id).await {
+        Ok(Some(bundle)) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(bundle))
+            .expect("respuesta de captura"),
+        Ok(None) => json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "captura no encontrada" }),
+        ),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn load_capture(ctx: &ProxyContext, id: &str) -> Result<RecordedBundle, Response<Body>> {
+    let Some(store) = ctx.capture() else {
+        return Err(capture_disabled());
+    };
+    match store.get(id).await {
+        Ok(Some(bytes)) => read_bundle(&bytes).map_err(internal_error),
+        Ok(None) => Err(json_error(StatusCode::NOT_FOUND, "captura no encontrada")),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+/// Política efectiva aplanada; `?format=csv|json`, JSON por defecto.
+fn export_policy(ctx: &ProxyContext, req: &Request<Body>) -> Response<Body> {
+    let format = match query_param(req, "format")
+        .map(str::parse::<Format>)
+        .transpose()
+    {
+        Ok(format) => format.unwrap_or_default(),
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    policy_export::respond(policy_export::collect(ctx), format)
+}
+
+/// Tamaño máximo del cuerpo de `POST /api/policy/simulate`; una tanda de
+/// [`crate::policy_sim::LIVE_BATCH`] destinos cabe holgada.
+const MAX_SIMULATE_REQUEST: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SimulateRequest {
+    targets: Vec<Target>,
+}
+
+/// Decisión de la política en marcha para cada destino, en el mismo orden;
+/// es lo que compara `proxy-ia policy simulate`.
+async fn simulate_policy(ctx: &ProxyContext, req: Request<Body>) -> Response<Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() <= MAX_SIMULATE_REQUEST => body,
+        Ok(_) => return json_error(StatusCode::PAYLOAD_TOO_LARGE, "petición demasiado grande"),
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let request: SimulateRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let decisions = Policies::of(ctx).evaluate_all(&request.targets);
+    json_response(StatusCode::OK, json!({ "decisions": decisions }))
+}
+
+/// Valores efectivos de una petición hipotética y la capa de cada uno; es
+/// lo que muestra `proxy-ia explain`. Pide `host` y `client`; `method`
+/// es `GET` si falta, y `user` y `profile` describen su identidad.
+fn explain(ctx: &ProxyContext, req: &Request<Body>) -> Response<Body> {
+    let param = |name| query_param(req, name).map(percent_decode);
+    let Some(host) = param("host").filter(|host| !host.is_empty()) else {
+        return json_error(StatusCode::BAD_REQUEST, "falta el parámetro host");
+    };
+    let client = match param("client").map(|client| client.parse::<IpAddr>()) {
+        Some(Ok(client)) => client,
+        Some(Err(e)) => return json_error(StatusCode::BAD_REQUEST, format!("client: {e}")),
+        None => return json_error(StatusCode::BAD_REQUEST, "falta el parámetro client"),
+    };
+    let method = match param("method").map(|method| method.to_ascii_uppercase().parse::<Method>()) {
+        Some(Ok(method)) => method,
+        Some(Err(e)) => return json_error(StatusCode::BAD_REQUEST, format!("method: {e}")),
+        None => Method::GET,
+    };
+    let port = match param("port").map(|port| port.parse::<u16>()) {
+        Some(Ok(port)) => Some(port),
+        Some(Err(e)) => return json_error(StatusCode::BAD_REQUEST, format!("port: {e}")),
+        None => None,
+    };
+    let facts = RequestFacts::hypothetical(method, &host, port, client)
+        .with_identity(param("user"), param("profile"));
+    let effective = EffectiveSettings::for_request(ctx, &facts);
+    json_response(StatusCode::OK, json!(Explanation::new(&facts, &effective)))
+}
+
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// La captura como comando `curl` contra el proxy: el de `?proxy=` o el
+/// listener configurado.
+async fn capture_curl(ctx: &ProxyContext, id: &str, req: &Request<Body>) -> Response<Body> {
+    let bundle = match load_capture(ctx, id).await {
+        Ok(bundle) => bundle,
+        Err(response) => return response,
+    };
+    let proxy = match (query_param(req, "proxy"), ctx.listen()) {
+        (Some(proxy), _) => percent_decode(proxy),
+        (None, Some(listen)) => {
+            // A wildcard listener is reachable through loopback.
+            let ip = match listen.ip() {
+                ip if !ip.is_unspecified() => ip,
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            };
+            format!("http://{}", SocketAddr::new(ip, listen.port()))
+        }
+        (None, None) => {
+            return json_error(StatusCode::BAD_REQUEST, "falta el parámetro proxy");
+        }
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(bundle.request.to_curl(&proxy)))
+        .expect("respuesta curl")
+}
+
+/// Repite la captura por el camino normal del proxy, como su cliente
+/// original, y devuelve la respuesta nueva. Los métodos no idempotentes
+/// piden `?confirm=true`.
+async fn replay_capture(ctx: &ProxyContext, id: &str, req: &Request<Body>) -> Response<Body> {
+    let bundle = match load_capture(ctx, id).await {
+        Ok(bundle) => bundle,
+        Err(response) => return response,
+    };
+    let request = bundle.request;
+    let Ok(method) = Method::from_bytes(request.method.as_bytes()) else {
+        return json_error(StatusCode::BAD_REQUEST, "método capturado inválido");
+    };
+    if method == Method::CONNECT {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "los túneles CONNECT no se pueden repetir",
+        );
+    }
+    if request.body_truncated {
+        return json_error(
+            StatusCode::CONFLICT,
+            "el body se capturó truncado; repítala con el comando curl",
+        );
+    }
+    if !method.is_idempotent() && query_param(req, "confirm") != Some("true") {
+        return json_error(
+            StatusCode::CONFLICT,
+            format!("{method} no es idempotente; confirme con ?confirm=true"),
+        );
+    }
+    let uri = match request.replay_url() {
+        Ok(uri) => uri,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let mut replay = Request::new(Body::from(request.body.clone()));
+    *replay.method_mut() = method;
+    *replay.uri_mut() = uri;
+    *replay.headers_mut() = request.replay_headers();
+    replay.extensions_mut().insert(Replayed(bundle.request_id));
+    let client = bundle
+        .client
+        .parse()
+        .unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    match crate::proxy::handle_request(ctx.clone(), client, replay).await {
+        Ok(mut response) => {
+            if let Ok(value) = HeaderValue::from_str(id) {
+                response.headers_mut().insert("x-proxy-replay", value);
+            }
+            response
+        }
+        Err(e) => json_error(StatusCode::BAD_GATEWAY, e),
+    }
+}
+
+/// Búsqueda en el archivo de intercambios, del más reciente al más antiguo.
+fn search_intercepts(ctx: &ProxyContext, req: &Request<Body>) -> Response<Body> {
+    let Some(archive) = ctx.archive() else {
+        return archive_disabled();
+    };
+    match InterceptQuery::parse(req.uri().query().unwrap_or_default()) {
+        Ok(query) => json_response(StatusCode::OK, json!(archive.search(&query))),
+        Err(e) => json_response(StatusCode::BAD_REQUEST, json!({ "error": e })),
+    }
+}
+
+async fn get_intercept(ctx: &ProxyContext, id: &str) -> Response<Body> {
+    let Some(archive) = ctx.archive() else {
+        return archive_disabled();
+    };
+    let not_found = || {
+        json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "intercambio no encontrado" }),
+        )
+    };
+    let Ok(id) = id.parse() else {
+        return not_found();
+    };
+    match archive.get(id).await {
+        Ok(Some(exchange)) => json_response(StatusCode::OK, json!(exchange)),
+        Ok(None) => not_found(),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn preview_report(ctx: &ProxyContext) -> Response<Body> {
+    let Some(reporter) = ctx.reporter() else {
+        return reports_disabled();
+    };
+    match reporter.render(crate::stats::today() + 1) {
+        Ok(html) => Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(html))
+            .expect("respuesta de informe"),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn generate_report(ctx: &ProxyContext) -> Response<Body> {
+    let Some(reporter) = ctx.reporter() else {
+        return reports_disabled();
+    };
+    match reporter.generate().await {
+        Ok((_, delivered)) => json_response(StatusCode::OK, json!({ "delivered": delivered })),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Páginas de error del destino reemplazadas y cortes que ya no se pudieron
+/// reemplazar.
+fn error_pages(ctx: &ProxyContext) -> Response<Body> {
+    if ctx.error_pages().is_none() {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "no hay reglas de páginas de error" }),
+        );
+    }
+    json_response(
+        StatusCode::OK,
+        json!({
+            "replaced": ctx.metrics().error_pages_replaced(),
+            "unreplaceable": ctx.metrics().error_pages_unreplaceable(),
+        }),
+    )
+}
+
+/// Filas y aciertos de cada mapa de redirecciones.
+fn redirect_maps(ctx: &ProxyContext) -> Response<Body> {
+    let Some(maps) = ctx.redirect_maps() else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "no hay mapas de redirecciones" }),
+        );
+    };
+    json_response(StatusCode::OK, json!(maps.list()))
+}
+
+/// Redirección que abrió el router; `mapping` es `null` hasta que la
+/// concede o si la parada ya la borró.
+#[cfg(feature = "upnp")]
+fn port_mapping(ctx: &ProxyContext) -> Response<Body> {
+    let Some(mapper) = ctx.port_mapper() else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "la apertura de puertos en el router está desactivada" }),
+        );
+    };
+    json_response(StatusCode::OK, json!({ "mapping": mapper.current() }))
+}
+
+/// Bytes ahorrados por cliente y en total.
+#[cfg(feature = "transcoding")]
+fn data_saver_savings(ctx: &ProxyContext) -> Response<Body> {
+    let Some(transcoder) = ctx.transcoder() else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "el ahorro de datos está desactivado" }),
+        );
+    };
+    let clients: std::collections::BTreeMap<String, u64> = transcoder
+        .saved_by_client()
+        .into_iter()
+        .map(|(ip, bytes)| (ip.to_string(), bytes))
+        .collect();
+    json_response(
+        StatusCode::OK,
+        json!({
+            "bytes_saved": ctx.metrics().data_saver_bytes_saved(),
+            "passthrough": ctx.metrics().data_saver_passthrough(),
+            "clients": clients,
+        }),
+    )
+}
+
+/// Decisión de salida para `?url=`: una URL absoluta se evalúa como petición
+/// HTTP y un `host:puerto` como túnel `CONNECT`.
+fn egress_debug(ctx: &ProxyContext, req: &Request<Body>) -> Response<Body> {
+    let Some(egress) = ctx.egress() else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "la salida no está restringida (egress_policy = allow)" }),
+        );
+    };
+    let target = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("url="))
+        .and_then(|url| url.parse::<hyper::Uri>().ok());
+    let Some((target, host)) = target.and_then(|uri| {
+        let host = uri.host()?.to_string();
+        Some((uri, host))
+    }) else {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({ "error": "falta el parámetro url o no es válido" }),
+        );
+    };
+    let decision = match target.scheme_str() {
+        Some(scheme) => {
+            let default = if scheme == "https" { 443 } else { 80 };
+            let port = target.port_u16().unwrap_or(default);
+            egress.decide(&host, port, Some(target.path()))
+        }
+        None => egress.decide(&host, target.port_u16().unwrap_or(443), None),
+    };
+    json_response(StatusCode::OK, json!(decision))
+}
+
+/// Cadena canónica que se firmaría para `?url=` (codificada), con `method`,
+/// `body` y `header=nombre:valor` opcionales. La firma no se muestra.
+fn signing_debug(ctx: &ProxyContext, req: &Request<Body>) -> Response<Body> {
+    let Some(signer) = ctx.signer() else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "no hay reglas de firma" }),
+        );
+    };
+    let mut target = None;
+    let mut method = Method::GET;
+    let mut body = String::new();
+    let mut headers = hyper::HeaderMap::new();
+    for pair in req.uri().query().unwrap_or_default().split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value);
+        match key {
+            "url" => target = value.parse::<hyper::Uri>().ok(),
+            "method" => method = value.parse().unwrap_or(Method::GET),
+            "body" => body = value,
+            "header" => {
+                let header = value.split_once(':').and_then(|(name, value)| {
+                    Some((
+                        hyper::header::HeaderName::from_bytes(name.trim().as_bytes()).ok()?,
+                        hyper::header::HeaderValue::from_str(value.trim()).ok()?,
+                    ))
+                });
+                if let Some((name, value)) = header {
+                    headers.append(name, value);
+                }
+            }
+            _ => {}
+        }
+    }
+    let Some(target) = target.filter(|uri| uri.host().is_some()) else {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({ "error": "falta el parámetro url o no es válido" }),
+        );
+    };
+    match signer.explain(&method, &target, &headers, body.as_bytes()) {
+        Some(trace) => json_response(StatusCode::OK, json!(trace)),
+        None => json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "ninguna regla de firma cubre esa URL" }),
+        ),
+    }
+}
+
+/// Resultado de resolver `?host=` con las zonas y reescrituras DNS.
+async fn resolve_debug(ctx: &ProxyContext, req: &Request<Body>) -> Response<Body> {
+    let Some(dns) = ctx.dns() else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "el resolver no admite reglas DNS" }),
+        );
+    };
+    let host = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("host="));
+    let Some(host) = host.filter(|h| !h.is_empty()) else {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({ "error": "falta el parámetro host" }),
+        );
+    };
+    match dns.explain(host).await {
+        Ok(trace) => json_response(StatusCode::OK, json!(trace)),
+        Err(e) => json_response(StatusCode::BAD_GATEWAY, json!({ "error": e.to_string() })),
+    }
+}
+
+/// Evaluaciones de rollout por feature desde el arranque.
+fn rollout_exposures(ctx: &ProxyContext) -> Response<Body> {
+    let features: serde_json::Map<String, serde_json::Value> = Feature::ALL
+        .into_iter()
+        .map(|feature| {
+            let (enabled, disabled) = ctx.metrics().feature_exposures(feature);
+            let value = json!({ "enabled": enabled, "disabled": disabled });
+            (feature.as_str().to_string(), value)
+        })
+        .collect();
+    json_response(StatusCode::OK, serde_json::Value::Object(features))
+}
+
+/// Ocupación de la caché de sesiones TLS hacia los destinos y los saludos
+/// por host.
+fn upstream_tls(ctx: &ProxyContext) -> Response<Body> {
+    let no_resumption: Vec<String> = ctx
+        .upstream_tls()
+        .no_resumption()
+        .iter()
+        .map(|host| host.to_string())
+        .collect();
+    json_response(
+        StatusCode::OK,
+        json!({
+            "session_cache": ctx.tls_sessions().occupancy(),
+            "disable_resumption": no_resumption,
+            "hosts": ctx.metrics().host_tls_stats(),
+        }),
+    )
+}
+
+/// Conexiones por protocolo y reparto del ancho de banda por cliente
+/// (`null` sin `[bandwidth]`).
+fn stats(ctx: &ProxyContext) -> Response<Body> {
+    let protocols: serde_json::Map<_, _> = Protocol::ALL
+        .iter()
+        .map(|protocol| {
+            let count = ctx.metrics().protocols(*protocol);
+            (protocol.as_str().to_string(), json!(count))
+        })
+        .collect();
+    let bandwidth = ctx.bandwidth().map(|scheduler| {
+        json!({
+            "bytes_per_sec": scheduler.settings().bytes_per_sec(),
+            "clients": scheduler.snapshot(),
+        })
+    });
+    let metrics = ctx.metrics();
+    json_response(
+        StatusCode::OK,
+        json!({
+            "log_profile": ctx.sanitizer().profile().as_str(),
+            "requests": metrics.requests(),
+            "active_tunnels": metrics.tunnels_open(),
+            "hosts": metrics.host_traffic(),
+            "upstream_tls": metrics.host_tls_stats(),
+            "protocols": protocols,
+            "bandwidth": bandwidth,
+            "buffered": {
+                "bytes": metrics.buffered_bytes(),
+                "high_water_bytes": metrics.buffered_high_water(),
+            },
+            "accept": {
+                "rate_limited": metrics.accept_rejections(),
+                "paused": metrics.accept_pauses(),
+                "fd_exhausted": metrics.accept_fd_exhausted(),
+            },
+            "resources": ctx.resources().map(|resources| resources.snapshot()),
+            "nat64": ctx.nat64().map(|nat64| nat64.snapshot()),
+            "events": ctx.events().map(|events| events.stats()),
+            "access_log": ctx.access_log().map(|log| log.stats()),
+            "archive": ctx.archive().map(|archive| archive.stats()),
+            "cert_expiry": ctx
+                .cert_expiry()
+                .map(|monitor| monitor.snapshot(crate::cert_expiry::unix_now())),
+            "tokens": ctx.ai_router().map(|router| router.usage().snapshot()),
+            "header_limits": ctx.header_limits().map(|limits| {
+                let rejected: serde_json::Map<_, _> = HeaderLimit::ALL
+                    .iter()
+                    .map(|limit| {
+                        let count = metrics.header_rejections(*limit);
+                        (limit.as_str().to_string(), json!(count))
+                    })
+                    .collect();
+                json!({
+                    "max_header_bytes": limits.max_header_bytes(),
+                    "max_total_bytes": limits.max_total_bytes(),
+                    "max_count": limits.max_count(),
+                    "rejected": rejected,
+                    "upstream_rejected": metrics.upstream_errors(ProxyError::HeadersTooLarge),
+                })
+            }),
+            "rate_limit": ctx.rate_limiter().map(|limiter| limiter.snapshot()),
+            "response_cache": ctx.response_cache().map(|cache| {
+                let stale: serde_json::Map<_, _> = StaleReason::ALL
+                    .iter()
+                    .map(|reason| {
+                        let count = metrics.stale_served(*reason);
+                        (reason.as_str().to_string(), json!(count))
+                    })
+                    .collect();
+                json!({
+                    "cache": cache.snapshot(),
+                    "stale_served": stale,
+                })
+            }),
+            "prefetch": ctx.prefetcher().map(|prefetcher| {
+                let (queued, dropped) = metrics.prefetches();
+                json!({
+                    "max_per_page": prefetcher.settings().max_per_page(),
+                    "max_html_bytes": prefetcher.settings().max_html_bytes(),
+                    "queued": queued,
+                    "dropped": dropped,
+                    "hits": metrics.prefetch_hits(),
+                })
+            }),
+            "concurrency": ctx.concurrency().map(|concurrency| {
+                let rejected: serde_json::Map<_, _> = ConcurrencyLimit::ALL
+                    .iter()
+                    .map(|limit| {
+                        let count = metrics.concurrency_rejected(*limit);
+                        (limit.as_str().to_string(), json!(count))
+                    })
+                    .collect();
+                json!({
+                    "usage": concurrency.snapshot(),
+                    "rejected": rejected,
+                })
+            }),
+            "overload": ctx.overload().map(|overload| {
+                let shed: serde_json::Map<_, _> = ShedReason::ALL
+                    .iter()
+                    .map(|reason| {
+                        let by_class: serde_json::Map<_, _> = ClientClass::ALL
+                            .iter()
+                            .map(|class| {
+                                let count = metrics.overload_shed(*reason, *class);
+                                (class.as_str().to_string(), json!(count))
+                            })
+                            .collect();
+                        (reason.as_str().to_string(), json!(by_class))
+                    })
+                    .collect();
+                json!({
+                    "state": overload.snapshot(),
+                    "shed": shed,
+                    "retry_after_secs": metrics.overload_retry_after().snapshot(),
+                })
+            }),
+            "tunnel_quality": {
+                "rtt_ms": metrics.tunnel_quality().rtt_ms.snapshot(),
+                "retransmits": metrics.tunnel_quality().retransmits.snapshot(),
+            },
+        }),
+    )
+}
+
+/// Versión de `proxy-ia`, la misma que muestra `--version`.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+fn liveness(ctx: &ProxyContext) -> Response<Body> {
+    json_response(
+        StatusCode::OK,
+        json!({
+            "status": "ok",
+            "uptime_secs": ctx.shutdown().uptime().as_secs(),
+            "version": VERSION,
+        }),
+    )
+}
+
+/// 200 mientras la instancia acepta clientes nuevos; 503 al drenarse o
+/// pararse.
+fn readiness(ctx: &ProxyContext) -> Response<Body> {
+    let accepting = ctx.shutdown().is_accepting();
+    let draining = ctx.drain().is_draining();
+    let stopping = ctx.shutdown().is_stopping();
+    let ready = accepting && !draining && !stopping;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(
+        status,
+        json!({
+            "ready": ready,
+            "accepting": accepting,
+            "draining": draining,
+            "stopping": stopping,
+            "uptime_secs": ctx.shutdown().uptime().as_secs(),
+            "version": VERSION,
+        }),
+    )
+}
+
+fn drain_status(ctx: &ProxyContext, status: StatusCode) -> Response<Body> {
+    json_response(status, json!(ctx.drain().snapshot(ctx.shutdown().active())))
+}
+
+/// Baneos activos y cuántas conexiones rechazaron.
+fn list_bans(ctx: &ProxyContext) -> Response<Body> {
+    let Some(bans) = ctx.bans() else {
+        return bans_disabled();
+    };
+    json_response(
+        StatusCode::OK,
+        json!({
+            "bans": bans.active(),
+            "rejected": ctx.metrics().ban_rejections(),
+        }),
+    )
+}
+
+fn revoke_ban(ctx: &ProxyContext, ip: &str) -> Response<Body> {
+    let Some(bans) = ctx.bans() else {
+        return bans_disabled();
+    };
+    let Ok(ip) = ip.parse() else {
+        return json_response(StatusCode::BAD_REQUEST, json!({ "error": "IP inválida" }));
+    };
+    if bans.revoke(ip) {
+        json_response(StatusCode::OK, json!({ "revoked": ip }))
+    } else {
+        json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "la IP no está baneada" }),
+        )
+    }
+}
+
+/// Código de `?reason=` con el que se cierran túneles; `admin` si no hay y
+/// `None` si no es válido.
+fn termination_reason(req: &Request<Body>) -> Option<String> {
+    let reason = query_param(req, "reason").map_or_else(|| "admin".to_string(), percent_decode);
+    let valid = (1..=64).contains(&reason.len())
+        && reason
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'));
+    valid.then_some(reason)
+}
+
+fn invalid_reason() -> Response<Body> {
+    json_response(
+        StatusCode::BAD_REQUEST,
+        json!({ "error": "reason admite hasta 64 letras, dígitos, `_`, `-`, `.` o `:`" }),
+    )
+}
+
+/// Quién pide un cierre, para el log; `-` si la petición no llegó por
+/// [`serve`].
+fn admin_client(req: &Request<Body>) -> String {
+    req.extensions()
+        .get::<AdminClient>()
+        .map_or_else(|| "-".to_string(), |client| client.0.to_string())
+}
+
+/// Deja constancia de cada cierre pedido, en el log y en los eventos.
+fn audit_terminations(
+    ctx: &ProxyContext,
+    req: &Request<Body>,
+    tunnels: &[TunnelSnapshot],
+    reason: &str,
+) {
+    let admin = admin_client(req);
+    for tunnel in tunnels {
+        info!(admin = %admin, tunnel = tunnel.id, client = %tunnel.client, host = %tunnel.host, reason, "Cierre de túnel pedido desde la API de administración");
+        ctx.publish(|| Event::TunnelTerminated {
+            tunnel: tunnel.id,
+            client: tunnel.client,
+            host: tunnel.host.clone(),
+            reason: reason.to_string(),
+        });
+    }
+}
+
+fn terminate_tunnel(ctx: &ProxyContext, id: &str, req: &Request<Body>) -> Response<Body> {
+    let Some(reason) = termination_reason(req) else {
+        return invalid_reason();
+    };
+    let Ok(id) = id.parse() else {
+        return json_response(StatusCode::BAD_REQUEST, json!({ "error": "id inválido" }));
+    };
+    let Some(tunnel) = ctx.tunnels().terminate(id, &reason) else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "el túnel no está abierto" }),
+        );
+    };
+    audit_terminations(ctx, req, std::slice::from_ref(&tunnel), &reason);
+    json_response(StatusCode::OK, json!({ "closed": 1, "tunnels": [id] }))
+}
+
+fn terminate_client_tunnels(ctx: &ProxyContext, ip: &str, req: &Request<Body>) -> Response<Body> {
+    let Some(reason) = termination_reason(req) else {
+        return invalid_reason();
+    };
+    let Ok(ip) = ip.parse() else {
+        return json_response(StatusCode::BAD_REQUEST, json!({ "error": "IP inválida" }));
+    };
+    let tunnels = ctx.tunnels().terminate_client(ip, &reason);
+    audit_terminations(ctx, req, &tunnels, &reason);
+    let ids: Vec<u64> = tunnels.iter().map(|tunnel| tunnel.id).collect();
+    json_response(
+        StatusCode::OK,
+        json!({ "closed": ids.len(), "tunnels": ids }),
+    )
+}
+
+/// Como [`audit_terminations`], para las conexiones.
+fn audit_connection_terminations(
+    ctx: &ProxyContext,
+    req: &Request<Body>,
+    connections: &[ConnectionSnapshot],
+    reason: &str,
+) {
+    let admin = admin_client(req);
+    for connection in connections {
+        info!(admin = %admin, connection = connection.id, client = %connection.client, kind = connection.kind.as_str(), host = connection.host.as_deref(), reason, "Cierre de conexión pedido desde la API de administración");
+        ctx.publish(|| Event::ConnectionTerminated {
+            connection: connection.id,
+            client: connection.client,
+            kind: connection.kind.as_str(),
+            host: connection.host.clone(),
+            reason: reason.to_string(),
+        });
+    }
+}
+
+fn terminate_connection(ctx: &ProxyContext, id: &str, req: &Request<Body>) -> Response<Body> {
+    let Some(reason) = termination_reason(req) else {
+        return invalid_reason();
+    };
+    let Ok(id) = id.parse() else {
+        return json_response(StatusCode::BAD_REQUEST, json!({ "error": "id inválido" }));
+    };
+    let Some(connection) = ctx.connections().terminate(id, &reason) else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "la conexión no está abierta" }),
+        );
+    };
+    audit_connection_terminations(ctx, req, std::slice::from_ref(&connection), &reason);
+    json_response(StatusCode::OK, json!({ "closed": 1, "connections": [id] }))
+}
+
+/// Cierra las conexiones y los túneles de una IP.
+fn terminate_client_connections(
+    ctx: &ProxyContext,
+    ip: &str,
+    req: &Request<Body>,
+) -> Response<Body> {
+    let Some(reason) = termination_reason(req) else {
+        return invalid_reason();
+    };
+    let Ok(ip) = ip.parse() else {
+        return json_response(StatusCode::BAD_REQUEST, json!({ "error": "IP inválida" }));
+    };
+    let connections = ctx.connections().terminate_client(ip, &reason);
+    audit_connection_terminations(ctx, req, &connections, &reason);
+    let tunnels = ctx.tunnels().terminate_client(ip, &reason);
+    audit_terminations(ctx, req, &tunnels, &reason);
+    let connection_ids: Vec<u64> = connections.iter().map(|connection| connection.id).collect();
+    let tunnel_ids: Vec<u64> = tunnels.iter().map(|tunnel| tunnel.id).collect();
+    json_response(
+        StatusCode::OK,
+        json!({
+            "closed": connection_ids.len() + tunnel_ids.len(),
+            "connections": connection_ids,
+            "tunnels": tunnel_ids,
+        }),
+    )
+}
+
+fn pause_queue(ctx: &ProxyContext, name: &str, paused: bool) -> Response<Body> {
+    if ctx.jobs().set_paused(name, paused) {
+        json_response(StatusCode::OK, json!({ "queue": name, "paused": paused }))
+    } else {
+        json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": "cola desconocida" }),
+        )
+    }
+}
+
+/// Cuerpo de `POST /api/tickets`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TicketRequest {
+    device: String,
+    destinations: Vec<String>,
+    ttl_secs: u64,
+}
+
+/// Tamaño máximo del cuerpo de `POST /api/tickets`.
+const MAX_TICKET_REQUEST: usize = 16 * 1024;
+
+fn list_tickets(ctx: &ProxyContext) -> Response<Body> {
+    let Some(tickets) = ctx.tickets() else {
+        return tickets_disabled();
+    };
+    let (accepted, rejected) = tickets.counts();
+    json_response(
+        StatusCode::OK,
+        json!({
+            "revoked": tickets.revoked(),
+            "max_ttl_secs": tickets.max_ttl().as_secs(),
+            "accepted": accepted,
+            "rejected": rejected,
+        }),
+    )
+}
+
+/// Emite un ticket; el token solo aparece en esta respuesta.
+async fn mint_ticket(ctx: &ProxyContext, req: Request<Body>) -> Response<Body> {
+    let Some(tickets) = ctx.tickets() else {
+        return tickets_disabled();
+    };
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() <= MAX_TICKET_REQUEST => body,
+        Ok(_) => return json_error(StatusCode::PAYLOAD_TOO_LARGE, "petición demasiado grande"),
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let request: TicketRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let destinations = match request
+        .destinations
+        .iter()
+        .map(|rule| rule.parse::<HostRule>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(destinations) => destinations,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let ttl = Duration::from_secs(request.ttl_secs);
+    match tickets.mint(&request.device, destinations, ttl, SystemTime::now()) {
+        Ok((ticket, token)) => {
+            info!(ticket = %ticket.id, device = %ticket.device, expires_at = ticket.expires_at, "Ticket emitido");
+            json_response(
+                StatusCode::CREATED,
+                json!({
+                    "id": ticket.id,
+                    "device": ticket.device,
+                    "destinations": request.destinations,
+                    "expires_at": ticket.expires_at,
+                    "token": token,
+                }),
+            )
+        }
+        Err(e) => json_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+fn revoke_ticket(ctx: &ProxyContext, id: &str) -> Response<Body> {
+    let Some(tickets) = ctx.tickets() else {
+        return tickets_disabled();
+    };
+    // Tickets are stateless, so any id can be revoked ahead of its use.
+    let newly = tickets.revoke(id);
+    if newly {
+        info!(ticket = %id, "Ticket revocado");
+    }
+    json_response(StatusCode::OK, json!({ "revoked": id, "already": !newly }))
+}
+
+/// Cuerpo de `POST /api/blocklist`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockRequest {
+    host: String,
+}
+
+/// Tamaño máximo del cuerpo de `POST /api/blocklist`.
+const MAX_BLOCK_REQUEST: usize = 1024;
+
+fn list_blocklist(ctx: &ProxyContext) -> Response<Body> {
+    let Some(blocklist) = ctx.blocklist() else {
+        return blocklist_disabled();
+    };
+    json_response(
+        StatusCode::OK,
+        json!({
+            "file": blocklist.path(),
+            "entries": blocklist.list(),
+        }),
+    )
+}
+
+/// Bloquea un host; 201 si es nuevo y 200 si ya estaba.
+async fn add_to_blocklist(ctx: &ProxyContext, req: Request<Body>) -> Response<Body> {
+    let Some(blocklist) = ctx.blocklist() else {
+        return blocklist_disabled();
+    };
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() <= MAX_BLOCK_REQUEST => body,
+        Ok(_) => return json_error(StatusCode::PAYLOAD_TOO_LARGE, "petición demasiado grande"),
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let request: BlockRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let rule: HostRule = match request.host.parse() {
+        Ok(rule) => rule,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    match blocklist.add(rule) {
+        Ok((entry, true)) => json_response(StatusCode::OK, json!(entry)),
+        Ok((entry, false)) => {
+            info!(host = %entry.host, "Host añadido a la lista de bloqueo");
+            json_response(StatusCode::CREATED, json!(entry))
+        }
+        Err(e) => {
+            error!(error = %e, "No se pudo guardar la lista de bloqueo");
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+        }
+    }
+}
+
+fn remove_from_blocklist(ctx: &ProxyContext, rule: &str) -> Response<Body> {
+    let Some(blocklist) = ctx.blocklist() else {
+        return blocklist_disabled();
+    };
+    let rule: HostRule = match rule.parse() {
+        Ok(rule) => rule,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    match blocklist.remove(&rule) {
+        Ok(true) => {
+            info!(host = %rule, "Host quitado de la lista de bloqueo");
+            json_response(StatusCode::OK, json!({ "removed": rule.to_string() }))
+        }
+        Ok(false) => json_error(
+            StatusCode::NOT_FOUND,
+            format!("`{rule}` no está en la lista de bloqueo"),
+        ),
+        Err(e) => {
+            error!(error = %e, "No se pudo guardar la lista de bloqueo");
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+        }
+    }
+}
+
+fn blocklist_disabled() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({ "error": "la lista de bloqueo está desactivada" }),
+    )
+}
+
+/// Cuerpo de `PUT /api/canary`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CanaryWeight {
+    weight: f64,
+}
+
+/// Tamaño máximo del cuerpo de `PUT /api/canary`.
+const MAX_CANARY_REQUEST: usize = 1024;
+
+fn canary_status(ctx: &ProxyContext, status: StatusCode) -> Response<Body> {
+    match ctx.canary() {
+        Some(canary) => json_response(status, json!(canary.snapshot())),
+        None => canary_disabled(),
+    }
+}
+
+async fn set_canary_weight(ctx: &ProxyContext, req: Request<Body>) -> Response<Body> {
+    let Some(canary) = ctx.canary() else {
+        return canary_disabled();
+    };
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() <= MAX_CANARY_REQUEST => body,
+        Ok(_) => return json_error(StatusCode::PAYLOAD_TOO_LARGE, "petición demasiado grande"),
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let request: CanaryWeight = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    match canary.set_weight(request.weight) {
+        Ok(()) => {
+            info!(weight = request.weight, "Peso del canary cambiado");
+            canary_status(ctx, StatusCode::OK)
+        }
+        Err(e @ CanaryError::RolledBack) => json_error(StatusCode::CONFLICT, e),
+        Err(e) => json_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+fn reset_canary(ctx: &ProxyContext) -> Response<Body> {
+    let Some(canary) = ctx.canary() else {
+        return canary_disabled();
+    };
+    canary.reset();
+    info!(weight = canary.settings().weight(), "Canary restablecido");
+    canary_status(ctx, StatusCode::OK)
+}
+
+fn canary_disabled() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({ "error": "el canary está desactivado" }),
+    )
+}
+
+fn tickets_disabled() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({ "error": "los tickets están desactivados" }),
+    )
+}
+
+fn bans_disabled() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({ "error": "los baneos están desactivados" }),
+    )
+}
+
+fn reports_disabled() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({ "error": "los informes están desactivados" }),
+    )
+}
+
+fn capture_disabled() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({ "error": "la captura está desactivada" }),
+    )
+}
+
+fn archive_disabled() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({ "error": "el archivo de intercambios está desactivado" }),
+    )
+}
+
+fn internal_error(e: impl std::fmt::Display) -> Response<Body> {
+    error!(error = %e, "Error en la API de administración");
+    json_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        json!({ "error": e.to_string() }),
+    )
+}
+
+/// Error con la forma de los de la API: `{"error": mensaje}`.
+pub fn json_error(status: StatusCode, message: impl std::fmt::Display) -> Response<Body> {
+    json_response(status, json!({ "error": message.to_string() }))
+}
+
+pub fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .expect("respuesta JSON")
+}
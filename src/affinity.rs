@@ -0,0 +1,243 @@
+//! Afinidad de conexiones hacia destinos con autenticación por conexión.
+//!
+//! NTLM y Negotiate autentican el socket TCP, no la petición, así que una
+//! conexión del pool compartido podría llevar la sesión de otro cliente. Para
+//! los destinos con afinidad cada conexión de cliente tiene su propio
+//! [`AffinitySession`], con un cliente hyper y un pool que nadie más usa y que
+//! se cierra al cerrarse la conexión del cliente.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use futures_util::StreamExt;
+use hyper::header::WWW_AUTHENTICATE;
+use hyper::{Body, Client, HeaderMap, Request, Response};
+use tokio::sync::Semaphore;
+use tracing::info;
+
+use crate::dialer::{DialConnector, Dialer};
+use crate::origin_tls::{self, OriginConnector};
+use crate::settings::AffinitySettings;
+
+/// Tope de destinos detectados automáticamente.
+const MAX_DETECTED_HOSTS: usize = 1024;
+
+/// Decide qué destinos requieren afinidad.
+pub struct AffinityRouter {
+    settings: AffinitySettings,
+    detected: Mutex<HashSet<String>>,
+}
+
+impl AffinityRouter {
+    pub fn new(settings: AffinitySettings) -> Self {
+        Self {
+            settings,
+            detected: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn settings(&self) -> &AffinitySettings {
+        &self.settings
+    }
+
+    pub fn applies(&self, host: &str) -> bool {
+        self.settings.hosts().iter().any(|p| p.matches(host))
+            || self
+                .detected
+                .lock()
+                .expect("destinos detectados")
+                .contains(&host.to_ascii_lowercase())
+    }
+
+    /// Registra los destinos que piden NTLM o Negotiate y no tienen afinidad
+    /// configurada, para sugerir añadirlos a la configuración.
+    pub fn observe(&self, host: &str, headers: &HeaderMap) {
+        if !self.settings.auto_detect() || self.applies(host) {
+            return;
+        }
+        let connection_auth = headers.get_all(WWW_AUTHENTICATE).iter().any(|value| {
+            let scheme = value.to_str().unwrap_or("").split_whitespace().next();
+            scheme.is_some_and(|s| {
+                s.eq_ignore_ascii_case("ntlm") || s.eq_ignore_ascii_case("negotiate")
+            })
+        });
+        if !connection_auth {
+            return;
+        }
+        let mut detected = self.detected.lock().expect("destinos detectados");
+        if detected.len() < MAX_DETECTED_HOSTS && detected.insert(host.to_ascii_lowercase()) {
+            info!(%host, "Destino con autenticación por conexión; se recomienda añadirlo a [affinity].hosts");
+        }
+    }
+
+    pub fn detected(&self) -> Vec<String> {
+        let mut hosts: Vec<_> = self
+            .detected
+            .lock()
+            .expect("destinos detectados")
+            .iter()
+            .cloned()
+            .collect();
+        hosts.sort();
+        hosts
+    }
+}
+
+/// Conexiones hacia destinos con afinidad de una única conexión de cliente.
+pub struct AffinitySession {
+    dialer: Arc<Dialer>,
+    client: OnceLock<Client<OriginConnector>>,
+    permits: Arc<Semaphore>,
+    max_connections: usize,
+}
+
+impl AffinitySession {
+    pub fn new(dialer: Arc<Dialer>, max_connections: usize) -> Self {
+        Self {
+            dialer,
+            client: OnceLock::new(),
+            permits: Arc::new(Semaphore::new(max_connections)),
+            max_connections,
+        }
+    }
+
+    /// Envía `req` por el pool propio de esta sesión. Cada petición en curso
+    /// ocupa un permiso hasta que su body termina, lo que acota las
+    /// conexiones abiertas al límite por cliente.
+    pub async fn request(&self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semáforo de afinidad");
+        let client = self.client.get_or_init(|| {
+            Client::builder()
+                .pool_max_idle_per_host(self.max_connections)
+                // HTTP/2 would share the authenticated connection.
+                .build(OriginConnector::new(
+                    DialConnector::new(self.dialer.clone()),
+                    origin_tls::client_config(true),
+                ))
+        });
+        let response = client.request(req).await?;
+        let (parts, body) = response.into_parts();
+        let body = body.map(move |chunk| {
+            let _held = &permit;
+            chunk
+        });
+        Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use hyper::body::to_bytes;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::connection::accept_loop;
+    use crate::dialer::SystemResolver;
+    use crate::proxy::ProxyContext;
+    use crate::settings::{ConnectionLimits, DialSettings, ListenerProtocols};
+
+    /// Origin that answers with the peer address of the socket it came on.
+    async fn spawn_origin(open: Arc<AtomicUsize>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let open = open.clone();
+                open.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let service = service_fn(move |_req| async move {
+                        Ok::<_, Infallible>(Response::new(Body::from(peer.to_string())))
+                    });
+                    let _ = Http::new().serve_connection(stream, service).await;
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        addr
+    }
+
+    async fn client(proxy: SocketAddr) -> hyper::client::conn::SendRequest<Body> {
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let (sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        sender
+    }
+
+    async fn upstream_socket(
+        sender: &mut hyper::client::conn::SendRequest<Body>,
+        origin: SocketAddr,
+    ) -> String {
+        let req = Request::get(format!("http://{origin}/"))
+            .body(Body::empty())
+            .unwrap();
+        let res = sender.send_request(req).await.unwrap();
+        String::from_utf8(to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_clients_never_share_upstream_socket() {
+        let open = Arc::new(AtomicUsize::new(0));
+        let origin = spawn_origin(open.clone()).await;
+        let dialer = Dialer::new(DialSettings::default(), Arc::new(SystemResolver));
+        let affinity = AffinitySettings::default().with_host("127.0.0.1".parse().unwrap());
+        let ctx = ProxyContext::new(dialer).with_affinity(AffinityRouter::new(affinity));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(accept_loop(
+            listener,
+            ctx,
+            ConnectionLimits::default(),
+            ListenerProtocols::default(),
+        ));
+
+        let mut alice = client(proxy).await;
+        let mut bob = client(proxy).await;
+        let a1 = upstream_socket(&mut alice, origin).await;
+        let b1 = upstream_socket(&mut bob, origin).await;
+        let a2 = upstream_socket(&mut alice, origin).await;
+        let b2 = upstream_socket(&mut bob, origin).await;
+        assert_eq!(a1, a2, "cada cliente reutiliza su propia conexión");
+        assert_eq!(b1, b2);
+        assert_ne!(a1, b1, "dos clientes nunca comparten socket");
+
+        drop(alice);
+        drop(bob);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while open.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("las conexiones al destino se cierran con las del cliente");
+    }
+
+    #[test]
+    fn test_detects_connection_oriented_auth() {
+        let router = AffinityRouter::new(AffinitySettings::default());
+        let mut headers = HeaderMap::new();
+        headers.insert(WWW_AUTHENTICATE, "Basic realm=x".parse().unwrap());
+        router.observe("web.test", &headers);
+        assert!(!router.applies("web.test"));
+
+        headers.append(WWW_AUTHENTICATE, "Negotiate".parse().unwrap());
+        router.observe("Intranet.test", &headers);
+        assert!(router.applies("intranet.test"));
+        assert_eq!(router.detected(), ["intranet.test"]);
+
+        let off = AffinityRouter::new(AffinitySettings::default().with_auto_detect(false));
+        off.observe("intranet.test", &headers);
+        assert!(!off.applies("intranet.test"));
+    }
+}
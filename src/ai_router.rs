@@ -0,0 +1,507 @@
+//! Router IA: un único endpoint compatible con OpenAI delante de varios
+//! backends.
+//!
+//! Los `POST` que llegan al propio proxy (en forma de origen, sin host en la
+//! línea de petición) a las rutas de `[ai_router]` se reenvían al backend del
+//! `model` de su body: `llama3` a un Ollama local, `gpt-4o` a OpenAI. El body
+//! se retiene hasta `max_body_bytes` para leer el modelo y se reenvía tal
+//! cual. Un modelo sin backend, un JSON inválido o un body mayor que el
+//! límite van a `default_upstream`. La URI y el `Host` se reescriben al
+//! backend elegido, así que el filtro de hosts y las claves de
+//! `[[providers]]` se aplican a él como a cualquier otro destino. El consumo
+//! de tokens de sus respuestas se cuenta por cliente ([`crate::token_usage`])
+//! y las peticiones repetidas pueden servirse desde [`crate::llm_cache`]; con
+//! `[token_budget]`, cada cliente gasta de su presupuesto
+//! ([`crate::token_budget`]).
+//!
+//! Con `[ai_router.retry]`, un 429 o 529 del backend no llega al cliente
+//! mientras queden intentos: si el modelo tiene backends de reserva se pasa
+//! al siguiente sin esperar y, si no, se repite en el mismo tras la espera
+//! de su `Retry-After`, con un tope. Solo se reintentan las peticiones cuyo
+//! body se retuvo entero para enrutarlas; la decisión se toma con la
+//! cabecera de la respuesta, antes de enviar nada al cliente, así que un
+//! stream que ya empezó a llegarle no se repite nunca.
+//!
+//! Un modelo con varios backends los reparte según su estrategia y aparta
+//! los que fallan ([`crate::balancer`]). El backend que atendió cada
+//! petición queda en su línea del log de acceso y, con `--debug-headers`,
+//! en la cabecera `X-Upstream` de la respuesta.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures_util::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, HOST, RETRY_AFTER};
+use hyper::http::Extensions;
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::balancer::{Backend, Balancer, Flight};
+use crate::capture::buffer_prefix;
+use crate::connection;
+use crate::llm_cache::{LlmCache, Lookup};
+use crate::metrics::Metrics;
+use crate::settings::{AiRetrySettings, AiRouterSettings};
+use crate::token_budget::{self, Exhausted, Reservation, TokenBudgets, REMAINING_TOKENS};
+use crate::token_usage::{self, TokenLedger};
+
+/// Intentos que hizo el proxy además del primero.
+pub const X_PROXY_RETRIES: &str = "x-proxy-retries";
+
+/// Backend que atendió la petición, con `--debug-headers`.
+pub const X_UPSTREAM: &str = "x-upstream";
+
+/// Espera sin `Retry-After` antes de repetir en el mismo backend.
+const DEFAULT_WAIT: Duration = Duration::from_secs(1);
+
+/// Lo único que hace falta del body.
+#[derive(Deserialize)]
+struct ModelField {
+    model: String,
+}
+
+/// Extensión de la respuesta con la URL base del backend que la dio, para
+/// el log de acceso.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutedTo(pub String);
+
+pub struct AiRouter {
+    settings: AiRouterSettings,
+    balancer: Balancer,
+    usage: Arc<TokenLedger>,
+    cache: Option<LlmCache>,
+    budgets: Option<Arc<TokenBudgets>>,
+    debug_headers: bool,
+}
+
+impl AiRouter {
+    pub fn new(settings: AiRouterSettings) -> Self {
+        Self {
+            balancer: Balancer::new(&settings),
+            settings,
+            usage: Arc::default(),
+            cache: None,
+            budgets: None,
+            debug_headers: false,
+        }
+    }
+
+    /// Añade `X-Upstream` a las respuestas.
+    pub fn with_debug_headers(mut self, debug_headers: bool) -> Self {
+        self.debug_headers = debug_headers;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: LlmCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn with_budgets(mut self, budgets: TokenBudgets) -> Self {
+        self.budgets = Some(Arc::new(budgets));
+        self
+    }
+
+    pub fn settings(&self) -> &AiRouterSettings {
+        &self.settings
+    }
+
+    /// Tokens consumidos por cada cliente.
+    pub fn usage(&self) -> &TokenLedger {
+        &self.usage
+    }
+
+    /// Reserva para `client` los tokens estimados del prompt; sin
+    /// presupuestos no hay nada que reservar.
+    pub fn reserve(
+        &self,
+        client: &str,
+        prompt_tokens: u64,
+    ) -> Result<Option<Reservation>, Exhausted> {
+        self.budgets
+            .as_ref()
+            .map(|budgets| budgets.reserve(client, prompt_tokens))
+            .transpose()
+    }
+
+    /// Cuenta para `client` los tokens de `response` mientras se entrega y
+    /// corrige con ellos la reserva.
+    pub fn meter(
+        &self,
+        client: String,
+        response: &mut Response<Body>,
+        reservation: Option<Reservation>,
+    ) {
+        let mut settle = None;
+        if let Some(reservation) = reservation {
+            response
+                .headers_mut()
+                .insert(REMAINING_TOKENS, HeaderValue::from(reservation.remaining()));
+            settle = Some(reservation);
+        }
+        token_usage::meter(
+            self.usage.clone(),
+            client,
+            response,
+            self.settings.max_body(),
+            move |count| {
+                if let Some(reservation) = settle {
+                    reservation.settle(count);
+                }
+            },
+        );
+    }
+
+    /// Si `req` es un `POST` dirigido al proxy en una de las rutas del router.
+    pub fn applies(&self, req: &Request<Body>) -> bool {
+        req.method() == Method::POST
+            && req.uri().authority().is_none()
+            && self
+                .settings
+                .paths()
+                .iter()
+                .any(|path| path == req.uri().path())
+    }
+
+    /// Respuesta guardada para la petición, si la caché tiene una.
+    pub fn cached(&self, lookup: &Lookup, metrics: &Metrics) -> Option<Response<Body>> {
+        match (&self.cache, lookup) {
+            (Some(cache), Lookup::Key(key)) => cache.lookup(key, metrics),
+            _ => None,
+        }
+    }
+
+    /// Marca la respuesta del backend y la guarda en la caché si se puede.
+    pub fn remember(&self, lookup: Lookup, response: &mut Response<Body>) {
+        if let Some(cache) = &self.cache {
+            cache.store(lookup, response, self.settings.max_body());
+        }
+    }
+
+    /// Anota en el backend si `result` fue un fallo: un error de conexión o
+    /// un 5xx.
+    pub fn observe(&self, flight: &Flight, result: &Result<Response<Body>, hyper::Error>) {
+        let ok = result
+            .as_ref()
+            .is_ok_and(|response| !response.status().is_server_error());
+        flight.backend().observe(ok);
+    }
+
+    /// Deja en `response` el backend que la dio; la petición sigue en curso
+    /// para el reparto hasta que el body termina.
+    pub fn deliver(&self, flight: Flight, response: &mut Response<Body>) {
+        let upstream = flight.backend().uri().to_string();
+        if self.debug_headers {
+            if let Ok(value) = HeaderValue::from_str(&upstream) {
+                response.headers_mut().insert(X_UPSTREAM, value);
+            }
+        }
+        response.extensions_mut().insert(RoutedTo(upstream));
+        if !response.body().is_end_stream() {
+            let body = std::mem::take(response.body_mut()).inspect(move |_| {
+                let _in_flight = &flight;
+            });
+            *response.body_mut() = Body::wrap_stream(body);
+        }
+    }
+
+    /// Reescribe `req` hacia el backend de su modelo; el body llega al
+    /// backend idéntico.
+    pub async fn route(&self, req: Request<Body>) -> Routed {
+        let (mut parts, body) = req.into_parts();
+        let (prefix, truncated, body) = buffer_prefix(body, self.settings.max_body()).await;
+        // Past the limit only the declared length is known.
+        let prompt_tokens = match truncated {
+            true => parts
+                .headers
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+                .unwrap_or(prefix.len() as u64)
+                .div_ceil(4),
+            false => token_budget::estimate_prompt_tokens(&prefix),
+        };
+        let lookup = self.cache.as_ref().map(|cache| match truncated {
+            true => Lookup::Bypass("body"),
+            false => cache.key(parts.uri.path(), &prefix),
+        });
+        let model = if truncated {
+            None
+        } else {
+            serde_json::from_slice::<ModelField>(&prefix)
+                .ok()
+                .map(|field| field.model)
+        };
+        let pool = model
+            .as_deref()
+            .and_then(|model| self.settings.pool(model))
+            .map(|(name, _)| name);
+        let backends = self.balancer.order(pool);
+        let flight = backends[0].start();
+        let path_and_query = parts
+            .uri
+            .path_and_query()
+            .map(|pq| pq.as_str().to_string())
+            .unwrap_or_else(|| "/".to_string());
+        let uri = target(backends[0].uri(), &path_and_query);
+        set_host(&mut parts.headers, &uri);
+        debug!(
+            model = model.as_deref().unwrap_or("-"),
+            truncated,
+            target = %uri,
+            "Petición de IA enrutada"
+        );
+        parts.uri = uri;
+        // A body cut short by an error cannot be sent again as it was.
+        let complete = !truncated && body.size_hint().exact() == Some(prefix.len() as u64);
+        let retry = self
+            .settings
+            .retry()
+            .filter(|_| complete)
+            .map(|settings| Retry {
+                settings: *settings,
+                method: parts.method.clone(),
+                version: parts.version,
+                headers: parts.headers.clone(),
+                extensions: connection_extensions(&parts.extensions),
+                path_and_query,
+                body: Bytes::from(prefix),
+                backends,
+                current: 0,
+                attempt: 1,
+            });
+        Routed {
+            request: Request::from_parts(parts, body),
+            lookup,
+            prompt_tokens,
+            flight,
+            retry,
+        }
+    }
+}
+
+/// Una petición enrutada y lo que el proxy necesita después de ella.
+pub struct Routed {
+    pub request: Request<Body>,
+    /// Cómo buscarla en la caché, si hay.
+    pub lookup: Option<Lookup>,
+    /// Tokens estimados del prompt.
+    pub prompt_tokens: u64,
+    /// La petición en curso hacia el backend elegido.
+    pub flight: Flight,
+    /// Copia para reintentarla, si hay reintentos y el body cupo entero.
+    pub retry: Option<Retry>,
+}
+
+/// Reintentos de una petición enrutada ante un backend saturado.
+pub struct Retry {
+    settings: AiRetrySettings,
+    method: Method,
+    version: Version,
+    headers: HeaderMap,
+    extensions: Extensions,
+    path_and_query: String,
+    body: Bytes,
+    backends: Vec<Arc<Backend>>,
+    current: usize,
+    attempt: u32,
+}
+
+impl Retry {
+    /// La petición que sigue a `response`, la espera antes de enviarla y su
+    /// backend, si `response` es un 429 o 529 y quedan intentos.
+    pub fn next(&mut self, response: &Response<Body>) -> Option<(Request<Body>, Duration, Flight)> {
+        let throttled =
+            response.status() == StatusCode::TOO_MANY_REQUESTS || response.status().as_u16() == 529;
+        if !throttled || self.attempt >= self.settings.attempts() {
+            return None;
+        }
+        let wait = if self.settings.failover() && self.current + 1 < self.backends.len() {
+            self.current += 1;
+            Duration::ZERO
+        } else {
+            retry_after(response.headers())
+                .unwrap_or(DEFAULT_WAIT)
+                .min(self.settings.max_wait())
+        };
+        self.attempt += 1;
+        let backend = &self.backends[self.current];
+        let uri = target(backend.uri(), &self.path_and_query);
+        let mut req = Request::new(Body::from(self.body.clone()));
+        *req.method_mut() = self.method.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = self.headers.clone();
+        *req.extensions_mut() = connection_extensions(&self.extensions);
+        set_host(req.headers_mut(), &uri);
+        *req.uri_mut() = uri;
+        Some((req, wait, backend.start()))
+    }
+
+    /// Intentos hechos, contando el primero.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Anota en la respuesta final los intentos de más, si los hubo.
+    pub fn annotate(&self, response: &mut Response<Body>) {
+        if self.attempt > 1 {
+            response
+                .headers_mut()
+                .insert(X_PROXY_RETRIES, HeaderValue::from(self.attempt - 1));
+        }
+    }
+}
+
+fn connection_extensions(from: &Extensions) -> Extensions {
+    let mut extensions = Extensions::new();
+    connection::copy_extensions(from, &mut extensions);
+    extensions
+}
+
+/// URI de `path_and_query` en el backend `upstream`, bajo su ruta base.
+fn target(upstream: &Uri, path_and_query: &str) -> Uri {
+    format!(
+        "{}://{}{}{}",
+        upstream.scheme_str().unwrap_or("http"),
+        upstream.authority().map(|a| a.as_str()).unwrap_or_default(),
+        upstream.path().trim_end_matches('/'),
+        path_and_query
+    )
+    .parse()
+    .expect("URI del backend del router IA")
+}
+
+fn set_host(headers: &mut HeaderMap, uri: &Uri) {
+    if let Some(authority) = uri.authority() {
+        let host = HeaderValue::from_str(authority.as_str()).expect("host del backend");
+        headers.insert(HOST, host);
+    }
+}
+
+/// Espera que pide `Retry-After`, en segundos o como fecha.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_model_picks_the_backend_and_fallbacks_use_the_default() {
+        let router = AiRouter::new(
+            AiRouterSettings::new("https://api.openai.test".parse().unwrap())
+                .with_model("llama3", "http://ollama.test:11434/".parse().unwrap())
+                .with_model("mistral", "http://mistral.test/base".parse().unwrap())
+                .with_max_body(64),
+        );
+        let request = |path: &str, body: &'static str| {
+            Request::post(path)
+                .header(HOST, "proxy.test:8080")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let target = |req: &Request<Body>| {
+            (
+                req.uri().to_string(),
+                req.headers()[HOST].to_str().unwrap().to_string(),
+            )
+        };
+
+        let req = request("/v1/chat/completions", r#"{"model":"llama3","n":1}"#);
+        assert!(router.applies(&req));
+        let routed = router.route(req).await.request;
+        assert_eq!(
+            target(&routed),
+            (
+                "http://ollama.test:11434/v1/chat/completions".to_string(),
+                "ollama.test:11434".to_string()
+            )
+        );
+        let body = hyper::body::to_bytes(routed.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"{"model":"llama3","n":1}"#);
+
+        let routed = router
+            .route(request(
+                "/v1/embeddings?x=1",
+                r#"{"model":"mistral-large"}"#,
+            ))
+            .await;
+        assert_eq!(routed.lookup, None);
+        assert_eq!(routed.prompt_tokens, 7);
+        assert!(routed.retry.is_none());
+        assert_eq!(
+            routed.request.uri(),
+            "http://mistral.test/base/v1/embeddings?x=1"
+        );
+
+        for body in [
+            r#"{"model":"gpt-4o"}"#,
+            "no es json",
+            r#"{"model":"llama3","padding":"...................................................."}"#,
+        ] {
+            let routed = router.route(request("/v1/completions", body)).await.request;
+            assert_eq!(routed.uri(), "https://api.openai.test/v1/completions");
+            assert_eq!(routed.headers()[HOST], "api.openai.test");
+        }
+
+        assert!(!router.applies(&request("/v1/models", "")));
+        assert!(!router.applies(
+            &Request::get("/v1/chat/completions")
+                .body(Body::empty())
+                .unwrap()
+        ));
+        assert!(!router.applies(
+            &Request::post("http://api.test/v1/chat/completions")
+                .body(Body::empty())
+                .unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retries_wait_for_retry_after_without_failover() {
+        let retry = AiRetrySettings::default()
+            .with_failover(false)
+            .with_max_wait(Duration::from_secs(5));
+        let router = AiRouter::new(
+            AiRouterSettings::new("http://a.test".parse().unwrap())
+                .with_model("llama3", "http://a.test".parse().unwrap())
+                .with_model("llama3", "http://b.test/v2".parse().unwrap())
+                .with_retry(retry),
+        );
+        let req = Request::post("/v1/chat/completions")
+            .body(Body::from(r#"{"model":"llama3"}"#))
+            .unwrap();
+        let mut retry = router.route(req).await.retry.expect("complete body");
+        let throttled = |status: u16, retry_after: Option<&str>| {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::from_u16(status).unwrap();
+            if let Some(value) = retry_after {
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, value.parse().unwrap());
+            }
+            response
+        };
+
+        assert!(retry.next(&throttled(503, Some("1"))).is_none());
+        let (again, wait, _) = retry.next(&throttled(529, Some("120"))).unwrap();
+        assert_eq!(wait, Duration::from_secs(5));
+        assert_eq!(again.uri(), "http://a.test/v1/chat/completions");
+        let body = hyper::body::to_bytes(again.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"{"model":"llama3"}"#);
+        let (_, wait, _) = retry.next(&throttled(429, None)).unwrap();
+        assert_eq!(wait, DEFAULT_WAIT);
+        assert!(retry.next(&throttled(429, None)).is_none(), "3 attempts");
+
+        let mut response = Response::new(Body::empty());
+        retry.annotate(&mut response);
+        assert_eq!(response.headers()[X_PROXY_RETRIES], "2");
+    }
+}
@@ -0,0 +1,1211 @@
+//! Archivo consultable de los intercambios HTTP que atraviesan el proxy.
+//!
+//! A diferencia de la captura de fallos, que deja un JSON suelto por
+//! petición, el archivo es un registro continuo pensado para investigar: cada
+//! intercambio (método, URL redactada, cabeceras, estado, cliente y duración)
+//! se añade a segmentos JSONL del directorio, y un índice en memoria,
+//! reconstruido al arrancar, permite buscar por host, ruta, estado, cliente y
+//! fecha. Los bodies solo se guardan si hay clave: hasta `max_body_bytes` de
+//! cada uno, cifrados con AES-256-GCM. La escritura va por un canal a una
+//! tarea aparte para no frenar el tráfico; si la cola se llena el intercambio
+//! no se archiva. Los segmentos se borran enteros al superar `max_age` o
+//! `max_total_bytes`. Solo queda lo que el proxy ve en claro: de un túnel
+//! `CONNECT` ciego se archiva el destino y el estado; si `[mitm]` lo
+//! intercepta, cada petición descifrada pasa por el mismo pipeline y se
+//! archiva entera, con su URL `https://`.
+//!
+//! No se usa SQLite: traería una biblioteca en C al build, y las búsquedas
+//! (filtros por campo sobre entradas ordenadas por fecha) caben en un índice
+//! en memoria. Los segmentos JSONL se podan borrando ficheros enteros, sin
+//! `VACUUM`, y se leen con cualquier herramienta de texto.
+//!
+//! Los bodies van al [`BlobStore`] de `blobs/`: un body repetido, como la
+//! misma respuesta servida a muchos clientes, ocupa un solo blob, y cada
+//! línea guarda su hash. El nonce sale de un HMAC del contenido para que el
+//! mismo body cifre igual. Las líneas de antes, con el body cifrado dentro,
+//! se siguen leyendo y desaparecen con su segmento.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::blob_store::{BlobId, BlobStats, BlobStore};
+use crate::host_pattern::HostPattern;
+use crate::jobs::{self, JobScheduler};
+use crate::log_throttle::LogThrottle;
+use crate::privacy::Sanitizer;
+use crate::redact::redact_headers;
+use crate::settings::ArchiveSettings;
+
+/// Intercambios que pueden esperar a la tarea de escritura.
+const QUEUE: usize = 1024;
+const MAX_BATCH: usize = 256;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+/// Resultados por búsqueda si no se pide `limit`.
+pub const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// Fila del índice, tal como la devuelve una búsqueda.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExchangeSummary {
+    pub id: u64,
+    pub at_ms: u64,
+    pub client: String,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: f64,
+}
+
+/// Intercambio completo, con los bodies ya descifrados.
+#[derive(Debug, Clone, Serialize)]
+pub struct Exchange {
+    #[serde(flatten)]
+    pub summary: ExchangeSummary,
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    pub response_headers: Vec<(String, String)>,
+    pub request_body: Option<ArchivedBody>,
+    pub response_body: Option<ArchivedBody>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArchivedBody {
+    /// Convertido a UTF-8 con pérdida.
+    pub text: String,
+    pub truncated: bool,
+}
+
+/// Filtros de `GET /api/intercepts`; todos deben cumplirse.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterceptQuery {
+    pub host: Option<HostPattern>,
+    /// Prefijo de la ruta.
+    pub path: Option<String>,
+    pub status: Option<StatusFilter>,
+    pub client: Option<String>,
+    pub method: Option<String>,
+    pub since_ms: Option<u64>,
+    pub until_ms: Option<u64>,
+    /// Texto a buscar en la URL, sin distinguir mayúsculas.
+    pub text: Option<String>,
+    pub limit: usize,
+}
+
+impl Default for InterceptQuery {
+    fn default() -> Self {
+        Self {
+            host: None,
+            path: None,
+            status: None,
+            client: None,
+            method: None,
+            since_ms: None,
+            until_ms: None,
+            text: None,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+/// `404` casa con ese estado y `4xx` con cualquiera de la clase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFilter {
+    Exact(u16),
+    Class(u16),
+}
+
+impl InterceptQuery {
+    /// Interpreta la query string de la API; el error nombra el parámetro.
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value);
+            let invalid = || format!("valor inválido para {name}");
+            match name {
+                "host" => parsed.host = Some(value.parse().map_err(|_| invalid())?),
+                "path" => parsed.path = Some(value),
+                "status" => {
+                    let class = value
+                        .strip_suffix("xx")
+                        .and_then(|digit| digit.parse::<u16>().ok())
+                        .filter(|digit| (1..=5).contains(digit));
+                    parsed.status = Some(match class {
+                        Some(class) => StatusFilter::Class(class),
+                        None => StatusFilter::Exact(value.parse().map_err(|_| invalid())?),
+                    });
+                }
+                "client" => parsed.client = Some(value.to_string()),
+                "method" => parsed.method = Some(value.to_ascii_uppercase()),
+                "since" => parsed.since_ms = Some(value.parse().map_err(|_| invalid())?),
+                "until" => parsed.until_ms = Some(value.parse().map_err(|_| invalid())?),
+                "q" => parsed.text = Some(value.to_lowercase()),
+                "limit" => {
+                    let limit: usize = value.parse().map_err(|_| invalid())?;
+                    parsed.limit = limit.clamp(1, MAX_LIMIT);
+                }
+                _ => return Err(format!("parámetro desconocido: {name}")),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        let summary = &entry.summary;
+        self.host
+            .as_ref()
+            .is_none_or(|host| host.matches(&summary.host))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|prefix| summary.path.starts_with(prefix.as_str()))
+            && self.status.is_none_or(|status| match status {
+                StatusFilter::Exact(code) => summary.status == code,
+                StatusFilter::Class(class) => summary.status / 100 == class,
+            })
+            && self
+                .client
+                .as_ref()
+                .is_none_or(|client| summary.client == *client)
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| summary.method == *method)
+            && self.since_ms.is_none_or(|since| summary.at_ms >= since)
+            && self.until_ms.is_none_or(|until| summary.at_ms <= until)
+            && self
+                .text
+                .as_ref()
+                .is_none_or(|text| entry.url.contains(text.as_str()))
+    }
+}
+
+/// `%XX` y `+` de una query string; lo que no es válido se deja tal cual.
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Inicio de un body, anotado mientras pasa hacia su destino.
+#[derive(Debug, Default)]
+struct Prefix {
+    bytes: Vec<u8>,
+    truncated: bool,
+}
+
+type SharedPrefix = Arc<Mutex<Prefix>>;
+
+fn take(prefix: &SharedPrefix) -> Prefix {
+    std::mem::take(&mut *prefix.lock().expect("lock del body archivado"))
+}
+
+/// Body que copia su inicio en `prefix` al reenviarse. `on_drop` corre
+/// cuando hyper lo suelta, haya terminado o no.
+struct Tee {
+    body: Body,
+    cap: usize,
+    prefix: SharedPrefix,
+    on_drop: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Stream for Tee {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.body).poll_data(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            let mut prefix = this.prefix.lock().expect("lock del body archivado");
+            let room = this.cap.saturating_sub(prefix.bytes.len());
+            prefix.truncated |= chunk.len() > room;
+            prefix
+                .bytes
+                .extend_from_slice(&chunk[..room.min(chunk.len())]);
+        }
+        poll
+    }
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.on_drop.take() {
+            on_drop();
+        }
+    }
+}
+
+/// Intercambio observado, a la espera de la tarea de escritura.
+struct Observed {
+    at_ms: u64,
+    client: String,
+    method: String,
+    url: String,
+    host: String,
+    path: String,
+    status: u16,
+    duration_ms: f64,
+    request_headers: Vec<(String, String)>,
+    response_headers: Vec<(String, String)>,
+    request_body: Option<Prefix>,
+    response_body: Option<Prefix>,
+}
+
+/// Petición en curso; se completa con [`InterceptArchive::finish`].
+pub struct PendingExchange {
+    started: Instant,
+    at_ms: u64,
+    client: String,
+    method: String,
+    url: String,
+    host: String,
+    path: String,
+    request_headers: Vec<(String, String)>,
+    request_body: Option<SharedPrefix>,
+}
+
+/// Línea de un segmento.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    id: u64,
+    at_ms: u64,
+    client: String,
+    method: String,
+    url: String,
+    host: String,
+    path: String,
+    status: u16,
+    duration_ms: f64,
+    request_headers: Vec<(String, String)>,
+    response_headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_body: Option<StoredBody>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response_body: Option<StoredBody>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredBody {
+    /// Blob cifrado: nonce seguido del texto cifrado con su etiqueta.
+    Blob { sha256: String, truncated: bool },
+    /// Formato anterior, con el body dentro de la línea.
+    Inline(Sealed),
+}
+
+impl StoredBody {
+    fn blob(&self) -> Option<BlobId> {
+        match self {
+            Self::Blob { sha256, .. } => sha256.parse().ok(),
+            Self::Inline(_) => None,
+        }
+    }
+}
+
+/// Body cifrado: nonce y texto cifrado con su etiqueta, en base64.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    data: String,
+    truncated: bool,
+}
+
+struct Entry {
+    summary: ExchangeSummary,
+    /// URL en minúsculas, para `q`.
+    url: String,
+    segment: u64,
+    offset: u64,
+    len: usize,
+    /// Blobs de sus bodies, que se sueltan al borrar el segmento.
+    blobs: Vec<BlobId>,
+}
+
+struct Segment {
+    bytes: u64,
+    newest_ms: u64,
+}
+
+struct Current {
+    segment: u64,
+    file: File,
+}
+
+#[derive(Default)]
+struct State {
+    /// Ordenadas por id.
+    entries: Vec<Entry>,
+    /// Por id del primer intercambio, que da nombre al fichero.
+    segments: BTreeMap<u64, Segment>,
+    current: Option<Current>,
+    next_id: u64,
+}
+
+/// Extremo de la cola que usa el tráfico.
+struct Queue {
+    tx: mpsc::Sender<Observed>,
+    dropped: AtomicU64,
+    drop_log: LogThrottle,
+}
+
+impl Queue {
+    fn send(&self, observed: Observed) {
+        if self.tx.try_send(observed).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            if let Some(suppressed) = self.drop_log.should_log() {
+                warn!(
+                    suppressed,
+                    "Cola del archivo llena: intercambio sin archivar"
+                );
+            }
+        }
+    }
+}
+
+/// Estado del archivo, tal como lo muestra `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArchiveStats {
+    pub exchanges: usize,
+    pub segments: usize,
+    pub segment_bytes: u64,
+    pub dropped: u64,
+    pub bodies: BlobStats,
+}
+
+pub struct InterceptArchive {
+    settings: ArchiveSettings,
+    key: Option<LessSafeKey>,
+    /// Deriva el nonce de cada blob de su contenido.
+    nonce_key: Option<hmac::Key>,
+    blobs: BlobStore,
+    state: Mutex<State>,
+    queue: Arc<Queue>,
+    rx: Mutex<Option<mpsc::Receiver<Observed>>>,
+}
+
+impl InterceptArchive {
+    /// Abre el directorio y reconstruye el índice de los segmentos que ya
+    /// tenga. Las líneas ilegibles, como una escritura cortada, se saltan, y
+    /// los blobs que ninguna línea nombra se borran.
+    pub fn open(settings: ArchiveSettings) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(settings.dir())?;
+        let blobs = BlobStore::open(settings.dir().join("blobs"))?;
+        let mut state = State::default();
+        let mut files: Vec<(u64, PathBuf)> = std::fs::read_dir(settings.dir())?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let first = path
+                    .extension()
+                    .filter(|ext| *ext == "jsonl")
+                    .and(path.file_stem())?
+                    .to_str()?
+                    .parse()
+                    .ok()?;
+                Some((first, path))
+            })
+            .collect();
+        files.sort();
+        for (first, path) in files {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut segment = Segment {
+                bytes: 0,
+                newest_ms: 0,
+            };
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let read = reader.read_line(&mut line)?;
+                if read == 0 {
+                    break;
+                }
+                let offset = segment.bytes;
+                segment.bytes += read as u64;
+                let Ok(stored) = serde_json::from_str::<Stored>(line.trim_end()) else {
+                    warn!(segment = %path.display(), offset, "Línea ilegible en el archivo");
+                    continue;
+                };
+                segment.newest_ms = segment.newest_ms.max(stored.at_ms);
+                state.next_id = state.next_id.max(stored.id + 1);
+                let entry = Entry::new(&stored, first, offset, line.trim_end().len());
+                entry.blobs.iter().for_each(|id| blobs.retain(*id));
+                state.entries.push(entry);
+            }
+            state.segments.insert(first, segment);
+        }
+        state.entries.sort_by_key(|entry| entry.summary.id);
+        blobs.collect_orphans()?;
+
+        let key = settings.key().map(|key| {
+            LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key.bytes()).expect("clave de 32 bytes"))
+        });
+        let nonce_key = settings.key().map(|key| {
+            let derived = hmac::sign(
+                &hmac::Key::new(hmac::HMAC_SHA256, key.bytes()),
+                b"proxy-ia archive blob nonce",
+            );
+            hmac::Key::new(hmac::HMAC_SHA256, derived.as_ref())
+        });
+        let (tx, rx) = mpsc::channel(QUEUE);
+        Ok(Self {
+            settings,
+            key,
+            nonce_key,
+            blobs,
+            state: Mutex::new(state),
+            queue: Arc::new(Queue {
+                tx,
+                dropped: AtomicU64::new(0),
+                drop_log: LogThrottle::new(Duration::from_secs(10)),
+            }),
+            rx: Mutex::new(Some(rx)),
+        })
+    }
+
+    pub fn settings(&self) -> &ArchiveSettings {
+        &self.settings
+    }
+
+    /// Intercambios que no se archivaron por tener la cola llena.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> ArchiveStats {
+        let state = self.state.lock().expect("lock del archivo");
+        ArchiveStats {
+            exchanges: state.entries.len(),
+            segments: state.segments.len(),
+            segment_bytes: state.segments.values().map(|segment| segment.bytes).sum(),
+            dropped: self.dropped(),
+            bodies: self.blobs.stats(),
+        }
+    }
+
+    /// Empieza a seguir una petición. Con clave, su body se copia mientras se
+    /// reenvía, sin esperar a leerlo. El cliente, la URL, la ruta y las
+    /// cabeceras quedan como diga `sanitizer`.
+    pub fn start(
+        &self,
+        req: &mut Request<Body>,
+        client: SocketAddr,
+        sanitizer: &Sanitizer,
+    ) -> PendingExchange {
+        let request_body =
+            (self.key.is_some() && req.method() != Method::CONNECT && !req.body().is_end_stream())
+                .then(|| {
+                    let prefix = SharedPrefix::default();
+                    let body = std::mem::take(req.body_mut());
+                    *req.body_mut() = self.tee(body, prefix.clone(), None);
+                    prefix
+                });
+        let uri = req.uri();
+        PendingExchange {
+            started: Instant::now(),
+            at_ms: unix_millis(SystemTime::now()),
+            client: sanitizer.ip(client.ip()),
+            method: req.method().to_string(),
+            url: sanitizer.url(uri),
+            host: uri
+                .host()
+                .unwrap_or_default()
+                .trim_end_matches('.')
+                .to_ascii_lowercase(),
+            path: sanitizer.url_text(uri.path()),
+            request_headers: sanitizer.headers(redact_headers(req.headers())),
+            request_body,
+        }
+    }
+
+    /// Encola el intercambio. Si hay body que archivar se encola cuando
+    /// termina de enviarse al cliente.
+    pub fn finish(
+        &self,
+        pending: PendingExchange,
+        response: &mut Response<Body>,
+        sanitizer: &Sanitizer,
+    ) {
+        let status = response.status();
+        let mut observed = Observed {
+            at_ms: pending.at_ms,
+            client: pending.client,
+            method: pending.method,
+            url: pending.url,
+            host: pending.host,
+            path: pending.path,
+            status: status.as_u16(),
+            duration_ms: pending.started.elapsed().as_secs_f64() * 1000.0,
+            request_headers: pending.request_headers,
+            response_headers: sanitizer.headers(redact_headers(response.headers())),
+            request_body: None,
+            response_body: None,
+        };
+        let request_body = pending.request_body;
+        let tee = self.key.is_some()
+            && observed.method != Method::CONNECT.as_str()
+            && status != StatusCode::SWITCHING_PROTOCOLS
+            && !response.body().is_end_stream();
+        if !tee {
+            observed.request_body = request_body.as_ref().map(take);
+            self.queue.send(observed);
+            return;
+        }
+        let prefix = SharedPrefix::default();
+        let on_drop = {
+            let prefix = prefix.clone();
+            let queue = self.queue.clone();
+            move || {
+                observed.request_body = request_body.as_ref().map(take);
+                observed.response_body = Some(take(&prefix));
+                queue.send(observed);
+            }
+        };
+        let body = std::mem::take(response.body_mut());
+        *response.body_mut() = self.tee(body, prefix, Some(Box::new(on_drop)));
+    }
+
+    fn tee(
+        &self,
+        body: Body,
+        prefix: SharedPrefix,
+        on_drop: Option<Box<dyn FnOnce() + Send>>,
+    ) -> Body {
+        Body::wrap_stream(Tee {
+            body,
+            cap: self.settings.max_body_bytes(),
+            prefix,
+            on_drop,
+        })
+    }
+
+    /// Escribe lo que llega por la cola en lotes y poda cada minuto, como
+    /// trabajos de la cola `archive`. El disco se toca fuera de los hilos del
+    /// runtime.
+    pub async fn run(self: Arc<Self>, jobs: Arc<JobScheduler>) {
+        let Some(mut rx) = self.rx.lock().expect("cola del archivo").take() else {
+            return;
+        };
+        let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            let archive = self.clone();
+            let done = tokio::select! {
+                observed = rx.recv() => {
+                    let Some(first) = observed else {
+                        return;
+                    };
+                    let mut batch = vec![first];
+                    while batch.len() < MAX_BATCH {
+                        match rx.try_recv() {
+                            Ok(observed) => batch.push(observed),
+                            Err(_) => break,
+                        }
+                    }
+                    let done = jobs.run(jobs::ARCHIVE, async move {
+                        tokio::task::spawn_blocking(move || archive.write(batch)).await
+                    });
+                    done.await
+                }
+                _ = prune.tick() => {
+                    jobs.run(jobs::ARCHIVE, async move {
+                        tokio::task::spawn_blocking(move || {
+                            archive.prune(unix_millis(SystemTime::now()))
+                        })
+                        .await
+                    })
+                    .await
+                }
+            };
+            match done {
+                Some(Ok(Err(e))) => {
+                    warn!(error = %e, "No se pudo actualizar el archivo de intercambios")
+                }
+                None => warn!("La cola de trabajo interno descartó una escritura del archivo"),
+                _ => {}
+            }
+        }
+    }
+
+    fn write(&self, batch: Vec<Observed>) -> io::Result<()> {
+        {
+            let mut state = self.state.lock().expect("lock del archivo");
+            let segment_bytes = (self.settings.max_total_bytes() / 8).clamp(1, MAX_SEGMENT_BYTES);
+            for observed in batch {
+                let id = state.next_id;
+                state.next_id += 1;
+                let stored = self.store(id, observed)?;
+                let line = serde_json::to_string(&stored)?;
+
+                let full = state
+                    .current
+                    .as_ref()
+                    .and_then(|current| state.segments.get(&current.segment))
+                    .is_none_or(|segment| segment.bytes >= segment_bytes);
+                if full {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(self.segment_path(id))?;
+                    state.segments.insert(
+                        id,
+                        Segment {
+                            bytes: 0,
+                            newest_ms: 0,
+                        },
+                    );
+                    state.current = Some(Current { segment: id, file });
+                }
+                let current = state.current.as_mut().expect("segmento abierto");
+                let segment_id = current.segment;
+                current.file.write_all(format!("{line}\n").as_bytes())?;
+                let segment = state
+                    .segments
+                    .get_mut(&segment_id)
+                    .expect("segmento indexado");
+                let offset = segment.bytes;
+                segment.bytes += line.len() as u64 + 1;
+                segment.newest_ms = segment.newest_ms.max(stored.at_ms);
+                state
+                    .entries
+                    .push(Entry::new(&stored, segment_id, offset, line.len()));
+            }
+            if let Some(current) = state.current.as_mut() {
+                current.file.flush()?;
+            }
+        }
+        self.prune(unix_millis(SystemTime::now()))
+    }
+
+    /// Guarda los bodies como blobs y arma la línea.
+    fn store(&self, id: u64, observed: Observed) -> io::Result<Stored> {
+        let seal = |prefix: Option<Prefix>| -> io::Result<Option<StoredBody>> {
+            let (Some(key), Some(nonce_key), Some(prefix)) =
+                (self.key.as_ref(), self.nonce_key.as_ref(), prefix)
+            else {
+                return Ok(None);
+            };
+            // The same body always seals to the same blob; the nonce only
+            // repeats for the same plaintext.
+            let nonce: [u8; NONCE_LEN] = hmac::sign(nonce_key, &prefix.bytes).as_ref()[..NONCE_LEN]
+                .try_into()
+                .expect("HMAC más largo que el nonce");
+            let mut data = prefix.bytes;
+            key.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(BLOB_AAD),
+                &mut data,
+            )
+            .expect("cifrado AES-GCM");
+            let mut blob = nonce.to_vec();
+            blob.extend_from_slice(&data);
+            Ok(Some(StoredBody::Blob {
+                sha256: self.blobs.put(&blob)?.to_string(),
+                truncated: prefix.truncated,
+            }))
+        };
+        Ok(Stored {
+            id,
+            at_ms: observed.at_ms,
+            client: observed.client,
+            request_body: seal(observed.request_body)?,
+            response_body: seal(observed.response_body)?,
+            method: observed.method,
+            url: observed.url,
+            host: observed.host,
+            path: observed.path,
+            status: observed.status,
+            duration_ms: observed.duration_ms,
+            request_headers: observed.request_headers,
+            response_headers: observed.response_headers,
+        })
+    }
+
+    /// Borra los segmentos más antiguos mientras su intercambio más reciente
+    /// haya superado `max_age` o el total, blobs incluidos, supere
+    /// `max_total_bytes`. El segmento en uso solo se borra por antigüedad.
+    fn prune(&self, now_ms: u64) -> io::Result<()> {
+        let cutoff = now_ms.saturating_sub(self.settings.max_age().as_millis() as u64);
+        let mut state = self.state.lock().expect("lock del archivo");
+        let mut total: u64 = state
+            .segments
+            .values()
+            .map(|segment| segment.bytes)
+            .sum::<u64>()
+            + self.blobs.physical_bytes();
+        while let Some((&first, segment)) = state.segments.first_key_value() {
+            let current = state
+                .current
+                .as_ref()
+                .is_some_and(|current| current.segment == first);
+            let expired = segment.newest_ms < cutoff;
+            if !expired && (current || total <= self.settings.max_total_bytes()) {
+                break;
+            }
+            total -= segment.bytes;
+            if current {
+                state.current = None;
+            }
+            match std::fs::remove_file(self.segment_path(first)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            state.segments.remove(&first);
+            state.entries.retain(|entry| {
+                if entry.segment != first {
+                    return true;
+                }
+                for id in &entry.blobs {
+                    total -= self.blobs.release(id);
+                }
+                false
+            });
+        }
+        Ok(())
+    }
+
+    /// Intercambios que cumplen `query`, del más reciente al más antiguo.
+    pub fn search(&self, query: &InterceptQuery) -> Vec<ExchangeSummary> {
+        let cutoff = unix_millis(SystemTime::now())
+            .saturating_sub(self.settings.max_age().as_millis() as u64);
+        let state = self.state.lock().expect("lock del archivo");
+        state
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.summary.at_ms >= cutoff && query.matches(entry))
+            .take(query.limit)
+            .map(|entry| entry.summary.clone())
+            .collect()
+    }
+
+    /// Un intercambio con sus bodies descifrados. Falla si están cifrados con
+    /// otra clave o no hay ninguna configurada.
+    pub async fn get(&self, id: u64) -> anyhow::Result<Option<Exchange>> {
+        let location = {
+            let state = self.state.lock().expect("lock del archivo");
+            state
+                .entries
+                .binary_search_by_key(&id, |entry| entry.summary.id)
+                .ok()
+                .map(|index| {
+                    let entry = &state.entries[index];
+                    (entry.segment, entry.offset, entry.len)
+                })
+        };
+        let Some((segment, offset, len)) = location else {
+            return Ok(None);
+        };
+        let mut file = match tokio::fs::File::open(self.segment_path(segment)).await {
+            Ok(file) => file,
+            // Pruned between the lookup and the read.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        let mut line = vec![0u8; len];
+        file.read_exact(&mut line).await?;
+        let stored: Stored = serde_json::from_slice(&line)?;
+
+        let request_body = self.open_body(id, "request", stored.request_body).await?;
+        let response_body = self.open_body(id, "response", stored.response_body).await?;
+        Ok(Some(Exchange {
+            summary: ExchangeSummary {
+                id: stored.id,
+                at_ms: stored.at_ms,
+                client: stored.client,
+                method: stored.method,
+                host: stored.host,
+                path: stored.path,
+                status: stored.status,
+                duration_ms: stored.duration_ms,
+            },
+            url: stored.url,
+            request_headers: stored.request_headers,
+            response_headers: stored.response_headers,
+            request_body,
+            response_body,
+        }))
+    }
+
+    /// Descifra un body. Sin su blob, porque se borró con otro segmento o
+    /// estaba dañado, el intercambio se devuelve sin él.
+    async fn open_body(
+        &self,
+        id: u64,
+        part: &str,
+        body: Option<StoredBody>,
+    ) -> anyhow::Result<Option<ArchivedBody>> {
+        let Some(body) = body else {
+            return Ok(None);
+        };
+        let key = self
+            .key
+            .as_ref()
+            .context("el intercambio tiene bodies cifrados y no hay clave configurada")?;
+        let (nonce, mut data, aad, truncated) = match body {
+            StoredBody::Blob { sha256, truncated } => {
+                let blob_id: BlobId = sha256
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("hash de blob inválido en el intercambio {id}"))?;
+                let Some(mut blob) = self.blobs.read(&blob_id).await? else {
+                    return Ok(None);
+                };
+                anyhow::ensure!(blob.len() >= NONCE_LEN, "blob {blob_id} sin nonce");
+                let data = blob.split_off(NONCE_LEN);
+                (blob, data, BLOB_AAD.to_vec(), truncated)
+            }
+            StoredBody::Inline(sealed) => (
+                STANDARD.decode(&sealed.nonce)?,
+                STANDARD.decode(&sealed.data)?,
+                aad(id, part),
+                sealed.truncated,
+            ),
+        };
+        let nonce: [u8; NONCE_LEN] = nonce
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("nonce inválido en el intercambio {id}"))?;
+        let plain = key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut data,
+            )
+            .map_err(|_| {
+                anyhow::anyhow!("no se pudo descifrar el intercambio {id}: ¿cambió la clave?")
+            })?;
+        Ok(Some(ArchivedBody {
+            text: String::from_utf8_lossy(plain).into_owned(),
+            truncated,
+        }))
+    }
+
+    fn segment_path(&self, first: u64) -> PathBuf {
+        self.settings.dir().join(format!("{first:020}.jsonl"))
+    }
+}
+
+impl Entry {
+    fn new(stored: &Stored, segment: u64, offset: u64, len: usize) -> Self {
+        Self {
+            summary: ExchangeSummary {
+                id: stored.id,
+                at_ms: stored.at_ms,
+                client: stored.client.clone(),
+                method: stored.method.clone(),
+                host: stored.host.clone(),
+                path: stored.path.clone(),
+                status: stored.status,
+                duration_ms: stored.duration_ms,
+            },
+            url: stored.url.to_lowercase(),
+            segment,
+            offset,
+            len,
+            blobs: [&stored.request_body, &stored.response_body]
+                .into_iter()
+                .filter_map(|body| body.as_ref()?.blob())
+                .collect(),
+        }
+    }
+}
+
+/// Un blob lo comparten todos los intercambios con el mismo body, así que
+/// no se ata a ninguno.
+const BLOB_AAD: &[u8] = b"proxy-ia archive blob";
+
+/// Ata cada body cifrado en línea a su intercambio: no se puede copiar a
+/// otro.
+fn aad(id: u64, part: &str) -> Vec<u8> {
+    format!("{id}:{part}").into_bytes()
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ArchiveKey;
+
+    fn observed(host: &str, path: &str, status: u16, client: &str, at_ms: u64) -> Observed {
+        Observed {
+            at_ms,
+            client: client.into(),
+            method: "GET".into(),
+            url: format!("http://{host}{path}"),
+            host: host.into(),
+            path: path.into(),
+            status,
+            duration_ms: 1.0,
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            request_body: None,
+            response_body: None,
+        }
+    }
+
+    fn ids(found: Vec<ExchangeSummary>) -> Vec<u64> {
+        found.into_iter().map(|summary| summary.id).collect()
+    }
+
+    #[test]
+    fn test_search_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = InterceptArchive::open(ArchiveSettings::new(dir.path())).unwrap();
+        let now = unix_millis(SystemTime::now());
+        archive
+            .write(vec![
+                observed("api.example.com", "/v1/users", 200, "10.0.0.1", now - 3000),
+                observed("api.example.com", "/v1/orders", 503, "10.0.0.2", now - 2000),
+                observed("cdn.other.test", "/v1/users", 404, "10.0.0.1", now - 1000),
+            ])
+            .unwrap();
+        let search = |query: &str| ids(archive.search(&InterceptQuery::parse(query).unwrap()));
+
+        assert_eq!(search(""), [2, 1, 0]);
+        assert_eq!(search("host=*.example.com"), [1, 0]);
+        assert_eq!(search("path=/v1/users"), [2, 0]);
+        assert_eq!(search("status=5xx"), [1]);
+        assert_eq!(search("status=404&client=10.0.0.1"), [2]);
+        assert_eq!(search(&format!("since={}", now - 2500)), [2, 1]);
+        assert_eq!(search(&format!("until={}", now - 2500)), [0]);
+        assert_eq!(search("q=%2FV1%2Forders"), [1]);
+        assert_eq!(search("limit=1"), [2]);
+        assert!(InterceptQuery::parse("status=abc").is_err());
+        assert!(InterceptQuery::parse("color=red").is_err());
+    }
+
+    #[test]
+    fn test_retention_prunes_old_and_oversized_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = ArchiveSettings::new(dir.path())
+            .with_max_age(Duration::from_secs(3600))
+            .with_max_total_bytes(2048);
+        let archive = InterceptArchive::open(settings.clone()).unwrap();
+        let now = unix_millis(SystemTime::now());
+        let old = now - 2 * 3600 * 1000;
+        archive
+            .write(vec![observed("a.test", "/", 200, "10.0.0.1", old)])
+            .unwrap();
+        assert!(archive.search(&InterceptQuery::default()).is_empty());
+        let segments = || {
+            std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap())
+                .filter(|meta| meta.is_file())
+        };
+        assert!(segments().next().is_none());
+
+        for _ in 0..40 {
+            archive
+                .write(vec![observed("b.test", "/", 200, "10.0.0.1", now)])
+                .unwrap();
+        }
+        let on_disk: u64 = segments().map(|meta| meta.len()).sum();
+        assert!(on_disk <= 2048 + 2048 / 8 + 512, "{on_disk}");
+        let kept = ids(archive.search(&InterceptQuery::default()));
+        assert_eq!(kept[0], 40);
+        assert!(kept.len() < 40);
+
+        // The index is rebuilt from what survived on disk.
+        drop(archive);
+        let reopened = InterceptArchive::open(settings).unwrap();
+        assert_eq!(ids(reopened.search(&InterceptQuery::default())), kept);
+    }
+
+    #[tokio::test]
+    async fn test_bodies_round_trip_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ArchiveKey::new([7; 32]);
+        let settings = ArchiveSettings::new(dir.path())
+            .with_key(key)
+            .with_max_body_bytes(8);
+        let archive = Arc::new(InterceptArchive::open(settings.clone()).unwrap());
+        tokio::spawn(archive.clone().run(Arc::new(JobScheduler::default())));
+
+        let mut req = Request::post("http://api.example.com/login")
+            .header("authorization", "Bearer secreto")
+            .body(Body::from("usuario=ana"))
+            .unwrap();
+        let pending = archive.start(
+            &mut req,
+            "127.0.0.1:1".parse().unwrap(),
+            &Sanitizer::default(),
+        );
+        // Forwarding still sees the whole body.
+        assert_eq!(
+            hyper::body::to_bytes(req.into_body()).await.unwrap(),
+            "usuario=ana"
+        );
+        let mut response = Response::new(Body::from("bienvenida"));
+        archive.finish(pending, &mut response, &Sanitizer::default());
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "bienvenida"
+        );
+
+        let exchange = loop {
+            if let Some(exchange) = archive.get(0).await.unwrap() {
+                break exchange;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let body = |text: &str, truncated| ArchivedBody {
+            text: text.into(),
+            truncated,
+        };
+        assert_eq!(exchange.request_body, Some(body("usuario=", true)));
+        assert_eq!(exchange.response_body, Some(body("bienveni", true)));
+        assert!(exchange
+            .request_headers
+            .contains(&("authorization".into(), "[redacted]".into())));
+
+        let raw: Vec<u8> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .chain(std::fs::read_dir(dir.path().join("blobs")).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .flat_map(|path| std::fs::read(path).unwrap())
+            .collect();
+        assert!(!String::from_utf8_lossy(&raw).contains("usuario"));
+
+        let other_key = settings.clone().with_key(ArchiveKey::new([8; 32]));
+        let wrong = InterceptArchive::open(other_key).unwrap();
+        assert!(wrong.get(0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_bodies_share_blobs_that_survive_pruning_and_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ArchiveKey::new([7; 32]);
+        let settings = ArchiveSettings::new(dir.path()).with_key(key.clone());
+        let archive = InterceptArchive::open(settings.clone()).unwrap();
+        let now = unix_millis(SystemTime::now());
+        let with_body = |body: &str| Observed {
+            response_body: Some(Prefix {
+                bytes: body.as_bytes().to_vec(),
+                truncated: false,
+            }),
+            ..observed("cdn.test", "/logo", 200, "10.0.0.1", now)
+        };
+        archive
+            .write(vec![
+                with_body("misma respuesta"),
+                with_body("misma respuesta"),
+                with_body("otra"),
+            ])
+            .unwrap();
+        let blobs = || -> Vec<PathBuf> {
+            std::fs::read_dir(dir.path().join("blobs"))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect()
+        };
+        assert_eq!(blobs().len(), 2);
+        let stats = archive.stats().bodies;
+        assert_eq!(stats.references, 3);
+        assert!(stats.logical_bytes > stats.physical_bytes);
+        let response = |exchange: Option<Exchange>| exchange.unwrap().response_body.map(|b| b.text);
+        assert_eq!(
+            response(archive.get(1).await.unwrap()).unwrap(),
+            "misma respuesta"
+        );
+
+        // A line written before the blob store keeps its body inline.
+        let sealing = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key.bytes()).unwrap());
+        let mut data = b"antigua".to_vec();
+        sealing
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key([1; NONCE_LEN]),
+                Aad::from(aad(3, "response")),
+                &mut data,
+            )
+            .unwrap();
+        let mut legacy = archive
+            .store(3, observed("old.test", "/", 200, "10.0.0.1", now))
+            .unwrap();
+        legacy.response_body = Some(StoredBody::Inline(Sealed {
+            nonce: STANDARD.encode([1; NONCE_LEN]),
+            data: STANDARD.encode(data),
+            truncated: false,
+        }));
+        let line = serde_json::to_string(&legacy).unwrap();
+        std::fs::write(archive.segment_path(3), format!("{line}\n")).unwrap();
+        drop(archive);
+        std::fs::write(dir.path().join("blobs").join("huerfano.tmp"), b"x").unwrap();
+
+        let archive = InterceptArchive::open(settings).unwrap();
+        assert_eq!(blobs().len(), 2, "orphans are collected on open");
+        assert_eq!(archive.stats().bodies.references, 3);
+        assert_eq!(response(archive.get(3).await.unwrap()).unwrap(), "antigua");
+
+        // A damaged blob is dropped, and the next copy of the body heals
+        // every exchange that pointed at it.
+        let shared = blobs()
+            .into_iter()
+            .max_by_key(|path| std::fs::metadata(path).unwrap().len())
+            .unwrap();
+        std::fs::write(&shared, b"basura").unwrap();
+        assert_eq!(response(archive.get(0).await.unwrap()), None);
+        assert_eq!(archive.stats().bodies.corrupt, 1);
+        archive.write(vec![with_body("misma respuesta")]).unwrap();
+        assert_eq!(
+            response(archive.get(0).await.unwrap()).unwrap(),
+            "misma respuesta"
+        );
+
+        // Pruning every segment releases every reference.
+        archive.prune(now + 30 * 24 * 3600 * 1000).unwrap();
+        assert!(blobs().is_empty());
+        let stats = archive.stats().bodies;
+        assert_eq!(
+            (stats.blobs, stats.references, stats.physical_bytes),
+            (0, 0, 0)
+        );
+    }
+}
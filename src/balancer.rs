@@ -0,0 +1,318 @@
+//! Reparto de las peticiones del router IA entre los backends de un modelo.
+//!
+//! Un modelo con varios backends en `[ai_router.models]` los usa según su
+//! estrategia: siempre el primero sano (`failover`, la de por defecto), por
+//! turnos (`round_robin`), el que tiene menos peticiones en curso
+//! (`least_active`) o por turnos en proporción a su peso (`weighted`). Una
+//! petición está en curso desde que sale hacia el backend hasta que su
+//! respuesta termina de llegar al cliente.
+//!
+//! Tras `unhealthy_after` fallos de conexión o respuestas 5xx seguidos, un
+//! backend deja de recibir peticiones. Una tarea en segundo plano espera
+//! `cooldown_secs` y prueba a conectar con él; si lo consigue vuelve al
+//! reparto a medio abrir: un solo fallo más lo aparta de nuevo. Si todos los
+//! backends de un modelo están apartados se usan igualmente, que un intento
+//! es mejor que un error seguro. Cada URL tiene un único estado aunque la
+//! usen varios modelos.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::Uri;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::settings::{AiHealthSettings, AiRouterSettings, BalanceStrategy, UpstreamPool};
+
+/// Tope de la espera de cada sonda.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Estado de un backend.
+pub struct Backend {
+    uri: Uri,
+    health: AiHealthSettings,
+    active: AtomicUsize,
+    failures: AtomicU32,
+    down: AtomicBool,
+}
+
+impl Backend {
+    fn new(uri: Uri, health: AiHealthSettings) -> Self {
+        Self {
+            uri,
+            health,
+            active: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Peticiones en curso.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        !self.down.load(Ordering::SeqCst)
+    }
+
+    /// Empieza una petición hacia el backend; termina al soltar el
+    /// [`Flight`].
+    pub fn start(self: &Arc<Self>) -> Flight {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Flight(self.clone())
+    }
+
+    /// Anota el resultado de una petición: `false` es un fallo de conexión
+    /// o un 5xx.
+    pub fn observe(self: &Arc<Self>, ok: bool) {
+        if ok {
+            self.failures.store(0, Ordering::SeqCst);
+            return;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= self.health.unhealthy_after() && !self.down.swap(true, Ordering::SeqCst) {
+            warn!(upstream = %self.uri, failures, "Backend de IA apartado del reparto");
+            tokio::spawn(self.clone().probe());
+        }
+    }
+
+    /// Prueba a conectar tras cada espera hasta que el backend responde.
+    async fn probe(self: Arc<Self>) {
+        let host = self.uri.host().unwrap_or_default().to_string();
+        let port = self.uri.port_u16().unwrap_or(match self.uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+        loop {
+            tokio::time::sleep(self.health.cooldown()).await;
+            let connect = TcpStream::connect((host.as_str(), port));
+            if let Ok(Ok(_)) = tokio::time::timeout(PROBE_TIMEOUT, connect).await {
+                break;
+            }
+        }
+        // Half-open: one more failure takes it out again.
+        self.failures
+            .store(self.health.unhealthy_after() - 1, Ordering::SeqCst);
+        self.down.store(false, Ordering::SeqCst);
+        info!(upstream = %self.uri, "Backend de IA de vuelta en el reparto");
+    }
+}
+
+/// Una petición en curso hacia un backend.
+pub struct Flight(Arc<Backend>);
+
+impl Flight {
+    pub fn backend(&self) -> &Arc<Backend> {
+        &self.0
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Pool {
+    strategy: BalanceStrategy,
+    members: Vec<(Arc<Backend>, u32)>,
+    /// Turno de `round_robin` y `weighted`.
+    turn: AtomicUsize,
+}
+
+impl Pool {
+    fn new(pool: &UpstreamPool, backend: &mut impl FnMut(&Uri) -> Arc<Backend>) -> Self {
+        Self {
+            strategy: pool.strategy(),
+            members: pool
+                .upstreams()
+                .iter()
+                .map(|(uri, weight)| (backend(uri), *weight))
+                .collect(),
+            turn: AtomicUsize::new(0),
+        }
+    }
+
+    /// Los backends en el orden en que probarlos: el elegido primero y los
+    /// demás sanos detrás, como reserva.
+    fn order(&self) -> Vec<Arc<Backend>> {
+        let mut members: Vec<&(Arc<Backend>, u32)> = self
+            .members
+            .iter()
+            .filter(|(backend, _)| backend.is_healthy())
+            .collect();
+        if members.is_empty() {
+            members = self.members.iter().collect();
+        }
+        let first = match self.strategy {
+            BalanceStrategy::Failover => 0,
+            BalanceStrategy::RoundRobin => {
+                self.turn.fetch_add(1, Ordering::Relaxed) % members.len()
+            }
+            BalanceStrategy::LeastActive => {
+                // Ties go round, so idle backends share the load.
+                let start = self.turn.fetch_add(1, Ordering::Relaxed) % members.len();
+                (0..members.len())
+                    .map(|i| (start + i) % members.len())
+                    .min_by_key(|&i| members[i].0.active())
+                    .unwrap_or(0)
+            }
+            BalanceStrategy::Weighted => {
+                let total: usize = members.iter().map(|(_, weight)| *weight as usize).sum();
+                let mut point = self.turn.fetch_add(1, Ordering::Relaxed) % total;
+                members
+                    .iter()
+                    .position(|(_, weight)| match point.checked_sub(*weight as usize) {
+                        Some(rest) => {
+                            point = rest;
+                            false
+                        }
+                        None => true,
+                    })
+                    .unwrap_or(0)
+            }
+        };
+        let chosen = members.remove(first);
+        std::iter::once(chosen)
+            .chain(members)
+            .map(|(backend, _)| backend.clone())
+            .collect()
+    }
+}
+
+/// Backends de todos los modelos del router.
+pub struct Balancer {
+    models: HashMap<String, Pool>,
+    default: Pool,
+}
+
+impl Balancer {
+    pub fn new(settings: &AiRouterSettings) -> Self {
+        let mut backends: HashMap<Uri, Arc<Backend>> = HashMap::new();
+        let mut backend = |uri: &Uri| {
+            backends
+                .entry(uri.clone())
+                .or_insert_with(|| Arc::new(Backend::new(uri.clone(), settings.health())))
+                .clone()
+        };
+        let default = Pool {
+            strategy: BalanceStrategy::Failover,
+            members: vec![(backend(settings.default_upstream()), 1)],
+            turn: AtomicUsize::new(0),
+        };
+        let models = settings
+            .models()
+            .iter()
+            .map(|(model, pool)| (model.clone(), Pool::new(pool, &mut backend)))
+            .collect();
+        Self { models, default }
+    }
+
+    /// Backends para una petición de `model` (el nombre configurado), o de
+    /// `default_upstream` si no tiene; el primero es el elegido.
+    pub fn order(&self, model: Option<&str>) -> Vec<Arc<Backend>> {
+        model
+            .and_then(|model| self.models.get(model))
+            .unwrap_or(&self.default)
+            .order()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn uris(order: Vec<Arc<Backend>>) -> Vec<String> {
+        order
+            .iter()
+            .map(|backend| backend.uri().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_strategies_pick_among_healthy_backends() {
+        let a: Uri = "http://a.test/".parse().unwrap();
+        let b: Uri = "http://b.test/".parse().unwrap();
+        let settings = AiRouterSettings::new(a.clone())
+            .with_model("rr", a.clone())
+            .with_model("rr", b.clone())
+            .with_balance("rr", BalanceStrategy::RoundRobin)
+            .with_weighted_model("w", a.clone(), 3)
+            .with_weighted_model("w", b.clone(), 1)
+            .with_balance("w", BalanceStrategy::Weighted)
+            .with_model("least", a.clone())
+            .with_model("least", b.clone())
+            .with_balance("least", BalanceStrategy::LeastActive)
+            .with_health(AiHealthSettings::default().with_cooldown(Duration::from_secs(3600)));
+        let balancer = Balancer::new(&settings);
+        let first = |model| uris(balancer.order(Some(model)))[0].clone();
+
+        let rr: Vec<_> = (0..4).map(|_| first("rr")).collect();
+        assert_eq!(rr, ["http://a.test/", "http://b.test/"].repeat(2));
+        let w: Vec<_> = (0..4).map(|_| first("w")).collect();
+        assert_eq!(
+            w,
+            [
+                "http://a.test/",
+                "http://a.test/",
+                "http://a.test/",
+                "http://b.test/"
+            ]
+        );
+        assert_eq!(
+            uris(balancer.order(Some("rr"))),
+            ["http://a.test/", "http://b.test/"],
+            "the rest follow as spares"
+        );
+
+        // `a` is shared by every pool: one busy request is seen by all.
+        let busy = balancer.order(Some("least"))[0].start();
+        assert_eq!(busy.backend().uri(), &a);
+        assert_eq!(first("least"), "http://b.test/");
+        assert_eq!(first("least"), "http://b.test/");
+        drop(busy);
+
+        // Three failures in a row take `a` out; a success resets the count.
+        let a_backend = balancer.order(None)[0].clone();
+        a_backend.observe(false);
+        a_backend.observe(true);
+        a_backend.observe(false);
+        a_backend.observe(false);
+        assert!(a_backend.is_healthy());
+        a_backend.observe(false);
+        assert!(!a_backend.is_healthy());
+        assert_eq!(uris(balancer.order(Some("w"))), ["http://b.test/"]);
+        assert_eq!(first("rr"), "http://b.test/");
+        // With nobody healthy left, the default still gets its requests.
+        assert_eq!(uris(balancer.order(None)), ["http://a.test/"]);
+    }
+
+    #[tokio::test]
+    async fn test_probe_brings_a_backend_back_half_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri: Uri = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let health = AiHealthSettings::default()
+            .with_unhealthy_after(2)
+            .with_cooldown(Duration::from_millis(20));
+        let backend = Arc::new(Backend::new(uri, health));
+        backend.observe(false);
+        backend.observe(false);
+        assert!(!backend.is_healthy());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(backend.is_healthy(), "the probe connected");
+        backend.observe(false);
+        assert!(!backend.is_healthy(), "half-open: one failure is enough");
+        drop(listener);
+    }
+}
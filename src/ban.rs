@@ -0,0 +1,314 @@
+//! Baneo temporal de clientes abusivos.
+//!
+//! Cada infracción de un cliente se anota con su motivo; si acumula
+//! `threshold` dentro de la ventana, su IP entra en la lista de baneos y las
+//! conexiones nuevas se cortan antes de leer nada. Un cliente que vuelve a
+//! caer tras un baneo recibe uno del doble de duración. Los baneos se pueden
+//! revocar desde la API de administración y, con `path`, se guardan en disco
+//! para sobrevivir a los reinicios.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::settings::BanSettings;
+
+/// Clientes con infracciones recientes que se siguen como mucho; al
+/// superarlo se olvidan los que ya salieron de la ventana.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Infracción que cuenta para el baneo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    AuthFailure,
+    BlockedDestination,
+    Smuggling,
+    RateLimit,
+}
+
+impl Violation {
+    pub const ALL: [Violation; 4] = [
+        Violation::AuthFailure,
+        Violation::BlockedDestination,
+        Violation::Smuggling,
+        Violation::RateLimit,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Violation::AuthFailure => "auth_failure",
+            Violation::BlockedDestination => "blocked_destination",
+            Violation::Smuggling => "smuggling",
+            Violation::RateLimit => "rate_limit",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Baneo de una IP; también se conserva tras expirar para escalar el
+/// siguiente.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    /// Segundos Unix en que termina.
+    pub until: u64,
+    /// Baneos acumulados por esta IP, incluido el actual.
+    pub strikes: u32,
+    /// Infracciones que dispararon el último baneo.
+    pub reasons: Vec<String>,
+}
+
+/// Baneo activo tal como lo muestra la API de administración.
+#[derive(Debug, Clone, Serialize)]
+pub struct BanView {
+    pub ip: IpAddr,
+    pub remaining_secs: u64,
+    pub strikes: u32,
+    pub reasons: Vec<String>,
+}
+
+#[derive(Default)]
+struct State {
+    offenses: HashMap<IpAddr, VecDeque<(Instant, Violation)>>,
+    bans: HashMap<IpAddr, Ban>,
+}
+
+pub struct BanList {
+    settings: BanSettings,
+    state: Mutex<State>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl BanList {
+    /// Carga los baneos guardados si hay `path` y el archivo existe.
+    pub fn new(settings: BanSettings) -> anyhow::Result<Self> {
+        let mut state = State::default();
+        if let Some(path) = settings.path() {
+            match std::fs::read(path) {
+                Ok(bytes) => {
+                    state.bans = serde_json::from_slice(&bytes)
+                        .with_context(|| format!("Baneos corruptos en {}", path.display()))?;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("No se pudo leer {}", path.display()))
+                }
+            }
+        }
+        Ok(Self {
+            settings,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn settings(&self) -> &BanSettings {
+        &self.settings
+    }
+
+    fn trusted(&self, ip: IpAddr) -> bool {
+        self.settings
+            .trusted()
+            .iter()
+            .any(|range| range.contains(ip))
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let state = self.state.lock().expect("lista de baneos");
+        state
+            .bans
+            .get(&ip)
+            .is_some_and(|ban| ban.until > now_secs())
+    }
+
+    /// Anota una infracción de `ip`. Devuelve la duración del baneo si esta
+    /// infracción lo dispara.
+    pub fn record(&self, ip: IpAddr, violation: Violation) -> Option<Duration> {
+        if self.trusted(ip) {
+            return None;
+        }
+        let (duration, ban) = {
+            let mut state = self.state.lock().expect("lista de baneos");
+            let now = Instant::now();
+            let window = self.settings.window();
+            if state.offenses.len() >= MAX_TRACKED_CLIENTS {
+                state.offenses.retain(|_, offenses| {
+                    offenses
+                        .back()
+                        .is_some_and(|(at, _)| now.duration_since(*at) < window)
+                });
+            }
+            let offenses = state.offenses.entry(ip).or_default();
+            offenses.push_back((now, violation));
+            while offenses
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) >= window)
+            {
+                offenses.pop_front();
+            }
+            if offenses.len() < self.settings.threshold() {
+                return None;
+            }
+            let mut reasons: Vec<String> = Vec::new();
+            for (_, violation) in offenses.drain(..) {
+                let reason = violation.as_str().to_string();
+                if !reasons.contains(&reason) {
+                    reasons.push(reason);
+                }
+            }
+            state.offenses.remove(&ip);
+
+            let strikes = state.bans.get(&ip).map_or(0, |ban| ban.strikes) + 1;
+            let factor = 2u32.saturating_pow(strikes - 1);
+            let duration = self
+                .settings
+                .duration()
+                .saturating_mul(factor)
+                .min(self.settings.max_duration());
+            let ban = Ban {
+                until: now_secs() + duration.as_secs(),
+                strikes,
+                reasons,
+            };
+            state.bans.insert(ip, ban.clone());
+            (duration, ban)
+        };
+        warn!(
+            %ip,
+            secs = duration.as_secs(),
+            strikes = ban.strikes,
+            reasons = ?ban.reasons,
+            "Cliente baneado"
+        );
+        self.persist();
+        Some(duration)
+    }
+
+    /// Levanta el baneo de `ip` y olvida su historial.
+    pub fn revoke(&self, ip: IpAddr) -> bool {
+        let removed = {
+            let mut state = self.state.lock().expect("lista de baneos");
+            state.offenses.remove(&ip);
+            state.bans.remove(&ip).is_some()
+        };
+        if removed {
+            info!(%ip, "Baneo revocado");
+            self.persist();
+        }
+        removed
+    }
+
+    /// Baneos activos, del que más tiempo le queda al que menos.
+    pub fn active(&self) -> Vec<BanView> {
+        let now = now_secs();
+        let state = self.state.lock().expect("lista de baneos");
+        let mut bans: Vec<BanView> = state
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.until > now)
+            .map(|(ip, ban)| BanView {
+                ip: *ip,
+                remaining_secs: ban.until - now,
+                strikes: ban.strikes,
+                reasons: ban.reasons.clone(),
+            })
+            .collect();
+        bans.sort_by_key(|ban| std::cmp::Reverse(ban.remaining_secs));
+        bans
+    }
+
+    /// Guarda los baneos; los expirados hace más de `max_duration` se
+    /// olvidan, así que su próximo baneo vuelve a ser el más corto.
+    fn persist(&self) {
+        let Some(path) = self.settings.path() else {
+            return;
+        };
+        let bytes = {
+            let mut state = self.state.lock().expect("lista de baneos");
+            let forget_before = now_secs().saturating_sub(self.settings.max_duration().as_secs());
+            state.bans.retain(|_, ban| ban.until > forget_before);
+            serde_json::to_vec(&state.bans)
+        };
+        let tmp = path.with_extension("tmp");
+        let written = bytes
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(std::fs::write(&tmp, bytes)?))
+            .and_then(|()| Ok(std::fs::rename(&tmp, path)?));
+        if let Err(e) = written {
+            warn!(path = %path.display(), error = %e, "No se pudieron guardar los baneos");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> BanSettings {
+        BanSettings::default()
+            .with_threshold(3)
+            .with_duration(Duration::from_secs(60))
+            .with_trusted("10.0.0.0/8".parse().unwrap())
+    }
+
+    fn offend(bans: &BanList, ip: IpAddr, times: usize) -> Option<Duration> {
+        (0..times)
+            .map(|_| bans.record(ip, Violation::BlockedDestination))
+            .last()
+            .flatten()
+    }
+
+    #[test]
+    fn test_threshold_bans_and_escalates() {
+        let bans = BanList::new(settings()).unwrap();
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        assert_eq!(bans.record(ip, Violation::Smuggling), None);
+        assert_eq!(offend(&bans, ip, 1), None);
+        assert!(!bans.is_banned(ip));
+        assert_eq!(offend(&bans, ip, 1), Some(Duration::from_secs(60)));
+        assert!(bans.is_banned(ip));
+        let view = &bans.active()[0];
+        assert_eq!(view.reasons, ["smuggling", "blocked_destination"]);
+
+        // The next ban for the same client lasts twice as long.
+        assert_eq!(offend(&bans, ip, 3), Some(Duration::from_secs(120)));
+        assert_eq!(bans.active()[0].strikes, 2);
+
+        let trusted: IpAddr = "10.1.2.3".parse().unwrap();
+        assert_eq!(offend(&bans, trusted, 10), None);
+        assert!(!bans.is_banned(trusted));
+    }
+
+    #[test]
+    fn test_revoke_and_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+        let ip: IpAddr = "192.0.2.8".parse().unwrap();
+        let other: IpAddr = "192.0.2.9".parse().unwrap();
+        {
+            let bans = BanList::new(settings().with_path(&path)).unwrap();
+            offend(&bans, ip, 3);
+            offend(&bans, other, 3);
+            assert!(bans.revoke(other));
+            assert!(!bans.revoke(other));
+        }
+        let reloaded = BanList::new(settings().with_path(&path)).unwrap();
+        assert!(reloaded.is_banned(ip));
+        assert!(!reloaded.is_banned(other));
+        // Revoking forgets the history, so the next ban starts short again.
+        assert!(reloaded.revoke(ip));
+        assert_eq!(offend(&reloaded, ip, 3), Some(Duration::from_secs(60)));
+    }
+}
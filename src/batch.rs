@@ -0,0 +1,233 @@
+//! Lotes de líneas para los sinks de logs (log de acceso y flujo de eventos).
+//!
+//! Quien registra deja la línea en el buffer de su hilo: cada worker de
+//! tokio tiene el suyo, así que el camino de las peticiones no compite con
+//! los demás workers ni hace un envío por canal por línea. El consumidor
+//! recoge todos los buffers de una vez cuando se junta un lote o vence su
+//! intervalo y escribe el lote con E/S vectorizada ([`write_lines`]).
+//!
+//! Las líneas salen en el orden en que se registraron aunque la tarea que
+//! las produjo cambie de worker: cada una lleva un número que se toma con el
+//! buffer bloqueado, y una recogida solo entrega las anteriores a la marca
+//! leída antes de recorrer los buffers (las demás esperan a la siguiente).
+//!
+//! Si lo pendiente llega a la capacidad, las líneas nuevas se descartan y se
+//! cuentan: registrar nunca espera.
+
+use std::io::{self, IoSlice, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Buffers por cola; los hilos se reparten entre ellos por orden de llegada.
+const SHARDS: usize = 16;
+
+/// Trozos por llamada de E/S vectorizada (`IOV_MAX` en Linux es 1024).
+const IOV_MAX: usize = 1024;
+
+type Shard = Mutex<Vec<(u64, Arc<str>)>>;
+
+/// Líneas en espera de su consumidor, repartidas en buffers por hilo.
+pub struct Batcher {
+    shards: Box<[Shard]>,
+    /// Lo que una recogida no pudo entregar todavía, ya ordenado.
+    carry: Mutex<Vec<(u64, Arc<str>)>>,
+    next_seq: AtomicU64,
+    pending: AtomicUsize,
+    capacity: usize,
+    batch: usize,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+    wake: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Batcher {
+    /// Cola de hasta `capacity` líneas que llama a `wake` cuando se junta un
+    /// lote de `batch`.
+    pub fn new(capacity: usize, batch: usize, wake: impl Fn() + Send + Sync + 'static) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            carry: Mutex::default(),
+            next_seq: AtomicU64::new(0),
+            pending: AtomicUsize::new(0),
+            capacity: capacity.max(1),
+            batch: batch.clamp(1, capacity.max(1)),
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            wake: Box::new(wake),
+        }
+    }
+
+    /// Deja `line` en el buffer de este hilo. Devuelve `false` si no cabía o
+    /// el consumidor se fue: la línea se descarta y se cuenta.
+    pub fn push(&self, line: Arc<str>) -> bool {
+        if self.is_closed() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let before = self.pending.fetch_add(1, Ordering::AcqRel);
+        if before >= self.capacity {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        {
+            let mut shard = self.shards[shard_index()].lock().expect("lock del lote");
+            // Numbered while holding the shard, see `take`.
+            let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+            shard.push((seq, line));
+        }
+        if before + 1 == self.batch {
+            (self.wake)();
+        }
+        true
+    }
+
+    /// Recoge en orden lo registrado hasta ahora. Pensado para un único
+    /// consumidor.
+    pub fn take(&self) -> Vec<Arc<str>> {
+        // Every line numbered below the mark was pushed while its shard was
+        // locked, so locking each shard after reading the mark finds it.
+        let mark = self.next_seq.load(Ordering::SeqCst);
+        let mut carry = self.carry.lock().expect("lock del lote");
+        let mut lines = std::mem::take(&mut *carry);
+        for shard in self.shards.iter() {
+            lines.append(&mut shard.lock().expect("lock del lote"));
+        }
+        lines.sort_unstable_by_key(|(seq, _)| *seq);
+        let ready = lines.partition_point(|(seq, _)| *seq < mark);
+        *carry = lines.split_off(ready);
+        self.pending.fetch_sub(lines.len(), Ordering::AcqRel);
+        lines.into_iter().map(|(_, line)| line).collect()
+    }
+
+    /// Líneas registradas que el consumidor aún no recogió.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// El consumidor se fue: lo que llegue a partir de ahora se descarta.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        (self.wake)();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// Buffer del hilo actual: cada hilo se queda con uno al registrar por
+/// primera vez.
+fn shard_index() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: usize = NEXT.fetch_add(1, Ordering::Relaxed) % SHARDS;
+    }
+    SHARD.with(|shard| *shard)
+}
+
+/// Escribe `lines` con tantas llamadas vectorizadas como hagan falta.
+pub fn write_lines(out: &mut impl Write, lines: &[Arc<str>]) -> io::Result<()> {
+    for chunk in lines.chunks(IOV_MAX) {
+        let mut slices: Vec<IoSlice<'_>> =
+            chunk.iter().map(|l| IoSlice::new(l.as_bytes())).collect();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            match out.write_vectored(slices) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// [`write_lines`] sobre un socket asíncrono.
+pub async fn write_lines_async(
+    out: &mut (impl AsyncWrite + Unpin),
+    lines: &[Arc<str>],
+) -> io::Result<()> {
+    for chunk in lines.chunks(IOV_MAX) {
+        let mut slices: Vec<IoSlice<'_>> =
+            chunk.iter().map(|l| IoSlice::new(l.as_bytes())).collect();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            match out.write_vectored(slices).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => IoSlice::advance_slices(&mut slices, n),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(n: usize) -> Arc<str> {
+        format!("{n}\n").into()
+    }
+
+    #[test]
+    fn test_full_queue_drops_and_counts_then_keeps_order_across_threads() {
+        let woken = Arc::new(AtomicUsize::new(0));
+        let counter = woken.clone();
+        let batcher = Batcher::new(4, 3, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert!((0..6)
+            .map(|n| batcher.push(line(n)))
+            .eq([true, true, true, true, false, false]));
+        assert_eq!((batcher.pending(), batcher.dropped()), (4, 2));
+        assert_eq!(woken.load(Ordering::Relaxed), 1, "one wake per batch");
+        assert_eq!(batcher.take(), [line(0), line(1), line(2), line(3)]);
+        assert_eq!(batcher.pending(), 0);
+
+        // Lines from several threads come out in the order they were pushed.
+        let batcher = Arc::new(Batcher::new(10_000, 64, || {}));
+        let mut next = 0;
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let batcher = batcher.clone();
+                let n = next;
+                next += 100;
+                scope.spawn(move || {
+                    for i in n..n + 100 {
+                        batcher.push(line(i));
+                    }
+                });
+            }
+        });
+        let seen: Vec<usize> = batcher
+            .take()
+            .iter()
+            .map(|l| l.trim().parse().unwrap())
+            .collect();
+        assert_eq!(seen.len(), 400);
+        for thread in 0..4 {
+            let own: Vec<_> = seen.iter().filter(|n| *n / 100 == thread).collect();
+            assert!(own.windows(2).all(|w| w[0] < w[1]), "{own:?}");
+        }
+
+        batcher.close();
+        assert!(!batcher.push(line(0)));
+        assert_eq!(batcher.dropped(), 1);
+    }
+
+    #[test]
+    fn test_write_lines_splits_past_iov_max() {
+        let lines: Vec<Arc<str>> = (0..IOV_MAX * 2 + 7).map(line).collect();
+        let mut out = Vec::new();
+        write_lines(&mut out, &lines).unwrap();
+        assert_eq!(out, lines.concat().into_bytes());
+    }
+}
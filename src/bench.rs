@@ -0,0 +1,803 @@
+//! Banco de carga y prueba de resistencia (`proxy-ia bench`).
+//!
+//! Antes de poner un despliegue en producción conviene saber cuánto aguanta.
+//! El banco mantiene `connections` conexiones con el proxy durante
+//! `duration` y mide cada petición: en modo `http`, peticiones seguidas en
+//! conexiones persistentes; en `connect`, un túnel nuevo por petición; en
+//! `mixed`, la mitad de las conexiones de cada tipo. Con el destino `self`
+//! las respuestas salen de un origen local que levanta el propio banco.
+//!
+//! La carga sale de un runtime con sus propios hilos ([`LoadGenerator`]):
+//! con el proxy en el mismo proceso, no le quita los suyos. Las latencias
+//! van a un [`Histogram`] de precisión relativa fija, que ocupa lo mismo tras
+//! horas de prueba que tras un minuto. Si el proxy tiene API de
+//! administración, `GET /api/stats` se muestrea al empezar, en cada punto de
+//! control y al terminar.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use hyper::body::Bytes;
+use hyper::client::conn::SendRequest;
+use hyper::header::{AUTHORIZATION, CONNECTION, HOST};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+use crate::settings::{AdminToken, BenchMode, BenchSettings, BenchTarget};
+
+/// Tiempo máximo de una petición, túnel incluido.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tiempo máximo de una consulta a la API de administración.
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Espera tras un fallo de conexión, para no girar en vacío.
+const FAILURE_PAUSE: Duration = Duration::from_millis(10);
+
+/// Mayor cabecera de respuesta a un `CONNECT` que se lee.
+const MAX_CONNECT_HEAD: usize = 8 * 1024;
+
+/// Subdivisiones de cada potencia de dos: error relativo por debajo del 1 %.
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// `500ms`, `60s`, `5m` o `2h`; un número sin unidad son segundos.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("duración no válida: {value}"))?;
+    let unit = match unit {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("unidad de duración desconocida: {value}")),
+    };
+    Ok(Duration::from_secs(number.saturating_mul(unit)))
+}
+
+/// Histograma de latencias en microsegundos, por cubos de ancho
+/// proporcional al valor.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum_micros: u128,
+    max_micros: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let index = bucket(micros);
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.count += 1;
+        self.sum_micros += u128::from(micros);
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum_micros += other.sum_micros;
+        self.max_micros = self.max_micros.max(other.max_micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_micros((self.sum_micros / u128::from(count)) as u64),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_micros)
+    }
+
+    /// Latencia que no supera la fracción `q` de las muestras.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(bucket_upper(index).min(self.max_micros));
+            }
+        }
+        self.max()
+    }
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let shift = 63 - micros.leading_zeros() - SUB_BUCKET_BITS;
+    ((u64::from(shift) + 1) * SUB_BUCKETS + (micros >> shift) - SUB_BUCKETS) as usize
+}
+
+/// Mayor valor que cae en el cubo `index`.
+fn bucket_upper(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let sub = u128::from(index % SUB_BUCKETS + SUB_BUCKETS);
+    u64::try_from(((sub + 1) << shift) - 1).unwrap_or(u64::MAX)
+}
+
+/// Lo medido en un intervalo, o en toda la prueba.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    bytes: u64,
+    errors: BTreeMap<String, u64>,
+    latency: Histogram,
+}
+
+impl Stats {
+    pub fn record_ok(&mut self, latency: Duration, bytes: u64) {
+        self.latency.record(latency);
+        self.bytes += bytes;
+    }
+
+    pub fn record_error(&mut self, kind: &str) {
+        *self.errors.entry(kind.to_string()).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        self.bytes += other.bytes;
+        for (kind, count) in &other.errors {
+            *self.errors.entry(kind.clone()).or_default() += count;
+        }
+        self.latency.merge(&other.latency);
+    }
+
+    pub fn summary(&self, elapsed: Duration) -> Summary {
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        Summary {
+            elapsed_secs: elapsed.as_secs_f64(),
+            ok: self.latency.count(),
+            failed: self.errors.values().sum(),
+            errors: self.errors.clone(),
+            requests_per_sec: self.latency.count() as f64 / secs,
+            bytes_per_sec: self.bytes as f64 / secs,
+            latency_ms: Latencies {
+                mean: ms(self.latency.mean()),
+                p50: ms(self.latency.quantile(0.5)),
+                p90: ms(self.latency.quantile(0.9)),
+                p99: ms(self.latency.quantile(0.99)),
+                p999: ms(self.latency.quantile(0.999)),
+                max: ms(self.latency.max()),
+            },
+        }
+    }
+}
+
+/// Resumen de unas [`Stats`]; las latencias son de las peticiones correctas.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub elapsed_secs: f64,
+    pub ok: u64,
+    pub failed: u64,
+    /// Fallos por tipo: `connect`, `io`, `timeout`, `status_<código>` o
+    /// `tunnel_<código>`.
+    pub errors: BTreeMap<String, u64>,
+    pub requests_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub latency_ms: Latencies,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Latencies {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub p999: f64,
+    pub max: f64,
+}
+
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "media {:.2} ms, p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, p99.9 {:.2} ms, máx {:.2} ms",
+            self.mean, self.p50, self.p90, self.p99, self.p999, self.max
+        )
+    }
+}
+
+/// Lo que dice `GET /api/stats` de los recursos del proxy.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProxySample {
+    /// Descriptores abiertos del proceso del proxy; con el proxy en el mismo
+    /// proceso incluye los del banco.
+    pub open_fds: Option<u64>,
+    pub buffered_bytes: u64,
+    pub buffered_high_water_bytes: u64,
+    pub accept_rejected: u64,
+    pub accept_fd_exhausted: u64,
+}
+
+impl ProxySample {
+    pub fn from_stats(stats: &serde_json::Value) -> Self {
+        let number = |pointer: &str| stats.pointer(pointer).and_then(|v| v.as_u64());
+        Self {
+            open_fds: number("/resources/open_fds"),
+            buffered_bytes: number("/buffered/bytes").unwrap_or_default(),
+            buffered_high_water_bytes: number("/buffered/high_water_bytes").unwrap_or_default(),
+            accept_rejected: number("/accept/rate_limited").unwrap_or_default(),
+            accept_fd_exhausted: number("/accept/fd_exhausted").unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for ProxySample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.open_fds {
+            Some(fds) => write!(f, "{fds} descriptores, ")?,
+            None => write!(f, "descriptores desconocidos, ")?,
+        }
+        write!(
+            f,
+            "{} KiB en buffers (máximo {} KiB), {} aceptaciones rechazadas, {} sin descriptores",
+            self.buffered_bytes / 1024,
+            self.buffered_high_water_bytes / 1024,
+            self.accept_rejected,
+            self.accept_fd_exhausted
+        )
+    }
+}
+
+/// Un punto de control: lo medido desde el anterior.
+#[derive(Debug, Clone, Serialize)]
+pub struct Checkpoint {
+    pub at_secs: f64,
+    pub window: Summary,
+    pub proxy: Option<ProxySample>,
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:>6.0} s] {} correctas, {} fallidas, {:.1} pet/s, p50 {:.2} ms, p99 {:.2} ms",
+            self.at_secs,
+            self.window.ok,
+            self.window.failed,
+            self.window.requests_per_sec,
+            self.window.latency_ms.p50,
+            self.window.latency_ms.p99
+        )?;
+        if let Some(proxy) = &self.proxy {
+            write!(f, "; proxy: {proxy}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub mode: &'static str,
+    pub connections: usize,
+    pub proxy: String,
+    pub target: String,
+    pub total: Summary,
+    pub proxy_before: Option<ProxySample>,
+    pub proxy_after: Option<ProxySample>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Carga {} con {} conexiones a través de {} hacia {} ({:.0} s)",
+            self.mode, self.connections, self.proxy, self.target, self.total.elapsed_secs
+        )?;
+        write!(
+            f,
+            "Peticiones: {} correctas, {} fallidas",
+            self.total.ok, self.total.failed
+        )?;
+        if !self.total.errors.is_empty() {
+            let errors: Vec<_> = self
+                .total
+                .errors
+                .iter()
+                .map(|(kind, count)| format!("{kind} {count}"))
+                .collect();
+            write!(f, " ({})", errors.join(", "))?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Rendimiento: {:.1} pet/s, {:.1} KiB/s",
+            self.total.requests_per_sec,
+            self.total.bytes_per_sec / 1024.0
+        )?;
+        write!(f, "Latencia: {}", self.total.latency_ms)?;
+        if let Some(before) = &self.proxy_before {
+            write!(f, "\nProxy al empezar: {before}")?;
+        }
+        if let Some(after) = &self.proxy_after {
+            write!(f, "\nProxy al terminar: {after}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runtime propio de la carga y, con el destino `self`, el origen interno.
+pub struct LoadGenerator {
+    runtime: Runtime,
+    settings: BenchSettings,
+    origin: String,
+}
+
+impl LoadGenerator {
+    pub fn new(settings: BenchSettings) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(settings.load_threads())
+            .thread_name("bench-load")
+            .enable_all()
+            .build()
+            .context("No se pudo crear el runtime de la carga")?;
+        let origin = match settings.target() {
+            BenchTarget::Internal => {
+                let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+                listener.set_nonblocking(true)?;
+                let addr = listener.local_addr()?;
+                let body = Bytes::from(vec![b'x'; settings.body_bytes()]);
+                runtime.spawn(serve_origin(listener, body));
+                addr.to_string()
+            }
+            BenchTarget::Origin(authority) => authority.clone(),
+        };
+        Ok(Self {
+            runtime,
+            settings,
+            origin,
+        })
+    }
+
+    /// `host:puerto` al que van las peticiones.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn origin_port(&self) -> Option<u16> {
+        self.origin.rsplit_once(':')?.1.parse().ok()
+    }
+
+    /// Genera la carga a través de `proxy` y muestrea `admin`;
+    /// `on_checkpoint` recibe cada punto de control según se produce.
+    pub async fn run(
+        self,
+        proxy: SocketAddr,
+        admin: Option<SocketAddr>,
+        on_checkpoint: impl Fn(&Checkpoint) + Send + 'static,
+    ) -> anyhow::Result<BenchReport> {
+        let LoadGenerator {
+            runtime,
+            settings,
+            origin,
+        } = self;
+        let task = runtime.spawn(drive(settings, origin, proxy, admin, on_checkpoint));
+        let report = task.await;
+        runtime.shutdown_background();
+        Ok(report?)
+    }
+}
+
+async fn serve_origin(listener: std::net::TcpListener, body: Bytes) {
+    let Ok(listener) = TcpListener::from_std(listener) else {
+        return;
+    };
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => {
+                tokio::time::sleep(FAILURE_PAUSE).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let body = body.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |_req| {
+                let body = body.clone();
+                async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+            });
+            let _ = Http::new().serve_connection(stream, service).await;
+        });
+    }
+}
+
+async fn drive(
+    settings: BenchSettings,
+    origin: String,
+    proxy: SocketAddr,
+    admin: Option<SocketAddr>,
+    on_checkpoint: impl Fn(&Checkpoint),
+) -> BenchReport {
+    let token = settings.admin_token();
+    let proxy_before = sample(admin, token).await;
+    let start = Instant::now();
+    let deadline = start + settings.duration();
+    let windows: Vec<Arc<Mutex<Stats>>> = (0..settings.connections())
+        .map(|_| Arc::default())
+        .collect();
+    let workers: Vec<_> = windows
+        .iter()
+        .enumerate()
+        .map(|(index, window)| {
+            let worker = Worker {
+                proxy,
+                origin: origin.clone(),
+                deadline,
+                window: window.clone(),
+            };
+            let tunnels = match settings.mode() {
+                BenchMode::Http => false,
+                BenchMode::Connect => true,
+                BenchMode::Mixed => index % 2 == 1,
+            };
+            tokio::spawn(worker.run(tunnels))
+        })
+        .collect();
+
+    let mut total = Stats::default();
+    let mut checkpoints = Vec::new();
+    if let Some(every) = settings.checkpoint() {
+        let mut last = start;
+        while last + every < deadline {
+            tokio::time::sleep_until((last + every).into()).await;
+            let window = collect(&windows);
+            total.merge(&window);
+            let checkpoint = Checkpoint {
+                at_secs: start.elapsed().as_secs_f64(),
+                window: window.summary(last.elapsed()),
+                proxy: sample(admin, token).await,
+            };
+            on_checkpoint(&checkpoint);
+            checkpoints.push(checkpoint);
+            last = Instant::now();
+        }
+    }
+    for worker in workers {
+        let _ = worker.await;
+    }
+    let elapsed = start.elapsed();
+    total.merge(&collect(&windows));
+
+    BenchReport {
+        mode: settings.mode().as_str(),
+        connections: settings.connections(),
+        proxy: proxy.to_string(),
+        target: origin,
+        total: total.summary(elapsed),
+        proxy_before,
+        proxy_after: sample(admin, token).await,
+        checkpoints,
+    }
+}
+
+/// Vacía la ventana de cada conexión y las suma.
+fn collect(windows: &[Arc<Mutex<Stats>>]) -> Stats {
+    let mut merged = Stats::default();
+    for window in windows {
+        let taken = std::mem::take(&mut *window.lock().unwrap());
+        merged.merge(&taken);
+    }
+    merged
+}
+
+async fn sample(admin: Option<SocketAddr>, token: Option<&AdminToken>) -> Option<ProxySample> {
+    let uri = format!("http://{}/api/stats", admin?);
+    let mut request = Request::get(uri);
+    if let Some(token) = token {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token.expose()));
+    }
+    let request = request.body(Body::empty()).ok()?;
+    let response = tokio::time::timeout(SAMPLE_TIMEOUT, Client::new().request(request))
+        .await
+        .ok()?
+        .ok()?;
+    if response.status() != StatusCode::OK {
+        return None;
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await.ok()?;
+    let stats: serde_json::Value = serde_json::from_slice(&body).ok()?;
+    Some(ProxySample::from_stats(&stats))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// No se pudo conectar con el proxy.
+    Connect,
+    /// La conexión falló o se cerró a medias.
+    Io,
+    Timeout,
+    /// Respuesta que no es 2xx.
+    Status(u16),
+    /// El proxy no abrió el túnel.
+    Tunnel(u16),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Connect => write!(f, "connect"),
+            Failure::Io => write!(f, "io"),
+            Failure::Timeout => write!(f, "timeout"),
+            Failure::Status(status) => write!(f, "status_{status}"),
+            Failure::Tunnel(status) => write!(f, "tunnel_{status}"),
+        }
+    }
+}
+
+/// Una de las conexiones de la carga.
+struct Worker {
+    proxy: SocketAddr,
+    origin: String,
+    deadline: Instant,
+    window: Arc<Mutex<Stats>>,
+}
+
+impl Worker {
+    async fn run(self, tunnels: bool) {
+        let mut sender: Option<SendRequest<Body>> = None;
+        while Instant::now() < self.deadline {
+            let result = if tunnels {
+                let started = Instant::now();
+                let result = tokio::time::timeout(REQUEST_TIMEOUT, self.tunnel()).await;
+                result.map(|result| result.map(|bytes| (bytes, started)))
+            } else {
+                tokio::time::timeout(REQUEST_TIMEOUT, self.request(&mut sender)).await
+            };
+            match result.unwrap_or(Err(Failure::Timeout)) {
+                Ok((bytes, started)) => self
+                    .window
+                    .lock()
+                    .unwrap()
+                    .record_ok(started.elapsed(), bytes),
+                Err(failure) => {
+                    sender = None;
+                    self.window
+                        .lock()
+                        .unwrap()
+                        .record_error(&failure.to_string());
+                    if matches!(failure, Failure::Connect | Failure::Io) {
+                        tokio::time::sleep(FAILURE_PAUSE).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Una petición por la conexión persistente, que se abre si hace falta;
+    /// la latencia no incluye abrirla.
+    async fn request(
+        &self,
+        sender: &mut Option<SendRequest<Body>>,
+    ) -> Result<(u64, Instant), Failure> {
+        if sender.is_none() {
+            let stream = TcpStream::connect(self.proxy)
+                .await
+                .map_err(|_| Failure::Connect)?;
+            *sender = Some(handshake(stream).await?);
+        }
+        let Some(conn) = sender.as_mut() else {
+            return Err(Failure::Io);
+        };
+        let started = Instant::now();
+        let uri = format!("http://{}/", self.origin);
+        let (bytes, close) = exchange(conn, &uri, &self.origin).await?;
+        if close {
+            *sender = None;
+        }
+        Ok((bytes, started))
+    }
+
+    /// Un túnel nuevo con una petición dentro.
+    async fn tunnel(&self) -> Result<u64, Failure> {
+        let mut stream = TcpStream::connect(self.proxy)
+            .await
+            .map_err(|_| Failure::Connect)?;
+        let _ = stream.set_nodelay(true);
+        let connect = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", self.origin);
+        stream
+            .write_all(connect.as_bytes())
+            .await
+            .map_err(|_| Failure::Io)?;
+        match connect_status(&mut stream).await? {
+            200 => {}
+            status => return Err(Failure::Tunnel(status)),
+        }
+        let mut sender = handshake(stream).await?;
+        exchange(&mut sender, "/", &self.origin)
+            .await
+            .map(|(bytes, _)| bytes)
+    }
+}
+
+async fn handshake(stream: TcpStream) -> Result<SendRequest<Body>, Failure> {
+    let _ = stream.set_nodelay(true);
+    let (sender, conn) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(|_| Failure::Io)?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    Ok(sender)
+}
+
+/// Envía un `GET` y lee la respuesta entera: sus bytes y si el servidor
+/// cierra la conexión.
+async fn exchange(
+    sender: &mut SendRequest<Body>,
+    uri: &str,
+    host: &str,
+) -> Result<(u64, bool), Failure> {
+    futures_util::future::poll_fn(|cx| sender.poll_ready(cx))
+        .await
+        .map_err(|_| Failure::Io)?;
+    let request = Request::get(uri)
+        .header(HOST, host)
+        .body(Body::empty())
+        .map_err(|_| Failure::Io)?;
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|_| Failure::Io)?;
+    let status = response.status();
+    let close = response
+        .headers()
+        .get(CONNECTION)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"close"));
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|_| Failure::Io)?;
+    if !status.is_success() {
+        return Err(Failure::Status(status.as_u16()));
+    }
+    Ok((body.len() as u64, close))
+}
+
+/// Código de la respuesta del proxy a un `CONNECT`.
+async fn connect_status(stream: &mut TcpStream) -> Result<u16, Failure> {
+    let mut head = Vec::with_capacity(256);
+    let mut buf = [0u8; 512];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_CONNECT_HEAD {
+            return Err(Failure::Io);
+        }
+        let n = stream.read(&mut buf).await.map_err(|_| Failure::Io)?;
+        if n == 0 {
+            return Err(Failure::Io);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    std::str::from_utf8(&head)
+        .ok()
+        .and_then(|head| head.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or(Failure::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ProxyServer;
+    use crate::settings::ProxySettings;
+
+    #[test]
+    fn test_histogram_quantiles_and_merge() {
+        let mut fast = Histogram::default();
+        for micros in 1..=1000 {
+            fast.record(Duration::from_micros(micros));
+        }
+        let p50 = fast.quantile(0.5).as_micros() as f64;
+        assert!((p50 - 500.0).abs() / 500.0 < 0.01, "{p50}");
+        assert_eq!(fast.quantile(1.0), Duration::from_micros(1000));
+        assert_eq!(fast.mean(), Duration::from_micros(500));
+
+        // Merged windows answer as if recorded together.
+        let mut slow = Histogram::default();
+        for _ in 0..1000 {
+            slow.record(Duration::from_secs(2));
+        }
+        let mut total = Histogram::default();
+        total.merge(&fast);
+        total.merge(&slow);
+        assert_eq!(total.count(), 2000);
+        assert!(total.quantile(0.25) < Duration::from_millis(1));
+        let p99 = total.quantile(0.99);
+        assert!(p99 > Duration::from_millis(1980) && p99 <= Duration::from_secs(2));
+        assert_eq!(total.max(), Duration::from_secs(2));
+
+        let mut stats = Stats::default();
+        stats.record_ok(Duration::from_millis(4), 100);
+        stats.record_error("timeout");
+        let mut other = Stats::default();
+        other.record_ok(Duration::from_millis(6), 300);
+        other.record_error("timeout");
+        other.record_error("status_502");
+        stats.merge(&other);
+        let summary = stats.summary(Duration::from_secs(2));
+        assert_eq!((summary.ok, summary.failed), (2, 3));
+        assert_eq!(summary.errors["timeout"], 2);
+        assert_eq!(summary.requests_per_sec, 1.0);
+        assert_eq!(summary.bytes_per_sec, 200.0);
+        assert!((summary.latency_ms.mean - 5.0).abs() < 0.01);
+
+        assert_eq!(parse_duration("60s"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_duration("1d").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_smoke_run_through_an_in_process_proxy() {
+        let load = LoadGenerator::new(
+            BenchSettings::new(BenchTarget::Internal, BenchMode::Mixed)
+                .with_duration(Duration::from_millis(300))
+                .with_connections(4)
+                .with_checkpoint(Duration::from_secs(1))
+                .with_body_bytes(64),
+        )
+        .unwrap();
+        let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let server = ProxyServer::new(
+            ProxySettings::new(loopback)
+                .with_admin_listen(loopback)
+                .with_connect_ports(vec![load.origin_port().unwrap()]),
+        )
+        .unwrap();
+        let handle = server.start().await.unwrap();
+
+        let report = load
+            .run(handle.local_addr(), handle.admin_addr(), |_| {})
+            .await
+            .unwrap();
+        assert!(report.total.ok > 0, "{report}");
+        assert_eq!(report.total.failed, 0, "{report}");
+        let bytes = report.total.bytes_per_sec * report.total.elapsed_secs;
+        assert!(
+            (bytes - 64.0 * report.total.ok as f64).abs() < 1.0,
+            "{report}"
+        );
+        assert!(report.checkpoints.is_empty(), "shorter than a checkpoint");
+        assert!(report.proxy_after.is_some());
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,312 @@
+//! Almacén de bodies direccionado por contenido.
+//!
+//! Cada blob es un fichero `<sha256>` del directorio, con el SHA-256 de sus
+//! propios bytes como nombre: el mismo contenido se guarda una sola vez
+//! aunque lo referencien muchas entradas. El almacén lleva en memoria cuántas
+//! referencias tiene cada blob y lo borra cuando se suelta la última; quien
+//! lo usa se las vuelve a dar al arrancar con [`BlobStore::retain`], y los
+//! ficheros que nadie reclama se borran con [`BlobStore::collect_orphans`].
+//! Al leer se comprueba el hash: un blob dañado se borra y se da por perdido
+//! hasta que alguien vuelva a guardar el mismo contenido.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use ring::digest;
+use serde::Serialize;
+use tracing::warn;
+
+/// SHA-256 de los bytes de un blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobId([u8; 32]);
+
+impl BlobId {
+    pub fn of(bytes: &[u8]) -> Self {
+        let digest = digest::digest(&digest::SHA256, bytes);
+        Self(digest.as_ref().try_into().expect("SHA-256 de 32 bytes"))
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl FromStr for BlobId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(());
+        }
+        let mut id = [0u8; 32];
+        for (byte, pair) in id.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| ())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| ())?;
+        }
+        Ok(Self(id))
+    }
+}
+
+/// Deduplicación del almacén, tal como la muestra `GET /api/stats`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BlobStats {
+    pub blobs: usize,
+    pub references: u64,
+    /// Bytes que ocuparían los bodies sin deduplicar.
+    pub logical_bytes: u64,
+    /// Bytes de los blobs en disco.
+    pub physical_bytes: u64,
+    /// Blobs borrados por no casar con su hash.
+    pub corrupt: u64,
+}
+
+struct Blob {
+    refs: u64,
+    bytes: u64,
+    /// Dañado o ausente en disco: el próximo `put` lo vuelve a escribir.
+    missing: bool,
+}
+
+#[derive(Default)]
+struct Blobs {
+    by_id: HashMap<BlobId, Blob>,
+    physical: u64,
+}
+
+pub struct BlobStore {
+    dir: PathBuf,
+    blobs: Mutex<Blobs>,
+    corrupt: AtomicU64,
+}
+
+impl BlobStore {
+    /// Abre el directorio, creándolo si hace falta. Empieza sin referencias.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            blobs: Mutex::default(),
+            corrupt: AtomicU64::new(0),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Guarda `bytes` si no estaban ya y suma una referencia.
+    pub fn put(&self, bytes: &[u8]) -> io::Result<BlobId> {
+        let id = BlobId::of(bytes);
+        let mut blobs = self.blobs.lock().expect("lock de blobs");
+        if let Some(blob) = blobs.by_id.get_mut(&id).filter(|blob| !blob.missing) {
+            blob.refs += 1;
+            return Ok(id);
+        }
+        // Written aside and renamed, so a crash never leaves a short blob
+        // under its final name.
+        let path = self.path(&id);
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_data()?;
+        std::fs::rename(&tmp, &path)?;
+
+        let len = bytes.len() as u64;
+        blobs.physical += len;
+        let blob = blobs.by_id.entry(id).or_insert(Blob {
+            refs: 0,
+            bytes: 0,
+            missing: true,
+        });
+        blob.refs += 1;
+        blob.bytes = len;
+        blob.missing = false;
+        Ok(id)
+    }
+
+    /// Suma una referencia a un blob que ya está en disco, al reconstruir el
+    /// índice de quien lo usa.
+    pub fn retain(&self, id: BlobId) {
+        let mut blobs = self.blobs.lock().expect("lock de blobs");
+        if let Some(blob) = blobs.by_id.get_mut(&id) {
+            blob.refs += 1;
+            return;
+        }
+        let bytes = std::fs::metadata(self.path(&id))
+            .ok()
+            .map(|meta| meta.len());
+        blobs.physical += bytes.unwrap_or(0);
+        blobs.by_id.insert(
+            id,
+            Blob {
+                refs: 1,
+                bytes: bytes.unwrap_or(0),
+                missing: bytes.is_none(),
+            },
+        );
+    }
+
+    /// Resta una referencia; con la última se borra el blob. Devuelve los
+    /// bytes liberados en disco.
+    pub fn release(&self, id: &BlobId) -> u64 {
+        let mut blobs = self.blobs.lock().expect("lock de blobs");
+        let Some(blob) = blobs.by_id.get_mut(id) else {
+            return 0;
+        };
+        blob.refs = blob.refs.saturating_sub(1);
+        if blob.refs > 0 {
+            return 0;
+        }
+        let freed = if blob.missing { 0 } else { blob.bytes };
+        blobs.by_id.remove(id);
+        blobs.physical -= freed;
+        match std::fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                // Collected as an orphan on the next start.
+                warn!(blob = %id, error = %e, "No se pudo borrar un blob sin referencias");
+            }
+            _ => {}
+        }
+        freed
+    }
+
+    /// Borra los ficheros del directorio que no tienen referencias, como los
+    /// de una escritura cortada. Se llama cuando ya se han dado todas con
+    /// [`BlobStore::retain`].
+    pub fn collect_orphans(&self) -> io::Result<usize> {
+        let blobs = self.blobs.lock().expect("lock de blobs");
+        let mut removed = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let referenced = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<BlobId>().ok())
+                .is_some_and(|id| blobs.by_id.contains_key(&id));
+            if !referenced && path.is_file() {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Lee un blob y comprueba su hash. `None` si no está o estaba dañado;
+    /// en ese caso se borra.
+    pub async fn read(&self, id: &BlobId) -> io::Result<Option<Vec<u8>>> {
+        let bytes = match tokio::fs::read(self.path(id)).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if BlobId::of(&bytes) == *id {
+            return Ok(Some(bytes));
+        }
+        warn!(blob = %id, "Blob dañado: no casa con su hash y se borra");
+        self.corrupt.fetch_add(1, Ordering::Relaxed);
+        let mut blobs = self.blobs.lock().expect("lock de blobs");
+        let Blobs { by_id, physical } = &mut *blobs;
+        if let Some(blob) = by_id.get_mut(id).filter(|blob| !blob.missing) {
+            blob.missing = true;
+            *physical -= blob.bytes;
+        }
+        match std::fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Bytes de los blobs en disco.
+    pub fn physical_bytes(&self) -> u64 {
+        self.blobs.lock().expect("lock de blobs").physical
+    }
+
+    pub fn stats(&self) -> BlobStats {
+        let blobs = self.blobs.lock().expect("lock de blobs");
+        let present = || blobs.by_id.values().filter(|blob| !blob.missing);
+        BlobStats {
+            blobs: present().count(),
+            references: blobs.by_id.values().map(|blob| blob.refs).sum(),
+            logical_bytes: present().map(|blob| blob.refs * blob.bytes).sum(),
+            physical_bytes: blobs.physical,
+            corrupt: self.corrupt.load(Ordering::Relaxed),
+        }
+    }
+
+    fn path(&self, id: &BlobId) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(store: &BlobStore) -> usize {
+        std::fs::read_dir(store.dir()).unwrap().count()
+    }
+
+    #[test]
+    fn test_identical_bodies_share_a_blob_until_the_last_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlobStore::open(dir.path()).unwrap();
+        let first = store.put(b"mismo body").unwrap();
+        let second = store.put(b"mismo body").unwrap();
+        let other = store.put(b"otro").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.to_string().parse::<BlobId>(), Ok(first));
+        assert_eq!(files(&store), 2);
+        let stats = store.stats();
+        assert_eq!((stats.blobs, stats.references), (2, 3));
+        assert_eq!((stats.logical_bytes, stats.physical_bytes), (24, 14));
+
+        assert_eq!(store.release(&first), 0);
+        assert_eq!(files(&store), 2);
+        assert_eq!(store.release(&first), 10);
+        assert_eq!(store.release(&other), 4);
+        assert_eq!(files(&store), 0);
+        assert_eq!(store.stats(), BlobStats::default());
+
+        // On restart only what the owner retains survives.
+        let kept = store.put(b"mismo body").unwrap();
+        store.put(b"huerfano").unwrap();
+        std::fs::write(dir.path().join("cortado.tmp"), b"x").unwrap();
+        let reopened = BlobStore::open(dir.path()).unwrap();
+        reopened.retain(kept);
+        assert_eq!(reopened.collect_orphans().unwrap(), 2);
+        assert_eq!(files(&reopened), 1);
+        assert_eq!(reopened.stats().physical_bytes, 10);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_blob_is_evicted_and_stored_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlobStore::open(dir.path()).unwrap();
+        let id = store.put(b"contenido").unwrap();
+        assert_eq!(store.read(&id).await.unwrap().unwrap(), b"contenido");
+
+        std::fs::write(dir.path().join(id.to_string()), b"contenid0").unwrap();
+        assert_eq!(store.read(&id).await.unwrap(), None);
+        assert_eq!(files(&store), 0);
+        let stats = store.stats();
+        assert_eq!(
+            (stats.blobs, stats.physical_bytes, stats.corrupt),
+            (0, 0, 1)
+        );
+
+        // The same content passing through again brings it back for every
+        // reference.
+        assert_eq!(store.put(b"contenido").unwrap(), id);
+        assert_eq!(store.read(&id).await.unwrap().unwrap(), b"contenido");
+        assert_eq!(store.stats().references, 2);
+    }
+}
@@ -0,0 +1,179 @@
+//! Hosts bloqueados en caliente desde la API de administración.
+//!
+//! `POST /api/blocklist` añade una regla con la sintaxis de `[host_filter]`
+//! (host exacto, `*.dominio` o rango CIDR) y la siguiente petición hacia ese
+//! destino ya recibe un 403, sea HTTP o `CONNECT`. Estas reglas se evalúan
+//! antes que `[host_filter]`: un host permitido por la configuración se
+//! puede bloquear sin tocarla. Con `blocklist_file`, la lista se guarda en
+//! ese JSON con cada cambio y se carga al arrancar.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::host_filter::HostRule;
+
+/// Una regla de la lista, con el momento en que se añadió.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockEntry {
+    pub host: String,
+    /// Segundos Unix.
+    pub added_at: u64,
+}
+
+pub struct Blocklist {
+    path: Option<PathBuf>,
+    /// Por regla normalizada, en el orden en que se listan.
+    entries: RwLock<BTreeMap<String, (HostRule, BlockEntry)>>,
+}
+
+impl Blocklist {
+    /// Lista vacía que solo vive en memoria.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            entries: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Carga la lista de `path`; si no existe se empieza vacía.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let entries: Vec<BlockEntry> = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Lista de bloqueo corrupta en {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("No se pudo leer {}", path.display())),
+        };
+        let mut loaded = BTreeMap::new();
+        for entry in entries {
+            let rule: HostRule = entry
+                .host
+                .parse()
+                .with_context(|| format!("Regla inválida en {}", path.display()))?;
+            loaded.insert(rule.to_string(), (rule, entry));
+        }
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            entries: RwLock::new(loaded),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn list(&self) -> Vec<BlockEntry> {
+        let entries = self.entries.read().expect("lock de la lista de bloqueo");
+        entries.values().map(|(_, entry)| entry.clone()).collect()
+    }
+
+    /// La regla que bloquea `host`, si hay alguna.
+    pub fn check(&self, host: &str) -> Option<String> {
+        let entries = self.entries.read().expect("lock de la lista de bloqueo");
+        entries
+            .iter()
+            .find(|(_, (rule, _))| rule.matches(host))
+            .map(|(key, _)| key.clone())
+    }
+
+    /// Añade `rule` y devuelve su entrada, y si ya estaba. Si no se puede
+    /// guardar, la lista queda como estaba.
+    pub fn add(&self, rule: HostRule) -> anyhow::Result<(BlockEntry, bool)> {
+        let key = rule.to_string();
+        let mut entries = self.entries.write().expect("lock de la lista de bloqueo");
+        if let Some((_, entry)) = entries.get(&key) {
+            return Ok((entry.clone(), true));
+        }
+        let entry = BlockEntry {
+            host: key.clone(),
+            added_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        entries.insert(key.clone(), (rule, entry.clone()));
+        if let Err(e) = self.save(&entries) {
+            entries.remove(&key);
+            return Err(e);
+        }
+        Ok((entry, false))
+    }
+
+    /// Quita la regla `rule` (con la misma sintaxis con que se añadió);
+    /// `false` si no estaba.
+    pub fn remove(&self, rule: &HostRule) -> anyhow::Result<bool> {
+        let key = rule.to_string();
+        let mut entries = self.entries.write().expect("lock de la lista de bloqueo");
+        let Some(removed) = entries.remove(&key) else {
+            return Ok(false);
+        };
+        if let Err(e) = self.save(&entries) {
+            entries.insert(key, removed);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn save(&self, entries: &BTreeMap<String, (HostRule, BlockEntry)>) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let list: Vec<&BlockEntry> = entries.values().map(|(_, entry)| entry).collect();
+        let bytes = serde_json::to_vec_pretty(&list)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, bytes)
+            .with_context(|| format!("No se pudo escribir {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("No se pudo reemplazar {}", path.display()))?;
+        Ok(())
+    }
+
+    /// 403 para `host`, bloqueado por `rule`.
+    pub fn blocked(host: &str, rule: &str) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header("x-proxy-blocklist", "blocked")
+            .body(Body::from(format!(
+                "El destino {host} está bloqueado por la regla `{rule}` de la lista de bloqueo.\n"
+            )))
+            .expect("respuesta de destino bloqueado")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_persist_and_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.json");
+        let list = Blocklist::open(&path).unwrap();
+        assert!(list.list().is_empty());
+
+        let (entry, existed) = list.add("*.Ads.Test".parse().unwrap()).unwrap();
+        assert_eq!((entry.host.as_str(), existed), ("*.ads.test", false));
+        assert!(list.add("*.ads.test".parse().unwrap()).unwrap().1);
+        list.add("10.0.0.0/8".parse().unwrap()).unwrap();
+        assert_eq!(list.check("cdn.ads.test").as_deref(), Some("*.ads.test"));
+        assert_eq!(list.check("10.1.2.3").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(list.check("example.test"), None);
+
+        let reopened = Blocklist::open(&path).unwrap();
+        assert_eq!(reopened.list(), list.list());
+        assert!(reopened.remove(&"*.ads.test".parse().unwrap()).unwrap());
+        assert!(!reopened.remove(&"*.ads.test".parse().unwrap()).unwrap());
+        let hosts: Vec<_> = Blocklist::open(&path)
+            .unwrap()
+            .list()
+            .into_iter()
+            .map(|entry| entry.host)
+            .collect();
+        assert_eq!(hosts, ["10.0.0.0/8"]);
+    }
+}
@@ -0,0 +1,128 @@
+//! Tamaño máximo de los bodies de las peticiones HTTP y de sus respuestas.
+//!
+//! Con `max_request_body`, una petición cuyo `Content-Length` lo supera
+//! recibe un 413 sin llegar al destino; un body sin longitud (chunked) se
+//! cuenta según pasa y, si cruza el límite, el envío al destino se corta y
+//! el cliente recibe el mismo 413. Con `max_response_body`, una respuesta que
+//! declara más se cambia por un 502 y una que lo cruza a mitad de stream se
+//! corta, así que el cliente la ve incompleta y nunca como válida. Nada se
+//! acumula en memoria: solo se cuentan los bytes de cada chunk. Los túneles
+//! `CONNECT` no tienen límite.
+
+use std::fmt;
+
+use futures_util::StreamExt;
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+
+/// Un body superó `limit` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyTooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "el body supera el límite de {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// El [`BodyTooLarge`] que lleva `err` o alguna de sus causas.
+pub fn exceeded<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a BodyTooLarge> {
+    let mut source = Some(err);
+    while let Some(cause) = source {
+        if let Some(found) = cause.downcast_ref::<BodyTooLarge>() {
+            return Some(found);
+        }
+        source = cause.source();
+    }
+    None
+}
+
+/// `Content-Length` de `headers` si supera `limit`.
+pub fn declared_over(headers: &HeaderMap, limit: u64) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .filter(|length| *length > limit)
+}
+
+/// `body` tal cual hasta `limit` bytes; el chunk que lo cruza es un error
+/// [`BodyTooLarge`] y `on_exceeded` se llama una vez.
+pub fn limit_body(body: Body, limit: u64, on_exceeded: impl FnOnce() + Send + 'static) -> Body {
+    let mut seen = 0u64;
+    let mut on_exceeded = Some(on_exceeded);
+    let chunks = body.map(
+        move |chunk| -> Result<Bytes, Box<dyn std::error::Error + Send + Sync>> {
+            let chunk = chunk?;
+            seen += chunk.len() as u64;
+            if seen > limit {
+                if let Some(on_exceeded) = on_exceeded.take() {
+                    on_exceeded();
+                }
+                return Err(Box::new(BodyTooLarge { limit }));
+            }
+            Ok(chunk)
+        },
+    );
+    // Nothing more is read once the limit is crossed.
+    let mut failed = false;
+    let chunks = chunks.take_while(move |chunk| {
+        let keep = !failed;
+        failed |= chunk.is_err();
+        futures_util::future::ready(keep)
+    });
+    Body::wrap_stream(chunks)
+}
+
+/// 413 para una petición cuyo body supera `limit`.
+pub fn request_too_large(limit: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header("x-proxy-error", "request_body_too_large")
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(format!(
+            "El body de la petición supera el límite de {limit} bytes\n"
+        )))
+        .expect("respuesta 413")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use hyper::body::HttpBody;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limit_counts_chunks_and_fails_once_crossed() {
+        let chunks = ["abcd", "efgh", "ijkl"].map(|c| Ok::<_, std::io::Error>(Bytes::from(c)));
+        let calls = Arc::new(AtomicU32::new(0));
+        let counted = calls.clone();
+        let mut body = limit_body(
+            Body::wrap_stream(futures_util::stream::iter(chunks)),
+            6,
+            move || {
+                counted.fetch_add(1, Ordering::Relaxed);
+            },
+        );
+        assert_eq!(body.data().await.unwrap().unwrap(), "abcd");
+        let err = body.data().await.unwrap().unwrap_err();
+        assert_eq!(exceeded(&err), Some(&BodyTooLarge { limit: 6 }));
+        assert!(body.data().await.is_none());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, "7".parse().unwrap());
+        assert_eq!(declared_over(&headers, 6), Some(7));
+        assert_eq!(declared_over(&headers, 7), None);
+    }
+}
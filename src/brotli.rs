@@ -0,0 +1,1572 @@
+//! Decodificador de brotli (RFC 7932) para [`crate::decompress`].
+//!
+//! Funciona como los decodificadores de `flate2` que usa ese módulo: se le
+//! escriben los bytes comprimidos según llegan y deja lo descomprimido en
+//! un `Vec` que se vacía con [`BrotliDecoder::get_mut`]. Deja de producir en
+//! cuanto el total pasa de `max`, así que unos pocos bytes que piden
+//! millones de copias nunca ocupan más que ese límite. El diccionario
+//! estático es el del apéndice A de la RFC.
+
+use std::io;
+
+/// Bits de índice de las palabras del diccionario según su longitud.
+const DICTIONARY_BITS: [u32; 25] = [
+    0, 0, 0, 0, 10, 10, 11, 11, 10, 10, 10, 10, 10, 9, 9, 8, 7, 7, 8, 7, 7, 6, 6, 5, 5,
+];
+
+static DICTIONARY: &[u8; 122_784] = include_bytes!("brotli_dictionary.bin");
+
+/// (base, bits extra) de los códigos de longitud de bloque (§6).
+const BLOCK_LENGTHS: [(u32, u32); 26] = [
+    (1, 2),
+    (5, 2),
+    (9, 2),
+    (13, 2),
+    (17, 3),
+    (25, 3),
+    (33, 3),
+    (41, 3),
+    (49, 4),
+    (65, 4),
+    (81, 4),
+    (97, 4),
+    (113, 5),
+    (145, 5),
+    (177, 5),
+    (209, 5),
+    (241, 6),
+    (305, 6),
+    (369, 7),
+    (497, 8),
+    (753, 9),
+    (1265, 10),
+    (2289, 11),
+    (4337, 12),
+    (8433, 13),
+    (16625, 24),
+];
+
+/// (base, bits extra) de los códigos de longitud de inserción (§5).
+const INSERT_LENGTHS: [(u32, u32); 24] = [
+    (0, 0),
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 1),
+    (8, 1),
+    (10, 2),
+    (14, 2),
+    (18, 3),
+    (26, 3),
+    (34, 4),
+    (50, 4),
+    (66, 5),
+    (98, 5),
+    (130, 6),
+    (194, 7),
+    (322, 8),
+    (578, 9),
+    (1090, 10),
+    (2114, 12),
+    (6210, 14),
+    (22594, 24),
+];
+
+/// (base, bits extra) de los códigos de longitud de copia (§5).
+const COPY_LENGTHS: [(u32, u32); 24] = [
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 1),
+    (12, 1),
+    (14, 2),
+    (18, 2),
+    (22, 3),
+    (30, 3),
+    (38, 4),
+    (54, 4),
+    (70, 5),
+    (102, 5),
+    (134, 6),
+    (198, 7),
+    (326, 8),
+    (582, 9),
+    (1094, 10),
+    (2118, 24),
+];
+
+/// Primer código de inserción y de copia de cada celda de 64 comandos.
+const COMMAND_CELLS: [(u32, u32); 11] = [
+    (0, 0),
+    (0, 8),
+    (0, 0),
+    (0, 8),
+    (8, 0),
+    (8, 8),
+    (0, 16),
+    (16, 0),
+    (8, 16),
+    (16, 8),
+    (16, 16),
+];
+
+/// Orden en que llegan las longitudes del código de longitudes (§3.5).
+const CODE_LENGTH_ORDER: [usize; 18] =
+    [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Un error al leer: faltan bytes o el stream no es brotli.
+enum Fail {
+    NeedInput,
+    Corrupt,
+}
+
+type Step<T> = Result<T, Fail>;
+
+/// Los bytes comprimidos pendientes, leídos bit a bit desde el menos
+/// significativo.
+#[derive(Default)]
+struct Bits {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Bits {
+    fn available(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    /// Hasta `n` (≤ 24) bits sin consumirlos, y cuántos de ellos hay.
+    fn peek(&self, n: usize) -> (u32, usize) {
+        let byte = self.pos / 8;
+        let mut word = 0u64;
+        for (i, b) in self.data[byte.min(self.data.len())..]
+            .iter()
+            .take(4)
+            .enumerate()
+        {
+            word |= u64::from(*b) << (8 * i);
+        }
+        let value = (word >> (self.pos % 8)) as u32 & ((1 << n) - 1);
+        (value, n.min(self.available()))
+    }
+
+    fn read(&mut self, n: u32) -> Step<u32> {
+        let n = n as usize;
+        if n > self.available() {
+            return Err(Fail::NeedInput);
+        }
+        let (value, _) = self.peek(n);
+        self.pos += n;
+        Ok(value)
+    }
+
+    /// Salta hasta el siguiente byte; el relleno tiene que ser cero.
+    fn align(&mut self) -> Step<()> {
+        match self.read(((8 - self.pos % 8) % 8) as u32)? {
+            0 => Ok(()),
+            _ => Err(Fail::Corrupt),
+        }
+    }
+}
+
+/// Un código prefijo canónico.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+    /// El único símbolo de un código que se lee sin gastar bits.
+    single: Option<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols = Vec::new();
+        for len in 1..16 {
+            symbols.extend(
+                (0..lengths.len() as u16).filter(|&symbol| lengths[usize::from(symbol)] == len),
+            );
+        }
+        let single = (symbols.len() == 1).then(|| symbols[0]);
+        Self {
+            counts,
+            symbols,
+            single,
+        }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Step<u16> {
+        if let Some(symbol) = self.single {
+            return Ok(symbol);
+        }
+        let (peeked, available) = bits.peek(15);
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            if len > available {
+                return Err(Fail::NeedInput);
+            }
+            code |= (peeked >> (len - 1)) as i32 & 1;
+            let count = i32::from(self.counts[len]);
+            if code - first < count {
+                bits.pos += len;
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Fail::Corrupt)
+    }
+
+    /// Lee un código para un alfabeto de `size` símbolos (§3.2-3.5).
+    fn read(bits: &mut Bits, size: usize) -> Step<Self> {
+        let mut lengths = vec![0u8; size];
+        let skip = bits.read(2)?;
+        if skip == 1 {
+            let width = usize::BITS - (size - 1).leading_zeros();
+            let count = bits.read(2)? as usize + 1;
+            let mut symbols = [0usize; 4];
+            for i in 0..count {
+                symbols[i] = bits.read(width)? as usize;
+                if symbols[i] >= size || symbols[..i].contains(&symbols[i]) {
+                    return Err(Fail::Corrupt);
+                }
+            }
+            let shape: &[u8] = match count {
+                1 => &[0],
+                2 => &[1, 1],
+                3 => &[1, 2, 2],
+                _ if bits.read(1)? == 0 => &[2, 2, 2, 2],
+                _ => &[1, 2, 3, 3],
+            };
+            if count == 1 {
+                return Ok(Self {
+                    counts: [0; 16],
+                    symbols: vec![symbols[0] as u16],
+                    single: Some(symbols[0] as u16),
+                });
+            }
+            for (&symbol, &len) in symbols.iter().zip(shape) {
+                lengths[symbol] = len;
+            }
+            return Ok(Self::new(&lengths));
+        }
+
+        // Lengths of the code-length code, themselves in a fixed code.
+        const PREFIX_LENGTH: [usize; 16] = [2, 2, 2, 3, 2, 2, 2, 4, 2, 2, 2, 3, 2, 2, 2, 4];
+        const PREFIX_VALUE: [u8; 16] = [0, 4, 3, 2, 0, 4, 3, 1, 0, 4, 3, 2, 0, 4, 3, 5];
+        let mut code_lengths = [0u8; 18];
+        let (mut space, mut codes) = (32i32, 0);
+        for &symbol in &CODE_LENGTH_ORDER[skip as usize..] {
+            let (peeked, available) = bits.peek(4);
+            let len = PREFIX_LENGTH[peeked as usize];
+            if len > available {
+                return Err(Fail::NeedInput);
+            }
+            bits.pos += len;
+            let value = PREFIX_VALUE[peeked as usize];
+            code_lengths[symbol] = value;
+            if value != 0 {
+                space -= 32 >> value;
+                codes += 1;
+                if space <= 0 {
+                    break;
+                }
+            }
+        }
+        if codes != 1 && space != 0 {
+            return Err(Fail::Corrupt);
+        }
+        let code_lengths = Self::new(&code_lengths);
+
+        let (mut symbol, mut space) = (0, 32768i32);
+        let (mut previous, mut repeat, mut repeat_len) = (8u8, 0usize, 0u8);
+        while symbol < size && space > 0 {
+            let code = code_lengths.decode(bits)? as u8;
+            if code < 16 {
+                repeat = 0;
+                lengths[symbol] = code;
+                if code != 0 {
+                    previous = code;
+                    space -= 32768 >> code;
+                }
+                symbol += 1;
+                continue;
+            }
+            let (extra, len) = if code == 16 { (2, previous) } else { (3, 0) };
+            if repeat_len != len {
+                repeat = 0;
+                repeat_len = len;
+            }
+            let old = repeat;
+            if repeat > 0 {
+                repeat = (repeat - 2) << extra;
+            }
+            repeat += bits.read(extra)? as usize + 3;
+            let delta = repeat - old;
+            if symbol + delta > size {
+                return Err(Fail::Corrupt);
+            }
+            lengths[symbol..symbol + delta].fill(len);
+            symbol += delta;
+            if len != 0 {
+                space -= delta as i32 * (32768 >> len);
+            }
+        }
+        if space != 0 {
+            return Err(Fail::Corrupt);
+        }
+        Ok(Self::new(&lengths))
+    }
+}
+
+/// Un número de 1 a 256 como los de NBLTYPES y NTREES (§9.2).
+fn read_count(bits: &mut Bits) -> Step<usize> {
+    if bits.read(1)? == 0 {
+        return Ok(1);
+    }
+    let n = bits.read(3)?;
+    Ok((1 << n) + bits.read(n)? as usize + 1)
+}
+
+/// El tipo y lo que queda del bloque actual de una categoría.
+#[derive(Clone, Copy)]
+struct Position {
+    current: usize,
+    previous: usize,
+    left: u32,
+}
+
+/// Los bloques de una categoría: literales, comandos o distancias.
+struct Blocks {
+    types: usize,
+    codes: Option<(Huffman, Huffman)>,
+    position: Position,
+}
+
+impl Blocks {
+    fn read(bits: &mut Bits) -> Step<Self> {
+        let types = read_count(bits)?;
+        let mut blocks = Self {
+            types,
+            codes: None,
+            position: Position {
+                current: 0,
+                previous: 1,
+                left: 1 << 24,
+            },
+        };
+        if types >= 2 {
+            let kinds = Huffman::read(bits, types + 2)?;
+            let lengths = Huffman::read(bits, BLOCK_LENGTHS.len())?;
+            blocks.position.left = block_length(&lengths, bits)?;
+            blocks.codes = Some((kinds, lengths));
+        }
+        Ok(blocks)
+    }
+
+    /// La posición tras consumir un símbolo, cambiando de bloque si el
+    /// actual se ha acabado. Solo se guarda cuando el símbolo entero se
+    /// ha podido leer.
+    fn next(&self, bits: &mut Bits) -> Step<Position> {
+        let mut position = self.position;
+        if position.left == 0 {
+            let Some((kinds, lengths)) = &self.codes else {
+                return Err(Fail::Corrupt);
+            };
+            let mut kind = match kinds.decode(bits)? {
+                0 => position.previous,
+                1 => position.current + 1,
+                n => usize::from(n) - 2,
+            };
+            if kind >= self.types {
+                kind -= self.types;
+            }
+            position = Position {
+                current: kind,
+                previous: position.current,
+                left: block_length(lengths, bits)?,
+            };
+        }
+        position.left -= 1;
+        Ok(position)
+    }
+}
+
+fn block_length(code: &Huffman, bits: &mut Bits) -> Step<u32> {
+    let (base, extra) = BLOCK_LENGTHS[usize::from(code.decode(bits)?)];
+    Ok(base + bits.read(extra)?)
+}
+
+/// Un mapa de contexto a árboles (§7.3).
+fn read_context_map(bits: &mut Bits, size: usize, trees: usize) -> Step<Vec<u8>> {
+    if trees < 2 {
+        return Ok(vec![0; size]);
+    }
+    let rle_max = match bits.read(1)? {
+        1 => bits.read(4)? as usize + 1,
+        _ => 0,
+    };
+    let code = Huffman::read(bits, trees + rle_max)?;
+    let mut map = Vec::with_capacity(size);
+    while map.len() < size {
+        match usize::from(code.decode(bits)?) {
+            0 => map.push(0),
+            run if run <= rle_max => {
+                let zeros = (1 << run) + bits.read(run as u32)? as usize;
+                if map.len() + zeros > size {
+                    return Err(Fail::Corrupt);
+                }
+                map.resize(map.len() + zeros, 0);
+            }
+            tree => map.push((tree - rle_max) as u8),
+        }
+    }
+    if bits.read(1)? == 1 {
+        let mut mtf: Vec<u8> = (0..=255).collect();
+        for value in &mut map {
+            let index = usize::from(*value);
+            let moved = mtf.remove(index);
+            mtf.insert(0, moved);
+            *value = moved;
+        }
+    }
+    Ok(map)
+}
+
+/// Todo lo que dice la cabecera de un meta-bloque comprimido.
+struct MetaBlock {
+    literal_blocks: Blocks,
+    command_blocks: Blocks,
+    distance_blocks: Blocks,
+    postfix: u32,
+    direct: u32,
+    modes: Vec<u8>,
+    literal_map: Vec<u8>,
+    distance_map: Vec<u8>,
+    literals: Vec<Huffman>,
+    commands: Vec<Huffman>,
+    distances: Vec<Huffman>,
+}
+
+impl MetaBlock {
+    fn read(bits: &mut Bits) -> Step<Self> {
+        let literal_blocks = Blocks::read(bits)?;
+        let command_blocks = Blocks::read(bits)?;
+        let distance_blocks = Blocks::read(bits)?;
+        let postfix = bits.read(2)?;
+        let direct = bits.read(4)? << postfix;
+        let modes = (0..literal_blocks.types)
+            .map(|_| bits.read(2).map(|mode| mode as u8))
+            .collect::<Step<Vec<_>>>()?;
+        let literal_trees = read_count(bits)?;
+        let literal_map = read_context_map(bits, 64 * literal_blocks.types, literal_trees)?;
+        let distance_trees = read_count(bits)?;
+        let distance_map = read_context_map(bits, 4 * distance_blocks.types, distance_trees)?;
+        let mut trees = |count: usize, size: usize| {
+            (0..count)
+                .map(|_| Huffman::read(bits, size))
+                .collect::<Step<Vec<_>>>()
+        };
+        let literals = trees(literal_trees, 256)?;
+        let commands = trees(command_blocks.types, 704)?;
+        let distances = trees(distance_trees, 16 + direct as usize + (48 << postfix))?;
+        Ok(Self {
+            literal_blocks,
+            command_blocks,
+            distance_blocks,
+            postfix,
+            direct,
+            modes,
+            literal_map,
+            distance_map,
+            literals,
+            commands,
+            distances,
+        })
+    }
+}
+
+/// Lo que abre un meta-bloque.
+enum Header {
+    /// Fin del stream.
+    End,
+    Metadata(usize),
+    Uncompressed(usize),
+    Compressed(usize, Box<MetaBlock>),
+}
+
+fn read_header(bits: &mut Bits, last: &mut bool) -> Step<Header> {
+    let is_last = bits.read(1)? == 1;
+    if is_last && bits.read(1)? == 1 {
+        *last = true;
+        return Ok(Header::End);
+    }
+    let nibbles = match bits.read(2)? {
+        3 => 0,
+        n => n + 4,
+    };
+    if nibbles == 0 {
+        if is_last || bits.read(1)? != 0 {
+            return Err(Fail::Corrupt);
+        }
+        let bytes = bits.read(2)?;
+        let mut skip = 0;
+        for i in 0..bytes {
+            let byte = bits.read(8)? as usize;
+            if i + 1 == bytes && bytes > 1 && byte == 0 {
+                return Err(Fail::Corrupt);
+            }
+            skip |= byte << (8 * i);
+        }
+        bits.align()?;
+        return Ok(Header::Metadata(if bytes == 0 { 0 } else { skip + 1 }));
+    }
+    let mut len = 0;
+    for i in 0..nibbles {
+        let nibble = bits.read(4)? as usize;
+        if i + 1 == nibbles && nibbles > 4 && nibble == 0 {
+            return Err(Fail::Corrupt);
+        }
+        len |= nibble << (4 * i);
+    }
+    let len = len + 1;
+    *last = is_last;
+    if !is_last && bits.read(1)? == 1 {
+        bits.align()?;
+        return Ok(Header::Uncompressed(len));
+    }
+    Ok(Header::Compressed(len, Box::new(MetaBlock::read(bits)?)))
+}
+
+/// Las transformaciones de las palabras del diccionario (apéndice B).
+#[derive(Clone, Copy)]
+enum Transform {
+    Identity,
+    OmitLast(usize),
+    UppercaseFirst,
+    UppercaseAll,
+    OmitFirst(usize),
+}
+
+/// Pasa a mayúsculas el carácter UTF-8 del principio de `word` como lo
+/// hace la RFC, y devuelve cuántos bytes ocupa.
+fn uppercase(word: &mut [u8]) -> usize {
+    match word[0] {
+        b'a'..=b'z' => {
+            word[0] ^= 32;
+            1
+        }
+        0..=0xbf => 1,
+        0xc0..=0xdf => {
+            if let Some(byte) = word.get_mut(1) {
+                *byte ^= 32;
+            }
+            2
+        }
+        _ => {
+            if let Some(byte) = word.get_mut(2) {
+                *byte ^= 5;
+            }
+            3
+        }
+    }
+}
+
+/// La palabra `address` del diccionario con longitud base `len`.
+fn dictionary_word(len: usize, address: usize) -> Step<Vec<u8>> {
+    if !(4..=24).contains(&len) {
+        return Err(Fail::Corrupt);
+    }
+    let bits = DICTIONARY_BITS[len];
+    let index = address & ((1 << bits) - 1);
+    let (prefix, transform, suffix) = *TRANSFORMS.get(address >> bits).ok_or(Fail::Corrupt)?;
+    let offset = (4..len)
+        .map(|shorter| shorter << DICTIONARY_BITS[shorter])
+        .sum::<usize>()
+        + index * len;
+    let mut word = &DICTIONARY[offset..offset + len];
+    match transform {
+        Transform::OmitFirst(n) => word = &word[n.min(len)..],
+        Transform::OmitLast(n) => word = &word[..len.saturating_sub(n)],
+        _ => {}
+    }
+    let mut out = prefix.to_vec();
+    let start = out.len();
+    out.extend_from_slice(word);
+    match transform {
+        Transform::UppercaseFirst if !word.is_empty() => {
+            uppercase(&mut out[start..]);
+        }
+        Transform::UppercaseAll => {
+            let mut at = start;
+            while at < out.len() {
+                at += uppercase(&mut out[at..]);
+            }
+        }
+        _ => {}
+    }
+    out.extend_from_slice(suffix);
+    Ok(out)
+}
+
+/// El contexto de un literal según los dos bytes anteriores (§7.1).
+fn literal_context(mode: u8, p1: u8, p2: u8) -> usize {
+    let signed = |byte: u8| match byte {
+        0 => 0,
+        1..=15 => 1,
+        16..=63 => 2,
+        64..=127 => 3,
+        128..=191 => 4,
+        192..=239 => 5,
+        240..=254 => 6,
+        255 => 7,
+    };
+    usize::from(match mode {
+        0 => p1 & 0x3f,
+        1 => p1 >> 2,
+        2 => UTF8_P1[usize::from(p1)] | UTF8_P2[usize::from(p2)],
+        _ => signed(p1) << 3 | signed(p2),
+    })
+}
+
+/// Por dónde va la decodificación.
+#[derive(Clone)]
+enum State {
+    /// Antes de la cabecera del stream.
+    Stream,
+    Header,
+    Uncompressed(usize),
+    Metadata(usize),
+    Command,
+    Literals {
+        insert: usize,
+        copy: usize,
+        implicit: bool,
+    },
+    Distance {
+        copy: usize,
+        implicit: bool,
+    },
+    Copy {
+        distance: usize,
+        left: usize,
+    },
+    Word {
+        word: Vec<u8>,
+        at: usize,
+    },
+    Done,
+}
+
+/// Las salidas recientes, para las copias hacia atrás.
+struct Window {
+    ring: Vec<u8>,
+    size: usize,
+    written: usize,
+}
+
+impl Window {
+    fn push(&mut self, byte: u8) {
+        if self.ring.len() < self.size {
+            self.ring.push(byte);
+        } else {
+            self.ring[self.written & (self.size - 1)] = byte;
+        }
+        self.written += 1;
+    }
+
+    fn back(&self, distance: usize) -> u8 {
+        self.ring[(self.written - distance) & (self.size - 1)]
+    }
+}
+
+/// Descompresión incremental de un stream brotli.
+pub struct BrotliDecoder {
+    bits: Bits,
+    state: State,
+    window: Window,
+    meta: Option<Box<MetaBlock>>,
+    /// Bytes que le quedan al meta-bloque actual.
+    remaining: usize,
+    last: bool,
+    /// Las cuatro últimas distancias, de la más reciente a la más vieja.
+    distances: [usize; 4],
+    max: usize,
+    out: Vec<u8>,
+}
+
+impl BrotliDecoder {
+    /// Deja de producir en cuanto ha sacado más de `max` bytes.
+    pub fn new(max: usize) -> Self {
+        Self {
+            bits: Bits::default(),
+            state: State::Stream,
+            window: Window {
+                ring: Vec::new(),
+                size: 1 << 16,
+                written: 0,
+            },
+            meta: None,
+            remaining: 0,
+            last: false,
+            distances: [4, 11, 15, 16],
+            max,
+            out: Vec::new(),
+        }
+    }
+
+    /// Lo descomprimido que aún no se ha recogido.
+    pub fn get_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.out
+    }
+
+    pub fn write_all(&mut self, input: &[u8]) -> io::Result<()> {
+        let consumed = self.bits.pos / 8;
+        self.bits.data.drain(..consumed);
+        self.bits.pos -= consumed * 8;
+        self.bits.data.extend_from_slice(input);
+        self.run()
+    }
+
+    /// Comprueba que el stream ha terminado.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.run()?;
+        match self.state {
+            State::Done => Ok(()),
+            _ if self.window.written > self.max => Ok(()),
+            _ => Err(corrupt()),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        while self.window.written <= self.max {
+            let start = self.bits.pos;
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(Fail::NeedInput) => {
+                    self.bits.pos = start;
+                    break;
+                }
+                Err(Fail::Corrupt) => return Err(corrupt()),
+            }
+        }
+        if matches!(self.state, State::Done) && self.bits.data.len() > self.bits.pos.div_ceil(8) {
+            // Bytes after the last meta-block.
+            return Err(corrupt());
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8) {
+        self.window.push(byte);
+        self.out.push(byte);
+        self.remaining -= 1;
+    }
+
+    fn end_meta_block(&mut self) {
+        self.meta = None;
+        self.state = if self.last {
+            State::Done
+        } else {
+            State::Header
+        };
+    }
+
+    /// Avanza una unidad que se lee entera o no se lee: si faltan bytes, lo
+    /// leído se descarta y se repite cuando lleguen. `false` si no queda
+    /// nada que hacer.
+    fn step(&mut self) -> Step<bool> {
+        match self.state.clone() {
+            State::Stream => {
+                let bits = &mut self.bits;
+                let window_bits = if bits.read(1)? == 0 {
+                    16
+                } else {
+                    match bits.read(3)? {
+                        0 => match bits.read(3)? {
+                            0 => 17,
+                            1 => return Err(Fail::Corrupt),
+                            n => 8 + n,
+                        },
+                        n => 17 + n,
+                    }
+                };
+                self.window.size = 1 << window_bits;
+                self.state = State::Header;
+            }
+            State::Header => match read_header(&mut self.bits, &mut self.last)? {
+                Header::End => self.state = State::Done,
+                Header::Metadata(len) => self.state = State::Metadata(len),
+                Header::Uncompressed(len) => {
+                    self.remaining = len;
+                    self.state = State::Uncompressed(len);
+                }
+                Header::Compressed(len, meta) => {
+                    self.remaining = len;
+                    self.meta = Some(meta);
+                    self.state = State::Command;
+                }
+            },
+            State::Metadata(left) => {
+                let skip = left.min(self.bits.available() / 8);
+                if left > 0 && skip == 0 {
+                    return Err(Fail::NeedInput);
+                }
+                self.bits.pos += skip * 8;
+                self.state = match left - skip {
+                    0 => State::Header,
+                    left => State::Metadata(left),
+                };
+            }
+            State::Uncompressed(left) => {
+                let budget = self.max + 1 - self.window.written;
+                let take = left.min(self.bits.available() / 8).min(budget);
+                if take == 0 {
+                    return Err(Fail::NeedInput);
+                }
+                let from = self.bits.pos / 8;
+                self.bits.pos += take * 8;
+                for i in from..from + take {
+                    let byte = self.bits.data[i];
+                    self.emit(byte);
+                }
+                match left - take {
+                    0 => self.end_meta_block(),
+                    left => self.state = State::Uncompressed(left),
+                }
+            }
+            State::Command => {
+                if self.remaining == 0 {
+                    self.end_meta_block();
+                    return Ok(true);
+                }
+                let bits = &mut self.bits;
+                let meta = self.meta.as_mut().ok_or(Fail::Corrupt)?;
+                let position = meta.command_blocks.next(bits)?;
+                let symbol = u32::from(meta.commands[position.current].decode(bits)?);
+                let (insert_base, copy_base) = COMMAND_CELLS[(symbol >> 6) as usize];
+                let (insert, insert_extra) =
+                    INSERT_LENGTHS[(insert_base + (symbol >> 3 & 7)) as usize];
+                let (copy, copy_extra) = COPY_LENGTHS[(copy_base + (symbol & 7)) as usize];
+                let insert = insert + bits.read(insert_extra)?;
+                let copy = copy + bits.read(copy_extra)?;
+                meta.command_blocks.position = position;
+                self.state = State::Literals {
+                    insert: insert as usize,
+                    copy: copy as usize,
+                    implicit: symbol < 128,
+                };
+            }
+            State::Literals {
+                insert: 0,
+                copy,
+                implicit,
+            } => {
+                if self.remaining == 0 {
+                    self.end_meta_block();
+                } else {
+                    self.state = State::Distance { copy, implicit };
+                }
+            }
+            State::Literals {
+                insert,
+                copy,
+                implicit,
+            } => {
+                if self.remaining == 0 {
+                    return Err(Fail::Corrupt);
+                }
+                let bits = &mut self.bits;
+                let meta = self.meta.as_mut().ok_or(Fail::Corrupt)?;
+                let position = meta.literal_blocks.next(bits)?;
+                let (p1, p2) = match self.window.written {
+                    0 => (0, 0),
+                    1 => (self.window.back(1), 0),
+                    _ => (self.window.back(1), self.window.back(2)),
+                };
+                let context = literal_context(meta.modes[position.current], p1, p2);
+                let tree = meta.literal_map[64 * position.current + context];
+                let byte = meta.literals[usize::from(tree)].decode(bits)? as u8;
+                meta.literal_blocks.position = position;
+                self.state = State::Literals {
+                    insert: insert - 1,
+                    copy,
+                    implicit,
+                };
+                self.emit(byte);
+            }
+            State::Distance { copy, implicit } => {
+                let bits = &mut self.bits;
+                let meta = self.meta.as_mut().ok_or(Fail::Corrupt)?;
+                let (symbol, distance) = if implicit {
+                    (0, self.distances[0])
+                } else {
+                    let position = meta.distance_blocks.next(bits)?;
+                    let context = if copy > 4 { 3 } else { copy - 2 };
+                    let tree = meta.distance_map[4 * position.current + context];
+                    let symbol = u32::from(meta.distances[usize::from(tree)].decode(bits)?);
+                    let distance = distance(symbol, meta, &self.distances, bits)?;
+                    meta.distance_blocks.position = position;
+                    (symbol, distance)
+                };
+                let max_distance = (self.window.size - 16).min(self.window.written);
+                if distance > max_distance {
+                    let word = dictionary_word(copy, distance - max_distance - 1)?;
+                    if word.len() > self.remaining {
+                        return Err(Fail::Corrupt);
+                    }
+                    self.state = State::Word { word, at: 0 };
+                } else {
+                    if copy > self.remaining {
+                        return Err(Fail::Corrupt);
+                    }
+                    if symbol != 0 {
+                        self.distances.rotate_right(1);
+                        self.distances[0] = distance;
+                    }
+                    self.state = State::Copy {
+                        distance,
+                        left: copy,
+                    };
+                }
+            }
+            State::Copy { distance, mut left } => {
+                while left > 0 && self.window.written <= self.max {
+                    let byte = self.window.back(distance);
+                    self.emit(byte);
+                    left -= 1;
+                }
+                self.state = match left {
+                    0 => State::Command,
+                    left => State::Copy { distance, left },
+                };
+            }
+            State::Word { word, mut at } => {
+                while at < word.len() && self.window.written <= self.max {
+                    self.emit(word[at]);
+                    at += 1;
+                }
+                self.state = match at == word.len() {
+                    true => State::Command,
+                    false => State::Word { word, at },
+                };
+            }
+            State::Done => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "stream brotli inválido")
+}
+
+/// La distancia que indica `symbol` (§4).
+fn distance(symbol: u32, meta: &MetaBlock, last: &[usize; 4], bits: &mut Bits) -> Step<usize> {
+    let relative = |base: usize, delta: isize| match base.checked_add_signed(delta) {
+        Some(distance) if distance > 0 => Ok(distance),
+        _ => Err(Fail::Corrupt),
+    };
+    match symbol {
+        0..=3 => Ok(last[symbol as usize]),
+        4..=9 => relative(last[0], [-1, 1, -2, 2, -3, 3][symbol as usize - 4]),
+        10..=15 => relative(last[1], [-1, 1, -2, 2, -3, 3][symbol as usize - 10]),
+        _ if symbol < 16 + meta.direct => Ok(symbol as usize - 15),
+        _ => {
+            let code = symbol - meta.direct - 16;
+            let extra_bits = 1 + (code >> (meta.postfix + 1));
+            let high = code >> meta.postfix;
+            let low = code & ((1 << meta.postfix) - 1);
+            let offset = ((2 + (high & 1) as usize) << extra_bits) - 4;
+            let extra = bits.read(extra_bits)? as usize;
+            Ok(((offset + extra) << meta.postfix) + low as usize + meta.direct as usize + 1)
+        }
+    }
+}
+
+use Transform::{Identity, OmitFirst, OmitLast, UppercaseAll, UppercaseFirst};
+
+/// (prefijo, transformación, sufijo) según el identificador del apéndice B.
+#[rustfmt::skip]
+const TRANSFORMS: [(&[u8], Transform, &[u8]); 121] = [
+    (b"", Identity, b""),
+    (b"", Identity, b" "),
+    (b" ", Identity, b" "),
+    (b"", OmitFirst(1), b""),
+    (b"", UppercaseFirst, b" "),
+    (b"", Identity, b" the "),
+    (b" ", Identity, b""),
+    (b"s ", Identity, b" "),
+    (b"", Identity, b" of "),
+    (b"", UppercaseFirst, b""),
+    (b"", Identity, b" and "),
+    (b"", OmitFirst(2), b""),
+    (b"", OmitLast(1), b""),
+    (b", ", Identity, b" "),
+    (b"", Identity, b", "),
+    (b" ", UppercaseFirst, b" "),
+    (b"", Identity, b" in "),
+    (b"", Identity, b" to "),
+    (b"e ", Identity, b" "),
+    (b"", Identity, b"\""),
+    (b"", Identity, b"."),
+    (b"", Identity, b"\">"),
+    (b"", Identity, b"\x0a"),
+    (b"", OmitLast(3), b""),
+    (b"", Identity, b"]"),
+    (b"", Identity, b" for "),
+    (b"", OmitFirst(3), b""),
+    (b"", OmitLast(2), b""),
+    (b"", Identity, b" a "),
+    (b"", Identity, b" that "),
+    (b" ", UppercaseFirst, b""),
+    (b"", Identity, b". "),
+    (b".", Identity, b""),
+    (b" ", Identity, b", "),
+    (b"", OmitFirst(4), b""),
+    (b"", Identity, b" with "),
+    (b"", Identity, b"'"),
+    (b"", Identity, b" from "),
+    (b"", Identity, b" by "),
+    (b"", OmitFirst(5), b""),
+    (b"", OmitFirst(6), b""),
+    (b" the ", Identity, b""),
+    (b"", OmitLast(4), b""),
+    (b"", Identity, b". The "),
+    (b"", UppercaseAll, b""),
+    (b"", Identity, b" on "),
+    (b"", Identity, b" as "),
+    (b"", Identity, b" is "),
+    (b"", OmitLast(7), b""),
+    (b"", OmitLast(1), b"ing "),
+    (b"", Identity, b"\x0a\x09"),
+    (b"", Identity, b":"),
+    (b" ", Identity, b". "),
+    (b"", Identity, b"ed "),
+    (b"", OmitFirst(9), b""),
+    (b"", OmitFirst(7), b""),
+    (b"", OmitLast(6), b""),
+    (b"", Identity, b"("),
+    (b"", UppercaseFirst, b", "),
+    (b"", OmitLast(8), b""),
+    (b"", Identity, b" at "),
+    (b"", Identity, b"ly "),
+    (b" the ", Identity, b" of "),
+    (b"", OmitLast(5), b""),
+    (b"", OmitLast(9), b""),
+    (b" ", UppercaseFirst, b", "),
+    (b"", UppercaseFirst, b"\""),
+    (b".", Identity, b"("),
+    (b"", UppercaseAll, b" "),
+    (b"", UppercaseFirst, b"\">"),
+    (b"", Identity, b"=\""),
+    (b" ", Identity, b"."),
+    (b".com/", Identity, b""),
+    (b" the ", Identity, b" of the "),
+    (b"", UppercaseFirst, b"'"),
+    (b"", Identity, b". This "),
+    (b"", Identity, b","),
+    (b".", Identity, b" "),
+    (b"", UppercaseFirst, b"("),
+    (b"", UppercaseFirst, b"."),
+    (b"", Identity, b" not "),
+    (b" ", Identity, b"=\""),
+    (b"", Identity, b"er "),
+    (b" ", UppercaseAll, b" "),
+    (b"", Identity, b"al "),
+    (b" ", UppercaseAll, b""),
+    (b"", Identity, b"='"),
+    (b"", UppercaseAll, b"\""),
+    (b"", UppercaseFirst, b". "),
+    (b" ", Identity, b"("),
+    (b"", Identity, b"ful "),
+    (b" ", UppercaseFirst, b". "),
+    (b"", Identity, b"ive "),
+    (b"", Identity, b"less "),
+    (b"", UppercaseAll, b"'"),
+    (b"", Identity, b"est "),
+    (b" ", UppercaseFirst, b"."),
+    (b"", UppercaseAll, b"\">"),
+    (b" ", Identity, b"='"),
+    (b"", UppercaseFirst, b","),
+    (b"", Identity, b"ize "),
+    (b"", UppercaseAll, b"."),
+    (b"\xc2\xa0", Identity, b""),
+    (b" ", Identity, b","),
+    (b"", UppercaseFirst, b"=\""),
+    (b"", UppercaseAll, b"=\""),
+    (b"", Identity, b"ous "),
+    (b"", UppercaseAll, b", "),
+    (b"", UppercaseFirst, b"='"),
+    (b" ", UppercaseFirst, b","),
+    (b" ", UppercaseAll, b"=\""),
+    (b" ", UppercaseAll, b", "),
+    (b"", UppercaseAll, b","),
+    (b"", UppercaseAll, b"("),
+    (b"", UppercaseAll, b". "),
+    (b" ", UppercaseAll, b"."),
+    (b"", UppercaseAll, b"='"),
+    (b" ", UppercaseAll, b". "),
+    (b" ", UppercaseFirst, b"=\""),
+    (b" ", UppercaseAll, b"='"),
+    (b" ", UppercaseFirst, b"='"),
+];
+
+/// Contexto UTF-8 según el último byte.
+#[rustfmt::skip]
+const UTF8_P1: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 4, 0, 0, 4, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    8, 12, 16, 12, 12, 20, 12, 16, 24, 28, 12, 12, 32, 12, 36, 12,
+    44, 44, 44, 44, 44, 44, 44, 44, 44, 44, 32, 32, 24, 40, 28, 12,
+    12, 48, 52, 52, 52, 48, 52, 52, 52, 48, 52, 52, 52, 52, 52, 48,
+    52, 52, 52, 52, 52, 48, 52, 52, 52, 52, 52, 24, 12, 28, 12, 12,
+    12, 56, 60, 60, 60, 56, 60, 60, 60, 56, 60, 60, 60, 60, 60, 56,
+    60, 60, 60, 60, 60, 56, 60, 60, 60, 60, 60, 24, 12, 28, 12, 0,
+    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
+    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
+    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
+    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
+    2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3,
+    2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3,
+    2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3,
+    2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3,
+];
+
+/// Contexto UTF-8 según el penúltimo byte.
+#[rustfmt::skip]
+const UTF8_P2: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1,
+    1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1,
+    1, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 1, 1, 1, 1, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Vectors written by the reference encoder (libbrotli 1.2) unless noted.
+
+    /// Words from the static dictionary, some of them transformed.
+    const INFORMATION: &[u8] = &[
+        0x1b, 0x3b, 0x00, 0xf8, 0x8d, 0x54, 0xb5, 0xbf, 0x0a, 0x73, 0x4b, 0x10, 0x16, 0xb2, 0x7e,
+        0x04, 0x18, 0x61, 0xc2, 0xd1, 0x4c, 0x70, 0xf3, 0x92, 0x4e, 0xda, 0x43, 0x25, 0x92, 0x76,
+        0xcc, 0x39, 0x00,
+    ];
+    const UKRAINE: &[u8] = &[
+        0x1b, 0x40, 0x00, 0xe0, 0x8d, 0x94, 0xab, 0x1b, 0xe3, 0x9e, 0x9e, 0xac, 0xc5, 0x17, 0xb3,
+        0x4e, 0xc9, 0xd6, 0x05, 0xc1, 0x98, 0x40, 0x12, 0x20, 0xb0, 0x01, 0x27, 0x42, 0x95, 0xd9,
+        0xe2, 0xf7, 0x89, 0x65, 0xdb, 0x59, 0xab, 0xf5, 0x9c, 0x60, 0x69, 0x09, 0xe6, 0xfb, 0xa9,
+        0x0a, 0x46, 0x49, 0xe2, 0x8e, 0xa8, 0x02,
+    ];
+    const TIMEDOWN: &[u8] = &[
+        0x1b, 0x37, 0x00, 0x30, 0x14, 0xa1, 0xd2, 0x9d, 0x72, 0xa4, 0xcd, 0x5b, 0x44, 0x89, 0x16,
+        0xdd, 0x3a, 0x32, 0xe4, 0x80, 0xfd, 0x9a, 0x41, 0x5b, 0x40, 0xf5, 0x80, 0xc7, 0x47, 0x97,
+        0x2c, 0x20, 0xab, 0xfb, 0xb6, 0xee, 0x02, 0x40, 0x56, 0x70, 0xfa, 0xe8, 0x50, 0x2f, 0x1b,
+        0x17, 0xaa,
+    ];
+
+    /// Incompressible for the encoder, which stores it uncompressed.
+    const FOX: &[u8] = &[
+        0x0b, 0x15, 0x80, 0x54, 0x68, 0x65, 0x20, 0x71, 0x75, 0x69, 0x63, 0x6b, 0x20, 0x62, 0x72,
+        0x6f, 0x77, 0x6e, 0x20, 0x66, 0x6f, 0x78, 0x20, 0x6a, 0x75, 0x6d, 0x70, 0x73, 0x20, 0x6f,
+        0x76, 0x65, 0x72, 0x20, 0x74, 0x68, 0x65, 0x20, 0x6c, 0x61, 0x7a, 0x79, 0x20, 0x64, 0x6f,
+        0x67, 0x03,
+    ];
+
+    /// Three flushed meta-blocks, the second one copying from the first,
+    /// and a metadata block before the third.
+    const MULTI: &[u8] = &[
+        0x0b, 0x1e, 0x00, 0x80, 0x9c, 0x07, 0x76, 0x0c, 0x99, 0x06, 0x5a, 0x58, 0xc0, 0x69, 0xbf,
+        0x16, 0xd9, 0x83, 0xdb, 0x5a, 0x67, 0x68, 0xad, 0xc3, 0xd2, 0xd5, 0x37, 0x45, 0xc2, 0xe8,
+        0x40, 0x19, 0x27, 0x02, 0xb3, 0xd7, 0xeb, 0x3c, 0x81, 0x37, 0x66, 0xd3, 0xf9, 0x20, 0xce,
+        0xc3, 0x97, 0x3d, 0x59, 0x36, 0x83, 0x52, 0x45, 0x18, 0x72, 0xf3, 0xdd, 0x00, 0x08, 0x02,
+        0x80, 0xdf, 0x71, 0xe0, 0xc6, 0xd2, 0xe6, 0x7c, 0x89, 0xe9, 0xa5, 0x48, 0x18, 0x5a, 0xb2,
+        0xe8, 0xf4, 0x65, 0xd7, 0x5e, 0x1f, 0x4c, 0xe4, 0xc0, 0x31, 0xa1, 0xb6, 0xf8, 0xe4, 0x6f,
+        0x16, 0x98, 0x10, 0xc4, 0x17, 0x6f, 0x32, 0x89, 0x92, 0x59, 0xb6, 0x01, 0x16, 0x02, 0x6d,
+        0x65, 0x74, 0x61, 0x64, 0x61, 0x74, 0x6f, 0x73, 0xe0, 0x00, 0x08, 0x7b, 0x22, 0x6d, 0x6f,
+        0x64, 0x65, 0x6c, 0x22, 0x3a, 0x22, 0x67, 0x70, 0x74, 0x22, 0x2c, 0x22, 0x73, 0x74, 0x72,
+        0x65, 0x61, 0x6d, 0x22, 0x3a, 0x74, 0x72, 0x75, 0x65, 0x7d, 0x03,
+    ];
+
+    /// For each window size, `far_copy` at that size.
+    const WINDOWS: [(u32, &[u8]); 15] = [
+        (
+            10,
+            &[
+                0xa1, 0xe0, 0x20, 0x00, 0x7f, 0xa4, 0x64, 0x6d, 0x8e, 0x8d, 0x50, 0xe9, 0x31, 0x04,
+                0xe5, 0xb6, 0xa5, 0xf8, 0xb9, 0xfe, 0xe4, 0x82, 0x30, 0x44, 0xa7, 0xc2, 0x06, 0x1c,
+                0x38, 0xc7, 0xa1, 0x07, 0x80, 0x83, 0xdb, 0x41, 0x68, 0x83, 0x8b, 0x86, 0xb3, 0x3b,
+                0x19, 0xb4, 0x75, 0x3e, 0xba, 0xa8, 0xd9, 0xf3, 0x21, 0xd7, 0xb9, 0x0d, 0x59, 0xce,
+                0x69, 0xe7, 0xbb, 0x1c, 0x74, 0x92, 0x03,
+            ],
+        ),
+        (
+            11,
+            &[
+                0xb1, 0xe0, 0x40, 0xc0, 0x7f, 0xa4, 0x64, 0x6d, 0x8e, 0x15, 0x43, 0x90, 0xb9, 0x21,
+                0x4a, 0x56, 0xdb, 0x02, 0x27, 0x48, 0xc8, 0x7a, 0x46, 0x19, 0x63, 0x62, 0x21, 0x70,
+                0x4e, 0xe6, 0xdb, 0x99, 0x42, 0x58, 0xfb, 0xa1, 0x83, 0x82, 0xd5, 0x2e, 0x72, 0x57,
+                0x9e, 0x51, 0x51, 0x45, 0xdd, 0xfa, 0x8c, 0xeb, 0xe1, 0xc4, 0x95, 0x01,
+            ],
+        ),
+        (
+            12,
+            &[
+                0xc1, 0xe0, 0x80, 0xc0, 0x7f, 0xa4, 0x64, 0x6d, 0x8e, 0x15, 0x43, 0x90, 0xb9, 0x21,
+                0x4a, 0x56, 0xdb, 0x02, 0x27, 0x48, 0xc8, 0x7a, 0x46, 0x19, 0x73, 0x62, 0x21, 0x70,
+                0x4f, 0xe6, 0x3b, 0x00, 0x70, 0xa6, 0x10, 0xd6, 0x7e, 0xe8, 0xa0, 0x60, 0xb5, 0x8b,
+                0xdc, 0x95, 0x67, 0x54, 0x54, 0x51, 0xb7, 0x3e, 0xe3, 0x7a, 0x38, 0x71, 0xe5,
+            ],
+        ),
+        (
+            13,
+            &[
+                0xd1, 0xe0, 0x00, 0xc1, 0x7f, 0xa4, 0x64, 0x6d, 0x8e, 0x15, 0x43, 0x90, 0xb9, 0x21,
+                0x4a, 0x56, 0xdb, 0x02, 0x27, 0x48, 0xc8, 0x7a, 0x46, 0x19, 0x73, 0x62, 0x21, 0x70,
+                0x50, 0xe6, 0xbb, 0x00, 0x70, 0xa6, 0x10, 0xd6, 0x7e, 0xe8, 0xa0, 0x60, 0xb5, 0x8b,
+                0xdc, 0x95, 0x67, 0x54, 0x54, 0x51, 0xb7, 0x3e, 0xe3, 0x7a, 0x38, 0x71, 0xe5, 0x01,
+            ],
+        ),
+        (
+            14,
+            &[
+                0xe1, 0xe0, 0x00, 0xc2, 0x7f, 0xa4, 0x64, 0x6d, 0x8e, 0x15, 0x43, 0x90, 0xb9, 0x21,
+                0x4a, 0x56, 0xdb, 0x02, 0x27, 0x48, 0xc8, 0x7a, 0x46, 0x19, 0x73, 0x62, 0x21, 0x70,
+                0x51, 0xe6, 0xbb, 0x01, 0x70, 0xa6, 0x10, 0xd6, 0x7e, 0xe8, 0xa0, 0x60, 0xb5, 0x8b,
+                0xdc, 0x95, 0x67, 0x54, 0x54, 0x51, 0xb7, 0x3e, 0xe3, 0x7a, 0x38, 0x71, 0xe5, 0x03,
+            ],
+        ),
+        (
+            15,
+            &[
+                0xf1, 0xe0, 0x00, 0xc4, 0x7f, 0xa4, 0x64, 0x6d, 0x8e, 0x15, 0x43, 0x90, 0xb9, 0x21,
+                0x4a, 0x56, 0xdb, 0x02, 0x27, 0x48, 0xc8, 0x7a, 0x46, 0x19, 0x73, 0x62, 0x21, 0x70,
+                0x52, 0xe6, 0xbb, 0x03, 0x70, 0xa6, 0x10, 0xd6, 0x7e, 0xe8, 0xa0, 0x60, 0xb5, 0x8b,
+                0xdc, 0x95, 0x67, 0x54, 0x54, 0x51, 0xb7, 0x3e, 0xe3, 0x7a, 0x38, 0x71, 0xe5, 0x07,
+            ],
+        ),
+        (
+            16,
+            &[
+                0x8a, 0x03, 0x20, 0xf0, 0x1f, 0x29, 0x59, 0x9b, 0x63, 0xc5, 0x10, 0x64, 0x6e, 0x88,
+                0x92, 0xd5, 0xb6, 0xc0, 0x09, 0x12, 0xb2, 0x9e, 0x51, 0xc6, 0x9c, 0x58, 0x08, 0xdc,
+                0x94, 0xf9, 0xee, 0x01, 0x9c, 0x29, 0x84, 0xb5, 0x1f, 0x3a, 0x28, 0x58, 0xed, 0x22,
+                0x77, 0xe5, 0x19, 0x15, 0x55, 0xd4, 0xad, 0xcf, 0xb8, 0x1e, 0x4e, 0x5c, 0xf9, 0x03,
+            ],
+        ),
+        (
+            17,
+            &[
+                0x81, 0xe2, 0x00, 0x10, 0xfc, 0x47, 0x4a, 0xd6, 0xe6, 0x58, 0x31, 0x04, 0x99, 0x1b,
+                0xa2, 0x64, 0xb5, 0x2d, 0x70, 0x82, 0x84, 0xac, 0x67, 0x94, 0x31, 0x27, 0x16, 0x02,
+                0x47, 0x65, 0xbe, 0xfb, 0x00, 0x67, 0x0a, 0x61, 0xed, 0x87, 0x0e, 0x0a, 0x56, 0xbb,
+                0xc8, 0x5d, 0x79, 0x46, 0x45, 0x15, 0x75, 0xeb, 0x33, 0xae, 0x87, 0x13, 0x57, 0xfe,
+                0x01,
+            ],
+        ),
+        (
+            18,
+            &[
+                0x53, 0x1c, 0x00, 0x84, 0xff, 0x48, 0xc9, 0xda, 0x1c, 0x2b, 0x86, 0x20, 0x73, 0x43,
+                0x94, 0xac, 0xb6, 0x05, 0x4e, 0x90, 0x90, 0xf5, 0x8c, 0x32, 0xe6, 0xc4, 0x42, 0xe0,
+                0xaa, 0xcc, 0x77, 0x3f, 0xe0, 0x4c, 0x21, 0xac, 0xfd, 0xd0, 0x41, 0xc1, 0x6a, 0x17,
+                0xb9, 0x2b, 0xcf, 0xa8, 0xa8, 0xa2, 0x6e, 0x7d, 0xc6, 0xf5, 0x70, 0xe2, 0xca, 0x7f,
+            ],
+        ),
+        (
+            19,
+            &[
+                0x55, 0x1c, 0x00, 0x88, 0xff, 0x48, 0xc9, 0xda, 0x1c, 0x2b, 0x86, 0x20, 0x73, 0x43,
+                0x94, 0xac, 0xb6, 0x05, 0x4e, 0x90, 0x90, 0xf5, 0x8c, 0x32, 0xe6, 0xc4, 0x42, 0xe0,
+                0xac, 0xcc, 0x77, 0x7f, 0xe0, 0x4c, 0x21, 0xac, 0xfd, 0xd0, 0x41, 0xc1, 0x6a, 0x17,
+                0xb9, 0x2b, 0xcf, 0xa8, 0xa8, 0xa2, 0x6e, 0x7d, 0xc6, 0xf5, 0x70, 0xe2, 0xca, 0xff,
+            ],
+        ),
+        (
+            20,
+            &[
+                0x97, 0x1c, 0x00, 0x10, 0xf8, 0x8f, 0x94, 0xac, 0xcd, 0xb1, 0x62, 0x08, 0x32, 0x37,
+                0x44, 0xc9, 0x6a, 0x5b, 0xe0, 0x04, 0x09, 0x59, 0xcf, 0x28, 0x63, 0x4e, 0x2c, 0x04,
+                0xee, 0xca, 0x7c, 0xf7, 0x0f, 0xce, 0x14, 0xc2, 0xda, 0x0f, 0x1d, 0x14, 0xac, 0x76,
+                0x91, 0xbb, 0xf2, 0x8c, 0x8a, 0x2a, 0xea, 0xd6, 0x67, 0x5c, 0x0f, 0x27, 0xae, 0xfc,
+                0x1f,
+            ],
+        ),
+        (
+            21,
+            &[
+                0x99, 0x1c, 0x00, 0x20, 0xf8, 0x8f, 0x94, 0xac, 0xcd, 0xb1, 0x62, 0x08, 0x32, 0x37,
+                0x44, 0xc9, 0x6a, 0x5b, 0xe0, 0x04, 0x09, 0x59, 0xcf, 0x28, 0x63, 0x4e, 0x2c, 0x04,
+                0x0e, 0xcb, 0x7c, 0xf7, 0x1f, 0xce, 0x14, 0xc2, 0xda, 0x0f, 0x1d, 0x14, 0xac, 0x76,
+                0x91, 0xbb, 0xf2, 0x8c, 0x8a, 0x2a, 0xea, 0xd6, 0x67, 0x5c, 0x0f, 0x27, 0xae, 0xfc,
+                0x3f,
+            ],
+        ),
+        (
+            22,
+            &[
+                0x9b, 0x1c, 0x00, 0x40, 0xf8, 0x8f, 0x94, 0xac, 0xcd, 0xb1, 0x62, 0x08, 0x32, 0x37,
+                0x44, 0xc9, 0x6a, 0x5b, 0xe0, 0x04, 0x09, 0x59, 0xcf, 0x28, 0x63, 0x4e, 0x2c, 0x04,
+                0x2e, 0xcb, 0x7c, 0xf7, 0x3f, 0xce, 0x14, 0xc2, 0xda, 0x0f, 0x1d, 0x14, 0xac, 0x76,
+                0x91, 0xbb, 0xf2, 0x8c, 0x8a, 0x2a, 0xea, 0xd6, 0x67, 0x5c, 0x0f, 0x27, 0xae, 0xfc,
+                0x7f,
+            ],
+        ),
+        (
+            23,
+            &[
+                0x9d, 0x1c, 0x00, 0x80, 0xf8, 0x8f, 0x94, 0xac, 0xcd, 0xb1, 0x62, 0x08, 0x32, 0x37,
+                0x44, 0xc9, 0x6a, 0x5b, 0xe0, 0x04, 0x09, 0x59, 0xcf, 0x28, 0x63, 0x4e, 0x2c, 0x04,
+                0x4e, 0xcb, 0x7c, 0xf7, 0x7f, 0xce, 0x14, 0xc2, 0xda, 0x0f, 0x1d, 0x14, 0xac, 0x76,
+                0x91, 0xbb, 0xf2, 0x8c, 0x8a, 0x2a, 0xea, 0xd6, 0x67, 0x5c, 0x0f, 0x27, 0xae, 0xfc,
+                0xff,
+            ],
+        ),
+        (
+            24,
+            &[
+                0xcf, 0xff, 0xff, 0x7f, 0xf8, 0x8f, 0x94, 0xac, 0xcd, 0xb1, 0x62, 0x08, 0x32, 0x37,
+                0x44, 0xc9, 0x6a, 0x5b, 0xe0, 0x04, 0x09, 0x59, 0xcf, 0x28, 0x61, 0x4e, 0x2c, 0x04,
+                0x6e, 0xcb, 0x7c, 0xf7, 0xff, 0xce, 0x14, 0xc2, 0xda, 0x0f, 0x1d, 0x14, 0xac, 0x76,
+                0x91, 0xbb, 0xf2, 0x8c, 0x8a, 0x2a, 0xea, 0xd6, 0x67, 0x5c, 0x0f, 0x27, 0x2c, 0xff,
+                0xff, 0xe0, 0x00, 0xc0, 0x2f, 0x01, 0x10, 0x44, 0x04, 0x00, 0x07,
+            ],
+        ),
+    ];
+
+    /// Written by hand: 16-bit window; metadata block with `ignorado`;
+    /// uncompressed blocks `hola, ` and `mundo`; empty last block.
+    const HAND: &[u8] = &[
+        0xac, 0x03, 0x69, 0x67, 0x6e, 0x6f, 0x72, 0x61, 0x64, 0x6f, 0x28, 0x00, 0x08, 0x68, 0x6f,
+        0x6c, 0x61, 0x2c, 0x20, 0x20, 0x00, 0x08, 0x6d, 0x75, 0x6e, 0x64, 0x6f, 0x03,
+    ];
+
+    /// 8 MiB of zeros.
+    const BOMB: &[u8] = &[
+        0x9f, 0xff, 0xff, 0x7f, 0xf8, 0x27, 0x00, 0xe2, 0xb1, 0x40, 0x20, 0xf7, 0xfe, 0x0f,
+    ];
+
+    fn decode(input: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        let mut decoder = BrotliDecoder::new(max);
+        decoder.write_all(input)?;
+        decoder.try_finish()?;
+        Ok(std::mem::take(decoder.get_mut()))
+    }
+
+    fn decode_bytewise(input: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        let mut decoder = BrotliDecoder::new(max);
+        let mut out = Vec::new();
+        for byte in input {
+            decoder.write_all(std::slice::from_ref(byte))?;
+            out.append(decoder.get_mut());
+        }
+        decoder.try_finish()?;
+        out.append(decoder.get_mut());
+        Ok(out)
+    }
+
+    /// A header, zeros up to the farthest distance the window allows, and
+    /// the header again.
+    fn far_copy(window_bits: u32) -> Vec<u8> {
+        let head = b"Cabecera que se repite al borde de la ventana";
+        let mut plain = head.to_vec();
+        plain.resize((1 << window_bits) - 16, 0);
+        plain.extend_from_slice(head);
+        plain
+    }
+
+    fn multi_plain() -> Vec<u8> {
+        [
+            &br#"{"model":"gpt","messages":[{"role":"user","content":"hola"}]}"#[..],
+            br#"{"model":"gpt","messages":[{"role":"assistant","content":"hola"}]}"#,
+            br#"{"model":"gpt","stream":true}"#,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_decodes_dictionary_words_and_transforms() {
+        let cases: [(&[u8], &str); 3] = [
+            (
+                INFORMATION,
+                "Information about the Government, THE WORLD and <div class=\"",
+            ),
+            (
+                UKRAINE,
+                "Ukraine. Examples: beautifully, Understanding; history's NATIONAL",
+            ),
+            (
+                TIMEDOWN,
+                "the timedown of the life. Left back, lifeless, DOWNLOADS",
+            ),
+        ];
+        for (br, plain) in cases {
+            assert_eq!(decode(br, 1024).unwrap(), plain.as_bytes());
+            assert_eq!(decode_bytewise(br, 1024).unwrap(), plain.as_bytes());
+        }
+
+        // The first word of length 4 through transforms 0, 3, 9, 44 and 54.
+        let word = |transform: usize| dictionary_word(4, transform << 10).ok().unwrap();
+        assert_eq!(word(0), b"time");
+        assert_eq!(word(3), b"ime");
+        assert_eq!(word(9), b"Time");
+        assert_eq!(word(44), b"TIME");
+        assert_eq!(word(54), b"");
+        assert_eq!(word(5), b"time the ");
+        assert!(dictionary_word(4, 121 << 10).is_err());
+        assert!(dictionary_word(3, 0).is_err());
+        assert!(dictionary_word(25, 0).is_err());
+
+        // Multi-byte characters as the RFC uppercases them.
+        let mut two = "ж".as_bytes().to_vec();
+        assert_eq!(uppercase(&mut two), 2);
+        assert_eq!(two, "Ж".as_bytes());
+        let mut three = [0xe4, 0xb8, 0xad];
+        assert_eq!(uppercase(&mut three), 3);
+        assert_eq!(three, [0xe4, 0xb8, 0xa8]);
+    }
+
+    #[test]
+    fn test_decodes_uncompressed_and_metadata_meta_blocks() {
+        assert_eq!(decode(HAND, 1024).unwrap(), b"hola, mundo");
+        assert_eq!(decode_bytewise(HAND, 1024).unwrap(), b"hola, mundo");
+        let fox = b"The quick brown fox jumps over the lazy dog";
+        assert_eq!(decode(FOX, 1024).unwrap(), fox);
+        assert_eq!(decode_bytewise(FOX, 1024).unwrap(), fox);
+    }
+
+    #[test]
+    fn test_decodes_several_meta_blocks() {
+        assert_eq!(decode(MULTI, 1024).unwrap(), multi_plain());
+        assert_eq!(decode_bytewise(MULTI, 1024).unwrap(), multi_plain());
+        // Split at every point.
+        for at in 0..MULTI.len() {
+            let mut decoder = BrotliDecoder::new(1024);
+            decoder.write_all(&MULTI[..at]).unwrap();
+            decoder.write_all(&MULTI[at..]).unwrap();
+            decoder.try_finish().unwrap();
+            assert_eq!(*decoder.get_mut(), multi_plain());
+        }
+    }
+
+    #[test]
+    fn test_decodes_every_window_size() {
+        for (window_bits, br) in WINDOWS {
+            let plain = far_copy(window_bits);
+            let mut decoder = BrotliDecoder::new(plain.len());
+            decoder.write_all(br).unwrap();
+            decoder.try_finish().unwrap();
+            assert_eq!(decoder.window.size, 1 << window_bits);
+            assert!(*decoder.get_mut() == plain, "ventana de {window_bits} bits");
+        }
+    }
+
+    #[test]
+    fn test_rejects_truncated_input() {
+        let (_, small_window) = WINDOWS[0];
+        for br in [
+            INFORMATION,
+            UKRAINE,
+            TIMEDOWN,
+            FOX,
+            MULTI,
+            HAND,
+            small_window,
+        ] {
+            for len in 0..br.len() {
+                assert!(
+                    decode(&br[..len], 1 << 20).is_err(),
+                    "{len} de {}",
+                    br.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_corrupt_input_without_panicking() {
+        // Reserved window size.
+        assert!(decode(&[0x11, 0x03], 1024).is_err());
+        // Bytes after the last meta-block.
+        assert!(decode(&[HAND, &[0]].concat(), 1024).is_err());
+        // Reserved bit of the metadata block set.
+        let mut reserved = HAND.to_vec();
+        reserved[0] |= 0x10;
+        assert!(decode(&reserved, 1024).is_err());
+        // Padding after an uncompressed header that is not zero.
+        let mut padding = HAND.to_vec();
+        padding[10] |= 0x80;
+        assert!(decode(&padding, 1024).is_err());
+        assert!(decode(b"no es brotli", 1024).is_err());
+
+        // Any flipped bit may decode to something else, but never panics
+        // nor goes past the limit.
+        for br in [INFORMATION, UKRAINE, TIMEDOWN, FOX, MULTI, HAND, BOMB] {
+            for bit in 0..br.len() * 8 {
+                let mut flipped = br.to_vec();
+                flipped[bit / 8] ^= 1 << (bit % 8);
+                if let Ok(out) = decode(&flipped, 4096) {
+                    assert!(out.len() <= 4097);
+                }
+            }
+        }
+        // Nor does garbage.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..2000 {
+            let garbage: Vec<u8> = (0..64)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            if let Ok(out) = decode(&garbage, 4096) {
+                assert!(out.len() <= 4097);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stops_one_byte_past_the_limit() {
+        let max = 256 << 10;
+        let mut decoder = BrotliDecoder::new(max);
+        decoder.write_all(BOMB).unwrap();
+        assert_eq!(decoder.get_mut().len(), max + 1);
+        assert!(decoder.get_mut().iter().all(|byte| *byte == 0));
+        assert!(decoder.try_finish().is_ok());
+
+        // Also inside uncompressed blocks and dictionary words.
+        assert_eq!(decode(HAND, 3).unwrap(), b"hola");
+        assert_eq!(decode(INFORMATION, 14).unwrap(), b"Information abo");
+        let (window_bits, br) = WINDOWS[14];
+        let plain = far_copy(window_bits);
+        assert_eq!(decode(br, 100).unwrap(), plain[..101]);
+    }
+}
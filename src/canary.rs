@@ -0,0 +1,447 @@
+//! Canary ponderado hacia un proxy padre nuevo.
+//!
+//! `[canary]` manda `weight` % de las peticiones que casan con `hosts` por
+//! su propio proxy padre; el resto sigue el camino que tendría sin la
+//! sección (PAC, `[upstream_proxy]` o directo), que hace de estable. Cada
+//! asignación se registra en el log con el lado elegido. De los dos lados se
+//! guardan los resultados de la última ventana: cuenta como error un fallo
+//! del proxy al llegar al destino o una respuesta 5xx, y la latencia es la
+//! de las cabeceras de respuesta (la apertura, en un túnel). Si el canary
+//! supera al estable por más del margen en tasa de errores o en p95, su
+//! peso baja a cero, se avisa al webhook y así queda hasta que se
+//! restablezca desde `POST /api/canary/reset`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use rand::Rng;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::settings::CanarySettings;
+use crate::upstream::UpstreamProxy;
+
+/// Resultados que se guardan de cada lado, como máximo.
+const MAX_SAMPLES: usize = 10_000;
+
+/// Lado de una asignación.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arm {
+    Stable,
+    Canary,
+}
+
+impl Arm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Arm::Stable => "stable",
+            Arm::Canary => "canary",
+        }
+    }
+}
+
+/// Resultados de un lado en la ventana.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ArmStats {
+    pub requests: usize,
+    pub error_rate: f64,
+    pub p95_ms: Option<f64>,
+}
+
+/// Por qué y cuándo se retiró el canary.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rollback {
+    /// `error_rate` o `p95_latency`.
+    pub reason: &'static str,
+    pub at: u64,
+    pub canary: ArmStats,
+    pub stable: ArmStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanaryError {
+    InvalidWeight,
+    /// El canary se retiró y hay que restablecerlo antes de darle peso.
+    RolledBack,
+}
+
+impl fmt::Display for CanaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CanaryError::InvalidWeight => write!(f, "el peso debe estar entre 0 y 100"),
+            CanaryError::RolledBack => write!(
+                f,
+                "el canary se retiró automáticamente; restablézcalo con POST /api/canary/reset"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    error: bool,
+    latency: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Window(VecDeque<Sample>);
+
+impl Window {
+    fn push(&mut self, sample: Sample, window: Duration) {
+        if self.0.len() == MAX_SAMPLES {
+            self.0.pop_front();
+        }
+        self.0.push_back(sample);
+        self.prune(sample.at, window);
+    }
+
+    fn prune(&mut self, now: Instant, window: Duration) {
+        while self
+            .0
+            .front()
+            .is_some_and(|sample| now.saturating_duration_since(sample.at) > window)
+        {
+            self.0.pop_front();
+        }
+    }
+
+    fn stats(&self) -> ArmStats {
+        let requests = self.0.len();
+        if requests == 0 {
+            return ArmStats {
+                requests,
+                error_rate: 0.0,
+                p95_ms: None,
+            };
+        }
+        let errors = self.0.iter().filter(|sample| sample.error).count();
+        let mut latencies: Vec<Duration> = self.0.iter().map(|sample| sample.latency).collect();
+        latencies.sort_unstable();
+        // Nearest rank.
+        let rank = (requests * 95).div_ceil(100).max(1);
+        ArmStats {
+            requests,
+            error_rate: errors as f64 / requests as f64,
+            p95_ms: Some(latencies[rank - 1].as_secs_f64() * 1000.0),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    weight: f64,
+    rollback: Option<Rollback>,
+    stable: Window,
+    canary: Window,
+    assigned: [u64; 2],
+}
+
+/// Estado del canary, tal como lo muestra `GET /api/canary`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CanarySnapshot {
+    pub parent: String,
+    pub weight: f64,
+    pub configured_weight: f64,
+    pub hosts: Vec<String>,
+    pub window_secs: u64,
+    pub stable: ArmStats,
+    pub canary: ArmStats,
+    pub assigned_stable: u64,
+    pub assigned_canary: u64,
+    pub rollback: Option<Rollback>,
+}
+
+pub struct Canary {
+    settings: CanarySettings,
+    parent: UpstreamProxy,
+    state: Mutex<State>,
+    client: Client<HttpConnector>,
+    webhook: Option<Uri>,
+}
+
+impl Canary {
+    pub fn new(settings: CanarySettings) -> anyhow::Result<Self> {
+        let webhook = match settings.webhook() {
+            Some(url) => {
+                let url: Uri = url.parse()?;
+                if url.scheme_str() != Some("http") {
+                    anyhow::bail!("el webhook del canary debe usar una URL http://: {url}");
+                }
+                Some(url)
+            }
+            None => None,
+        };
+        Ok(Self {
+            parent: UpstreamProxy::new(settings.parent()),
+            state: Mutex::new(State {
+                weight: settings.weight(),
+                rollback: None,
+                stable: Window::default(),
+                canary: Window::default(),
+                assigned: [0; 2],
+            }),
+            client: Client::new(),
+            webhook,
+            settings,
+        })
+    }
+
+    pub fn settings(&self) -> &CanarySettings {
+        &self.settings
+    }
+
+    /// El proxy padre del canary.
+    pub fn parent(&self) -> &UpstreamProxy {
+        &self.parent
+    }
+
+    /// Peso en marcha: el configurado salvo retirada o cambio desde la API.
+    pub fn weight(&self) -> f64 {
+        self.state.lock().expect("estado del canary").weight
+    }
+
+    pub fn applies(&self, host: &str) -> bool {
+        let hosts = self.settings.hosts();
+        hosts.is_empty() || hosts.iter().any(|rule| rule.matches(host))
+    }
+
+    /// Elige el lado de una petición hacia `host`; `None` si el canary no
+    /// la cubre.
+    pub fn assign(&self, host: &str) -> Option<Arm> {
+        if !self.applies(host) {
+            return None;
+        }
+        let mut state = self.state.lock().expect("estado del canary");
+        let arm = if rand::thread_rng().gen::<f64>() * 100.0 < state.weight {
+            Arm::Canary
+        } else {
+            Arm::Stable
+        };
+        state.assigned[arm as usize] += 1;
+        info!(
+            host,
+            arm = arm.as_str(),
+            weight = state.weight,
+            "Asignación de canary"
+        );
+        Some(arm)
+    }
+
+    /// Guarda el resultado de una petición y, si el canary queda peor que el
+    /// estable, lo retira. Devuelve la retirada cuando ocurre en esta llamada.
+    pub fn record(
+        &self,
+        arm: Arm,
+        error: bool,
+        latency: Duration,
+        now: Instant,
+    ) -> Option<Rollback> {
+        let window = self.settings.window();
+        let mut state = self.state.lock().expect("estado del canary");
+        let sample = Sample {
+            at: now,
+            error,
+            latency,
+        };
+        match arm {
+            Arm::Stable => state.stable.push(sample, window),
+            Arm::Canary => state.canary.push(sample, window),
+        }
+        if arm == Arm::Stable || state.rollback.is_some() {
+            return None;
+        }
+        state.stable.prune(now, window);
+        let (canary, stable) = (state.canary.stats(), state.stable.stats());
+        let min = self.settings.min_requests();
+        if canary.requests < min || stable.requests < min {
+            return None;
+        }
+        let p95_margin = self.settings.p95_margin().as_secs_f64() * 1000.0;
+        let reason = if canary.error_rate - stable.error_rate > self.settings.error_margin() {
+            "error_rate"
+        } else if canary
+            .p95_ms
+            .zip(stable.p95_ms)
+            .is_some_and(|(canary, stable)| canary - stable > p95_margin)
+        {
+            "p95_latency"
+        } else {
+            return None;
+        };
+        let rollback = Rollback {
+            reason,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            canary,
+            stable,
+        };
+        state.weight = 0.0;
+        state.rollback = Some(rollback.clone());
+        Some(rollback)
+    }
+
+    /// [`Canary::record`] con la hora actual; una retirada se registra en el
+    /// log y se envía al webhook.
+    pub fn observe(self: &Arc<Self>, arm: Arm, error: bool, latency: Duration) {
+        let Some(rollback) = self.record(arm, error, latency, Instant::now()) else {
+            return;
+        };
+        warn!(
+            parent = %self.parent.parent(),
+            reason = rollback.reason,
+            canary_error_rate = rollback.canary.error_rate,
+            stable_error_rate = rollback.stable.error_rate,
+            canary_p95_ms = rollback.canary.p95_ms,
+            stable_p95_ms = rollback.stable.p95_ms,
+            "Canary retirado automáticamente"
+        );
+        if let Some(url) = self.webhook.clone() {
+            let canary = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = canary.notify(url, &rollback).await {
+                    error!(error = %e, "No se pudo avisar de la retirada del canary");
+                }
+            });
+        }
+    }
+
+    async fn notify(&self, url: Uri, rollback: &Rollback) -> anyhow::Result<()> {
+        let body = serde_json::json!({
+            "event": "canary_rollback",
+            "parent": self.parent.parent().to_string(),
+            "rollback": rollback,
+        });
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))?;
+        let res = self.client.request(req).await?;
+        if !res.status().is_success() {
+            anyhow::bail!("el webhook del canary respondió {}", res.status());
+        }
+        Ok(())
+    }
+
+    pub fn set_weight(&self, weight: f64) -> Result<(), CanaryError> {
+        if !(0.0..=100.0).contains(&weight) {
+            return Err(CanaryError::InvalidWeight);
+        }
+        let mut state = self.state.lock().expect("estado del canary");
+        if state.rollback.is_some() {
+            return Err(CanaryError::RolledBack);
+        }
+        state.weight = weight;
+        Ok(())
+    }
+
+    /// Olvida la retirada y los resultados, y vuelve al peso configurado.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().expect("estado del canary");
+        state.weight = self.settings.weight();
+        state.rollback = None;
+        state.stable = Window::default();
+        state.canary = Window::default();
+    }
+
+    pub fn snapshot(&self) -> CanarySnapshot {
+        let mut state = self.state.lock().expect("estado del canary");
+        let now = Instant::now();
+        let window = self.settings.window();
+        state.stable.prune(now, window);
+        state.canary.prune(now, window);
+        CanarySnapshot {
+            parent: self.parent.parent().to_string(),
+            weight: state.weight,
+            configured_weight: self.settings.weight(),
+            hosts: self
+                .settings
+                .hosts()
+                .iter()
+                .map(|rule| rule.to_string())
+                .collect(),
+            window_secs: window.as_secs(),
+            stable: state.stable.stats(),
+            canary: state.canary.stats(),
+            assigned_stable: state.assigned[Arm::Stable as usize],
+            assigned_canary: state.assigned[Arm::Canary as usize],
+            rollback: state.rollback.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::UpstreamProxySettings;
+
+    fn canary(weight: f64) -> Canary {
+        let parent = UpstreamProxySettings::new("canary.internal:3128".parse().unwrap());
+        let settings = CanarySettings::new(parent, weight)
+            .with_window(Duration::from_secs(60))
+            .with_min_requests(10)
+            .with_error_margin(0.1)
+            .with_p95_margin(Duration::from_millis(200))
+            .with_host("*.example.com".parse().unwrap());
+        Canary::new(settings).unwrap()
+    }
+
+    #[test]
+    fn test_rollback_compares_both_arms_and_latches() {
+        let canary = canary(100.0);
+        assert_eq!(canary.assign("other.org"), None);
+        assert_eq!(canary.assign("api.example.com"), Some(Arm::Canary));
+
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        for i in 0..10 {
+            let at = start + ms(i);
+            assert_eq!(canary.record(Arm::Stable, i == 0, ms(50), at), None);
+        }
+        // Slower than stable, but inside the margin.
+        for i in 0..10 {
+            let at = start + ms(10 + i);
+            assert_eq!(canary.record(Arm::Canary, i == 0, ms(200), at), None);
+        }
+        // Slow samples push the canary's p95 over stable's plus 200 ms.
+        let mut rollback = None;
+        for i in 0..5 {
+            rollback = rollback.or(canary.record(Arm::Canary, false, ms(400), start + ms(20 + i)));
+        }
+        let rollback = rollback.expect("el canary debe retirarse");
+        assert_eq!(rollback.reason, "p95_latency");
+        assert_eq!(rollback.stable.p95_ms, Some(50.0));
+        assert_eq!(canary.assign("api.example.com"), Some(Arm::Stable));
+        assert_eq!(canary.set_weight(50.0), Err(CanaryError::RolledBack));
+        assert_eq!(
+            canary.record(Arm::Canary, true, ms(1), start + ms(30)),
+            None
+        );
+
+        canary.reset();
+        let snapshot = canary.snapshot();
+        assert_eq!((snapshot.weight, snapshot.rollback), (100.0, None));
+        assert_eq!(snapshot.canary.requests, 0);
+        assert_eq!(canary.set_weight(120.0), Err(CanaryError::InvalidWeight));
+        assert_eq!(canary.set_weight(30.0), Ok(()));
+
+        // Errors trip it too.
+        let later = start + Duration::from_secs(120);
+        for i in 0..10 {
+            canary.record(Arm::Stable, false, ms(50), later + ms(i));
+        }
+        let rollback = (0..10)
+            .find_map(|i| canary.record(Arm::Canary, i % 2 == 0, ms(50), later + ms(10 + i)))
+            .unwrap();
+        assert_eq!(rollback.reason, "error_rate");
+        assert_eq!(rollback.stable.requests, 10);
+        assert_eq!(rollback.canary.error_rate, 0.5);
+    }
+}
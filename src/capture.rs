@@ -0,0 +1,676 @@
+//! Captura de peticiones fallidas para reproducirlas después.
+//!
+//! Las peticiones muestreadas se preparan al entrar (cabeceras y el inicio del
+//! body, ya redactados con las reglas de [`crate::redact`]) y solo se escriben a disco si la respuesta es un 5xx,
+//! o siempre con `all_responses`. Cada captura es un JSON en el directorio
+//! configurado, con la línea de tiempo de los pasos hacia el destino y la
+//! respuesta enviada al cliente.
+//!
+//! El formato lleva `format_version` para que [`read_bundle`] siga leyendo
+//! capturas antiguas: la versión 1 no tenía el campo ni el body de la
+//! respuesta.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use futures_util::{stream, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, HOST};
+use hyper::{Body, HeaderMap, Method, Request, Response, Uri};
+use serde::{Deserialize, Serialize};
+
+use crate::privacy::Sanitizer;
+use crate::redact::{redact_headers, redact_text, REDACTED};
+use crate::settings::CaptureSettings;
+use crate::streaming;
+
+/// Versión del formato que se escribe.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Pasos hacia el destino de una petición capturable o con `Server-Timing`.
+/// Viaja en las extensiones de la petición para que cada etapa del proxy
+/// anote el suyo.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    started: Instant,
+    events: Arc<Mutex<Vec<TimelineEvent>>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub stage: std::borrow::Cow<'static, str>,
+    pub offset_ms: f64,
+    pub duration_ms: f64,
+    pub error: Option<String>,
+}
+
+impl Timeline {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn of(req: &Request<Body>) -> Option<Self> {
+        req.extensions().get::<Timeline>().cloned()
+    }
+
+    /// La timeline de `req`, creándola si todavía no tiene.
+    pub fn install(req: &mut Request<Body>) -> Self {
+        match Timeline::of(req) {
+            Some(timeline) => timeline,
+            None => {
+                let timeline = Timeline::new();
+                req.extensions_mut().insert(timeline.clone());
+                timeline
+            }
+        }
+    }
+
+    /// La timeline de la petición en curso dentro de [`Timeline::in_scope`].
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Ejecuta `fut` con `timeline` como la actual, para que anoten sus
+    /// pasos las etapas que no ven la petición, como el dialer.
+    pub async fn in_scope<F: Future>(timeline: Option<Timeline>, fut: F) -> F::Output {
+        match timeline {
+            Some(timeline) => CURRENT.scope(timeline, fut).await,
+            None => fut.await,
+        }
+    }
+
+    /// Anota la etapa `stage`, iniciada en `since`.
+    pub fn record(&self, stage: &'static str, since: Instant, error: Option<String>) {
+        let event = TimelineEvent {
+            stage: stage.into(),
+            offset_ms: millis(since.saturating_duration_since(self.started)),
+            duration_ms: millis(since.elapsed()),
+            error,
+        };
+        self.events.lock().expect("lock de timeline").push(event);
+    }
+
+    pub(crate) fn events(&self) -> Vec<TimelineEvent> {
+        self.events.lock().expect("lock de timeline").clone()
+    }
+
+    /// Tiempo desde que llegó la petición.
+    pub fn elapsed(&self) -> std::time::Duration {
+        self.started.elapsed()
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Timeline;
+}
+
+fn millis(duration: std::time::Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Marca de las peticiones que la API de administración repite desde una
+/// captura; lleva el id de la captura.
+#[derive(Debug, Clone)]
+pub struct Replayed(pub String);
+
+/// Petición preparada para guardarse si termina mal.
+pub struct PendingCapture {
+    client: String,
+    request: CapturedRequest,
+    timeline: Timeline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// Convertido a UTF-8 con pérdida.
+    pub body: String,
+    pub body_truncated: bool,
+}
+
+impl CapturedRequest {
+    /// URL capturada, con lo redactado escapado para que siga siendo válida.
+    pub fn replay_url(&self) -> anyhow::Result<Uri> {
+        self.url
+            .replace(REDACTED, "%5Bredacted%5D")
+            .parse()
+            .with_context(|| format!("URL capturada inválida: {}", self.url))
+    }
+
+    /// Cabeceras que tiene sentido repetir: sin las redactadas, las de salto
+    /// ni las que recalcula el cliente HTTP.
+    pub fn replay_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if value == REDACTED {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        crate::proxy::sanitize_headers(&mut headers);
+        headers.remove(HOST);
+        headers.remove(CONTENT_LENGTH);
+        headers
+    }
+
+    /// Comando `curl` que repite la petición a través de `proxy`. Si el body
+    /// se capturó truncado, el comando lo lee de `body.bin`.
+    pub fn to_curl(&self, proxy: &str) -> String {
+        let mut args = vec!["curl".to_string(), "-x".to_string(), shell_quote(proxy)];
+        match self.method.as_str() {
+            "GET" => {}
+            "HEAD" => args.push("--head".to_string()),
+            method => args.extend(["-X".to_string(), shell_quote(method)]),
+        }
+        let url = self.url.replace(REDACTED, "%5Bredacted%5D");
+        args.push(shell_quote(&url));
+        let mut command = args.join(" ");
+        for (name, value) in &self.replay_headers() {
+            let header = format!("{name}: {}", String::from_utf8_lossy(value.as_bytes()));
+            command.push_str(&format!(" \\\n  -H {}", shell_quote(&header)));
+        }
+        if self.body_truncated {
+            command.push_str(" \\\n  --data-binary @body.bin");
+            return format!(
+                "# El body se capturó truncado: guarde el completo en body.bin\n{command}\n"
+            );
+        }
+        if !self.body.is_empty() {
+            command.push_str(&format!(" \\\n  --data-binary {}", shell_quote(&self.body)));
+        }
+        command.push('\n');
+        command
+    }
+}
+
+/// Entre comillas simples para `sh`.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// Inicio del body; vacío en las capturas de la versión 1.
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub body_truncated: bool,
+}
+
+#[derive(Serialize)]
+struct Bundle<'a> {
+    format_version: u32,
+    request_id: &'a str,
+    captured_at_ms: u64,
+    client: String,
+    request: &'a CapturedRequest,
+    timeline: Vec<TimelineEvent>,
+    error_category: Option<&'a str>,
+    response: CapturedResponse,
+}
+
+/// Captura leída de disco, de cualquier versión del formato.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedBundle {
+    #[serde(default = "legacy_version")]
+    pub format_version: u32,
+    pub request_id: String,
+    pub captured_at_ms: u64,
+    /// `ip:puerto` del cliente original.
+    #[serde(default)]
+    pub client: String,
+    pub request: CapturedRequest,
+    #[serde(default)]
+    pub timeline: Vec<TimelineEvent>,
+    pub response: CapturedResponse,
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+impl RecordedBundle {
+    /// Duración de la etapa `stage` en la línea de tiempo, si se anotó.
+    pub fn stage_ms(&self, stage: &str) -> Option<f64> {
+        self.timeline
+            .iter()
+            .find(|event| event.stage == stage)
+            .map(|event| event.duration_ms)
+    }
+}
+
+/// Lee una captura; rechaza las de una versión más nueva que esta.
+pub fn read_bundle(bytes: &[u8]) -> anyhow::Result<RecordedBundle> {
+    let bundle: RecordedBundle = serde_json::from_slice(bytes)?;
+    if bundle.format_version > FORMAT_VERSION {
+        anyhow::bail!(
+            "captura {} en formato {} (se admite hasta {FORMAT_VERSION})",
+            bundle.request_id,
+            bundle.format_version
+        );
+    }
+    Ok(bundle)
+}
+
+/// Entrada del listado de capturas.
+#[derive(Debug, Serialize)]
+pub struct CaptureEntry {
+    pub request_id: String,
+    pub bytes: u64,
+    pub captured_at_ms: u64,
+}
+
+pub struct CaptureStore {
+    settings: CaptureSettings,
+    seq: AtomicU64,
+    // Serializes writes so eviction sees a consistent directory.
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl CaptureStore {
+    pub fn new(settings: CaptureSettings) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(settings.dir())?;
+        Ok(Self {
+            settings,
+            seq: AtomicU64::new(0),
+            write_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Prepara la captura si la petición entra en el muestreo y en el filtro
+    /// de hosts. Lee por adelantado hasta `max_body_bytes` del body. El
+    /// cliente, la URL y las cabeceras quedan como diga `sanitizer`.
+    pub async fn start(
+        &self,
+        req: &mut Request<Body>,
+        client: SocketAddr,
+        sanitizer: &Sanitizer,
+    ) -> Option<PendingCapture> {
+        let host = req.uri().host().unwrap_or_default();
+        if !self.settings.matches_host(host) {
+            return None;
+        }
+        let rate = self.settings.sample_rate();
+        if rate < 1.0 && rand::random::<f64>() >= rate {
+            return None;
+        }
+
+        let (body, body_truncated) = if req.method() == Method::CONNECT {
+            (Vec::new(), false)
+        } else {
+            let original = std::mem::take(req.body_mut());
+            let (prefix, truncated, rebuilt) =
+                buffer_prefix(original, self.settings.max_body_bytes()).await;
+            *req.body_mut() = rebuilt;
+            (prefix, truncated)
+        };
+
+        let timeline = Timeline::install(req);
+        Some(PendingCapture {
+            client: sanitizer.addr(client),
+            request: CapturedRequest {
+                method: req.method().to_string(),
+                url: sanitizer.url(req.uri()),
+                headers: sanitizer.headers(redact_headers(req.headers())),
+                body: redact_text(&String::from_utf8_lossy(&body)),
+                body_truncated,
+            },
+            timeline,
+        })
+    }
+
+    /// Guarda la captura si `response` es un 5xx (o siempre, con
+    /// `all_responses`) y devuelve su id. Lee por adelantado hasta
+    /// `max_body_bytes` del body de la respuesta, salvo si va en streaming.
+    pub async fn finish(
+        &self,
+        pending: PendingCapture,
+        response: &mut Response<Body>,
+        sanitizer: &Sanitizer,
+    ) -> anyhow::Result<Option<String>> {
+        if !response.status().is_server_error() && !self.settings.all_responses() {
+            return Ok(None);
+        }
+        // A streamed body is not read ahead: the capture keeps only its head.
+        let (body, body_truncated) = if streaming::is_streamed(response) {
+            (Vec::new(), true)
+        } else {
+            let original = std::mem::take(response.body_mut());
+            let (body, truncated, rebuilt) =
+                buffer_prefix(original, self.settings.max_body_bytes()).await;
+            *response.body_mut() = rebuilt;
+            (body, truncated)
+        };
+        let now = unix_millis(SystemTime::now());
+        let id = format!("{now}-{}", self.seq.fetch_add(1, Ordering::Relaxed));
+        let bundle = Bundle {
+            format_version: FORMAT_VERSION,
+            request_id: &id,
+            captured_at_ms: now,
+            client: pending.client,
+            request: &pending.request,
+            timeline: pending.timeline.events(),
+            error_category: response
+                .headers()
+                .get("x-proxy-error")
+                .and_then(|v| v.to_str().ok()),
+            response: CapturedResponse {
+                status: response.status().as_u16(),
+                headers: sanitizer.headers(redact_headers(response.headers())),
+                body: redact_text(&String::from_utf8_lossy(&body)),
+                body_truncated,
+            },
+        };
+        let json = serde_json::to_vec_pretty(&bundle)?;
+
+        let _guard = self.write_lock.lock().await;
+        tokio::fs::write(self.path(&id), json).await?;
+        self.evict().await?;
+        Ok(Some(id))
+    }
+
+    /// Capturas disponibles, de la más reciente a la más antigua.
+    pub async fn list(&self) -> std::io::Result<Vec<CaptureEntry>> {
+        let mut entries: Vec<CaptureEntry> = self
+            .files()
+            .await?
+            .into_iter()
+            .filter_map(|(path, bytes, modified)| {
+                let request_id = path.file_stem()?.to_str()?.to_string();
+                Some(CaptureEntry {
+                    request_id,
+                    bytes,
+                    captured_at_ms: unix_millis(modified),
+                })
+            })
+            .collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.captured_at_ms));
+        Ok(entries)
+    }
+
+    /// Contenido JSON de una captura.
+    pub async fn get(&self, request_id: &str) -> std::io::Result<Option<Vec<u8>>> {
+        if !is_valid_id(request_id) {
+            return Ok(None);
+        }
+        match tokio::fs::read(self.path(request_id)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn path(&self, request_id: &str) -> PathBuf {
+        self.settings.dir().join(format!("{request_id}.json"))
+    }
+
+    async fn files(&self) -> std::io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut files = Vec::new();
+        let mut dir = tokio::fs::read_dir(self.settings.dir()).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let meta = entry.metadata().await?;
+                files.push((path, meta.len(), meta.modified()?));
+            }
+        }
+        Ok(files)
+    }
+
+    async fn evict(&self) -> std::io::Result<()> {
+        let mut files = self.files().await?;
+        let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+        files.sort_by_key(|(path, _, modified)| (*modified, path.clone()));
+        // The newest bundle is kept even if it alone exceeds the budget.
+        files.pop();
+        for (path, len, _) in files {
+            if total <= self.settings.max_total_bytes() {
+                break;
+            }
+            tokio::fs::remove_file(&path).await?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_digit() || c == '-')
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Lee hasta `cap` bytes del body y devuelve ese prefijo, si quedó cortado y
+/// un body equivalente al original para reenviar.
+pub(crate) async fn buffer_prefix(mut body: Body, cap: usize) -> (Vec<u8>, bool, Body) {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut len = 0;
+    let mut failure = None;
+    let mut ended = false;
+    while len <= cap {
+        match body.data().await {
+            Some(Ok(chunk)) => {
+                len += chunk.len();
+                chunks.push(chunk);
+            }
+            Some(Err(e)) => {
+                failure = Some(e);
+                break;
+            }
+            None => {
+                ended = true;
+                break;
+            }
+        }
+    }
+
+    let mut prefix: Vec<u8> = chunks.iter().flat_map(|c| c.iter().copied()).collect();
+    let truncated = prefix.len() > cap;
+    prefix.truncate(cap);
+
+    let replay = stream::iter(chunks.clone().into_iter().map(Ok::<_, hyper::Error>));
+    let rebuilt = if ended {
+        Body::from(chunks.concat())
+    } else if let Some(e) = failure {
+        Body::wrap_stream(replay.chain(stream::once(async move { Err(e) })))
+    } else {
+        Body::wrap_stream(replay.chain(body))
+    };
+    (prefix, truncated, rebuilt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_curl_export_drops_credentials_and_hop_headers() {
+        let request = CapturedRequest {
+            method: "POST".into(),
+            url: format!("http://api.test/v1/chat?api_key={REDACTED}&q=1"),
+            headers: vec![
+                ("host".into(), "api.test".into()),
+                ("authorization".into(), REDACTED.into()),
+                ("connection".into(), "keep-alive".into()),
+                ("content-length".into(), "26".into()),
+                ("content-type".into(), "application/json".into()),
+                ("x-note".into(), "it's".into()),
+            ],
+            body: r#"{"prompt":"it's ok"}"#.into(),
+            body_truncated: false,
+        };
+        assert_eq!(
+            request.to_curl("http://127.0.0.1:8888"),
+            "curl -x 'http://127.0.0.1:8888' -X 'POST' \
+             'http://api.test/v1/chat?api_key=%5Bredacted%5D&q=1' \\\n  \
+             -H 'content-type: application/json' \\\n  \
+             -H 'x-note: it'\\''s' \\\n  \
+             --data-binary '{\"prompt\":\"it'\\''s ok\"}'\n"
+        );
+
+        let head = CapturedRequest {
+            method: "HEAD".into(),
+            headers: Vec::new(),
+            body: "{\"a\"".into(),
+            body_truncated: true,
+            ..request
+        };
+        let curl = head.to_curl("http://proxy:3128");
+        assert!(curl.starts_with("# El body se capturó truncado"), "{curl}");
+        assert!(curl.contains("curl -x 'http://proxy:3128' --head 'http://api.test/"));
+        assert!(curl.ends_with("--data-binary @body.bin\n"), "{curl}");
+    }
+
+    #[tokio::test]
+    async fn test_buffer_prefix_preserves_body() {
+        let (prefix, truncated, rebuilt) = buffer_prefix(Body::from("hola mundo"), 4).await;
+        assert_eq!(prefix, b"hola");
+        assert!(truncated);
+        assert_eq!(hyper::body::to_bytes(rebuilt).await.unwrap(), "hola mundo");
+
+        let (prefix, truncated, _) = buffer_prefix(Body::from("hola"), 64).await;
+        assert_eq!(prefix, b"hola");
+        assert!(!truncated);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_oldest_over_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let store =
+            CaptureStore::new(CaptureSettings::new(dir.path()).with_max_total_bytes(1500)).unwrap();
+
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            let mut req = Request::post("http://api.test/")
+                .body(Body::from("x".repeat(300)))
+                .unwrap();
+            let pending = store
+                .start(
+                    &mut req,
+                    "127.0.0.1:1".parse().unwrap(),
+                    &Sanitizer::default(),
+                )
+                .await
+                .unwrap();
+            let mut failed = Response::builder().status(502).body(Body::empty()).unwrap();
+            ids.push(
+                store
+                    .finish(pending, &mut failed, &Sanitizer::default())
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+            // Distinct mtimes keep the eviction order deterministic.
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        let listed: Vec<String> = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.request_id)
+            .collect();
+        assert!(listed.len() < 4);
+        assert_eq!(listed[0], ids[3]);
+        assert!(store.get(&ids[0]).await.unwrap().is_none());
+        assert!(store.get("../secreto").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_all_responses_keep_the_body_and_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let store =
+            CaptureStore::new(CaptureSettings::new(dir.path()).with_all_responses(true)).unwrap();
+        let mut req = Request::post("http://api.test/v1/chat")
+            .body(Body::from("{}"))
+            .unwrap();
+        let pending = store
+            .start(
+                &mut req,
+                "127.0.0.1:1".parse().unwrap(),
+                &Sanitizer::default(),
+            )
+            .await
+            .unwrap();
+        let mut ok = Response::new(Body::from("respuesta"));
+        let id = store
+            .finish(pending, &mut ok, &Sanitizer::default())
+            .await
+            .unwrap()
+            .unwrap();
+        // The client still gets the whole body.
+        assert_eq!(
+            hyper::body::to_bytes(ok.into_body()).await.unwrap(),
+            "respuesta"
+        );
+
+        let bundle = read_bundle(&store.get(&id).await.unwrap().unwrap()).unwrap();
+        assert_eq!(bundle.format_version, FORMAT_VERSION);
+        assert_eq!(bundle.response.body, "respuesta");
+
+        let newer = serde_json::json!({
+            "format_version": FORMAT_VERSION + 1,
+            "request_id": "1-0",
+            "captured_at_ms": 1,
+            "request": bundle.request,
+            "response": bundle.response,
+        });
+        assert!(read_bundle(newer.to_string().as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sampling_and_host_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = "127.0.0.1:1".parse().unwrap();
+        let store = CaptureStore::new(
+            CaptureSettings::new(dir.path()).with_hosts(vec!["Example.com".into()]),
+        )
+        .unwrap();
+        let mut req = Request::get("http://api.example.com/")
+            .body(Body::empty())
+            .unwrap();
+        assert!(store
+            .start(&mut req, client, &Sanitizer::default())
+            .await
+            .is_some());
+        let mut req = Request::get("http://notexample.com/")
+            .body(Body::empty())
+            .unwrap();
+        assert!(store
+            .start(&mut req, client, &Sanitizer::default())
+            .await
+            .is_none());
+
+        let never =
+            CaptureStore::new(CaptureSettings::new(dir.path()).with_sample_rate(0.0)).unwrap();
+        let mut req = Request::get("http://api.example.com/")
+            .body(Body::empty())
+            .unwrap();
+        assert!(never
+            .start(&mut req, client, &Sanitizer::default())
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,416 @@
+//! Aviso de caducidad de los certificados que sirve el proxy.
+//!
+//! Cada `check_interval` se mira el `not_after` de toda la cadena del
+//! listener TLS, de la CA de `[mitm]` y de los certificados que esa CA tiene
+//! emitidos y en uso. Cuando a uno le quedan menos días que un umbral (30, 7
+//! y 1 por defecto) se registra un `warn` y, con `webhook`, se envía un POST
+//! JSON; cada umbral avisa una vez por certificado, y uno ya caducado avisa
+//! con `error`. Los emitidos se vuelven a firmar a mitad de su validez, así
+//! que en la práctica solo avisan cuando la CA les recorta la validez. El
+//! estado de cada certificado sale en `GET /api/stats`.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use serde::Serialize;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tracing::{error, warn};
+
+use crate::mitm::Interceptor;
+use crate::settings::CertExpirySettings;
+
+const DAY_SECS: i64 = 24 * 3600;
+
+/// De dónde sale un certificado vigilado.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertSource {
+    /// Cadena del listener TLS (`--tls-cert`).
+    Listener,
+    MitmCa,
+    /// Emitido por la CA de `[mitm]` para un host.
+    MitmLeaf,
+}
+
+/// Lo que se vigila de un certificado.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Watched {
+    pub source: CertSource,
+    pub subject: String,
+    /// Número de serie en hexadecimal.
+    pub serial: String,
+    /// Segundos Unix.
+    pub not_after: i64,
+}
+
+impl Watched {
+    pub fn parse(source: CertSource, der: &CertificateDer<'_>) -> anyhow::Result<Self> {
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(der).context("Certificado X.509 ilegible")?;
+        Ok(Self {
+            source,
+            subject: cert.subject().to_string(),
+            serial: cert.raw_serial_as_string(),
+            not_after: cert.validity().not_after.timestamp(),
+        })
+    }
+
+    /// Días enteros que le quedan en `now`; negativo si ya caducó.
+    pub fn days_left(&self, now: i64) -> i64 {
+        (self.not_after - now).div_euclid(DAY_SECS)
+    }
+}
+
+/// Un certificado que acaba de cruzar un umbral.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExpiryAlert {
+    #[serde(flatten)]
+    pub certificate: Watched,
+    pub days_left: i64,
+    /// 0 si ya caducó.
+    pub threshold_days: u64,
+}
+
+/// Estado de un certificado en `GET /api/stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CertStatus {
+    #[serde(flatten)]
+    pub certificate: Watched,
+    pub days_left: i64,
+    /// Último umbral avisado.
+    pub alerted_threshold_days: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpirySnapshot {
+    pub thresholds_days: Vec<u64>,
+    pub alerts: u64,
+    pub certificates: Vec<CertStatus>,
+    /// Certificados emitidos en uso; van aparte porque pueden ser cientos.
+    pub mitm_leaves: Option<LeafSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LeafSummary {
+    pub in_use: usize,
+    /// El que antes caduca.
+    pub earliest: Option<CertStatus>,
+}
+
+pub struct ExpiryMonitor {
+    settings: CertExpirySettings,
+    /// Listener y CA, leídos al arrancar.
+    fixed: Vec<Watched>,
+    mitm: Option<Arc<Interceptor>>,
+    /// Último umbral avisado por certificado.
+    alerted: Mutex<HashMap<(CertSource, String), u64>>,
+    alerts: AtomicU64,
+    client: Client<HttpConnector>,
+    webhook: Option<Uri>,
+}
+
+impl ExpiryMonitor {
+    /// `listener` es la cadena del listener TLS, si lo hay.
+    pub fn new(
+        settings: CertExpirySettings,
+        listener: &[CertificateDer<'_>],
+        mitm: Option<Arc<Interceptor>>,
+    ) -> anyhow::Result<Self> {
+        let webhook = match settings.webhook() {
+            Some(url) => {
+                let url: Uri = url.parse()?;
+                if url.scheme_str() != Some("http") {
+                    anyhow::bail!("el webhook de caducidad debe usar una URL http://: {url}");
+                }
+                Some(url)
+            }
+            None => None,
+        };
+        let mut fixed = listener
+            .iter()
+            .map(|cert| Watched::parse(CertSource::Listener, cert))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("Certificado del listener TLS ilegible")?;
+        if let Some(mitm) = &mitm {
+            fixed.push(
+                Watched::parse(CertSource::MitmCa, mitm.ca_cert())
+                    .context("CA de [mitm] ilegible")?,
+            );
+        }
+        Ok(Self {
+            settings,
+            fixed,
+            mitm,
+            alerted: Mutex::new(HashMap::new()),
+            alerts: AtomicU64::new(0),
+            client: Client::new(),
+            webhook,
+        })
+    }
+
+    pub fn settings(&self) -> &CertExpirySettings {
+        &self.settings
+    }
+
+    fn leaves(&self) -> Vec<Watched> {
+        let Some(mitm) = &self.mitm else {
+            return Vec::new();
+        };
+        mitm.leaves()
+            .iter()
+            .filter_map(|cert| Watched::parse(CertSource::MitmLeaf, cert).ok())
+            .collect()
+    }
+
+    /// Umbral en el que está un certificado con `days_left` días: el menor
+    /// que ya alcanzó, 0 si caducó.
+    fn threshold(&self, days_left: i64) -> Option<u64> {
+        if days_left < 0 {
+            return Some(0);
+        }
+        self.settings
+            .thresholds_days()
+            .iter()
+            .rev()
+            .find(|&&days| days_left < days as i64)
+            .copied()
+    }
+
+    /// Revisa todos los certificados en `now` (segundos Unix) y devuelve los
+    /// que cruzaron un umbral desde la última revisión.
+    pub fn check(&self, now: i64) -> Vec<ExpiryAlert> {
+        let leaves = self.leaves();
+        let mut alerted = self.alerted.lock().expect("avisos de caducidad");
+        // Leaves that were re-signed or evicted stop counting.
+        let live: HashSet<&str> = leaves.iter().map(|leaf| leaf.serial.as_str()).collect();
+        alerted.retain(|(source, serial), _| {
+            *source != CertSource::MitmLeaf || live.contains(serial.as_str())
+        });
+        let mut alerts = Vec::new();
+        for certificate in self.fixed.iter().chain(&leaves).cloned() {
+            let days_left = certificate.days_left(now);
+            let Some(threshold) = self.threshold(days_left) else {
+                continue;
+            };
+            let key = (certificate.source, certificate.serial.clone());
+            if alerted.get(&key).is_some_and(|&last| last <= threshold) {
+                continue;
+            }
+            alerted.insert(key, threshold);
+            alerts.push(ExpiryAlert {
+                certificate,
+                days_left,
+                threshold_days: threshold,
+            });
+        }
+        self.alerts
+            .fetch_add(alerts.len() as u64, Ordering::Relaxed);
+        alerts
+    }
+
+    /// [`ExpiryMonitor::check`] con la hora actual; cada aviso va al log y
+    /// al webhook.
+    pub fn observe(self: &Arc<Self>) {
+        for alert in self.check(unix_now()) {
+            let certificate = &alert.certificate;
+            if alert.threshold_days == 0 {
+                error!(
+                    source = ?certificate.source,
+                    subject = %certificate.subject,
+                    serial = %certificate.serial,
+                    not_after = certificate.not_after,
+                    "Certificado caducado"
+                );
+            } else {
+                warn!(
+                    source = ?certificate.source,
+                    subject = %certificate.subject,
+                    serial = %certificate.serial,
+                    not_after = certificate.not_after,
+                    days_left = alert.days_left,
+                    threshold_days = alert.threshold_days,
+                    "Certificado a punto de caducar"
+                );
+            }
+            if let Some(url) = self.webhook.clone() {
+                let monitor = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = monitor.notify(url, &alert).await {
+                        error!(error = %e, "No se pudo avisar de la caducidad de un certificado");
+                    }
+                });
+            }
+        }
+    }
+
+    async fn notify(&self, url: Uri, alert: &ExpiryAlert) -> anyhow::Result<()> {
+        let body = serde_json::json!({
+            "event": "cert_expiry",
+            "certificate": alert,
+        });
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))?;
+        let res = self.client.request(req).await?;
+        if !res.status().is_success() {
+            anyhow::bail!("el webhook de caducidad respondió {}", res.status());
+        }
+        Ok(())
+    }
+
+    /// Revisa al arrancar y después cada `check_interval`.
+    pub async fn run(self: Arc<Self>) {
+        let mut ticks = tokio::time::interval(self.settings.check_interval());
+        loop {
+            ticks.tick().await;
+            self.observe();
+        }
+    }
+
+    pub fn snapshot(&self, now: i64) -> ExpirySnapshot {
+        let alerted = self.alerted.lock().expect("avisos de caducidad").clone();
+        let status = |certificate: Watched| CertStatus {
+            days_left: certificate.days_left(now),
+            alerted_threshold_days: alerted
+                .get(&(certificate.source, certificate.serial.clone()))
+                .copied(),
+            certificate,
+        };
+        let mitm_leaves = self.mitm.as_ref().map(|_| {
+            let leaves = self.leaves();
+            LeafSummary {
+                in_use: leaves.len(),
+                earliest: leaves
+                    .into_iter()
+                    .min_by_key(|leaf| leaf.not_after)
+                    .map(status),
+            }
+        });
+        ExpirySnapshot {
+            thresholds_days: self.settings.thresholds_days().to_vec(),
+            alerts: self.alerts.load(Ordering::Relaxed),
+            certificates: self.fixed.iter().cloned().map(status).collect(),
+            mitm_leaves,
+        }
+    }
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+    use time::OffsetDateTime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Certificado autofirmado que caduca `days` días después de `now`.
+    fn cert(name: &str, now: i64, days: i64) -> CertificateDer<'static> {
+        let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.not_before = OffsetDateTime::from_unix_timestamp(now - DAY_SECS).unwrap();
+        params.not_after = OffsetDateTime::from_unix_timestamp(now + days * DAY_SECS).unwrap();
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().clone()
+    }
+
+    #[test]
+    fn test_each_threshold_alerts_once_per_certificate() {
+        let now = unix_now();
+        let chain = [cert("largo.test", now, 90), cert("corto.test", now, 10)];
+        let monitor = ExpiryMonitor::new(CertExpirySettings::default(), &chain, None).unwrap();
+
+        let alerts = monitor.check(now);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].certificate.source, CertSource::Listener);
+        assert!(alerts[0].certificate.subject.contains("corto.test"));
+        assert_eq!(alerts[0].days_left, 10);
+        assert_eq!(alerts[0].threshold_days, 30);
+        assert!(monitor.check(now + 3600).is_empty());
+
+        // Four days later it is under the 7-day threshold, then expired.
+        let alerts = monitor.check(now + 4 * DAY_SECS);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].threshold_days, 7);
+        let alerts = monitor.check(now + 11 * DAY_SECS);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].threshold_days, 0);
+        assert!(monitor.check(now + 12 * DAY_SECS).is_empty());
+
+        let snapshot = monitor.snapshot(now);
+        assert_eq!(snapshot.alerts, 3);
+        assert_eq!(snapshot.thresholds_days, [30, 7, 1]);
+        assert_eq!(snapshot.certificates[0].alerted_threshold_days, None);
+        assert_eq!(snapshot.certificates[1].alerted_threshold_days, Some(0));
+        assert!(snapshot.mitm_leaves.is_none());
+    }
+
+    #[test]
+    fn test_a_certificate_found_late_alerts_only_its_lowest_threshold() {
+        let now = unix_now();
+        let settings = CertExpirySettings::default().with_thresholds_days(vec![1, 30, 7]);
+        let monitor = ExpiryMonitor::new(settings, &[cert("hoy.test", now, 0)], None).unwrap();
+        let alerts = monitor.check(now - 3600);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].threshold_days, 1);
+        assert_eq!(alerts[0].days_left, 0);
+    }
+
+    #[tokio::test]
+    async fn test_alerts_are_posted_to_the_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            let _ = tx.send(String::from_utf8(request).unwrap());
+        });
+
+        let now = unix_now();
+        let settings =
+            CertExpirySettings::default().with_webhook(format!("http://{webhook}/certs"));
+        let monitor =
+            Arc::new(ExpiryMonitor::new(settings, &[cert("pronto.test", now, 3)], None).unwrap());
+        monitor.observe();
+
+        let request = rx.await.unwrap();
+        assert!(request.starts_with("POST /certs HTTP/1.1"));
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        let event: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(event["event"], "cert_expiry");
+        assert_eq!(event["certificate"]["source"], "listener");
+        assert_eq!(event["certificate"]["threshold_days"], 7);
+        assert_eq!(event["certificate"]["days_left"], 3);
+    }
+
+    #[test]
+    fn test_rejects_non_http_webhooks() {
+        let settings = CertExpirySettings::default().with_webhook("https://alertas.test/certs");
+        assert!(ExpiryMonitor::new(settings, &[], None).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! Límite de peticiones y túneles simultáneos.
+//!
+//! Con `max_connections` el proxy atiende como mucho ese número de
+//! peticiones y túneles `CONNECT` a la vez, que es lo que ocupa descriptores
+//! hacia los destinos; el que pasa recibe un 503 al momento en lugar de
+//! esperar. Con `max_tunnels`, una IP de cliente que ya tiene ese número de
+//! túneles abiertos recibe un 429 al pedir otro. Una petición ocupa su plaza
+//! hasta que tiene la cabecera de la respuesta; un túnel, hasta que se
+//! cierra, termine como termine: su [`Slot`] viaja con la tarea que copia
+//! los bytes y se suelta al terminar esta, también si falla o se aborta.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::log_throttle::LogThrottle;
+use crate::settings::ConcurrencyLimits;
+
+/// Cada cuánto se registra la ocupación con nivel `debug`.
+const USAGE_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Límite que rechazó una petición.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrencyLimit {
+    /// `max_connections`: el proxy entero estaba lleno.
+    Connections,
+    /// `max_tunnels`: la IP ya tenía todos sus túneles abiertos.
+    Tunnels,
+}
+
+impl ConcurrencyLimit {
+    pub const ALL: [ConcurrencyLimit; 2] =
+        [ConcurrencyLimit::Connections, ConcurrencyLimit::Tunnels];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConcurrencyLimit::Connections => "connections",
+            ConcurrencyLimit::Tunnels => "tunnels",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// 503 si el proxy está lleno, 429 si lo está el cliente.
+    pub fn into_response(self) -> Response<Body> {
+        let (status, body, error) = match self {
+            ConcurrencyLimit::Connections => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Demasiadas conexiones en curso; reintenta en unos segundos\n",
+                "too_many_connections",
+            ),
+            ConcurrencyLimit::Tunnels => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Demasiados túneles abiertos desde esta IP\n",
+                "too_many_tunnels",
+            ),
+        };
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        let headers = response.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        headers.insert("x-proxy-error", HeaderValue::from_static(error));
+        response
+    }
+}
+
+/// Ocupación, tal como la muestra `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConcurrencySnapshot {
+    pub max_connections: Option<usize>,
+    /// Peticiones y túneles que ocupan plaza ahora.
+    pub connections: usize,
+    pub max_tunnels: Option<usize>,
+    /// Túneles contados contra `max_tunnels`.
+    pub tunnels: usize,
+    pub tunnel_clients: usize,
+}
+
+type TunnelCounts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Plaza de una petición o un túnel; se suelta al soltarla.
+pub struct Slot {
+    _connection: Option<OwnedSemaphorePermit>,
+    _tunnel: Option<TunnelSlot>,
+}
+
+struct TunnelSlot {
+    ip: IpAddr,
+    counts: TunnelCounts,
+}
+
+impl Drop for TunnelSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().expect("lock de túneles");
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+pub struct Concurrency {
+    limits: ConcurrencyLimits,
+    connections: Option<Arc<Semaphore>>,
+    tunnels: TunnelCounts,
+    rejection_log: LogThrottle,
+}
+
+impl Concurrency {
+    pub fn new(limits: ConcurrencyLimits) -> Self {
+        Self {
+            limits,
+            connections: limits
+                .max_connections()
+                .map(|max| Arc::new(Semaphore::new(max))),
+            tunnels: TunnelCounts::default(),
+            rejection_log: LogThrottle::new(Duration::from_secs(10)),
+        }
+    }
+
+    pub fn limits(&self) -> ConcurrencyLimits {
+        self.limits
+    }
+
+    /// Limita el log de los rechazos: una avalancha generaría uno por
+    /// petición.
+    pub fn rejection_log(&self) -> &LogThrottle {
+        &self.rejection_log
+    }
+
+    /// Plaza para una petición que no abre túnel.
+    pub fn request(&self) -> Result<Slot, ConcurrencyLimit> {
+        Ok(Slot {
+            _connection: self.connection()?,
+            _tunnel: None,
+        })
+    }
+
+    /// Plaza para un túnel de `ip`. El cupo del cliente se mira primero,
+    /// para que quien abusa no gaste plazas del resto.
+    pub fn tunnel(&self, ip: IpAddr) -> Result<Slot, ConcurrencyLimit> {
+        let tunnel = match self.limits.max_tunnels() {
+            Some(max) => {
+                let mut counts = self.tunnels.lock().expect("lock de túneles");
+                let count = counts.entry(ip).or_default();
+                if *count >= max {
+                    return Err(ConcurrencyLimit::Tunnels);
+                }
+                *count += 1;
+                Some(TunnelSlot {
+                    ip,
+                    counts: self.tunnels.clone(),
+                })
+            }
+            None => None,
+        };
+        // Dropping `tunnel` on failure gives the client's place back.
+        let connection = self.connection()?;
+        Ok(Slot {
+            _connection: connection,
+            _tunnel: tunnel,
+        })
+    }
+
+    fn connection(&self) -> Result<Option<OwnedSemaphorePermit>, ConcurrencyLimit> {
+        match &self.connections {
+            Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => Err(ConcurrencyLimit::Connections),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn snapshot(&self) -> ConcurrencySnapshot {
+        let counts = self.tunnels.lock().expect("lock de túneles");
+        ConcurrencySnapshot {
+            max_connections: self.limits.max_connections(),
+            connections: match (&self.connections, self.limits.max_connections()) {
+                (Some(semaphore), Some(max)) => max - semaphore.available_permits(),
+                _ => 0,
+            },
+            max_tunnels: self.limits.max_tunnels(),
+            tunnels: counts.values().sum(),
+            tunnel_clients: counts.len(),
+        }
+    }
+
+    /// Registra la ocupación cada [`USAGE_LOG_INTERVAL`] mientras haya algo
+    /// en curso.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(USAGE_LOG_INTERVAL);
+        loop {
+            interval.tick().await;
+            let usage = self.snapshot();
+            if usage.connections > 0 || usage.tunnels > 0 {
+                debug!(
+                    connections = usage.connections,
+                    max_connections = usage.max_connections,
+                    tunnels = usage.tunnels,
+                    tunnel_clients = usage.tunnel_clients,
+                    "Ocupación de conexiones y túneles"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_given_back_when_dropped() {
+        let limits = ConcurrencyLimits::default()
+            .with_max_connections(2)
+            .with_max_tunnels(1);
+        let concurrency = Concurrency::new(limits);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let tunnel = concurrency.tunnel(a).unwrap();
+        assert_eq!(concurrency.tunnel(a).err(), Some(ConcurrencyLimit::Tunnels));
+        let request = concurrency.request().unwrap();
+        // The global limit refuses b, and b's tunnel count is not kept.
+        assert_eq!(
+            concurrency.tunnel(b).err(),
+            Some(ConcurrencyLimit::Connections)
+        );
+        let snapshot = concurrency.snapshot();
+        assert_eq!(
+            (
+                snapshot.connections,
+                snapshot.tunnels,
+                snapshot.tunnel_clients
+            ),
+            (2, 1, 1)
+        );
+
+        drop(tunnel);
+        drop(request);
+        let snapshot = concurrency.snapshot();
+        assert_eq!(
+            (
+                snapshot.connections,
+                snapshot.tunnels,
+                snapshot.tunnel_clients
+            ),
+            (0, 0, 0)
+        );
+        assert!(concurrency.tunnel(a).is_ok());
+    }
+}
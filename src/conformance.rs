@@ -0,0 +1,777 @@
+//! Suite de conformidad del proxy.
+//!
+//! Levanta el proxy y orígenes locales, y comprueba semánticas de proxy HTTP
+//! hablando bytes crudos por TCP. Cada comprobación guarda la transcripción
+//! exacta de lo enviado y recibido para mostrarla si falla.
+//!
+//! Las comprobaciones marcadas como pendientes documentan huecos conocidos:
+//! su fallo se informa pero no hace fallar la suite.
+
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::connection::accept_loop;
+use crate::dialer::{Dialer, SystemResolver};
+use crate::forwarded;
+use crate::proxy::ProxyContext;
+use crate::settings::{ConnectionLimits, DialSettings, ListenerProtocols};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+/// Conexión cruda que registra todo lo que pasa por ella.
+pub struct Wire {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    transcript: Vec<(Direction, Vec<u8>)>,
+}
+
+/// Mensaje HTTP/1.1 leído de un [`Wire`].
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub start_line: String,
+    /// Nombres en minúsculas, en el orden recibido.
+    pub headers: Vec<(String, String)>,
+    /// Body ya sin codificación chunked.
+    pub body: Vec<u8>,
+    /// Trailers del body chunked, con nombres en minúsculas.
+    pub trailers: Vec<(String, String)>,
+}
+
+impl Message {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn has_header(&self, name: &str) -> bool {
+        self.header(name).is_some()
+    }
+
+    /// Código de estado si es una respuesta.
+    pub fn status(&self) -> Option<u16> {
+        self.start_line.split(' ').nth(1)?.parse().ok()
+    }
+}
+
+/// Qué se espera leer, para decidir cómo se delimita el body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expect {
+    Request,
+    Response,
+    /// Respuesta a un HEAD: nunca lleva body.
+    HeadResponse,
+}
+
+impl Wire {
+    pub async fn connect(addr: SocketAddr) -> std::io::Result<Self> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+            transcript: Vec::new(),
+        }
+    }
+
+    pub async fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.transcript.push((Direction::Sent, bytes.to_vec()));
+        self.stream.write_all(bytes).await
+    }
+
+    /// Lee más bytes al buffer; devuelve `false` en EOF.
+    async fn fill(&mut self) -> std::io::Result<bool> {
+        let mut chunk = [0u8; 4096];
+        let n = tokio::time::timeout(IO_TIMEOUT, self.stream.read(&mut chunk))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "sin datos a tiempo")
+            })??;
+        if n > 0 {
+            self.transcript
+                .push((Direction::Received, chunk[..n].to_vec()));
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(n > 0)
+    }
+
+    /// Devuelve exactamente `n` bytes.
+    pub async fn read_exact(&mut self, n: usize) -> std::io::Result<Vec<u8>> {
+        while self.buf.len() < n {
+            if !self.fill().await? {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    async fn read_line(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            if let Some(pos) = find(&self.buf, b"\r\n") {
+                let line = self.buf.drain(..pos + 2).collect::<Vec<u8>>();
+                return Ok(line[..pos].to_vec());
+            }
+            if !self.fill().await? {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    pub async fn read_message(&mut self, expect: Expect) -> std::io::Result<Message> {
+        let head = loop {
+            if let Some(pos) = find(&self.buf, b"\r\n\r\n") {
+                break self.buf.drain(..pos + 4).collect::<Vec<u8>>();
+            }
+            if !self.fill().await? {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        };
+        let head = String::from_utf8_lossy(&head[..head.len() - 4]).into_owned();
+        let mut lines = head.split("\r\n");
+        let mut message = Message {
+            start_line: lines.next().unwrap_or_default().to_string(),
+            ..Message::default()
+        };
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                message
+                    .headers
+                    .push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+
+        let bodyless_status = message
+            .status()
+            .is_some_and(|s| (100..200).contains(&s) || s == 204 || s == 304);
+        if expect == Expect::HeadResponse || (expect == Expect::Response && bodyless_status) {
+            return Ok(message);
+        }
+        let chunked = message
+            .header("transfer-encoding")
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+        if chunked {
+            loop {
+                let line = self.read_line().await?;
+                let size = String::from_utf8_lossy(&line);
+                let size = size.split(';').next().unwrap_or_default().trim();
+                let size = usize::from_str_radix(size, 16).map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "chunk inválido")
+                })?;
+                if size == 0 {
+                    loop {
+                        let line = self.read_line().await?;
+                        let line = String::from_utf8_lossy(&line);
+                        let Some((name, value)) = line.split_once(':') else {
+                            break;
+                        };
+                        message
+                            .trailers
+                            .push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+                    }
+                    break;
+                }
+                let data = self.read_exact(size + 2).await?;
+                message.body.extend_from_slice(&data[..size]);
+            }
+        } else if let Some(len) = message.header("content-length") {
+            let len = len.parse().map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "content-length inválido")
+            })?;
+            message.body = self.read_exact(len).await?;
+        } else if expect == Expect::Response {
+            while self.fill().await? {}
+            message.body = std::mem::take(&mut self.buf);
+        }
+        Ok(message)
+    }
+
+    /// Transcripción con los bytes escapados, una línea por lectura o escritura.
+    pub fn transcript(&self) -> String {
+        let mut out = String::new();
+        for (direction, bytes) in &self.transcript {
+            let arrow = match direction {
+                Direction::Sent => ">",
+                Direction::Received => "<",
+            };
+            out.push_str(&format!("    {arrow} {}\n", bytes.escape_ascii()));
+        }
+        out
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+type Respond = Arc<dyn Fn(&Message) -> Vec<u8> + Send + Sync>;
+
+/// Origen HTTP crudo que guarda cada petición recibida.
+pub struct Origin {
+    pub addr: SocketAddr,
+    received: Arc<Mutex<Vec<Message>>>,
+}
+
+impl Origin {
+    pub async fn spawn(respond: impl Fn(&Message) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind del origen");
+        let addr = listener.local_addr().expect("dirección del origen");
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let respond: Respond = Arc::new(respond);
+        let log = received.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (log, respond) = (log.clone(), respond.clone());
+                tokio::spawn(async move {
+                    let mut wire = Wire::new(stream);
+                    while let Ok(message) = wire.read_message(Expect::Request).await {
+                        let response = respond(&message);
+                        log.lock().expect("lock del origen").push(message);
+                        if wire.send(&response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Self { addr, received }
+    }
+
+    /// Origen que responde siempre `200 ok`.
+    pub async fn ok() -> Self {
+        Self::spawn(|_| b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok".to_vec()).await
+    }
+
+    pub fn received(&self) -> Vec<Message> {
+        self.received.lock().expect("lock del origen").clone()
+    }
+}
+
+/// Origen TCP que devuelve todo lo que recibe.
+async fn spawn_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind del eco");
+    let addr = listener.local_addr().expect("dirección del eco");
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Resultado de una comprobación.
+pub struct Outcome {
+    pub name: &'static str,
+    pub pending: bool,
+    pub failure: Option<String>,
+    pub transcript: String,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match (self.passed(), self.pending) {
+            (true, false) => "PASS",
+            (false, false) => "FAIL",
+            (false, true) => "XFAIL",
+            // A pending check that passes should lose its marker.
+            (true, true) => "XPASS",
+        };
+        write!(f, "{label} {}", self.name)?;
+        if let Some(failure) = &self.failure {
+            writeln!(f, ": {failure}")?;
+            write!(f, "{}", self.transcript)?;
+        }
+        Ok(())
+    }
+}
+
+struct Env {
+    proxy: SocketAddr,
+}
+
+type CheckFuture<'a> = Pin<Box<dyn Future<Output = CheckResult> + Send + 'a>>;
+
+/// `Err` lleva la explicación del fallo.
+type CheckResult = (Result<(), String>, Option<Wire>);
+
+struct Check {
+    name: &'static str,
+    pending: bool,
+    run: for<'a> fn(&'a Env) -> CheckFuture<'a>,
+}
+
+macro_rules! check {
+    ($name:literal, $pending:expr, $fn:ident) => {
+        Check {
+            name: $name,
+            pending: $pending,
+            run: |env| Box::pin($fn(env)),
+        }
+    };
+}
+
+fn checks() -> Vec<Check> {
+    vec![
+        check!("hop_by_hop_static", false, hop_by_hop_static),
+        check!(
+            "hop_by_hop_connection_listed",
+            false,
+            hop_by_hop_connection_listed
+        ),
+        check!("via_header_added", false, via_header_added),
+        check!("loop_to_self_terminates", false, loop_to_self_terminates),
+        check!("connect_early_data", false, connect_early_data),
+        check!("chunked_roundtrip", false, chunked_roundtrip),
+        check!("chunked_trailers_relayed", false, chunked_trailers),
+        check!("expect_100_continue", false, expect_100_continue),
+        check!("head_response_without_body", false, head_without_body),
+        check!("smuggling_cl_and_te", false, smuggling_cl_and_te),
+        check!("smuggling_duplicate_cl", false, smuggling_duplicate_cl),
+        check!("absolute_form_to_origin_form", false, absolute_form),
+        check!("origin_form_rejected", false, origin_form_rejected),
+    ]
+}
+
+/// Levanta un proxy con la configuración por defecto y ejecuta la suite.
+pub async fn run_suite() -> anyhow::Result<Vec<Outcome>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy = listener.local_addr()?;
+    let ctx = ProxyContext::new(Dialer::new(
+        DialSettings::default(),
+        Arc::new(SystemResolver),
+    ));
+    let server = tokio::spawn(accept_loop(
+        listener,
+        ctx,
+        ConnectionLimits::default(),
+        ListenerProtocols::default(),
+    ));
+
+    let env = Env { proxy };
+    let mut outcomes = Vec::new();
+    for check in checks() {
+        let (result, wire) = (check.run)(&env).await;
+        outcomes.push(Outcome {
+            name: check.name,
+            pending: check.pending,
+            failure: result.err(),
+            transcript: wire.map(|w| w.transcript()).unwrap_or_default(),
+        });
+    }
+    server.abort();
+    Ok(outcomes)
+}
+
+/// Comprobaciones no pendientes que fallaron.
+pub fn failures(outcomes: &[Outcome]) -> usize {
+    outcomes
+        .iter()
+        .filter(|o| !o.passed() && !o.pending)
+        .count()
+}
+
+/// Envía `request` por una conexión nueva al proxy y lee la respuesta.
+async fn exchange(
+    env: &Env,
+    request: String,
+    expect: Expect,
+) -> (Result<Message, String>, Option<Wire>) {
+    let mut wire = match Wire::connect(env.proxy).await {
+        Ok(wire) => wire,
+        Err(e) => return (Err(format!("no se pudo conectar: {e}")), None),
+    };
+    let result = match wire.send(request.as_bytes()).await {
+        Ok(()) => wire
+            .read_message(expect)
+            .await
+            .map_err(|e| format!("respuesta ilegible: {e}")),
+        Err(e) => Err(format!("envío fallido: {e}")),
+    };
+    (result, Some(wire))
+}
+
+fn expect_status(response: &Message, status: u16) -> Result<(), String> {
+    match response.status() {
+        Some(s) if s == status => Ok(()),
+        _ => Err(format!(
+            "esperado estado {status}, recibido `{}`",
+            response.start_line
+        )),
+    }
+}
+
+async fn forwarded_headers(env: &Env, extra: &str) -> (Result<Message, String>, Option<Wire>) {
+    let origin = Origin::ok().await;
+    let request = format!(
+        "GET http://{0}/hop HTTP/1.1\r\nhost: {0}\r\n{extra}\r\n",
+        origin.addr
+    );
+    let (response, wire) = exchange(env, request, Expect::Response).await;
+    let result = response.and_then(|response| {
+        expect_status(&response, 200)?;
+        origin
+            .received()
+            .pop()
+            .ok_or_else(|| "el origen no recibió la petición".to_string())
+    });
+    (result, wire)
+}
+
+async fn hop_by_hop_static(env: &Env) -> CheckResult {
+    let extra = "keep-alive: timeout=5\r\nproxy-authorization: Basic Zm9vOmJhcg==\r\nte: deflate\r\nx-keep: 1\r\n";
+    let (received, wire) = forwarded_headers(env, extra).await;
+    let result = received.and_then(|request| {
+        for name in ["keep-alive", "proxy-authorization", "te"] {
+            if request.has_header(name) {
+                return Err(format!("`{name}` llegó al origen"));
+            }
+        }
+        if !request.has_header("x-keep") {
+            return Err("`x-keep` no llegó al origen".into());
+        }
+        Ok(())
+    });
+    (result, wire)
+}
+
+async fn hop_by_hop_connection_listed(env: &Env) -> CheckResult {
+    let extra = "connection: x-drop-me\r\nx-drop-me: 1\r\n";
+    let (received, wire) = forwarded_headers(env, extra).await;
+    let result = received.and_then(|request| match request.has_header("x-drop-me") {
+        true => Err("`x-drop-me`, listada en `connection`, llegó al origen".into()),
+        false => Ok(()),
+    });
+    (result, wire)
+}
+
+async fn via_header_added(env: &Env) -> CheckResult {
+    let (received, wire) = forwarded_headers(env, "").await;
+    let result = received.and_then(|request| match request.has_header("via") {
+        true => Ok(()),
+        false => Err("el origen no recibió `via`".into()),
+    });
+    (result, wire)
+}
+
+async fn loop_to_self_terminates(env: &Env) -> CheckResult {
+    let request = format!("GET http://{0}/ HTTP/1.1\r\nhost: {0}\r\n\r\n", env.proxy);
+    let (response, wire) = exchange(env, request, Expect::Response).await;
+    let result = response.and_then(|response| {
+        let detected = response.status() == Some(508)
+            && response.header("x-proxy-error") == Some("via_loop")
+            && response.body == forwarded::LOOP_MESSAGE.as_bytes();
+        match detected {
+            true => Ok(()),
+            false => Err(format!(
+                "una petición al propio proxy respondió `{}` sin detectar el bucle por `Via`",
+                response.start_line
+            )),
+        }
+    });
+    (result, wire)
+}
+
+async fn connect_early_data(env: &Env) -> CheckResult {
+    let echo = spawn_echo().await;
+    let mut wire = match Wire::connect(env.proxy).await {
+        Ok(wire) => wire,
+        Err(e) => return (Err(format!("no se pudo conectar: {e}")), None),
+    };
+    let request = format!("CONNECT {echo} HTTP/1.1\r\nhost: {echo}\r\n\r\nearly-bytes");
+    let result = async {
+        wire.send(request.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        let response = wire
+            .read_message(Expect::HeadResponse)
+            .await
+            .map_err(|e| e.to_string())?;
+        expect_status(&response, 200)?;
+        let echoed = wire.read_exact(11).await.map_err(|e| e.to_string())?;
+        match echoed.as_slice() {
+            b"early-bytes" => Ok(()),
+            other => Err(format!(
+                "el túnel devolvió `{}` en lugar de `early-bytes`",
+                other.escape_ascii()
+            )),
+        }
+    }
+    .await;
+    (result, Some(wire))
+}
+
+async fn chunked_roundtrip(env: &Env) -> CheckResult {
+    let origin = Origin::spawn(|_| {
+        b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"
+            .to_vec()
+    })
+    .await;
+    let request = format!(
+        "POST http://{0}/ HTTP/1.1\r\nhost: {0}\r\ntransfer-encoding: chunked\r\n\r\n4\r\nwiki\r\n5\r\npedia\r\n0\r\n\r\n",
+        origin.addr
+    );
+    let (response, wire) = exchange(env, request, Expect::Response).await;
+    let result = response.and_then(|response| {
+        expect_status(&response, 200)?;
+        if response.body != b"hello world" {
+            return Err(format!(
+                "body de respuesta `{}`, esperado `hello world`",
+                response.body.escape_ascii()
+            ));
+        }
+        match origin.received().pop() {
+            Some(request) if request.body == b"wikipedia" => Ok(()),
+            Some(request) => Err(format!(
+                "el origen recibió `{}`, esperado `wikipedia`",
+                request.body.escape_ascii()
+            )),
+            None => Err("el origen no recibió la petición".into()),
+        }
+    });
+    (result, wire)
+}
+
+async fn chunked_trailers(env: &Env) -> CheckResult {
+    let origin = Origin::spawn(|request| {
+        let te = request.header("te").unwrap_or_default().to_string();
+        format!(
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ntrailer: grpc-status\r\n\r\n\
+             2\r\nok\r\n0\r\ngrpc-status: 0\r\nx-origin-te: {te}\r\n\r\n"
+        )
+        .into_bytes()
+    })
+    .await;
+    let request = format!(
+        "GET http://{0}/ HTTP/1.1\r\nhost: {0}\r\nte: trailers\r\n\r\n",
+        origin.addr
+    );
+    let (response, wire) = exchange(env, request, Expect::Response).await;
+    let result = response.and_then(|response| {
+        expect_status(&response, 200)?;
+        if response.header("trailer") != Some("grpc-status") {
+            return Err("falta la cabecera `trailer`".into());
+        }
+        let trailer = |name: &str| {
+            response
+                .trailers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        match (
+            response.body.as_slice(),
+            trailer("grpc-status"),
+            trailer("x-origin-te"),
+        ) {
+            (b"ok", Some("0"), Some("trailers")) => Ok(()),
+            (_, None, _) => Err("el trailer `grpc-status` no llegó al cliente".into()),
+            (_, _, te) => Err(format!(
+                "el origen recibió `te: {}`",
+                te.unwrap_or_default()
+            )),
+        }
+    });
+    (result, wire)
+}
+
+async fn expect_100_continue(env: &Env) -> CheckResult {
+    let origin = Origin::ok().await;
+    let mut wire = match Wire::connect(env.proxy).await {
+        Ok(wire) => wire,
+        Err(e) => return (Err(format!("no se pudo conectar: {e}")), None),
+    };
+    let request = format!(
+        "POST http://{0}/ HTTP/1.1\r\nhost: {0}\r\ncontent-length: 4\r\nexpect: 100-continue\r\n\r\n",
+        origin.addr
+    );
+    let result = async {
+        wire.send(request.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        let interim = wire
+            .read_message(Expect::Response)
+            .await
+            .map_err(|e| format!("sin `100 Continue` antes del body: {e}"))?;
+        expect_status(&interim, 100)?;
+        wire.send(b"data").await.map_err(|e| e.to_string())?;
+        let response = wire
+            .read_message(Expect::Response)
+            .await
+            .map_err(|e| e.to_string())?;
+        expect_status(&response, 200)?;
+        match origin.received().pop() {
+            Some(request) if request.body == b"data" => Ok(()),
+            _ => Err("el origen no recibió el body".to_string()),
+        }
+    }
+    .await;
+    (result, Some(wire))
+}
+
+async fn head_without_body(env: &Env) -> CheckResult {
+    let origin = Origin::spawn(|request| {
+        if request.start_line.starts_with("HEAD") {
+            b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n".to_vec()
+        } else {
+            b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello".to_vec()
+        }
+    })
+    .await;
+    let mut wire = match Wire::connect(env.proxy).await {
+        Ok(wire) => wire,
+        Err(e) => return (Err(format!("no se pudo conectar: {e}")), None),
+    };
+    let result = async {
+        let head = format!(
+            "HEAD http://{0}/ HTTP/1.1\r\nhost: {0}\r\n\r\n",
+            origin.addr
+        );
+        wire.send(head.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        let response = wire
+            .read_message(Expect::HeadResponse)
+            .await
+            .map_err(|e| e.to_string())?;
+        expect_status(&response, 200)?;
+
+        // Any body bytes after the HEAD response would corrupt the next one.
+        let get = format!("GET http://{0}/ HTTP/1.1\r\nhost: {0}\r\n\r\n", origin.addr);
+        wire.send(get.as_bytes()).await.map_err(|e| e.to_string())?;
+        let response = wire
+            .read_message(Expect::Response)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.start_line.starts_with("HTTP/1.1 ") || response.body != b"hello" {
+            return Err(format!(
+                "la respuesta tras el HEAD se leyó como `{}`",
+                response.start_line
+            ));
+        }
+        Ok(())
+    }
+    .await;
+    (result, Some(wire))
+}
+
+async fn smuggling_cl_and_te(env: &Env) -> CheckResult {
+    let origin = Origin::ok().await;
+    let request = format!(
+        "POST http://{0}/ HTTP/1.1\r\nhost: {0}\r\ncontent-length: 4\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n",
+        origin.addr
+    );
+    let (response, wire) = exchange(env, request, Expect::Response).await;
+    let result = response.and_then(|response| {
+        if response.status() == Some(400) {
+            return Ok(());
+        }
+        match origin.received().pop() {
+            Some(request)
+                if request.has_header("content-length")
+                    && request.has_header("transfer-encoding") =>
+            {
+                Err("el origen recibió content-length y transfer-encoding a la vez".into())
+            }
+            Some(request) if request.header("content-length").is_some_and(|l| l != "0") => {
+                Err(format!(
+                    "el origen recibió content-length `{}` con un body vacío",
+                    request.header("content-length").unwrap_or_default()
+                ))
+            }
+            _ => Ok(()),
+        }
+    });
+    (result, wire)
+}
+
+async fn smuggling_duplicate_cl(env: &Env) -> CheckResult {
+    let origin = Origin::ok().await;
+    let request = format!(
+        "POST http://{0}/ HTTP/1.1\r\nhost: {0}\r\ncontent-length: 4\r\ncontent-length: 5\r\n\r\ndata",
+        origin.addr
+    );
+    let (response, wire) = exchange(env, request, Expect::Response).await;
+    let result = response.and_then(|response| expect_status(&response, 400));
+    (result, wire)
+}
+
+async fn absolute_form(env: &Env) -> CheckResult {
+    let origin = Origin::ok().await;
+    let request = format!(
+        "GET http://{0}/a/b?c=1 HTTP/1.1\r\nhost: {0}\r\n\r\n",
+        origin.addr
+    );
+    let (response, wire) = exchange(env, request, Expect::Response).await;
+    let result = response.and_then(|response| {
+        expect_status(&response, 200)?;
+        let request = origin
+            .received()
+            .pop()
+            .ok_or_else(|| "el origen no recibió la petición".to_string())?;
+        if request.start_line != "GET /a/b?c=1 HTTP/1.1" {
+            return Err(format!(
+                "línea de petición en el origen `{}`",
+                request.start_line
+            ));
+        }
+        match request.header("host") {
+            Some(host) if host == origin.addr.to_string() => Ok(()),
+            other => Err(format!("host en el origen {other:?}")),
+        }
+    });
+    (result, wire)
+}
+
+async fn origin_form_rejected(env: &Env) -> CheckResult {
+    let origin = Origin::ok().await;
+    let request = format!("GET /a HTTP/1.1\r\nhost: {}\r\n\r\n", origin.addr);
+    let (response, wire) = exchange(env, request, Expect::Response).await;
+    let result = response.and_then(|response| {
+        expect_status(&response, 400)?;
+        match origin.received().is_empty() {
+            true => Ok(()),
+            false => Err("una petición en origin-form llegó al origen".into()),
+        }
+    });
+    (result, wire)
+}
+
+#[cfg(all(test, feature = "conformance"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_conformance_suite() {
+        let outcomes = run_suite().await.unwrap();
+        let report: Vec<String> = outcomes.iter().map(|o| o.to_string()).collect();
+        assert_eq!(failures(&outcomes), 0, "\n{}", report.join("\n"));
+    }
+}
@@ -0,0 +1,1453 @@
+//! Aceptación de conexiones de clientes y límites de keep-alive.
+//!
+//! Si el listener admite más de un protocolo, cada conexión se clasifica por
+//! su primer byte antes de atenderla: `0x16` es un saludo TLS, `0x05` un
+//! cliente SOCKS5 y una letra mayúscula el método de una petición HTTP. Con
+//! TLS configurado no se clasifica nada: toda conexión empieza por el saludo
+//! TLS y dentro lleva HTTP.
+//!
+//! Las conexiones del socket UNIX ([`accept_unix_loop`]) hablan siempre HTTP
+//! sin TLS. A quién deja entrar lo deciden los permisos del archivo, así que
+//! no pasan por los baneos ni por el cupo de aceptación; para todo lo que va
+//! por IP cuentan como un cliente de loopback.
+
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderValue, CONNECTION};
+use hyper::http::Extensions;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Method, Request};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+
+use crate::affinity::AffinitySession;
+use crate::ban::Violation;
+use crate::connections::{ConnectionKind, TERMINATION_GRACE};
+use crate::events::Event;
+use crate::header_limits;
+use crate::identity::{ClientCertificate, Identity, IdentityError};
+use crate::lifecycle::{ConnectionRecord, RecordedIo};
+#[cfg(unix)]
+use crate::listener::UnixSocketListener;
+use crate::log_throttle::LogThrottle;
+use crate::metrics::RequestLabels;
+use crate::proxy::{handle_request, ProxyContext};
+use crate::resources;
+use crate::settings::{BanAction, ConnectionLimits, ListenerProtocols};
+use crate::socks::{self, SocksBridge};
+use crate::throttle::ConnectionThrottle;
+use crate::tls;
+use crate::trailers::{TrailerSlot, TrailerWriter};
+use crate::tunnel_quality::SocketProbe;
+
+/// Primeros bytes de una conexión rechazada que se muestran en el log.
+const LOGGED_BYTES: usize = 16;
+
+/// Pausa de la aceptación cuando no quedan descriptores.
+const FD_EXHAUSTED_PAUSE: Duration = Duration::from_millis(250);
+
+/// Motivo por el que el proxy cerró una conexión keep-alive de un cliente.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Idle,
+    Lifetime,
+    MaxRequests,
+    /// Cerrada desde la API de administración.
+    Admin,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 4] = [
+        CloseReason::Idle,
+        CloseReason::Lifetime,
+        CloseReason::MaxRequests,
+        CloseReason::Admin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Idle => "idle",
+            CloseReason::Lifetime => "lifetime",
+            CloseReason::MaxRequests => "max_requests",
+            CloseReason::Admin => "admin",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Quién está al otro lado de la conexión de un cliente.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Un proceso conectado al socket UNIX, con sus credenciales si el
+    /// sistema las da.
+    Unix {
+        uid: Option<u32>,
+        pid: Option<i32>,
+    },
+}
+
+impl Peer {
+    /// Dirección con la que se aplican las reglas por IP: la de loopback
+    /// para los clientes del socket UNIX.
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Peer::Tcp(addr) => *addr,
+            Peer::Unix { .. } => SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        }
+    }
+
+    /// El de la conexión en la que llegó `req`, o `remote_addr` si no pasó
+    /// por un listener.
+    pub fn of<B>(req: &Request<B>, remote_addr: SocketAddr) -> Self {
+        req.extensions()
+            .get::<Peer>()
+            .copied()
+            .unwrap_or(Peer::Tcp(remote_addr))
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => addr.fmt(f),
+            Peer::Unix { uid, pid } => {
+                f.write_str("unix")?;
+                if let Some(uid) = uid {
+                    write!(f, ":uid={uid}")?;
+                }
+                if let Some(pid) = pid {
+                    write!(f, ":pid={pid}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Dirección local de la conexión en la que llegó una petición.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalAddr(pub SocketAddr);
+
+/// Socket del cliente en el que llegó una petición, para leer su `TCP_INFO`
+/// mientras dura un túnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSocket(pub SocketProbe);
+
+/// Protocolo de una conexión según sus primeros bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Http,
+    Tls,
+    Socks5,
+    Unknown,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 4] = [
+        Protocol::Http,
+        Protocol::Tls,
+        Protocol::Socks5,
+        Protocol::Unknown,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Http => "http",
+            Protocol::Tls => "tls",
+            Protocol::Socks5 => "socks5",
+            Protocol::Unknown => "unknown",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    fn detect(first: u8) -> Self {
+        match first {
+            // TLS handshake record.
+            0x16 => Protocol::Tls,
+            0x05 => Protocol::Socks5,
+            // Every HTTP method starts with an uppercase token.
+            b'A'..=b'Z' => Protocol::Http,
+            _ => Protocol::Unknown,
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Acepta conexiones hasta que empiece la parada o el listener falle de
+/// forma irrecuperable.
+pub async fn accept_loop(
+    listener: TcpListener,
+    ctx: ProxyContext,
+    limits: ConnectionLimits,
+    protocols: ListenerProtocols,
+) -> anyhow::Result<()> {
+    let fd_log = LogThrottle::new(Duration::from_secs(10));
+    let listener_addr = listener.local_addr()?;
+    ctx.shutdown().mark_accepting();
+    loop {
+        if let Some(guard) = ctx.accept_guard() {
+            tokio::select! {
+                _ = guard.wait_for_descriptors(ctx.metrics()) => {}
+                _ = ctx.shutdown().draining() => {
+                    debug!("Listener cerrado por la parada");
+                    return Ok(());
+                }
+            }
+        }
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = ctx.shutdown().draining() => {
+                debug!("Listener cerrado por la parada");
+                return Ok(());
+            }
+        };
+        let (stream, remote_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) if resources::is_fd_exhaustion(&e) => {
+                // Retrying at once would spin: the connection stays in the
+                // backlog until a descriptor is freed.
+                ctx.metrics().record_accept_fd_exhausted();
+                if let Some(suppressed) = fd_log.should_log() {
+                    warn!(error = %e, suppressed, pause_ms = FD_EXHAUSTED_PAUSE.as_millis() as u64, "Sin descriptores libres; se pausa la aceptación");
+                }
+                tokio::time::sleep(FD_EXHAUSTED_PAUSE).await;
+                continue;
+            }
+            Err(e) => {
+                // Per-connection errors (e.g. the peer reset before accept)
+                // must not take the listener down.
+                error!(error = %e, "Error aceptando conexión");
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                continue;
+            }
+        };
+        if ctx.is_banned(remote_addr.ip()) {
+            reject_banned(&ctx, stream);
+            continue;
+        }
+        // Dropping the stream closes it before anything is read.
+        if let Some(guard) = ctx.accept_guard() {
+            if !guard.admit(remote_addr.ip(), ctx.metrics()) {
+                continue;
+            }
+        }
+        let ctx = ctx.clone();
+        tokio::spawn(serve_client(
+            ctx,
+            stream,
+            remote_addr,
+            listener_addr,
+            limits,
+            protocols,
+        ));
+    }
+}
+
+/// Acepta conexiones en el socket UNIX hasta que empiece la parada, que
+/// borra el archivo del socket.
+#[cfg(unix)]
+pub async fn accept_unix_loop(
+    listener: UnixSocketListener,
+    ctx: ProxyContext,
+    limits: ConnectionLimits,
+) -> anyhow::Result<()> {
+    let fd_log = LogThrottle::new(Duration::from_secs(10));
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = ctx.shutdown().draining() => {
+                debug!(path = %listener.path().display(), "Socket UNIX cerrado por la parada");
+                return Ok(());
+            }
+        };
+        let stream = match accepted {
+            Ok(stream) => stream,
+            Err(e) if resources::is_fd_exhaustion(&e) => {
+                ctx.metrics().record_accept_fd_exhausted();
+                if let Some(suppressed) = fd_log.should_log() {
+                    warn!(error = %e, suppressed, pause_ms = FD_EXHAUSTED_PAUSE.as_millis() as u64, "Sin descriptores libres; se pausa la aceptación");
+                }
+                tokio::time::sleep(FD_EXHAUSTED_PAUSE).await;
+                continue;
+            }
+            Err(e) => {
+                error!(error = %e, "Error aceptando conexión en el socket UNIX");
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                continue;
+            }
+        };
+        let peer = match stream.peer_cred() {
+            Ok(cred) => Peer::Unix {
+                uid: Some(cred.uid()),
+                pid: cred.pid(),
+            },
+            Err(_) => Peer::Unix {
+                uid: None,
+                pid: None,
+            },
+        };
+        ctx.metrics().record_protocol(Protocol::Http);
+        tokio::spawn(serve_connection(
+            ctx.clone(),
+            RecordedIo::new(stream, None),
+            ClientConn::new(peer, None, None),
+            limits,
+            Protocol::Http,
+        ));
+    }
+}
+
+/// Detecta el protocolo del cliente si hace falta y atiende la conexión con
+/// él; las de un protocolo no admitido se cierran.
+async fn serve_client(
+    ctx: ProxyContext,
+    mut stream: TcpStream,
+    remote_addr: SocketAddr,
+    listener: SocketAddr,
+    limits: ConnectionLimits,
+    protocols: ListenerProtocols,
+) {
+    let record = ctx
+        .connection_hooks()
+        .and_then(|hooks| ConnectionRecord::open(hooks, remote_addr, listener));
+    let local_addr = stream.local_addr().ok().map(LocalAddr);
+    let socket = ClientSocket(SocketProbe::new(&stream));
+    if let Some(acceptor) = ctx.tls().cloned() {
+        ctx.metrics().record_protocol(Protocol::Tls);
+        let stream = match tokio::time::timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+            .await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                warn!(%remote_addr, error = %e, "Saludo TLS fallido; conexión cerrada");
+                return;
+            }
+            Err(_) => {
+                warn!(%remote_addr, "El cliente no completó el saludo TLS a tiempo; conexión cerrada");
+                return;
+            }
+        };
+        let mut client = ClientConn::new(Peer::Tcp(remote_addr), local_addr, Some(socket));
+        let certificate = stream.get_ref().1.peer_certificates();
+        if let (Some(mapper), Some([leaf, ..])) = (ctx.identity(), certificate) {
+            let identity = ClientCertificate::from_der(leaf)
+                .ok_or(IdentityError::Unknown)
+                .and_then(|cert| mapper.resolve(&cert));
+            match identity {
+                Ok(identity) => {
+                    debug!(%remote_addr, user = identity.user(), profile = identity.profile(), "Cliente identificado por certificado");
+                    client.identity = Some(identity);
+                }
+                Err(e) => {
+                    warn!(%remote_addr, error = %e, "Certificado de cliente rechazado; conexión cerrada");
+                    ctx.record_violation(remote_addr.ip(), Violation::AuthFailure);
+                    return;
+                }
+            }
+        }
+        serve_connection(
+            ctx,
+            RecordedIo::new(stream, record),
+            client,
+            limits,
+            Protocol::Tls,
+        )
+        .await;
+        return;
+    }
+    if !protocols.detects() {
+        ctx.metrics().record_protocol(Protocol::Http);
+        serve_connection(
+            ctx,
+            RecordedIo::new(stream, record),
+            ClientConn::new(Peer::Tcp(remote_addr), local_addr, Some(socket)),
+            limits,
+            Protocol::Http,
+        )
+        .await;
+        return;
+    }
+    let mut first = [0u8; LOGGED_BYTES];
+    let (protocol, peeked) =
+        match tokio::time::timeout(protocols.detect_timeout(), stream.peek(&mut first)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => return,
+            Ok(Ok(n)) => (Protocol::detect(first[0]), n),
+            // Clients that wait for the server to speak first are left to
+            // hyper, which applies the usual idle limits.
+            Err(_) => (Protocol::Http, 0),
+        };
+    ctx.metrics().record_protocol(protocol);
+    let first = &first[..peeked];
+
+    let enabled = match protocol {
+        Protocol::Http => protocols.http(),
+        Protocol::Socks5 => protocols.socks5(),
+        Protocol::Tls | Protocol::Unknown => false,
+    };
+    if !enabled {
+        if protocol == Protocol::Tls {
+            warn!(%remote_addr, first_bytes = %hex(first), "Cliente TLS en un puerto sin modo HTTPS; conexión cerrada");
+        } else {
+            warn!(
+                %remote_addr,
+                protocol = protocol.as_str(),
+                first_bytes = %hex(first),
+                "Protocolo no admitido en este puerto; conexión cerrada"
+            );
+        }
+        return;
+    }
+
+    if protocol == Protocol::Socks5 {
+        match socks::handshake(&mut stream).await {
+            Ok(target) => {
+                let bridge = SocksBridge::new(stream, &target);
+                serve_connection(
+                    ctx,
+                    RecordedIo::new(bridge, record),
+                    ClientConn::new(Peer::Tcp(remote_addr), local_addr, Some(socket)),
+                    limits,
+                    protocol,
+                )
+                .await;
+            }
+            Err(e) => warn!(
+                %remote_addr,
+                first_bytes = %hex(first),
+                error = %e,
+                "Saludo SOCKS5 fallido; conexión cerrada"
+            ),
+        }
+        return;
+    }
+    serve_connection(
+        ctx,
+        RecordedIo::new(stream, record),
+        ClientConn::new(Peer::Tcp(remote_addr), local_addr, Some(socket)),
+        limits,
+        protocol,
+    )
+    .await;
+}
+
+/// Corta la conexión de un cliente baneado sin leer nada de ella.
+fn reject_banned(ctx: &ProxyContext, mut stream: TcpStream) {
+    let action = ctx.bans().map(|ban| ban.settings().action());
+    if action != Some(BanAction::Forbidden) {
+        return;
+    }
+    tokio::spawn(async move {
+        let response = b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+        let _ = stream.write_all(response).await;
+        let _ = stream.shutdown().await;
+    });
+}
+
+/// Copia en `to` las extensiones que [`serve_connection`] pone en cada
+/// petición, para una copia de la petición que se envía otra vez.
+pub(crate) fn copy_extensions(from: &Extensions, to: &mut Extensions) {
+    fn copy<T: Clone + Send + Sync + 'static>(from: &Extensions, to: &mut Extensions) {
+        if let Some(value) = from.get::<T>() {
+            to.insert(value.clone());
+        }
+    }
+    copy::<Arc<AffinitySession>>(from, to);
+    copy::<Arc<ConnectionThrottle>>(from, to);
+    copy::<TrailerSlot>(from, to);
+    copy::<Protocol>(from, to);
+    copy::<LocalAddr>(from, to);
+    copy::<ClientSocket>(from, to);
+    copy::<Identity>(from, to);
+    copy::<Peer>(from, to);
+}
+
+#[derive(Debug)]
+struct ConnState {
+    /// El de [`crate::connections::ConnectionRegistry`].
+    id: u64,
+    active: AtomicUsize,
+    requests: AtomicU64,
+    last_activity: Mutex<Instant>,
+}
+
+impl ConnState {
+    fn new(id: u64) -> Self {
+        Self {
+            id,
+            active: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            last_activity: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn begin(&self) -> u64 {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.requests.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn finish(&self) {
+        *self.last_activity.lock().expect("lock de actividad") = Instant::now();
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn idle_for(&self) -> Option<Duration> {
+        if self.active.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        Some(
+            self.last_activity
+                .lock()
+                .expect("lock de actividad")
+                .elapsed(),
+        )
+    }
+}
+
+/// Lo que se sabe del cliente al aceptar su conexión; [`serve_connection`]
+/// lo pone en las extensiones de cada una de sus peticiones.
+struct ClientConn {
+    peer: Peer,
+    local_addr: Option<LocalAddr>,
+    socket: Option<ClientSocket>,
+    /// Usuario y perfil de su certificado, en el listener TLS con
+    /// `[identity]`.
+    identity: Option<Identity>,
+}
+
+impl ClientConn {
+    fn new(peer: Peer, local_addr: Option<LocalAddr>, socket: Option<ClientSocket>) -> Self {
+        Self {
+            peer,
+            local_addr,
+            socket,
+            identity: None,
+        }
+    }
+}
+
+async fn serve_connection<S>(
+    ctx: ProxyContext,
+    stream: RecordedIo<S>,
+    client: ClientConn,
+    limits: ConnectionLimits,
+    protocol: Protocol,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ClientConn {
+        peer,
+        local_addr,
+        socket,
+        identity,
+    } = client;
+    let remote_addr = peer.addr();
+    let _tracked = ctx.shutdown().track();
+    // Listed until this function returns; a tunnel takes over the socket
+    // and is listed in `tunnels` from then on.
+    let open = ctx
+        .connections()
+        .open(remote_addr, ConnectionKind::Http, protocol, None);
+    let connection = open.record().clone();
+    let state = Arc::new(ConnState::new(connection.id()));
+    // Dropped with the service when the client goes away, which closes the
+    // upstream sockets bound to this client.
+    let affinity = ctx.affinity_session();
+    let throttle = ctx.connection_throttle();
+    // The socket holds the record too, so a tunnel keeps the connection open
+    // for the hooks until the tunnel closes.
+    let record = stream.record().cloned();
+    let stream = TrailerWriter::new(stream);
+    let trailers = stream.slot();
+    let service = {
+        let ctx = ctx.clone();
+        let state = state.clone();
+        let connection = connection.clone();
+        service_fn(move |mut req| {
+            let ctx = ctx.clone();
+            let state = state.clone();
+            let connection = connection.clone();
+            let record = record.clone();
+            if let Some(session) = &affinity {
+                req.extensions_mut().insert(session.clone());
+            }
+            if let Some(throttle) = &throttle {
+                req.extensions_mut().insert(throttle.clone());
+            }
+            req.extensions_mut().insert(trailers.clone());
+            req.extensions_mut().insert(protocol);
+            if let Some(local_addr) = local_addr {
+                req.extensions_mut().insert(local_addr);
+            }
+            if let Some(socket) = socket {
+                req.extensions_mut().insert(socket);
+            }
+            if let Some(identity) = &identity {
+                req.extensions_mut().insert(identity.clone());
+            }
+            req.extensions_mut().insert(peer);
+            async move {
+                let is_connect = req.method() == Method::CONNECT;
+                let served = state.begin();
+                connection.request();
+                let labels = RequestLabels {
+                    method: req.method().clone(),
+                    host: req.uri().host().unwrap_or_default().to_string(),
+                    protocol,
+                    client: remote_addr.ip(),
+                };
+                let started = Instant::now();
+                ctx.metrics().record_request_started(&labels);
+                if let Some(record) = &record {
+                    record.request(&labels);
+                }
+                ctx.publish(|| Event::RequestStarted {
+                    connection: state.id,
+                    client: remote_addr,
+                    method: labels.method.to_string(),
+                    host: labels.host.clone(),
+                    protocol: protocol.as_str(),
+                });
+                let access = ctx
+                    .access_log()
+                    .map(|log| log.start(&mut req, remote_addr, ctx.sanitizer()));
+                let mut result = handle_request(ctx.clone(), remote_addr, req).await;
+                state.finish();
+                let status = result.as_ref().ok().map(|response| response.status());
+                let elapsed = started.elapsed();
+                ctx.metrics()
+                    .record_request_finished(&labels, status, elapsed);
+                ctx.publish(|| Event::RequestFinished {
+                    connection: state.id,
+                    client: remote_addr,
+                    method: labels.method.to_string(),
+                    host: labels.host.clone(),
+                    status: status.map(|status| status.as_u16()),
+                    elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+                });
+
+                if let (Ok(response), false, true) =
+                    (&mut result, is_connect, ctx.drain().closes_keepalive())
+                {
+                    response
+                        .headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static("close"));
+                }
+                if let (Ok(response), Some(max)) = (&mut result, limits.max_requests()) {
+                    // Tunnels take over the socket, so only plain exchanges can
+                    // ask hyper to close after the response.
+                    if served >= max && !is_connect {
+                        response
+                            .headers_mut()
+                            .insert(CONNECTION, HeaderValue::from_static("close"));
+                        debug!(%peer, served, "Límite de peticiones por conexión");
+                        ctx.metrics()
+                            .record_connection_close(CloseReason::MaxRequests);
+                    }
+                }
+                if let Some(access) = access {
+                    access.finish(result.as_mut().ok());
+                }
+                result
+            }
+        })
+    };
+
+    let mut http = Http::new();
+    if let Some(limits) = ctx.header_limits() {
+        http.max_buf_size(header_limits::buffer_size(limits))
+            .http2_max_header_list_size(header_limits::h2_list_size(limits));
+    }
+    let conn = http.serve_connection(stream, service).with_upgrades();
+    tokio::pin!(conn);
+
+    let shutdown = ctx.shutdown().clone();
+    let started = Instant::now();
+    let mut tick = tokio::time::interval(limits.check_interval());
+    let mut closing = false;
+    let mut terminated = false;
+    let result = loop {
+        tokio::select! {
+        result = conn.as_mut() => break result,
+        _ = shutdown.aborting() => {
+            debug!(%peer, "Conexión abortada al vencer el drenaje");
+            break Ok(());
+        }
+        () = connection.terminated(), if !terminated => {
+            let reason = connection.termination().unwrap_or_default();
+            info!(%peer, reason, "Conexión cerrada desde la API de administración");
+            ctx.metrics().record_connection_close(CloseReason::Admin);
+            if state.requests.load(Ordering::SeqCst) == 0 {
+                break Ok(());
+            }
+            conn.as_mut().graceful_shutdown();
+            closing = true;
+            terminated = true;
+        }
+        () = tokio::time::sleep(TERMINATION_GRACE), if terminated => {
+            debug!(%peer, "Conexión soltada al vencer el margen de cierre");
+            break Ok(());
+        }
+        _ = shutdown.draining(), if !closing => {
+            if state.requests.load(Ordering::SeqCst) == 0 {
+                break Ok(());
+            }
+            conn.as_mut().graceful_shutdown();
+            closing = true;
+        }
+        _ = tick.tick(), if !closing && !limits.is_unlimited() => {
+                let reason = if limits
+                    .max_lifetime()
+                    .is_some_and(|max| started.elapsed() >= max)
+                {
+                    Some(CloseReason::Lifetime)
+                } else if limits
+                    .idle_timeout()
+                    .zip(state.idle_for())
+                    .is_some_and(|(max, idle)| idle >= max)
+                {
+                    Some(CloseReason::Idle)
+                } else {
+                    None
+                };
+
+                if let Some(reason) = reason {
+                    debug!(%peer, reason = reason.as_str(), "Cerrando conexión del cliente");
+                    ctx.metrics().record_connection_close(reason);
+                    // Hyper's graceful shutdown ignores connections that
+                    // never sent a request, so those are simply dropped.
+                    if state.requests.load(Ordering::SeqCst) == 0 {
+                        break Ok(());
+                    }
+                    // Hyper finishes any in-flight exchange before closing.
+                    conn.as_mut().graceful_shutdown();
+                    closing = true;
+                }
+            }
+        }
+    };
+
+    if let Err(e) = result {
+        debug!(%peer, error = %e, "Conexión del cliente terminó con error");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialer::{Dialer, SystemResolver};
+    use crate::lifecycle::{ConnectionHooks, ConnectionSummary};
+    use crate::settings::DialSettings;
+    use hyper::{Body, Request, StatusCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn spawn_origin() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(|_req| async {
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(Body::from("ok")))
+                });
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        });
+        addr
+    }
+
+    async fn spawn_proxy(limits: ConnectionLimits) -> (SocketAddr, ProxyContext) {
+        spawn_listener(limits, ListenerProtocols::default()).await
+    }
+
+    async fn spawn_listener(
+        limits: ConnectionLimits,
+        protocols: ListenerProtocols,
+    ) -> (SocketAddr, ProxyContext) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ctx = ProxyContext::new(Dialer::new(
+            DialSettings::default(),
+            Arc::new(SystemResolver),
+        ));
+        tokio::spawn(accept_loop(listener, ctx.clone(), limits, protocols));
+        (addr, ctx)
+    }
+
+    async fn request(
+        sender: &mut hyper::client::conn::SendRequest<Body>,
+        origin: SocketAddr,
+    ) -> hyper::Response<Body> {
+        let req = Request::get(format!("http://{origin}/"))
+            .body(Body::empty())
+            .unwrap();
+        sender.send_request(req).await.unwrap()
+    }
+
+    async fn is_closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0u8; 1];
+        matches!(
+            tokio::time::timeout(Duration::from_millis(100), stream.read(&mut buf)).await,
+            Ok(Ok(0)) | Ok(Err(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_closed_active_survives() {
+        let origin = spawn_origin().await;
+        let limits = ConnectionLimits::default().with_idle_timeout(Duration::from_millis(300));
+        let (proxy, ctx) = spawn_proxy(limits).await;
+
+        // Connection that never sends anything.
+        let mut idle = TcpStream::connect(proxy).await.unwrap();
+
+        // Connection that keeps making requests more often than the timeout.
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        let conn = tokio::spawn(conn);
+        for _ in 0..6 {
+            let res = request(&mut sender, origin).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            hyper::body::to_bytes(res.into_body()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!conn.is_finished());
+
+        assert!(is_closed(&mut idle).await);
+        assert!(ctx.metrics().connection_closes(CloseReason::Idle) >= 1);
+
+        // Once it goes quiet the active connection is closed too.
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert!(conn.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_max_requests_per_connection() {
+        let origin = spawn_origin().await;
+        let limits = ConnectionLimits::default().with_max_requests(2);
+        let (proxy, ctx) = spawn_proxy(limits).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let req = format!("GET http://{origin}/ HTTP/1.1\r\nhost: {origin}\r\n\r\n");
+        stream.write_all(req.as_bytes()).await.unwrap();
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut received))
+            .await
+            .expect("el proxy debe cerrar tras la segunda respuesta")
+            .unwrap();
+        let text = String::from_utf8_lossy(&received);
+        assert_eq!(text.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(text.contains("connection: close"));
+        assert_eq!(ctx.metrics().connection_closes(CloseReason::MaxRequests), 1);
+    }
+
+    async fn socks_connect(proxy: SocketAddr, target: SocketAddr) -> (TcpStream, u8) {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut chosen = [0u8; 2];
+        stream.read_exact(&mut chosen).await.unwrap();
+        assert_eq!(chosen, [5, 0]);
+        let SocketAddr::V4(target) = target else {
+            unreachable!()
+        };
+        let mut request = vec![5, 1, 0, 1];
+        request.extend(target.ip().octets());
+        request.extend(target.port().to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        (stream, reply[1])
+    }
+
+    #[tokio::test]
+    async fn test_protocols_detected_on_one_port() {
+        let origin = spawn_origin().await;
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        let protocols = ListenerProtocols::default().with_socks5(true);
+        let (proxy, ctx) = spawn_listener(ConnectionLimits::default(), protocols).await;
+
+        let mut http = TcpStream::connect(proxy).await.unwrap();
+        let req =
+            format!("GET http://{origin}/ HTTP/1.1\r\nhost: {origin}\r\nconnection: close\r\n\r\n");
+        http.write_all(req.as_bytes()).await.unwrap();
+        let mut received = Vec::new();
+        http.read_to_end(&mut received).await.unwrap();
+        assert!(received.starts_with(b"HTTP/1.1 200 OK"));
+
+        let (mut socks, reply) = socks_connect(proxy, echo_addr).await;
+        assert_eq!(reply, 0);
+        socks.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        socks.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        // A refused destination maps to the matching SOCKS reply.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let (mut refused, reply) = socks_connect(proxy, closed_addr).await;
+        assert_eq!(reply, 0x05);
+        assert!(is_closed(&mut refused).await);
+
+        // No TLS acceptor and garbage: both are closed without a response.
+        for opening in [&[0x16, 0x03, 0x01, 0x00, 0xa5][..], &[0x00, 0xff, 0x13]] {
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            stream.write_all(opening).await.unwrap();
+            assert!(is_closed(&mut stream).await);
+        }
+
+        let metrics = ctx.metrics();
+        assert_eq!(metrics.protocols(Protocol::Http), 1);
+        assert_eq!(metrics.protocols(Protocol::Socks5), 2);
+        assert_eq!(metrics.protocols(Protocol::Tls), 1);
+        assert_eq!(metrics.protocols(Protocol::Unknown), 1);
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_defaults_to_http() {
+        let origin = spawn_origin().await;
+        let protocols = ListenerProtocols::default()
+            .with_socks5(true)
+            .with_detect_timeout(Duration::from_millis(50));
+        let (proxy, ctx) = spawn_listener(ConnectionLimits::default(), protocols).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let req =
+            format!("GET http://{origin}/ HTTP/1.1\r\nhost: {origin}\r\nconnection: close\r\n\r\n");
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(received.starts_with(b"HTTP/1.1 200 OK"));
+        assert_eq!(ctx.metrics().protocols(Protocol::Http), 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_info_reports_http_version_and_client() {
+        let (proxy, ctx) = spawn_proxy(ConnectionLimits::default()).await;
+        let fetch = |http2: bool| async move {
+            let stream = TcpStream::connect(proxy).await.unwrap();
+            let local = stream.local_addr().unwrap();
+            let (mut sender, conn) = hyper::client::conn::Builder::new()
+                .http2_only(http2)
+                .handshake(stream)
+                .await
+                .unwrap();
+            tokio::spawn(conn);
+            // HTTP/2 always carries an authority, here the proxy itself.
+            let uri = if http2 {
+                format!("http://proxy.local:{}/proxy-info", proxy.port())
+            } else {
+                "/proxy-info".to_string()
+            };
+            let res = sender
+                .send_request(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            (info, local)
+        };
+
+        let (info, local) = fetch(false).await;
+        assert_eq!(info["http_version"], "HTTP/1.1");
+        assert_eq!(info["protocol"], "http");
+        assert_eq!(info["client"]["ip"], "127.0.0.1");
+        assert_eq!(info["client"]["port"], local.port());
+        assert!(info["tls"].is_null() && info["identity"].is_null());
+        assert_eq!(info["features"], serde_json::json!([]));
+
+        let (info, local) = fetch(true).await;
+        assert_eq!(info["http_version"], "HTTP/2");
+        assert_eq!(info["client"]["port"], local.port());
+        assert_eq!(ctx.metrics().requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_lifetime_closes_between_requests() {
+        let origin = spawn_origin().await;
+        let limits = ConnectionLimits::default().with_max_lifetime(Duration::from_millis(200));
+        let (proxy, ctx) = spawn_proxy(limits).await;
+
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        let conn = tokio::spawn(conn);
+        let res = request(&mut sender, origin).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(conn.is_finished());
+        assert_eq!(ctx.metrics().connection_closes(CloseReason::Lifetime), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_burst_from_one_address_is_cut_before_http() {
+        use crate::listener::{self, AcceptGuard};
+        use crate::settings::{AcceptRate, ListenerHardening};
+
+        let origin = spawn_origin().await;
+        let hardening = ListenerHardening::default()
+            .with_backlog(64)
+            .with_defer_accept(Duration::from_secs(1))
+            .with_accept_rate(AcceptRate::new(0.1, 3));
+        let listener = listener::bind("127.0.0.1:0".parse().unwrap(), hardening).unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let ctx = ProxyContext::new(Dialer::new(
+            DialSettings::default(),
+            Arc::new(SystemResolver),
+        ))
+        .with_accept_guard(AcceptGuard::new(hardening).unwrap());
+        tokio::spawn(accept_loop(
+            listener,
+            ctx.clone(),
+            ConnectionLimits::default(),
+            ListenerProtocols::default(),
+        ));
+
+        let req =
+            format!("GET http://{origin}/ HTTP/1.1\r\nhost: {origin}\r\nconnection: close\r\n\r\n");
+        let mut served = 0;
+        for _ in 0..10 {
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            // Deferred accept only surfaces the connection once data arrives.
+            let _ = stream.write_all(req.as_bytes()).await;
+            let mut received = Vec::new();
+            let _ = stream.read_to_end(&mut received).await;
+            if received.starts_with(b"HTTP/1.1 200 OK") {
+                served += 1;
+            } else {
+                assert!(received.is_empty());
+            }
+        }
+        assert_eq!(served, 3);
+        assert_eq!(ctx.metrics().accept_rejections(), 7);
+        // The rejected ones never reached the HTTP layer.
+        assert_eq!(ctx.metrics().requests(), 3);
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        next: AtomicU64,
+        opened: Mutex<Vec<(u64, SocketAddr)>>,
+        requests: Mutex<Vec<(u64, Method, String)>>,
+        closed: Mutex<Vec<(u64, ConnectionSummary)>>,
+    }
+
+    impl ConnectionHooks for Recorder {
+        type Conn = u64;
+
+        fn on_connection_open(&self, remote_addr: SocketAddr, listener: SocketAddr) -> Option<u64> {
+            let id = self.next.fetch_add(1, Ordering::SeqCst);
+            self.opened.lock().unwrap().push((id, listener));
+            assert!(remote_addr.ip().is_loopback());
+            Some(id)
+        }
+
+        fn on_request(&self, conn: &u64, request: &RequestLabels) {
+            let entry = (*conn, request.method.clone(), request.host.clone());
+            self.requests.lock().unwrap().push(entry);
+        }
+
+        fn on_connection_close(&self, conn: u64, summary: &ConnectionSummary) {
+            self.closed.lock().unwrap().push((conn, *summary));
+        }
+    }
+
+    async fn spawn_hooked<H: ConnectionHooks>(hooks: Arc<H>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ctx = ProxyContext::new(Dialer::new(
+            DialSettings::default(),
+            Arc::new(SystemResolver),
+        ))
+        .with_connection_hooks(hooks);
+        tokio::spawn(accept_loop(
+            listener,
+            ctx,
+            ConnectionLimits::default(),
+            ListenerProtocols::default(),
+        ));
+        addr
+    }
+
+    /// Escribe `request` y lee hasta que el proxy cierre.
+    async fn exchange(proxy: SocketAddr, request: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        received
+    }
+
+    async fn wait_for_closes(recorder: &Recorder, count: usize) -> Vec<(u64, ConnectionSummary)> {
+        for _ in 0..100 {
+            let closed = recorder.closed.lock().unwrap().clone();
+            if closed.len() >= count {
+                return closed;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("faltan cierres de conexión");
+    }
+
+    #[tokio::test]
+    async fn test_connection_hooks_pair_open_and_close_with_a_summary() {
+        let origin = spawn_origin().await;
+        let recorder = Arc::new(Recorder::default());
+        let proxy = spawn_hooked(recorder.clone()).await;
+
+        // Two keep-alive requests on one connection.
+        let get = format!("GET http://{origin}/ HTTP/1.1\r\nhost: {origin}\r\n\r\n");
+        let last =
+            format!("GET http://{origin}/ HTTP/1.1\r\nhost: {origin}\r\nconnection: close\r\n\r\n");
+        let sent = format!("{get}{last}");
+        let received = exchange(proxy, sent.as_bytes()).await;
+        assert_eq!(
+            String::from_utf8_lossy(&received)
+                .matches("HTTP/1.1 200 OK")
+                .count(),
+            2
+        );
+        let closed = wait_for_closes(&recorder, 1).await;
+        assert_eq!(closed[0].0, 0);
+        let summary = closed[0].1;
+        assert_eq!(summary.requests, 2);
+        assert_eq!(summary.bytes_received, sent.len() as u64);
+        assert_eq!(summary.bytes_sent, received.len() as u64);
+
+        // A tunnel keeps its connection open until the tunnel closes.
+        let tunneled = format!(
+            "CONNECT {origin} HTTP/1.1\r\nhost: {origin}\r\n\r\n\
+             GET / HTTP/1.1\r\nhost: {origin}\r\nconnection: close\r\n\r\n"
+        );
+        let received = exchange(proxy, tunneled.as_bytes()).await;
+        let text = String::from_utf8_lossy(&received);
+        assert!(text.starts_with("HTTP/1.1 200 OK") && text.ends_with("ok"));
+        let closed = wait_for_closes(&recorder, 2).await;
+        assert_eq!(closed[1].0, 1);
+        assert_eq!(closed[1].1.requests, 1);
+        assert_eq!(closed[1].1.bytes_received, tunneled.len() as u64);
+        assert_eq!(closed[1].1.bytes_sent, received.len() as u64);
+
+        // One that never says anything still gets its close.
+        drop(TcpStream::connect(proxy).await.unwrap());
+        let closed = wait_for_closes(&recorder, 3).await;
+        assert_eq!(closed[2].0, 2);
+        assert_eq!(closed[2].1.requests, 0);
+
+        let opened = recorder.opened.lock().unwrap().clone();
+        assert_eq!(opened.len(), 3);
+        assert!(opened.iter().all(|(_, listener)| *listener == proxy));
+        let requests = recorder.requests.lock().unwrap().clone();
+        let host = origin.ip().to_string();
+        assert_eq!(
+            requests,
+            vec![
+                (0, Method::GET, host.clone()),
+                (0, Method::GET, host.clone()),
+                (1, Method::CONNECT, host),
+            ]
+        );
+    }
+
+    #[derive(Default)]
+    struct Panicky {
+        opens: AtomicU64,
+        closes: AtomicU64,
+    }
+
+    impl ConnectionHooks for Panicky {
+        type Conn = ();
+
+        fn on_connection_open(&self, _: SocketAddr, _: SocketAddr) -> Option<()> {
+            if self.opens.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("apertura");
+            }
+            Some(())
+        }
+
+        fn on_request(&self, _: &(), _: &RequestLabels) {
+            panic!("petición");
+        }
+
+        fn on_connection_close(&self, _: (), _: &ConnectionSummary) {
+            self.closes.fetch_add(1, Ordering::SeqCst);
+            panic!("cierre");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicking_connection_hooks_do_not_break_the_connection() {
+        let origin = spawn_origin().await;
+        let hooks = Arc::new(Panicky::default());
+        let proxy = spawn_hooked(hooks.clone()).await;
+        let req =
+            format!("GET http://{origin}/ HTTP/1.1\r\nhost: {origin}\r\nconnection: close\r\n\r\n");
+        for _ in 0..3 {
+            let received = exchange(proxy, req.as_bytes()).await;
+            assert!(received.starts_with(b"HTTP/1.1 200 OK"));
+        }
+        // The first open panicked, so only the other two are closed.
+        for _ in 0..100 {
+            if hooks.closes.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(hooks.opens.load(Ordering::SeqCst), 3);
+        assert_eq!(hooks.closes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener_serves_requests_and_tunnels_inside_the_session() {
+        use crate::settings::TlsSettings;
+        use tokio_rustls::rustls::crypto::ring;
+        use tokio_rustls::rustls::pki_types::pem::PemObject;
+        use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_rustls::TlsConnector;
+
+        let fixture = |name: &str| format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+        let error = tls::acceptor(
+            &TlsSettings::new(fixture("localhost.crt"), "/no/existe.key"),
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{error:#}").contains("/no/existe.key"), "{error:#}");
+        let acceptor = tls::acceptor(
+            &TlsSettings::new(fixture("localhost.crt"), fixture("localhost.key")),
+            None,
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let ctx = ProxyContext::new(Dialer::new(
+            DialSettings::default(),
+            Arc::new(SystemResolver),
+        ))
+        .with_tls(acceptor);
+        tokio::spawn(accept_loop(
+            listener,
+            ctx.clone(),
+            ConnectionLimits::default(),
+            ListenerProtocols::default(),
+        ));
+
+        let mut roots = RootCertStore::empty();
+        let pem = std::fs::read(fixture("localhost.crt")).unwrap();
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let connect = || async {
+            let stream = TcpStream::connect(proxy).await.unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            connector.connect(name, stream).await.unwrap()
+        };
+
+        // A client speaking plaintext fails the handshake without taking
+        // the listener down.
+        let mut plain = TcpStream::connect(proxy).await.unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buf = Vec::new();
+        let _ = plain.read_to_end(&mut buf).await;
+        assert!(!buf.starts_with(b"HTTP/"));
+
+        let origin = spawn_origin().await;
+        let (mut sender, conn) = hyper::client::conn::handshake(connect().await)
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let res = request(&mut sender, origin).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "ok");
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let mut tunnel = connect().await;
+        tunnel
+            .write_all(
+                format!("CONNECT {echo_addr} HTTP/1.1\r\nhost: {echo_addr}\r\n\r\n").as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(tunnel.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 200"));
+        tunnel.write_all(b"ping").await.unwrap();
+        let mut reply = [0u8; 4];
+        tunnel.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        assert_eq!(ctx.metrics().protocols(Protocol::Tls), 3);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates_map_to_profiles_on_the_tls_listener() {
+        use crate::identity::IdentityMapper;
+        use crate::settings::{CertMatcher, IdentitySettings, ProfileSettings, TlsSettings};
+        use base64::Engine;
+        use rcgen::{
+            BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer,
+            KeyPair,
+        };
+        use tokio_rustls::rustls::crypto::ring;
+        use tokio_rustls::rustls::pki_types::pem::PemObject;
+        use tokio_rustls::rustls::pki_types::{
+            CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName,
+        };
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_rustls::TlsConnector;
+
+        let fixture = |name: &str| format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca.distinguished_name.push(DnType::CommonName, "clientes");
+        ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_der = ca.self_signed(&ca_key).unwrap().der().to_vec();
+        let issuer = Issuer::new(ca, ca_key);
+        let dir = tempfile::tempdir().unwrap();
+        let ca_path = dir.path().join("clientes.pem");
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&ca_der);
+        std::fs::write(
+            &ca_path,
+            format!("-----BEGIN CERTIFICATE-----\n{encoded}\n-----END CERTIFICATE-----\n"),
+        )
+        .unwrap();
+        let client_cert = |san: &str, cn: &str| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![san.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, cn);
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let cert = params.signed_by(&key, &issuer).unwrap();
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+            (cert.der().clone(), key)
+        };
+
+        let identity = IdentitySettings::default()
+            .with_client_ca(&ca_path)
+            .with_profile(ProfileSettings::new("backend").with_allow("127.0.0.1".parse().unwrap()))
+            .with_profile(
+                ProfileSettings::new("publico").with_allow("api.publica.test".parse().unwrap()),
+            )
+            .with_rule(
+                CertMatcher::San("facturacion.clientes.test".into()),
+                "facturacion",
+                "backend",
+            )
+            .with_rule(
+                CertMatcher::CommonName("kiosco".into()),
+                "kiosco",
+                "publico",
+            );
+        let acceptor = tls::acceptor(
+            &TlsSettings::new(fixture("localhost.crt"), fixture("localhost.key")),
+            Some(&identity),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let ctx = ProxyContext::new(Dialer::new(
+            DialSettings::default(),
+            Arc::new(SystemResolver),
+        ))
+        .with_tls(acceptor)
+        .with_identity(IdentityMapper::new(identity).unwrap());
+        tokio::spawn(accept_loop(
+            listener,
+            ctx.clone(),
+            ConnectionLimits::default(),
+            ListenerProtocols::default(),
+        ));
+
+        let mut roots = RootCertStore::empty();
+        let pem = std::fs::read(fixture("localhost.crt")).unwrap();
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let origin = spawn_origin().await;
+        let status = |cert: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>| {
+            let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots.clone());
+            let config = match cert {
+                Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            async move {
+                let stream = TcpStream::connect(proxy).await.unwrap();
+                let name = ServerName::try_from("localhost").unwrap();
+                let tls = TlsConnector::from(Arc::new(config))
+                    .connect(name, stream)
+                    .await
+                    .unwrap();
+                let (mut sender, conn) = hyper::client::conn::handshake(tls).await.unwrap();
+                tokio::spawn(conn);
+                let req = Request::get(format!("http://{origin}/"))
+                    .body(Body::empty())
+                    .unwrap();
+                sender.send_request(req).await.map(|res| res.status()).ok()
+            }
+        };
+
+        // Same destination, divergent profiles.
+        let billing = client_cert("facturacion.clientes.test", "facturacion");
+        assert_eq!(status(Some(billing)).await, Some(StatusCode::OK));
+        let kiosk = client_cert("kiosco.clientes.test", "kiosco");
+        assert_eq!(status(Some(kiosk)).await, Some(StatusCode::FORBIDDEN));
+        // A valid certificate no rule knows closes the connection; no
+        // certificate at all is an ordinary client.
+        let stranger = client_cert("otro.clientes.test", "otro");
+        assert_eq!(status(Some(stranger)).await, None);
+        assert_eq!(ctx.metrics().violations(Violation::AuthFailure), 1);
+        assert_eq!(status(None).await, Some(StatusCode::OK));
+    }
+}
@@ -0,0 +1,232 @@
+//! Conexiones de clientes abiertas, para listarlas y cerrarlas.
+//!
+//! Cada conexión HTTP aceptada (en claro, con TLS o por el socket UNIX) se
+//! registra aquí mientras dura, igual que cada túnel interceptado con las
+//! peticiones descifradas que lleva dentro. Los túneles `CONNECT` que no se
+//! interceptan van aparte, en [`crate::tunnels`]. La API de administración
+//! puede cerrar una conexión con [`ConnectionRegistry::terminate`]: la
+//! petición en curso termina, no se atienden más y, si no acaba dentro del
+//! margen, el socket se suelta.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::connection::Protocol;
+
+/// Cuánto se espera, tras pedir el cierre de una conexión o de un túnel, a
+/// que lo que está en curso termine antes de soltar los sockets.
+pub const TERMINATION_GRACE: Duration = Duration::from_secs(2);
+
+/// Qué lleva la conexión.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionKind {
+    /// Peticiones HTTP del cliente, una tras otra con keep-alive.
+    Http,
+    /// Un túnel `CONNECT` descifrado por `[mitm]`.
+    Intercepted,
+}
+
+impl ConnectionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionKind::Http => "http",
+            ConnectionKind::Intercepted => "intercepted",
+        }
+    }
+}
+
+/// Conexión abierta, tal como la lista la API de administración.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectionSnapshot {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub kind: ConnectionKind,
+    pub protocol: &'static str,
+    /// Destino del túnel interceptado.
+    pub host: Option<String>,
+    pub started_at_ms: u64,
+    pub age_secs: f64,
+    /// Peticiones recibidas hasta ahora.
+    pub requests: u64,
+}
+
+/// Cierre pedido desde la API de administración, con su motivo.
+#[derive(Debug)]
+pub struct Termination(watch::Sender<Option<String>>);
+
+impl Default for Termination {
+    fn default() -> Self {
+        Self(watch::Sender::new(None))
+    }
+}
+
+impl Termination {
+    /// Pide el cierre; `false` si ya se había pedido.
+    pub fn request(&self, reason: &str) -> bool {
+        self.0.send_if_modified(|termination| {
+            let first = termination.is_none();
+            if first {
+                *termination = Some(reason.to_string());
+            }
+            first
+        })
+    }
+
+    /// Motivo del cierre pedido, si se pidió.
+    pub fn reason(&self) -> Option<String> {
+        self.0.borrow().clone()
+    }
+
+    /// Termina cuando se pide el cierre.
+    pub async fn requested(&self) {
+        let mut termination = self.0.subscribe();
+        // The sender lives in `self`, so the wait only ends with a reason.
+        let _ = termination.wait_for(Option::is_some).await;
+    }
+}
+
+#[derive(Debug)]
+pub struct LiveConnection {
+    id: u64,
+    client: SocketAddr,
+    kind: ConnectionKind,
+    protocol: Protocol,
+    host: Option<String>,
+    started_at_ms: u64,
+    started: Instant,
+    requests: AtomicU64,
+    termination: Termination,
+}
+
+impl LiveConnection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn termination(&self) -> Option<String> {
+        self.termination.reason()
+    }
+
+    /// Termina cuando se pide cerrar la conexión.
+    pub async fn terminated(&self) {
+        self.termination.requested().await
+    }
+
+    fn snapshot(&self) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            id: self.id,
+            client: self.client,
+            kind: self.kind,
+            protocol: self.protocol.as_str(),
+            host: self.host.clone(),
+            started_at_ms: self.started_at_ms,
+            age_secs: self.started.elapsed().as_secs_f64(),
+            requests: self.requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    open: Mutex<BTreeMap<u64, Arc<LiveConnection>>>,
+}
+
+impl ConnectionRegistry {
+    /// Registra una conexión aceptada; sale de la lista cuando se suelta el
+    /// guard. `host` es el destino de un túnel interceptado.
+    pub fn open(
+        self: &Arc<Self>,
+        client: SocketAddr,
+        kind: ConnectionKind,
+        protocol: Protocol,
+        host: Option<&str>,
+    ) -> OpenConnection {
+        let record = Arc::new(LiveConnection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            client,
+            kind,
+            protocol,
+            host: host.map(str::to_string),
+            started_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            termination: Termination::default(),
+        });
+        self.open
+            .lock()
+            .expect("lock de conexiones")
+            .insert(record.id, record.clone());
+        OpenConnection {
+            registry: self.clone(),
+            record,
+        }
+    }
+
+    /// Conexiones abiertas, de la más antigua a la más reciente.
+    pub fn list(&self) -> Vec<ConnectionSnapshot> {
+        self.open
+            .lock()
+            .expect("lock de conexiones")
+            .values()
+            .map(|record| record.snapshot())
+            .collect()
+    }
+
+    /// Pide cerrar la conexión `id`. `None` si no está abierta o ya se pidió.
+    pub fn terminate(&self, id: u64, reason: &str) -> Option<ConnectionSnapshot> {
+        let open = self.open.lock().expect("lock de conexiones");
+        let record = open.get(&id)?;
+        record
+            .termination
+            .request(reason)
+            .then(|| record.snapshot())
+    }
+
+    /// Pide cerrar todas las conexiones abiertas de la IP `client`.
+    pub fn terminate_client(&self, client: IpAddr, reason: &str) -> Vec<ConnectionSnapshot> {
+        self.open
+            .lock()
+            .expect("lock de conexiones")
+            .values()
+            .filter(|record| record.client.ip() == client && record.termination.request(reason))
+            .map(|record| record.snapshot())
+            .collect()
+    }
+}
+
+/// Mantiene una conexión en [`ConnectionRegistry::list`] mientras vive.
+pub struct OpenConnection {
+    registry: Arc<ConnectionRegistry>,
+    record: Arc<LiveConnection>,
+}
+
+impl OpenConnection {
+    pub fn record(&self) -> &Arc<LiveConnection> {
+        &self.record
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.registry
+            .open
+            .lock()
+            .expect("lock de conexiones")
+            .remove(&self.record.id);
+    }
+}
@@ -0,0 +1,177 @@
+//! Inyección de credenciales hacia destinos internos.
+//!
+//! Los clientes no necesitan conocer los tokens de los servicios: el proxy
+//! añade la cabecera configurada en las peticiones HTTP cuyo destino coincide
+//! con el patrón. Nunca se aplica a CONNECT, porque el proxy no ve el tráfico
+//! del túnel. Los valores se leen del entorno al arrancar y no se registran.
+
+use base64::Engine;
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use hyper::HeaderMap;
+use tracing::debug;
+
+use crate::host_pattern::HostPattern;
+use crate::settings::{CredentialKind, CredentialSettings};
+
+struct Rule {
+    host: HostPattern,
+    header: HeaderName,
+    value: HeaderValue,
+    replace_client: bool,
+}
+
+pub struct CredentialInjector {
+    rules: Vec<Rule>,
+}
+
+impl CredentialInjector {
+    /// Resuelve los valores desde `env`; falla si falta alguna variable.
+    pub fn new(
+        settings: &[CredentialSettings],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<Self> {
+        let var = |name: &str| {
+            env(name).ok_or_else(|| {
+                anyhow::anyhow!("la variable de entorno {name} de una credencial no está definida")
+            })
+        };
+        let mut rules = Vec::with_capacity(settings.len());
+        for credential in settings {
+            let (header, value) = match credential.kind() {
+                CredentialKind::Bearer { token_env } => {
+                    (AUTHORIZATION, format!("Bearer {}", var(token_env)?))
+                }
+                CredentialKind::Basic {
+                    username_env,
+                    password_env,
+                } => {
+                    let pair = format!("{}:{}", var(username_env)?, var(password_env)?);
+                    let encoded = base64::engine::general_purpose::STANDARD.encode(pair);
+                    (AUTHORIZATION, format!("Basic {encoded}"))
+                }
+                CredentialKind::Header { name, value_env } => {
+                    (HeaderName::from_bytes(name.as_bytes())?, var(value_env)?)
+                }
+            };
+            let mut value = HeaderValue::from_str(&value).map_err(|_| {
+                anyhow::anyhow!(
+                    "la credencial para {} no es un valor de cabecera válido",
+                    credential.host()
+                )
+            })?;
+            value.set_sensitive(true);
+            rules.push(Rule {
+                host: credential.host().clone(),
+                header,
+                value,
+                replace_client: credential.replace_client(),
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Si alguna credencial se inyecta hacia `host`.
+    pub fn applies(&self, host: &str) -> bool {
+        self.rules.iter().any(|rule| rule.host.matches(host))
+    }
+
+    /// Aplica a `headers` las credenciales de `host`. Con `replace_client`
+    /// el valor del cliente se sustituye; sin él, se respeta y no se inyecta.
+    pub fn apply(&self, host: &str, headers: &mut HeaderMap) {
+        for rule in self.rules.iter().filter(|rule| rule.host.matches(host)) {
+            if headers.contains_key(&rule.header) && !rule.replace_client {
+                continue;
+            }
+            headers.insert(rule.header.clone(), rule.value.clone());
+            debug!(%host, header = %rule.header, "Credencial inyectada");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "TOKEN" => Some("t0k3n".into()),
+            "USER" => Some("svc".into()),
+            "PASS" => Some("s3cr3t".into()),
+            "KEY" => Some("k3y".into()),
+            _ => None,
+        }
+    }
+
+    fn injector() -> CredentialInjector {
+        let settings = [
+            CredentialSettings::new(
+                "*.internal.test".parse().unwrap(),
+                CredentialKind::Bearer {
+                    token_env: "TOKEN".into(),
+                },
+            ),
+            CredentialSettings::new(
+                "legacy.test".parse().unwrap(),
+                CredentialKind::Basic {
+                    username_env: "USER".into(),
+                    password_env: "PASS".into(),
+                },
+            ),
+            CredentialSettings::new(
+                "keys.test".parse().unwrap(),
+                CredentialKind::Header {
+                    name: "x-api-key".into(),
+                    value_env: "KEY".into(),
+                },
+            )
+            .with_replace_client(false),
+        ];
+        CredentialInjector::new(&settings, env).unwrap()
+    }
+
+    #[test]
+    fn test_injects_for_matching_host_replacing_client_value() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer del-cliente".parse().unwrap());
+        injector().apply("api.internal.test", &mut headers);
+        assert_eq!(headers[AUTHORIZATION], "Bearer t0k3n");
+        assert_eq!(headers.get_all(AUTHORIZATION).iter().count(), 1);
+
+        let mut headers = HeaderMap::new();
+        injector().apply("legacy.test", &mut headers);
+        assert_eq!(headers[AUTHORIZATION], "Basic c3ZjOnMzY3IzdA==");
+    }
+
+    #[test]
+    fn test_no_leak_to_other_hosts() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer del-cliente".parse().unwrap());
+        injector().apply("internal.test.evil.com", &mut headers);
+        assert_eq!(headers[AUTHORIZATION], "Bearer del-cliente");
+        assert!(!headers.contains_key("x-api-key"));
+    }
+
+    #[test]
+    fn test_client_value_kept_without_replace() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "del-cliente".parse().unwrap());
+        injector().apply("keys.test", &mut headers);
+        assert_eq!(headers["x-api-key"], "del-cliente");
+
+        let mut headers = HeaderMap::new();
+        injector().apply("keys.test", &mut headers);
+        assert_eq!(headers["x-api-key"], "k3y");
+    }
+
+    #[test]
+    fn test_missing_env_var_fails() {
+        let settings = [CredentialSettings::new(
+            "a.test".parse().unwrap(),
+            CredentialKind::Bearer {
+                token_env: "NO_EXISTE".into(),
+            },
+        )];
+        let err = CredentialInjector::new(&settings, env).err().unwrap();
+        assert!(err.to_string().contains("NO_EXISTE"));
+    }
+}
@@ -0,0 +1,301 @@
+//! Descompresión de los bodies que el proxy inspecciona.
+//!
+//! Los proveedores de IA suelen responder con `Content-Encoding: gzip`, y
+//! contar tokens, guardar en caché o aplicar filtros sobre esos bytes no
+//! sirve de nada. Las funciones que leen un body lo pasan por un
+//! [`Decoder`], que descomprime gzip, deflate y brotli según llegan los
+//! chunks y corta al pasar de un tamaño descomprimido máximo, así que una
+//! bomba de compresión nunca ocupa más que ese límite. Lo que solo se
+//! inspecciona sigue hacia el cliente comprimido, tal como llegó; lo que se
+//! modifica o se guarda para servirlo después va sin comprimir y sin su
+//! `Content-Encoding`. Con cualquier otra codificación (`zstd`, varias
+//! encadenadas) el body no se inspecciona.
+
+use std::fmt;
+use std::io::Write;
+
+use flate2::write::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use hyper::header::CONTENT_ENCODING;
+use hyper::HeaderMap;
+
+use crate::brotli::BrotliDecoder;
+
+/// Compresión que se lee de una vez. Acota lo que un solo paso puede
+/// producir por encima del límite: deflate no pasa de unas 1000 veces, y
+/// brotli se para solo al pasarlo.
+const STEP: usize = 512;
+
+/// Codificaciones que se saben descomprimir.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// zlib según la RFC 9110, o deflate sin envoltorio como mandan
+    /// algunos servidores.
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// La codificación del body con estas cabeceras: `None` si no está
+    /// comprimido.
+    pub fn of(headers: &HeaderMap) -> Result<Option<Self>, Unsupported> {
+        let codings: Vec<String> = headers
+            .get_all(CONTENT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|coding| coding.trim().to_ascii_lowercase())
+            .filter(|coding| !coding.is_empty() && coding != "identity")
+            .collect();
+        match codings.as_slice() {
+            [] => Ok(None),
+            [coding] if coding == "gzip" || coding == "x-gzip" => Ok(Some(Self::Gzip)),
+            [coding] if coding == "deflate" => Ok(Some(Self::Deflate)),
+            [coding] if coding == "br" => Ok(Some(Self::Brotli)),
+            _ => Err(Unsupported(codings.join(", "))),
+        }
+    }
+}
+
+/// Una `Content-Encoding` que no se sabe descomprimir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported(pub String);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Descomprimido pasa del límite.
+    TooLarge,
+    /// No es un stream válido de su codificación.
+    Corrupt,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DecodeError::TooLarge => "el body descomprimido supera el límite",
+            DecodeError::Corrupt => "el body no se puede descomprimir",
+        })
+    }
+}
+
+enum Inner {
+    Gzip(GzDecoder<Vec<u8>>),
+    Zlib(ZlibDecoder<Vec<u8>>),
+    Raw(DeflateDecoder<Vec<u8>>),
+    Brotli(Box<BrotliDecoder>),
+    /// Deflate del que aún no se sabe si lleva cabecera zlib.
+    Sniffing(Vec<u8>),
+}
+
+/// Descompresión incremental de un body.
+pub struct Decoder {
+    inner: Inner,
+    max: usize,
+    decoded: usize,
+}
+
+impl Decoder {
+    /// `max` es el mayor tamaño descomprimido que se acepta.
+    pub fn new(encoding: Encoding, max: usize) -> Self {
+        let inner = match encoding {
+            Encoding::Gzip => Inner::Gzip(GzDecoder::new(Vec::new())),
+            Encoding::Deflate => Inner::Sniffing(Vec::new()),
+            Encoding::Brotli => Inner::Brotli(Box::new(BrotliDecoder::new(max))),
+        };
+        Self {
+            inner,
+            max,
+            decoded: 0,
+        }
+    }
+
+    /// Lo que `chunk` añade al body descomprimido.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        if let Inner::Sniffing(head) = &mut self.inner {
+            head.extend_from_slice(chunk);
+            if head.len() < 2 {
+                return Ok(Vec::new());
+            }
+            let head = std::mem::take(head);
+            self.inner = if is_zlib(&head) {
+                Inner::Zlib(ZlibDecoder::new(Vec::new()))
+            } else {
+                Inner::Raw(DeflateDecoder::new(Vec::new()))
+            };
+            return self.feed(&head);
+        }
+        let mut out = Vec::new();
+        for step in chunk.chunks(STEP) {
+            let written = match &mut self.inner {
+                Inner::Gzip(decoder) => decoder.write_all(step),
+                Inner::Zlib(decoder) => decoder.write_all(step),
+                Inner::Raw(decoder) => decoder.write_all(step),
+                Inner::Brotli(decoder) => decoder.write_all(step),
+                Inner::Sniffing(_) => unreachable!("cabecera deflate ya leída"),
+            };
+            written.map_err(|_| DecodeError::Corrupt)?;
+            self.drain(&mut out)?;
+        }
+        Ok(out)
+    }
+
+    /// Lo que queda del body al terminar el stream comprimido.
+    pub fn finish(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let finished = match &mut self.inner {
+            Inner::Gzip(decoder) => decoder.try_finish(),
+            Inner::Zlib(decoder) => decoder.try_finish(),
+            Inner::Raw(decoder) => decoder.try_finish(),
+            Inner::Brotli(decoder) => decoder.try_finish(),
+            Inner::Sniffing(head) if head.is_empty() => Ok(()),
+            // One byte can't hold a deflate stream.
+            Inner::Sniffing(_) => return Err(DecodeError::Corrupt),
+        };
+        finished.map_err(|_| DecodeError::Corrupt)?;
+        let mut out = Vec::new();
+        self.drain(&mut out)?;
+        Ok(out)
+    }
+
+    fn drain(&mut self, out: &mut Vec<u8>) -> Result<(), DecodeError> {
+        let buf = match &mut self.inner {
+            Inner::Gzip(decoder) => decoder.get_mut(),
+            Inner::Zlib(decoder) => decoder.get_mut(),
+            Inner::Raw(decoder) => decoder.get_mut(),
+            Inner::Brotli(decoder) => decoder.get_mut(),
+            Inner::Sniffing(_) => return Ok(()),
+        };
+        self.decoded += buf.len();
+        if self.decoded > self.max {
+            return Err(DecodeError::TooLarge);
+        }
+        out.append(buf);
+        Ok(())
+    }
+}
+
+/// Si `head` empieza con una cabecera zlib (RFC 1950 §2.2).
+fn is_zlib(head: &[u8]) -> bool {
+    head[0] & 0x0f == 8 && (u16::from(head[0]) << 8 | u16::from(head[1])) % 31 == 0
+}
+
+/// `body` entero descomprimido, hasta `max` bytes.
+pub fn decode(encoding: Encoding, body: &[u8], max: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut decoder = Decoder::new(encoding, max);
+    let mut out = decoder.feed(body)?;
+    out.extend(decoder.finish()?);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_decodes_gzip_and_both_deflates_within_the_limit() {
+        let plain = br#"{"usage":{"total_tokens":12}}"#.repeat(10);
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&plain).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&plain).unwrap();
+        let zlib = zlib.finish().unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(&plain).unwrap();
+        let raw = raw.finish().unwrap();
+
+        assert_eq!(decode(Encoding::Gzip, &gzip, 1024).unwrap(), plain);
+        assert_eq!(decode(Encoding::Deflate, &zlib, 1024).unwrap(), plain);
+        assert_eq!(decode(Encoding::Deflate, &raw, 1024).unwrap(), plain);
+        // Byte by byte, as a slow origin would send it.
+        let mut decoder = Decoder::new(Encoding::Deflate, 1024);
+        let mut out = Vec::new();
+        for byte in &zlib {
+            out.extend(decoder.feed(std::slice::from_ref(byte)).unwrap());
+        }
+        out.extend(decoder.finish().unwrap());
+        assert_eq!(out, plain);
+
+        assert_eq!(
+            decode(Encoding::Gzip, &gzip, plain.len() - 1),
+            Err(DecodeError::TooLarge)
+        );
+        assert_eq!(
+            decode(Encoding::Gzip, b"no es gzip", 1024),
+            Err(DecodeError::Corrupt)
+        );
+
+        // A bomb stops at the limit, not at its full size.
+        let mut bomb = GzEncoder::new(Vec::new(), Compression::best());
+        bomb.write_all(&vec![0; 8 << 20]).unwrap();
+        let bomb = bomb.finish().unwrap();
+        let mut decoder = Decoder::new(Encoding::Gzip, 256 << 10);
+        assert_eq!(decoder.feed(&bomb), Err(DecodeError::TooLarge));
+
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(value));
+            headers
+        };
+        assert_eq!(Encoding::of(&HeaderMap::new()), Ok(None));
+        assert_eq!(Encoding::of(&headers("identity")), Ok(None));
+        assert_eq!(Encoding::of(&headers("GZIP")), Ok(Some(Encoding::Gzip)));
+        assert_eq!(
+            Encoding::of(&headers("deflate")),
+            Ok(Some(Encoding::Deflate))
+        );
+        assert_eq!(Encoding::of(&headers("br")), Ok(Some(Encoding::Brotli)));
+        assert_eq!(
+            Encoding::of(&headers("zstd")),
+            Err(Unsupported("zstd".to_string()))
+        );
+        assert!(Encoding::of(&headers("gzip, gzip")).is_err());
+    }
+
+    #[test]
+    fn test_decodes_brotli_within_the_limit() {
+        let plain = br#"{"usage":{"total_tokens":12}}"#.repeat(10);
+        // `plain` at quality 11, as the reference encoder writes it.
+        let br = [
+            0x1b, 0x21, 0x01, 0xf8, 0x1d, 0xa7, 0xc5, 0x9a, 0x13, 0x4c, 0xcb, 0x41, 0x1d, 0x23,
+            0xb3, 0xd7, 0x27, 0x74, 0x52, 0xe4, 0x6b, 0xae, 0x17, 0x99, 0x64, 0x94, 0xd9, 0x34,
+            0x0b, 0x68, 0x4c, 0x72, 0x0b, 0xd3, 0x44, 0xfa, 0xff, 0x0d, 0x1f,
+        ];
+        assert_eq!(decode(Encoding::Brotli, &br, 1024).unwrap(), plain);
+        let mut decoder = Decoder::new(Encoding::Brotli, 1024);
+        let mut out = Vec::new();
+        for byte in &br {
+            out.extend(decoder.feed(std::slice::from_ref(byte)).unwrap());
+        }
+        out.extend(decoder.finish().unwrap());
+        assert_eq!(out, plain);
+
+        assert_eq!(
+            decode(Encoding::Brotli, &br, plain.len() - 1),
+            Err(DecodeError::TooLarge)
+        );
+        assert_eq!(
+            decode(Encoding::Brotli, &br[..br.len() - 1], 1024),
+            Err(DecodeError::Corrupt)
+        );
+        assert_eq!(
+            decode(Encoding::Brotli, b"no es brotli", 1024),
+            Err(DecodeError::Corrupt)
+        );
+
+        // 8 MiB of zeros in 14 bytes stop at the limit.
+        let bomb = [
+            0x9f, 0xff, 0xff, 0x7f, 0xf8, 0x27, 0x00, 0xe2, 0xb1, 0x40, 0x20, 0xf7, 0xfe, 0x0f,
+        ];
+        let mut decoder = Decoder::new(Encoding::Brotli, 256 << 10);
+        assert_eq!(decoder.feed(&bomb), Err(DecodeError::TooLarge));
+    }
+}
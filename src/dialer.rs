@@ -0,0 +1,587 @@
+//! Conexiones TCP hacia los destinos.
+//!
+//! Cuando un host resuelve a varias direcciones, se lanzan hasta
+//! `race_width` intentos escalonados y gana el primero que conecte; el resto
+//! se cancela. Los intentos alternan entre IPv6 e IPv4 empezando por la
+//! familia preferida (RFC 8305), así que una ruta IPv6 rota cuesta un
+//! `stagger` y no el timeout del sistema. La latencia de cada dirección se recuerda por host para probar
+//! antes las más rápidas, y las puntuaciones pierden peso con el tiempo para
+//! que una dirección lenta pueda recuperarse.
+//!
+//! Con un servidor SOCKS5 de salida, las conexiones con los destinos de los
+//! clientes se piden a él y las resuelve él; ver [`crate::socks_upstream`].
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hyper::service::Service;
+use hyper::Uri;
+use lru::LruCache;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::capture::Timeline;
+use crate::metrics::Metrics;
+use crate::settings::{AddressFamily, DialSettings};
+use crate::socks_upstream::Socks5Upstream;
+use crate::ssrf::NetworkGuard;
+
+/// Latencia asignada a un intento fallido.
+const FAILURE_PENALTY: Duration = Duration::from_secs(5);
+/// Peso de la última medición en la media móvil.
+const EWMA_WEIGHT: f64 = 0.3;
+
+/// Resolución de nombres. Existe para poder sustituir el DNS en pruebas.
+pub trait Resolve: Send + Sync + 'static {
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>>;
+}
+
+/// Resolución del sistema vía Tokio.
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        let host = host.to_string();
+        Box::pin(async move {
+            Ok(tokio::net::lookup_host((host.as_str(), port))
+                .await?
+                .collect())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AddrScore {
+    latency_ms: f64,
+    failures: u64,
+    updated: Instant,
+}
+
+/// Puntuación de una dirección tal como la expone la API de administración.
+#[derive(Debug, Clone, Serialize)]
+pub struct AddrScoreView {
+    pub addr: SocketAddr,
+    pub latency_ms: f64,
+    pub failures: u64,
+    pub age_secs: u64,
+}
+
+pub struct Dialer {
+    settings: DialSettings,
+    resolver: Arc<dyn Resolve>,
+    scores: Mutex<LruCache<String, HashMap<SocketAddr, AddrScore>>>,
+    guard: Option<NetworkGuard>,
+    socks5: Option<Socks5Upstream>,
+}
+
+impl Dialer {
+    pub fn new(settings: DialSettings, resolver: Arc<dyn Resolve>) -> Self {
+        let size = NonZeroUsize::new(settings.max_scored_hosts()).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            settings,
+            resolver,
+            scores: Mutex::new(LruCache::new(size)),
+            guard: None,
+            socks5: None,
+        }
+    }
+
+    /// Redes con las que no se conecta en nombre de un cliente.
+    pub fn with_guard(mut self, guard: NetworkGuard) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    pub fn guard(&self) -> Option<&NetworkGuard> {
+        self.guard.as_ref()
+    }
+
+    /// Servidor SOCKS5 por el que se conecta con los destinos.
+    pub fn with_socks5(mut self, socks5: Socks5Upstream) -> Self {
+        self.socks5 = Some(socks5);
+        self
+    }
+
+    pub fn socks5(&self) -> Option<&Socks5Upstream> {
+        self.socks5.as_ref()
+    }
+
+    /// Error con un [`Blocked`](crate::ssrf::Blocked) si alguna de `addrs`
+    /// está en una red bloqueada.
+    pub fn vet(&self, host: &str, addrs: &[SocketAddr]) -> io::Result<()> {
+        match &self.guard {
+            Some(guard) => guard.check(host, addrs),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs = self.resolver.resolve(host, port).await?;
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{host} no resolvió a ninguna dirección"),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    /// Resuelve `host` y conecta con la mejor de sus direcciones.
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        self.dial(host, port, false).await
+    }
+
+    /// Como [`Dialer::connect`], para un destino que pidió un cliente: falla
+    /// si alguna dirección está en una red bloqueada, y pasa por el servidor
+    /// SOCKS5 si lo hay.
+    pub async fn connect_destination(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        match &self.socks5 {
+            Some(socks5) => self.via_socks5(socks5, host, port).await,
+            None => self.dial(host, port, true).await,
+        }
+    }
+
+    /// Conecta con `addrs`, ya resueltas para un destino que pidió un
+    /// cliente.
+    pub async fn connect_resolved(
+        &self,
+        host: &str,
+        addrs: Vec<SocketAddr>,
+    ) -> io::Result<TcpStream> {
+        self.vet(host, &addrs)?;
+        match (&self.socks5, addrs.first()) {
+            // The server is asked for the address that was just checked.
+            (Some(socks5), Some(addr)) => {
+                self.via_socks5(socks5, &addr.ip().to_string(), addr.port())
+                    .await
+            }
+            _ => self.connect_addrs(host, addrs).await,
+        }
+    }
+
+    async fn via_socks5(
+        &self,
+        socks5: &Socks5Upstream,
+        host: &str,
+        port: u16,
+    ) -> io::Result<TcpStream> {
+        // Names are resolved by the server; only literal IPs can be checked.
+        if let Ok(ip) = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            self.vet(host, &[SocketAddr::new(ip, port)])?;
+        }
+        let stream = self.connect(socks5.server().host(), socks5.port()).await?;
+        socks5.connect(stream, host, port).await
+    }
+
+    async fn dial(&self, host: &str, port: u16, vet: bool) -> io::Result<TcpStream> {
+        let timeline = Timeline::current();
+        let started = Instant::now();
+        let resolved = self.resolve(host, port).await;
+        if let Some(timeline) = &timeline {
+            let error = resolved.as_ref().err().map(|e| e.to_string());
+            timeline.record("dns", started, error);
+        }
+        let addrs = resolved?;
+        // The addresses just checked are the ones dialed below.
+        if vet {
+            self.vet(host, &addrs)?;
+        }
+        let started = Instant::now();
+        let connected = self.connect_addrs(host, addrs).await;
+        if let Some(timeline) = &timeline {
+            let error = connected.as_ref().err().map(|e| e.to_string());
+            timeline.record("connect", started, error);
+        }
+        connected
+    }
+
+    /// Conecta con alguna de `addrs`, ya resueltas para `host`.
+    pub async fn connect_addrs(&self, host: &str, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let host = host.to_ascii_lowercase();
+        let mut addrs = interleave(addrs, self.settings.prefer_family());
+        self.order(&host, &mut addrs);
+
+        let width = self.settings.race_width();
+        let mut pending = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut started_at: HashMap<SocketAddr, Instant> = HashMap::new();
+        let mut last_error = None;
+
+        let launch = |addr: SocketAddr, started_at: &mut HashMap<SocketAddr, Instant>| {
+            started_at.insert(addr, Instant::now());
+            async move { (addr, TcpStream::connect(addr).await) }
+        };
+        if let Some(addr) = pending.next() {
+            attempts.push(launch(addr, &mut started_at));
+        }
+
+        loop {
+            let stagger = tokio::time::sleep(self.settings.stagger());
+            tokio::pin!(stagger);
+            let can_launch = attempts.len() < width && pending.len() > 0;
+            tokio::select! {
+                finished = attempts.next() => {
+                    let Some((addr, result)) = finished else {
+                        return Err(last_error.unwrap_or_else(|| {
+                            io::Error::new(io::ErrorKind::NotFound, "sin direcciones para conectar")
+                        }));
+                    };
+                    let elapsed = started_at[&addr].elapsed();
+                    match result {
+                        Ok(stream) => {
+                            self.record(&host, addr, Some(elapsed));
+                            // Losers are dropped with `attempts`; how long they
+                            // had been trying is a lower bound of their latency.
+                            for (other, since) in &started_at {
+                                if *other != addr {
+                                    let lower_bound = since.elapsed().max(elapsed);
+                                    self.record(&host, *other, Some(lower_bound));
+                                }
+                            }
+                            debug!(%host, %addr, ?elapsed, "Conectado con el destino");
+                            return Ok(stream);
+                        }
+                        Err(e) => {
+                            debug!(%host, %addr, error = %e, "Intento de conexión fallido");
+                            self.record(&host, addr, None);
+                            started_at.remove(&addr);
+                            last_error = Some(e);
+                            if let Some(next) = pending.next() {
+                                attempts.push(launch(next, &mut started_at));
+                            }
+                        }
+                    }
+                }
+                _ = &mut stagger, if can_launch => {
+                    if let Some(next) = pending.next() {
+                        attempts.push(launch(next, &mut started_at));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ordena las direcciones de menor a mayor latencia conocida. Las que no
+    /// tienen historial van primero para que se lleguen a medir; a igual
+    /// latencia se respeta el orden de [`interleave`].
+    fn order(&self, host: &str, addrs: &mut [SocketAddr]) {
+        let mut scores = self.scores.lock().expect("lock de puntuaciones");
+        let Some(known) = scores.get(host) else {
+            return;
+        };
+        let half_life = self.settings.score_half_life();
+        addrs.sort_by(|a, b| {
+            let score = |addr| {
+                known
+                    .get(addr)
+                    .map(|s| decayed(s, half_life))
+                    .unwrap_or(0.0)
+            };
+            score(a).total_cmp(&score(b))
+        });
+    }
+
+    fn record(&self, host: &str, addr: SocketAddr, latency: Option<Duration>) {
+        let sample = latency.unwrap_or(FAILURE_PENALTY).as_secs_f64() * 1000.0;
+        let half_life = self.settings.score_half_life();
+        let mut scores = self.scores.lock().expect("lock de puntuaciones");
+        let entry = scores.get_or_insert_mut(host.to_string(), HashMap::new);
+        let now = Instant::now();
+        let score = entry.entry(addr).or_insert(AddrScore {
+            latency_ms: sample,
+            failures: 0,
+            updated: now,
+        });
+        let previous = decayed(score, half_life);
+        score.latency_ms = previous + EWMA_WEIGHT * (sample - previous);
+        score.updated = now;
+        if latency.is_none() {
+            score.failures += 1;
+        }
+    }
+
+    /// Puntuaciones actuales por host, para depuración.
+    pub fn scores(&self) -> HashMap<String, Vec<AddrScoreView>> {
+        let half_life = self.settings.score_half_life();
+        let scores = self.scores.lock().expect("lock de puntuaciones");
+        scores
+            .iter()
+            .map(|(host, addrs)| {
+                let mut views: Vec<AddrScoreView> = addrs
+                    .iter()
+                    .map(|(addr, score)| AddrScoreView {
+                        addr: *addr,
+                        latency_ms: decayed(score, half_life),
+                        failures: score.failures,
+                        age_secs: score.updated.elapsed().as_secs(),
+                    })
+                    .collect();
+                views.sort_by(|a, b| a.latency_ms.total_cmp(&b.latency_ms));
+                (host.clone(), views)
+            })
+            .collect()
+    }
+}
+
+/// Alterna las familias empezando por `prefer`, sin cambiar el orden del
+/// resolver dentro de cada una.
+fn interleave(addrs: Vec<SocketAddr>, prefer: AddressFamily) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| prefer.contains(addr));
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let mut other = other.into_iter();
+    for addr in preferred {
+        ordered.push(addr);
+        ordered.extend(other.next());
+    }
+    ordered.extend(other);
+    ordered
+}
+
+/// Latencia recordada, reducida a la mitad por cada `half_life` sin medir.
+fn decayed(score: &AddrScore, half_life: Duration) -> f64 {
+    if half_life.is_zero() {
+        return score.latency_ms;
+    }
+    let age = score.updated.elapsed().as_secs_f64() / half_life.as_secs_f64();
+    score.latency_ms * 0.5f64.powf(age)
+}
+
+/// Conector de hyper que usa el [`Dialer`] del proxy; solo conecta con
+/// destinos.
+#[derive(Clone)]
+pub struct DialConnector {
+    dialer: Arc<Dialer>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl DialConnector {
+    pub fn new(dialer: Arc<Dialer>) -> Self {
+        Self {
+            dialer,
+            nodelay: false,
+            keepalive: None,
+            metrics: None,
+        }
+    }
+
+    /// `TCP_NODELAY` y TCP keepalive en cada conexión.
+    pub fn with_tcp(mut self, nodelay: bool, keepalive: Option<Duration>) -> Self {
+        self.nodelay = nodelay;
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Cuenta en `metrics` las conexiones que abre.
+    pub fn counting(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+impl Service<Uri> for DialConnector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI sin host"))?;
+            let default_port = if uri.scheme_str() == Some("https") {
+                443
+            } else {
+                80
+            };
+            let stream = connector
+                .dialer
+                .connect_destination(host, uri.port_u16().unwrap_or(default_port))
+                .await?;
+            if connector.nodelay {
+                stream.set_nodelay(true)?;
+            }
+            if let Some(idle) = connector.keepalive {
+                let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+                socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+            }
+            if let Some(metrics) = &connector.metrics {
+                metrics.record_upstream_connection();
+            }
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpSocket};
+
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Resolve for StaticResolver {
+        fn resolve(
+            &self,
+            _host: &str,
+            _port: u16,
+        ) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+            let addrs = self.0.clone();
+            Box::pin(async move { Ok(addrs) })
+        }
+    }
+
+    /// Dirección cuyo handshake nunca termina: un listener que nunca acepta
+    /// y tiene la cola llena descarta los SYN nuevos.
+    async fn blackhole() -> (SocketAddr, TcpListener, Vec<TcpStream>) {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = socket.listen(0).unwrap();
+        let mut fillers = Vec::new();
+        while let Ok(Ok(stream)) =
+            tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await
+        {
+            fillers.push(stream);
+        }
+        (addr, listener, fillers)
+    }
+
+    fn dialer(addrs: Vec<SocketAddr>, settings: DialSettings) -> Dialer {
+        Dialer::new(settings, Arc::new(StaticResolver(addrs)))
+    }
+
+    #[tokio::test]
+    async fn test_races_past_blackholed_address() {
+        let (slow, _listener, _fillers) = blackhole().await;
+        let fast_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fast = fast_listener.local_addr().unwrap();
+
+        let settings = DialSettings::default()
+            .with_race_width(2)
+            .with_stagger(Duration::from_millis(50));
+        let dialer = dialer(vec![slow, fast], settings);
+
+        let started = Instant::now();
+        let stream = dialer.connect("origin.test", 80).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), fast);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // The slow address is now known to be slower, so the fast one is
+        // tried first and wins without waiting for the stagger.
+        let scores = dialer.scores();
+        assert_eq!(scores["origin.test"][0].addr, fast);
+        let started = Instant::now();
+        let stream = dialer.connect("origin.test", 80).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), fast);
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_families_alternate_from_the_preferred_one() {
+        let v4 = |last: u8| SocketAddr::from(([192, 0, 2, last], 443));
+        let v6 = |last: u16| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, last], 443));
+        let resolved = vec![v4(1), v4(2), v4(3), v6(1), v6(2)];
+        assert_eq!(
+            interleave(resolved.clone(), AddressFamily::Ipv6),
+            [v6(1), v4(1), v6(2), v4(2), v4(3)]
+        );
+        assert_eq!(
+            interleave(resolved, AddressFamily::Ipv4),
+            [v4(1), v6(1), v4(2), v6(2), v4(3)]
+        );
+        assert_eq!(
+            interleave(vec![v4(2), v4(1)], AddressFamily::Ipv6),
+            [v4(2), v4(1)]
+        );
+
+        // A dead IPv4 route costs one stagger, not the system's timeout.
+        let (dead, _listener, _fillers) = blackhole().await;
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        let settings = DialSettings::default().with_stagger(Duration::from_millis(50));
+        let preferring = |family| {
+            self::dialer(
+                vec![dead, live],
+                settings.clone().with_prefer_family(family),
+            )
+        };
+        let started = Instant::now();
+        let stream = preferring(AddressFamily::Ipv4)
+            .connect("origin.test", 443)
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let started = Instant::now();
+        preferring(AddressFamily::Ipv6)
+            .connect("origin.test", 443)
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_refused_address_falls_through() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+
+        let dialer = dialer(
+            vec![closed, open],
+            DialSettings::default()
+                .with_race_width(1)
+                .with_stagger(Duration::from_secs(10)),
+        );
+        let stream = dialer.connect("origin.test", 80).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        let scores = dialer.scores();
+        let failed = scores["origin.test"]
+            .iter()
+            .find(|s| s.addr == closed)
+            .unwrap();
+        assert_eq!(failed.failures, 1);
+
+        let only_closed = self::dialer(vec![closed], DialSettings::default());
+        assert!(only_closed.connect("origin.test", 80).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_score_table_is_bounded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dialer = dialer(vec![addr], DialSettings::default().with_max_scored_hosts(2));
+        for host in ["a.test", "b.test", "c.test"] {
+            dialer.connect(host, 80).await.unwrap();
+        }
+        let scores = dialer.scores();
+        assert_eq!(scores.len(), 2);
+        assert!(!scores.contains_key("a.test"));
+    }
+}
@@ -0,0 +1,218 @@
+//! Descubrimiento del proxy padre mediante un archivo PAC.
+//!
+//! El PAC se descarga de la URL configurada (o se lee de disco) y se vuelve a
+//! cargar cada `refresh` o cuando cambia la IP local, señal de que el equipo
+//! se movió a otra red. Las decisiones se cachean por host hasta la siguiente
+//! recarga. Si el PAC no está disponible o falla al evaluarse, se usa el
+//! proxy de respaldo configurado o, sin él, la conexión directa.
+
+use std::net::{IpAddr, UdpSocket};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use hyper::client::HttpConnector;
+use hyper::{Client, Uri};
+use lru::LruCache;
+use tracing::{info, warn};
+
+use crate::dialer::Dialer;
+use crate::log_throttle::LogThrottle;
+use crate::pac::{parse_directives, PacDirective, PacRequest, PacScript};
+use crate::settings::PacSettings;
+
+/// Cada cuánto se comprueba si cambió la red.
+const NETWORK_CHECK: Duration = Duration::from_secs(30);
+
+pub struct PacDiscovery {
+    settings: PacSettings,
+    script: RwLock<Option<Arc<PacScript>>>,
+    cache: Mutex<LruCache<String, Vec<PacDirective>>>,
+    client: Client<HttpConnector>,
+    failure_log: LogThrottle,
+}
+
+impl PacDiscovery {
+    pub fn new(settings: PacSettings) -> Self {
+        let size = NonZeroUsize::new(settings.cache_size()).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            settings,
+            script: RwLock::new(None),
+            cache: Mutex::new(LruCache::new(size)),
+            client: Client::new(),
+            failure_log: LogThrottle::new(Duration::from_secs(30)),
+        }
+    }
+
+    /// Descarga y analiza el PAC. Si falla se conserva la versión anterior.
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let source = self.settings.url();
+        let text = if source.starts_with("http://") {
+            let uri: Uri = source.parse()?;
+            let res = self.client.get(uri).await?;
+            if !res.status().is_success() {
+                anyhow::bail!("el servidor del PAC respondió {}", res.status());
+            }
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            String::from_utf8(body.to_vec()).context("el PAC no es UTF-8")?
+        } else {
+            let path = source.strip_prefix("file://").unwrap_or(source);
+            tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("No se pudo leer el PAC {path}"))?
+        };
+        let script = PacScript::parse(&text)?;
+        *self.script.write().expect("script PAC") = Some(Arc::new(script));
+        self.cache.lock().expect("caché PAC").clear();
+        info!(%source, "PAC cargado");
+        Ok(())
+    }
+
+    pub fn settings(&self) -> &PacSettings {
+        &self.settings
+    }
+
+    /// Proxies a probar para `url`, en orden. Nunca devuelve una lista vacía.
+    pub async fn decide(
+        &self,
+        dialer: &Dialer,
+        url: &str,
+        host: &str,
+        port: u16,
+    ) -> Vec<PacDirective> {
+        let key = host.to_ascii_lowercase();
+        if let Some(hit) = self.cache.lock().expect("caché PAC").get(&key) {
+            return hit.clone();
+        }
+        let Some(script) = self.script.read().expect("script PAC").clone() else {
+            return self.fallback();
+        };
+        let resolved = match script.uses_dns() {
+            true => dialer
+                .resolve(host, port)
+                .await
+                .ok()
+                .and_then(|addrs| addrs.first().map(|addr| addr.ip())),
+            false => None,
+        };
+        let req = PacRequest {
+            url,
+            host,
+            resolved,
+            my_ip: local_ip(),
+        };
+        match script
+            .find_proxy(&req)
+            .map(|result| parse_directives(&result))
+        {
+            Ok(directives) if !directives.is_empty() => {
+                self.cache
+                    .lock()
+                    .expect("caché PAC")
+                    .put(key, directives.clone());
+                directives
+            }
+            Ok(_) => self.fallback(),
+            Err(e) => {
+                if let Some(suppressed) = self.failure_log.should_log() {
+                    warn!(%host, error = %e, suppressed, "El PAC falló; se usa el respaldo");
+                }
+                self.fallback()
+            }
+        }
+    }
+
+    fn fallback(&self) -> Vec<PacDirective> {
+        match self.settings.fallback() {
+            Some(parent) => vec![PacDirective::Proxy(parent.clone())],
+            None => vec![PacDirective::Direct],
+        }
+    }
+
+    /// Recarga el PAC periódicamente y al cambiar de red.
+    pub async fn run(self: Arc<Self>) {
+        let mut last_fetch: Option<Instant> = None;
+        let mut last_ip = local_ip();
+        loop {
+            let ip = local_ip();
+            let network_changed = ip != last_ip;
+            if network_changed {
+                info!(old = ?last_ip, new = ?ip, "Cambió la red; se recarga el PAC");
+                last_ip = ip;
+            }
+            let due = last_fetch.is_none_or(|at| at.elapsed() >= self.settings.refresh());
+            if due || network_changed {
+                match self.refresh().await {
+                    Ok(()) => last_fetch = Some(Instant::now()),
+                    Err(e) => warn!(error = %e, "No se pudo cargar el PAC"),
+                }
+            }
+            tokio::time::sleep(NETWORK_CHECK.min(self.settings.refresh())).await;
+        }
+    }
+}
+
+/// IP local que se usaría para salir a Internet. Conectar un socket UDP no
+/// envía ningún paquete.
+fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialer::SystemResolver;
+    use crate::settings::DialSettings;
+
+    fn dialer() -> Dialer {
+        Dialer::new(DialSettings::default(), Arc::new(SystemResolver))
+    }
+
+    #[tokio::test]
+    async fn test_fallback_until_loaded_and_on_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proxy.pac");
+        let settings = PacSettings::new(path.display().to_string())
+            .with_fallback("respaldo.example:3128".parse().unwrap());
+        let discovery = PacDiscovery::new(settings);
+        let backup = vec![PacDirective::Proxy(
+            "respaldo.example:3128".parse().unwrap(),
+        )];
+        assert_eq!(
+            discovery.decide(&dialer(), "http://a/", "a", 80).await,
+            backup
+        );
+        assert!(discovery.refresh().await.is_err());
+
+        std::fs::write(
+            &path,
+            "function FindProxyForURL(url, host) {\n\
+             if (host == 'roto') return weekdayRange('MON');\n\
+             return 'DIRECT'; }",
+        )
+        .unwrap();
+        discovery.refresh().await.unwrap();
+        let direct = vec![PacDirective::Direct];
+        assert_eq!(
+            discovery.decide(&dialer(), "http://a/", "a", 80).await,
+            direct
+        );
+        assert_eq!(
+            discovery
+                .decide(&dialer(), "http://roto/", "roto", 80)
+                .await,
+            backup
+        );
+
+        // A broken update keeps the previous script.
+        std::fs::write(&path, "function nada() {}").unwrap();
+        assert!(discovery.refresh().await.is_err());
+        assert_eq!(
+            discovery.decide(&dialer(), "http://a/", "a", 80).await,
+            direct
+        );
+    }
+}
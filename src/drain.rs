@@ -0,0 +1,104 @@
+//! Retirada de una instancia detrás de un balanceador.
+//!
+//! `POST /api/drain` pone el proxy en modo drenaje sin pararlo: `/readyz`
+//! empieza a responder 503 para que el balanceador deje de mandar clientes
+//! nuevos, el tráfico en curso sigue, las respuestas llevan
+//! `Connection: close` (con `close_keepalive`) para que los clientes
+//! keep-alive se vayan al terminar su petición, y pasado `connect_grace` se
+//! rechazan los `CONNECT` nuevos. `GET /api/drain` informa de las conexiones
+//! y túneles que quedan, para esperar a cero antes de parar el proceso.
+//! `DELETE /api/drain` lo cancela. El estado vive en memoria: sobrevive a
+//! las recargas de configuración, no a un reinicio.
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::settings::DrainSettings;
+
+/// Estado del drenaje tal como lo muestra la API de administración.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DrainSnapshot {
+    pub draining: bool,
+    /// Segundos desde que empezó.
+    pub draining_secs: Option<f64>,
+    pub close_keepalive: bool,
+    /// Si ya se rechazan los `CONNECT` nuevos.
+    pub connect_refused: bool,
+    /// Conexiones de clientes y túneles aún abiertos.
+    pub remaining: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Drain {
+    settings: DrainSettings,
+    since: Mutex<Option<Instant>>,
+}
+
+impl Drain {
+    pub fn new(settings: DrainSettings) -> Self {
+        Self {
+            settings,
+            since: Mutex::new(None),
+        }
+    }
+
+    fn since(&self) -> Option<Instant> {
+        *self.since.lock().expect("lock de drenaje")
+    }
+
+    /// Empieza el drenaje; `false` si ya estaba en curso.
+    pub fn start(&self) -> bool {
+        let mut since = self.since.lock().expect("lock de drenaje");
+        if since.is_some() {
+            return false;
+        }
+        *since = Some(Instant::now());
+        info!(
+            close_keepalive = self.settings.close_keepalive(),
+            connect_grace_secs = self.settings.connect_grace().as_secs(),
+            "Drenaje iniciado: la instancia deja de estar lista"
+        );
+        true
+    }
+
+    /// Cancela el drenaje; `false` si no había ninguno.
+    pub fn cancel(&self) -> bool {
+        let cancelled = self.since.lock().expect("lock de drenaje").take();
+        if let Some(since) = cancelled {
+            info!(
+                draining_secs = since.elapsed().as_secs(),
+                "Drenaje cancelado: la instancia vuelve a estar lista"
+            );
+        }
+        cancelled.is_some()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.since().is_some()
+    }
+
+    /// Si las respuestas deben cerrar la conexión keep-alive.
+    pub fn closes_keepalive(&self) -> bool {
+        self.settings.close_keepalive() && self.is_draining()
+    }
+
+    /// Si ya pasó `connect_grace` desde que empezó el drenaje.
+    pub fn refuses_connect(&self) -> bool {
+        self.since()
+            .is_some_and(|since| since.elapsed() >= self.settings.connect_grace())
+    }
+
+    pub fn snapshot(&self, remaining: usize) -> DrainSnapshot {
+        let since = self.since();
+        DrainSnapshot {
+            draining: since.is_some(),
+            draining_secs: since.map(|since| since.elapsed().as_secs_f64()),
+            close_keepalive: self.settings.close_keepalive(),
+            connect_refused: self.refuses_connect(),
+            remaining,
+        }
+    }
+}
@@ -0,0 +1,770 @@
+//! Valores efectivos de una petición y la capa que aporta cada uno.
+//!
+//! Varias capas pueden fijar el mismo valor. De menor a mayor precedencia:
+//!
+//! 1. `default`: el valor incorporado en el proxy.
+//! 2. `global`: la configuración (archivo, entorno y flags de la CLI).
+//! 3. `client`: lo que depende de quién pregunta: el perfil de su identidad
+//!    mTLS y el bucket de los rollouts en que cae.
+//! 4. `host`: las reglas por destino: vía rápida, filtro de hosts, reglas de
+//!    salida, canary, `passthrough` de la intercepción y hosts LLM.
+//! 5. `runtime`: el estado que cambia en marcha: baneos, drenaje y el peso
+//!    del canary tras una retirada o un cambio desde la API.
+//!
+//! Cada valor toma el de la capa más alta que lo fija. El acceso es la
+//! excepción: basta el rechazo de una capa para denegar, aunque otra más
+//! alta lo permita, y se informa el rechazo de más precedencia.
+//!
+//! [`EffectiveSettings::for_request`] es el único sitio que aplica este
+//! orden: los manejadores toman de ahí la vía rápida, los plazos, la ruta,
+//! la intercepción y la caché de respuestas, y `proxy-ia explain` pide a
+//! `GET /api/explain` el mismo resultado con la capa de cada valor.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Context;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+
+use crate::identity::Identity;
+use crate::proxy::ProxyContext;
+use crate::settings::{AdminToken, Feature, ProxySettings};
+
+/// Capa de la configuración, de menor a mayor precedencia.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    Default,
+    Global,
+    Client,
+    Host,
+    Runtime,
+}
+
+impl Layer {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Layer::Default => "default",
+            Layer::Global => "global",
+            Layer::Client => "client",
+            Layer::Host => "host",
+            Layer::Runtime => "runtime",
+        }
+    }
+}
+
+/// Un valor y la capa que lo fijó; `detail` nombra la regla o el motivo.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved<T> {
+    pub value: T,
+    pub layer: Layer,
+    pub detail: Option<String>,
+}
+
+impl<T> Resolved<T> {
+    fn new(value: T, layer: Layer) -> Self {
+        Self {
+            value,
+            layer,
+            detail: None,
+        }
+    }
+
+    fn because(value: T, layer: Layer, detail: impl Into<String>) -> Self {
+        Self {
+            value,
+            layer,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+/// Ruta hacia el destino fuera del canary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteChoice {
+    Direct,
+    Pac,
+    Parent,
+}
+
+/// Lo que decide los valores de una petición.
+#[derive(Debug, Clone)]
+pub struct RequestFacts {
+    method: Method,
+    host: String,
+    port: u16,
+    path: Option<String>,
+    client: IpAddr,
+    /// Usuario de la identidad; sin él, los rollouts reparten por IP.
+    user: Option<String>,
+    profile: Option<String>,
+    /// Las peticiones reales cuentan aplicaciones de reglas y exposiciones
+    /// de los rollouts; las explicaciones no.
+    record: bool,
+}
+
+impl RequestFacts {
+    /// Los de una petición que está atendiendo el proxy.
+    pub fn of(req: &Request<Body>, remote_addr: SocketAddr) -> Self {
+        let uri = req.uri();
+        let connect = req.method() == Method::CONNECT;
+        let https = connect || uri.scheme_str() == Some("https");
+        let identity = req.extensions().get::<Identity>();
+        Self {
+            method: req.method().clone(),
+            host: uri.host().unwrap_or_default().to_string(),
+            port: uri.port_u16().unwrap_or(if https { 443 } else { 80 }),
+            path: (!connect).then(|| uri.path().to_string()),
+            client: remote_addr.ip(),
+            user: identity.map(|identity| identity.user().to_string()),
+            profile: identity.map(|identity| identity.profile().to_string()),
+            record: true,
+        }
+    }
+
+    /// Los de una petición hipotética, para explicarla; `port` por defecto
+    /// es 443 en `CONNECT` y 80 en el resto.
+    pub fn hypothetical(method: Method, host: &str, port: Option<u16>, client: IpAddr) -> Self {
+        let connect = method == Method::CONNECT;
+        Self {
+            port: port.unwrap_or(if connect { 443 } else { 80 }),
+            path: (!connect).then(|| "/".to_string()),
+            method,
+            host: host.to_string(),
+            client,
+            user: None,
+            profile: None,
+            record: false,
+        }
+    }
+
+    /// Cliente identificado con `user` y su perfil.
+    pub fn with_identity(mut self, user: Option<String>, profile: Option<String>) -> Self {
+        self.user = user;
+        self.profile = profile;
+        self
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    fn is_connect(&self) -> bool {
+        self.method == Method::CONNECT
+    }
+
+    /// Sujeto de los rollouts.
+    fn subject(&self) -> String {
+        self.user.clone().unwrap_or_else(|| self.client.to_string())
+    }
+}
+
+/// Valores efectivos de una petición.
+#[derive(Debug, Clone)]
+pub struct EffectiveSettings {
+    host: String,
+    /// `true` si la petición puede seguir.
+    pub access: Resolved<bool>,
+    pub fast_path: Resolved<bool>,
+    pub connect_timeout: Resolved<Option<Duration>>,
+    pub request_timeout: Resolved<Option<Duration>>,
+    /// Peso del canary en porcentaje, si cubre el destino.
+    pub canary: Resolved<Option<f64>>,
+    pub route: Resolved<RouteChoice>,
+    pub intercept: Resolved<bool>,
+    pub response_cache: Resolved<bool>,
+    pub rate_limit: Resolved<Option<(f64, u32)>>,
+    pub max_rate_down: Resolved<Option<u64>>,
+    pub max_rate_up: Resolved<Option<u64>>,
+    pub bandwidth_weight: Resolved<Option<u32>>,
+}
+
+impl EffectiveSettings {
+    /// Resuelve los valores de `facts` en el orden del módulo.
+    pub fn for_request(ctx: &ProxyContext, facts: &RequestFacts) -> Self {
+        let host = facts.host.as_str();
+        let fast_path = match ctx.fast_path().find(host, facts.record) {
+            Some(rule) => Resolved::because(true, Layer::Host, format!("fast_path {rule}")),
+            None => Resolved::new(false, Layer::Default),
+        };
+        let fast = fast_path.value;
+        Self {
+            host: facts.host.clone(),
+            access: access(ctx, facts),
+            connect_timeout: timeout(
+                ctx.connect_timeout(),
+                ProxySettings::DEFAULT_CONNECT_TIMEOUT,
+            ),
+            request_timeout: timeout(
+                ctx.request_timeout(),
+                ProxySettings::DEFAULT_REQUEST_TIMEOUT,
+            ),
+            canary: canary(ctx, host),
+            route: route(ctx, facts),
+            intercept: match ctx.mitm() {
+                None => Resolved::new(false, Layer::Default),
+                Some(_) if fast => Resolved::because(false, Layer::Host, "fast_path"),
+                Some(mitm) if !mitm.intercepts(host) => {
+                    Resolved::because(false, Layer::Host, "passthrough")
+                }
+                Some(_) => Resolved::new(true, Layer::Global),
+            },
+            response_cache: match ctx.response_cache() {
+                None => Resolved::new(false, Layer::Default),
+                Some(_) if facts.method != Method::GET && facts.method != Method::HEAD => {
+                    Resolved::because(false, Layer::Default, "solo GET y HEAD")
+                }
+                Some(_) if fast => Resolved::because(false, Layer::Host, "fast_path"),
+                Some(_) if ctx.llm().is_some_and(|llm| llm.applies(host)) => {
+                    Resolved::because(false, Layer::Host, "host LLM")
+                }
+                Some(_) => Resolved::new(true, Layer::Global),
+            },
+            rate_limit: match ctx.rate_limiter() {
+                Some(limiter) => {
+                    let rate = limiter.rate();
+                    Resolved::new(Some((rate.per_sec(), rate.burst())), Layer::Global)
+                }
+                None => Resolved::new(None, Layer::Default),
+            },
+            max_rate_down: rate(ctx.throttle().down()),
+            max_rate_up: rate(ctx.throttle().up()),
+            bandwidth_weight: bandwidth_weight(ctx, facts),
+            fast_path,
+        }
+    }
+
+    /// Destino para el que se resolvieron.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn fast_path(&self) -> bool {
+        self.fast_path.value
+    }
+
+    /// Cada valor con su capa, en el orden en que se muestran.
+    pub fn explain(&self) -> Vec<Explained> {
+        let mut values = Vec::new();
+        let mut push =
+            |setting: &'static str, value: String, layer: Layer, detail: &Option<String>| {
+                values.push(Explained {
+                    setting: setting.to_string(),
+                    value,
+                    layer,
+                    detail: detail.clone(),
+                })
+            };
+        let yes_no = |value: bool| if value { "sí" } else { "no" }.to_string();
+        let limit = |value: Option<u64>| {
+            value.map_or_else(|| "sin límite".into(), |rate| format!("{rate} B/s"))
+        };
+        let access = &self.access;
+        let allowed = if access.value {
+            "permitido"
+        } else {
+            "denegado"
+        };
+        push("access", allowed.to_string(), access.layer, &access.detail);
+        let fast = &self.fast_path;
+        push("fast_path", yes_no(fast.value), fast.layer, &fast.detail);
+        for (setting, resolved) in [
+            ("connect_timeout", &self.connect_timeout),
+            ("request_timeout", &self.request_timeout),
+        ] {
+            let value = resolved.value.map_or_else(
+                || "sin límite".to_string(),
+                |timeout| format!("{timeout:?}"),
+            );
+            push(setting, value, resolved.layer, &resolved.detail);
+        }
+        let canary = &self.canary;
+        let weight = canary
+            .value
+            .map_or_else(|| "no".to_string(), |weight| format!("{weight} %"));
+        push("canary", weight, canary.layer, &canary.detail);
+        let route = &self.route;
+        let choice = match route.value {
+            RouteChoice::Direct => "directa",
+            RouteChoice::Pac => "pac",
+            RouteChoice::Parent => "proxy padre",
+        };
+        push("route", choice.to_string(), route.layer, &route.detail);
+        let intercept = &self.intercept;
+        push(
+            "intercept",
+            yes_no(intercept.value),
+            intercept.layer,
+            &intercept.detail,
+        );
+        let cache = &self.response_cache;
+        push(
+            "response_cache",
+            yes_no(cache.value),
+            cache.layer,
+            &cache.detail,
+        );
+        let rate_limit = &self.rate_limit;
+        let requests = rate_limit.value.map_or_else(
+            || "sin límite".to_string(),
+            |(per_sec, burst)| format!("{per_sec}/s, ráfaga {burst}"),
+        );
+        push("rate_limit", requests, rate_limit.layer, &rate_limit.detail);
+        let down = &self.max_rate_down;
+        push("max_rate_down", limit(down.value), down.layer, &down.detail);
+        let up = &self.max_rate_up;
+        push("max_rate_up", limit(up.value), up.layer, &up.detail);
+        let weight = &self.bandwidth_weight;
+        let share = weight
+            .value
+            .map_or_else(|| "sin reparto".to_string(), |weight| weight.to_string());
+        push("bandwidth_weight", share, weight.layer, &weight.detail);
+        values
+    }
+}
+
+/// Acceso en orden de precedencia: cada rechazo sustituye al de una capa
+/// inferior, y un permiso solo cuenta si nada ha rechazado.
+fn access(ctx: &ProxyContext, facts: &RequestFacts) -> Resolved<bool> {
+    let host = facts.host.as_str();
+    let mut access = Resolved::new(true, Layer::Default);
+    if facts.is_connect() {
+        match ctx.connect_ports() {
+            Some(ports) if !ports.contains(&facts.port) => {
+                access = Resolved::because(false, Layer::Global, "connect_ports");
+            }
+            Some(_) => allow(&mut access, Layer::Global, "connect_ports".into()),
+            None => {}
+        }
+    }
+    let profile = facts.profile.as_deref().and_then(|name| {
+        ctx.identity()?
+            .profiles()
+            .iter()
+            .find(|profile| profile.name() == name)
+    });
+    if let Some(profile) = profile.filter(|profile| !profile.allow().is_empty()) {
+        let detail = format!("perfil {}", profile.name());
+        if profile.allow().iter().any(|pattern| pattern.matches(host)) {
+            allow(&mut access, Layer::Client, detail);
+        } else {
+            access = Resolved::because(false, Layer::Client, detail);
+        }
+    }
+    if let Some(filter) = ctx.host_filter() {
+        let verdict = filter.check(host);
+        let detail = match verdict.rule() {
+            Some(rule) => format!("host_filter {rule}"),
+            None => "host_filter".to_string(),
+        };
+        if !verdict.is_allowed() {
+            access = Resolved::because(false, Layer::Host, detail);
+        } else if verdict.rule().is_some() {
+            allow(&mut access, Layer::Host, detail);
+        }
+    }
+    if let Some(egress) = ctx.egress() {
+        match egress.allowing(host, facts.port, facts.path.as_deref()) {
+            Some(rule) => allow(&mut access, Layer::Host, format!("egress {}", rule.name())),
+            None => access = Resolved::because(false, Layer::Host, "egress"),
+        }
+    }
+    if ctx.bans().is_some_and(|ban| ban.is_banned(facts.client)) {
+        access = Resolved::because(false, Layer::Runtime, "cliente baneado");
+    }
+    if facts.is_connect() && ctx.drain().refuses_connect() {
+        access = Resolved::because(false, Layer::Runtime, "drenaje");
+    }
+    access
+}
+
+fn allow(access: &mut Resolved<bool>, layer: Layer, detail: String) {
+    if access.value {
+        *access = Resolved::because(true, layer, detail);
+    }
+}
+
+fn timeout(value: Option<Duration>, default: Duration) -> Resolved<Option<Duration>> {
+    let layer = if value == Some(default) {
+        Layer::Default
+    } else {
+        Layer::Global
+    };
+    Resolved::new(value, layer)
+}
+
+fn rate(value: Option<u64>) -> Resolved<Option<u64>> {
+    match value {
+        Some(rate) => Resolved::new(Some(rate), Layer::Global),
+        None => Resolved::new(None, Layer::Default),
+    }
+}
+
+/// El peso en marcha manda sobre el configurado.
+fn canary(ctx: &ProxyContext, host: &str) -> Resolved<Option<f64>> {
+    match ctx.canary().filter(|canary| canary.applies(host)) {
+        None => Resolved::new(None, Layer::Default),
+        Some(canary) if canary.weight() != canary.settings().weight() => Resolved::because(
+            Some(canary.weight()),
+            Layer::Runtime,
+            format!("configurado {} %", canary.settings().weight()),
+        ),
+        Some(canary) => Resolved::because(
+            Some(canary.weight()),
+            Layer::Host,
+            format!("padre {}", canary.parent().parent()),
+        ),
+    }
+}
+
+/// PAC si está activo para el cliente, si no el proxy padre, si no directa.
+fn route(ctx: &ProxyContext, facts: &RequestFacts) -> Resolved<RouteChoice> {
+    let fallback = match ctx.upstream_proxy() {
+        Some(parent) => Resolved::because(
+            RouteChoice::Parent,
+            Layer::Global,
+            parent.parent().to_string(),
+        ),
+        None => Resolved::new(RouteChoice::Direct, Layer::Default),
+    };
+    if ctx.pac().is_none() {
+        return fallback;
+    }
+    let rollouts = ctx.rollouts();
+    let subject = facts.subject();
+    let enabled = if facts.record {
+        rollouts.enabled(Feature::UpstreamPac, &subject, ctx.metrics())
+    } else {
+        rollouts.decide(Feature::UpstreamPac, &subject)
+    };
+    if enabled {
+        Resolved::new(RouteChoice::Pac, Layer::Global)
+    } else {
+        Resolved {
+            layer: Layer::Client,
+            detail: Some("fuera del rollout de upstream_pac".to_string()),
+            ..fallback
+        }
+    }
+}
+
+fn bandwidth_weight(ctx: &ProxyContext, facts: &RequestFacts) -> Resolved<Option<u32>> {
+    let Some(scheduler) = ctx.bandwidth() else {
+        return Resolved::new(None, Layer::Default);
+    };
+    let settings = scheduler.settings();
+    match facts.profile.as_deref() {
+        Some(profile) if settings.profile_weight(profile).is_some() => Resolved::because(
+            Some(settings.weight(Some(profile))),
+            Layer::Client,
+            format!("perfil {profile}"),
+        ),
+        _ => Resolved::new(Some(settings.weight(None)), Layer::Default),
+    }
+}
+
+/// Un valor tal como lo muestra `proxy-ia explain`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Explained {
+    pub setting: String,
+    pub value: String,
+    pub layer: Layer,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Respuesta de `GET /api/explain`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Explanation {
+    pub method: String,
+    pub host: String,
+    pub port: u16,
+    pub client: IpAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub values: Vec<Explained>,
+}
+
+impl Explanation {
+    pub fn new(facts: &RequestFacts, effective: &EffectiveSettings) -> Self {
+        Self {
+            method: facts.method.to_string(),
+            host: facts.host.clone(),
+            port: facts.port,
+            client: facts.client,
+            user: facts.user.clone(),
+            profile: facts.profile.clone(),
+            values: effective.explain(),
+        }
+    }
+
+    pub fn get(&self, setting: &str) -> Option<&Explained> {
+        self.values.iter().find(|value| value.setting == setting)
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}:{} desde {}",
+            self.method, self.host, self.port, self.client
+        )?;
+        match (&self.user, &self.profile) {
+            (Some(user), Some(profile)) => write!(f, " ({user}, perfil {profile})")?,
+            (Some(user), None) => write!(f, " ({user})")?,
+            (None, Some(profile)) => write!(f, " (perfil {profile})")?,
+            (None, None) => {}
+        }
+        writeln!(f)?;
+        for value in &self.values {
+            write!(f, "  {:<18} {:<22} ", value.setting, value.value)?;
+            match &value.detail {
+                Some(detail) => writeln!(f, "{:<8} {detail}", value.layer.as_str())?,
+                None => writeln!(f, "{}", value.layer.as_str())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pide a la API de administración en `admin` la explicación de una
+/// petición.
+pub async fn fetch(
+    admin: SocketAddr,
+    token: Option<&AdminToken>,
+    facts: &RequestFacts,
+) -> anyhow::Result<Explanation> {
+    let mut query = format!(
+        "method={}&host={}&port={}&client={}",
+        facts.method,
+        encode(&facts.host),
+        facts.port,
+        facts.client
+    );
+    if let Some(user) = &facts.user {
+        query.push_str(&format!("&user={}", encode(user)));
+    }
+    if let Some(profile) = &facts.profile {
+        query.push_str(&format!("&profile={}", encode(profile)));
+    }
+    let uri: Uri = format!("http://{admin}/api/explain?{query}").parse()?;
+    let mut request = Request::builder().uri(uri);
+    if let Some(token) = token {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token.expose()));
+    }
+    let response = Client::new()
+        .request(request.body(Body::empty())?)
+        .await
+        .with_context(|| format!("La API de administración en {admin} no responde"))?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    if status != StatusCode::OK {
+        anyhow::bail!(
+            "La API de administración respondió {status}: {}",
+            String::from_utf8_lossy(&body)
+        );
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Codifica lo que no puede ir tal cual en un parámetro de la query.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ban::BanList;
+    use crate::ban::Violation;
+    use crate::canary::Canary;
+    use crate::dialer::{Dialer, SystemResolver};
+    use crate::fast_path::FastPath;
+    use crate::host_filter::HostFilter;
+    use crate::identity::IdentityMapper;
+    use crate::settings::{
+        BanSettings, BandwidthSettings, CanarySettings, DialSettings, FeatureRollout,
+        HostFilterSettings, IdentitySettings, PacSettings, ProfileSettings, RolloutSettings,
+        UpstreamProxySettings,
+    };
+    use crate::upstream::UpstreamProxy;
+    use std::sync::Arc;
+
+    fn context() -> ProxyContext {
+        ProxyContext::new(Dialer::new(
+            DialSettings::default(),
+            Arc::new(SystemResolver),
+        ))
+    }
+
+    fn facts(method: Method, host: &str) -> RequestFacts {
+        RequestFacts::hypothetical(method, host, None, "10.1.2.3".parse().unwrap())
+    }
+
+    fn resolve(ctx: &ProxyContext, method: Method, host: &str) -> EffectiveSettings {
+        EffectiveSettings::for_request(ctx, &facts(method, host))
+    }
+
+    fn parent(authority: &str) -> UpstreamProxySettings {
+        UpstreamProxySettings::new(authority.parse().unwrap())
+    }
+
+    #[test]
+    fn test_global_overrides_default() {
+        let ctx = context();
+        let effective = resolve(&ctx, Method::GET, "a.test");
+        assert_eq!(effective.connect_timeout.layer, Layer::Default);
+        assert_eq!(effective.route.value, RouteChoice::Direct);
+        assert_eq!(effective.route.layer, Layer::Default);
+
+        let ctx = ctx
+            .with_connect_timeout(Some(Duration::from_secs(3)))
+            .with_request_timeout(None)
+            .with_upstream_proxy(UpstreamProxy::new(&parent("parent.test:3128")));
+        let effective = resolve(&ctx, Method::GET, "a.test");
+        assert_eq!(
+            effective.connect_timeout,
+            Resolved::new(Some(Duration::from_secs(3)), Layer::Global)
+        );
+        assert_eq!(
+            effective.request_timeout,
+            Resolved::new(None, Layer::Global)
+        );
+        assert_eq!(effective.route.value, RouteChoice::Parent);
+        assert_eq!(effective.route.layer, Layer::Global);
+    }
+
+    #[test]
+    fn test_client_overrides_global() {
+        let identity = IdentitySettings::default()
+            .with_profile(ProfileSettings::new("lento"))
+            .with_profile(ProfileSettings::new("normal"));
+        let ctx = context()
+            .with_identity(IdentityMapper::new(identity).unwrap())
+            .with_bandwidth(BandwidthSettings::new(1_000_000).with_profile_weight("lento", 4))
+            .with_upstream_proxy(UpstreamProxy::new(&parent("parent.test:3128")))
+            .with_pac(crate::discovery::PacDiscovery::new(PacSettings::new(
+                "http://wpad.test/proxy.pac",
+            )))
+            .with_rollouts(RolloutSettings::default().with_feature(
+                Feature::UpstreamPac,
+                FeatureRollout::new(0.0).with_include("ops"),
+            ));
+        let weight = |profile: &str| {
+            let facts = facts(Method::GET, "a.test").with_identity(None, Some(profile.into()));
+            EffectiveSettings::for_request(&ctx, &facts).bandwidth_weight
+        };
+        assert_eq!(weight("lento").value, Some(4));
+        assert_eq!(weight("lento").layer, Layer::Client);
+        assert_eq!(weight("normal"), Resolved::new(Some(1), Layer::Default));
+
+        // The PAC is configured, but this client falls outside its rollout.
+        let route = resolve(&ctx, Method::GET, "a.test").route;
+        assert_eq!(route.value, RouteChoice::Parent);
+        assert_eq!(route.layer, Layer::Client);
+        let ops = facts(Method::GET, "a.test").with_identity(Some("ops".into()), None);
+        let route = EffectiveSettings::for_request(&ctx, &ops).route;
+        assert_eq!(route, Resolved::new(RouteChoice::Pac, Layer::Global));
+    }
+
+    #[test]
+    fn test_host_overrides_client_and_global() {
+        let canary = CanarySettings::new(parent("canary.test:3128"), 25.0)
+            .with_host("*.canary.test".parse().unwrap());
+        let ctx = context()
+            .with_response_cache(crate::response_cache::ResponseCache::new(Default::default()))
+            .with_fast_path(FastPath::new(vec!["fast.test".parse().unwrap()]))
+            .with_canary(Canary::new(canary).unwrap());
+
+        let cached = resolve(&ctx, Method::GET, "a.test").response_cache;
+        assert_eq!(cached, Resolved::new(true, Layer::Global));
+        let effective = resolve(&ctx, Method::GET, "fast.test");
+        assert_eq!(effective.fast_path.layer, Layer::Host);
+        assert_eq!(
+            effective.response_cache,
+            Resolved::because(false, Layer::Host, "fast_path")
+        );
+        let posted = resolve(&ctx, Method::POST, "a.test").response_cache;
+        assert_eq!(posted.layer, Layer::Default);
+
+        let canary = resolve(&ctx, Method::GET, "www.canary.test").canary;
+        assert_eq!(canary.value, Some(25.0));
+        assert_eq!(canary.layer, Layer::Host);
+        assert_eq!(resolve(&ctx, Method::GET, "a.test").canary.value, None);
+    }
+
+    #[test]
+    fn test_runtime_overrides_host() {
+        let canary = CanarySettings::new(parent("canary.test:3128"), 25.0);
+        let ctx = context()
+            .with_canary(Canary::new(canary).unwrap())
+            .with_ban(BanList::new(BanSettings::default().with_threshold(1)).unwrap())
+            .with_host_filter(HostFilter::new(
+                HostFilterSettings::default().with_allow("*.test".parse().unwrap()),
+            ));
+        ctx.canary().unwrap().set_weight(5.0).unwrap();
+        let canary = resolve(&ctx, Method::GET, "a.test").canary;
+        assert_eq!(canary.value, Some(5.0));
+        assert_eq!(canary.layer, Layer::Runtime);
+
+        assert_eq!(
+            resolve(&ctx, Method::GET, "a.test").access.layer,
+            Layer::Host
+        );
+        let client = "10.1.2.3".parse().unwrap();
+        ctx.bans()
+            .unwrap()
+            .record(client, Violation::BlockedDestination);
+        let access = resolve(&ctx, Method::GET, "a.test").access;
+        assert_eq!(
+            access,
+            Resolved::because(false, Layer::Runtime, "cliente baneado")
+        );
+    }
+
+    #[test]
+    fn test_a_lower_layer_denial_still_denies() {
+        let identity = IdentitySettings::default().with_profile(
+            ProfileSettings::new("interno").with_allow("*.corp.test".parse().unwrap()),
+        );
+        let ctx = context()
+            .with_identity(IdentityMapper::new(identity).unwrap())
+            .with_host_filter(HostFilter::new(
+                HostFilterSettings::default()
+                    .with_allow("*.test".parse().unwrap())
+                    .with_deny("bad.test".parse().unwrap()),
+            ));
+        let access = |host: &str| {
+            let facts = facts(Method::GET, host).with_identity(None, Some("interno".into()));
+            EffectiveSettings::for_request(&ctx, &facts).access
+        };
+        // The host filter allows it, the profile does not.
+        assert_eq!(
+            access("a.test"),
+            Resolved::because(false, Layer::Client, "perfil interno")
+        );
+        assert_eq!(
+            access("ok.corp.test"),
+            Resolved::because(true, Layer::Host, "host_filter *.test")
+        );
+        // Both deny: the higher layer is reported.
+        assert_eq!(
+            access("bad.test"),
+            Resolved::because(false, Layer::Host, "host_filter bad.test")
+        );
+    }
+}
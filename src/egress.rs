@@ -0,0 +1,171 @@
+//! Salida con denegación por defecto.
+//!
+//! Con `egress_policy = "deny"` solo salen las peticiones cuyo destino casa
+//! con alguna regla `allow`; el resto recibe un 403 propio que explica cómo
+//! pedir que se agregue. La regla se evalúa sobre el destino que pidió el
+//! cliente, antes de que el script lo reencamine. En este modo las reglas
+//! `allow` son lo único que decide: la reputación de los destinos y los
+//! bloqueos del script no se consultan. Los perfiles de identidad, que
+//! también son listas de destinos permitidos, siguen acotando a sus
+//! usuarios, y los baneos y la reputación de clientes se aplican igual.
+
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::policy_export::{HitCount, RuleHits};
+use crate::settings::{EgressRule, EgressSettings};
+
+/// Resultado de evaluar un destino, tal como lo muestra la API de
+/// administración.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EgressDecision {
+    pub host: String,
+    pub port: u16,
+    pub path: Option<String>,
+    pub allowed: bool,
+    /// Regla que lo permitió.
+    pub rule: Option<String>,
+}
+
+pub struct EgressPolicy {
+    settings: EgressSettings,
+    /// One per rule, in the same order.
+    hits: Vec<RuleHits>,
+}
+
+impl EgressPolicy {
+    /// Avisa al arrancar de cuántas reglas quedan activas, para que un
+    /// despliegue sin ninguna no pase desapercibido.
+    pub fn new(settings: EgressSettings) -> Self {
+        let scoped = settings
+            .rules()
+            .iter()
+            .filter(|rule| !rule.path_prefixes().is_empty())
+            .count();
+        warn!(
+            rules = settings.rules().len(),
+            path_scoped = scoped,
+            "Salida restringida: solo se permiten los destinos de las reglas allow"
+        );
+        let hits = settings
+            .rules()
+            .iter()
+            .map(|_| RuleHits::default())
+            .collect();
+        Self { settings, hits }
+    }
+
+    pub fn settings(&self) -> &EgressSettings {
+        &self.settings
+    }
+
+    /// Reglas con sus aplicaciones.
+    pub fn rules(&self) -> impl Iterator<Item = (&EgressRule, HitCount)> {
+        self.settings
+            .rules()
+            .iter()
+            .zip(self.hits.iter().map(RuleHits::get))
+    }
+
+    fn position(&self, host: &str, port: u16, path: Option<&str>) -> Option<usize> {
+        self.settings.rules().iter().position(|rule| {
+            rule.host().matches(host)
+                && rule.port().is_none_or(|allowed| allowed == port)
+                && (rule.path_prefixes().is_empty()
+                    || path.is_some_and(|path| within(path, rule.path_prefixes())))
+        })
+    }
+
+    /// Primera regla que permite el destino. `path` es `None` en los
+    /// túneles, que solo casan con reglas sin prefijos de ruta.
+    pub fn allowing(&self, host: &str, port: u16, path: Option<&str>) -> Option<&EgressRule> {
+        self.position(host, port, path)
+            .map(|i| &self.settings.rules()[i])
+    }
+
+    /// Como [`EgressPolicy::allowing`], contando la aplicación de la regla;
+    /// lo usa el tráfico real, no las consultas de depuración.
+    pub fn admit(&self, host: &str, port: u16, path: Option<&str>) -> Option<&EgressRule> {
+        let i = self.position(host, port, path)?;
+        self.hits[i].record();
+        Some(&self.settings.rules()[i])
+    }
+
+    pub fn decide(&self, host: &str, port: u16, path: Option<&str>) -> EgressDecision {
+        let rule = self.allowing(host, port, path);
+        EgressDecision {
+            host: host.to_string(),
+            port,
+            path: path.map(str::to_string),
+            allowed: rule.is_some(),
+            rule: rule.map(|rule| rule.name().to_string()),
+        }
+    }
+
+    /// 403 para un destino fuera de la lista, distinto de los de bloqueo.
+    pub fn denied(&self, host: &str, port: u16) -> Response<Body> {
+        let contact = self.settings.contact().unwrap_or(
+            "Para pedir que se agregue, contacte con el administrador del proxy indicando host y puerto.",
+        );
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header("x-proxy-egress", "denied")
+            .body(Body::from(format!(
+                "El destino {host}:{port} no está en la lista de salida permitida. {contact}\n"
+            )))
+            .expect("respuesta de salida denegada")
+    }
+}
+
+/// Si `path` empieza por alguno de los prefijos. Las rutas con segmentos
+/// `.` o `..`, aunque vengan codificados, no casan: el destino podría
+/// resolverlos fuera del prefijo.
+fn within(path: &str, prefixes: &[String]) -> bool {
+    let dot_segment = path.split('/').any(|segment| {
+        let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+        segment == "." || segment == ".."
+    });
+    !dot_segment
+        && prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::EgressMode;
+
+    #[test]
+    fn test_rules_scope_hosts_ports_and_paths() {
+        let settings = EgressSettings::default()
+            .with_mode(EgressMode::Deny)
+            .with_rule(EgressRule::new("crates.io".parse().unwrap(), Some(443)))
+            .with_rule(
+                EgressRule::new("*.example.test".parse().unwrap(), None)
+                    .with_name("api")
+                    .with_path_prefix("/api/"),
+            );
+        let policy = EgressPolicy::new(settings);
+
+        let rule = policy.admit("crates.io", 443, None).unwrap();
+        assert_eq!(rule.name(), "crates.io:443");
+        assert!(policy.allowing("crates.io", 80, Some("/")).is_none());
+
+        let decision = policy.decide("a.example.test", 8080, Some("/api/v1"));
+        assert!(decision.allowed);
+        assert_eq!(decision.rule.as_deref(), Some("api"));
+        assert!(policy
+            .allowing("a.example.test", 80, Some("/admin"))
+            .is_none());
+        assert!(policy
+            .allowing("a.example.test", 80, Some("/api/%2E%2e/admin"))
+            .is_none());
+        // A tunnel cannot show its path, so path-scoped rules never match it.
+        assert!(policy.allowing("a.example.test", 443, None).is_none());
+
+        let hits: Vec<u64> = policy.rules().map(|(_, count)| count.hits).collect();
+        assert_eq!(hits, [1, 0]);
+    }
+}
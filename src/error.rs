@@ -0,0 +1,180 @@
+use std::fmt;
+
+use hyper::{Body, Response, StatusCode};
+
+/// Taxonomía de errores que el proxy puede devolver al cliente.
+///
+/// Cada variante conoce su código HTTP y una categoría estable que se usa en
+/// logs y métricas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyError {
+    /// No se pudo establecer la conexión TCP con el destino.
+    Connect,
+    /// El destino cerró o reinició la conexión antes de responder.
+    Reset,
+    /// El destino respondió con bytes que no son HTTP válido.
+    InvalidResponse,
+    /// El destino no respondió a tiempo.
+    Timeout,
+    /// Las cabeceras de la respuesta superan `[header_limits]` o el buffer
+    /// de lectura.
+    HeadersTooLarge,
+    /// Cualquier otro fallo del cliente HTTP hacia el destino.
+    Other,
+    /// El destino resuelve a una red bloqueada (ver [`crate::ssrf`]).
+    BlockedNetwork,
+    /// El body de la respuesta supera `max_response_body` (ver
+    /// [`crate::body_limits`]).
+    BodyTooLarge,
+}
+
+impl ProxyError {
+    pub const ALL: [ProxyError; 8] = [
+        ProxyError::Connect,
+        ProxyError::Reset,
+        ProxyError::InvalidResponse,
+        ProxyError::Timeout,
+        ProxyError::HeadersTooLarge,
+        ProxyError::Other,
+        ProxyError::BlockedNetwork,
+        ProxyError::BodyTooLarge,
+    ];
+
+    /// Clasifica un error del cliente hyper. Devuelve `None` cuando el fallo
+    /// viene del lado del cliente (downstream) y no tiene sentido responder.
+    pub fn from_upstream(err: &hyper::Error) -> Option<Self> {
+        if err.is_user() {
+            return None;
+        }
+        let kind = if crate::ssrf::blocked(err).is_some() {
+            ProxyError::BlockedNetwork
+        } else if err.is_timeout() {
+            ProxyError::Timeout
+        } else if err.is_connect() {
+            ProxyError::Connect
+        } else if err.is_parse_too_large() {
+            ProxyError::HeadersTooLarge
+        } else if err.is_parse() || err.is_parse_status() {
+            ProxyError::InvalidResponse
+        } else if err.is_incomplete_message() || err.is_closed() || is_io_reset(err) {
+            ProxyError::Reset
+        } else {
+            ProxyError::Other
+        };
+        Some(kind)
+    }
+
+    /// Clasifica un fallo al conectar con el destino.
+    pub fn from_connect(err: &std::io::Error) -> Self {
+        if crate::ssrf::blocked(err).is_some() {
+            ProxyError::BlockedNetwork
+        } else if err.kind() == std::io::ErrorKind::TimedOut {
+            ProxyError::Timeout
+        } else {
+            ProxyError::Connect
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::BlockedNetwork => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Categoría estable para logs y métricas.
+    pub fn category(&self) -> &'static str {
+        match self {
+            ProxyError::Connect => "upstream_connect",
+            ProxyError::Reset => "upstream_reset",
+            ProxyError::InvalidResponse => "upstream_invalid_response",
+            ProxyError::Timeout => "upstream_timeout",
+            ProxyError::HeadersTooLarge => "upstream_headers_too_large",
+            ProxyError::Other => "upstream_other",
+            ProxyError::BlockedNetwork => "upstream_blocked_network",
+            ProxyError::BodyTooLarge => "upstream_body_too_large",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            ProxyError::Connect => "No se pudo conectar con el destino",
+            ProxyError::Reset => "El destino cerró la conexión inesperadamente",
+            ProxyError::InvalidResponse => "El destino devolvió una respuesta HTTP inválida",
+            ProxyError::Timeout => "El destino no respondió a tiempo",
+            ProxyError::HeadersTooLarge => "El destino respondió con cabeceras demasiado grandes",
+            ProxyError::Other => "Error al comunicarse con el destino",
+            ProxyError::BlockedNetwork => "El destino está en una red a la que el proxy no conecta",
+            ProxyError::BodyTooLarge => "El destino respondió con un body demasiado grande",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        ProxyError::ALL
+            .iter()
+            .position(|kind| kind == self)
+            .expect("variante registrada en ALL")
+    }
+
+    /// Respuesta que recibe el cliente del proxy.
+    pub fn into_response(self) -> Response<Body> {
+        Response::builder()
+            .status(self.status())
+            .header("x-proxy-error", self.category())
+            .body(Body::from(self.message()))
+            .expect("respuesta de error del proxy")
+    }
+
+    /// Como [`ProxyError::into_response`], contando los `attempts` intentos
+    /// si hubo más de uno.
+    pub fn into_response_after(self, attempts: u32) -> Response<Body> {
+        let mut response = self.into_response();
+        if attempts > 1 {
+            *response.body_mut() =
+                Body::from(format!("{} tras {attempts} intentos", self.message()));
+        }
+        response
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+fn is_io_reset(err: &hyper::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        source = cause.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_and_category() {
+        assert_eq!(ProxyError::Timeout.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(ProxyError::Reset.status(), StatusCode::BAD_GATEWAY);
+        for (i, kind) in ProxyError::ALL.iter().enumerate() {
+            assert_eq!(kind.index(), i);
+        }
+        let res = ProxyError::Connect.into_response();
+        assert_eq!(res.headers()["x-proxy-error"], "upstream_connect");
+    }
+}
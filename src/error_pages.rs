@@ -0,0 +1,255 @@
+//! Páginas de error propias en lugar de las del destino.
+//!
+//! Hay destinos que, cuando fallan, devuelven trazas y páginas de error del
+//! framework. Para los hosts (y rutas) de `[error_pages]`, las respuestas con
+//! los códigos configurados (por defecto 500-599) se reemplazan por la
+//! plantilla del proxy, con el código original o el que fije la regla. El
+//! body original se registra redactado y recortado a `max_log_bytes`, y se
+//! cuenta. Un desarrollador puede ver la respuesta original enviando
+//! `X-Debug-Errors` con el token de la regla; la cabecera nunca llega al
+//! destino.
+//!
+//! Solo se reemplaza lo que el destino declara como error en las cabeceras:
+//! si el body de otra respuesta se corta a mitad de stream, las cabeceras ya
+//! salieron hacia el cliente y solo queda registrarlo.
+
+use std::sync::Arc;
+
+use futures_util::TryStreamExt;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, HeaderMap, Response, StatusCode, Uri};
+use tracing::warn;
+
+use crate::capture::buffer_prefix;
+use crate::metrics::Metrics;
+use crate::redact::{redact_text, redact_url};
+use crate::settings::{AdminToken, ErrorPageRule, ErrorPageSettings};
+
+pub const DEBUG_HEADER: &str = "x-debug-errors";
+
+const DEFAULT_TEMPLATE: &str = r#"<!doctype html>
+<html lang="es">
+<head><meta charset="utf-8"><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>El servicio no pudo completar la petición. Inténtelo de nuevo más tarde.</p>
+</body>
+</html>
+"#;
+
+struct Rule {
+    settings: ErrorPageRule,
+    /// Compared like the admin token, in constant time.
+    token: Option<AdminToken>,
+}
+
+pub struct ErrorPages {
+    rules: Vec<Rule>,
+    template: String,
+    max_log_bytes: usize,
+}
+
+impl ErrorPages {
+    /// Lee la plantilla y resuelve los tokens desde `env`; falla si falta
+    /// alguno de los dos.
+    pub fn new(
+        settings: &ErrorPageSettings,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<Self> {
+        let template = match settings.template() {
+            Some(path) => std::fs::read_to_string(path).map_err(|e| {
+                anyhow::anyhow!("no se pudo leer la plantilla {}: {e}", path.display())
+            })?,
+            None => DEFAULT_TEMPLATE.to_string(),
+        };
+        let rules = settings
+            .rules()
+            .iter()
+            .map(|rule| {
+                let token = match rule.debug_token_env() {
+                    Some(name) => Some(AdminToken::new(env(name).ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "la variable de entorno {name} de una página de error no está definida"
+                        )
+                    })?)),
+                    None => None,
+                };
+                Ok(Rule {
+                    settings: rule.clone(),
+                    token,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            rules,
+            template,
+            max_log_bytes: settings.max_log_bytes(),
+        })
+    }
+
+    /// Quita `X-Debug-Errors` de la petición y devuelve la regla que se
+    /// aplicará a la respuesta: ninguna si el host no tiene o si la petición
+    /// trae su token.
+    pub fn intercept(
+        &self,
+        host: &str,
+        path: &str,
+        headers: &mut HeaderMap,
+    ) -> Option<ErrorPageRule> {
+        let presented = headers.remove(DEBUG_HEADER);
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.settings.matches(host, path))?;
+        let bypass = match (&rule.token, &presented) {
+            (Some(token), Some(presented)) => token.matches(presented.as_bytes()),
+            _ => false,
+        };
+        (!bypass).then(|| rule.settings.clone())
+    }
+
+    /// Reemplaza la respuesta si su código está entre los de `rule`; si no,
+    /// la deja pasar vigilando que el body no se corte.
+    pub async fn apply(
+        &self,
+        rule: &ErrorPageRule,
+        uri: &Uri,
+        response: Response<Body>,
+        metrics: &Arc<Metrics>,
+    ) -> Response<Body> {
+        let original = response.status();
+        if !rule
+            .statuses()
+            .iter()
+            .any(|range| range.contains(original.as_u16()))
+        {
+            return watch_body(response, uri.clone(), metrics.clone());
+        }
+
+        let (parts, body) = response.into_parts();
+        let (prefix, truncated, _) = buffer_prefix(body, self.max_log_bytes).await;
+        warn!(
+            uri = %redact_url(uri),
+            status = original.as_u16(),
+            truncated,
+            body = %redact_text(&String::from_utf8_lossy(&prefix)),
+            "Página de error del destino reemplazada"
+        );
+        metrics.record_error_page_replaced();
+
+        let status = rule
+            .status()
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .unwrap_or(original);
+        let page = self
+            .template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or("Error"));
+        let mut replaced = Response::new(Body::from(page));
+        *replaced.status_mut() = status;
+        let headers = replaced.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        if let Some(retry_after) = parts.headers.get(RETRY_AFTER) {
+            headers.insert(RETRY_AFTER, retry_after.clone());
+        }
+        replaced
+    }
+}
+
+fn watch_body(response: Response<Body>, uri: Uri, metrics: Arc<Metrics>) -> Response<Body> {
+    let status = response.status().as_u16();
+    let (parts, body) = response.into_parts();
+    let body = body.inspect_err(move |e| {
+        warn!(
+            uri = %redact_url(&uri),
+            status,
+            error = %e,
+            "El body del destino falló con las cabeceras ya enviadas; no se puede mostrar la página de error"
+        );
+        metrics.record_error_page_unreplaceable();
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_pattern::HostPattern;
+    use crate::settings::StatusRange;
+    use hyper::body::HttpBody;
+
+    fn pages() -> ErrorPages {
+        let rule = ErrorPageRule::new("api.test".parse::<HostPattern>().unwrap())
+            .with_statuses(vec!["500-599".parse().unwrap(), "404".parse().unwrap()])
+            .with_status(502)
+            .with_debug_token_env("DEBUG_TOKEN");
+        let settings = ErrorPageSettings::default()
+            .with_rule(rule)
+            .with_max_log_bytes(32);
+        ErrorPages::new(&settings, |_| Some("dev-secreto".into())).unwrap()
+    }
+
+    #[test]
+    fn test_parses_status_ranges() {
+        let range: StatusRange = "500-503".parse().unwrap();
+        assert!(range.contains(500) && range.contains(503) && !range.contains(504));
+        assert!("404".parse::<StatusRange>().unwrap().contains(404));
+        assert!("503-500".parse::<StatusRange>().is_err());
+        assert!("700".parse::<StatusRange>().is_err());
+    }
+
+    #[test]
+    fn test_bypass_token_and_header_stripping() {
+        let pages = pages();
+        let mut headers = HeaderMap::new();
+        headers.insert(DEBUG_HEADER, "otro".parse().unwrap());
+        assert!(pages.intercept("api.test", "/", &mut headers).is_some());
+        assert!(!headers.contains_key(DEBUG_HEADER));
+
+        headers.insert(DEBUG_HEADER, "dev-secreto".parse().unwrap());
+        assert!(pages.intercept("api.test", "/", &mut headers).is_none());
+        assert!(!headers.contains_key(DEBUG_HEADER));
+
+        // Hosts without a rule still lose the header.
+        headers.insert(DEBUG_HEADER, "dev-secreto".parse().unwrap());
+        assert!(pages.intercept("otro.test", "/", &mut headers).is_none());
+        assert!(!headers.contains_key(DEBUG_HEADER));
+
+        assert!(ErrorPages::new(
+            &ErrorPageSettings::default()
+                .with_rule(ErrorPageRule::new("*".parse().unwrap()).with_debug_token_env("NADA")),
+            |_| None,
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mid_stream_failure_is_logged_not_replaced() {
+        let pages = pages();
+        let rule = pages
+            .intercept("api.test", "/", &mut HeaderMap::new())
+            .unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let (mut sender, body) = Body::channel();
+        let uri: Uri = "http://api.test/descarga".parse().unwrap();
+        let response = pages
+            .apply(&rule, &uri, Response::new(body), &metrics)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        sender
+            .send_data(hyper::body::Bytes::from_static(b"parte"))
+            .await
+            .unwrap();
+        sender.abort();
+        let mut body = response.into_body();
+        assert_eq!(&body.data().await.unwrap().unwrap()[..], b"parte");
+        assert!(body.data().await.unwrap().is_err());
+        assert_eq!(metrics.error_pages_unreplaceable(), 1);
+        assert_eq!(metrics.error_pages_replaced(), 0);
+    }
+}
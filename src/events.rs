@@ -0,0 +1,444 @@
+//! Flujo de eventos en tiempo real para agentes locales.
+//!
+//! Un EDR o un auditor que quiere seguir la actividad del proxy sin parsear
+//! logs se conecta al socket de `[events]` (UNIX o TCP en loopback) y recibe
+//! un evento JSON por línea: inicio y fin de cada petición, apertura y
+//! cierre de túneles (con SNI, bytes y duración), decisiones de política y
+//! baneos. Cada línea lleva `v` (la versión del esquema, [`SCHEMA_VERSION`]),
+//! `seq`, `at_ms` y `type`; los campos de cada tipo son los de [`Event`].
+//! Los eventos de una misma conexión de cliente llegan en orden. Cada
+//! suscriptor tiene una cola acotada ([`crate::batch`]) que se le escribe en
+//! lotes, cuando se juntan [`BATCH`] o cada [`FLUSH_INTERVAL`]: si no lee a
+//! tiempo, los eventos que no caben se descartan y se cuentan, y el proxy
+//! nunca espera por él.
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::batch::{self, Batcher};
+use crate::settings::{EventEndpoint, EventSettings};
+
+/// Versión del esquema; cambia cuando un campo cambia de significado o
+/// desaparece, no al añadir campos o tipos.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Eventos que despiertan al suscriptor antes de su intervalo.
+pub const BATCH: usize = 64;
+
+/// Lo más que espera un evento antes de enviarse.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Lo que se cuenta en el flujo. `connection` identifica la conexión del
+/// cliente que llevó la petición y `tunnel` es el id de `GET /api/tunnels`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    RequestStarted {
+        connection: u64,
+        client: SocketAddr,
+        method: String,
+        host: String,
+        protocol: &'static str,
+    },
+    RequestFinished {
+        connection: u64,
+        client: SocketAddr,
+        method: String,
+        host: String,
+        /// `None` si la conexión falló antes de responder.
+        status: Option<u16>,
+        elapsed_ms: f64,
+    },
+    TunnelOpened {
+        tunnel: u64,
+        client: SocketAddr,
+        host: String,
+        port: u16,
+        ip: IpAddr,
+        via_parent: bool,
+    },
+    TunnelClosed {
+        tunnel: u64,
+        client: SocketAddr,
+        host: String,
+        port: u16,
+        sni: Option<String>,
+        /// Del cliente hacia el destino.
+        bytes_sent: u64,
+        bytes_received: u64,
+        duration_ms: f64,
+    },
+    /// La API de administración pidió cerrar el túnel; el `tunnel_closed`
+    /// llega cuando termina de cerrarse.
+    TunnelTerminated {
+        tunnel: u64,
+        client: SocketAddr,
+        host: String,
+        reason: String,
+    },
+    /// Como `tunnel_terminated`, para una conexión HTTP o un túnel
+    /// interceptado de `GET /api/connections`.
+    ConnectionTerminated {
+        connection: u64,
+        client: SocketAddr,
+        kind: &'static str,
+        host: Option<String>,
+        reason: String,
+    },
+    Policy {
+        policy: &'static str,
+        decision: &'static str,
+        /// Host de destino, o la IP del cliente en la reputación de clientes.
+        target: String,
+    },
+    Ban {
+        ip: IpAddr,
+        secs: u64,
+        /// Infracción que disparó el baneo.
+        violation: &'static str,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    v: u32,
+    seq: u64,
+    at_ms: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Un suscriptor, tal como lo muestra `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SubscriberSnapshot {
+    pub id: u64,
+    pub dropped: u64,
+}
+
+/// Estado del flujo para `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EventStats {
+    pub schema_version: u32,
+    pub published: u64,
+    pub dropped: u64,
+    pub subscribers: Vec<SubscriberSnapshot>,
+}
+
+struct Subscriber {
+    id: u64,
+    lines: Arc<Batcher>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    seq: u64,
+    list: Vec<Subscriber>,
+}
+
+pub struct EventHub {
+    settings: EventSettings,
+    subscribers: Mutex<Subscribers>,
+    published: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl EventHub {
+    pub fn new(settings: EventSettings) -> Self {
+        Self {
+            settings,
+            subscribers: Mutex::new(Subscribers::default()),
+            published: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn settings(&self) -> &EventSettings {
+        &self.settings
+    }
+
+    /// Indica si alguien escucha; sin suscriptores no se construyen eventos.
+    pub fn has_subscribers(&self) -> bool {
+        !self
+            .subscribers
+            .lock()
+            .expect("lock de suscriptores")
+            .list
+            .is_empty()
+    }
+
+    /// Encola `event` a cada suscriptor sin esperar a ninguno.
+    pub fn publish(&self, event: &Event) {
+        let mut subscribers = self.subscribers.lock().expect("lock de suscriptores");
+        if subscribers.list.is_empty() {
+            return;
+        }
+        // Numbered under the lock so every queue gets the same order.
+        subscribers.seq += 1;
+        let envelope = Envelope {
+            v: SCHEMA_VERSION,
+            seq: subscribers.seq,
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            event,
+        };
+        let mut line = serde_json::to_string(&envelope).expect("evento serializable");
+        line.push('\n');
+        let line: Arc<str> = line.into();
+        subscribers
+            .list
+            .retain(|subscriber| !subscriber.lines.is_closed());
+        for subscriber in &subscribers.list {
+            if !subscriber.lines.push(line.clone()) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.published.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Nuevo suscriptor con su cola; se da de baja al soltar la
+    /// [`Subscription`].
+    pub fn subscribe(&self) -> Subscription {
+        let ready = Arc::new(Notify::new());
+        let wake = ready.clone();
+        let lines = Arc::new(Batcher::new(self.settings.queue(), BATCH, move || {
+            wake.notify_one()
+        }));
+        let mut subscribers = self.subscribers.lock().expect("lock de suscriptores");
+        subscribers.next_id += 1;
+        let id = subscribers.next_id;
+        subscribers.list.push(Subscriber {
+            id,
+            lines: lines.clone(),
+        });
+        Subscription {
+            lines,
+            ready,
+            received: VecDeque::new(),
+        }
+    }
+
+    pub fn stats(&self) -> EventStats {
+        let subscribers = self.subscribers.lock().expect("lock de suscriptores");
+        EventStats {
+            schema_version: SCHEMA_VERSION,
+            published: self.published.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            subscribers: subscribers
+                .list
+                .iter()
+                .filter(|subscriber| !subscriber.lines.is_closed())
+                .map(|subscriber| SubscriberSnapshot {
+                    id: subscriber.id,
+                    dropped: subscriber.lines.dropped(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Atiende a los suscriptores hasta que el listener falle.
+    pub async fn serve(self: Arc<Self>, listener: EventListener) {
+        loop {
+            let accepted = match &listener {
+                EventListener::Tcp(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, addr)| (Box::new(stream) as Box<dyn Stream>, addr.to_string())),
+                #[cfg(unix)]
+                EventListener::Unix(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| (Box::new(stream) as Box<dyn Stream>, "unix".to_string())),
+            };
+            match accepted {
+                Ok((stream, peer)) => {
+                    info!(%peer, "Suscriptor del flujo de eventos conectado");
+                    tokio::spawn(feed(stream, self.subscribe(), peer));
+                }
+                Err(e) => warn!(error = %e, "Error al aceptar un suscriptor de eventos"),
+            }
+        }
+    }
+}
+
+/// Cola de un suscriptor.
+pub struct Subscription {
+    lines: Arc<Batcher>,
+    ready: Arc<Notify>,
+    received: VecDeque<Arc<str>>,
+}
+
+impl Subscription {
+    /// Siguiente evento; `None` si el hub ya no existe.
+    pub async fn recv(&mut self) -> Option<Arc<str>> {
+        loop {
+            if let Some(line) = self.received.pop_front() {
+                return Some(line);
+            }
+            let batch = self.next_batch().await?;
+            self.received.extend(batch);
+        }
+    }
+
+    /// Lo encolado, en cuanto se junta un lote o vence el intervalo.
+    async fn next_batch(&mut self) -> Option<Vec<Arc<str>>> {
+        loop {
+            let batch = self.lines.take();
+            if !batch.is_empty() {
+                return Some(batch);
+            }
+            if Arc::strong_count(&self.lines) == 1 {
+                return None;
+            }
+            let _ = tokio::time::timeout(FLUSH_INTERVAL, self.ready.notified()).await;
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.lines.close();
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Escribe la cola del suscriptor en su socket hasta que se desconecte.
+async fn feed(stream: Box<dyn Stream>, mut queue: Subscription, peer: String) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut ignored = [0u8; 64];
+    loop {
+        tokio::select! {
+            // `take` runs with no await before returning, so losing this
+            // branch never loses lines.
+            lines = queue.next_batch() => {
+                let Some(lines) = lines else { break };
+                if batch::write_lines_async(&mut writer, &lines).await.is_err() {
+                    break;
+                }
+            }
+            // Subscribers only listen; EOF or an error means they left.
+            read = reader.read(&mut ignored) => {
+                if !matches!(read, Ok(1..)) {
+                    break;
+                }
+            }
+        }
+    }
+    debug!(%peer, "Suscriptor del flujo de eventos desconectado");
+}
+
+/// Socket donde se conectan los suscriptores.
+pub enum EventListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl EventListener {
+    /// Abre `endpoint`; un socket UNIX que quedó de una ejecución anterior
+    /// se reemplaza, cualquier otro archivo en esa ruta es un error.
+    pub async fn bind(endpoint: &EventEndpoint) -> anyhow::Result<Self> {
+        match endpoint {
+            EventEndpoint::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("No se pudo escuchar eventos en {addr}"))?;
+                Ok(EventListener::Tcp(listener))
+            }
+            #[cfg(unix)]
+            EventEndpoint::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    if !meta.file_type().is_socket() {
+                        anyhow::bail!(
+                            "{} existe y no es un socket: no se reemplaza",
+                            path.display()
+                        );
+                    }
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("No se pudo reemplazar {}", path.display()))?;
+                }
+                let listener = tokio::net::UnixListener::bind(path).with_context(|| {
+                    format!("No se pudo escuchar eventos en {}", path.display())
+                })?;
+                Ok(EventListener::Unix(listener))
+            }
+            #[cfg(not(unix))]
+            EventEndpoint::Unix(path) => anyhow::bail!(
+                "Los sockets UNIX ({}) no existen en esta plataforma",
+                path.display()
+            ),
+        }
+    }
+
+    /// Dirección TCP, para los listeners en un puerto efímero.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            EventListener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            EventListener::Unix(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(n: u8) -> Event {
+        Event::Ban {
+            ip: IpAddr::from([10, 0, 0, n]),
+            secs: 60,
+            violation: "auth_failure",
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_drops_and_counts_without_blocking_others() {
+        let hub = EventHub::new(
+            EventSettings::new(EventEndpoint::Tcp(([127, 0, 0, 1], 0).into())).with_queue(2),
+        );
+        assert!(!hub.has_subscribers());
+        let mut slow = hub.subscribe();
+        let mut fast = hub.subscribe();
+        let mut seen = Vec::new();
+        for n in 1..=5 {
+            hub.publish(&ban(n));
+            let line = fast.recv().await.unwrap();
+            let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+            seen.push(event["seq"].as_u64().unwrap());
+            assert_eq!(event["v"], SCHEMA_VERSION);
+            assert_eq!(event["type"], "ban");
+        }
+        assert_eq!(seen, [1, 2, 3, 4, 5]);
+
+        let stats = hub.stats();
+        assert_eq!((stats.published, stats.dropped), (5, 3));
+        assert_eq!(stats.subscribers[0].dropped, 3);
+        assert_eq!(stats.subscribers[1].dropped, 0);
+        // The slow one keeps the oldest events it had room for.
+        let first: serde_json::Value = serde_json::from_str(&slow.recv().await.unwrap()).unwrap();
+        assert_eq!(first["seq"], 1);
+        assert_eq!(first["ip"], "10.0.0.1");
+
+        drop(slow);
+        hub.publish(&ban(6));
+        assert_eq!(hub.stats().subscribers.len(), 1);
+    }
+}
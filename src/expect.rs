@@ -0,0 +1,160 @@
+//! Reenvío de peticiones con `Expect: 100-continue`.
+//!
+//! El cliente de hyper envía el body sin esperar al `100 Continue` del
+//! destino, y el servidor de hyper contesta `100 Continue` al cliente en
+//! cuanto se lee el body. Para que el cliente solo suba el body cuando el
+//! destino lo acepta, la petición viaja por una conexión propia cuyo body
+//! permanece retenido hasta que el destino responde `100` (o pasa el
+//! timeout). Si antes llega una respuesta final (417, 413, 401...), se
+//! devuelve tal cual sin leer el body del cliente, así que hyper no le envía
+//! `100` y descarta lo que quede de la subida al cerrar la conexión.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, EXPECT};
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tracing::debug;
+
+/// Longitud máxima de la primera línea de estado que se inspecciona.
+const MAX_STATUS_LINE: usize = 256;
+
+/// Indica si la petición espera un `100 Continue` antes de enviar el body.
+pub fn wants_continue(headers: &HeaderMap) -> bool {
+    headers
+        .get(EXPECT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue"))
+}
+
+/// Envía `req` por `stream` reteniendo el body hasta que el destino lo pida.
+/// La URI se escribe tal cual: en forma de origen para un destino, absoluta
+/// para un proxy padre.
+pub async fn send(
+    stream: TcpStream,
+    req: Request<Body>,
+    timeout: Duration,
+) -> Result<Response<Body>, hyper::Error> {
+    let (continue_tx, continue_rx) = oneshot::channel();
+    let stream = InterimWatch {
+        inner: stream,
+        line: Vec::new(),
+        on_continue: Some(continue_tx),
+    };
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        // The request body is aborted on purpose after an early rejection.
+        if let Err(e) = conn.await {
+            debug!(error = %e, "Conexión con Expect terminó con error");
+        }
+    });
+
+    let (parts, client_body) = req.into_parts();
+    let (gate_tx, gate_rx) = oneshot::channel::<()>();
+    let body = futures_util::stream::once(gate_rx)
+        .filter_map(|opened| async move {
+            match opened {
+                Ok(()) => None,
+                Err(_) => Some(Err::<Bytes, _>(io::Error::other(
+                    "subida descartada tras el rechazo",
+                ))),
+            }
+        })
+        .chain(client_body.map(|chunk| chunk.map_err(io::Error::other)));
+    let response = sender.send_request(Request::from_parts(parts, Body::wrap_stream(body)));
+    tokio::pin!(response);
+
+    let proceed = async {
+        match tokio::time::timeout(timeout, continue_rx).await {
+            Ok(Ok(())) => debug!("El destino pidió el body (100 Continue)"),
+            // Another status arrived first; the response branch handles it.
+            Ok(Err(_)) => std::future::pending().await,
+            Err(_) => debug!("Sin respuesta a Expect; se envía el body"),
+        }
+    };
+    tokio::select! {
+        result = &mut response => {
+            let response = result?;
+            debug!(status = %response.status(), "Respuesta final antes del body");
+            // Keep the gate closed until the response body is relayed:
+            // aborting the upload earlier would also tear down the read side.
+            let (parts, body) = response.into_parts();
+            let body = body.map(move |chunk| {
+                let _ = &gate_tx;
+                chunk
+            });
+            Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+        }
+        () = proceed => {
+            let _ = gate_tx.send(());
+            response.await
+        }
+    }
+}
+
+/// Envoltorio del socket que avisa cuando la primera respuesta del destino es
+/// un `100 Continue`; hyper consume esas respuestas sin exponerlas.
+struct InterimWatch {
+    inner: TcpStream,
+    line: Vec<u8>,
+    on_continue: Option<oneshot::Sender<()>>,
+}
+
+impl InterimWatch {
+    fn scan(&mut self, bytes: &[u8]) {
+        let end = bytes.iter().position(|b| *b == b'\n');
+        let take = end.map_or(bytes.len(), |i| i + 1);
+        self.line.extend_from_slice(&bytes[..take]);
+        if end.is_none() && self.line.len() < MAX_STATUS_LINE {
+            return;
+        }
+        let line = String::from_utf8_lossy(&self.line);
+        let status = line.split_whitespace().nth(1);
+        if let (Some("100"), Some(tx)) = (status, self.on_continue.take()) {
+            let _ = tx.send(());
+        }
+        self.on_continue = None;
+        self.line = Vec::new();
+    }
+}
+
+impl AsyncRead for InterimWatch {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), true) = (&poll, self.on_continue.is_some()) {
+            let filled = buf.filled()[before..].to_vec();
+            self.scan(&filled);
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for InterimWatch {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
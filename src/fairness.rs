@@ -0,0 +1,399 @@
+//! Reparto justo del ancho de banda entre clientes.
+//!
+//! Con `[bandwidth]`, cada trozo de body de respuesta y de túnel pide turno a
+//! un [`FairScheduler`] compartido antes de enviarse. El planificador genera
+//! el presupuesto global cada `TICK` y lo reparte con deficit round-robin:
+//! cada cliente activo suma `quantum × peso` por ronda y solo envía lo que su
+//! déficit cubre, repartido entre todas sus conexiones. Un cliente interactivo espera como mucho una ronda aunque
+//! otro esté descargando, y la descarga se lleva la capacidad que los demás
+//! no usan. Cada flujo retiene a lo sumo un trozo de `quantum` bytes, así que
+//! la memoria en espera crece con las conexiones, no con lo pendiente.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Response};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::settings::BandwidthSettings;
+
+const TICK: Duration = Duration::from_millis(10);
+/// Presupuesto que se acumula sin clientes esperando, en ticks.
+const BURST_TICKS: i64 = 5;
+/// Peso de cada tick en la media del caudal por cliente.
+const RATE_SMOOTHING: f64 = 0.1;
+
+struct ClientQueue {
+    weight: u32,
+    /// Bytes que el cliente puede enviar sin esperar a la ronda.
+    deficit: u64,
+    /// Está en la ronda.
+    active: bool,
+    waiters: VecDeque<(u64, oneshot::Sender<()>)>,
+    /// Bytes enviados desde el último tick.
+    granted: u64,
+    /// Media móvil de bytes por tick.
+    rate: f64,
+}
+
+impl ClientQueue {
+    /// Despacha los trozos en espera que su déficit cubre.
+    fn grant(&mut self) {
+        while let Some(&(bytes, _)) = self.waiters.front() {
+            if bytes > self.deficit {
+                break;
+            }
+            let (_, waiter) = self.waiters.pop_front().expect("trozo en espera");
+            // A flow that went away keeps nothing of the deficit.
+            if waiter.send(()).is_ok() {
+                self.deficit -= bytes;
+                self.granted += bytes;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    clients: HashMap<Arc<str>, ClientQueue>,
+    /// Clientes que enviaron o esperan desde el último tick, en orden de ronda.
+    active: VecDeque<Arc<str>>,
+    /// Negativo mientras se devuelve el último déficit repartido.
+    budget: i64,
+}
+/// Reparto observado de un cliente.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientShare {
+    pub client: String,
+    pub weight: u32,
+    /// Fracción del caudal total reciente (0-1).
+    pub share: f64,
+    pub bytes_per_sec: u64,
+    pub queued_chunks: usize,
+    pub queued_bytes: u64,
+}
+
+pub struct FairScheduler {
+    settings: BandwidthSettings,
+    state: Mutex<State>,
+}
+
+impl FairScheduler {
+    pub fn new(settings: BandwidthSettings) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn settings(&self) -> &BandwidthSettings {
+        &self.settings
+    }
+
+    pub fn pacer(self: &Arc<Self>, client: impl Into<Arc<str>>, weight: u32) -> Pacer {
+        Pacer {
+            scheduler: self.clone(),
+            client: client.into(),
+            weight: weight.max(1),
+        }
+    }
+
+    /// Genera el presupuesto y atiende las colas. Sin esta tarea ningún trozo
+    /// sale, así que se lanza junto con el servidor.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(TICK);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last = Instant::now();
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            self.tick(now - last);
+            last = now;
+        }
+    }
+
+    fn tick(&self, elapsed: Duration) {
+        let rate = self.settings.bytes_per_sec() as i64;
+        let produced = (rate as f64 * elapsed.as_secs_f64()) as i64;
+        let burst = (rate * TICK.as_millis() as i64 * BURST_TICKS / 1000)
+            .max(self.settings.quantum() as i64);
+        let mut state = self.state.lock().expect("planificador de ancho de banda");
+        let state = &mut *state;
+        state.budget = (state.budget + produced).min(burst);
+        // Clients that neither sent nor waited since the last tick leave the
+        // round, and their unspent deficit with them.
+        let clients = &mut state.clients;
+        state.active.retain(|client| {
+            let queue = clients.get_mut(client).expect("cliente activo");
+            queue.active = !queue.waiters.is_empty() || queue.granted > 0;
+            if !queue.active {
+                queue.deficit = 0;
+            }
+            queue.active
+        });
+        self.serve(state);
+        state.clients.retain(|_, queue| {
+            queue.rate += (queue.granted as f64 - queue.rate) * RATE_SMOOTHING;
+            queue.granted = 0;
+            queue.active || queue.rate >= 1.0
+        });
+    }
+
+    /// Una ronda de deficit round-robin: cada cliente con trozos en espera
+    /// recibe `quantum × peso` del presupuesto. El déficit sobrante se gasta
+    /// en [`Pacer::acquire`] sin esperar, así que un único flujo que pide un
+    /// trozo tras otro también aprovecha su peso.
+    fn serve(&self, state: &mut State) {
+        let quantum = self.settings.quantum() as u64;
+        let mut idle = 0;
+        while idle < state.active.len() && state.budget > 0 {
+            let client = state.active.front().cloned().expect("ronda no vacía");
+            let queue = state.clients.get_mut(&client).expect("cliente activo");
+            state.active.rotate_left(1);
+            queue.grant();
+            if queue.waiters.is_empty() {
+                idle += 1;
+                continue;
+            }
+            idle = 0;
+            let credit = quantum * u64::from(queue.weight);
+            queue.deficit += credit;
+            // The whole quantum is handed out even past the budget, so weights
+            // hold with slow links; the debt delays the next round.
+            state.budget -= credit as i64;
+            queue.grant();
+        }
+    }
+
+    /// Caudal reciente y cola de cada cliente, del que más recibe al que menos.
+    pub fn snapshot(&self) -> Vec<ClientShare> {
+        let state = self.state.lock().expect("planificador de ancho de banda");
+        let total: f64 = state.clients.values().map(|queue| queue.rate).sum();
+        let ticks_per_sec = 1000 / TICK.as_millis() as u64;
+        let mut shares: Vec<ClientShare> = state
+            .clients
+            .iter()
+            .map(|(client, queue)| ClientShare {
+                client: client.to_string(),
+                weight: queue.weight,
+                share: if total > 0.0 { queue.rate / total } else { 0.0 },
+                bytes_per_sec: queue.rate as u64 * ticks_per_sec,
+                queued_chunks: queue.waiters.len(),
+                queued_bytes: queue.waiters.iter().map(|(bytes, _)| bytes).sum(),
+            })
+            .collect();
+        shares.sort_by(|a, b| b.share.total_cmp(&a.share));
+        shares
+    }
+}
+
+/// Turno de envío de un cliente en el [`FairScheduler`].
+#[derive(Clone)]
+pub struct Pacer {
+    scheduler: Arc<FairScheduler>,
+    client: Arc<str>,
+    weight: u32,
+}
+
+impl Pacer {
+    /// Mayor trozo que se pide de una vez.
+    pub fn quantum(&self) -> usize {
+        self.scheduler.settings.quantum()
+    }
+
+    /// Espera el turno para enviar `bytes` (como mucho [`Pacer::quantum`]).
+    pub async fn acquire(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let waiting = {
+            let mut state = self
+                .scheduler
+                .state
+                .lock()
+                .expect("planificador de ancho de banda");
+            let state = &mut *state;
+            let queue = state
+                .clients
+                .entry(self.client.clone())
+                .or_insert_with(|| ClientQueue {
+                    weight: self.weight,
+                    deficit: 0,
+                    active: false,
+                    waiters: VecDeque::new(),
+                    granted: 0,
+                    rate: 0.0,
+                });
+            if !queue.active {
+                queue.active = true;
+                state.active.push_back(self.client.clone());
+            }
+            let bytes = bytes as u64;
+            if queue.waiters.is_empty() && bytes <= queue.deficit {
+                queue.deficit -= bytes;
+                queue.granted += bytes;
+                return;
+            }
+            let (tx, rx) = oneshot::channel();
+            queue.waiters.push_back((bytes, tx));
+            rx
+        };
+        let _ = waiting.await;
+    }
+}
+
+/// Envía el body de `response` al ritmo que asigne `pacer`.
+pub fn pace_response(response: Response<Body>, pacer: Pacer) -> Response<Body> {
+    let (parts, body) = response.into_parts();
+    let chunks = futures_util::stream::unfold(
+        (body, Bytes::new(), pacer),
+        |(mut body, mut rest, pacer)| async move {
+            if rest.is_empty() {
+                match body.data().await? {
+                    Ok(chunk) => rest = chunk,
+                    Err(e) => return Some((Err(e), (body, rest, pacer))),
+                }
+            }
+            let piece = rest.split_to(rest.len().min(pacer.quantum()));
+            pacer.acquire(piece.len()).await;
+            Some((Ok(piece), (body, rest, pacer)))
+        },
+    );
+    Response::from_parts(parts, Body::wrap_stream(chunks))
+}
+
+/// Como [`tokio::io::copy_bidirectional`], pidiendo turno para cada lectura.
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B, pacer: &Pacer) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+    tokio::try_join!(
+        pipe(&mut a_read, &mut b_write, pacer),
+        pipe(&mut b_read, &mut a_write, pacer)
+    )
+}
+
+async fn pipe<R, W>(reader: &mut R, writer: &mut W, pacer: &Pacer) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; pacer.quantum()];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+        pacer.acquire(n).await;
+        writer.write_all(&buf[..n]).await?;
+        total += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const RATE: u64 = 1_000_000;
+
+    /// Lanza `flows` transferencias sin fin de `client` que cuentan lo enviado.
+    fn bulk(
+        scheduler: &Arc<FairScheduler>,
+        client: &str,
+        weight: u32,
+        flows: usize,
+    ) -> Arc<AtomicU64> {
+        let sent = Arc::new(AtomicU64::new(0));
+        for _ in 0..flows {
+            let pacer = scheduler.pacer(client, weight);
+            let sent = sent.clone();
+            tokio::spawn(async move {
+                loop {
+                    pacer.acquire(pacer.quantum()).await;
+                    sent.fetch_add(pacer.quantum() as u64, Ordering::Relaxed);
+                }
+            });
+        }
+        sent
+    }
+
+    fn start(settings: BandwidthSettings) -> Arc<FairScheduler> {
+        let scheduler = Arc::new(FairScheduler::new(settings));
+        tokio::spawn(scheduler.clone().run());
+        scheduler
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_split_follows_clients_and_weights() {
+        let scheduler = start(BandwidthSettings::new(RATE));
+        // Four parallel downloads do not buy a bigger share than one.
+        let bulk_sent = bulk(&scheduler, "10.0.0.1", 1, 4);
+        let single_sent = bulk(&scheduler, "10.0.0.2", 1, 1);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let (bulk_sent, single_sent) = (
+            bulk_sent.load(Ordering::Relaxed),
+            single_sent.load(Ordering::Relaxed),
+        );
+        let total = bulk_sent + single_sent;
+        assert!((1_800_000..=2_200_000).contains(&total), "{total}");
+        let share = single_sent as f64 / total as f64;
+        assert!((0.45..0.55).contains(&share), "{share}");
+
+        let shares = scheduler.snapshot();
+        assert_eq!(shares.len(), 2);
+        assert!(
+            shares.iter().all(|s| (0.4..0.6).contains(&s.share)),
+            "{shares:?}"
+        );
+        assert_eq!(
+            shares
+                .iter()
+                .find(|s| s.client == "10.0.0.1")
+                .unwrap()
+                .queued_chunks,
+            4
+        );
+
+        let weighted = start(BandwidthSettings::new(RATE));
+        // A single flow also gets the share its weight buys.
+        let heavy = bulk(&weighted, "vip", 3, 1);
+        let light = bulk(&weighted, "kiosco", 1, 1);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let share = heavy.load(Ordering::Relaxed) as f64
+            / (heavy.load(Ordering::Relaxed) + light.load(Ordering::Relaxed)) as f64;
+        assert!((0.7..0.8).contains(&share), "{share}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_newcomer_gets_its_share_quickly() {
+        let scheduler = start(BandwidthSettings::new(RATE));
+        let bulk_sent = bulk(&scheduler, "10.0.0.1", 1, 8);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // An interactive request waits at most about one round.
+        let pacer = scheduler.pacer("10.0.0.2", 1);
+        let started = Instant::now();
+        pacer.acquire(1024).await;
+        assert!(started.elapsed() <= TICK * 3, "{:?}", started.elapsed());
+
+        let before = bulk_sent.load(Ordering::Relaxed);
+        let newcomer = bulk(&scheduler, "10.0.0.2", 1, 1);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let bulk_sent = bulk_sent.load(Ordering::Relaxed) - before;
+        let share = newcomer.load(Ordering::Relaxed) as f64
+            / (newcomer.load(Ordering::Relaxed) + bulk_sent) as f64;
+        assert!((0.4..0.6).contains(&share), "{share}");
+    }
+}
@@ -0,0 +1,78 @@
+//! Destinos de confianza que se reenvían sin inspección.
+//!
+//! Para los hosts de `fast_path` la decisión se toma una vez, al entrar la
+//! petición, y desde ahí se va directo de la limpieza de cabeceras al
+//! reenvío o al túnel: no se evalúan reputación ni scripts, no se capturan
+//! ni archivan intercambios, no se aplican presupuestos LLM ni
+//! `Idempotency-Key`, no se inyectan credenciales ni firmas y no se toca el
+//! body. Se mantienen el control de acceso (baneos, lista de salida y
+//! perfiles), el enrutado hacia proxies padre, las estadísticas y el conteo
+//! de bytes. La lista se relee sin reiniciar.
+
+use std::sync::{Arc, RwLock};
+
+use tracing::info;
+
+use crate::host_pattern::HostPattern;
+use crate::policy_export::{HitCount, RuleHits};
+
+#[derive(Debug, Default)]
+pub struct FastPath {
+    hosts: RwLock<Arc<[(HostPattern, RuleHits)]>>,
+}
+
+impl FastPath {
+    pub fn new(hosts: Vec<HostPattern>) -> Self {
+        let hosts: Vec<_> = hosts
+            .into_iter()
+            .map(|host| (host, RuleHits::default()))
+            .collect();
+        Self {
+            hosts: RwLock::new(hosts.into()),
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        self.find(host, true).is_some()
+    }
+
+    /// Patrón que cubre `host`; con `record` cuenta la aplicación.
+    pub fn find(&self, host: &str, record: bool) -> Option<HostPattern> {
+        let hosts = self.hosts.read().expect("lock de la vía rápida");
+        let (pattern, hits) = hosts.iter().find(|(pattern, _)| pattern.matches(host))?;
+        if record {
+            hits.record();
+        }
+        Some(pattern.clone())
+    }
+
+    /// Hosts en vigor con sus aplicaciones.
+    pub fn hosts(&self) -> Vec<(HostPattern, HitCount)> {
+        self.hosts
+            .read()
+            .expect("lock de la vía rápida")
+            .iter()
+            .map(|(pattern, hits)| (pattern.clone(), hits.get()))
+            .collect()
+    }
+
+    /// Reemplaza la lista; los hosts que siguen en ella conservan su cuenta.
+    pub fn reload(&self, hosts: Vec<HostPattern>) {
+        let mut current = self.hosts.write().expect("lock de la vía rápida");
+        if !current.iter().map(|(pattern, _)| pattern).eq(hosts.iter()) {
+            info!(hosts = hosts.len(), "Vía rápida actualizada");
+            let hosts: Vec<_> = hosts
+                .into_iter()
+                .map(|host| {
+                    let count = current
+                        .iter()
+                        .find(|(pattern, _)| *pattern == host)
+                        .map(|(_, hits)| hits.get())
+                        .unwrap_or_default();
+                    (host, RuleHits::starting_at(count))
+                })
+                .collect();
+            *current = hosts.into();
+        }
+    }
+}
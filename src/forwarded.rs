@@ -0,0 +1,107 @@
+//! Cabeceras de intermediario: `Via`, `X-Forwarded-For` y
+//! `X-Forwarded-Proto`.
+//!
+//! Cada petición HTTP reenviada suma la IP del cliente a `X-Forwarded-For`
+//! (detrás de las que ya traiga), indica en `X-Forwarded-Proto` cómo llegó
+//! el cliente al proxy y añade una entrada `Via` propia, también en la
+//! respuesta que vuelve; las entradas `Via` anteriores se conservan, así que
+//! una cadena de proxies queda a la vista. Una petición que ya trae la
+//! entrada `Via` del proxy ha vuelto a él y se corta con
+//! [`loop_detected`]. En modo anónimo (`anonymous = true`) no se añade nada
+//! y además se quitan las que mande el cliente, para que el destino no vea
+//! de dónde viene la petición.
+
+use std::net::IpAddr;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, VIA};
+use hyper::{Body, Response, StatusCode, Version};
+
+/// Nombre con el que el proxy firma sus entradas `Via`.
+pub const PSEUDONYM: &str = "proxy-ia";
+
+/// Cuerpo del 508 de [`loop_detected`].
+pub const LOOP_MESSAGE: &str = "La petición ya pasó por este proxy: su Via lleva proxy-ia";
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// Cabeceras que delatan al cliente y se quitan en modo anónimo.
+const REVEALING: [&str; 5] = [
+    "via",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "forwarded",
+];
+
+/// Entrada `Via` para un mensaje recibido con `version`.
+fn via(version: Version) -> HeaderValue {
+    let received = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    HeaderValue::try_from(format!("{received} {PSEUDONYM}")).expect("Via en ASCII")
+}
+
+/// Prepara las cabeceras de una petición que sale hacia el destino. `tls`
+/// indica si el cliente habló con el proxy por TLS.
+pub fn forward_request(
+    headers: &mut HeaderMap,
+    version: Version,
+    client: IpAddr,
+    tls: bool,
+    anonymous: bool,
+) {
+    if anonymous {
+        for name in REVEALING {
+            headers.remove(name);
+        }
+        return;
+    }
+    // Several lines are one comma-separated list; they become a single one.
+    let mut chain: Vec<&str> = headers
+        .get_all(&X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect();
+    let client = client.to_string();
+    chain.push(&client);
+    let value = HeaderValue::try_from(chain.join(", ")).expect("X-Forwarded-For en ASCII");
+    headers.insert(X_FORWARDED_FOR, value);
+    let proto = if tls { "https" } else { "http" };
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    headers.append(VIA, via(version));
+}
+
+/// Si alguna entrada `Via` la puso el proxy, es decir, si la petición ya
+/// pasó por él.
+pub fn loops(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        // `received-protocol received-by [comment]`.
+        .any(|entry| entry.split_whitespace().nth(1) == Some(PSEUDONYM))
+}
+
+/// 508 para una petición que ha vuelto al proxy según su `Via`.
+pub fn loop_detected() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::LOOP_DETECTED)
+        .header("x-proxy-error", "via_loop")
+        .body(Body::from(LOOP_MESSAGE))
+        .expect("respuesta de bucle por Via")
+}
+
+/// Añade la entrada `Via` a una respuesta que vuelve del destino.
+pub fn forward_response(headers: &mut HeaderMap, version: Version, anonymous: bool) {
+    if !anonymous {
+        headers.append(VIA, via(version));
+    }
+}
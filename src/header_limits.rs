@@ -0,0 +1,222 @@
+//! Límites de tamaño y número de cabeceras.
+//!
+//! Un cliente que manda megas de cookies ocupa memoria en el proxy y el
+//! destino lo acaba rechazando con errores confusos. Con `[header_limits]`
+//! el proxy comprueba tres límites en las cabeceras de cada petición: el
+//! tamaño de una cabecera, el de todas juntas y cuántas hay. Una petición
+//! que pasa alguno recibe un 431 cuyo body dice cuál; una respuesta del
+//! destino que los pasa se cambia por un 502 `upstream_headers_too_large`.
+//! El buffer de lectura de hyper se dimensiona a partir de `max_total_bytes`
+//! con margen para la línea de la petición, así que las cabeceras que pasan
+//! el límite por poco llegan enteras a la comprobación; las que además
+//! desbordan el buffer las corta hyper con un 431 sin body.
+
+use std::fmt;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+
+use crate::error::ProxyError;
+use crate::settings::HeaderLimits;
+
+/// Margen del buffer para la línea de la petición o de estado.
+const START_LINE_SLACK: usize = 16 * 1024;
+
+/// Lo mínimo que hyper admite como buffer de lectura.
+const MIN_BUFFER: usize = 8 * 1024;
+
+/// Lo que HTTP/2 suma a cada cabecera al medir la lista (RFC 9113 §6.5.2).
+const H2_ENTRY_OVERHEAD: usize = 32;
+
+/// Qué límite se superó.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HeaderLimit {
+    HeaderBytes,
+    TotalBytes,
+    Count,
+}
+
+impl HeaderLimit {
+    pub const ALL: [HeaderLimit; 3] = [
+        HeaderLimit::HeaderBytes,
+        HeaderLimit::TotalBytes,
+        HeaderLimit::Count,
+    ];
+
+    /// Nombre de la clave de configuración.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeaderLimit::HeaderBytes => "max_header_bytes",
+            HeaderLimit::TotalBytes => "max_total_bytes",
+            HeaderLimit::Count => "max_count",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        HeaderLimit::ALL
+            .iter()
+            .position(|limit| limit == self)
+            .expect("variante registrada en ALL")
+    }
+}
+
+/// Un límite superado, con el valor que lo superó.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exceeded {
+    pub limit: HeaderLimit,
+    pub actual: usize,
+    pub max: usize,
+    /// La cabecera demasiado grande, con `max_header_bytes`.
+    pub header: Option<HeaderName>,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (actual, max, limit) = (self.actual, self.max, self.limit.as_str());
+        match (&self.header, self.limit) {
+            (Some(name), _) => write!(
+                f,
+                "la cabecera {name} ocupa {actual} bytes ({limit} = {max})"
+            ),
+            (None, HeaderLimit::Count) => write!(f, "hay {actual} cabeceras ({limit} = {max})"),
+            (None, _) => write!(f, "las cabeceras ocupan {actual} bytes ({limit} = {max})"),
+        }
+    }
+}
+
+/// Bytes de una cabecera en HTTP/1: nombre, `: `, valor y fin de línea.
+fn wire_size(name: &HeaderName, value: &HeaderValue) -> usize {
+    name.as_str().len() + value.len() + 4
+}
+
+/// Primer límite que superan `headers`: primero el número, luego cada
+/// cabecera y por último el total.
+pub fn check(limits: &HeaderLimits, headers: &HeaderMap) -> Result<(), Exceeded> {
+    if headers.len() > limits.max_count() {
+        return Err(Exceeded {
+            limit: HeaderLimit::Count,
+            actual: headers.len(),
+            max: limits.max_count(),
+            header: None,
+        });
+    }
+    let mut total = 0;
+    for (name, value) in headers {
+        let size = wire_size(name, value);
+        if size > limits.max_header_bytes() {
+            return Err(Exceeded {
+                limit: HeaderLimit::HeaderBytes,
+                actual: size,
+                max: limits.max_header_bytes(),
+                header: Some(name.clone()),
+            });
+        }
+        total += size;
+    }
+    if total > limits.max_total_bytes() {
+        return Err(Exceeded {
+            limit: HeaderLimit::TotalBytes,
+            actual: total,
+            max: limits.max_total_bytes(),
+            header: None,
+        });
+    }
+    Ok(())
+}
+
+/// Buffer de lectura de HTTP/1 para hyper, en el servidor y en el cliente.
+pub fn buffer_size(limits: &HeaderLimits) -> usize {
+    (limits.max_total_bytes() + START_LINE_SLACK).max(MIN_BUFFER)
+}
+
+/// `SETTINGS_MAX_HEADER_LIST_SIZE` que se anuncia a los clientes HTTP/2.
+pub fn h2_list_size(limits: &HeaderLimits) -> u32 {
+    let size = buffer_size(limits) + H2_ENTRY_OVERHEAD * limits.max_count();
+    u32::try_from(size).unwrap_or(u32::MAX)
+}
+
+fn with_reason(mut response: Response<Body>, reason: String) -> Response<Body> {
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    *response.body_mut() = Body::from(reason);
+    response
+}
+
+/// 431 para una petición que superó `exceeded`.
+pub fn request_too_large(exceeded: &Exceeded) -> Response<Body> {
+    let response = Response::builder()
+        .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        .header("x-proxy-error", "request_headers_too_large")
+        .body(Body::empty())
+        .expect("respuesta 431");
+    with_reason(
+        response,
+        format!("Cabeceras de la petición demasiado grandes: {exceeded}"),
+    )
+}
+
+/// 502 en lugar de una respuesta del destino que superó `exceeded`.
+pub fn response_too_large(exceeded: &Exceeded) -> Response<Body> {
+    with_reason(
+        ProxyError::HeadersTooLarge.into_response(),
+        format!("{}: {exceeded}", ProxyError::HeadersTooLarge),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_check_names_the_limit_just_over_each_one() {
+        // "cookie: " plus CRLF is 10 bytes.
+        let limits = HeaderLimits::default()
+            .with_max_header_bytes(110)
+            .with_max_total_bytes(200)
+            .with_max_count(3);
+        let cookie = |len: usize| ("cookie", "c".repeat(len));
+
+        assert_eq!(check(&limits, &headers(&[cookie(100)])), Ok(()));
+        let over = check(&limits, &headers(&[cookie(101)])).unwrap_err();
+        assert_eq!(over.limit, HeaderLimit::HeaderBytes);
+        assert_eq!((over.actual, over.max), (111, 110));
+        assert_eq!(
+            over.to_string(),
+            "la cabecera cookie ocupa 111 bytes (max_header_bytes = 110)"
+        );
+
+        // "x-a: " plus CRLF is 7 bytes.
+        let pair = |a: usize, b: usize| headers(&[cookie(a), ("x-a", "a".repeat(b))]);
+        assert_eq!(check(&limits, &pair(100, 83)), Ok(()));
+        let over = check(&limits, &pair(100, 84)).unwrap_err();
+        assert_eq!(over.limit, HeaderLimit::TotalBytes);
+        assert_eq!((over.actual, over.header), (201, None));
+
+        let many = |n: usize| {
+            let names = ["x-a", "x-b", "x-c", "x-d"];
+            headers(
+                &names[..n]
+                    .iter()
+                    .map(|name| (*name, "1".to_string()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(check(&limits, &many(3)), Ok(()));
+        let over = check(&limits, &many(4)).unwrap_err();
+        assert_eq!(over.to_string(), "hay 4 cabeceras (max_count = 3)");
+    }
+}
@@ -0,0 +1,90 @@
+//! Reescritura de cabeceras configurable.
+//!
+//! `[header_rules]` quita, fija o añade cabeceras en las peticiones HTTP que
+//! salen hacia el destino y en las respuestas que vuelven al cliente, para
+//! todos los destinos o solo para los que casan con un patrón de host. Las
+//! reglas se aplican en el orden de la configuración: las de petición después
+//! de quitar las hop-by-hop y de añadir `Via`/`X-Forwarded-*`, y antes de las
+//! credenciales y la firma; las de respuesta en cuanto llega la cabecera del
+//! destino, antes de páginas de error, caché y demás. No se aplican a los
+//! túneles `CONNECT` ni a las respuestas que genera el propio proxy.
+
+use hyper::HeaderMap;
+use tracing::debug;
+
+use crate::settings::{HeaderDirection, HeaderOp, HeaderRule};
+
+pub struct HeaderRewriter {
+    rules: Vec<HeaderRule>,
+}
+
+impl HeaderRewriter {
+    pub fn new(rules: Vec<HeaderRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn rules(&self) -> &[HeaderRule] {
+        &self.rules
+    }
+
+    /// Aplica a `headers` las reglas de `direction` que casan con `host`.
+    pub fn apply(&self, direction: HeaderDirection, host: &str, headers: &mut HeaderMap) {
+        let rules = self.rules.iter().filter(|rule| {
+            rule.direction() == direction && rule.host().is_none_or(|pattern| pattern.matches(host))
+        });
+        for rule in rules {
+            match rule.op() {
+                HeaderOp::Remove => {
+                    headers.remove(rule.name());
+                }
+                HeaderOp::Set(value) => {
+                    headers.insert(rule.name().clone(), value.clone());
+                }
+                HeaderOp::Append(value) => {
+                    headers.append(rule.name().clone(), value.clone());
+                }
+            }
+            debug!(%host, direction = direction.as_str(), header = %rule.name(), "Cabecera reescrita");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_apply_in_order_per_direction_and_host() {
+        use HeaderDirection::{Request, Response};
+
+        let rewriter = HeaderRewriter::new(vec![
+            HeaderRule::remove(Request, "User-Agent").unwrap(),
+            HeaderRule::set(Request, "x-env", "prod").unwrap(),
+            HeaderRule::append(Request, "X-Env", "eu")
+                .unwrap()
+                .with_host("*.internal.test".parse().unwrap()),
+            HeaderRule::remove(Request, "x-env")
+                .unwrap()
+                .with_host("legacy.test".parse().unwrap()),
+            HeaderRule::set(Response, "x-backend", "interno").unwrap(),
+        ]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("user-agent", "curl/8".parse().unwrap());
+        headers.insert("x-env", "dev".parse().unwrap());
+        rewriter.apply(Request, "api.internal.test", &mut headers);
+        assert!(!headers.contains_key("user-agent"));
+        let values: Vec<_> = headers.get_all("x-env").iter().collect();
+        assert_eq!(values, ["prod", "eu"]);
+        assert!(!headers.contains_key("x-backend"));
+
+        let mut headers = HeaderMap::new();
+        rewriter.apply(Request, "legacy.test", &mut headers);
+        assert!(headers.is_empty());
+
+        // Names and values are checked when the rule is built.
+        assert!(HeaderRule::remove(Request, "mal nombre").is_err());
+        assert!(HeaderRule::set(Request, "x-ok", "línea\r\nrota").is_err());
+        assert!(HeaderRule::set(Response, "Transfer-Encoding", "chunked").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Listas de destinos permitidos y bloqueados.
+//!
+//! `[host_filter]` acota con qué destinos habla el proxy, tanto en las
+//! peticiones HTTP como en los `CONNECT`, antes de abrir ninguna conexión al
+//! destino. Cada regla es un host exacto, un sufijo `*.example.com` o un
+//! rango CIDR que casa con las IPs literales. Una regla `deny` gana siempre;
+//! si hay reglas `allow`, solo pasan los destinos que casan con alguna. Sin
+//! reglas todo pasa, como sin la sección. Se evalúa después de los mapas de
+//! redirecciones, así que un destino reescrito se comprueba como cualquier
+//! otro, y antes de las reglas de salida y del script.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use hyper::{Body, Response, StatusCode};
+
+use crate::cidr::Cidr;
+use crate::host_pattern::HostPattern;
+use crate::settings::HostFilterSettings;
+
+/// Una regla de `allow` o `deny`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostRule {
+    Host(HostPattern),
+    /// Solo casa con IPs literales, nunca con nombres.
+    Range(Cidr),
+}
+
+impl HostRule {
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostRule::Host(pattern) => pattern.matches(host),
+            HostRule::Range(range) => host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| range.contains(ip)),
+        }
+    }
+}
+
+impl FromStr for HostRule {
+    type Err = anyhow::Error;
+
+    /// Lo que empieza como una IP es un rango; lo demás, un patrón de host.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = s.trim().split('/').next().unwrap_or_default();
+        if addr.parse::<IpAddr>().is_ok() {
+            return Ok(HostRule::Range(s.parse()?));
+        }
+        Ok(HostRule::Host(s.parse()?))
+    }
+}
+
+impl fmt::Display for HostRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostRule::Host(pattern) => write!(f, "{pattern}"),
+            HostRule::Range(range) => write!(f, "{range}"),
+        }
+    }
+}
+
+/// Por qué se decidió así un destino.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict<'a> {
+    /// Lo permite esta regla `allow`, o `None` si no hay reglas `allow`.
+    Allowed(Option<&'a HostRule>),
+    Denied(&'a HostRule),
+    /// Hay reglas `allow` y ninguna casa.
+    NotAllowed,
+}
+
+impl Verdict<'_> {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Verdict::Allowed(_))
+    }
+
+    /// La regla que decidió, si la hay.
+    pub fn rule(&self) -> Option<&HostRule> {
+        match self {
+            Verdict::Allowed(rule) => *rule,
+            Verdict::Denied(rule) => Some(rule),
+            Verdict::NotAllowed => None,
+        }
+    }
+}
+
+pub struct HostFilter {
+    settings: HostFilterSettings,
+}
+
+impl HostFilter {
+    pub fn new(settings: HostFilterSettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn settings(&self) -> &HostFilterSettings {
+        &self.settings
+    }
+
+    pub fn check(&self, host: &str) -> Verdict<'_> {
+        if let Some(rule) = self.settings.deny().iter().find(|rule| rule.matches(host)) {
+            return Verdict::Denied(rule);
+        }
+        if self.settings.allow().is_empty() {
+            return Verdict::Allowed(None);
+        }
+        match self.settings.allow().iter().find(|rule| rule.matches(host)) {
+            Some(rule) => Verdict::Allowed(Some(rule)),
+            None => Verdict::NotAllowed,
+        }
+    }
+
+    /// 403 para un destino que `verdict` no deja pasar.
+    pub fn blocked(&self, host: &str, verdict: Verdict<'_>) -> Response<Body> {
+        let reason = match verdict.rule() {
+            Some(rule) => format!("El destino {host} está bloqueado por la regla `{rule}`.\n"),
+            None => format!("El destino {host} no está en la lista de destinos permitidos.\n"),
+        };
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header("x-proxy-host-filter", "blocked")
+            .body(Body::from(reason))
+            .expect("respuesta de destino bloqueado")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(patterns: &[&str]) -> Vec<HostRule> {
+        patterns.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_deny_wins_and_ranges_match_only_ip_literals() {
+        let mut settings = HostFilterSettings::default();
+        for rule in rules(&["*.internal.corp", "10.0.0.0/8", "2001:db8::/32"]) {
+            settings = settings.with_allow(rule);
+        }
+        for rule in rules(&["secret.internal.corp", "10.1.0.0/16"]) {
+            settings = settings.with_deny(rule);
+        }
+        let filter = HostFilter::new(settings);
+
+        assert_eq!(
+            filter
+                .check("wiki.internal.corp")
+                .rule()
+                .map(|r| r.to_string()),
+            Some("*.internal.corp".to_string())
+        );
+        assert!(filter.check("10.2.3.4").is_allowed());
+        assert!(filter.check("[2001:db8::1]").is_allowed());
+        let denied = filter.check("SECRET.internal.corp");
+        assert!(matches!(denied, Verdict::Denied(_)));
+        assert!(matches!(filter.check("10.1.2.3"), Verdict::Denied(_)));
+        assert_eq!(filter.check("example.com"), Verdict::NotAllowed);
+        // A name inside the range is not an IP literal.
+        assert_eq!(filter.check("10.0.0.1.nip.io"), Verdict::NotAllowed);
+
+        let open = HostFilter::new(HostFilterSettings::default());
+        assert_eq!(open.check("example.com"), Verdict::Allowed(None));
+        assert!("10.0.0.0/40".parse::<HostRule>().is_err());
+    }
+}
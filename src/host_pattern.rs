@@ -0,0 +1,79 @@
+//! Patrones de host usados por la configuración: `api.example.com` coincide
+//! solo con ese host, `*.example.com` con cualquier subdominio y `*` con todo.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Any,
+    Exact(String),
+    /// Sufijo con el punto inicial incluido, p. ej. `.example.com`.
+    Subdomains(String),
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Exact(exact) => host == *exact,
+            HostPattern::Subdomains(suffix) => host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim().trim_end_matches('.').to_ascii_lowercase();
+        if pattern == "*" {
+            return Ok(HostPattern::Any);
+        }
+        if let Some(suffix) = pattern.strip_prefix("*.") {
+            if !suffix.is_empty() && !suffix.contains('*') {
+                return Ok(HostPattern::Subdomains(format!(".{suffix}")));
+            }
+        }
+        if pattern.is_empty() || pattern.contains('*') {
+            anyhow::bail!("patrón de host inválido: `{s}`");
+        }
+        Ok(HostPattern::Exact(pattern))
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Any => write!(f, "*"),
+            HostPattern::Exact(host) => write!(f, "{host}"),
+            HostPattern::Subdomains(suffix) => write!(f, "*{suffix}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        let exact: HostPattern = "API.example.com".parse().unwrap();
+        assert!(exact.matches("api.example.com"));
+        assert!(!exact.matches("x.api.example.com"));
+
+        let wildcard: HostPattern = "*.example.com".parse().unwrap();
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+        assert_eq!(wildcard.to_string(), "*.example.com");
+
+        assert!("*".parse::<HostPattern>().unwrap().matches("cualquiera"));
+        assert!("a*.com".parse::<HostPattern>().is_err());
+    }
+}
@@ -0,0 +1,404 @@
+//! Reintentos con `Idempotency-Key` en las rutas configuradas.
+//!
+//! Los clientes móviles con mala red repiten los `POST` tras un timeout. En
+//! las rutas de `[idempotency]`, la primera petición con una clave se reenvía
+//! y su respuesta se guarda (hasta `max_body_bytes`, durante `ttl_secs`); las
+//! repeticiones reciben esa respuesta con `Idempotent-Replayed: true` sin
+//! llegar al destino. La clave se asocia a la ruta y a un hash del método, la
+//! URI y el body: repetirla con otro contenido responde 422. Una repetición
+//! que llega mientras la original sigue en curso espera su respuesta.
+//!
+//! No se guardan las respuestas que generó el propio proxy (`x-proxy-error`)
+//! ni las que superan el límite: en esos casos la siguiente repetición se
+//! reenvía. Las respuestas viven en memoria y se pierden al reiniciar.
+
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hyper::body::Bytes;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use lru::LruCache;
+use ring::digest;
+use tokio::sync::watch;
+use tracing::{debug, info};
+
+use crate::capture::buffer_prefix;
+use crate::metrics::{CacheKind, Metrics};
+use crate::settings::IdempotencySettings;
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+pub const REPLAYED: &str = "idempotent-replayed";
+
+/// Ruta (índice en la configuración) y clave.
+type Key = (usize, String);
+
+/// Respuesta guardada para una clave.
+struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Stored {
+    fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+            .headers_mut()
+            .insert(REPLAYED, hyper::header::HeaderValue::from_static("true"));
+        response
+    }
+}
+
+enum Entry {
+    /// La original sigue en curso; el canal se cierra si termina sin
+    /// respuesta que guardar.
+    InFlight {
+        fingerprint: Vec<u8>,
+        done: watch::Receiver<()>,
+    },
+    Done {
+        fingerprint: Vec<u8>,
+        stored: Arc<Stored>,
+        expires: Instant,
+    },
+}
+
+enum Begin {
+    Replay(Arc<Stored>),
+    Mismatch,
+    Wait(watch::Receiver<()>),
+    Lead(watch::Sender<()>),
+}
+
+pub struct IdempotencyGuard {
+    settings: IdempotencySettings,
+    entries: Mutex<LruCache<Key, Entry>>,
+}
+
+impl IdempotencyGuard {
+    pub fn new(settings: IdempotencySettings) -> Self {
+        let size = NonZeroUsize::new(settings.max_entries()).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            settings,
+            entries: Mutex::new(LruCache::new(size)),
+        }
+    }
+
+    /// Reenvía la petición con `forward` salvo que sea la repetición de una
+    /// clave ya respondida.
+    pub async fn run<F, Fut>(
+        &self,
+        req: Request<Body>,
+        metrics: &Metrics,
+        forward: F,
+    ) -> Result<Response<Body>, hyper::Error>
+    where
+        F: FnOnce(Request<Body>) -> Fut,
+        Fut: Future<Output = Result<Response<Body>, hyper::Error>>,
+    {
+        let host = req.uri().host().unwrap_or_default();
+        let route = self
+            .settings
+            .routes()
+            .iter()
+            .position(|route| route.matches(host, req.uri().path()));
+        let key = req
+            .headers()
+            .get(IDEMPOTENCY_KEY)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|key| !key.is_empty());
+        let (Some(route), Some(key)) = (route, key) else {
+            return forward(req).await;
+        };
+        let key = (route, key.to_string());
+        let host = host.to_string();
+
+        let (parts, body) = req.into_parts();
+        let (bytes, truncated, body) = buffer_prefix(body, self.settings.max_body()).await;
+        if truncated {
+            return Ok(body_too_large(self.settings.max_body()));
+        }
+        let mut hasher = digest::Context::new(&digest::SHA256);
+        hasher.update(parts.method.as_str().as_bytes());
+        hasher.update(b"\n");
+        hasher.update(parts.uri.to_string().as_bytes());
+        hasher.update(b"\n");
+        hasher.update(&bytes);
+        let fingerprint = hasher.finish().as_ref().to_vec();
+        let req = Request::from_parts(parts, body);
+
+        let done = loop {
+            match self.begin(&key, &fingerprint) {
+                Begin::Replay(stored) => {
+                    metrics.record_cache_lookup(CacheKind::Idempotency, true);
+                    info!(%host, key = %key.1, "Respuesta repetida por Idempotency-Key");
+                    return Ok(stored.response());
+                }
+                Begin::Mismatch => {
+                    info!(%host, key = %key.1, "Idempotency-Key repetida con otro contenido");
+                    return Ok(key_reused());
+                }
+                Begin::Wait(mut done) => {
+                    debug!(%host, key = %key.1, "Esperando a la petición original");
+                    // Either outcome means the entry changed: look again.
+                    let _ = done.changed().await;
+                }
+                Begin::Lead(done) => {
+                    metrics.record_cache_lookup(CacheKind::Idempotency, false);
+                    break done;
+                }
+            }
+        };
+        let lead = Lead {
+            guard: self,
+            key,
+            done,
+        };
+
+        let response = forward(req).await?;
+        if response.headers().contains_key("x-proxy-error") {
+            return Ok(response);
+        }
+        let (parts, body) = response.into_parts();
+        let (bytes, truncated, body) = buffer_prefix(body, self.settings.max_body()).await;
+        if !truncated {
+            lead.complete(
+                fingerprint,
+                Stored {
+                    status: parts.status,
+                    headers: parts.headers.clone(),
+                    body: Bytes::from(bytes),
+                },
+            );
+        }
+        Ok(Response::from_parts(parts, body))
+    }
+
+    fn begin(&self, key: &Key, fingerprint: &[u8]) -> Begin {
+        let mut entries = self.entries.lock().expect("lock de idempotencia");
+        match entries.get(key) {
+            Some(Entry::Done {
+                fingerprint: stored,
+                stored: response,
+                expires,
+            }) if *expires > Instant::now() => {
+                return if stored == fingerprint {
+                    Begin::Replay(response.clone())
+                } else {
+                    Begin::Mismatch
+                };
+            }
+            Some(Entry::InFlight {
+                fingerprint: stored,
+                done,
+            }) => {
+                return if stored == fingerprint {
+                    Begin::Wait(done.clone())
+                } else {
+                    Begin::Mismatch
+                };
+            }
+            _ => {}
+        }
+        let (sender, receiver) = watch::channel(());
+        entries.put(
+            key.clone(),
+            Entry::InFlight {
+                fingerprint: fingerprint.to_vec(),
+                done: receiver,
+            },
+        );
+        Begin::Lead(sender)
+    }
+}
+
+/// Petición original en curso. Si se suelta sin respuesta que guardar, la
+/// clave queda libre y las repeticiones en espera se reenvían.
+struct Lead<'a> {
+    guard: &'a IdempotencyGuard,
+    key: Key,
+    done: watch::Sender<()>,
+}
+
+impl Lead<'_> {
+    fn complete(self, fingerprint: Vec<u8>, stored: Stored) {
+        let expires = Instant::now() + self.guard.settings.ttl();
+        self.guard
+            .entries
+            .lock()
+            .expect("lock de idempotencia")
+            .put(
+                self.key.clone(),
+                Entry::Done {
+                    fingerprint,
+                    stored: Arc::new(stored),
+                    expires,
+                },
+            );
+        self.done.send_replace(());
+    }
+}
+
+impl Drop for Lead<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.guard.entries.lock().expect("lock de idempotencia");
+        if matches!(entries.peek(&self.key), Some(Entry::InFlight { .. })) {
+            entries.pop(&self.key);
+        }
+    }
+}
+
+fn key_reused() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNPROCESSABLE_ENTITY)
+        .header("x-proxy-error", "idempotency_key_reused")
+        .body(Body::from(
+            "La Idempotency-Key ya se usó con otra petición\n",
+        ))
+        .expect("respuesta de clave de idempotencia reutilizada")
+}
+
+fn body_too_large(limit: usize) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header("x-proxy-error", "idempotency_body_too_large")
+        .body(Body::from(format!(
+            "El body supera los {limit} bytes admitidos con Idempotency-Key en este destino\n"
+        )))
+        .expect("respuesta de body demasiado grande para idempotencia")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::settings::IdempotencyRoute;
+
+    fn guard() -> IdempotencyGuard {
+        IdempotencyGuard::new(IdempotencySettings::default().with_route(
+            IdempotencyRoute::new("shop.example".parse().unwrap()).with_path_prefix("/orders"),
+        ))
+    }
+
+    fn order(key: &str, body: &'static str) -> Request<Body> {
+        Request::post("http://shop.example/orders")
+            .header(IDEMPOTENCY_KEY, key)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn origin(
+        calls: &AtomicUsize,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let body = hyper::body::to_bytes(req.into_body()).await?;
+        let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .header("x-order", n.to_string())
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_duplicates_replay_the_stored_response() {
+        let guard = guard();
+        let metrics = Metrics::default();
+        let calls = AtomicUsize::new(0);
+
+        let first = guard
+            .run(order("k1", "2 cafés"), &metrics, |req| origin(&calls, req))
+            .await
+            .unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(!first.headers().contains_key(REPLAYED));
+        let again = guard
+            .run(order("k1", "2 cafés"), &metrics, |req| origin(&calls, req))
+            .await
+            .unwrap();
+        assert_eq!(again.status(), StatusCode::CREATED);
+        assert_eq!(again.headers()[REPLAYED], "true");
+        assert_eq!(again.headers()["x-order"], "1");
+        let body = hyper::body::to_bytes(again.into_body()).await.unwrap();
+        assert_eq!(&body[..], "2 cafés".as_bytes());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Other routes and requests without a key are left alone.
+        let other = Request::post("http://shop.example/cart")
+            .header(IDEMPOTENCY_KEY, "k1")
+            .body(Body::from("x"))
+            .unwrap();
+        guard
+            .run(other, &metrics, |req| origin(&calls, req))
+            .await
+            .unwrap();
+        let keyless = Request::post("http://shop.example/orders")
+            .body(Body::from("2 cafés"))
+            .unwrap();
+        guard
+            .run(keyless, &metrics, |req| origin(&calls, req))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_duplicates_wait_for_the_original() {
+        let guard = guard();
+        let metrics = Metrics::default();
+        let calls = AtomicUsize::new(0);
+
+        let (a, b, c) = tokio::join!(
+            guard.run(order("k2", "pedido"), &metrics, |req| origin(&calls, req)),
+            guard.run(order("k2", "pedido"), &metrics, |req| origin(&calls, req)),
+            guard.run(order("k2", "pedido"), &metrics, |req| origin(&calls, req)),
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let replayed = [a.unwrap(), b.unwrap(), c.unwrap()]
+            .iter()
+            .filter(|res| res.headers().contains_key(REPLAYED))
+            .count();
+        assert_eq!(replayed, 2);
+
+        // A failed original frees the key for the next attempt.
+        let failed = guard
+            .run(order("k3", "pedido"), &metrics, |_| async {
+                Ok(crate::error::ProxyError::Connect.into_response())
+            })
+            .await
+            .unwrap();
+        assert_eq!(failed.status(), StatusCode::BAD_GATEWAY);
+        guard
+            .run(order("k3", "pedido"), &metrics, |req| origin(&calls, req))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reused_key_with_another_body_is_rejected() {
+        let guard = guard();
+        let metrics = Metrics::default();
+        let calls = AtomicUsize::new(0);
+
+        guard
+            .run(order("k4", "1 té"), &metrics, |req| origin(&calls, req))
+            .await
+            .unwrap();
+        let res = guard
+            .run(order("k4", "5 tés"), &metrics, |req| origin(&calls, req))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(res.headers()["x-proxy-error"], "idempotency_key_reused");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
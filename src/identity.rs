@@ -0,0 +1,314 @@
+//! Identidad de clientes a partir del certificado mTLS.
+//!
+//! Tras el saludo, el listener TLS extrae los atributos del certificado ya
+//! validado ([`ClientCertificate::from_der`]) y [`IdentityMapper`] los traduce
+//! a un usuario y un perfil, que viajan en cada petición de la conexión igual
+//! que si el cliente se hubiera autenticado con `Proxy-Authorization`. Un
+//! certificado revocado o sin identidad cierra la conexión. La lista de
+//! revocación (hashes SPKI) se relee con cada `SIGHUP`
+//! ([`IdentityMapper::reload_denylist`]) sin reiniciar el proxy.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::RwLock;
+
+use anyhow::Context;
+use ring::digest;
+use tracing::info;
+use x509_parser::extensions::GeneralName;
+
+use crate::host_pattern::HostPattern;
+use crate::settings::{CertMatcher, IdentitySettings, ProfileSettings, UnknownCertPolicy};
+
+/// Atributos de un certificado de cliente que ya superó la validación de la
+/// cadena.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertificate {
+    pub sans: Vec<String>,
+    pub common_name: Option<String>,
+    /// SHA-256 de la SubjectPublicKeyInfo en hexadecimal.
+    pub spki_sha256: String,
+}
+
+impl ClientCertificate {
+    /// Atributos del certificado DER `der`: los SAN de tipo DNS, correo o
+    /// URI, el primer CN y el hash SPKI. `None` si no se puede leer.
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            Ok(None) => Vec::new(),
+            Err(_) => return None,
+        };
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+        let spki = digest::digest(&digest::SHA256, cert.public_key().raw);
+        Some(Self {
+            sans,
+            common_name,
+            spki_sha256: spki.as_ref().iter().map(|b| format!("{b:02x}")).collect(),
+        })
+    }
+}
+
+/// Usuario y perfil asociados a una conexión; se guarda en las extensiones de
+/// cada petición para ACLs, cuotas y logs de acceso.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    user: String,
+    profile: String,
+    allow: Vec<HostPattern>,
+    data_saver: bool,
+}
+
+impl Identity {
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// Indica si el perfil activa el modo de ahorro de datos.
+    pub fn data_saver(&self) -> bool {
+        self.data_saver
+    }
+
+    /// Indica si el perfil permite conectar con `host`.
+    pub fn may_reach(&self, host: &str) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|pattern| pattern.matches(host))
+    }
+}
+
+/// Motivo por el que se rechaza un certificado.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityError {
+    Revoked,
+    Unknown,
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityError::Revoked => write!(f, "certificado revocado"),
+            IdentityError::Unknown => write!(f, "certificado sin identidad asociada"),
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {}
+
+pub struct IdentityMapper {
+    settings: IdentitySettings,
+    profiles: HashMap<String, ProfileSettings>,
+    denylist: RwLock<HashSet<String>>,
+}
+
+impl IdentityMapper {
+    /// Valida que las reglas apunten a perfiles definidos y carga la lista de
+    /// revocación si está configurada.
+    pub fn new(settings: IdentitySettings) -> anyhow::Result<Self> {
+        let profiles: HashMap<_, _> = settings
+            .profiles()
+            .iter()
+            .map(|p| (p.name().to_string(), p.clone()))
+            .collect();
+        let default_profile = match settings.unknown() {
+            UnknownCertPolicy::Profile(profile) => Some(profile),
+            UnknownCertPolicy::Reject => None,
+        };
+        let referenced = settings.rules().iter().map(|(_, _, p)| p);
+        for profile in referenced.chain(default_profile) {
+            if !profiles.contains_key(profile) {
+                anyhow::bail!("perfil de identidad desconocido: {profile}");
+            }
+        }
+        let mapper = Self {
+            settings,
+            profiles,
+            denylist: RwLock::new(HashSet::new()),
+        };
+        mapper.reload_denylist()?;
+        Ok(mapper)
+    }
+
+    /// Relee la lista de revocación. Si el archivo no se puede leer se
+    /// conserva la lista anterior.
+    pub fn reload_denylist(&self) -> anyhow::Result<usize> {
+        let Some(path) = self.settings.denylist() else {
+            return Ok(0);
+        };
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("No se pudo leer {}", path.display()))?;
+        let revoked: HashSet<String> = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(normalize_spki)
+            .collect();
+        let count = revoked.len();
+        *self.denylist.write().expect("lista de revocación") = revoked;
+        info!(path = %path.display(), count, "Lista de revocación cargada");
+        Ok(count)
+    }
+
+    pub fn profiles(&self) -> &[ProfileSettings] {
+        self.settings.profiles()
+    }
+
+    pub fn denylist_path(&self) -> Option<&std::path::Path> {
+        self.settings.denylist().map(|path| path.as_path())
+    }
+
+    /// Hashes SPKI revocados, ordenados.
+    pub fn revoked(&self) -> Vec<String> {
+        let mut revoked: Vec<String> = self
+            .denylist
+            .read()
+            .expect("lista de revocación")
+            .iter()
+            .cloned()
+            .collect();
+        revoked.sort();
+        revoked
+    }
+
+    pub fn resolve(&self, cert: &ClientCertificate) -> Result<Identity, IdentityError> {
+        let spki = normalize_spki(&cert.spki_sha256);
+        if self
+            .denylist
+            .read()
+            .expect("lista de revocación")
+            .contains(&spki)
+        {
+            return Err(IdentityError::Revoked);
+        }
+        let matched = self
+            .settings
+            .rules()
+            .iter()
+            .find(|(matcher, _, _)| match matcher {
+                CertMatcher::San(san) => cert.sans.iter().any(|s| s.eq_ignore_ascii_case(san)),
+                CertMatcher::CommonName(cn) => cert.common_name.as_deref() == Some(cn.as_str()),
+                CertMatcher::Spki(hash) => normalize_spki(hash) == spki,
+            });
+        let (user, profile) = match (matched, self.settings.unknown()) {
+            (Some((_, user, profile)), _) => (user.clone(), profile.clone()),
+            (None, UnknownCertPolicy::Profile(profile)) => {
+                let user = cert.common_name.clone().unwrap_or_else(|| spki.clone());
+                (user, profile.clone())
+            }
+            (None, UnknownCertPolicy::Reject) => return Err(IdentityError::Unknown),
+        };
+        let settings = &self.profiles[&profile];
+        Ok(Identity {
+            allow: settings.allow().to_vec(),
+            data_saver: settings.data_saver(),
+            user,
+            profile,
+        })
+    }
+}
+
+fn normalize_spki(hash: &str) -> String {
+    hash.chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn cert(cn: &str, spki: &str) -> ClientCertificate {
+        ClientCertificate {
+            sans: vec![format!("{cn}.clientes.test")],
+            common_name: Some(cn.to_string()),
+            spki_sha256: spki.to_string(),
+        }
+    }
+
+    fn settings() -> IdentitySettings {
+        IdentitySettings::default()
+            .with_profile(
+                ProfileSettings::new("backend").with_allow("*.interno.test".parse().unwrap()),
+            )
+            .with_profile(
+                ProfileSettings::new("publico").with_allow("api.publica.test".parse().unwrap()),
+            )
+            .with_rule(
+                CertMatcher::San("facturacion.clientes.test".into()),
+                "facturacion",
+                "backend",
+            )
+            .with_rule(CertMatcher::Spki("AB:CD".into()), "kiosco", "publico")
+    }
+
+    #[test]
+    fn test_two_certs_get_divergent_permissions() {
+        let mapper = IdentityMapper::new(settings()).unwrap();
+
+        let billing = mapper.resolve(&cert("facturacion", "01")).unwrap();
+        assert_eq!(billing.user(), "facturacion");
+        assert_eq!(billing.profile(), "backend");
+        assert!(billing.may_reach("db.interno.test"));
+        assert!(!billing.may_reach("api.publica.test"));
+
+        let kiosk = mapper.resolve(&cert("kiosco", "abcd")).unwrap();
+        assert_eq!(kiosk.profile(), "publico");
+        assert!(kiosk.may_reach("api.publica.test"));
+        assert!(!kiosk.may_reach("db.interno.test"));
+    }
+
+    #[test]
+    fn test_unknown_cert_rejected_or_defaulted() {
+        let mapper = IdentityMapper::new(settings()).unwrap();
+        assert_eq!(
+            mapper.resolve(&cert("otro", "ff")),
+            Err(IdentityError::Unknown)
+        );
+
+        let mapper = IdentityMapper::new(
+            settings().with_unknown(UnknownCertPolicy::Profile("publico".into())),
+        )
+        .unwrap();
+        let identity = mapper.resolve(&cert("otro", "ff")).unwrap();
+        assert_eq!(identity.user(), "otro");
+        assert_eq!(identity.profile(), "publico");
+
+        let missing = settings().with_unknown(UnknownCertPolicy::Profile("nadie".into()));
+        assert!(IdentityMapper::new(missing).is_err());
+    }
+
+    #[test]
+    fn test_denylist_reload_revokes() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mapper = IdentityMapper::new(settings().with_denylist(file.path())).unwrap();
+        assert!(mapper.resolve(&cert("kiosco", "abcd")).is_ok());
+
+        writeln!(file, "# revocados\nAB:CD").unwrap();
+        assert_eq!(mapper.reload_denylist().unwrap(), 1);
+        assert_eq!(
+            mapper.resolve(&cert("kiosco", "abcd")),
+            Err(IdentityError::Revoked)
+        );
+    }
+}
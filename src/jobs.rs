@@ -0,0 +1,381 @@
+//! Planificador del trabajo interno en segundo plano.
+//!
+//! La escritura del archivo de intercambios, el guardado de estadísticas,
+//! los informes programados, las revalidaciones de la caché de respuestas y
+//! las precargas pasan por colas con nombre en vez de lanzar tareas sueltas. Cada cola
+//! tiene prioridad, un máximo de trabajos a la vez y un máximo en espera: al
+//! llenarse, los trabajos nuevos se descartan y se cuentan. Las colas de
+//! baja prioridad comparten además un cupo común y no arrancan trabajos
+//! mientras el proxy está ocupado, es decir, mientras hay `busy_requests`
+//! peticiones o más en curso; lo que ya corre termina. La API de
+//! administración lista las colas y permite pausarlas.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use serde::Serialize;
+use tokio::sync::{oneshot, watch, Semaphore};
+use tracing::{error, info};
+
+use crate::settings::{JobPriority, JobSettings, QueueSettings};
+
+pub const ARCHIVE: &str = "archive";
+pub const STATS: &str = "stats";
+pub const REPORTS: &str = "reports";
+pub const REVALIDATE: &str = "revalidate";
+pub const PREFETCH: &str = "prefetch";
+
+/// Colas del proxy con su configuración por defecto.
+pub const BUILTIN_QUEUES: [&str; 5] = [ARCHIVE, STATS, REPORTS, REVALIDATE, PREFETCH];
+
+/// Configuración por defecto de las colas del proxy; `None` para otras.
+pub fn default_queue(name: &str) -> Option<QueueSettings> {
+    match name {
+        ARCHIVE => Some(QueueSettings::new(JobPriority::Low, 1, 16)),
+        STATS | REPORTS => Some(QueueSettings::new(JobPriority::Low, 1, 2)),
+        // A client is already holding a stale copy while these run.
+        REVALIDATE => Some(QueueSettings::new(JobPriority::High, 4, 64)),
+        PREFETCH => Some(QueueSettings::new(JobPriority::Low, 4, 64)),
+        _ => None,
+    }
+}
+
+fn queue_settings(settings: &JobSettings, name: &str) -> QueueSettings {
+    settings
+        .queues()
+        .get(name)
+        .copied()
+        .or_else(|| default_queue(name))
+        .unwrap_or(QueueSettings::new(JobPriority::Low, 1, 2))
+}
+
+type Job = BoxFuture<'static, ()>;
+
+/// Estado de una cola, tal como lo lista la API de administración.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QueueSnapshot {
+    pub name: String,
+    pub priority: &'static str,
+    pub concurrency: usize,
+    pub capacity: usize,
+    pub depth: usize,
+    pub running: usize,
+    pub processed: u64,
+    pub dropped: u64,
+    pub paused: bool,
+}
+
+struct Queue {
+    name: String,
+    settings: QueueSettings,
+    state: Mutex<QueueState>,
+    paused: watch::Sender<bool>,
+    processed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: VecDeque<Job>,
+    running: usize,
+}
+
+impl Queue {
+    fn new(name: &str, settings: QueueSettings) -> Self {
+        Self {
+            name: name.to_string(),
+            settings,
+            state: Mutex::default(),
+            paused: watch::Sender::new(false),
+            processed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self) -> QueueSnapshot {
+        let state = self.state.lock().expect("lock de cola");
+        QueueSnapshot {
+            name: self.name.clone(),
+            priority: self.settings.priority().as_str(),
+            concurrency: self.settings.concurrency(),
+            capacity: self.settings.capacity(),
+            depth: state.pending.len(),
+            running: state.running,
+            processed: self.processed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            paused: *self.paused.borrow(),
+        }
+    }
+}
+
+pub struct JobScheduler {
+    settings: JobSettings,
+    queues: Mutex<BTreeMap<String, Arc<Queue>>>,
+    /// Cupo común de las colas de baja prioridad.
+    background: Arc<Semaphore>,
+    in_flight: AtomicUsize,
+    busy: watch::Sender<bool>,
+}
+
+impl Default for JobScheduler {
+    fn default() -> Self {
+        Self::new(JobSettings::default())
+    }
+}
+
+impl JobScheduler {
+    pub fn new(settings: JobSettings) -> Self {
+        let queues = BUILTIN_QUEUES
+            .iter()
+            .map(|name| {
+                let queue = Queue::new(name, queue_settings(&settings, name));
+                (name.to_string(), Arc::new(queue))
+            })
+            .collect();
+        Self {
+            background: Arc::new(Semaphore::new(settings.background_concurrency())),
+            settings,
+            queues: Mutex::new(queues),
+            in_flight: AtomicUsize::new(0),
+            busy: watch::Sender::new(false),
+        }
+    }
+
+    /// Encola un trabajo. Devuelve `false` si la cola estaba llena y el
+    /// trabajo se descartó.
+    pub fn submit(
+        self: &Arc<Self>,
+        queue: &str,
+        job: impl Future<Output = ()> + Send + 'static,
+    ) -> bool {
+        let queue = self.queue(queue);
+        let mut state = queue.state.lock().expect("lock de cola");
+        if state.pending.len() >= queue.settings.capacity() {
+            queue.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        state.pending.push_back(Box::pin(job));
+        if state.running < queue.settings.concurrency() {
+            state.running += 1;
+            tokio::spawn(self.clone().drain(queue.clone()));
+        }
+        true
+    }
+
+    /// Encola un trabajo y espera su resultado; `None` si se descartó.
+    pub async fn run<T: Send + 'static>(
+        self: &Arc<Self>,
+        queue: &str,
+        job: impl Future<Output = T> + Send + 'static,
+    ) -> Option<T> {
+        let (tx, rx) = oneshot::channel();
+        let queued = self.submit(queue, async move {
+            let _ = tx.send(job.await);
+        });
+        if !queued {
+            return None;
+        }
+        rx.await.ok()
+    }
+
+    /// Cuenta una petición en curso mientras vive el guard.
+    pub fn enter(self: &Arc<Self>) -> InFlight {
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) + 1 == self.settings.busy_requests() {
+            self.update_busy();
+        }
+        InFlight {
+            scheduler: self.clone(),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        *self.busy.borrow()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn list(&self) -> Vec<QueueSnapshot> {
+        self.queues
+            .lock()
+            .expect("lock de colas")
+            .values()
+            .map(|queue| queue.snapshot())
+            .collect()
+    }
+
+    /// Pausa o reanuda una cola; `false` si no existe. Lo que ya corre
+    /// termina y los trabajos nuevos se siguen encolando.
+    pub fn set_paused(&self, name: &str, paused: bool) -> bool {
+        let Some(queue) = self
+            .queues
+            .lock()
+            .expect("lock de colas")
+            .get(name)
+            .cloned()
+        else {
+            return false;
+        };
+        queue.paused.send_replace(paused);
+        info!(queue = name, paused, "Cola de trabajo interno actualizada");
+        true
+    }
+
+    fn queue(&self, name: &str) -> Arc<Queue> {
+        self.queues
+            .lock()
+            .expect("lock de colas")
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Queue::new(name, queue_settings(&self.settings, name))))
+            .clone()
+    }
+
+    fn update_busy(&self) {
+        // The counter is read under the watch lock so the last crossing
+        // leaves the right state whatever order concurrent crossings run in.
+        self.busy.send_if_modified(|current| {
+            let busy = self.in_flight.load(Ordering::Relaxed) >= self.settings.busy_requests();
+            let changed = *current != busy;
+            *current = busy;
+            changed
+        });
+    }
+
+    async fn drain(self: Arc<Self>, queue: Arc<Queue>) {
+        loop {
+            let _ = queue.paused.subscribe().wait_for(|paused| !*paused).await;
+            let permit = match queue.settings.priority() {
+                JobPriority::High => None,
+                JobPriority::Low => {
+                    let _ = self.busy.subscribe().wait_for(|busy| !*busy).await;
+                    Some(
+                        self.background
+                            .clone()
+                            .acquire_owned()
+                            .await
+                            .expect("el cupo de segundo plano no se cierra"),
+                    )
+                }
+            };
+            let job = {
+                let mut state = queue.state.lock().expect("lock de cola");
+                match state.pending.pop_front() {
+                    Some(job) => job,
+                    None => {
+                        state.running -= 1;
+                        return;
+                    }
+                }
+            };
+            if AssertUnwindSafe(job).catch_unwind().await.is_err() {
+                error!(queue = %queue.name, "Un trabajo interno falló con un pánico");
+            }
+            queue.processed.fetch_add(1, Ordering::Relaxed);
+            drop(permit);
+        }
+    }
+}
+
+/// Petición en curso para [`JobScheduler::is_busy`].
+pub struct InFlight {
+    scheduler: Arc<JobScheduler>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let scheduler = &self.scheduler;
+        if scheduler.in_flight.fetch_sub(1, Ordering::Relaxed) == scheduler.settings.busy_requests()
+        {
+            scheduler.update_busy();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_busy_proxy_holds_background_work_but_not_requests() {
+        let scheduler = Arc::new(JobScheduler::new(
+            JobSettings::default()
+                .with_busy_requests(2)
+                .with_queue("urgent", QueueSettings::new(JobPriority::High, 1, 8)),
+        ));
+        let load = [scheduler.enter(), scheduler.enter()];
+        assert!(scheduler.is_busy());
+
+        // Blocking jobs stand in for CPU-heavy background work on this
+        // single-threaded runtime: if one ran, request latency would jump.
+        for _ in 0..5 {
+            assert!(scheduler.submit(ARCHIVE, async {
+                std::thread::sleep(Duration::from_millis(50));
+            }));
+        }
+        let mut worst = Duration::ZERO;
+        for _ in 0..20 {
+            let started = Instant::now();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            worst = worst.max(started.elapsed());
+        }
+        assert!(worst < Duration::from_millis(40), "{worst:?}");
+        let archive = |scheduler: &JobScheduler| {
+            scheduler
+                .list()
+                .into_iter()
+                .find(|queue| queue.name == ARCHIVE)
+                .unwrap()
+        };
+        assert_eq!(archive(&scheduler).processed, 0);
+        assert_eq!(archive(&scheduler).depth, 5);
+
+        // High priority is not held back.
+        assert_eq!(scheduler.run("urgent", async { 7 }).await, Some(7));
+
+        drop(load);
+        assert!(!scheduler.is_busy());
+        assert_eq!(
+            scheduler.run(ARCHIVE, async { "hecho" }).await,
+            Some("hecho")
+        );
+        assert_eq!(archive(&scheduler).processed, 6);
+    }
+
+    #[tokio::test]
+    async fn test_full_and_paused_queues() {
+        let scheduler = Arc::new(JobScheduler::default());
+        assert!(scheduler.set_paused(STATS, true));
+        assert!(!scheduler.set_paused("nada", true));
+        assert!(scheduler.submit(STATS, async {}));
+        assert!(scheduler.submit(STATS, async {}));
+        assert!(!scheduler.submit(STATS, async {}));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let stats = scheduler
+            .list()
+            .into_iter()
+            .find(|q| q.name == STATS)
+            .unwrap();
+        assert_eq!((stats.depth, stats.dropped, stats.paused), (2, 1, true));
+
+        scheduler.set_paused(STATS, false);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(scheduler.run(STATS, async { 1 }).await, Some(1));
+        let stats = scheduler
+            .list()
+            .into_iter()
+            .find(|q| q.name == STATS)
+            .unwrap();
+        assert_eq!((stats.depth, stats.processed), (0, 3));
+
+        // A panicking job does not stall the queue.
+        assert!(scheduler.submit(STATS, async { panic!("roto") }));
+        assert_eq!(scheduler.run(STATS, async { 2 }).await, Some(2));
+    }
+}
@@ -0,0 +1,111 @@
+//! Núcleo del proxy IA: servidor, configuración y utilidades compartidas por
+//! el binario `proxy-ia`.
+
+pub mod access_log;
+pub mod admin;
+pub mod affinity;
+pub mod ai_router;
+pub mod archive;
+pub mod balancer;
+pub mod ban;
+pub mod batch;
+pub mod bench;
+pub mod blob_store;
+pub mod blocklist;
+pub mod body_limits;
+pub mod brotli;
+pub mod canary;
+pub mod capture;
+pub mod cert_expiry;
+pub mod cidr;
+pub mod concurrency;
+pub mod config;
+pub mod conformance;
+pub mod connection;
+pub mod connections;
+pub mod content_filter;
+pub mod credentials;
+pub mod decompress;
+pub mod dialer;
+pub mod discovery;
+pub mod drain;
+pub mod effective;
+pub mod egress;
+pub mod error;
+pub mod error_pages;
+pub mod events;
+pub mod expect;
+pub mod fairness;
+pub mod fast_path;
+pub mod forwarded;
+pub mod header_limits;
+pub mod header_rules;
+pub mod host_filter;
+pub mod host_pattern;
+pub mod idempotency;
+pub mod identity;
+pub mod jobs;
+pub mod lifecycle;
+pub mod listener;
+pub mod live;
+pub mod llm;
+pub mod llm_cache;
+pub mod log_throttle;
+pub mod meta;
+pub mod metadata_echo;
+pub mod metrics;
+pub mod mitm;
+pub mod nat64;
+pub mod origin_tls;
+pub mod overload;
+pub mod pac;
+pub mod pac_serve;
+pub mod policy_export;
+pub mod policy_sim;
+#[cfg(feature = "upnp")]
+pub mod port_mapping;
+pub mod prefetch;
+pub mod privacy;
+pub mod prometheus;
+pub mod providers;
+pub mod proxy;
+pub mod proxy_auth;
+pub mod proxy_info;
+pub mod rate_limit;
+pub mod redact;
+pub mod redirect_map;
+pub mod replay;
+pub mod report;
+pub mod reputation;
+pub mod request_id;
+pub mod resources;
+pub mod response_cache;
+pub mod retry;
+pub mod rollout;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod server_timing;
+pub mod settings;
+pub mod shutdown;
+pub mod signing;
+pub mod sni_check;
+pub mod sniff;
+pub mod socks;
+pub mod socks_upstream;
+pub mod split_dns;
+pub mod ssrf;
+pub mod stats;
+pub mod streaming;
+pub mod throttle;
+pub mod tickets;
+pub mod tls;
+pub mod token_budget;
+pub mod token_usage;
+pub mod trailers;
+#[cfg(feature = "transcoding")]
+pub mod transcode;
+pub mod transparent;
+pub mod tunnel_quality;
+pub mod tunnels;
+pub mod upgrade;
+pub mod upstream;
@@ -0,0 +1,218 @@
+//! Ganchos del ciclo de vida de las conexiones para quien embebe el proxy.
+//!
+//! [`ConnectionHooks::on_connection_open`] se llama con cada conexión
+//! aceptada y puede devolver un contexto propio (`Conn`); ese contexto llega
+//! a [`ConnectionHooks::on_request`] con cada petición de la conexión y, al
+//! final, a [`ConnectionHooks::on_connection_close`] junto con un resumen.
+//! Si la apertura devuelve `None`, la conexión no se sigue y no habrá cierre
+//! para ella. Cada apertura que devuelve `Some` tiene exactamente un cierre.
+//!
+//! La conexión termina cuando se suelta su último socket: si abrió un túnel
+//! con `CONNECT`, cuando se cierra el túnel. Con la parada, el cierre de las
+//! conexiones que se drenan llega antes de que [`ProxyHandle::wait`]
+//! devuelva su informe; el de las que se abortan al vencer el drenaje, en el
+//! segundo de gracia que sigue. Las conexiones que llegan después de empezar
+//! la parada ya no se aceptan, así que no se abren.
+//!
+//! Un gancho que entra en pánico no tumba nada: el pánico se registra en el
+//! log y la conexión sigue (un pánico en la apertura cuenta como `None`).
+//! Sin ganchos configurados no se cuenta nada.
+//!
+//! [`ProxyHandle::wait`]: crate::proxy::ProxyHandle::wait
+
+use std::any::Any;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::error;
+
+use crate::metrics::RequestLabels;
+
+/// Lo que hizo una conexión, tal como llega al cierre.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    pub duration: Duration,
+    /// Peticiones atendidas, contando el `CONNECT` de un túnel.
+    pub requests: u64,
+    /// Bytes leídos del cliente, túneles incluidos.
+    pub bytes_received: u64,
+    /// Bytes escritos al cliente, túneles incluidos.
+    pub bytes_sent: u64,
+}
+
+/// Ganchos de apertura y cierre de las conexiones de clientes. Se llaman en
+/// el camino de cada conexión: no deben bloquear.
+pub trait ConnectionHooks: Send + Sync + 'static {
+    /// Contexto propio de cada conexión.
+    type Conn: Send + Sync + 'static;
+
+    /// Llegó una conexión de `remote_addr` al listener de `listener`.
+    fn on_connection_open(
+        &self,
+        remote_addr: SocketAddr,
+        listener: SocketAddr,
+    ) -> Option<Self::Conn>;
+
+    /// Llegó una petición por la conexión.
+    fn on_request(&self, _conn: &Self::Conn, _request: &RequestLabels) {}
+
+    fn on_connection_close(&self, _conn: Self::Conn, _summary: &ConnectionSummary) {}
+}
+
+type ErasedConn = Box<dyn Any + Send + Sync>;
+
+/// [`ConnectionHooks`] sin el tipo del contexto, para guardarlos en el
+/// contexto del proxy.
+pub(crate) trait ErasedHooks: Send + Sync + 'static {
+    fn open(&self, remote_addr: SocketAddr, listener: SocketAddr) -> Option<ErasedConn>;
+    fn request(&self, conn: &ErasedConn, request: &RequestLabels);
+    fn close(&self, conn: ErasedConn, summary: &ConnectionSummary);
+}
+
+impl<H: ConnectionHooks> ErasedHooks for H {
+    fn open(&self, remote_addr: SocketAddr, listener: SocketAddr) -> Option<ErasedConn> {
+        self.on_connection_open(remote_addr, listener)
+            .map(|conn| Box::new(conn) as ErasedConn)
+    }
+
+    fn request(&self, conn: &ErasedConn, request: &RequestLabels) {
+        if let Some(conn) = conn.downcast_ref() {
+            self.on_request(conn, request);
+        }
+    }
+
+    fn close(&self, conn: ErasedConn, summary: &ConnectionSummary) {
+        if let Ok(conn) = conn.downcast() {
+            self.on_connection_close(*conn, summary);
+        }
+    }
+}
+
+/// Ejecuta un gancho y convierte su pánico en una línea de log.
+fn guarded<T>(hook: &'static str, f: impl FnOnce() -> T) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => Some(value),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            error!(hook, panic = %message, "Un gancho de conexión entró en pánico");
+            None
+        }
+    }
+}
+
+/// Una conexión abierta con los ganchos; el cierre se llama al soltar la
+/// última referencia.
+pub(crate) struct ConnectionRecord {
+    hooks: Arc<dyn ErasedHooks>,
+    conn: Option<ErasedConn>,
+    opened: Instant,
+    requests: AtomicU64,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl ConnectionRecord {
+    /// `None` si los ganchos no quieren seguir la conexión.
+    pub(crate) fn open(
+        hooks: &Arc<dyn ErasedHooks>,
+        remote_addr: SocketAddr,
+        listener: SocketAddr,
+    ) -> Option<Arc<Self>> {
+        let conn = guarded("on_connection_open", || hooks.open(remote_addr, listener)).flatten()?;
+        Some(Arc::new(Self {
+            hooks: hooks.clone(),
+            conn: Some(conn),
+            opened: Instant::now(),
+            requests: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+        }))
+    }
+
+    pub(crate) fn request(&self, request: &RequestLabels) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(conn) = &self.conn {
+            guarded("on_request", || self.hooks.request(conn, request));
+        }
+    }
+}
+
+impl Drop for ConnectionRecord {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        let summary = ConnectionSummary {
+            duration: self.opened.elapsed(),
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_received: self.received.load(Ordering::Relaxed),
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+        };
+        guarded("on_connection_close", || self.hooks.close(conn, &summary));
+    }
+}
+
+/// Socket del cliente que cuenta sus bytes en la conexión, si se sigue.
+pub(crate) struct RecordedIo<S> {
+    inner: S,
+    record: Option<Arc<ConnectionRecord>>,
+}
+
+impl<S> RecordedIo<S> {
+    pub(crate) fn new(inner: S, record: Option<Arc<ConnectionRecord>>) -> Self {
+        Self { inner, record }
+    }
+
+    pub(crate) fn record(&self) -> Option<&Arc<ConnectionRecord>> {
+        self.record.as_ref()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordedIo<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(record) = &self.record {
+            let read = (buf.filled().len() - before) as u64;
+            record.received.fetch_add(read, Ordering::Relaxed);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordedIo<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if let Some(record) = &self.record {
+            record.sent.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,267 @@
+//! Defensas del puerto de escucha frente a avalanchas de conexiones.
+//!
+//! El socket se abre con el backlog configurado y, en Linux, con
+//! `TCP_DEFER_ACCEPT`, de modo que el kernel no entrega conexiones que no
+//! envían nada. Ya en el bucle de aceptación, [`AcceptGuard`] cierra las
+//! conexiones de una IP que supera su cupo (un token bucket pequeño, mucho
+//! más barato que atender la petición) y deja de aceptar mientras el proceso
+//! está cerca de su límite de descriptores abiertos; las conexiones esperan
+//! en el backlog hasta que baja.
+//!
+//! En Unix el proxy puede escuchar además en un socket UNIX
+//! ([`UnixSocketListener`]), cuyo archivo se borra al parar.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpListener, TcpSocket};
+use tracing::{info, warn};
+
+use crate::log_throttle::LogThrottle;
+use crate::metrics::Metrics;
+use crate::rate_limit::Bucket;
+use crate::resources;
+#[cfg(unix)]
+use crate::settings::UnixListenSettings;
+use crate::settings::{AcceptRate, ListenerHardening};
+
+/// IPs con cupo propio a partir de las cuales se olvidan las que ya lo
+/// tienen lleno.
+const MAX_TRACKED: usize = 16_384;
+
+/// Cada cuánto se recuentan los descriptores abiertos.
+const FD_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Abre el listener de `addr` con el backlog y el `TCP_DEFER_ACCEPT` de
+/// `hardening`.
+pub fn bind(addr: SocketAddr, hardening: ListenerHardening) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // Same as `TcpListener::bind`, so a restart can reuse the port at once.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    if let Some(timeout) = hardening.defer_accept() {
+        defer_accept(&socket, timeout)?;
+    }
+    socket.listen(hardening.backlog())
+}
+
+#[cfg(target_os = "linux")]
+fn defer_accept(socket: &TcpSocket, timeout: Duration) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let secs = libc::c_int::try_from(timeout.as_secs().max(1)).unwrap_or(libc::c_int::MAX);
+    // SAFETY: the descriptor belongs to `socket`, which outlives the call,
+    // and the option value is a live `c_int` of the advertised size.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_DEFER_ACCEPT,
+            (&secs as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn defer_accept(_socket: &TcpSocket, _timeout: Duration) -> io::Result<()> {
+    warn!("TCP_DEFER_ACCEPT solo está disponible en Linux; se ignora");
+    Ok(())
+}
+
+/// Socket UNIX en el que escucha el proxy; el archivo se borra al soltarlo.
+#[cfg(unix)]
+pub struct UnixSocketListener {
+    listener: tokio::net::UnixListener,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocketListener {
+    /// Abre el socket de `settings` con sus permisos. Un socket que quedó de
+    /// una ejecución anterior se reemplaza; uno que alguien atiende o
+    /// cualquier otro archivo en esa ruta es un error.
+    pub fn bind(settings: &UnixListenSettings) -> io::Result<Self> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let path = settings.path();
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} existe y no es un socket: no se reemplaza",
+                        path.display()
+                    ),
+                ));
+            }
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} está en uso por otro proceso", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)?;
+            info!(path = %path.display(), "Socket UNIX de una ejecución anterior reemplazado");
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        let listener = Self {
+            listener,
+            path: path.to_path_buf(),
+        };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(settings.mode()))?;
+        Ok(listener)
+    }
+
+    pub async fn accept(&self) -> io::Result<tokio::net::UnixStream> {
+        self.listener.accept().await.map(|(stream, _)| stream)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), error = %e, "No se pudo borrar el socket UNIX");
+        }
+    }
+}
+
+/// Filtro del bucle de aceptación: cupo de conexiones por IP y pausa por
+/// descriptores.
+pub struct AcceptGuard {
+    rate: Option<AcceptRate>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    fd_threshold: Option<usize>,
+    fd_checked: Mutex<Option<Instant>>,
+    rejection_log: LogThrottle,
+}
+
+impl AcceptGuard {
+    /// `None` si `hardening` no limita nada en el bucle de aceptación.
+    pub fn new(hardening: ListenerHardening) -> Option<Self> {
+        let fd_threshold = hardening.fd_pause_percent().and_then(|percent| {
+            let threshold = fd_limit().map(|limit| limit * usize::from(percent) / 100);
+            if threshold.is_none() {
+                warn!("No se pudo leer el límite de descriptores; la pausa por descriptores queda desactivada");
+            }
+            threshold
+        });
+        if hardening.accept_rate().is_none() && fd_threshold.is_none() {
+            return None;
+        }
+        Some(Self {
+            rate: hardening.accept_rate(),
+            buckets: Mutex::default(),
+            fd_threshold,
+            fd_checked: Mutex::new(None),
+            rejection_log: LogThrottle::new(Duration::from_secs(10)),
+        })
+    }
+
+    /// Gasta un token de `ip`; `false` si no le quedan y hay que cerrar la
+    /// conexión.
+    pub fn admit(&self, ip: IpAddr, metrics: &Metrics) -> bool {
+        let Some(rate) = self.rate else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("lock de cupos");
+        if buckets.len() >= MAX_TRACKED {
+            // A full bucket is the same as no bucket at all.
+            buckets.retain(|_, bucket| {
+                bucket.refill(rate.per_sec(), rate.burst(), now);
+                !bucket.is_full(rate.burst())
+            });
+        }
+        let bucket = buckets
+            .entry(ip)
+            .or_insert_with(|| Bucket::full(rate.burst(), now));
+        bucket.refill(rate.per_sec(), rate.burst(), now);
+        if bucket.take() {
+            return true;
+        }
+        drop(buckets);
+        metrics.record_accept_rejection();
+        if let Some(suppressed) = self.rejection_log.should_log() {
+            warn!(%ip, suppressed, "Cliente por encima del cupo de conexiones; conexión cerrada");
+        }
+        false
+    }
+
+    /// Espera mientras los descriptores abiertos superan el umbral.
+    pub async fn wait_for_descriptors(&self, metrics: &Metrics) {
+        let Some(threshold) = self.fd_threshold else {
+            return;
+        };
+        {
+            let mut checked = self.fd_checked.lock().expect("lock de descriptores");
+            if checked.is_some_and(|at| at.elapsed() < FD_CHECK_INTERVAL) {
+                return;
+            }
+            *checked = Some(Instant::now());
+        }
+        let over = || resources::open_fds().is_some_and(|open| open >= threshold);
+        if !over() {
+            return;
+        }
+        metrics.record_accept_pause();
+        warn!(
+            threshold,
+            "Demasiados descriptores abiertos; se dejan de aceptar conexiones"
+        );
+        while over() {
+            tokio::time::sleep(FD_CHECK_INTERVAL).await;
+        }
+        info!("Descriptores por debajo del umbral; se vuelven a aceptar conexiones");
+    }
+}
+
+/// Límite blando de descriptores del proceso; `None` si es ilimitado o no
+/// se puede leer.
+fn fd_limit() -> Option<usize> {
+    let soft = resources::nofile_limit()?.soft?;
+    usize::try_from(soft).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills_at_the_configured_rate() {
+        let guard = AcceptGuard::new(
+            ListenerHardening::default().with_accept_rate(AcceptRate::new(20.0, 2)),
+        )
+        .unwrap();
+        let metrics = Metrics::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(guard.admit(ip, &metrics));
+        assert!(guard.admit(ip, &metrics));
+        assert!(!guard.admit(ip, &metrics));
+        // Other clients have their own bucket.
+        assert!(guard.admit("192.0.2.2".parse().unwrap(), &metrics));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(guard.admit(ip, &metrics));
+        assert_eq!(metrics.accept_rejections(), 1);
+
+        assert!(AcceptGuard::new(ListenerHardening::default()).is_none());
+    }
+}
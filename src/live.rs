@@ -0,0 +1,277 @@
+//! Secciones de la configuración que se recargan sin reiniciar.
+//!
+//! `[host_filter]`, `[header_rules]`, `[rate_limit]` y `[upstream_proxy]` se
+//! vuelven a leer del fichero de configuración con cada `SIGHUP`. Cada
+//! petición toma al empezar la versión vigente y la conserva hasta terminar,
+//! así que un cambio no afecta a lo que ya está en curso ni corta conexiones.
+//! Una configuración inválida se rechaza entera y se sigue con la anterior.
+//! Las secciones que no cambian conservan su estado: con el mismo
+//! `[rate_limit]`, los clientes no recuperan el cupo que ya gastaron. El
+//! resto de la configuración, como la dirección de escucha, requiere
+//! reiniciar.
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use tracing::info;
+
+use crate::header_rules::HeaderRewriter;
+use crate::host_filter::{HostFilter, HostRule};
+use crate::rate_limit::RateLimiter;
+use crate::settings::{
+    HeaderRule, HostFilterSettings, ProxySettings, RateLimit, UpstreamProxySettings,
+};
+use crate::upstream::UpstreamProxy;
+
+/// Versión de las secciones recargables que usa una petición.
+#[derive(Clone, Default)]
+pub struct LiveSnapshot {
+    pub(crate) host_filter: Option<Arc<HostFilter>>,
+    pub(crate) header_rules: Option<Arc<HeaderRewriter>>,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) upstream_proxy: Option<Arc<UpstreamProxy>>,
+}
+
+/// Una sección que cambió al recargar, descrita antes y después.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub section: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.section, self.before, self.after)
+    }
+}
+
+/// Lo que se configuró para cada sección, para saber qué cambia.
+#[derive(Clone, Default, PartialEq)]
+struct Sections {
+    host_filter: Option<HostFilterSettings>,
+    header_rules: Vec<HeaderRule>,
+    rate_limit: Option<RateLimit>,
+    upstream_proxy: Option<UpstreamProxySettings>,
+}
+
+impl Sections {
+    fn from_settings(settings: &ProxySettings) -> Self {
+        Self {
+            host_filter: settings.host_filter().cloned(),
+            header_rules: settings.header_rules().to_vec(),
+            rate_limit: settings.rate_limit(),
+            upstream_proxy: settings.upstream_proxy().cloned(),
+        }
+    }
+
+    fn changes(&self, new: &Self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        let mut compare = |section, before: String, after: String| {
+            if before != after {
+                changes.push(Change {
+                    section,
+                    before,
+                    after,
+                });
+            }
+        };
+        compare(
+            "host_filter",
+            describe_host_filter(self.host_filter.as_ref()),
+            describe_host_filter(new.host_filter.as_ref()),
+        );
+        if self.header_rules != new.header_rules {
+            compare(
+                "header_rules",
+                format!("{} reglas", self.header_rules.len()),
+                format!("{} reglas", new.header_rules.len()),
+            );
+        }
+        compare(
+            "rate_limit",
+            describe_rate_limit(self.rate_limit),
+            describe_rate_limit(new.rate_limit),
+        );
+        if self.upstream_proxy != new.upstream_proxy {
+            let describe = |upstream: Option<&UpstreamProxySettings>| match upstream {
+                Some(upstream) if upstream.credentials().is_some() => {
+                    format!("{} (con credenciales)", upstream.parent())
+                }
+                Some(upstream) => upstream.parent().to_string(),
+                None => "directo".to_string(),
+            };
+            let (before, after) = (
+                describe(self.upstream_proxy.as_ref()),
+                describe(new.upstream_proxy.as_ref()),
+            );
+            // Only the credentials changed: say so without showing them.
+            let after = if before == after {
+                format!("{after} (credenciales nuevas)")
+            } else {
+                after
+            };
+            compare("upstream_proxy", before, after);
+        }
+        changes
+    }
+}
+
+fn describe_host_filter(settings: Option<&HostFilterSettings>) -> String {
+    let Some(settings) = settings else {
+        return "sin filtro".to_string();
+    };
+    let list = |rules: &[HostRule]| {
+        rules
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    format!(
+        "allow [{}], deny [{}]",
+        list(settings.allow()),
+        list(settings.deny())
+    )
+}
+
+fn describe_rate_limit(rate: Option<RateLimit>) -> String {
+    match rate {
+        Some(rate) => format!("{}/s, ráfaga {}", rate.per_sec(), rate.burst()),
+        None => "sin cupo".to_string(),
+    }
+}
+
+/// Secciones recargables vigentes, compartidas por todas las conexiones.
+pub struct LiveConfig {
+    current: RwLock<(Sections, LiveSnapshot)>,
+}
+
+impl LiveConfig {
+    pub fn new(settings: &ProxySettings) -> Self {
+        let sections = Sections::from_settings(settings);
+        let snapshot = build(&sections, &LiveSnapshot::default(), &Sections::default());
+        Self {
+            current: RwLock::new((sections, snapshot)),
+        }
+    }
+
+    /// La versión vigente; la petición que la toma no ve recargas
+    /// posteriores.
+    pub fn snapshot(&self) -> LiveSnapshot {
+        self.current
+            .read()
+            .expect("lock de configuración")
+            .1
+            .clone()
+    }
+
+    /// Pasa a las secciones de `settings` y devuelve qué cambió. Un
+    /// limitador de cupo nuevo tiene que ponerse en marcha con
+    /// [`RateLimiter::run`].
+    pub fn reload(&self, settings: &ProxySettings) -> Vec<Change> {
+        let sections = Sections::from_settings(settings);
+        let mut current = self.current.write().expect("lock de configuración");
+        let changes = current.0.changes(&sections);
+        if !changes.is_empty() {
+            let snapshot = build(&sections, &current.1, &current.0);
+            *current = (sections, snapshot);
+        }
+        for change in &changes {
+            info!(
+                section = change.section,
+                before = %change.before,
+                after = %change.after,
+                "Sección de la configuración recargada"
+            );
+        }
+        changes
+    }
+}
+
+/// Componentes para `sections`, reutilizando los de `previous` (construidos
+/// con `old`) que no cambian.
+fn build(sections: &Sections, previous: &LiveSnapshot, old: &Sections) -> LiveSnapshot {
+    let host_filter = if sections.host_filter == old.host_filter {
+        previous.host_filter.clone()
+    } else {
+        sections
+            .host_filter
+            .clone()
+            .map(|settings| Arc::new(HostFilter::new(settings)))
+    };
+    let header_rules = if sections.header_rules == old.header_rules {
+        previous.header_rules.clone()
+    } else {
+        Some(sections.header_rules.clone())
+            .filter(|rules| !rules.is_empty())
+            .map(|rules| Arc::new(HeaderRewriter::new(rules)))
+    };
+    let rate_limiter = if sections.rate_limit == old.rate_limit {
+        previous.rate_limiter.clone()
+    } else {
+        sections
+            .rate_limit
+            .map(|rate| Arc::new(RateLimiter::new(rate)))
+    };
+    let upstream_proxy = if sections.upstream_proxy == old.upstream_proxy {
+        previous.upstream_proxy.clone()
+    } else {
+        sections
+            .upstream_proxy
+            .as_ref()
+            .map(|settings| Arc::new(UpstreamProxy::new(settings)))
+    };
+    LiveSnapshot {
+        host_filter,
+        header_rules,
+        rate_limiter,
+        upstream_proxy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::HeaderDirection;
+
+    #[test]
+    fn test_reload_swaps_only_the_sections_that_changed() {
+        let base = ProxySettings::new("127.0.0.1:0".parse().unwrap())
+            .with_rate_limit(RateLimit::new(10.0, 20))
+            .with_host_filter(
+                HostFilterSettings::default().with_deny("blocked.test".parse().unwrap()),
+            );
+        let live = LiveConfig::new(&base);
+        let before = live.snapshot();
+        assert!(before.host_filter.is_some() && before.header_rules.is_none());
+
+        let changed = base
+            .clone()
+            .with_host_filter(HostFilterSettings::default())
+            .with_header_rule(HeaderRule::set(HeaderDirection::Request, "x-env", "prod").unwrap());
+        let changes = live.reload(&changed);
+        let sections: Vec<_> = changes.iter().map(|change| change.section).collect();
+        assert_eq!(sections, ["host_filter", "header_rules"]);
+        assert_eq!(changes[0].before, "allow [], deny [blocked.test]");
+        assert_eq!(changes[0].after, "allow [], deny []");
+
+        let after = live.snapshot();
+        // The rate limiter keeps its buckets; the old snapshot is untouched.
+        assert!(Arc::ptr_eq(
+            before.rate_limiter.as_ref().unwrap(),
+            after.rate_limiter.as_ref().unwrap()
+        ));
+        assert!(!before
+            .host_filter
+            .unwrap()
+            .check("blocked.test")
+            .is_allowed());
+        assert!(after
+            .host_filter
+            .unwrap()
+            .check("blocked.test")
+            .is_allowed());
+        assert!(live.reload(&changed).is_empty());
+    }
+}
@@ -0,0 +1,322 @@
+//! Presupuestos por petición en las rutas de gateway LLM.
+//!
+//! En las peticiones HTTP hacia los hosts de `[llm]` un cliente puede acotar
+//! el gasto con `X-LLM-Max-Cost` (dólares) y la espera con
+//! `X-LLM-Max-Latency` (`5s`, `800ms`, `2m` o segundos sin unidad). Antes de
+//! reenviar se estima el coste con la tabla de precios: los tokens de entrada
+//! se aproximan como un cuarto de los bytes del body y los de salida son
+//! `max_tokens` (o `default_max_tokens`). Si la estimación supera el
+//! presupuesto se responde 402 sin contactar con el destino. La latencia es
+//! el plazo para recibir la cabecera de la respuesta; al vencer se responde
+//! 504. Los errores son JSON con un `error.type` estable para que los SDK los
+//! distingan. Las dos cabeceras se quitan antes de reenviar, y la estimación
+//! se registra junto al `usage` real de la respuesta para calibrar precios.
+
+use std::time::Duration;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::capture::buffer_prefix;
+use crate::host_pattern::HostPattern;
+use crate::replay::Usage;
+use crate::settings::LlmSettings;
+use crate::streaming;
+
+pub const MAX_COST: &str = "x-llm-max-cost";
+pub const MAX_LATENCY: &str = "x-llm-max-latency";
+/// Aproximación habitual para texto en inglés y JSON.
+const BYTES_PER_TOKEN: u64 = 4;
+
+/// Límites que pidió el cliente para una petición.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Budget {
+    pub max_cost: Option<f64>,
+    pub max_latency: Option<Duration>,
+}
+
+impl Budget {
+    /// Quita las cabeceras de presupuesto y las interpreta. El error es la
+    /// cabecera con un valor inválido, a responder con [`invalid_budget`].
+    pub fn take(headers: &mut HeaderMap) -> Result<Self, &'static str> {
+        let mut take = |name: &str| {
+            headers
+                .remove(name)
+                .map(|value| value.to_str().map(str::trim).map(str::to_string))
+        };
+        let max_cost = match take(MAX_COST) {
+            None => None,
+            Some(value) => Some(
+                value
+                    .ok()
+                    .and_then(|v| v.parse::<f64>().ok())
+                    .filter(|cost| cost.is_finite() && *cost >= 0.0)
+                    .ok_or(MAX_COST)?,
+            ),
+        };
+        let max_latency = match take(MAX_LATENCY) {
+            None => None,
+            Some(value) => Some(
+                value
+                    .ok()
+                    .and_then(|v| parse_latency(&v))
+                    .ok_or(MAX_LATENCY)?,
+            ),
+        };
+        Ok(Self {
+            max_cost,
+            max_latency,
+        })
+    }
+}
+
+/// 400 para una cabecera de presupuesto que no se pudo interpretar.
+pub fn invalid_budget(header: &str) -> Response<Body> {
+    error_response(
+        StatusCode::BAD_REQUEST,
+        "invalid_budget",
+        format!("Valor inválido en {header}"),
+        json!({ "header": header }),
+    )
+}
+
+/// `5s`, `800ms`, `2m` o segundos sin unidad, con decimales.
+pub fn parse_latency(value: &str) -> Option<Duration> {
+    let (number, scale) = if let Some(ms) = value.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(s) = value.strip_suffix('s') {
+        (s, 1.0)
+    } else if let Some(m) = value.strip_suffix('m') {
+        (m, 60.0)
+    } else {
+        (value, 1.0)
+    };
+    let secs = number.trim().parse::<f64>().ok()? * scale;
+    (secs.is_finite() && secs > 0.0).then(|| Duration::from_secs_f64(secs))
+}
+
+/// Coste estimado de una petición antes de reenviarla.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub model: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+}
+
+pub struct LlmGateway {
+    settings: LlmSettings,
+}
+
+impl LlmGateway {
+    pub fn new(settings: LlmSettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn settings(&self) -> &LlmSettings {
+        &self.settings
+    }
+
+    pub fn applies(&self, host: &str) -> bool {
+        self.settings
+            .hosts()
+            .iter()
+            .any(|pattern: &HostPattern| pattern.matches(host))
+    }
+
+    /// Estima el coste de un body JSON de petición; el error explica por qué
+    /// no se pudo.
+    pub fn estimate(&self, body: &[u8]) -> Result<Estimate, String> {
+        let json: Value =
+            serde_json::from_slice(body).map_err(|_| "el body no es JSON".to_string())?;
+        let model = json
+            .get("model")
+            .and_then(Value::as_str)
+            .ok_or_else(|| "la petición no indica el modelo".to_string())?;
+        let price = self
+            .settings
+            .price(model)
+            .ok_or_else(|| format!("el modelo {model} no tiene precio configurado"))?;
+        let output_tokens = ["max_tokens", "max_completion_tokens", "max_output_tokens"]
+            .iter()
+            .find_map(|field| json.get(field)?.as_u64())
+            .unwrap_or(self.settings.default_max_tokens());
+        let input_tokens = (body.len() as u64).div_ceil(BYTES_PER_TOKEN);
+        Ok(Estimate {
+            model: model.to_string(),
+            input_tokens,
+            output_tokens,
+            cost: price.cost(input_tokens, output_tokens),
+        })
+    }
+
+    /// Estima la petición y la rechaza con 402 si supera `max_cost`. Si
+    /// cabe devuelve la petición lista para reenviar junto a la estimación.
+    pub async fn preflight(
+        &self,
+        req: Request<Body>,
+        max_cost: f64,
+    ) -> Result<(Request<Body>, Estimate), Response<Body>> {
+        let (parts, body) = req.into_parts();
+        let (bytes, truncated, body) = buffer_prefix(body, self.settings.max_body()).await;
+        let estimate = if truncated {
+            Err("el body es demasiado grande para estimarlo".to_string())
+        } else {
+            self.estimate(&bytes)
+        };
+        let estimate = match estimate {
+            Ok(estimate) => estimate,
+            Err(reason) => {
+                return Err(error_response(
+                    StatusCode::PAYMENT_REQUIRED,
+                    "budget_unestimable",
+                    format!("No se pudo estimar el coste: {reason}"),
+                    json!({ "max_cost": max_cost }),
+                ))
+            }
+        };
+        if estimate.cost > max_cost {
+            info!(
+                model = %estimate.model,
+                estimated_cost = estimate.cost,
+                max_cost,
+                "Petición LLM rechazada por presupuesto"
+            );
+            return Err(error_response(
+                StatusCode::PAYMENT_REQUIRED,
+                "budget_exceeded",
+                format!(
+                    "El coste estimado ({:.6} USD) supera el presupuesto ({max_cost} USD)",
+                    estimate.cost
+                ),
+                json!({
+                    "model": estimate.model,
+                    "estimated_cost": estimate.cost,
+                    "max_cost": max_cost,
+                    "estimated_input_tokens": estimate.input_tokens,
+                    "max_output_tokens": estimate.output_tokens,
+                }),
+            ));
+        }
+        Ok((Request::from_parts(parts, body), estimate))
+    }
+
+    /// Registra la estimación junto al `usage` real de una respuesta JSON.
+    /// Las respuestas en streaming se reenvían sin leerlas.
+    pub async fn observe(
+        &self,
+        response: Response<Body>,
+        estimate: &Estimate,
+        elapsed: Duration,
+    ) -> Response<Body> {
+        let is_json = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("json"));
+        let latency_ms = elapsed.as_millis() as u64;
+        if !is_json || streaming::is_streamed(&response) {
+            info!(model = %estimate.model, estimated_cost = estimate.cost, latency_ms, "Coste LLM estimado");
+            return response;
+        }
+        let (parts, body) = response.into_parts();
+        let (bytes, truncated, body) = buffer_prefix(body, self.settings.max_body()).await;
+        let usage = (!truncated)
+            .then(|| serde_json::from_slice::<Value>(&bytes).ok())
+            .flatten()
+            .and_then(|json| Usage::of(&json));
+        let actual_cost = usage.and_then(|usage| {
+            let price = self.settings.price(&estimate.model)?;
+            Some(price.cost(usage.prompt_tokens, usage.completion_tokens))
+        });
+        info!(
+            model = %estimate.model,
+            estimated_cost = estimate.cost,
+            actual_cost,
+            estimated_input_tokens = estimate.input_tokens,
+            input_tokens = usage.map(|usage| usage.prompt_tokens),
+            max_output_tokens = estimate.output_tokens,
+            output_tokens = usage.map(|usage| usage.completion_tokens),
+            latency_ms,
+            "Coste LLM estimado frente al real"
+        );
+        Response::from_parts(parts, body)
+    }
+}
+
+/// 504 cuando el destino no respondió dentro de `X-LLM-Max-Latency`.
+pub fn latency_exceeded(budget: Duration) -> Response<Body> {
+    let mut response = error_response(
+        StatusCode::GATEWAY_TIMEOUT,
+        "latency_budget_exceeded",
+        format!(
+            "El destino no respondió dentro del presupuesto de {} ms",
+            budget.as_millis()
+        ),
+        json!({ "max_latency_ms": budget.as_millis() as u64 }),
+    );
+    response.headers_mut().insert(
+        "x-proxy-error",
+        hyper::header::HeaderValue::from_static("latency_budget"),
+    );
+    response
+}
+
+fn error_response(
+    status: StatusCode,
+    kind: &str,
+    message: String,
+    details: Value,
+) -> Response<Body> {
+    let mut error = json!({ "type": kind, "message": message });
+    if let (Some(error), Value::Object(details)) = (error.as_object_mut(), details) {
+        error.extend(details);
+    }
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "error": error }).to_string()))
+        .expect("respuesta de presupuesto LLM")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ModelPrice;
+
+    #[test]
+    fn test_budget_headers_and_estimate() {
+        let mut headers = HeaderMap::new();
+        headers.insert(MAX_COST, "0.02".parse().unwrap());
+        headers.insert(MAX_LATENCY, "1.5s".parse().unwrap());
+        let budget = Budget::take(&mut headers).unwrap();
+        assert_eq!(budget.max_cost, Some(0.02));
+        assert_eq!(budget.max_latency, Some(Duration::from_millis(1500)));
+        assert!(headers.is_empty());
+        assert_eq!(parse_latency("800ms"), Some(Duration::from_millis(800)));
+        assert_eq!(parse_latency("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_latency("0"), None);
+
+        headers.insert(MAX_COST, "barato".parse().unwrap());
+        assert_eq!(Budget::take(&mut headers), Err(MAX_COST));
+        assert_eq!(invalid_budget(MAX_COST).status(), StatusCode::BAD_REQUEST);
+
+        let gateway = LlmGateway::new(LlmSettings::default().with_price(
+            "gpt-4o",
+            ModelPrice {
+                input_per_mtok: 2.5,
+                output_per_mtok: 10.0,
+            },
+        ));
+        let body = br#"{"model":"gpt-4o-2024-08-06","max_tokens":1000,"messages":[]}"#;
+        let estimate = gateway.estimate(body).unwrap();
+        assert_eq!(estimate.output_tokens, 1000);
+        assert_eq!(estimate.input_tokens, (body.len() as u64).div_ceil(4));
+        assert!(
+            (estimate.cost - (estimate.input_tokens as f64 * 2.5 + 10_000.0) / 1e6).abs() < 1e-12
+        );
+        assert!(gateway.estimate(br#"{"model":"otro"}"#).is_err());
+    }
+}
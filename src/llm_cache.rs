@@ -0,0 +1,527 @@
+//! Caché de respuestas del router IA por contenido de la petición.
+//!
+//! Con `[ai_router.cache]` las peticiones enrutadas cuyo body es un objeto
+//! JSON se identifican por un SHA-256 de su ruta y su body normalizado: las
+//! claves ordenadas, sin `user` y sin `stream: false`. Una respuesta 200 en
+//! JSON se guarda durante `ttl_secs` y la misma petición se contesta desde
+//! memoria con `X-LLM-Cache: HIT`, sin contactar con el backend; las que sí
+//! lo contactan llevan `MISS`, y las que no pueden guardarse `BYPASS`. Las
+//! peticiones con `temperature` mayor que cero o con `stream: true` no usan
+//! la caché salvo con `cache_sampled` o `cache_streams`: sus respuestas
+//! cambian de una vez a otra o llegan por partes. El body se copia mientras
+//! se entrega y se guarda al terminar, si no supera `max_body_bytes`. Con
+//! `dir`, cada entrada se escribe también en un archivo y las vigentes se
+//! cargan al arrancar. Al llenarse `max_entries` se descarta la menos usada.
+//! Una respuesta con `Content-Encoding: gzip` o `deflate` se guarda
+//! descomprimida y sin esa cabecera, y `max_body_bytes` cuenta lo
+//! descomprimido; con otra codificación no se guarda.
+
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use lru::LruCache;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::decompress::{Decoder, Encoding};
+use crate::metrics::{CacheKind, Metrics};
+use crate::settings::LlmCacheSettings;
+use crate::streaming;
+
+pub const X_LLM_CACHE: &str = "x-llm-cache";
+
+/// Lo que la caché hace con una petición.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    /// Se busca y se guarda con esta clave.
+    Key(String),
+    /// No pasa por la caché, por este motivo.
+    Bypass(&'static str),
+}
+
+/// Respuesta guardada, tal como se escribe en disco.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Base64.
+    body: String,
+    /// Milisegundos Unix.
+    expires_at_ms: u64,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    expires: SystemTime,
+}
+
+impl Entry {
+    fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+            .headers_mut()
+            .insert(X_LLM_CACHE, HeaderValue::from_static("HIT"));
+        response
+    }
+
+    fn from_stored(stored: Stored) -> Option<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in stored.headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(&value).ok()?,
+            );
+        }
+        Some(Self {
+            status: StatusCode::from_u16(stored.status).ok()?,
+            headers,
+            body: STANDARD.decode(stored.body).ok()?.into(),
+            expires: UNIX_EPOCH + Duration::from_millis(stored.expires_at_ms),
+        })
+    }
+
+    fn to_stored(&self) -> Stored {
+        Stored {
+            status: self.status.as_u16(),
+            headers: self
+                .headers
+                .iter()
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.into())))
+                .collect(),
+            body: STANDARD.encode(&self.body),
+            expires_at_ms: self
+                .expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+}
+
+pub struct LlmCache {
+    settings: LlmCacheSettings,
+    entries: Arc<Mutex<LruCache<String, Arc<Entry>>>>,
+}
+
+impl LlmCache {
+    /// Caché vacía o, con `dir`, con las entradas vigentes de ese directorio.
+    pub fn open(settings: LlmCacheSettings) -> anyhow::Result<Self> {
+        let size = NonZeroUsize::new(settings.max_entries()).unwrap_or(NonZeroUsize::MIN);
+        let mut entries = LruCache::new(size);
+        if let Some(dir) = settings.dir() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("No se pudo crear {}", dir.display()))?;
+            let now = SystemTime::now();
+            for file in std::fs::read_dir(dir)
+                .with_context(|| format!("No se pudo leer {}", dir.display()))?
+            {
+                let path = file?.path();
+                let Some(key) = entry_key(&path) else {
+                    continue;
+                };
+                let entry = std::fs::read(&path)
+                    .ok()
+                    .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                    .and_then(Entry::from_stored);
+                match entry {
+                    Some(entry) if entry.expires > now => {
+                        entries.push(key, Arc::new(entry));
+                    }
+                    Some(_) => {
+                        let _ = std::fs::remove_file(&path);
+                    }
+                    None => {
+                        warn!(path = %path.display(), "Entrada de la caché LLM ilegible; se ignora")
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            settings,
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    pub fn settings(&self) -> &LlmCacheSettings {
+        &self.settings
+    }
+
+    /// Clave de la petición a `path` con este body, o por qué no se guarda.
+    pub fn key(&self, path: &str, body: &[u8]) -> Lookup {
+        let Ok(Value::Object(mut request)) = serde_json::from_slice(body) else {
+            return Lookup::Bypass("body");
+        };
+        match request.get("stream") {
+            Some(Value::Bool(true)) if !self.settings.streams() => return Lookup::Bypass("stream"),
+            Some(Value::Bool(false)) => {
+                request.remove("stream");
+            }
+            _ => {}
+        }
+        let sampled = request
+            .get("temperature")
+            .and_then(Value::as_f64)
+            .is_some_and(|temperature| temperature > 0.0);
+        if sampled && !self.settings.sampled() {
+            return Lookup::Bypass("temperature");
+        }
+        request.remove("user");
+        // Object keys are sorted, so key order in the body does not matter.
+        let normalized = serde_json::to_vec(&request).expect("JSON serializable");
+        let mut hasher = digest::Context::new(&digest::SHA256);
+        hasher.update(path.as_bytes());
+        hasher.update(b"\n");
+        hasher.update(&normalized);
+        Lookup::Key(
+            hasher
+                .finish()
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+        )
+    }
+
+    /// Respuesta vigente para `key`.
+    pub fn lookup(&self, key: &str, metrics: &Metrics) -> Option<Response<Body>> {
+        let mut entries = self.entries.lock().expect("lock de la caché LLM");
+        let hit = match entries.get(key) {
+            Some(entry) if entry.expires > SystemTime::now() => Some(entry.response()),
+            Some(_) => {
+                entries.pop(key);
+                self.remove_file(key);
+                None
+            }
+            None => None,
+        };
+        metrics.record_cache_lookup(CacheKind::Llm, hit.is_some());
+        hit
+    }
+
+    /// Marca `response` según `lookup` y, si puede guardarse, la copia
+    /// mientras se entrega para guardarla al terminar.
+    pub fn store(&self, lookup: Lookup, response: &mut Response<Body>, max_body: usize) {
+        let key = match lookup {
+            Lookup::Key(key) => {
+                response
+                    .headers_mut()
+                    .insert(X_LLM_CACHE, HeaderValue::from_static("MISS"));
+                key
+            }
+            Lookup::Bypass(reason) => {
+                debug!(reason, "Petición LLM fuera de la caché");
+                response
+                    .headers_mut()
+                    .insert(X_LLM_CACHE, HeaderValue::from_static("BYPASS"));
+                return;
+            }
+        };
+        let headers = response.headers();
+        let json = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("json"));
+        let cacheable = response.status() == StatusCode::OK
+            && !headers.contains_key("x-proxy-error")
+            && (json || (self.settings.streams() && streaming::is_event_stream(headers)));
+        if !cacheable {
+            return;
+        }
+        let encoding = match Encoding::of(headers) {
+            Ok(encoding) => encoding,
+            Err(unsupported) => {
+                warn!(encoding = %unsupported, "Respuesta LLM comprimida fuera de la caché");
+                return;
+            }
+        };
+        let mut headers = response.headers().clone();
+        headers.remove(X_LLM_CACHE);
+        if encoding.is_some() {
+            headers.remove(CONTENT_ENCODING);
+            headers.remove(CONTENT_LENGTH);
+        }
+        let entry = Entry {
+            status: response.status(),
+            headers,
+            body: Bytes::new(),
+            expires: SystemTime::now() + self.settings.ttl(),
+        };
+        let saver = Saver {
+            entries: self.entries.clone(),
+            dir: self.settings.dir().map(Path::to_path_buf),
+            key,
+            entry: Some(entry),
+        };
+        let body = Copy {
+            body: std::mem::take(response.body_mut()),
+            copy: Vec::new(),
+            decoder: encoding.map(|encoding| Decoder::new(encoding, max_body)),
+            max_body,
+            saver,
+        };
+        *response.body_mut() = Body::wrap_stream(body);
+    }
+
+    fn remove_file(&self, key: &str) {
+        if let Some(dir) = self.settings.dir() {
+            let _ = std::fs::remove_file(entry_path(dir, key));
+        }
+    }
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{key}.json"))
+}
+
+/// La clave de un archivo de entrada, si lo es.
+fn entry_key(path: &Path) -> Option<String> {
+    if path.extension()? != "json" {
+        return None;
+    }
+    let key = path.file_stem()?.to_str()?;
+    key.bytes()
+        .all(|byte| byte.is_ascii_hexdigit())
+        .then(|| key.to_string())
+}
+
+/// Guarda la entrada cuando el body termina completo.
+struct Saver {
+    entries: Arc<Mutex<LruCache<String, Arc<Entry>>>>,
+    dir: Option<PathBuf>,
+    key: String,
+    entry: Option<Entry>,
+}
+
+impl Saver {
+    fn save(&mut self, body: Vec<u8>) {
+        let Some(mut entry) = self.entry.take() else {
+            return;
+        };
+        entry.body = body.into();
+        let entry = Arc::new(entry);
+        if let Some(dir) = &self.dir {
+            let stored = serde_json::to_vec(&entry.to_stored()).expect("entrada serializable");
+            let path = entry_path(dir, &self.key);
+            tokio::spawn(async move {
+                let tmp = path.with_extension("tmp");
+                let written = async {
+                    tokio::fs::write(&tmp, stored).await?;
+                    tokio::fs::rename(&tmp, &path).await
+                };
+                if let Err(e) = written.await {
+                    warn!(path = %path.display(), error = %e, "No se pudo guardar la entrada de la caché LLM");
+                }
+            });
+        }
+        let evicted = self
+            .entries
+            .lock()
+            .expect("lock de la caché LLM")
+            .push(self.key.clone(), entry);
+        if let (Some((evicted, _)), Some(dir)) = (evicted, &self.dir) {
+            if evicted != self.key {
+                let _ = std::fs::remove_file(entry_path(dir, &evicted));
+            }
+        }
+        debug!(key = %self.key, "Respuesta LLM guardada en caché");
+    }
+}
+
+/// Body que se entrega según llega y se copia hasta `max_body`.
+struct Copy {
+    body: Body,
+    copy: Vec<u8>,
+    /// Con el body comprimido: la copia se guarda descomprimida.
+    decoder: Option<Decoder>,
+    max_body: usize,
+    saver: Saver,
+}
+
+impl Copy {
+    /// Añade `chunk` a la copia; `None` es el final del body.
+    fn keep(&mut self, chunk: Option<&[u8]>) {
+        if self.saver.entry.is_none() {
+            return;
+        }
+        let decoded = match (&mut self.decoder, chunk) {
+            (None, _) => None,
+            (Some(decoder), Some(chunk)) => Some(decoder.feed(chunk)),
+            (Some(decoder), None) => Some(decoder.finish()),
+        };
+        let plain = match &decoded {
+            None => chunk.unwrap_or_default(),
+            Some(Ok(plain)) => plain.as_slice(),
+            Some(Err(error)) => {
+                debug!(%error, "Respuesta LLM fuera de la caché");
+                self.saver.entry = None;
+                return;
+            }
+        };
+        // Too large: never stored.
+        if self.copy.len() + plain.len() > self.max_body {
+            self.saver.entry = None;
+            return;
+        }
+        self.copy.extend_from_slice(plain);
+    }
+}
+
+impl Stream for Copy {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = ready!(Pin::new(&mut self.body).poll_data(cx));
+        match &next {
+            Some(Ok(chunk)) => self.keep(Some(chunk)),
+            // Broken: never stored.
+            Some(Err(_)) => self.saver.entry = None,
+            None => {
+                self.keep(None);
+                let copy = std::mem::take(&mut self.copy);
+                self.saver.save(copy);
+            }
+        }
+        Poll::Ready(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_ignores_order_user_and_stream_false() {
+        let cache = LlmCache::open(LlmCacheSettings::default()).unwrap();
+        let key = |body: &str| cache.key("/v1/chat/completions", body.as_bytes());
+        let base =
+            key(r#"{"model":"m","messages":[{"role":"user","content":"hola"}],"temperature":0}"#);
+        assert!(matches!(base, Lookup::Key(_)));
+        assert_eq!(
+            key(
+                r#"{"temperature":0,"user":"ci-7","stream":false,"messages":[{"content":"hola","role":"user"}],"model":"m"}"#
+            ),
+            base
+        );
+        assert_ne!(
+            key(r#"{"model":"m","messages":[{"role":"user","content":"hola!"}],"temperature":0}"#),
+            base
+        );
+        assert_ne!(
+            cache.key(
+                "/v1/completions",
+                br#"{"model":"m","messages":[{"role":"user","content":"hola"}],"temperature":0}"#
+            ),
+            base
+        );
+        assert_eq!(
+            key(r#"{"model":"m","temperature":0.7}"#),
+            Lookup::Bypass("temperature")
+        );
+        assert_eq!(
+            key(r#"{"model":"m","stream":true}"#),
+            Lookup::Bypass("stream")
+        );
+        assert_eq!(key("[1,2]"), Lookup::Bypass("body"));
+
+        let lenient = LlmCache::open(
+            LlmCacheSettings::default()
+                .with_sampled(true)
+                .with_streams(true),
+        )
+        .unwrap();
+        assert!(matches!(
+            lenient.key(
+                "/v1/chat/completions",
+                br#"{"model":"m","temperature":0.7,"stream":true}"#
+            ),
+            Lookup::Key(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_entries_survive_a_restart_in_the_spill_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = LlmCacheSettings::default().with_dir(dir.path());
+        let metrics = Metrics::default();
+        let cache = LlmCache::open(settings.clone()).unwrap();
+        let Lookup::Key(key) = cache.key("/v1/embeddings", br#"{"model":"m","input":"x"}"#) else {
+            panic!("la petición debería poder guardarse");
+        };
+        let mut response = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"data":[]}"#))
+            .unwrap();
+        cache.store(Lookup::Key(key.clone()), &mut response, 1024);
+        assert_eq!(response.headers()[X_LLM_CACHE], "MISS");
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        // The file is written in the background.
+        let path = entry_path(dir.path(), &key);
+        for _ in 0..100 {
+            if path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let reopened = LlmCache::open(settings).unwrap();
+        let hit = reopened.lookup(&key, &metrics).unwrap();
+        assert_eq!(hit.headers()[X_LLM_CACHE], "HIT");
+        assert_eq!(hit.headers()[CONTENT_TYPE], "application/json");
+        let body = hyper::body::to_bytes(hit.into_body()).await.unwrap();
+        assert_eq!(body, r#"{"data":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_responses_are_stored_decompressed() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let plain = r#"{"choices":[{"message":{"content":"hola"}}]}"#;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(plain.as_bytes()).unwrap();
+        let gzip = Bytes::from(encoder.finish().unwrap());
+        let cache = LlmCache::open(LlmCacheSettings::default()).unwrap();
+        let store = |key: &str, max_body| {
+            let mut response = Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "gzip")
+                .header(CONTENT_LENGTH, gzip.len())
+                .body(Body::from(gzip.clone()))
+                .unwrap();
+            cache.store(Lookup::Key(key.to_string()), &mut response, max_body);
+            response
+        };
+
+        // The client of the miss still gets the compressed bytes.
+        let miss = store("a", 1024);
+        assert_eq!(miss.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(hyper::body::to_bytes(miss.into_body()).await.unwrap(), gzip);
+        let hit = cache.lookup("a", &Metrics::default()).unwrap();
+        assert!(!hit.headers().contains_key(CONTENT_ENCODING));
+        assert!(!hit.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(hyper::body::to_bytes(hit.into_body()).await.unwrap(), plain);
+
+        // The limit counts decompressed bytes.
+        let miss = store("b", plain.len() - 1);
+        hyper::body::to_bytes(miss.into_body()).await.unwrap();
+        assert!(cache.lookup("b", &Metrics::default()).is_none());
+    }
+}
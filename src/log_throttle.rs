@@ -0,0 +1,37 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limita la frecuencia de un log repetitivo y cuenta lo que se omitió.
+pub struct LogThrottle {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+    suppressed: AtomicU64,
+}
+
+impl LogThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(None),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Devuelve cuántos eventos se omitieron desde el último log, o `None`
+    /// si este también debe omitirse.
+    pub fn should_log(&self) -> Option<u64> {
+        let mut last = self.last.lock().expect("lock del throttle");
+        let now = Instant::now();
+        match *last {
+            Some(at) if now.duration_since(at) < self.interval => {
+                self.suppressed.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            _ => {
+                *last = Some(now);
+                Some(self.suppressed.swap(0, Ordering::Relaxed))
+            }
+        }
+    }
+}
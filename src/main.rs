@@ -1,55 +1,2854 @@
-use clap::{ArgAction, Parser};
-use std::net::SocketAddr;
-use tracing::{info, Level};
+use base64::Engine;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::EnvFilter;
 
-mod proxy;
-mod settings;
+use prueba_codex_proxy_ia::bench::{self, LoadGenerator};
+use prueba_codex_proxy_ia::config::{
+    AiRouterConfig, ArchiveConfig, AuthConfig, AuthUserConfig, BanConfig, BandwidthConfig,
+    CanaryConfig, CertExpiryConfig, ContentFiltersConfig, CredentialConfig, DataSaverConfig,
+    DnsConfig, EgressConfig, ErrorPagesConfig, ExpectContinueConfig, FileConfig,
+    HeaderLimitsConfig, HeaderRulesConfig, IdempotencyConfig, IdentityConfig, IdentityRuleConfig,
+    JobsConfig, ListenerConfig, LlmCacheConfig, LlmConfig, MetadataEchoConfig, OverloadConfig,
+    ProviderConfig, RequestIdConfig, ResolvedConfig, ResponseCacheConfig, ServePacConfig,
+    ServerTimingConfig, SigningConfig, SniffConfig, TokenBudgetConfig, TrailersConfig,
+    TransparentConfig, UpstreamList, UpstreamPoolConfig, UpstreamProxyConfig, UpstreamSocks5Config,
+    VerifySniConfig,
+};
+use prueba_codex_proxy_ia::conformance;
+use prueba_codex_proxy_ia::effective::{self, RequestFacts};
+use prueba_codex_proxy_ia::host_pattern::HostPattern;
+use prueba_codex_proxy_ia::jobs;
+use prueba_codex_proxy_ia::policy_sim::{self, Comparison, PolicySet, Report};
+use prueba_codex_proxy_ia::privacy::{log_fields, Sanitizer};
+use prueba_codex_proxy_ia::proxy::ProxyServer;
+use prueba_codex_proxy_ia::replay;
+use prueba_codex_proxy_ia::settings::{
+    AcceptRate, AdminToken, AffinitySettings, AiHealthSettings, AiRetrySettings, AiRouterSettings,
+    ArchiveKey, ArchiveSettings, AuthSettings, BalanceStrategy, BanSettings, BandwidthSettings,
+    BenchMode, BenchSettings, BenchTarget, CanarySettings, CaptureSettings, CertExpirySettings,
+    CertMatcher, ClientClass, ClientPoolSettings, ConcurrencyLimits, ConnectionLimits,
+    ContentFilterSettings, ContentRule, CredentialKind, CredentialSettings, DataSaverSettings,
+    DialSettings, DnsRewriteAction, DnsSettings, DrainSettings, EgressRule, EgressSettings,
+    ErrorPageRule, ErrorPageSettings, EventEndpoint, EventSettings, ExpectContinue, FeatureRollout,
+    HeaderDirection, HeaderLimits, HeaderRule, HostFilterSettings, IdempotencyRoute,
+    IdempotencySettings, IdentitySettings, JobSettings, KeySource, ListenerHardening,
+    ListenerProtocols, LlmCacheSettings, LlmSettings, LogProfile, MetadataEchoSettings,
+    MitmSettings, ModelPrice, Nat64Settings, OverloadSettings, PacSettings, ParentResolve,
+    PortMappingSettings, PrefetchSettings, ProfileSettings, ProviderSettings, ProxySettings,
+    QueueSettings, RateLimit, ReplaySettings, ReportSettings, ReputationSettings,
+    RequestIdSettings, ResourceSettings, ResponseCacheSettings, RolloutSettings, ScriptSettings,
+    ServePacSettings, ServerTimingSettings, SigningAlgorithm, SigningSettings, SniffRule,
+    SniffSettings, Socks5UpstreamSettings, SsrfSettings, StaleDefaults, StatsSettings,
+    ThrottleSettings, TicketSettings, TlsSettings, TokenBudgetSettings, TrailerFallback,
+    TransparentSettings, TunnelQualitySettings, UnixListenSettings, UnknownCertPolicy,
+    UpstreamProxySettings, UpstreamTlsSettings, VerifySniSettings,
+};
+use prueba_codex_proxy_ia::shutdown::EXIT_CONFIG;
 
-use crate::proxy::ProxyServer;
-use crate::settings::ProxySettings;
+const DEFAULT_LISTEN: &str = "0.0.0.0:8888";
+const DEFAULT_LOG_LEVEL: &str = "info";
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(name = "proxy-ia", version, about = "Proxy HTTP(S) con IA en Rust", long_about = None)]
 struct Cli {
-    /// Dirección de escucha en formato host:puerto (ej. 0.0.0.0:8888)
-    #[arg(short, long, default_value = "0.0.0.0:8888")]
-    listen: SocketAddr,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Archivo de configuración TOML; los flags de la CLI tienen prioridad
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Aplica la sección `[env.<nombre>]` de la configuración
+    #[arg(long, global = true)]
+    env: Option<String>,
+
+    /// Dirección de escucha en formato host:puerto [por defecto: 0.0.0.0:8888]
+    #[arg(short, long)]
+    listen: Option<SocketAddr>,
+
+    /// Escucha también en este socket UNIX (solo en Unix)
+    #[arg(long, value_name = "RUTA")]
+    listen_uds: Option<PathBuf>,
 
-    /// Nivel de logs (trace, debug, info, warn, error)
-    #[arg(long, default_value = "info")]
-    log_level: String,
+    /// Permisos del socket de --listen-uds en octal [por defecto: 660]
+    #[arg(long, value_name = "MODO")]
+    listen_uds_mode: Option<String>,
+
+    /// Nivel de logs (trace, debug, info, warn, error) [por defecto: info]
+    #[arg(long)]
+    log_level: Option<String>,
 
     /// Activa el modo silencioso (solo errores)
     #[arg(short, long, action = ArgAction::SetTrue)]
     quiet: bool,
+
+    /// Saneamiento de IPs y URLs en logs, estadísticas, capturas y archivo (full, truncate, hashed) [por defecto: full]
+    #[arg(long)]
+    log_profile: Option<String>,
+
+    /// Script Rhai con las funciones `route` y/o `filter` (requiere la feature `scripting`)
+    #[arg(long)]
+    script: Option<PathBuf>,
+
+    /// Bloquea la petición si el script falla (por defecto se deja pasar)
+    #[arg(long, action = ArgAction::SetTrue)]
+    script_fail_closed: bool,
+
+    /// Cierra conexiones de clientes inactivas durante más de N segundos (0 = sin límite)
+    #[arg(long)]
+    client_idle_timeout: Option<u64>,
+
+    /// Obliga a los clientes a reconectar tras N segundos de vida de la conexión (0 = sin límite)
+    #[arg(long)]
+    client_max_connection_lifetime: Option<u64>,
+
+    /// Cierra la conexión tras N peticiones (0 = sin límite)
+    #[arg(long)]
+    client_max_requests_per_connection: Option<u64>,
+
+    /// Dirección de la API de administración (desactivada si no se indica)
+    #[arg(long)]
+    admin_listen: Option<SocketAddr>,
+
+    /// JSON donde se guardan los hosts bloqueados con `/api/blocklist` (solo en memoria si no se indica)
+    #[arg(long)]
+    blocklist_file: Option<PathBuf>,
+
+    /// Dirección que sirve `/metrics` en formato de Prometheus (desactivada si no se indica)
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Peticiones por segundo que admite cada IP de cliente; por encima, 429
+    #[arg(long)]
+    rate_limit: Option<f64>,
+
+    /// Ráfaga de peticiones por IP por encima de `--rate-limit` (por defecto, las de un segundo)
+    #[arg(long)]
+    rate_burst: Option<u32>,
+
+    /// Peticiones y túneles simultáneos en todo el proxy; por encima, 503
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Túneles CONNECT abiertos por IP de cliente; por encima, 429
+    #[arg(long)]
+    max_tunnels: Option<usize>,
+
+    /// Escribe una línea JSON por petición terminada en esta ruta (`-` para la salida estándar)
+    #[arg(long)]
+    access_log: Option<String>,
+
+    /// Guarda en este directorio las peticiones que terminan en 5xx
+    #[arg(long)]
+    capture_dir: Option<PathBuf>,
+
+    /// Segundos que la parada espera a las conexiones abiertas antes de abortarlas [por defecto: 30]
+    #[arg(long, alias = "shutdown-timeout")]
+    drain_timeout: Option<u64>,
+
+    /// Segundos para abrir un túnel CONNECT (0 = sin límite) [por defecto: 10]
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Segundos hasta la respuesta del destino en una petición HTTP (0 = sin límite) [por defecto: 60]
+    #[arg(long)]
+    request_timeout: Option<u64>,
+
+    /// Bytes como mucho en el body de una petición HTTP; más recibe un 413 [por defecto: sin límite]
+    #[arg(long)]
+    max_request_body: Option<u64>,
+
+    /// Bytes como mucho en el body de una respuesta del destino [por defecto: sin límite]
+    #[arg(long)]
+    max_response_body: Option<u64>,
+
+    /// Habla solo HTTP/1.1 con los destinos https://, sin negociar HTTP/2
+    #[arg(long, action = ArgAction::SetTrue)]
+    http1_only: bool,
+
+    /// Abre una conexión nueva con el destino en cada petición, sin pool, para depurar
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_pool: bool,
+
+    /// Conexiones ociosas que se guardan por destino [por defecto: sin límite]
+    #[arg(long)]
+    pool_max_idle_per_host: Option<usize>,
+
+    /// Tiempo que una conexión ociosa con un destino espera a la siguiente petición, como 90s o 5m [por defecto: 90s]
+    #[arg(long, value_parser = bench::parse_duration)]
+    pool_idle_timeout: Option<Duration>,
+
+    /// Envía Connection: close a los destinos HTTP/1.1, que cierran tras cada respuesta
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_http1_keep_alive: bool,
+
+    /// Activa TCP_NODELAY en las conexiones con los destinos
+    #[arg(long, action = ArgAction::SetTrue)]
+    tcp_nodelay: bool,
+
+    /// Sondas TCP keepalive tras este tiempo sin tráfico con un destino, como 60s [por defecto: sin keepalive]
+    #[arg(long, value_parser = bench::parse_duration)]
+    tcp_keepalive: Option<Duration>,
+
+    /// Añade cabeceras de depuración a las respuestas, como X-Upstream en las del router IA
+    #[arg(long, action = ArgAction::SetTrue)]
+    debug_headers: bool,
+
+    /// Intentos por petición GET/HEAD/OPTIONS ante fallos de conexión o 502/503/504 del destino [por defecto: 1, sin reintentos]
+    #[arg(long)]
+    retry_attempts: Option<u32>,
+
+    /// Milisegundos antes del primer reintento; se doblan en cada uno, con variación aleatoria [por defecto: 100]
+    #[arg(long)]
+    retry_base_delay_ms: Option<u64>,
+
+    /// Bytes por segundo hacia cada conexión de cliente, en respuestas y túneles (0 = sin límite)
+    #[arg(long)]
+    max_rate_down: Option<u64>,
+
+    /// Bytes por segundo desde cada conexión de cliente, en peticiones y túneles (0 = sin límite)
+    #[arg(long)]
+    max_rate_up: Option<u64>,
+
+    /// Certificado PEM con el que el proxy atiende a los clientes sobre TLS (requiere --tls-key)
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// Clave privada PEM del certificado de --tls-cert
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// CA PEM con la que se descifran los túneles CONNECT (requiere --mitm-ca-key)
+    #[arg(long)]
+    mitm_ca_cert: Option<PathBuf>,
+
+    /// Clave privada PKCS#8 de la CA de --mitm-ca-cert
+    #[arg(long)]
+    mitm_ca_key: Option<PathBuf>,
+
+    /// Puertos a los que se admite CONNECT, separados por comas [por defecto: 443]
+    #[arg(long, value_delimiter = ',')]
+    connect_ports: Vec<u16>,
+
+    /// Rechaza con 403 los destinos que resuelven a redes privadas, de loopback o link-local
+    #[arg(long, action = ArgAction::SetTrue)]
+    ssrf_protect: bool,
+
+    /// Rangos CIDR que también se rechazan, separados por comas
+    #[arg(long, value_delimiter = ',')]
+    blocked_networks: Vec<String>,
+
+    /// Sube el límite blando de descriptores hasta el duro al arrancar
+    #[arg(long, action = ArgAction::SetTrue)]
+    raise_nofile: bool,
+
+    /// Encadena todo el tráfico por este proxy padre (http://host:puerto)
+    #[arg(long)]
+    upstream_proxy: Option<String>,
+
+    /// Conecta con los destinos a través de este servidor SOCKS5 (host:puerto)
+    #[arg(long)]
+    upstream_socks5: Option<String>,
+
+    /// Sirve en GET /proxy.pac un PAC que apunta a este proxy
+    #[arg(long, action = ArgAction::SetTrue)]
+    serve_pac: bool,
+
+    /// host:puerto que anuncia el PAC de --serve-pac [por defecto: el de la petición]
+    #[arg(long)]
+    pac_advertise: Option<String>,
+
+    /// Acepta peticiones con URI relativa y las reenvía al destino de su cabecera Host
+    #[arg(long, action = ArgAction::SetTrue)]
+    transparent: bool,
+
+    /// Esquema de los destinos del modo transparente (http o https) [por defecto: http]
+    #[arg(long)]
+    transparent_scheme: Option<String>,
+
+    /// Cierra los túneles CONNECT cuyo SNI no es el destino pedido
+    #[arg(long, action = ArgAction::SetTrue)]
+    verify_sni: bool,
+
+    /// Pide al router por UPnP o NAT-PMP que abra el puerto del listener
+    #[arg(long, action = ArgAction::SetTrue)]
+    upnp: bool,
+
+    /// Puerto externo que se pide al router con --upnp [por defecto: el del listener]
+    #[arg(long, requires = "upnp")]
+    upnp_external_port: Option<u16>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Inspección de la configuración
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    /// Ejecuta la suite de conformidad contra un proxy local
+    Conformance,
+    /// Herramientas para el tráfico hacia backends LLM
+    Llm {
+        #[command(subcommand)]
+        action: LlmCommand,
+    },
+    /// Herramientas para la política de destinos
+    Policy {
+        #[command(subcommand)]
+        action: PolicyCommand,
+    },
+    /// Muestra los valores que el proxy en marcha aplicaría a una petición y la capa que fijó cada uno
+    Explain {
+        /// Host de destino
+        #[arg(long)]
+        host: String,
+
+        /// IP del cliente
+        #[arg(long)]
+        client: IpAddr,
+
+        /// Método de la petición
+        #[arg(long, default_value = "GET")]
+        method: hyper::Method,
+
+        /// Puerto de destino [por defecto: 443 con CONNECT, 80 en el resto]
+        #[arg(long)]
+        port: Option<u16>,
+
+        /// Usuario de la identidad mTLS del cliente
+        #[arg(long)]
+        user: Option<String>,
+
+        /// Perfil de la identidad mTLS del cliente
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// API de administración del proxy en marcha (por defecto, la de --config)
+        #[arg(long)]
+        admin: Option<SocketAddr>,
+
+        /// Formato de la salida
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
+    /// Mide la capacidad del proxy con carga generada en este proceso
+    Bench {
+        /// Origen de las peticiones: `self` levanta uno local; si no, `host:puerto`
+        #[arg(long, default_value = "self")]
+        target: BenchTarget,
+
+        /// Duración de la carga (`500ms`, `60s`, `5m`, `2h`) [por defecto: 60s, 4h con --soak]
+        #[arg(long, value_parser = bench::parse_duration)]
+        duration: Option<Duration>,
+
+        /// Conexiones simultáneas con el proxy
+        #[arg(long, default_value_t = BenchSettings::DEFAULT_CONNECTIONS)]
+        connections: usize,
+
+        /// Tráfico que se genera: http, connect o mixed
+        #[arg(long, default_value = "http")]
+        mode: BenchMode,
+
+        /// Proxy bajo prueba (por defecto, uno con --config en este mismo proceso)
+        #[arg(long)]
+        proxy_addr: Option<SocketAddr>,
+
+        /// API de administración del proxy de --proxy-addr (por defecto, la de --config)
+        #[arg(long, requires = "proxy_addr")]
+        admin: Option<SocketAddr>,
+
+        /// Prueba de resistencia: larga y con puntos de control
+        #[arg(long)]
+        soak: bool,
+
+        /// Intervalo entre puntos de control [por defecto: 5m con --soak]
+        #[arg(long, value_parser = bench::parse_duration)]
+        checkpoint: Option<Duration>,
+
+        /// Hilos que generan la carga, aparte de los del proxy
+        #[arg(long, default_value_t = BenchSettings::DEFAULT_LOAD_THREADS)]
+        load_threads: usize,
+
+        /// Formato del informe
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum PolicyCommand {
+    /// Evalúa tráfico grabado con la política de --config, sin abrir conexiones
+    Simulate {
+        /// Log de acceso en JSON o directorio de capturas
+        #[arg(long)]
+        input: PathBuf,
+
+        /// Configuración con la que comparar
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+
+        /// API de administración del proxy en marcha (por defecto, la de --config)
+        #[arg(long)]
+        admin: Option<SocketAddr>,
+
+        /// Formato del informe
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ReportFormat {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum LlmCommand {
+    /// Reproduce las capturas contra otro backend y compara las respuestas
+    Replay {
+        /// Directorio de capturas
+        #[arg(long)]
+        from: PathBuf,
+
+        /// Backend que recibe las peticiones (http://host:puerto)
+        #[arg(long)]
+        target: hyper::Uri,
+
+        /// Reemplazo de modelos, `original=nuevo`; se puede repetir
+        #[arg(long = "model-map", value_parser = parse_model_map, value_delimiter = ',')]
+        model_map: Vec<(String, String)>,
+
+        /// Peticiones por segundo
+        #[arg(long, default_value_t = ReplaySettings::DEFAULT_RATE_PER_SEC)]
+        rate: f64,
+
+        /// Omite las peticiones con marcas de redacción en la URL o el body
+        #[arg(long)]
+        skip_redacted: bool,
+
+        /// Directorio de resultados (por defecto `<from>/replay`)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+fn parse_model_map(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!("se esperaba `original=nuevo`: {value}")),
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ConfigCommand {
+    /// Muestra la configuración
+    Show {
+        /// Muestra el resultado de combinar includes y el entorno elegido
+        #[arg(long)]
+        resolved: bool,
+
+        /// Anota cada valor con el archivo que lo definió (implica --resolved)
+        #[arg(long)]
+        explain: bool,
+    },
+}
+
+/// Códigos de salida: 0 parada limpia, 1 error fatal, 2 drenaje agotado y
+/// [`EXIT_CONFIG`] si la configuración no permite arrancar.
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
+    let resolved = match &cli.config {
+        Some(path) => ResolvedConfig::load(path, cli.env.as_deref()),
+        None => Ok(ResolvedConfig::default()),
+    };
+    let resolved = match resolved {
+        Ok(resolved) => resolved,
+        Err(e) => return config_error(e),
+    };
+
+    let result = match &cli.command {
+        Some(Command::Config { action }) => show_config(&cli, action, &resolved),
+        Some(Command::Conformance) => run_conformance().await,
+        Some(Command::Llm { action }) => run_llm(action).await,
+        Some(Command::Policy { action }) => run_policy(&cli, action, &resolved).await,
+        Some(Command::Explain {
+            host,
+            client,
+            method,
+            port,
+            user,
+            profile,
+            admin,
+            format,
+        }) => {
+            let facts = RequestFacts::hypothetical(method.clone(), host, *port, *client)
+                .with_identity(user.clone(), profile.clone());
+            run_explain(&cli, &facts, *admin, *format, &resolved).await
+        }
+        Some(Command::Bench {
+            target,
+            duration,
+            connections,
+            mode,
+            proxy_addr,
+            admin,
+            soak,
+            checkpoint,
+            load_threads,
+            format,
+        }) => {
+            let default_duration = match soak {
+                true => BenchSettings::DEFAULT_SOAK_DURATION,
+                false => BenchSettings::DEFAULT_DURATION,
+            };
+            let mut settings = BenchSettings::new(target.clone(), *mode)
+                .with_duration(duration.unwrap_or(default_duration))
+                .with_connections(*connections)
+                .with_load_threads(*load_threads);
+            if let Some(every) = checkpoint.or(soak.then_some(BenchSettings::DEFAULT_CHECKPOINT)) {
+                settings = settings.with_checkpoint(every);
+            }
+            run_bench(&cli, settings, *proxy_addr, *admin, *format, &resolved).await
+        }
+        None => return serve(&cli, &resolved).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    init_tracing(&cli);
-    let settings = ProxySettings::new(cli.listen);
-    let server = ProxyServer::new(settings);
+async fn serve(cli: &Cli, resolved: &ResolvedConfig) -> ExitCode {
+    let file = match resolved.parse() {
+        Ok(file) => file,
+        Err(e) => return config_error(e),
+    };
+    // A bad profile is reported by build_settings; until then logs are full.
+    let sanitizer = Arc::new(Sanitizer::new(log_profile(cli, &file).unwrap_or_default()));
+    init_tracing(cli, &file, sanitizer.clone());
+    let settings = build_settings(cli, &file).map(|settings| match &cli.config {
+        Some(_) => settings.with_file_config(resolved.redacted()),
+        None => settings,
+    });
+    let server = match settings.and_then(ProxyServer::new) {
+        Ok(server) => server.with_sanitizer(sanitizer),
+        Err(e) => return config_error(e),
+    };
+    if let Some(path) = cli.config.clone() {
+        tokio::spawn(watch_config(path.clone(), cli.env.clone(), server.clone()));
+        tokio::spawn(reload_on_hangup(cli.clone(), path, server.clone()));
+    }
 
     info!(address = %server.address(), "Iniciando proxy");
-    server.run().await
+    let report = server.run().await;
+    ExitCode::from(report.outcome.exit_code())
+}
+
+fn config_error(e: anyhow::Error) -> ExitCode {
+    eprintln!("Error de configuración: {e:?}");
+    ExitCode::from(EXIT_CONFIG)
+}
+
+/// Relee la configuración periódicamente y aplica los cambios de
+/// `[rollout]`, `[dns]` y `fast_path`; el resto de secciones requiere
+/// reiniciar.
+async fn watch_config(path: PathBuf, env: Option<String>, server: ProxyServer) {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let reloaded = ResolvedConfig::load(&path, env.as_deref())
+            .and_then(|resolved| resolved.parse())
+            .and_then(|file| {
+                Ok((
+                    rollout_settings(&file)?,
+                    dns_settings(file.dns.as_ref())?,
+                    fast_path(&file)?,
+                ))
+            });
+        match reloaded {
+            Ok((rollouts, dns, fast_path)) => {
+                server.rollouts().reload(rollouts);
+                server.dns().reload(dns);
+                server.fast_path().reload(fast_path);
+            }
+            Err(e) => warn!(error = %e, "No se pudo recargar la configuración"),
+        }
+    }
+}
+
+/// Con cada `SIGHUP` relee la configuración y aplica `[host_filter]`,
+/// `[header_rules]`, `[rate_limit]`, `[upstream_proxy]` y la lista de
+/// revocación de `[identity]` sin cortar conexiones; si no es válida, se
+/// sigue con la anterior.
+async fn reload_on_hangup(cli: Cli, path: PathBuf, server: ProxyServer) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            error!("No se pudo instalar el manejador de SIGHUP de la configuración");
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            reload_settings(&cli, &path, &server);
+        }
+    }
+    #[cfg(not(unix))]
+    drop((cli, path, server));
+}
+
+fn reload_settings(cli: &Cli, path: &Path, server: &ProxyServer) {
+    let reloaded = ResolvedConfig::load(path, cli.env.as_deref())
+        .and_then(|resolved| resolved.parse())
+        .and_then(|file| build_settings(cli, &file))
+        .and_then(|settings| server.reload(&settings));
+    match reloaded {
+        Ok(changes) if changes.is_empty() => info!("SIGHUP: la configuración recargable no cambió"),
+        Ok(changes) => info!(sections = changes.len(), "SIGHUP: configuración recargada"),
+        Err(e) => error!(
+            error = %e,
+            "SIGHUP: configuración inválida, se mantiene la anterior"
+        ),
+    }
+}
+
+fn show_config(cli: &Cli, action: &ConfigCommand, resolved: &ResolvedConfig) -> anyhow::Result<()> {
+    let ConfigCommand::Show {
+        resolved: merged,
+        explain,
+    } = action;
+    let Some(path) = &cli.config else {
+        anyhow::bail!("`config show` requiere --config <archivo>");
+    };
+    // The same checks as startup, so what is shown is what would run.
+    build_settings(cli, &resolved.parse()?)?;
+    if *explain {
+        print!("{}", resolved.explain());
+    } else if *merged {
+        print!("{}", resolved.to_toml());
+    } else {
+        print!("{}", std::fs::read_to_string(path)?);
+    }
+    Ok(())
+}
+
+async fn run_conformance() -> anyhow::Result<()> {
+    let outcomes = conformance::run_suite().await?;
+    for outcome in &outcomes {
+        println!("{outcome}");
+    }
+    let failed = conformance::failures(&outcomes);
+    if failed > 0 {
+        anyhow::bail!("{failed} comprobaciones de conformidad fallaron");
+    }
+    Ok(())
+}
+
+async fn run_llm(action: &LlmCommand) -> anyhow::Result<()> {
+    let LlmCommand::Replay {
+        from,
+        target,
+        model_map,
+        rate,
+        skip_redacted,
+        out,
+    } = action;
+    let mut settings = ReplaySettings::new(from, target.clone())
+        .with_rate_per_sec(*rate)
+        .with_skip_redacted(*skip_redacted);
+    for (original, replacement) in model_map {
+        settings = settings.with_model(original, replacement);
+    }
+    if let Some(out) = out {
+        settings = settings.with_out(out);
+    }
+    let report = replay::replay(&settings).await?;
+    println!("{report}");
+    println!("Resultados en {}", settings.out().display());
+    Ok(())
+}
+
+async fn run_policy(
+    cli: &Cli,
+    action: &PolicyCommand,
+    resolved: &ResolvedConfig,
+) -> anyhow::Result<()> {
+    let PolicyCommand::Simulate {
+        input,
+        baseline,
+        admin,
+        format,
+    } = action;
+    if cli.config.is_none() {
+        anyhow::bail!("`policy simulate` requiere --config <archivo>");
+    }
+    let settings = build_settings(cli, &resolved.parse()?)?;
+    let input = policy_sim::read_input(input)?;
+    let (candidate, mut report) = simulate(cli, &settings, &input, baseline.as_deref())?;
+    if let Some(addr) = admin.or(settings.admin_listen()) {
+        let addr = reachable(addr);
+        match policy_sim::live_decisions(addr, settings.admin_token(), &input.targets).await {
+            Ok(reference) => {
+                report = report.with_comparison(Comparison::between(
+                    "live",
+                    &input.targets,
+                    &candidate,
+                    &reference,
+                ));
+            }
+            Err(e) => eprintln!("Aviso: sin comparación con la política en marcha: {e}"),
+        }
+    }
+    match format {
+        ReportFormat::Table => print!("{report}"),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+async fn run_explain(
+    cli: &Cli,
+    facts: &RequestFacts,
+    admin: Option<SocketAddr>,
+    format: ReportFormat,
+    resolved: &ResolvedConfig,
+) -> anyhow::Result<()> {
+    let settings = build_settings(cli, &resolved.parse()?)?;
+    let Some(addr) = admin.or(settings.admin_listen()) else {
+        anyhow::bail!(
+            "`explain` necesita la API de administración: --admin o admin_listen en --config"
+        );
+    };
+    let explanation = effective::fetch(reachable(addr), settings.admin_token(), facts).await?;
+    match format {
+        ReportFormat::Table => print!("{explanation}"),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&explanation)?),
+    }
+    Ok(())
+}
+
+/// Sin `proxy_addr`, el proxy de --config se levanta en este proceso, en
+/// puertos libres de loopback y con la API de administración abierta.
+async fn run_bench(
+    cli: &Cli,
+    mut bench: BenchSettings,
+    proxy_addr: Option<SocketAddr>,
+    admin: Option<SocketAddr>,
+    format: ReportFormat,
+    resolved: &ResolvedConfig,
+) -> anyhow::Result<()> {
+    let settings = build_settings(cli, &resolved.parse()?)?;
+    if let Some(token) = settings.admin_token() {
+        bench = bench.with_admin_token(token.clone());
+    }
+    let load = LoadGenerator::new(bench)?;
+    let (proxy, admin, handle) = match proxy_addr {
+        Some(addr) => (addr, admin.or(settings.admin_listen()).map(reachable), None),
+        None => {
+            let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+            // The tunnels in `connect` mode go to the origin's port.
+            let mut ports = settings.connect_ports().to_vec();
+            ports.extend(load.origin_port());
+            let server = ProxyServer::new(
+                settings
+                    .with_listen(loopback)
+                    .with_admin_listen(loopback)
+                    .with_connect_ports(ports),
+            )?;
+            let handle = server.start().await?;
+            (handle.local_addr(), handle.admin_addr(), Some(handle))
+        }
+    };
+    eprintln!("Carga a través de {proxy} hacia {}", load.origin());
+    let report = load
+        .run(proxy, admin, move |checkpoint| match format {
+            ReportFormat::Table => eprintln!("{checkpoint}"),
+            ReportFormat::Json => match serde_json::to_string(checkpoint) {
+                Ok(line) => eprintln!("{line}"),
+                Err(e) => eprintln!("Punto de control ilegible: {e}"),
+            },
+        })
+        .await;
+    if let Some(handle) = handle {
+        handle.shutdown();
+        handle.wait().await;
+    }
+    let report = report?;
+    match format {
+        ReportFormat::Table => println!("{report}"),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+/// Evalúa `input` con `settings` y, si se indica, con la configuración
+/// `baseline`, que se lee con los mismos flags de la CLI.
+fn simulate(
+    cli: &Cli,
+    settings: &ProxySettings,
+    input: &policy_sim::Input,
+    baseline: Option<&Path>,
+) -> anyhow::Result<(Vec<policy_sim::Decision>, Report)> {
+    let candidate = PolicySet::from_settings(settings)
+        .policies()
+        .evaluate_all(&input.targets);
+    let mut report = Report::new(input, &candidate);
+    if let Some(path) = baseline {
+        let file = ResolvedConfig::load(path, cli.env.as_deref())?.parse()?;
+        let reference = PolicySet::from_settings(&build_settings(cli, &file)?)
+            .policies()
+            .evaluate_all(&input.targets);
+        report = report.with_comparison(Comparison::between(
+            path.display().to_string(),
+            &input.targets,
+            &candidate,
+            &reference,
+        ));
+    }
+    Ok((candidate, report))
+}
+
+/// A wildcard listener is reached through loopback.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+fn build_settings(cli: &Cli, file: &FileConfig) -> anyhow::Result<ProxySettings> {
+    let listen = match cli.listen.or(file.listen) {
+        Some(listen) => listen,
+        None => DEFAULT_LISTEN.parse()?,
+    };
+
+    let secs = |cli: Option<u64>, file: Option<u64>| Duration::from_secs(cli.or(file).unwrap_or(0));
+    let limits = ConnectionLimits::default()
+        .with_idle_timeout(secs(cli.client_idle_timeout, file.client_idle_timeout))
+        .with_max_lifetime(secs(
+            cli.client_max_connection_lifetime,
+            file.client_max_connection_lifetime,
+        ))
+        .with_max_requests(
+            cli.client_max_requests_per_connection
+                .or(file.client_max_requests_per_connection)
+                .unwrap_or(0),
+        );
+    let mut settings = ProxySettings::new(listen).with_connection_limits(limits);
+    if let Some(uds) = listen_uds_settings(cli, file)? {
+        settings = settings.with_listen_uds(uds);
+    }
+    if let Some(secs) = cli.drain_timeout.or(file.drain_timeout) {
+        settings = settings.with_drain_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = cli.connect_timeout.or(file.connect_timeout) {
+        settings = settings.with_connect_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = cli.request_timeout.or(file.request_timeout) {
+        settings = settings.with_request_timeout(Duration::from_secs(secs));
+    }
+    if let Some(max) = cli.max_request_body.or(file.max_request_body) {
+        settings = settings.with_max_request_body(max);
+    }
+    if let Some(max) = cli.max_response_body.or(file.max_response_body) {
+        settings = settings.with_max_response_body(max);
+    }
+    settings = settings.with_http1_only(cli.http1_only || file.http1_only.unwrap_or(false));
+    if let Some(upstream_tls) = &file.upstream_tls {
+        let mut tls = UpstreamTlsSettings::default();
+        if let Some(size) = upstream_tls.session_cache_size {
+            tls = tls.with_session_cache_size(size);
+        }
+        let hosts = upstream_tls
+            .disable_resumption
+            .iter()
+            .map(|host| host.parse());
+        tls = tls.with_no_resumption(hosts.collect::<anyhow::Result<_>>()?);
+        settings = settings.with_upstream_tls(tls);
+    }
+    settings = settings.with_client_pool(client_pool_settings(cli, file)?);
+    settings =
+        settings.with_debug_headers(cli.debug_headers || file.debug_headers.unwrap_or(false));
+    if let Some(attempts) = cli.retry_attempts.or(file.retry_attempts) {
+        settings = settings.with_retry_attempts(attempts);
+    }
+    if let Some(ms) = cli.retry_base_delay_ms.or(file.retry_base_delay_ms) {
+        settings = settings.with_retry_base_delay(Duration::from_millis(ms));
+    }
+    settings = settings.with_throttle(
+        ThrottleSettings::default()
+            .with_down(cli.max_rate_down.or(file.max_rate_down).unwrap_or(0))
+            .with_up(cli.max_rate_up.or(file.max_rate_up).unwrap_or(0)),
+    );
+    let connect_ports = match (&cli.connect_ports[..], &file.connect_ports) {
+        ([], Some(ports)) => Some(ports.clone()),
+        ([], None) => None,
+        (ports, _) => Some(ports.to_vec()),
+    };
+    if let Some(ports) = connect_ports {
+        anyhow::ensure!(
+            !ports.is_empty() && !ports.contains(&0),
+            "connect_ports necesita al menos un puerto, y ninguno puede ser 0"
+        );
+        settings = settings.with_connect_ports(ports);
+    }
+    let mut ssrf = SsrfSettings::default().with_block_private_networks(
+        cli.ssrf_protect || file.block_private_networks.unwrap_or(false),
+    );
+    let blocked_networks = match (&cli.blocked_networks[..], &file.blocked_networks) {
+        ([], Some(ranges)) => &ranges[..],
+        (ranges, _) => ranges,
+    };
+    for range in blocked_networks {
+        ssrf = ssrf.with_blocked_network(range.parse()?);
+    }
+    settings = settings.with_ssrf(ssrf);
+    if let Some(listener) = &file.listener {
+        settings = settings
+            .with_protocols(listener_protocols(listener)?)
+            .with_hardening(listener_hardening(listener)?);
+    }
+    if let Some(tls) = tls_settings(cli, file)? {
+        settings = settings.with_tls(tls);
+    }
+    if let Some(mitm) = mitm_settings(cli, file)? {
+        settings = settings.with_mitm(mitm);
+    }
+    if let Some(cert_expiry) = &file.cert_expiry {
+        settings = settings.with_cert_expiry(cert_expiry_settings(cert_expiry)?);
+    }
+    if let Some(port_mapping) = port_mapping_settings(cli, file, listen)? {
+        settings = settings.with_port_mapping(port_mapping);
+    }
+
+    let file_script = file.script.as_ref();
+    if let Some(path) = cli.script.as_ref().or(file_script.map(|s| &s.path)) {
+        let fail_closed =
+            cli.script_fail_closed || file_script.and_then(|s| s.fail_closed).unwrap_or(false);
+        let mut script = ScriptSettings::new(path).with_fail_closed(fail_closed);
+        if let Some(ops) = file_script.and_then(|s| s.max_operations) {
+            script = script.with_max_operations(ops);
+        }
+        if let Some(ms) = file_script.and_then(|s| s.timeout_ms) {
+            script = script.with_timeout(Duration::from_millis(ms));
+        }
+        settings = settings.with_script(script);
+    }
+
+    if let Some(file) = &file.reputation {
+        let mut reputation = ReputationSettings::new(&file.url);
+        if let Some(ms) = file.timeout_ms {
+            reputation = reputation.with_timeout(Duration::from_millis(ms));
+        }
+        if let Some(size) = file.cache_size {
+            reputation = reputation.with_cache_size(size);
+        }
+        if let Some(secs) = file.cache_ttl_secs {
+            reputation = reputation.with_cache_ttl(Duration::from_secs(secs));
+        }
+        if let Some(ms) = file.batch_window_ms {
+            reputation = reputation.with_batch_window(Duration::from_millis(ms));
+        }
+        if let Some(max) = file.max_batch {
+            reputation = reputation.with_max_batch(max);
+        }
+        if let Some(check) = file.check_clients {
+            reputation = reputation.with_check_clients(check);
+        }
+        if let Some(fail_open) = file.fail_open {
+            reputation = reputation.with_fail_open(fail_open);
+        }
+        for threshold in &file.thresholds {
+            reputation = reputation.with_threshold(threshold.min_score, threshold.action.parse()?);
+        }
+        settings = settings.with_reputation(reputation);
+    }
+
+    if let Some(file) = &file.connect {
+        let mut dial = DialSettings::default();
+        if let Some(width) = file.race_width {
+            dial = dial.with_race_width(width);
+        }
+        if let Some(ms) = file.stagger_ms {
+            dial = dial.with_stagger(Duration::from_millis(ms));
+        }
+        if let Some(secs) = file.score_half_life_secs {
+            dial = dial.with_score_half_life(Duration::from_secs(secs));
+        }
+        if let Some(max) = file.max_scored_hosts {
+            dial = dial.with_max_scored_hosts(max);
+        }
+        if let Some(family) = &file.prefer_family {
+            dial = dial.with_prefer_family(family.parse()?);
+        }
+        settings = settings.with_dial(dial);
+    }
+
+    for credential in &file.credentials {
+        settings = settings.with_credential(credential_settings(credential)?);
+    }
+    for provider in &file.providers {
+        settings = settings.with_provider(provider_settings(provider)?);
+    }
+
+    for signing in &file.signing {
+        settings = settings.with_signing(signing_settings(signing)?);
+    }
+
+    if let Some(file) = &file.affinity {
+        let mut affinity = AffinitySettings::default();
+        for host in &file.hosts {
+            affinity = affinity.with_host(host.parse()?);
+        }
+        if let Some(max) = file.max_connections_per_client {
+            affinity = affinity.with_max_connections_per_client(max);
+        }
+        if let Some(auto_detect) = file.auto_detect {
+            affinity = affinity.with_auto_detect(auto_detect);
+        }
+        settings = settings.with_affinity(affinity);
+    }
+
+    if let Some(file) = &file.stats {
+        let mut stats = StatsSettings::new(&file.path);
+        if let Some(days) = file.retention_days {
+            stats = stats.with_retention_days(days);
+        }
+        if let Some(secs) = file.flush_interval_secs {
+            stats = stats.with_flush_interval(Duration::from_secs(secs.max(1)));
+        }
+        settings = settings.with_stats(stats);
+    }
+
+    if let Some(file) = &file.report {
+        let mut report = ReportSettings::new();
+        if let Some(schedule) = &file.schedule {
+            report = report.with_schedule(schedule.parse()?);
+        }
+        if let Some(days) = file.period_days {
+            report = report.with_period_days(days);
+        }
+        if let Some(top) = file.top {
+            report = report.with_top(top);
+        }
+        if let Some(path) = &file.template {
+            report = report.with_template(path);
+        }
+        if let Some(url) = &file.webhook {
+            report = report.with_webhook(url);
+        }
+        if let Some(dir) = &file.dir {
+            report = report.with_dir(dir);
+        }
+        settings = settings.with_report(report);
+    }
+
+    if let Some(file) = &file.upstream_pac {
+        let mut pac = PacSettings::new(&file.url);
+        if let Some(secs) = file.refresh_secs {
+            pac = pac.with_refresh(Duration::from_secs(secs));
+        }
+        if let Some(fallback) = &file.fallback {
+            pac = pac.with_fallback(fallback.parse()?);
+        }
+        if let Some(size) = file.cache_size {
+            pac = pac.with_cache_size(size);
+        }
+        if let Some(resolve) = &file.resolve {
+            pac = pac.with_resolve(parent_resolve(resolve)?);
+        }
+        settings = settings.with_pac(pac);
+    }
+
+    let upstream_proxy = upstream_proxy_settings(
+        "upstream_proxy",
+        cli.upstream_proxy.as_deref(),
+        file.upstream_proxy.as_ref(),
+        |name| std::env::var(name).ok(),
+    )?;
+    if let Some(upstream_proxy) = upstream_proxy {
+        settings = settings.with_upstream_proxy(upstream_proxy);
+    }
+    let socks5 = upstream_socks5_settings(
+        cli.upstream_socks5.as_deref(),
+        file.upstream_socks5.as_ref(),
+        |name| std::env::var(name).ok(),
+    )?;
+    if let Some(socks5) = socks5 {
+        settings = settings.with_upstream_socks5(socks5);
+    }
+    if let Some(file) = &file.canary {
+        settings = settings.with_canary(canary_settings(file, |name| std::env::var(name).ok())?);
+    }
+
+    settings = settings.with_rollout(rollout_settings(file)?);
+    settings = settings.with_dns(dns_settings(file.dns.as_ref())?);
+
+    if let Some(file) = &file.nat64 {
+        let mut nat64 = Nat64Settings::default();
+        if let Some(prefix) = &file.prefix {
+            nat64 = nat64.with_prefix(prefix.parse()?);
+        }
+        if let Some(secs) = file.probe_interval_secs {
+            nat64 = nat64.with_probe_interval(Duration::from_secs(secs.max(1)));
+        }
+        settings = settings.with_nat64(nat64);
+    }
+
+    if let Some(file) = &file.tunnel_quality {
+        let mut quality = TunnelQualitySettings::default();
+        if let Some(secs) = file.interval_secs {
+            quality = quality.with_interval(Duration::from_secs(secs.max(1)));
+        }
+        if let Some(secs) = file.min_age_secs {
+            quality = quality.with_min_age(Duration::from_secs(secs));
+        }
+        settings = settings.with_tunnel_quality(quality);
+    }
+    if let Some(verify_sni) = verify_sni_settings(cli, file.verify_sni.as_ref())? {
+        settings = settings.with_verify_sni(verify_sni);
+    }
+
+    if let Some(file) = &file.auth {
+        settings = settings.with_auth(auth_settings(file, |name| std::env::var(name).ok())?);
+    }
+
+    if let Some(file) = &file.tickets {
+        let key = std::env::var(&file.key_env)
+            .ok()
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "la variable de entorno {} con la clave de [tickets] no está definida",
+                    file.key_env
+                )
+            })?;
+        let mut tickets = TicketSettings::new(key);
+        if let Some(secs) = file.max_ttl_secs {
+            anyhow::ensure!(secs > 0, "[tickets] max_ttl_secs debe ser mayor que 0");
+            tickets = tickets.with_max_ttl(Duration::from_secs(secs));
+        }
+        for id in &file.revoked {
+            tickets = tickets.with_revoked(id);
+        }
+        settings = settings.with_tickets(tickets);
+    }
+
+    let mut resources = ResourceSettings::default();
+    if let Some(file) = &file.resources {
+        if let Some(connections) = file.expected_connections {
+            resources = resources.with_expected_connections(connections);
+        }
+        resources = resources.with_raise_nofile(file.raise_nofile.unwrap_or(false));
+    }
+    if cli.raise_nofile {
+        resources = resources.with_raise_nofile(true);
+    }
+    settings = settings.with_resources(resources);
+
+    if let Some(file) = &file.host_filter {
+        let mut host_filter = HostFilterSettings::default();
+        for rule in &file.allow {
+            host_filter = host_filter.with_allow(rule.parse()?);
+        }
+        for rule in &file.deny {
+            host_filter = host_filter.with_deny(rule.parse()?);
+        }
+        settings = settings.with_host_filter(host_filter);
+    }
+    if let Some(file) = &file.header_rules {
+        for rule in header_rules(file)? {
+            settings = settings.with_header_rule(rule);
+        }
+    }
+
+    if let Some(file) = &file.events {
+        let endpoint = match (&file.socket, file.listen) {
+            (Some(path), None) => EventEndpoint::Unix(path.clone()),
+            (None, Some(addr)) if addr.ip().is_loopback() => EventEndpoint::Tcp(addr),
+            (None, Some(addr)) => anyhow::bail!(
+                "[events] listen = {addr}: el flujo de eventos solo escucha en loopback"
+            ),
+            _ => anyhow::bail!("[events] requiere exactamente uno de `socket` o `listen`"),
+        };
+        let mut events = EventSettings::new(endpoint);
+        if let Some(queue) = file.queue {
+            events = events.with_queue(queue);
+        }
+        settings = settings.with_events(events);
+    }
+
+    if let Some(file) = &file.header_limits {
+        settings = settings.with_header_limits(header_limits(file)?);
+    }
+
+    if let Some(rate) = rate_limit(cli, file)? {
+        settings = settings.with_rate_limit(rate);
+    }
+
+    if let Some(limits) = concurrency_limits(cli, file)? {
+        settings = settings.with_concurrency(limits);
+    }
+
+    if let Some(overload) = &file.overload {
+        settings = settings.with_overload(overload_settings(overload, file)?);
+    }
+
+    if let Some(file) = &file.drain {
+        let mut drain = DrainSettings::default();
+        if let Some(close) = file.close_keepalive {
+            drain = drain.with_close_keepalive(close);
+        }
+        if let Some(secs) = file.connect_grace_secs {
+            drain = drain.with_connect_grace(Duration::from_secs(secs));
+        }
+        settings = settings.with_drain(drain);
+    }
+
+    if let Some(ban) = &file.ban {
+        settings = settings.with_ban(ban_settings(ban)?);
+    }
+
+    if let Some(expect) = &file.expect_continue {
+        settings = settings.with_expect_continue(expect_continue(expect)?);
+    }
+
+    if let Some(trailers) = &file.trailers {
+        settings = settings.with_trailer_fallback(trailer_fallback(trailers)?);
+    }
+
+    if let Some(bandwidth) = &file.bandwidth {
+        settings = settings.with_bandwidth(bandwidth_settings(bandwidth));
+    }
+
+    if let Some(llm) = &file.llm {
+        settings = settings.with_llm(llm_settings(llm)?);
+    }
+    if let Some(router) = &file.ai_router {
+        settings = settings.with_ai_router(ai_router_settings(router)?);
+    }
+    if let Some(budget) = &file.token_budget {
+        anyhow::ensure!(
+            file.ai_router.is_some(),
+            "[token_budget] solo se aplica a las peticiones de [ai_router]"
+        );
+        settings = settings.with_token_budget(token_budget_settings(budget)?);
+    }
+    if let Some(filters) = &file.content_filters {
+        settings = settings.with_content_filters(content_filter_settings(filters)?);
+    }
+    if let Some(request_id) = &file.request_id {
+        settings = settings.with_request_id(request_id_settings(request_id)?);
+    }
+
+    if let Some(sniff) = &file.sniff {
+        settings = settings.with_sniff(sniff_settings(sniff)?);
+    }
+    if let Some(idempotency) = &file.idempotency {
+        settings = settings.with_idempotency(idempotency_settings(idempotency)?);
+    }
+    if let Some(cache) = &file.response_cache {
+        settings = settings.with_response_cache(response_cache_settings(cache)?);
+    }
+    if let Some(prefetch) = &file.prefetch {
+        let mut defaults = PrefetchSettings::default();
+        if let Some(max) = prefetch.max_per_page {
+            defaults = defaults.with_max_per_page(max);
+        }
+        if let Some(max) = prefetch.max_html_bytes {
+            defaults = defaults.with_max_html_bytes(max);
+        }
+        settings = settings.with_prefetch(defaults);
+    }
+    if let Some(server_timing) = &file.server_timing {
+        settings = settings.with_server_timing(server_timing_settings(server_timing)?);
+    }
+    if let Some(echo) = &file.metadata_echo {
+        settings = settings.with_metadata_echo(metadata_echo_settings(file, echo)?);
+    }
+    if let Some(enabled) = file.proxy_info {
+        settings = settings.with_proxy_info(enabled);
+    }
+    if let Some(serve_pac) = serve_pac_settings(cli, file.serve_pac.as_ref())? {
+        settings = settings.with_serve_pac(serve_pac);
+    }
+    if let Some(transparent) = transparent_settings(cli, file.transparent.as_ref())? {
+        settings = settings.with_transparent(transparent);
+    }
+    if let Some(anonymous) = file.anonymous {
+        settings = settings.with_anonymous(anonymous);
+    }
+    settings = settings.with_log_profile(log_profile(cli, file)?);
+    for map in &file.redirect_maps {
+        settings = settings.with_redirect_map(&map.map_file);
+    }
+    if let Some(error_pages) = &file.error_pages {
+        settings = settings.with_error_pages(error_pages_settings(error_pages)?);
+    }
+    if let Some(jobs) = &file.jobs {
+        settings = settings.with_jobs(jobs_settings(jobs)?);
+    }
+    settings = settings.with_fast_path(fast_path(file)?);
+
+    settings = settings.with_egress(egress_settings(
+        file.egress_policy.as_deref(),
+        file.egress.as_ref(),
+    )?);
+
+    if let Some(data_saver) = &file.data_saver {
+        settings = settings.with_data_saver(data_saver_settings(data_saver));
+    }
+
+    if let Some(identity) = &file.identity {
+        settings = settings.with_identity(identity_settings(identity)?);
+    }
+
+    if let Some(addr) = cli.admin_listen.or(file.admin_listen) {
+        settings = settings.with_admin_listen(addr);
+    }
+    if let Some(path) = cli.blocklist_file.as_ref().or(file.blocklist_file.as_ref()) {
+        settings = settings.with_blocklist_file(path);
+    }
+    if let Some(addr) = cli.metrics_listen.or(file.metrics_listen) {
+        settings = settings.with_metrics_listen(addr);
+    }
+    if let Some(target) = cli.access_log.as_deref().or(file.access_log.as_deref()) {
+        settings = settings.with_access_log(target.parse()?);
+    }
+
+    if let Some(name) = &file.admin_token_env {
+        let token = std::env::var(name)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "la variable de entorno {name} con el token de administración no está definida"
+                )
+            })?;
+        settings = settings.with_admin_token(AdminToken::new(token));
+    }
+
+    let file_capture = file.capture.as_ref();
+    if let Some(dir) = cli.capture_dir.as_ref().or(file_capture.map(|c| &c.dir)) {
+        let mut capture = CaptureSettings::new(dir);
+        if let Some(file) = file_capture {
+            if let Some(max) = file.max_total_bytes {
+                capture = capture.with_max_total_bytes(max);
+            }
+            if let Some(max) = file.max_body_bytes {
+                capture = capture.with_max_body_bytes(max);
+            }
+            if let Some(rate) = file.sample_rate {
+                capture = capture.with_sample_rate(rate);
+            }
+            capture = capture
+                .with_hosts(file.hosts.clone())
+                .with_all_responses(file.all_responses);
+        }
+        settings = settings.with_capture(capture);
+    }
+    if let Some(archive) = &file.archive {
+        settings = settings.with_archive(archive_settings(archive)?);
+    }
+    Ok(settings)
+}
+
+/// Lee la clave del entorno; falla si falta o no son 32 bytes.
+fn archive_settings(file: &ArchiveConfig) -> anyhow::Result<ArchiveSettings> {
+    let mut archive = ArchiveSettings::new(&file.dir);
+    if let Some(name) = &file.key_env {
+        let encoded = std::env::var(name).map_err(|_| {
+            anyhow::anyhow!(
+                "la variable de entorno {name} con la clave del archivo no está definida"
+            )
+        })?;
+        let key: [u8; 32] = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow::anyhow!("la clave de {name} deben ser 32 bytes en base64"))?;
+        archive = archive.with_key(ArchiveKey::new(key));
+    }
+    if let Some(secs) = file.max_age_secs {
+        anyhow::ensure!(secs > 0, "archive.max_age_secs debe ser mayor que 0");
+        archive = archive.with_max_age(Duration::from_secs(secs));
+    }
+    if let Some(max) = file.max_total_bytes {
+        archive = archive.with_max_total_bytes(max);
+    }
+    if let Some(max) = file.max_body_bytes {
+        archive = archive.with_max_body_bytes(max);
+    }
+    Ok(archive)
+}
+
+fn credential_settings(file: &CredentialConfig) -> anyhow::Result<CredentialSettings> {
+    let field = |value: &Option<String>, name: &str| {
+        value.clone().ok_or_else(|| {
+            anyhow::anyhow!(
+                "la credencial `{}` para {} requiere `{name}`",
+                file.kind,
+                file.host
+            )
+        })
+    };
+    let kind = match file.kind.as_str() {
+        "bearer" => CredentialKind::Bearer {
+            token_env: field(&file.token_env, "token_env")?,
+        },
+        "basic" => CredentialKind::Basic {
+            username_env: field(&file.username_env, "username_env")?,
+            password_env: field(&file.password_env, "password_env")?,
+        },
+        "header" => CredentialKind::Header {
+            name: field(&file.header, "header")?,
+            value_env: field(&file.value_env, "value_env")?,
+        },
+        other => anyhow::bail!("tipo de credencial desconocido: {other}"),
+    };
+    Ok(CredentialSettings::new(file.host.parse()?, kind)
+        .with_replace_client(file.replace_client.unwrap_or(true)))
+}
+
+fn provider_settings(file: &ProviderConfig) -> anyhow::Result<ProviderSettings> {
+    let key = match (&file.key_env, &file.key_file) {
+        (Some(name), None) => KeySource::Env(name.clone()),
+        (None, Some(path)) => KeySource::File(path.clone()),
+        _ => anyhow::bail!(
+            "el proveedor {} requiere `key_env` o `key_file`, uno de los dos",
+            file.host
+        ),
+    };
+    let mut provider = ProviderSettings::new(file.host.parse()?, key);
+    if let Some(header) = &file.header {
+        let header = hyper::header::HeaderName::from_bytes(header.as_bytes())
+            .map_err(|_| anyhow::anyhow!("cabecera inválida para el proveedor {}", file.host))?;
+        provider = provider.with_header(header);
+    }
+    Ok(provider)
+}
+
+fn signing_settings(file: &SigningConfig) -> anyhow::Result<SigningSettings> {
+    let algorithm = match file.algorithm.as_deref().unwrap_or("hmac-sha256") {
+        "hmac-sha256" => SigningAlgorithm::HmacSha256,
+        "aws-sigv4" => {
+            let field = |value: &Option<String>, name: &str| {
+                value.clone().ok_or_else(|| {
+                    anyhow::anyhow!("la firma `aws-sigv4` para {} requiere `{name}`", file.host)
+                })
+            };
+            SigningAlgorithm::AwsSigV4 {
+                region: field(&file.region, "region")?,
+                service: field(&file.service, "service")?,
+            }
+        }
+        other => anyhow::bail!("algoritmo de firma desconocido: {other}"),
+    };
+    let mut signing = SigningSettings::new(
+        file.host.parse()?,
+        algorithm,
+        &file.key_id,
+        &file.secret_env,
+    );
+    if let Some(name) = &file.name {
+        signing = signing.with_name(name);
+    }
+    if let Some(prefix) = &file.path_prefix {
+        anyhow::ensure!(
+            prefix.starts_with('/'),
+            "el prefijo de ruta `{prefix}` de la firma debe empezar por `/`"
+        );
+        signing = signing.with_path_prefix(prefix);
+    }
+    for header in &file.signed_headers {
+        signing = signing.with_signed_header(header);
+    }
+    if let Some(max) = file.max_body_bytes {
+        signing = signing.with_max_body(max);
+    }
+    if let Some(secs) = file.skew_tolerance_secs {
+        signing = signing.with_skew_tolerance(Duration::from_secs(secs));
+    }
+    Ok(signing)
+}
+
+fn expect_continue(file: &ExpectContinueConfig) -> anyhow::Result<ExpectContinue> {
+    match file.mode.as_deref().unwrap_or("forward") {
+        "forward" => Ok(ExpectContinue::Forward {
+            timeout: file
+                .timeout_ms
+                .map_or(ExpectContinue::DEFAULT_TIMEOUT, Duration::from_millis),
+        }),
+        "local" => Ok(ExpectContinue::Local),
+        other => anyhow::bail!("modo de expect_continue desconocido: {other}"),
+    }
+}
+
+fn listen_uds_settings(cli: &Cli, file: &FileConfig) -> anyhow::Result<Option<UnixListenSettings>> {
+    let mode = cli
+        .listen_uds_mode
+        .as_ref()
+        .or(file.listen_uds_mode.as_ref());
+    let Some(path) = cli.listen_uds.as_ref().or(file.listen_uds.as_ref()) else {
+        anyhow::ensure!(mode.is_none(), "listen_uds_mode requiere listen_uds");
+        return Ok(None);
+    };
+    let mut uds = UnixListenSettings::new(path);
+    if let Some(mode) = mode {
+        let bits = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|bits| *bits <= 0o777)
+            .ok_or_else(|| anyhow::anyhow!("listen_uds_mode inválido: {mode} (octal, como 660)"))?;
+        uds = uds.with_mode(bits);
+    }
+    Ok(Some(uds))
+}
+
+fn parent_resolve(mode: &str) -> anyhow::Result<ParentResolve> {
+    match mode {
+        "remote" => Ok(ParentResolve::Remote),
+        "local" => Ok(ParentResolve::Local),
+        other => anyhow::bail!("modo de resolve desconocido: {other}"),
+    }
+}
+
+fn trailer_fallback(file: &TrailersConfig) -> anyhow::Result<TrailerFallback> {
+    match file.fallback.as_deref().unwrap_or("drop") {
+        "drop" => Ok(TrailerFallback::Drop),
+        "headers" => Ok(TrailerFallback::Headers {
+            max_body: file
+                .max_body_bytes
+                .unwrap_or(TrailerFallback::DEFAULT_MAX_BODY),
+        }),
+        other => anyhow::bail!("fallback de trailers desconocido: {other}"),
+    }
+}
+
+fn listener_protocols(file: &ListenerConfig) -> anyhow::Result<ListenerProtocols> {
+    let mut protocols = ListenerProtocols::default();
+    if let Some(http) = file.http {
+        protocols = protocols.with_http(http);
+    }
+    if let Some(socks5) = file.socks5 {
+        protocols = protocols.with_socks5(socks5);
+    }
+    if let Some(ms) = file.detect_timeout_ms {
+        protocols = protocols.with_detect_timeout(Duration::from_millis(ms));
+    }
+    if !protocols.http() && !protocols.socks5() {
+        anyhow::bail!("`listener` debe admitir al menos un protocolo");
+    }
+    Ok(protocols)
+}
+
+fn tls_settings(cli: &Cli, file: &FileConfig) -> anyhow::Result<Option<TlsSettings>> {
+    let listener = file.listener.as_ref();
+    let cert = cli
+        .tls_cert
+        .clone()
+        .or_else(|| listener.and_then(|listener| listener.tls_cert.clone()));
+    let key = cli
+        .tls_key
+        .clone()
+        .or_else(|| listener.and_then(|listener| listener.tls_key.clone()));
+    let tls = match (cert, key) {
+        (Some(cert), Some(key)) => Some(TlsSettings::new(cert, key)),
+        (None, None) => None,
+        _ => anyhow::bail!("`--tls-cert` y `--tls-key` van juntos: falta uno de los dos"),
+    };
+    match listener.and_then(|listener| listener.tls) {
+        Some(true) if tls.is_none() => {
+            anyhow::bail!("`listener.tls = true` requiere `tls_cert` y `tls_key`")
+        }
+        Some(false) if tls.is_some() => {
+            anyhow::bail!("`listener.tls = false` contradice el certificado TLS configurado")
+        }
+        _ => {}
+    }
+    if tls.is_some() && listener.and_then(|listener| listener.socks5) == Some(true) {
+        anyhow::bail!("Con TLS el puerto solo atiende HTTP: quita `listener.socks5`");
+    }
+    Ok(tls)
+}
+
+/// `--mitm-ca-cert`/`--mitm-ca-key` o la sección `[mitm]`; los flags ganan
+/// al archivo.
+fn mitm_settings(cli: &Cli, file: &FileConfig) -> anyhow::Result<Option<MitmSettings>> {
+    let mitm = file.mitm.as_ref();
+    let cert = cli
+        .mitm_ca_cert
+        .clone()
+        .or_else(|| mitm.and_then(|mitm| mitm.ca_cert.clone()));
+    let key = cli
+        .mitm_ca_key
+        .clone()
+        .or_else(|| mitm.and_then(|mitm| mitm.ca_key.clone()));
+    let mut settings = match (cert, key) {
+        (Some(cert), Some(key)) => MitmSettings::new(cert, key),
+        (None, None) if mitm.is_none() => return Ok(None),
+        (None, None) => anyhow::bail!("`[mitm]` requiere `ca_cert` y `ca_key`"),
+        _ => anyhow::bail!("`--mitm-ca-cert` y `--mitm-ca-key` van juntos: falta uno de los dos"),
+    };
+    if let Some(mitm) = mitm {
+        let passthrough = mitm.passthrough.iter().map(|host| host.parse());
+        settings = settings.with_passthrough(passthrough.collect::<anyhow::Result<_>>()?);
+        if let Some(path) = &mitm.upstream_ca {
+            settings = settings.with_upstream_ca(path);
+        }
+    }
+    Ok(Some(settings))
+}
+
+fn cert_expiry_settings(file: &CertExpiryConfig) -> anyhow::Result<CertExpirySettings> {
+    let mut settings = CertExpirySettings::default();
+    if let Some(days) = &file.thresholds_days {
+        if days.is_empty() || days.contains(&0) {
+            anyhow::bail!(
+                "[cert_expiry] `thresholds_days` necesita al menos un umbral mayor que 0"
+            );
+        }
+        settings = settings.with_thresholds_days(days.clone());
+    }
+    if let Some(secs) = file.check_interval_secs {
+        if secs == 0 {
+            anyhow::bail!("[cert_expiry] `check_interval_secs` debe ser mayor que 0");
+        }
+        settings = settings.with_check_interval(Duration::from_secs(secs));
+    }
+    if let Some(url) = &file.webhook {
+        settings = settings.with_webhook(url);
+    }
+    Ok(settings)
+}
+
+fn listener_hardening(file: &ListenerConfig) -> anyhow::Result<ListenerHardening> {
+    let mut hardening = ListenerHardening::default();
+    if let Some(backlog) = file.backlog {
+        hardening = hardening.with_backlog(backlog);
+    }
+    if let Some(secs) = file.defer_accept_secs {
+        hardening = hardening.with_defer_accept(Duration::from_secs(secs));
+    }
+    match (file.accept_rate_per_sec, file.accept_burst) {
+        (Some(per_sec), burst) => {
+            anyhow::ensure!(
+                per_sec > 0.0,
+                "`listener.accept_rate_per_sec` debe ser positivo"
+            );
+            // Without an explicit burst, one second's worth of connections.
+            let burst = burst.unwrap_or(per_sec.ceil() as u32).max(1);
+            hardening = hardening.with_accept_rate(AcceptRate::new(per_sec, burst));
+        }
+        (None, Some(_)) => {
+            anyhow::bail!("`listener.accept_burst` requiere `listener.accept_rate_per_sec`")
+        }
+        (None, None) => {}
+    }
+    if let Some(percent) = file.fd_pause_percent {
+        anyhow::ensure!(
+            (1..=100).contains(&percent),
+            "`listener.fd_pause_percent` debe estar entre 1 y 100"
+        );
+        hardening = hardening.with_fd_pause_percent(percent);
+    }
+    Ok(hardening)
+}
+
+fn egress_settings(
+    policy: Option<&str>,
+    file: Option<&EgressConfig>,
+) -> anyhow::Result<EgressSettings> {
+    let mut egress = EgressSettings::default();
+    if let Some(policy) = policy {
+        egress = egress.with_mode(policy.parse()?);
+    }
+    let Some(file) = file else {
+        return Ok(egress);
+    };
+    if let Some(contact) = &file.contact {
+        egress = egress.with_contact(contact);
+    }
+    for allow in &file.allow {
+        let mut rule = EgressRule::new(allow.host.parse()?, allow.port);
+        if let Some(name) = &allow.name {
+            rule = rule.with_name(name);
+        }
+        for path in &allow.paths {
+            anyhow::ensure!(
+                path.starts_with('/'),
+                "el prefijo de ruta `{path}` de la regla de salida debe empezar por `/`"
+            );
+            rule = rule.with_path_prefix(path);
+        }
+        egress = egress.with_rule(rule);
+    }
+    Ok(egress)
+}
+
+fn llm_settings(file: &LlmConfig) -> anyhow::Result<LlmSettings> {
+    let mut llm = LlmSettings::default();
+    for host in &file.hosts {
+        llm = llm.with_host(host.parse()?);
+    }
+    if let Some(tokens) = file.default_max_tokens {
+        llm = llm.with_default_max_tokens(tokens);
+    }
+    if let Some(max) = file.max_body_bytes {
+        llm = llm.with_max_body(max);
+    }
+    for (model, price) in &file.pricing {
+        anyhow::ensure!(
+            price.input_per_mtok >= 0.0 && price.output_per_mtok >= 0.0,
+            "el precio del modelo {model} no puede ser negativo"
+        );
+        llm = llm.with_price(
+            model,
+            ModelPrice {
+                input_per_mtok: price.input_per_mtok,
+                output_per_mtok: price.output_per_mtok,
+            },
+        );
+    }
+    Ok(llm)
+}
+
+fn ai_router_settings(file: &AiRouterConfig) -> anyhow::Result<AiRouterSettings> {
+    let mut router = AiRouterSettings::new(upstream_base(&file.default_upstream)?);
+    for (model, upstreams) in &file.models {
+        anyhow::ensure!(
+            !upstreams.urls().is_empty(),
+            "[ai_router.models] {model} necesita al menos un backend"
+        );
+        let weights = match upstreams {
+            UpstreamList::Pool(pool) => pool.weights.clone(),
+            _ => None,
+        };
+        let weights = weights.unwrap_or_else(|| vec![1; upstreams.urls().len()]);
+        anyhow::ensure!(
+            weights.len() == upstreams.urls().len(),
+            "[ai_router.models] {model}: weights necesita un peso por backend"
+        );
+        for (upstream, weight) in upstreams.urls().iter().zip(weights) {
+            anyhow::ensure!(
+                weight > 0,
+                "[ai_router.models] {model}: los pesos deben ser mayores que 0"
+            );
+            router = router.with_weighted_model(model, upstream_base(upstream)?, weight);
+        }
+        if let UpstreamList::Pool(UpstreamPoolConfig {
+            strategy: Some(strategy),
+            ..
+        }) = upstreams
+        {
+            let strategy = match strategy.as_str() {
+                "failover" => BalanceStrategy::Failover,
+                "round_robin" => BalanceStrategy::RoundRobin,
+                "least_active" => BalanceStrategy::LeastActive,
+                "weighted" => BalanceStrategy::Weighted,
+                other => anyhow::bail!(
+                    "[ai_router.models] {model}: estrategia desconocida {other} (failover, round_robin, least_active, weighted)"
+                ),
+            };
+            router = router.with_balance(model, strategy);
+        }
+    }
+    if let Some(paths) = &file.paths {
+        for path in paths {
+            anyhow::ensure!(
+                path.starts_with('/'),
+                "[ai_router] la ruta {path} debe empezar por /"
+            );
+        }
+        router = router.with_paths(paths.clone());
+    }
+    if let Some(max) = file.max_body_bytes {
+        router = router.with_max_body(max);
+    }
+    if let Some(cache) = &file.cache {
+        router = router.with_cache(llm_cache_settings(cache)?);
+    }
+    if let Some(retry) = &file.retry {
+        let mut settings = AiRetrySettings::default();
+        if let Some(attempts) = retry.attempts {
+            anyhow::ensure!(
+                attempts > 0,
+                "[ai_router.retry] attempts debe ser mayor que 0"
+            );
+            settings = settings.with_attempts(attempts);
+        }
+        if let Some(secs) = retry.max_wait_secs {
+            settings = settings.with_max_wait(Duration::from_secs(secs));
+        }
+        if let Some(failover) = retry.failover {
+            settings = settings.with_failover(failover);
+        }
+        router = router.with_retry(settings);
+    }
+    if let Some(health) = &file.health {
+        let mut settings = AiHealthSettings::default();
+        if let Some(failures) = health.unhealthy_after {
+            anyhow::ensure!(
+                failures > 0,
+                "[ai_router.health] unhealthy_after debe ser mayor que 0"
+            );
+            settings = settings.with_unhealthy_after(failures);
+        }
+        if let Some(secs) = health.cooldown_secs {
+            anyhow::ensure!(
+                secs > 0,
+                "[ai_router.health] cooldown_secs debe ser mayor que 0"
+            );
+            settings = settings.with_cooldown(Duration::from_secs(secs));
+        }
+        router = router.with_health(settings);
+    }
+    Ok(router)
+}
+
+fn token_budget_settings(file: &TokenBudgetConfig) -> anyhow::Result<TokenBudgetSettings> {
+    let mut budget = TokenBudgetSettings::default();
+    if let Some(tokens) = file.tokens_per_minute {
+        anyhow::ensure!(
+            tokens > 0,
+            "[token_budget] tokens_per_minute debe ser mayor que 0"
+        );
+        budget = budget.with_per_minute(tokens);
+    }
+    if let Some(tokens) = file.tokens_per_day {
+        anyhow::ensure!(
+            tokens > 0,
+            "[token_budget] tokens_per_day debe ser mayor que 0"
+        );
+        budget = budget.with_per_day(tokens);
+    }
+    anyhow::ensure!(
+        budget.per_minute().is_some() || budget.per_day().is_some(),
+        "[token_budget] necesita tokens_per_minute o tokens_per_day"
+    );
+    Ok(budget)
+}
+
+fn client_pool_settings(cli: &Cli, file: &FileConfig) -> anyhow::Result<ClientPoolSettings> {
+    let duration = |value: &Option<String>, name: &str| {
+        value
+            .as_deref()
+            .map(bench::parse_duration)
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("{name}: {e}"))
+    };
+    let mut pool = ClientPoolSettings::default()
+        .with_enabled(!cli.no_pool && file.pool_enabled.unwrap_or(true))
+        .with_http1_keep_alive(!cli.no_http1_keep_alive && file.http1_keep_alive.unwrap_or(true))
+        .with_tcp_nodelay(cli.tcp_nodelay || file.tcp_nodelay.unwrap_or(false));
+    if let Some(max) = cli.pool_max_idle_per_host.or(file.pool_max_idle_per_host) {
+        pool = pool.with_max_idle_per_host(max);
+    }
+    let idle_timeout = duration(&file.pool_idle_timeout, "pool_idle_timeout")?;
+    if let Some(timeout) = cli.pool_idle_timeout.or(idle_timeout) {
+        anyhow::ensure!(!timeout.is_zero(), "pool_idle_timeout debe ser mayor que 0");
+        pool = pool.with_idle_timeout(timeout);
+    }
+    let keepalive = duration(&file.tcp_keepalive, "tcp_keepalive")?;
+    if let Some(idle) = cli.tcp_keepalive.or(keepalive) {
+        anyhow::ensure!(!idle.is_zero(), "tcp_keepalive debe ser mayor que 0");
+        pool = pool.with_tcp_keepalive(idle);
+    }
+    Ok(pool)
+}
+
+fn request_id_settings(file: &RequestIdConfig) -> anyhow::Result<RequestIdSettings> {
+    let mut request_id = RequestIdSettings::default();
+    if let Some(enabled) = file.enabled {
+        request_id = request_id.with_enabled(enabled);
+    }
+    if let Some(header) = &file.header {
+        let header = hyper::header::HeaderName::from_bytes(header.as_bytes())
+            .map_err(|_| anyhow::anyhow!("[request_id] cabecera inválida: {header}"))?;
+        request_id = request_id.with_header(header);
+    }
+    if let Some(trust) = file.trust_incoming {
+        request_id = request_id.with_trust_incoming(trust);
+    }
+    Ok(request_id)
+}
+
+fn content_filter_settings(file: &ContentFiltersConfig) -> anyhow::Result<ContentFilterSettings> {
+    let mut filters = ContentFilterSettings::default();
+    if let Some(all) = file.all_text_bodies {
+        filters = filters.with_all_text_bodies(all);
+    }
+    if let Some(max) = file.max_body_bytes {
+        anyhow::ensure!(
+            max > 0,
+            "[content_filters] max_body_bytes debe ser mayor que 0"
+        );
+        filters = filters.with_max_body(max);
+    }
+    anyhow::ensure!(
+        !file.rules.is_empty(),
+        "[content_filters] necesita al menos una regla en [[content_filters.rules]]"
+    );
+    for (i, rule) in file.rules.iter().enumerate() {
+        anyhow::ensure!(
+            file.rules[..i].iter().all(|other| other.id != rule.id),
+            "[content_filters] regla {} repetida",
+            rule.id
+        );
+        let action = rule
+            .action
+            .parse()
+            .map_err(|e| anyhow::anyhow!("[content_filters] regla {}: {e}", rule.id))?;
+        let rule = ContentRule::new(&rule.id, &rule.pattern, action).map_err(|e| {
+            anyhow::anyhow!(
+                "[content_filters] patrón inválido en la regla {}: {e}",
+                rule.id
+            )
+        })?;
+        filters = filters.with_rule(rule);
+    }
+    Ok(filters)
+}
+
+fn llm_cache_settings(file: &LlmCacheConfig) -> anyhow::Result<LlmCacheSettings> {
+    let mut cache = LlmCacheSettings::default()
+        .with_sampled(file.cache_sampled.unwrap_or(false))
+        .with_streams(file.cache_streams.unwrap_or(false));
+    if let Some(secs) = file.ttl_secs {
+        anyhow::ensure!(secs > 0, "[ai_router.cache] ttl_secs debe ser mayor que 0");
+        cache = cache.with_ttl(Duration::from_secs(secs));
+    }
+    if let Some(max) = file.max_entries {
+        cache = cache.with_max_entries(max);
+    }
+    if let Some(dir) = &file.dir {
+        cache = cache.with_dir(dir);
+    }
+    Ok(cache)
+}
+
+/// URL base `http(s)://host[:puerto][/prefijo]` de un backend.
+fn upstream_base(url: &str) -> anyhow::Result<hyper::Uri> {
+    let uri: hyper::Uri = url
+        .parse()
+        .map_err(|_| anyhow::anyhow!("[ai_router] URL inválida: {url}"))?;
+    anyhow::ensure!(
+        matches!(uri.scheme_str(), Some("http" | "https"))
+            && uri.host().is_some()
+            && uri.query().is_none(),
+        "[ai_router] {url} debe ser una URL http(s):// sin query"
+    );
+    Ok(uri)
+}
+
+fn sniff_settings(file: &SniffConfig) -> anyhow::Result<SniffSettings> {
+    let mut sniff = SniffSettings::default();
+    if let Some(max) = file.max_bytes {
+        sniff = sniff.with_max_bytes(max);
+    }
+    for rule in &file.rules {
+        let action = match &rule.action {
+            Some(action) => action.parse()?,
+            None => Default::default(),
+        };
+        sniff = sniff.with_rule(SniffRule::new(rule.host.parse()?, action));
+    }
+    Ok(sniff)
+}
+
+fn idempotency_settings(file: &IdempotencyConfig) -> anyhow::Result<IdempotencySettings> {
+    let mut idempotency = IdempotencySettings::default();
+    if let Some(secs) = file.ttl_secs {
+        idempotency = idempotency.with_ttl(Duration::from_secs(secs));
+    }
+    if let Some(max) = file.max_entries {
+        idempotency = idempotency.with_max_entries(max);
+    }
+    if let Some(max) = file.max_body_bytes {
+        idempotency = idempotency.with_max_body(max);
+    }
+    for route in &file.routes {
+        let mut parsed = IdempotencyRoute::new(route.host.parse()?);
+        if let Some(prefix) = &route.path_prefix {
+            parsed = parsed.with_path_prefix(prefix);
+        }
+        idempotency = idempotency.with_route(parsed);
+    }
+    Ok(idempotency)
+}
+
+fn response_cache_settings(file: &ResponseCacheConfig) -> anyhow::Result<ResponseCacheSettings> {
+    let mut cache = ResponseCacheSettings::default();
+    if let Some(max) = file.max_entries {
+        cache = cache.with_max_entries(max);
+    }
+    if let Some(max) = file.max_body_bytes {
+        cache = cache.with_max_body(max);
+    }
+    if let Some(max) = file.max_revalidations_per_host {
+        cache = cache.with_max_revalidations_per_host(max);
+    }
+    for rule in &file.stale {
+        let mut stale = StaleDefaults::new(rule.host.parse()?);
+        if let Some(secs) = rule.stale_while_revalidate_secs {
+            stale = stale.with_while_revalidate(Duration::from_secs(secs));
+        }
+        if let Some(secs) = rule.stale_if_error_secs {
+            stale = stale.with_if_error(Duration::from_secs(secs));
+        }
+        cache = cache.with_stale(stale);
+    }
+    Ok(cache)
+}
+
+fn server_timing_settings(file: &ServerTimingConfig) -> anyhow::Result<ServerTimingSettings> {
+    let mut server_timing = ServerTimingSettings::default();
+    for host in &file.hosts {
+        server_timing = server_timing.with_host(host.parse()?);
+    }
+    for range in &file.clients {
+        server_timing = server_timing.with_client(range.parse()?);
+    }
+    Ok(server_timing)
+}
+
+fn metadata_echo_settings(
+    file: &FileConfig,
+    echo: &MetadataEchoConfig,
+) -> anyhow::Result<MetadataEchoSettings> {
+    let mut settings = MetadataEchoSettings::default();
+    for range in &echo.clients {
+        settings = settings.with_client(range.parse()?);
+    }
+    let profiles = file.identity.as_ref().map(|identity| &identity.profiles);
+    for name in &echo.profiles {
+        if !profiles.is_some_and(|profiles| profiles.iter().any(|p| &p.name == name)) {
+            anyhow::bail!("[metadata_echo] profiles: perfil desconocido en [identity]: {name}");
+        }
+        settings = settings.with_profile(name);
+    }
+    if let Some(max) = echo.max_bytes {
+        settings = settings.with_max_bytes(max);
+    }
+    if let Some(cacheable) = &echo.cacheable {
+        settings = settings.with_cacheable(cacheable.parse()?);
+    }
+    Ok(settings)
+}
+
+fn error_pages_settings(file: &ErrorPagesConfig) -> anyhow::Result<ErrorPageSettings> {
+    let mut error_pages = ErrorPageSettings::default();
+    if let Some(template) = &file.template {
+        error_pages = error_pages.with_template(template);
+    }
+    if let Some(max) = file.max_log_bytes {
+        error_pages = error_pages.with_max_log_bytes(max);
+    }
+    for rule in &file.rules {
+        let mut parsed = ErrorPageRule::new(rule.host.parse()?);
+        if let Some(prefix) = &rule.path_prefix {
+            parsed = parsed.with_path_prefix(prefix);
+        }
+        if let Some(statuses) = &rule.statuses {
+            let statuses = statuses
+                .iter()
+                .map(|range| range.parse())
+                .collect::<anyhow::Result<_>>()?;
+            parsed = parsed.with_statuses(statuses);
+        }
+        if let Some(status) = rule.status {
+            anyhow::ensure!(
+                (100..=599).contains(&status),
+                "código de estado inválido en una página de error: {status}"
+            );
+            parsed = parsed.with_status(status);
+        }
+        if let Some(name) = &rule.debug_token_env {
+            parsed = parsed.with_debug_token_env(name);
+        }
+        error_pages = error_pages.with_rule(parsed);
+    }
+    Ok(error_pages)
+}
+
+fn jobs_settings(file: &JobsConfig) -> anyhow::Result<JobSettings> {
+    let mut jobs = JobSettings::default();
+    if let Some(requests) = file.busy_requests {
+        jobs = jobs.with_busy_requests(requests);
+    }
+    if let Some(concurrency) = file.background_concurrency {
+        jobs = jobs.with_background_concurrency(concurrency);
+    }
+    for (name, queue) in &file.queues {
+        let Some(base) = jobs::default_queue(name) else {
+            anyhow::bail!("cola de trabajo interno desconocida: {name}");
+        };
+        let priority = match &queue.priority {
+            Some(priority) => priority.parse()?,
+            None => base.priority(),
+        };
+        jobs = jobs.with_queue(
+            name,
+            QueueSettings::new(
+                priority,
+                queue.concurrency.unwrap_or(base.concurrency()),
+                queue.capacity.unwrap_or(base.capacity()),
+            ),
+        );
+    }
+    Ok(jobs)
+}
+
+fn bandwidth_settings(file: &BandwidthConfig) -> BandwidthSettings {
+    let mut bandwidth = BandwidthSettings::new(file.bytes_per_sec);
+    if let Some(quantum) = file.quantum_bytes {
+        bandwidth = bandwidth.with_quantum(quantum);
+    }
+    for (profile, weight) in &file.weights {
+        bandwidth = bandwidth.with_profile_weight(profile, *weight);
+    }
+    bandwidth
+}
+
+fn ban_settings(file: &BanConfig) -> anyhow::Result<BanSettings> {
+    let mut ban = BanSettings::default();
+    if let Some(threshold) = file.threshold {
+        ban = ban.with_threshold(threshold);
+    }
+    if let Some(secs) = file.window_secs {
+        ban = ban.with_window(Duration::from_secs(secs));
+    }
+    if let Some(secs) = file.duration_secs {
+        ban = ban.with_duration(Duration::from_secs(secs));
+    }
+    if let Some(secs) = file.max_duration_secs {
+        ban = ban.with_max_duration(Duration::from_secs(secs));
+    }
+    if let Some(action) = &file.action {
+        ban = ban.with_action(action.parse()?);
+    }
+    for range in &file.trusted {
+        ban = ban.with_trusted(range.parse()?);
+    }
+    if let Some(path) = &file.path {
+        ban = ban.with_path(path);
+    }
+    Ok(ban)
+}
+
+fn dns_settings(file: Option<&DnsConfig>) -> anyhow::Result<DnsSettings> {
+    let mut dns = DnsSettings::default();
+    let Some(file) = file else {
+        return Ok(dns);
+    };
+    for zone in &file.zones {
+        dns = dns.with_zone(zone.zone.parse()?, zone.server);
+    }
+    for rewrite in &file.rewrites {
+        let action = match (rewrite.replace, rewrite.requery) {
+            (Some(ip), None) => DnsRewriteAction::Replace(ip),
+            (None, Some(server)) => DnsRewriteAction::Requery(server),
+            _ => anyhow::bail!(
+                "la reescritura DNS de {} necesita exactamente uno de replace o requery",
+                rewrite.host
+            ),
+        };
+        dns = dns.with_rewrite(rewrite.host.parse()?, rewrite.answer.parse()?, action);
+    }
+    Ok(dns)
+}
+
+fn auth_settings(
+    file: &AuthConfig,
+    env: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<AuthSettings> {
+    let mut auth = AuthSettings::default();
+    if let Some(realm) = &file.realm {
+        auth = auth.with_realm(realm);
+    }
+    let inline = file.username.as_ref().map(|username| AuthUserConfig {
+        username: username.clone(),
+        password: file.password.clone(),
+        password_env: file.password_env.clone(),
+    });
+    if inline.is_none() && (file.password.is_some() || file.password_env.is_some()) {
+        anyhow::bail!("[auth] tiene contraseña pero no `username`");
+    }
+    for user in inline.iter().chain(&file.users) {
+        let password = match (&user.password, &user.password_env) {
+            (Some(password), None) => password.clone(),
+            (None, Some(name)) => env(name)
+                .filter(|password| !password.is_empty())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "la variable de entorno {name} con la contraseña de {} no está definida",
+                        user.username
+                    )
+                })?,
+            _ => anyhow::bail!(
+                "el usuario {} de [auth] requiere exactamente uno de `password` o `password_env`",
+                user.username
+            ),
+        };
+        if user.username.is_empty() || user.username.contains(':') {
+            anyhow::bail!("usuario de [auth] inválido: {:?}", user.username);
+        }
+        auth = auth.with_user(&user.username, password);
+    }
+    if auth.users().is_empty() {
+        anyhow::bail!("[auth] no define ningún usuario");
+    }
+    Ok(auth)
+}
+
+/// `--upstream-proxy` manda sobre `url`; las credenciales salen siempre del
+/// archivo, para no dejarlas en la línea de comandos.
+/// `section` es la sección de la que salen los datos, para los errores.
+fn upstream_proxy_settings(
+    section: &str,
+    cli_url: Option<&str>,
+    file: Option<&UpstreamProxyConfig>,
+    env: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Option<UpstreamProxySettings>> {
+    let default = UpstreamProxyConfig::default();
+    let file = file.unwrap_or(&default);
+    let Some(url) = cli_url.or(file.url.as_deref()) else {
+        if file.username.is_some() {
+            anyhow::bail!("[{section}] tiene credenciales pero no `url`");
+        }
+        return Ok(None);
+    };
+    let uri: hyper::Uri = url
+        .parse()
+        .map_err(|e| anyhow::anyhow!("URL del proxy padre inválida: {url}: {e}"))?;
+    let authority = match (uri.scheme_str(), uri.authority()) {
+        (Some("http"), Some(authority)) if !authority.as_str().contains('@') => authority,
+        _ => anyhow::bail!(
+            "El proxy padre debe ser http://host:puerto, sin credenciales en la URL: {url}"
+        ),
+    };
+    if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+        anyhow::bail!("La URL del proxy padre no lleva ruta: {url}");
+    }
+    let mut upstream_proxy = UpstreamProxySettings::new(authority.clone());
+    let credentials = section_credentials(
+        section,
+        file.username.as_deref(),
+        file.password.as_deref(),
+        file.password_env.as_deref(),
+        env,
+    )?;
+    if let Some((username, password)) = credentials {
+        upstream_proxy = upstream_proxy.with_credentials(username, password);
+    }
+    Ok(Some(upstream_proxy))
+}
+
+/// Reglas de `[header_rules]` en el orden en que se aplican.
+fn header_rules(file: &HeaderRulesConfig) -> anyhow::Result<Vec<HeaderRule>> {
+    let mut rules = Vec::new();
+    for name in &file.remove_request {
+        rules.push(HeaderRule::remove(HeaderDirection::Request, name)?);
+    }
+    for name in &file.remove_response {
+        rules.push(HeaderRule::remove(HeaderDirection::Response, name)?);
+    }
+    for rule in &file.rules {
+        let direction: HeaderDirection = rule.direction.parse()?;
+        let value = || {
+            rule.value.as_deref().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "[header_rules] `{}` de `{}` necesita `value`",
+                    rule.action,
+                    rule.name
+                )
+            })
+        };
+        let mut parsed = match rule.action.as_str() {
+            "remove" if rule.value.is_some() => {
+                anyhow::bail!(
+                    "[header_rules] `remove` de `{}` no lleva `value`",
+                    rule.name
+                )
+            }
+            "remove" => HeaderRule::remove(direction, &rule.name)?,
+            "set" => HeaderRule::set(direction, &rule.name, value()?)?,
+            "append" => HeaderRule::append(direction, &rule.name, value()?)?,
+            other => anyhow::bail!("acción de [header_rules] desconocida: {other}"),
+        };
+        if let Some(host) = &rule.host {
+            parsed = parsed.with_host(host.parse()?);
+        }
+        rules.push(parsed);
+    }
+    Ok(rules)
+}
+
+/// `--transparent` o `[transparent]` lo activan; `--transparent-scheme`
+/// manda sobre `default_scheme`.
+fn transparent_settings(
+    cli: &Cli,
+    file: Option<&TransparentConfig>,
+) -> anyhow::Result<Option<TransparentSettings>> {
+    if !cli.transparent && file.is_none() && cli.transparent_scheme.is_none() {
+        return Ok(None);
+    }
+    let scheme = cli
+        .transparent_scheme
+        .as_deref()
+        .or(file.and_then(|file| file.default_scheme.as_deref()));
+    let mut transparent = TransparentSettings::default();
+    match scheme {
+        None | Some("http") => {}
+        Some("https") => transparent = transparent.with_scheme(hyper::http::uri::Scheme::HTTPS),
+        Some(other) => anyhow::bail!("Esquema del modo transparente desconocido: {other}"),
+    }
+    Ok(Some(transparent))
+}
+
+/// `--verify-sni` o `[verify_sni]` lo activan.
+fn verify_sni_settings(
+    cli: &Cli,
+    file: Option<&VerifySniConfig>,
+) -> anyhow::Result<Option<VerifySniSettings>> {
+    if !cli.verify_sni && file.is_none() {
+        return Ok(None);
+    }
+    let file = file.cloned().unwrap_or_default();
+    let mut verify_sni = VerifySniSettings::default();
+    if let Some(non_tls) = &file.non_tls {
+        verify_sni = verify_sni.with_non_tls(non_tls.parse()?);
+    }
+    if let Some(secs) = file.hello_timeout_secs {
+        anyhow::ensure!(
+            secs > 0,
+            "verify_sni.hello_timeout_secs debe ser mayor que cero"
+        );
+        verify_sni = verify_sni.with_hello_timeout(Duration::from_secs(secs));
+    }
+    Ok(Some(verify_sni))
+}
+
+/// `--serve-pac` o `[serve_pac]` lo activan; `--pac-advertise` manda sobre
+/// `advertise`.
+fn serve_pac_settings(
+    cli: &Cli,
+    file: Option<&ServePacConfig>,
+) -> anyhow::Result<Option<ServePacSettings>> {
+    if !cli.serve_pac && file.is_none() && cli.pac_advertise.is_none() {
+        return Ok(None);
+    }
+    let default = ServePacConfig::default();
+    let file = file.unwrap_or(&default);
+    let mut serve_pac = ServePacSettings::default();
+    if let Some(advertise) = cli.pac_advertise.as_deref().or(file.advertise.as_deref()) {
+        let authority: hyper::http::uri::Authority = advertise
+            .parse()
+            .ok()
+            .filter(|authority: &hyper::http::uri::Authority| {
+                authority.port_u16().is_some() && !authority.as_str().contains('@')
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!("La dirección que anuncia el PAC debe ser host:puerto: {advertise}")
+            })?;
+        serve_pac = serve_pac.with_advertise(authority);
+    }
+    for rule in &file.bypass {
+        serve_pac = serve_pac.with_bypass(rule.parse()?);
+    }
+    Ok(Some(serve_pac))
+}
+
+/// `--upstream-socks5` manda sobre `address`; las credenciales salen del
+/// archivo, como las de `[upstream_proxy]`.
+fn upstream_socks5_settings(
+    cli_address: Option<&str>,
+    file: Option<&UpstreamSocks5Config>,
+    env: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Option<Socks5UpstreamSettings>> {
+    let default = UpstreamSocks5Config::default();
+    let file = file.unwrap_or(&default);
+    let Some(address) = cli_address.or(file.address.as_deref()) else {
+        if file.username.is_some() {
+            anyhow::bail!("[upstream_socks5] tiene credenciales pero no `address`");
+        }
+        return Ok(None);
+    };
+    let server: hyper::http::uri::Authority = address
+        .parse()
+        .ok()
+        .filter(|server: &hyper::http::uri::Authority| {
+            server.port_u16().is_some() && !server.as_str().contains('@')
+        })
+        .ok_or_else(|| {
+            anyhow::anyhow!("El servidor SOCKS5 debe ser host:puerto, sin credenciales: {address}")
+        })?;
+    let mut socks5 = Socks5UpstreamSettings::new(server);
+    let credentials = section_credentials(
+        "upstream_socks5",
+        file.username.as_deref(),
+        file.password.as_deref(),
+        file.password_env.as_deref(),
+        env,
+    )?;
+    if let Some((username, password)) = credentials {
+        socks5 = socks5.with_credentials(username, password);
+    }
+    Ok(Some(socks5))
+}
+
+/// Usuario y contraseña de una sección: `username` con `password` o
+/// `password_env`, o nada.
+fn section_credentials(
+    section: &str,
+    username: Option<&str>,
+    password: Option<&str>,
+    password_env: Option<&str>,
+    env: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Option<(String, String)>> {
+    let Some(username) = username else {
+        if password.is_some() || password_env.is_some() {
+            anyhow::bail!("[{section}] tiene contraseña pero no `username`");
+        }
+        return Ok(None);
+    };
+    if username.is_empty() || username.contains(':') {
+        anyhow::bail!("usuario de [{section}] inválido: {username:?}");
+    }
+    let password = match (password, password_env) {
+        (Some(password), None) => password.to_string(),
+        (None, Some(name)) => env(name)
+            .filter(|password| !password.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "la variable de entorno {name} con la contraseña de [{section}] no está definida"
+                )
+            })?,
+        _ => anyhow::bail!(
+            "[{section}] requiere exactamente uno de `password` o `password_env`"
+        ),
+    };
+    Ok(Some((username.to_string(), password)))
+}
+
+fn canary_settings(
+    file: &CanaryConfig,
+    env: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<CanarySettings> {
+    let parent = UpstreamProxyConfig {
+        url: file.url.clone(),
+        username: file.username.clone(),
+        password: file.password.clone(),
+        password_env: file.password_env.clone(),
+    };
+    let Some(parent) = upstream_proxy_settings("canary", None, Some(&parent), env)? else {
+        anyhow::bail!("[canary] requiere `url` con el proxy padre del canary");
+    };
+    if !(0.0..=100.0).contains(&file.weight) {
+        anyhow::bail!(
+            "[canary] `weight` debe estar entre 0 y 100: {}",
+            file.weight
+        );
+    }
+    let mut canary = CanarySettings::new(parent, file.weight);
+    for rule in file.hosts.iter().flatten() {
+        canary = canary.with_host(rule.parse()?);
+    }
+    if let Some(secs) = file.window_secs {
+        if secs == 0 {
+            anyhow::bail!("[canary] `window_secs` debe ser mayor que 0");
+        }
+        canary = canary.with_window(Duration::from_secs(secs));
+    }
+    if let Some(min) = file.min_requests {
+        canary = canary.with_min_requests(min);
+    }
+    if let Some(margin) = file.max_error_rate_margin {
+        if !(0.0..=1.0).contains(&margin) {
+            anyhow::bail!("[canary] `max_error_rate_margin` va de 0 a 1: {margin}");
+        }
+        canary = canary.with_error_margin(margin);
+    }
+    if let Some(ms) = file.max_p95_margin_ms {
+        canary = canary.with_p95_margin(Duration::from_millis(ms));
+    }
+    if let Some(url) = &file.webhook {
+        canary = canary.with_webhook(url);
+    }
+    Ok(canary)
+}
+
+fn header_limits(file: &HeaderLimitsConfig) -> anyhow::Result<HeaderLimits> {
+    let mut limits = HeaderLimits::default();
+    if let Some(max) = file.max_header_bytes {
+        limits = limits.with_max_header_bytes(max);
+    }
+    if let Some(max) = file.max_total_bytes {
+        limits = limits.with_max_total_bytes(max);
+    }
+    if let Some(max) = file.max_count {
+        limits = limits.with_max_count(max);
+    }
+    if limits.max_header_bytes() == 0 || limits.max_total_bytes() == 0 || limits.max_count() == 0 {
+        anyhow::bail!("[header_limits] no admite límites a 0");
+    }
+    if limits.max_count() > HeaderLimits::MAX_COUNT {
+        anyhow::bail!(
+            "[header_limits] max_count = {} supera las {} cabeceras que admite hyper en HTTP/1",
+            limits.max_count(),
+            HeaderLimits::MAX_COUNT
+        );
+    }
+    Ok(limits)
+}
+
+fn rate_limit(cli: &Cli, file: &FileConfig) -> anyhow::Result<Option<RateLimit>> {
+    let from_file = file.rate_limit.as_ref();
+    let per_sec = cli.rate_limit.or(from_file.map(|rate| rate.per_sec));
+    let burst = cli.rate_burst.or(from_file.and_then(|rate| rate.burst));
+    match (per_sec, burst) {
+        (Some(per_sec), burst) => {
+            anyhow::ensure!(
+                per_sec > 0.0 && per_sec.is_finite(),
+                "`--rate-limit` debe ser positivo"
+            );
+            // Without an explicit burst, one second's worth of requests.
+            let burst = burst.unwrap_or(per_sec.ceil() as u32).max(1);
+            Ok(Some(RateLimit::new(per_sec, burst)))
+        }
+        (None, Some(_)) => anyhow::bail!("`--rate-burst` requiere `--rate-limit`"),
+        (None, None) => Ok(None),
+    }
+}
+
+fn concurrency_limits(cli: &Cli, file: &FileConfig) -> anyhow::Result<Option<ConcurrencyLimits>> {
+    let from_file = file.concurrency.as_ref();
+    let max_connections = cli
+        .max_connections
+        .or(from_file.and_then(|limits| limits.max_connections));
+    let max_tunnels = cli
+        .max_tunnels
+        .or(from_file.and_then(|limits| limits.max_tunnels));
+    if max_connections.is_none() && max_tunnels.is_none() {
+        return Ok(None);
+    }
+    anyhow::ensure!(
+        max_connections != Some(0) && max_tunnels != Some(0),
+        "`--max-connections` y `--max-tunnels` deben ser positivos"
+    );
+    let mut limits = ConcurrencyLimits::default();
+    if let Some(max) = max_connections {
+        limits = limits.with_max_connections(max);
+    }
+    if let Some(max) = max_tunnels {
+        limits = limits.with_max_tunnels(max);
+    }
+    Ok(Some(limits))
+}
+
+fn overload_settings(
+    overload: &OverloadConfig,
+    file: &FileConfig,
+) -> anyhow::Result<OverloadSettings> {
+    if overload.max_in_flight == 0 {
+        anyhow::bail!("[overload] max_in_flight debe ser mayor que 0");
+    }
+    let mut settings = OverloadSettings::new(overload.max_in_flight);
+    if let Some(max) = overload.max_queue {
+        settings = settings.with_max_queue(max);
+    }
+    if let Some(max) = overload.max_per_client {
+        settings = settings.with_max_per_client(max);
+    }
+    let profiles = file.identity.as_ref().map(|identity| &identity.profiles);
+    for name in &overload.trusted_profiles {
+        if !profiles.is_some_and(|profiles| profiles.iter().any(|p| &p.name == name)) {
+            anyhow::bail!("[overload] trusted_profiles: perfil desconocido en [identity]: {name}");
+        }
+        settings = settings.with_trusted_profile(name);
+    }
+    let queues = [
+        (ClientClass::Trusted, overload.trusted_queue_ms),
+        (ClientClass::Standard, overload.standard_queue_ms),
+        (ClientClass::Anonymous, overload.anonymous_queue_ms),
+    ];
+    for (class, ms) in queues {
+        if let Some(ms) = ms {
+            settings = settings.with_queue_timeout(class, Duration::from_millis(ms));
+        }
+    }
+    if let Some(secs) = overload.max_retry_after_secs {
+        settings = settings.with_max_retry_after(Duration::from_secs(secs));
+    }
+    Ok(settings)
+}
+
+fn fast_path(file: &FileConfig) -> anyhow::Result<Vec<HostPattern>> {
+    file.fast_path.iter().map(|host| host.parse()).collect()
+}
+
+fn rollout_settings(file: &FileConfig) -> anyhow::Result<RolloutSettings> {
+    let mut rollout = RolloutSettings::default();
+    for (name, config) in &file.rollout {
+        let mut feature = FeatureRollout::new(config.percent);
+        for subject in &config.include {
+            feature = feature.with_include(subject);
+        }
+        rollout = rollout.with_feature(name.parse()?, feature);
+    }
+    Ok(rollout)
+}
+
+/// `--upnp` o la sección `[upnp]`; los flags ganan al archivo.
+fn port_mapping_settings(
+    cli: &Cli,
+    file: &FileConfig,
+    listen: SocketAddr,
+) -> anyhow::Result<Option<PortMappingSettings>> {
+    if !cli.upnp && file.upnp.is_none() {
+        return Ok(None);
+    }
+    anyhow::ensure!(
+        !listen.ip().is_loopback(),
+        "UPnP necesita un listener accesible desde la red local, no {listen}"
+    );
+    let upnp = file.upnp.as_ref();
+    let mut port_mapping = PortMappingSettings::default();
+    if let Some(port) = cli
+        .upnp_external_port
+        .or(upnp.and_then(|upnp| upnp.external_port))
+    {
+        anyhow::ensure!(port != 0, "upnp.external_port no puede ser 0");
+        port_mapping = port_mapping.with_external_port(port);
+    }
+    if let Some(secs) = upnp.and_then(|upnp| upnp.lease_secs) {
+        anyhow::ensure!(
+            secs >= 60,
+            "upnp.lease_secs debe ser de al menos 60 segundos"
+        );
+        port_mapping = port_mapping.with_lease(Duration::from_secs(secs));
+    }
+    Ok(Some(port_mapping))
+}
+
+fn data_saver_settings(file: &DataSaverConfig) -> DataSaverSettings {
+    let mut data_saver = DataSaverSettings::default();
+    if let Some(bytes) = file.min_bytes {
+        data_saver = data_saver.with_min_bytes(bytes);
+    }
+    if let Some(bytes) = file.max_input_bytes {
+        data_saver = data_saver.with_max_input_bytes(bytes);
+    }
+    if let Some(quality) = file.jpeg_quality {
+        data_saver = data_saver.with_jpeg_quality(quality);
+    }
+    if let Some(pixels) = file.max_dimension {
+        data_saver = data_saver.with_max_dimension(pixels);
+    }
+    if let Some(ms) = file.timeout_ms {
+        data_saver = data_saver.with_timeout(Duration::from_millis(ms));
+    }
+    if let Some(max) = file.max_concurrent {
+        data_saver = data_saver.with_max_concurrent(max);
+    }
+    if let Some(strip) = file.strip_video_preload {
+        data_saver = data_saver.with_strip_video_preload(strip);
+    }
+    data_saver
+}
+
+fn identity_settings(file: &IdentityConfig) -> anyhow::Result<IdentitySettings> {
+    let mut identity = IdentitySettings::default();
+    if let Some(path) = &file.client_ca {
+        identity = identity.with_client_ca(path);
+    }
+    if let Some(profile) = &file.unknown_profile {
+        identity = identity.with_unknown(UnknownCertPolicy::Profile(profile.clone()));
+    }
+    if let Some(path) = &file.denylist {
+        identity = identity.with_denylist(path);
+    }
+    for profile in &file.profiles {
+        let mut settings = ProfileSettings::new(&profile.name);
+        for host in &profile.allow {
+            settings = settings.with_allow(host.parse()?);
+        }
+        if let Some(data_saver) = profile.data_saver {
+            settings = settings.with_data_saver(data_saver);
+        }
+        identity = identity.with_profile(settings);
+    }
+    for rule in &file.rules {
+        identity = identity.with_rule(cert_matcher(rule)?, &rule.user, &rule.profile);
+    }
+    Ok(identity)
+}
+
+fn cert_matcher(rule: &IdentityRuleConfig) -> anyhow::Result<CertMatcher> {
+    match (&rule.san, &rule.cn, &rule.spki) {
+        (Some(san), None, None) => Ok(CertMatcher::San(san.clone())),
+        (None, Some(cn), None) => Ok(CertMatcher::CommonName(cn.clone())),
+        (None, None, Some(spki)) => Ok(CertMatcher::Spki(spki.clone())),
+        _ => anyhow::bail!(
+            "la regla de identidad de {} requiere exactamente uno de `san`, `cn` o `spki`",
+            rule.user
+        ),
+    }
+}
+
+fn log_profile(cli: &Cli, file: &FileConfig) -> anyhow::Result<LogProfile> {
+    match cli.log_profile.as_deref().or(file.log_profile.as_deref()) {
+        Some(profile) => profile.parse(),
+        None => Ok(LogProfile::default()),
+    }
 }
 
-fn init_tracing(cli: &Cli) {
-    let level = if cli.quiet {
+fn init_tracing(cli: &Cli, file: &FileConfig, sanitizer: Arc<Sanitizer>) {
+    let level = if cli.quiet || file.quiet.unwrap_or(false) {
         Level::ERROR
     } else {
         cli.log_level
+            .as_deref()
+            .or(file.log_level.as_deref())
+            .unwrap_or(DEFAULT_LOG_LEVEL)
             .parse::<Level>()
-            .unwrap_or_else(|_| Level::INFO)
+            .unwrap_or(Level::INFO)
     };
 
     let env_filter = EnvFilter::from_default_env().add_directive(level.into());
 
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(env_filter)
-        .with_target(false)
-        .init();
+        .with_target(false);
+    if sanitizer.profile() == LogProfile::Full {
+        subscriber.init();
+    } else {
+        subscriber.fmt_fields(log_fields(sanitizer)).init();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("proxy-ia").chain(args.iter().copied())).unwrap()
+    }
+
+    fn file(text: &str) -> FileConfig {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_cli_flags_override_the_file_and_the_file_overrides_defaults() {
+        let file = file(
+            "listen = \"127.0.0.1:9000\"\nclient_idle_timeout = 30\nclient_max_requests_per_connection = 5\n",
+        );
+        let settings = build_settings(&cli(&["--listen", "127.0.0.1:9100"]), &file).unwrap();
+        assert_eq!(settings.listen(), "127.0.0.1:9100".parse().unwrap());
+        let limits = settings.connection_limits();
+        assert_eq!(limits.idle_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(limits.max_requests(), Some(5));
+
+        let settings =
+            build_settings(&cli(&["--client-max-requests-per-connection", "9"]), &file).unwrap();
+        assert_eq!(settings.listen(), "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(settings.connection_limits().max_requests(), Some(9));
+        assert!(settings.listen_uds().is_none());
+
+        let file = self::file("listen_uds = \"/run/a.sock\"\nlisten_uds_mode = \"0o600\"\n");
+        let settings = build_settings(&cli(&["--listen-uds", "/run/b.sock"]), &file).unwrap();
+        let uds = settings.listen_uds().unwrap();
+        assert_eq!(uds.path(), Path::new("/run/b.sock"));
+        assert_eq!(uds.mode(), 0o600);
+        let bad = self::file("listen_uds = \"/run/a.sock\"\nlisten_uds_mode = \"999\"\n");
+        assert!(build_settings(&cli(&[]), &bad).is_err());
+    }
+
+    #[test]
+    fn test_auth_users_come_from_the_table_and_the_list() {
+        let file = file(
+            "[auth]\nusername = \"ana\"\npassword_env = \"ANA_PASSWORD\"\n\
+             [[auth.users]]\nusername = \"luis\"\npassword = \"otra\"\n",
+        );
+        let env = |name: &str| (name == "ANA_PASSWORD").then(|| "secreto".to_string());
+        let auth = auth_settings(file.auth.as_ref().unwrap(), env).unwrap();
+        let users: Vec<&str> = auth.users().iter().map(|(user, _)| user.as_str()).collect();
+        assert_eq!(users, ["ana", "luis"]);
+        assert!(auth.users()[0].1.matches(b"secreto"));
+
+        let error = auth_settings(file.auth.as_ref().unwrap(), |_| None).unwrap_err();
+        assert!(error.to_string().contains("ANA_PASSWORD"));
+        let empty = AuthConfig::default();
+        assert!(auth_settings(&empty, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_policy_simulation_diffs_the_fixture_log_against_the_baseline() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/policy");
+        let new = ResolvedConfig::load(&fixtures.join("new.toml"), None)
+            .and_then(|resolved| resolved.parse())
+            .unwrap();
+        let settings = build_settings(&cli(&[]), &new).unwrap();
+        let input = policy_sim::read_input(&fixtures.join("access.log.json")).unwrap();
+        let (_, report) = simulate(
+            &cli(&[]),
+            &settings,
+            &input,
+            Some(&fixtures.join("old.toml")),
+        )
+        .unwrap();
+
+        assert_eq!((report.requests, report.skipped), (7, 2));
+        assert_eq!((report.allowed, report.blocked), (4, 3));
+        let hits = |policy: &str, rule: Option<&str>| {
+            report
+                .rules
+                .iter()
+                .find(|count| count.hit.policy == policy && count.hit.rule.as_deref() == rule)
+                .map_or(0, |count| count.hits)
+        };
+        assert_eq!(hits("egress", Some("openai")), 2);
+        assert_eq!(hits("egress", Some("interno")), 2);
+        assert_eq!(hits("egress", None), 1);
+        assert_eq!(hits("host_filter", Some("tracker.example")), 1);
+
+        let diff = &report.comparisons[0];
+        assert_eq!(diff.newly_blocked.count, 2);
+        let blocked: Vec<&str> = diff
+            .newly_blocked
+            .examples
+            .iter()
+            .map(|target| target.host.as_str())
+            .collect();
+        assert_eq!(blocked, ["tracker.example", "github.com"]);
+        // The old config kept the default CONNECT ports.
+        assert_eq!(diff.newly_allowed.count, 1);
+        assert_eq!(diff.newly_allowed.examples[0].port, 8443);
+    }
+
+    #[test]
+    fn test_defaults_apply_without_cli_flags_or_file() {
+        let settings = build_settings(&cli(&[]), &FileConfig::default()).unwrap();
+        assert_eq!(settings.listen(), DEFAULT_LISTEN.parse().unwrap());
+        assert!(settings.connection_limits().is_unlimited());
+        assert_eq!(
+            settings.drain_timeout(),
+            ProxySettings::new(settings.listen()).drain_timeout()
+        );
+    }
 }
@@ -0,0 +1,78 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::{Body, Method, Request};
+
+/// Vista de solo lectura de una petición, independiente de los tipos de hyper.
+///
+/// Es lo que ven las políticas y los scripts: nunca pueden modificar la
+/// petición original a través de ella.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestMeta {
+    pub method: String,
+    pub host: String,
+    pub port: Option<u16>,
+    pub path: String,
+    pub client_ip: IpAddr,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestMeta {
+    pub fn from_request(req: &Request<Body>, remote_addr: SocketAddr) -> Self {
+        let uri = req.uri();
+        let host = uri
+            .host()
+            .map(|h| h.trim_start_matches('[').trim_end_matches(']'))
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let path = if req.method() == Method::CONNECT {
+            String::new()
+        } else {
+            uri.path().to_string()
+        };
+        let headers = req
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|v| (name.as_str().to_string(), v.to_string()))
+            })
+            .collect();
+
+        Self {
+            method: req.method().as_str().to_string(),
+            host,
+            port: uri.port_u16(),
+            path,
+            client_ip: remote_addr.ip(),
+            headers,
+        }
+    }
+
+    pub fn is_connect(&self) -> bool {
+        self.method == Method::CONNECT.as_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_request() {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("http://Example.com:8080/api/v1?x=1")
+            .header("user-agent", "test")
+            .body(Body::empty())
+            .unwrap();
+        let meta = RequestMeta::from_request(&req, "10.0.0.1:5000".parse().unwrap());
+        assert_eq!(meta.host, "example.com");
+        assert_eq!(meta.port, Some(8080));
+        assert_eq!(meta.path, "/api/v1");
+        assert_eq!(meta.client_ip.to_string(), "10.0.0.1");
+        assert_eq!(meta.headers, vec![("user-agent".into(), "test".into())]);
+        assert!(!meta.is_connect());
+    }
+}
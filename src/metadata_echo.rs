@@ -0,0 +1,261 @@
+//! Metadatos de cada petición en cabeceras `X-ProxyIA-*`.
+//!
+//! Pensado para las suites de automatización de navegadores, que necesitan
+//! saber qué decidió el proxy con cada recurso de una página. Con
+//! `[metadata_echo]`, las respuestas a los clientes de `clients` y a los
+//! autenticados con un perfil de `profiles` llevan:
+//!
+//! - `X-ProxyIA-Request-Id`: el id de la petición de [`crate::request_id`]
+//!   o, si está desactivado, el `X-Request-Id` del cliente si es válido o
+//!   uno nuevo.
+//! - `X-ProxyIA-Decision`: `forward` si la respuesta viene del destino,
+//!   `cache` si sale de la caché de respuestas y `local` si la generó el
+//!   proxy (`local; error=<categoría>` para los fallos hacia el destino);
+//!   detrás, `fast-path` y `attempts=N` cuando aplican.
+//! - `X-ProxyIA-Route`: `direct`, `parent`, `pac`, `canary`, `cache` o
+//!   `local`.
+//! - `X-ProxyIA-Cache`: `hit`, `miss` o `none`.
+//! - `X-ProxyIA-Upstream-Ms`: milisegundos hasta las cabeceras del destino.
+//!
+//! Entran en ese orden mientras quepan en `max_bytes`. Una respuesta que una
+//! caché compartida podría guardar (`public`, `max-age`, `s-maxage`,
+//! `Expires` o `Last-Modified`, sin `private` ni `no-store`) las serviría a
+//! otros clientes: con `cacheable = "private"` (por defecto) pasa a
+//! `Cache-Control: private`, y con `"skip"` no las lleva.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderName, HeaderValue, CACHE_CONTROL, EXPIRES, LAST_MODIFIED};
+use hyper::{Body, HeaderMap, Request, Response};
+
+use crate::identity::Identity;
+use crate::request_id::{self, RequestId};
+use crate::response_cache::X_CACHE;
+use crate::settings::{EchoCacheable, MetadataEchoSettings};
+
+pub const X_PROXYIA_REQUEST_ID: HeaderName = HeaderName::from_static("x-proxyia-request-id");
+pub const X_PROXYIA_DECISION: HeaderName = HeaderName::from_static("x-proxyia-decision");
+pub const X_PROXYIA_ROUTE: HeaderName = HeaderName::from_static("x-proxyia-route");
+pub const X_PROXYIA_CACHE: HeaderName = HeaderName::from_static("x-proxyia-cache");
+pub const X_PROXYIA_UPSTREAM_MS: HeaderName = HeaderName::from_static("x-proxyia-upstream-ms");
+
+/// Cómo llegó la respuesta del destino; `handle_http` la deja en sus
+/// extensiones.
+#[derive(Debug, Clone, Copy)]
+pub struct Upstream {
+    pub route: &'static str,
+    pub latency: Duration,
+    pub attempts: u32,
+}
+
+pub struct MetadataEcho {
+    settings: MetadataEchoSettings,
+    seq: AtomicU64,
+}
+
+impl MetadataEcho {
+    pub fn new(settings: MetadataEchoSettings) -> Self {
+        Self {
+            settings,
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    pub fn settings(&self) -> &MetadataEchoSettings {
+        &self.settings
+    }
+
+    /// Si la respuesta a `req` lleva los metadatos.
+    pub fn trusts(&self, req: &Request<Body>, client: IpAddr) -> bool {
+        self.settings
+            .clients()
+            .iter()
+            .any(|range| range.contains(client))
+            || req.extensions().get::<Identity>().is_some_and(|identity| {
+                self.settings
+                    .profiles()
+                    .iter()
+                    .any(|profile| profile == identity.profile())
+            })
+    }
+
+    /// El id que le dio a `req` [`crate::request_id`] o, sin él, su
+    /// `X-Request-Id` si es válido, o uno nuevo.
+    pub fn request_id(&self, req: &Request<Body>) -> String {
+        if let Some(RequestId(id)) = req.extensions().get::<RequestId>() {
+            return id.clone();
+        }
+        let incoming = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| request_id::well_formed(id));
+        match incoming {
+            Some(id) => id.to_string(),
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                format!("{now}-{}", self.seq.fetch_add(1, Ordering::Relaxed))
+            }
+        }
+    }
+
+    /// Añade las cabeceras a `response`, o nada si una caché compartida
+    /// podría guardarla y `cacheable` es `skip`.
+    pub fn annotate(&self, id: &str, fast: bool, response: &mut Response<Body>) {
+        let cacheable = shared_cacheable(response.headers());
+        if cacheable && self.settings.cacheable() == EchoCacheable::Skip {
+            return;
+        }
+        let upstream = response.extensions().get::<Upstream>().copied();
+        let headers = response.headers_mut();
+        let cache = match headers.get(X_CACHE).map(HeaderValue::as_bytes) {
+            Some(b"HIT") => "hit",
+            Some(b"MISS") => "miss",
+            _ => "none",
+        };
+        let (mut decision, route) = match upstream {
+            Some(upstream) => ("forward".to_string(), upstream.route),
+            None if cache == "hit" => ("cache".to_string(), "cache"),
+            None => ("local".to_string(), "local"),
+        };
+        if let Some(category) = headers
+            .get("x-proxy-error")
+            .and_then(|value| value.to_str().ok())
+        {
+            decision = format!("local; error={category}");
+        }
+        if fast {
+            decision.push_str("; fast-path");
+        }
+        if let Some(attempts) = upstream
+            .map(|upstream| upstream.attempts)
+            .filter(|n| *n > 1)
+        {
+            decision.push_str(&format!("; attempts={attempts}"));
+        }
+        let mut values = vec![
+            (X_PROXYIA_REQUEST_ID, id.to_string()),
+            (X_PROXYIA_DECISION, decision),
+            (X_PROXYIA_ROUTE, route.to_string()),
+            (X_PROXYIA_CACHE, cache.to_string()),
+        ];
+        if let Some(upstream) = upstream {
+            let ms = upstream.latency.as_millis().to_string();
+            values.push((X_PROXYIA_UPSTREAM_MS, ms));
+        }
+        let mut left = self.settings.max_bytes();
+        for (name, value) in values {
+            let size = name.as_str().len() + value.len();
+            let Ok(value) = HeaderValue::from_str(&value) else {
+                continue;
+            };
+            if size > left {
+                break;
+            }
+            left -= size;
+            headers.insert(name, value);
+        }
+        if cacheable {
+            make_private(headers);
+        }
+    }
+}
+
+/// Si una caché compartida podría guardar la respuesta.
+fn shared_cacheable(headers: &HeaderMap) -> bool {
+    let directives = directives(headers);
+    if directives.iter().any(|d| d == "private" || d == "no-store") {
+        return false;
+    }
+    directives.iter().any(|d| {
+        d == "public"
+            || d.starts_with("s-maxage=")
+            || d.strip_prefix("max-age=")
+                .is_some_and(|secs| secs.trim_matches('"') != "0")
+    }) || headers.contains_key(EXPIRES)
+        || headers.contains_key(LAST_MODIFIED)
+}
+
+/// Deja `Cache-Control` en `private`, sin las directivas para cachés
+/// compartidas.
+fn make_private(headers: &mut HeaderMap) {
+    let mut kept: Vec<String> = directives(headers)
+        .into_iter()
+        .filter(|d| d != "public" && !d.starts_with("s-maxage="))
+        .collect();
+    kept.push("private".to_string());
+    if let Ok(value) = HeaderValue::from_str(&kept.join(", ")) {
+        headers.insert(CACHE_CONTROL, value);
+    }
+}
+
+fn directives(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .filter(|directive| !directive.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(settings: MetadataEchoSettings) -> MetadataEcho {
+        MetadataEcho::new(settings.with_client("10.0.0.0/8".parse().unwrap()))
+    }
+
+    #[test]
+    fn test_headers_fit_the_size_cap_in_order() {
+        let mut response = Response::new(Body::empty());
+        response.extensions_mut().insert(Upstream {
+            route: "parent",
+            latency: Duration::from_millis(42),
+            attempts: 2,
+        });
+        echo(MetadataEchoSettings::default()).annotate("abc", true, &mut response);
+        let headers = response.headers();
+        assert_eq!(headers[X_PROXYIA_REQUEST_ID], "abc");
+        assert_eq!(
+            headers[X_PROXYIA_DECISION],
+            "forward; fast-path; attempts=2"
+        );
+        assert_eq!(headers[X_PROXYIA_ROUTE], "parent");
+        assert_eq!(headers[X_PROXYIA_CACHE], "none");
+        assert_eq!(headers[X_PROXYIA_UPSTREAM_MS], "42");
+        assert!(!headers.contains_key(CACHE_CONTROL));
+
+        // Room for the id and the decision only.
+        let mut response = Response::new(Body::empty());
+        echo(MetadataEchoSettings::default().with_max_bytes(60)).annotate(
+            "abc",
+            false,
+            &mut response,
+        );
+        let names: Vec<_> = response.headers().keys().map(|n| n.as_str()).collect();
+        assert_eq!(names, ["x-proxyia-request-id", "x-proxyia-decision"]);
+    }
+
+    #[test]
+    fn test_request_id_accepts_only_well_formed_ids() {
+        let echo = echo(MetadataEchoSettings::default());
+        let req = |id: &str| {
+            Request::builder()
+                .header("x-request-id", id)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(echo.request_id(&req("run-7.step:3")), "run-7.step:3");
+        let fresh = echo.request_id(&req("a b"));
+        assert_ne!(fresh, "a b");
+        assert_ne!(echo.request_id(&req("a b")), fresh);
+    }
+}
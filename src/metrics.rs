@@ -0,0 +1,991 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use hyper::{Method, StatusCode};
+use serde::Serialize;
+
+use crate::ban::Violation;
+use crate::concurrency::ConcurrencyLimit;
+use crate::connection::{CloseReason, Protocol};
+use crate::error::ProxyError;
+use crate::header_limits::HeaderLimit;
+use crate::overload::{Shed, ShedReason};
+use crate::response_cache::StaleReason;
+use crate::settings::{ClientClass, Feature, ReputationAction};
+use crate::tunnel_quality::{Histogram, QualityHistograms, QualitySample};
+
+/// Métodos que se cuentan por separado; el resto va a `OTHER`.
+pub const REQUEST_METHODS: [&str; 10] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "PATCH", "TRACE", "OTHER",
+];
+
+/// Clases de código de las respuestas; `none` si no hubo respuesta.
+pub const STATUS_CLASSES: [&str; 6] = ["none", "1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// Límites superiores de los buckets de duración de las peticiones, en
+/// segundos.
+const REQUEST_DURATION_BUCKETS_SECS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Límites superiores de los buckets de duración de los saludos TLS con los
+/// destinos, en segundos.
+const TLS_HANDSHAKE_BUCKETS_SECS: [f64; 9] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Límites superiores de los buckets del `Retry-After` de los descartes por
+/// sobrecarga, en segundos.
+const RETRY_AFTER_BUCKETS_SECS: [f64; 8] = [1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+/// Hosts de destino con contador de bytes propio como mucho; el tráfico de
+/// los que llegan después se suma en [`OTHER_HOSTS`].
+pub const MAX_TRAFFIC_HOSTS: usize = 4096;
+
+/// Entrada que agrupa los hosts que no caben en [`MAX_TRAFFIC_HOSTS`].
+pub const OTHER_HOSTS: &str = "(otros)";
+
+fn method_index(method: &Method) -> usize {
+    REQUEST_METHODS
+        .iter()
+        .position(|known| *known == method.as_str())
+        .unwrap_or(REQUEST_METHODS.len() - 1)
+}
+
+fn status_index(status: Option<StatusCode>) -> usize {
+    status.map_or(0, |status| usize::from(status.as_u16() / 100).clamp(1, 5))
+}
+
+/// Petición de un cliente, tal como la etiquetan las métricas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLabels {
+    pub method: Method,
+    /// Host de destino; vacío si la petición no lo trae.
+    pub host: String,
+    pub protocol: Protocol,
+    pub client: IpAddr,
+}
+
+/// Túnel `CONNECT`, tal como lo etiquetan las métricas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelLabels {
+    pub host: String,
+    pub port: u16,
+    pub via_parent: bool,
+    pub client: IpAddr,
+}
+
+/// Cachés del proxy que informan de aciertos y fallos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    /// Puntuaciones de reputación por IP.
+    Reputation,
+    /// Respuestas guardadas por `Idempotency-Key`.
+    Idempotency,
+    /// Respuestas de `[response_cache]`.
+    Response,
+    /// Respuestas de `[ai_router.cache]`.
+    Llm,
+}
+
+impl CacheKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheKind::Reputation => "reputation",
+            CacheKind::Idempotency => "idempotency",
+            CacheKind::Response => "response",
+            CacheKind::Llm => "llm",
+        }
+    }
+}
+
+/// Política que decidió sobre una petición.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Egress,
+    Reputation,
+    Script,
+    Profile,
+    HostFilter,
+    Blocklist,
+    BlockedNetwork,
+}
+
+impl Policy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Policy::Egress => "egress",
+            Policy::Reputation => "reputation",
+            Policy::Script => "script",
+            Policy::Profile => "profile",
+            Policy::HostFilter => "host_filter",
+            Policy::Blocklist => "blocklist",
+            Policy::BlockedNetwork => "blocked_network",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allow,
+    Block,
+}
+
+impl PolicyDecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyDecision::Allow => "allow",
+            PolicyDecision::Block => "block",
+        }
+    }
+}
+
+/// Destino de las métricas del proxy para quien lo embebe y ya tiene su
+/// propio sistema. Cada punto de instrumentación pasa por aquí además de por
+/// los contadores de [`Metrics`], que alimentan la API de administración.
+/// Todos los métodos tienen una implementación vacía, así que basta con
+/// implementar los que interesen. Se llaman en el camino de cada petición:
+/// no deben bloquear.
+pub trait ProxyMetrics: Send + Sync + 'static {
+    /// Llegó una petición de un cliente.
+    fn request_started(&self, _request: &RequestLabels) {}
+
+    /// El proxy respondió a la petición; `status` es `None` si la conexión
+    /// falló antes de responder. En un `CONNECT`, termina al abrir el túnel.
+    fn request_finished(
+        &self,
+        _request: &RequestLabels,
+        _status: Option<StatusCode>,
+        _elapsed: Duration,
+    ) {
+    }
+
+    fn tunnel_opened(&self, _tunnel: &TunnelLabels) {}
+
+    /// `bytes_sent` va del cliente al destino; `bytes_received`, al revés.
+    fn tunnel_closed(&self, _tunnel: &TunnelLabels, _bytes_sent: u64, _bytes_received: u64) {}
+
+    /// Muestra periódica de un túnel largo con `[tunnel_quality]`.
+    fn tunnel_quality(&self, _tunnel: &TunnelLabels, _sample: &QualitySample) {}
+
+    fn cache_lookup(&self, _cache: CacheKind, _hit: bool) {}
+
+    fn policy_decision(&self, _policy: Policy, _decision: PolicyDecision) {}
+
+    fn upstream_error(&self, _category: ProxyError) {}
+
+    fn upstream_body_aborted(&self) {}
+
+    fn connection_accepted(&self, _protocol: Protocol) {}
+
+    fn connection_closed(&self, _reason: CloseReason) {}
+
+    /// Veredicto de reputación de una IP de destino o de cliente.
+    fn reputation_verdict(&self, _action: ReputationAction) {}
+
+    fn reputation_unavailable(&self) {}
+
+    fn data_saver_transcoded(&self, _saved_bytes: u64) {}
+
+    fn data_saver_passthrough(&self) {}
+
+    fn feature_exposure(&self, _feature: Feature, _enabled: bool) {}
+
+    fn violation(&self, _violation: Violation) {}
+
+    /// Petición rechazada con un 431 por `[header_limits]`.
+    fn request_headers_rejected(&self, _limit: HeaderLimit) {}
+
+    fn ban_rejected(&self) {}
+
+    fn accept_rejected(&self) {}
+
+    fn accept_paused(&self) {}
+
+    /// `accept` falló por falta de descriptores y se pausó la aceptación.
+    fn accept_fd_exhausted(&self) {}
+
+    fn error_page_replaced(&self) {}
+
+    fn error_page_unreplaceable(&self) {}
+
+    /// Bytes de respuestas retenidos ahora por el proxy, sumando todas las
+    /// peticiones.
+    fn buffered_bytes(&self, _bytes: u64) {}
+
+    /// Se descartó una petición por sobrecarga con un 503.
+    fn overload_shed(&self, _shed: &Shed) {}
+
+    /// Una IP sin cupo de peticiones recibió un 429.
+    fn request_rate_limited(&self) {}
+
+    /// Se cerró un túnel a petición de la API de administración.
+    fn tunnel_terminated(&self) {}
+
+    /// Se rechazó una petición o un túnel por `max_connections` (503) o
+    /// `max_tunnels` (429).
+    fn concurrency_rejected(&self, _limit: ConcurrencyLimit) {}
+
+    /// `[response_cache]` sirvió una copia caducada.
+    fn stale_served(&self, _reason: StaleReason) {}
+}
+
+/// Destino por defecto: no hace nada.
+pub struct NoopMetrics;
+
+impl ProxyMetrics for NoopMetrics {}
+
+struct Sink(RwLock<Arc<dyn ProxyMetrics>>);
+
+impl Default for Sink {
+    fn default() -> Self {
+        Self(RwLock::new(Arc::new(NoopMetrics)))
+    }
+}
+
+impl std::fmt::Debug for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Sink")
+    }
+}
+
+/// Contadores internos del proxy. Se comparten entre conexiones mediante `Arc`
+/// y solo usan atómicos para no bloquear el camino caliente. Cada evento se
+/// reenvía además al [`ProxyMetrics`] configurado.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sink: Sink,
+    requests: AtomicU64,
+    upstream_errors: [AtomicU64; ProxyError::ALL.len()],
+    upstream_body_aborts: AtomicU64,
+    upstream_retries: AtomicU64,
+    upstream_connections: AtomicU64,
+    request_body_rejections: AtomicU64,
+    connection_closes: [AtomicU64; CloseReason::ALL.len()],
+    protocols: [AtomicU64; Protocol::ALL.len()],
+    reputation_verdicts: [AtomicU64; ReputationAction::ALL.len()],
+    reputation_unavailable: AtomicU64,
+    data_saver_bytes_saved: AtomicU64,
+    data_saver_passthrough: AtomicU64,
+    /// Por feature: evaluaciones `[desactivada, activada]`.
+    feature_exposures: [[AtomicU64; 2]; Feature::ALL.len()],
+    violations: [AtomicU64; Violation::ALL.len()],
+    header_rejections: [AtomicU64; HeaderLimit::ALL.len()],
+    ban_rejections: AtomicU64,
+    accept_rejections: AtomicU64,
+    accept_pauses: AtomicU64,
+    accept_fd_exhausted: AtomicU64,
+    error_pages_replaced: AtomicU64,
+    error_pages_unreplaceable: AtomicU64,
+    buffered_bytes: AtomicU64,
+    buffered_high_water: AtomicU64,
+    tunnel_quality: QualityHistograms,
+    /// Por método, peticiones terminadas por clase de código.
+    finished: [[AtomicU64; STATUS_CLASSES.len()]; REQUEST_METHODS.len()],
+    request_duration: RequestDuration,
+    tunnels_open: AtomicU64,
+    tunnel_bytes_sent: AtomicU64,
+    tunnel_bytes_received: AtomicU64,
+    /// Por motivo, descartes por sobrecarga de cada clase de cliente.
+    overload_shed: [[AtomicU64; ClientClass::ALL.len()]; ShedReason::ALL.len()],
+    overload_retry_after: RetryAfter,
+    rate_limited: AtomicU64,
+    tunnels_terminated: AtomicU64,
+    concurrency_rejected: [AtomicU64; ConcurrencyLimit::ALL.len()],
+    stale_served: [AtomicU64; StaleReason::ALL.len()],
+    prefetch_queued: AtomicU64,
+    prefetch_dropped: AtomicU64,
+    prefetch_hits: AtomicU64,
+    hosts: RwLock<HashMap<String, Arc<HostBytes>>>,
+    upstream_tls: RwLock<HashMap<String, Arc<HostTls>>>,
+    tls_handshake: TlsHandshakeDuration,
+}
+
+/// Bytes intercambiados con un host de destino: bodies de las peticiones
+/// HTTP y sus respuestas, y lo copiado por los túneles al cerrarse.
+#[derive(Debug, Default)]
+pub struct HostBytes {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl HostBytes {
+    /// Del cliente hacia el destino.
+    pub fn add_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Del destino hacia el cliente.
+    pub fn add_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Tráfico acumulado de un host, para `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HostTraffic {
+    pub host: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug)]
+struct RequestDuration(Histogram);
+
+impl Default for RequestDuration {
+    fn default() -> Self {
+        Self(Histogram::new(&REQUEST_DURATION_BUCKETS_SECS))
+    }
+}
+
+/// Saludos TLS y peticiones `https://` del pool hacia un host de destino.
+#[derive(Debug, Default)]
+pub struct HostTls {
+    full: AtomicU64,
+    resumed: AtomicU64,
+    full_micros: AtomicU64,
+    resumed_micros: AtomicU64,
+    requests: AtomicU64,
+}
+
+impl HostTls {
+    /// Una petición que sale por el pool, por una conexión nueva o no.
+    pub fn add_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Saludos y reutilización de conexiones de un host, para `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostTlsStats {
+    pub host: String,
+    pub full_handshakes: u64,
+    pub resumed_handshakes: u64,
+    pub full_handshake_ms_avg: Option<f64>,
+    pub resumed_handshake_ms_avg: Option<f64>,
+    pub requests: u64,
+    /// Fracción de las peticiones que fueron por una conexión ya abierta.
+    pub reuse_ratio: Option<f64>,
+}
+
+#[derive(Debug)]
+struct TlsHandshakeDuration(Histogram);
+
+impl Default for TlsHandshakeDuration {
+    fn default() -> Self {
+        Self(Histogram::new(&TLS_HANDSHAKE_BUCKETS_SECS))
+    }
+}
+
+#[derive(Debug)]
+struct RetryAfter(Histogram);
+
+impl Default for RetryAfter {
+    fn default() -> Self {
+        Self(Histogram::new(&RETRY_AFTER_BUCKETS_SECS))
+    }
+}
+
+impl Metrics {
+    /// Reemplaza el destino de los eventos; por defecto, [`NoopMetrics`].
+    pub fn set_sink(&self, sink: Arc<dyn ProxyMetrics>) {
+        *self.sink.0.write().expect("lock de métricas") = sink;
+    }
+
+    fn sink(&self) -> Arc<dyn ProxyMetrics> {
+        self.sink.0.read().expect("lock de métricas").clone()
+    }
+
+    pub fn record_request_started(&self, request: &RequestLabels) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.sink().request_started(request);
+    }
+
+    pub fn record_request_finished(
+        &self,
+        request: &RequestLabels,
+        status: Option<StatusCode>,
+        elapsed: Duration,
+    ) {
+        self.finished[method_index(&request.method)][status_index(status)]
+            .fetch_add(1, Ordering::Relaxed);
+        self.request_duration.0.observe(elapsed.as_secs_f64());
+        self.sink().request_finished(request, status, elapsed);
+    }
+
+    pub fn record_tunnel_opened(&self, tunnel: &TunnelLabels) {
+        self.tunnels_open.fetch_add(1, Ordering::Relaxed);
+        self.sink().tunnel_opened(tunnel);
+    }
+
+    pub fn record_tunnel_closed(
+        &self,
+        tunnel: &TunnelLabels,
+        bytes_sent: u64,
+        bytes_received: u64,
+    ) {
+        self.tunnels_open.fetch_sub(1, Ordering::Relaxed);
+        self.tunnel_bytes_sent
+            .fetch_add(bytes_sent, Ordering::Relaxed);
+        self.tunnel_bytes_received
+            .fetch_add(bytes_received, Ordering::Relaxed);
+        let host = self.host_bytes(&tunnel.host);
+        host.add_sent(bytes_sent);
+        host.add_received(bytes_received);
+        self.sink()
+            .tunnel_closed(tunnel, bytes_sent, bytes_received);
+    }
+
+    /// `retransmits` son las del cliente y las del destino desde la muestra
+    /// anterior, cuando se conocen.
+    pub fn record_tunnel_quality(
+        &self,
+        tunnel: &TunnelLabels,
+        sample: &QualitySample,
+        retransmits: [Option<u32>; 2],
+    ) {
+        for info in [sample.client, sample.upstream].into_iter().flatten() {
+            self.tunnel_quality.rtt_ms.observe(info.rtt_ms);
+        }
+        for delta in retransmits.into_iter().flatten() {
+            self.tunnel_quality.retransmits.observe(f64::from(delta));
+        }
+        self.sink().tunnel_quality(tunnel, sample);
+    }
+
+    pub fn tunnel_quality(&self) -> &QualityHistograms {
+        &self.tunnel_quality
+    }
+
+    pub fn record_cache_lookup(&self, cache: CacheKind, hit: bool) {
+        self.sink().cache_lookup(cache, hit);
+    }
+
+    pub fn record_policy_decision(&self, policy: Policy, decision: PolicyDecision) {
+        self.sink().policy_decision(policy, decision);
+    }
+
+    pub fn record_upstream_error(&self, kind: ProxyError) {
+        self.upstream_errors[kind.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink().upstream_error(kind);
+    }
+
+    pub fn record_upstream_body_abort(&self) {
+        self.upstream_body_aborts.fetch_add(1, Ordering::Relaxed);
+        self.sink().upstream_body_aborted();
+    }
+
+    /// Una petición idempotente se vuelve a enviar al destino.
+    pub fn record_upstream_retry(&self) {
+        self.upstream_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// El pool de las peticiones HTTP abre una conexión con un destino.
+    pub fn record_upstream_connection(&self) {
+        self.upstream_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Una petición superó `max_request_body`.
+    pub fn record_request_body_rejection(&self) {
+        self.request_body_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_close(&self, reason: CloseReason) {
+        self.connection_closes[reason.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink().connection_closed(reason);
+    }
+
+    pub fn record_protocol(&self, protocol: Protocol) {
+        self.protocols[protocol.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink().connection_accepted(protocol);
+    }
+
+    pub fn record_reputation_verdict(&self, action: ReputationAction) {
+        self.reputation_verdicts[action.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink().reputation_verdict(action);
+    }
+
+    pub fn record_reputation_unavailable(&self) {
+        self.reputation_unavailable.fetch_add(1, Ordering::Relaxed);
+        self.sink().reputation_unavailable();
+    }
+
+    pub fn record_transcode_saved(&self, bytes: u64) {
+        self.data_saver_bytes_saved
+            .fetch_add(bytes, Ordering::Relaxed);
+        self.sink().data_saver_transcoded(bytes);
+    }
+
+    pub fn record_transcode_passthrough(&self) {
+        self.data_saver_passthrough.fetch_add(1, Ordering::Relaxed);
+        self.sink().data_saver_passthrough();
+    }
+
+    pub fn record_feature_exposure(&self, feature: Feature, enabled: bool) {
+        self.feature_exposures[feature.index()][usize::from(enabled)]
+            .fetch_add(1, Ordering::Relaxed);
+        self.sink().feature_exposure(feature, enabled);
+    }
+
+    pub fn record_violation(&self, violation: Violation) {
+        self.violations[violation.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink().violation(violation);
+    }
+
+    pub fn record_header_rejection(&self, limit: HeaderLimit) {
+        self.header_rejections[limit.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink().request_headers_rejected(limit);
+    }
+
+    pub fn record_ban_rejection(&self) {
+        self.ban_rejections.fetch_add(1, Ordering::Relaxed);
+        self.sink().ban_rejected();
+    }
+
+    pub fn record_accept_rejection(&self) {
+        self.accept_rejections.fetch_add(1, Ordering::Relaxed);
+        self.sink().accept_rejected();
+    }
+
+    pub fn record_accept_pause(&self) {
+        self.accept_pauses.fetch_add(1, Ordering::Relaxed);
+        self.sink().accept_paused();
+    }
+
+    pub fn record_accept_fd_exhausted(&self) {
+        self.accept_fd_exhausted.fetch_add(1, Ordering::Relaxed);
+        self.sink().accept_fd_exhausted();
+    }
+
+    pub fn record_error_page_replaced(&self) {
+        self.error_pages_replaced.fetch_add(1, Ordering::Relaxed);
+        self.sink().error_page_replaced();
+    }
+
+    pub fn record_error_page_unreplaceable(&self) {
+        self.error_pages_unreplaceable
+            .fetch_add(1, Ordering::Relaxed);
+        self.sink().error_page_unreplaceable();
+    }
+
+    pub fn record_overload_shed(&self, shed: &Shed) {
+        self.overload_shed[shed.reason.index()][shed.class.index()].fetch_add(1, Ordering::Relaxed);
+        self.overload_retry_after
+            .0
+            .observe(shed.retry_after.as_secs_f64());
+        self.sink().overload_shed(shed);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+        self.sink().request_rate_limited();
+    }
+
+    pub fn record_tunnel_terminated(&self) {
+        self.tunnels_terminated.fetch_add(1, Ordering::Relaxed);
+        self.sink().tunnel_terminated();
+    }
+
+    pub fn record_concurrency_rejected(&self, limit: ConcurrencyLimit) {
+        self.concurrency_rejected[limit.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink().concurrency_rejected(limit);
+    }
+
+    pub fn record_stale_served(&self, reason: StaleReason) {
+        self.stale_served[reason.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink().stale_served(reason);
+    }
+
+    /// Se encoló una precarga; con `queued` a `false`, la cola estaba llena.
+    pub fn record_prefetch(&self, queued: bool) {
+        match queued {
+            true => self.prefetch_queued.fetch_add(1, Ordering::Relaxed),
+            false => self.prefetch_dropped.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Un cliente recibió de la caché un recurso que guardó una precarga.
+    pub fn record_prefetch_hit(&self) {
+        self.prefetch_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Peticiones recibidas de clientes desde el arranque.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream_errors(&self, kind: ProxyError) -> u64 {
+        self.upstream_errors[kind.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn upstream_body_aborts(&self) -> u64 {
+        self.upstream_body_aborts.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream_retries(&self) -> u64 {
+        self.upstream_retries.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream_connections(&self) -> u64 {
+        self.upstream_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn request_body_rejections(&self) -> u64 {
+        self.request_body_rejections.load(Ordering::Relaxed)
+    }
+
+    pub fn connection_closes(&self, reason: CloseReason) -> u64 {
+        self.connection_closes[reason.index()].load(Ordering::Relaxed)
+    }
+
+    /// Conexiones aceptadas por protocolo detectado.
+    pub fn protocols(&self, protocol: Protocol) -> u64 {
+        self.protocols[protocol.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn reputation_verdicts(&self, action: ReputationAction) -> u64 {
+        self.reputation_verdicts[action.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn reputation_unavailable(&self) -> u64 {
+        self.reputation_unavailable.load(Ordering::Relaxed)
+    }
+
+    /// Bytes ahorrados por el modo de ahorro de datos.
+    pub fn data_saver_bytes_saved(&self) -> u64 {
+        self.data_saver_bytes_saved.load(Ordering::Relaxed)
+    }
+
+    /// Evaluaciones de rollout de `feature` como `(activada, desactivada)`.
+    pub fn feature_exposures(&self, feature: Feature) -> (u64, u64) {
+        let [off, on] = &self.feature_exposures[feature.index()];
+        (on.load(Ordering::Relaxed), off.load(Ordering::Relaxed))
+    }
+
+    /// Imágenes candidatas que se entregaron sin recomprimir.
+    pub fn data_saver_passthrough(&self) -> u64 {
+        self.data_saver_passthrough.load(Ordering::Relaxed)
+    }
+
+    pub fn violations(&self, violation: Violation) -> u64 {
+        self.violations[violation.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn header_rejections(&self, limit: HeaderLimit) -> u64 {
+        self.header_rejections[limit.index()].load(Ordering::Relaxed)
+    }
+
+    /// Conexiones y peticiones rechazadas por venir de un cliente baneado.
+    pub fn ban_rejections(&self) -> u64 {
+        self.ban_rejections.load(Ordering::Relaxed)
+    }
+
+    /// Conexiones cerradas al aceptarlas por superar el cupo de su IP.
+    pub fn accept_rejections(&self) -> u64 {
+        self.accept_rejections.load(Ordering::Relaxed)
+    }
+
+    /// Veces que el listener dejó de aceptar por falta de descriptores.
+    pub fn accept_pauses(&self) -> u64 {
+        self.accept_pauses.load(Ordering::Relaxed)
+    }
+
+    pub fn accept_fd_exhausted(&self) -> u64 {
+        self.accept_fd_exhausted.load(Ordering::Relaxed)
+    }
+
+    /// Respuestas de error del destino reemplazadas por la página del proxy.
+    pub fn error_pages_replaced(&self) -> u64 {
+        self.error_pages_replaced.load(Ordering::Relaxed)
+    }
+
+    /// Respuestas con regla de página de error que fallaron cuando las
+    /// cabeceras ya habían salido y no se pudieron reemplazar.
+    pub fn error_pages_unreplaceable(&self) -> u64 {
+        self.error_pages_unreplaceable.load(Ordering::Relaxed)
+    }
+
+    /// Bytes de respuestas leídos del destino y aún retenidos por el proxy,
+    /// sumando todas las peticiones en curso.
+    pub fn buffered_bytes(&self) -> u64 {
+        self.buffered_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Mayor [`BufferGauge::high_water`] de una petición desde el arranque.
+    pub fn buffered_high_water(&self) -> u64 {
+        self.buffered_high_water.load(Ordering::Relaxed)
+    }
+
+    /// Peticiones terminadas con `method` (de [`REQUEST_METHODS`]) y una
+    /// respuesta de la clase `status` (de [`STATUS_CLASSES`]).
+    pub fn finished_requests(&self, method: &str, status: &str) -> u64 {
+        let method = REQUEST_METHODS.iter().position(|known| *known == method);
+        let status = STATUS_CLASSES.iter().position(|known| *known == status);
+        match (method, status) {
+            (Some(method), Some(status)) => self.finished[method][status].load(Ordering::Relaxed),
+            _ => 0,
+        }
+    }
+
+    /// Duración de las peticiones, en segundos. Un `CONNECT` termina al
+    /// abrir el túnel.
+    pub fn request_duration(&self) -> &Histogram {
+        &self.request_duration.0
+    }
+
+    pub fn tunnels_open(&self) -> u64 {
+        self.tunnels_open.load(Ordering::Relaxed)
+    }
+
+    /// Bytes de los túneles cerrados, `(cliente → destino, destino →
+    /// cliente)`.
+    pub fn tunnel_bytes(&self) -> (u64, u64) {
+        (
+            self.tunnel_bytes_sent.load(Ordering::Relaxed),
+            self.tunnel_bytes_received.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn overload_shed(&self, reason: ShedReason, class: ClientClass) -> u64 {
+        self.overload_shed[reason.index()][class.index()].load(Ordering::Relaxed)
+    }
+
+    /// `Retry-After` de los descartes por sobrecarga, en segundos.
+    pub fn overload_retry_after(&self) -> &Histogram {
+        &self.overload_retry_after.0
+    }
+
+    /// Peticiones rechazadas con 429 por el cupo por IP.
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Túneles cerrados desde la API de administración.
+    pub fn tunnels_terminated(&self) -> u64 {
+        self.tunnels_terminated.load(Ordering::Relaxed)
+    }
+
+    /// Peticiones y túneles rechazados por cada límite de simultaneidad.
+    pub fn concurrency_rejected(&self, limit: ConcurrencyLimit) -> u64 {
+        self.concurrency_rejected[limit.index()].load(Ordering::Relaxed)
+    }
+
+    /// Precargas encoladas y descartadas por tener la cola llena.
+    pub fn prefetches(&self) -> (u64, u64) {
+        (
+            self.prefetch_queued.load(Ordering::Relaxed),
+            self.prefetch_dropped.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Recursos precargados que luego se sirvieron desde la caché.
+    pub fn prefetch_hits(&self) -> u64 {
+        self.prefetch_hits.load(Ordering::Relaxed)
+    }
+
+    /// Copias caducadas servidas por `[response_cache]`, por motivo.
+    pub fn stale_served(&self, reason: StaleReason) -> u64 {
+        self.stale_served[reason.index()].load(Ordering::Relaxed)
+    }
+
+    /// Contador de bytes de `host`; se pide una vez por petición o túnel y
+    /// se suma en él sin más bloqueos.
+    pub fn host_bytes(&self, host: &str) -> Arc<HostBytes> {
+        if let Some(bytes) = self.hosts.read().expect("lock de hosts").get(host) {
+            return bytes.clone();
+        }
+        let mut hosts = self.hosts.write().expect("lock de hosts");
+        let key = if hosts.len() < MAX_TRAFFIC_HOSTS || hosts.contains_key(host) {
+            host
+        } else {
+            OTHER_HOSTS
+        };
+        hosts.entry(key.to_string()).or_default().clone()
+    }
+
+    /// Contadores TLS de `host`, con el mismo tope de hosts que los bytes.
+    pub fn host_tls(&self, host: &str) -> Arc<HostTls> {
+        if let Some(tls) = self.upstream_tls.read().expect("lock de TLS").get(host) {
+            return tls.clone();
+        }
+        let mut hosts = self.upstream_tls.write().expect("lock de TLS");
+        let key = if hosts.len() < MAX_TRAFFIC_HOSTS || hosts.contains_key(host) {
+            host
+        } else {
+            OTHER_HOSTS
+        };
+        hosts.entry(key.to_string()).or_default().clone()
+    }
+
+    /// El pool abre TLS con `host`; `resumed` si reanudó una sesión guardada.
+    pub fn record_tls_handshake(&self, host: &str, resumed: bool, elapsed: Duration) {
+        let tls = self.host_tls(host);
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let (count, sum) = match resumed {
+            true => (&tls.resumed, &tls.resumed_micros),
+            false => (&tls.full, &tls.full_micros),
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        sum.fetch_add(micros, Ordering::Relaxed);
+        self.tls_handshake.0.observe(elapsed.as_secs_f64());
+    }
+
+    /// Duración de los saludos TLS con los destinos, en segundos.
+    pub fn tls_handshake_duration(&self) -> &Histogram {
+        &self.tls_handshake.0
+    }
+
+    /// Saludos TLS `(completos, reanudados)` de todos los hosts.
+    pub fn tls_handshakes(&self) -> (u64, u64) {
+        let hosts = self.upstream_tls.read().expect("lock de TLS");
+        hosts.values().fold((0, 0), |(full, resumed), tls| {
+            (
+                full + tls.full.load(Ordering::Relaxed),
+                resumed + tls.resumed.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Peticiones `https://` que salieron por el pool, de todos los hosts.
+    pub fn tls_requests(&self) -> u64 {
+        let hosts = self.upstream_tls.read().expect("lock de TLS");
+        hosts
+            .values()
+            .map(|tls| tls.requests.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Contadores TLS por host, de más a menos peticiones.
+    pub fn host_tls_stats(&self) -> Vec<HostTlsStats> {
+        let average = |micros: &AtomicU64, count: u64| {
+            (count > 0).then(|| micros.load(Ordering::Relaxed) as f64 / count as f64 / 1000.0)
+        };
+        let mut stats: Vec<_> = self
+            .upstream_tls
+            .read()
+            .expect("lock de TLS")
+            .iter()
+            .map(|(host, tls)| {
+                let full = tls.full.load(Ordering::Relaxed);
+                let resumed = tls.resumed.load(Ordering::Relaxed);
+                let requests = tls.requests.load(Ordering::Relaxed);
+                HostTlsStats {
+                    host: host.clone(),
+                    full_handshakes: full,
+                    resumed_handshakes: resumed,
+                    full_handshake_ms_avg: average(&tls.full_micros, full),
+                    resumed_handshake_ms_avg: average(&tls.resumed_micros, resumed),
+                    requests,
+                    reuse_ratio: (requests > 0)
+                        .then(|| requests.saturating_sub(full + resumed) as f64 / requests as f64),
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| {
+            b.requests
+                .cmp(&a.requests)
+                .then_with(|| a.host.cmp(&b.host))
+        });
+        stats
+    }
+
+    /// Bytes por host de destino desde el arranque, de más a menos tráfico.
+    pub fn host_traffic(&self) -> Vec<HostTraffic> {
+        let mut traffic: Vec<_> = self
+            .hosts
+            .read()
+            .expect("lock de hosts")
+            .iter()
+            .map(|(host, bytes)| HostTraffic {
+                host: host.clone(),
+                bytes_sent: bytes.sent.load(Ordering::Relaxed),
+                bytes_received: bytes.received.load(Ordering::Relaxed),
+            })
+            .collect();
+        traffic.sort_by(|a, b| {
+            (b.bytes_sent + b.bytes_received)
+                .cmp(&(a.bytes_sent + a.bytes_received))
+                .then_with(|| a.host.cmp(&b.host))
+        });
+        traffic
+    }
+}
+
+/// Bytes del body de una respuesta que el proxy leyó del destino y todavía
+/// no entregó a hyper para escribirlos al cliente. Cada capa del body
+/// (conteo, ritmo, trailers) solo lee de la anterior cuando le piden un
+/// chunk, así que con un cliente lento se queda en uno o dos chunks; las
+/// capas que retienen el body entero (recompresión, trailers como
+/// cabeceras) lo hacen hasta su límite configurado.
+#[derive(Debug)]
+pub struct BufferGauge {
+    metrics: Arc<Metrics>,
+    held: AtomicU64,
+    high_water: AtomicU64,
+}
+
+impl BufferGauge {
+    pub fn new(metrics: Arc<Metrics>) -> Arc<Self> {
+        Arc::new(Self {
+            metrics,
+            held: AtomicU64::new(0),
+            high_water: AtomicU64::new(0),
+        })
+    }
+
+    /// Se leyeron `bytes` del destino.
+    pub fn pulled(&self, bytes: u64) {
+        let held = self.held.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let total = self
+            .metrics
+            .buffered_bytes
+            .fetch_add(bytes, Ordering::Relaxed)
+            + bytes;
+        self.metrics.sink().buffered_bytes(total);
+        self.high_water.fetch_max(held, Ordering::Relaxed);
+        self.metrics
+            .buffered_high_water
+            .fetch_max(held, Ordering::Relaxed);
+    }
+
+    /// Hyper tomó `bytes` para el cliente. Una capa que reescribe el body
+    /// puede entregar menos de lo que leyó, nunca más de lo retenido.
+    pub fn delivered(&self, bytes: u64) {
+        let before = self
+            .held
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |held| {
+                Some(held.saturating_sub(bytes))
+            })
+            .expect("la actualización siempre devuelve un valor");
+        let released = before.min(bytes);
+        let total = self
+            .metrics
+            .buffered_bytes
+            .fetch_sub(released, Ordering::Relaxed)
+            .saturating_sub(released);
+        self.metrics.sink().buffered_bytes(total);
+    }
+
+    pub fn held(&self) -> u64 {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    /// Lo máximo retenido a la vez para esta respuesta.
+    pub fn high_water(&self) -> u64 {
+        self.high_water.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for BufferGauge {
+    fn drop(&mut self) {
+        // A client that hangs up leaves its bytes unread.
+        let held = *self.held.get_mut();
+        let total = self
+            .metrics
+            .buffered_bytes
+            .fetch_sub(held, Ordering::Relaxed)
+            .saturating_sub(held);
+        self.metrics.sink().buffered_bytes(total);
+    }
+}
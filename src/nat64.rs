@@ -0,0 +1,525 @@
+//! Salida en redes solo IPv6 con NAT64.
+//!
+//! [`Nat64Resolver`] envuelve la resolución del proxy. Cada `probe_interval`
+//! comprueba, con un socket UDP que no envía nada, si existe ruta IPv4; sin
+//! ella, la estrategia pasa a `ipv6_only`: se usan solo las direcciones AAAA
+//! y, si el nombre solo tiene A (el DNS no hace DNS64), se sintetizan con el
+//! prefijo NAT64 (RFC 6052). El prefijo es el configurado o el que se
+//! descubre resolviendo `ipv4only.arpa` (RFC 7050).
+//!
+//! Una dirección dentro del prefijo que incrusta una IPv4 no global
+//! (privada, loopback, enlace local...) se rechaza, la haya sintetizado el
+//! proxy o venga del DNS o del cliente: el traductor la llevaría a la red
+//! interna. La estrategia y el prefijo se ven en `/api/stats`.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::dialer::Resolve;
+use crate::settings::Nat64Settings;
+
+/// Nombre con el que se descubre el prefijo (RFC 7050).
+const DISCOVERY_NAME: &str = "ipv4only.arpa";
+/// Las IPv4 de `ipv4only.arpa`.
+const WELL_KNOWN_IPV4: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+/// Longitudes de prefijo de RFC 6052, de la más a la menos usada.
+const PREFIX_LENGTHS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+/// Destino de la sonda de ruta IPv4 (TEST-NET-1); `connect` en UDP solo
+/// consulta la tabla de rutas.
+const PROBE_TARGET: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 53);
+
+/// Prefijo NAT64 de RFC 6052, como `64:ff9b::/96`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nat64Prefix {
+    addr: Ipv6Addr,
+    len: u8,
+}
+
+impl Nat64Prefix {
+    /// El prefijo bien conocido `64:ff9b::/96`.
+    pub const WELL_KNOWN: Nat64Prefix = Nat64Prefix {
+        addr: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+        len: 96,
+    };
+
+    fn masked(addr: Ipv6Addr, len: u8) -> Self {
+        let bits = u128::from(addr) & (u128::MAX << (128 - u32::from(len)));
+        Self {
+            addr: Ipv6Addr::from(bits),
+            len,
+        }
+    }
+
+    /// Octetos de la dirección que ocupan los de la IPv4; el octeto 8 (bits
+    /// 64-71) queda siempre a cero.
+    fn positions(&self) -> impl Iterator<Item = usize> {
+        (usize::from(self.len / 8)..16).filter(|i| *i != 8).take(4)
+    }
+
+    /// La dirección IPv6 que representa a `ip` tras el traductor.
+    pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.addr.octets();
+        for (position, octet) in self.positions().zip(ip.octets()) {
+            octets[position] = octet;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    /// La IPv4 incrustada en `ip`, si pertenece al prefijo.
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        if Self::masked(ip, self.len) != *self {
+            return None;
+        }
+        let octets = ip.octets();
+        let mut embedded = [0u8; 4];
+        for (slot, position) in embedded.iter_mut().zip(self.positions()) {
+            *slot = octets[position];
+        }
+        Some(Ipv4Addr::from(embedded))
+    }
+}
+
+impl std::fmt::Display for Nat64Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+impl std::str::FromStr for Nat64Prefix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("prefijo NAT64 sin longitud: {s}"))?;
+        let addr: Ipv6Addr = addr
+            .parse()
+            .map_err(|_| anyhow::anyhow!("prefijo NAT64 inválido: {s}"))?;
+        let len: u8 = len
+            .parse()
+            .ok()
+            .filter(|len| PREFIX_LENGTHS.contains(len))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "longitud de prefijo NAT64 inválida en {s}: debe ser 32, 40, 48, 56, 64 o 96"
+                )
+            })?;
+        let prefix = Self::masked(addr, len);
+        if prefix.addr != addr {
+            anyhow::bail!("el prefijo NAT64 {s} tiene bits fuera de la longitud");
+        }
+        Ok(prefix)
+    }
+}
+
+/// Cómo se elige la familia de las direcciones de destino.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Hay ruta IPv4: las direcciones se usan como vienen del DNS.
+    DualStack,
+    /// Sin ruta IPv4: solo AAAA, sintetizadas si hace falta.
+    Ipv6Only,
+}
+
+/// De dónde salió el prefijo en uso.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrefixSource {
+    Configured,
+    Discovered,
+}
+
+/// Estado de la salida tal como lo muestra `/api/stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Nat64Snapshot {
+    /// `None` hasta la primera resolución.
+    pub strategy: Option<Strategy>,
+    /// Dirección local con la que saldría el tráfico IPv4, si hay ruta.
+    pub ipv4_source: Option<IpAddr>,
+    pub prefix: Option<String>,
+    pub prefix_source: Option<PrefixSource>,
+    /// Resoluciones con AAAA sintetizadas por el proxy.
+    pub synthesized: u64,
+    /// Destinos rechazados por incrustar una IPv4 no global.
+    pub blocked: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Egress {
+    strategy: Strategy,
+    ipv4_source: Option<IpAddr>,
+    prefix: Option<(Nat64Prefix, PrefixSource)>,
+}
+
+type RouteProbe = dyn Fn() -> Option<IpAddr> + Send + Sync;
+
+pub struct Nat64Resolver {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    settings: Nat64Settings,
+    resolver: Arc<dyn Resolve>,
+    probe: Box<RouteProbe>,
+    egress: Mutex<Option<(Instant, Egress)>>,
+    synthesized: AtomicU64,
+    blocked: AtomicU64,
+}
+
+impl Nat64Resolver {
+    /// Sondea la ruta IPv4 del sistema.
+    pub fn new(settings: Nat64Settings, resolver: Arc<dyn Resolve>) -> Self {
+        Self::with_probe(settings, resolver, probe_ipv4_route)
+    }
+
+    /// Como [`Nat64Resolver::new`], con otra sonda de ruta para las pruebas.
+    pub fn with_probe(
+        settings: Nat64Settings,
+        resolver: Arc<dyn Resolve>,
+        probe: impl Fn() -> Option<IpAddr> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                settings,
+                resolver,
+                probe: Box::new(probe),
+                egress: Mutex::new(None),
+                synthesized: AtomicU64::new(0),
+                blocked: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// La IPv4 que `ip` representa tras el traductor, si está en el prefijo
+    /// en uso.
+    pub fn embedded(&self, ip: IpAddr) -> Option<Ipv4Addr> {
+        let IpAddr::V6(ip) = ip else {
+            return None;
+        };
+        let egress = *self.inner.egress.lock().expect("lock de NAT64");
+        let (prefix, _) = egress.and_then(|(_, egress)| egress.prefix)?;
+        prefix.extract(ip)
+    }
+
+    pub fn snapshot(&self) -> Nat64Snapshot {
+        let egress = self
+            .inner
+            .egress
+            .lock()
+            .expect("lock de NAT64")
+            .map(|(_, egress)| egress);
+        Nat64Snapshot {
+            strategy: egress.map(|egress| egress.strategy),
+            ipv4_source: egress.and_then(|egress| egress.ipv4_source),
+            prefix: egress
+                .and_then(|egress| egress.prefix)
+                .map(|(prefix, _)| prefix.to_string()),
+            prefix_source: egress
+                .and_then(|egress| egress.prefix)
+                .map(|(_, source)| source),
+            synthesized: self.inner.synthesized.load(Ordering::Relaxed),
+            blocked: self.inner.blocked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Inner {
+    /// Estado de la salida, sondeado de nuevo cada `probe_interval`.
+    async fn egress(&self) -> Egress {
+        let cached = *self.egress.lock().expect("lock de NAT64");
+        if let Some((at, egress)) = cached {
+            if at.elapsed() < self.settings.probe_interval() {
+                return egress;
+            }
+        }
+        let ipv4_source = (self.probe)();
+        let prefix = match self.settings.prefix() {
+            Some(prefix) => Some((prefix, PrefixSource::Configured)),
+            None => self
+                .discover()
+                .await
+                .map(|prefix| (prefix, PrefixSource::Discovered)),
+        };
+        let egress = Egress {
+            strategy: if ipv4_source.is_some() {
+                Strategy::DualStack
+            } else {
+                Strategy::Ipv6Only
+            },
+            ipv4_source,
+            prefix,
+        };
+        let previous = self
+            .egress
+            .lock()
+            .expect("lock de NAT64")
+            .replace((Instant::now(), egress))
+            .map(|(_, previous)| previous);
+        if previous != Some(egress) {
+            let prefix = prefix.map(|(prefix, _)| prefix.to_string());
+            info!(strategy = ?egress.strategy, ?ipv4_source, ?prefix, "Estrategia de salida");
+            if egress.strategy == Strategy::Ipv6Only && prefix.is_none() {
+                warn!(
+                    "Sin ruta IPv4 ni prefijo NAT64: los destinos solo IPv4 no serán alcanzables"
+                );
+            }
+        }
+        egress
+    }
+
+    /// Prefijo que el DNS64 de la red usa para `ipv4only.arpa`.
+    async fn discover(&self) -> Option<Nat64Prefix> {
+        let addrs = self.resolver.resolve(DISCOVERY_NAME, 0).await.ok()?;
+        addrs.iter().find_map(|addr| {
+            let IpAddr::V6(ip) = addr.ip() else {
+                return None;
+            };
+            PREFIX_LENGTHS.iter().find_map(|len| {
+                let prefix = Nat64Prefix::masked(ip, *len);
+                WELL_KNOWN_IPV4
+                    .iter()
+                    .any(|known| prefix.embed(*known) == ip)
+                    .then_some(prefix)
+            })
+        })
+    }
+
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = self.resolver.resolve(host, port).await?;
+        let egress = self.egress().await;
+        let prefix = egress.prefix.map(|(prefix, _)| prefix);
+        if egress.strategy == Strategy::Ipv6Only {
+            if let Some(prefix) = prefix.filter(|_| addrs.iter().all(SocketAddr::is_ipv4)) {
+                for addr in &mut addrs {
+                    if let IpAddr::V4(ip) = addr.ip() {
+                        addr.set_ip(IpAddr::V6(prefix.embed(ip)));
+                    }
+                }
+                self.synthesized.fetch_add(1, Ordering::Relaxed);
+            } else if addrs.iter().any(SocketAddr::is_ipv6) {
+                addrs.retain(SocketAddr::is_ipv6);
+            }
+        }
+        let Some(prefix) = prefix else {
+            return Ok(addrs);
+        };
+        let translated = addrs.iter().find_map(|addr| match addr.ip() {
+            IpAddr::V6(ip) => prefix.extract(ip).filter(|embedded| !is_global(*embedded)),
+            IpAddr::V4(_) => None,
+        });
+        if let Some(embedded) = translated {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+            warn!(%host, %embedded, "Destino NAT64 con una IPv4 no global; rechazado");
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{host} llega por NAT64 a la IPv4 no global {embedded}"),
+            ));
+        }
+        Ok(addrs)
+    }
+}
+
+impl Resolve for Nat64Resolver {
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        let inner = self.inner.clone();
+        let host = host.to_string();
+        Box::pin(async move { inner.resolve(&host, port).await })
+    }
+}
+
+/// Dirección local con la que saldría un datagrama IPv4; `None` si no hay
+/// ruta. No envía nada.
+fn probe_ipv4_route() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(PROBE_TARGET).ok()?;
+    Some(socket.local_addr().ok()?.ip()).filter(|ip| !ip.is_unspecified())
+}
+
+/// Si `ip` es alcanzable en Internet: fuera de los rangos privados, de
+/// loopback, de enlace local, compartidos (100.64/10), reservados por la
+/// IANA (192.0.0/24), de documentación y de broadcast.
+fn is_global(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || a == 0
+        || a >= 240
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct MapResolver(HashMap<&'static str, Vec<IpAddr>>);
+
+    impl Resolve for MapResolver {
+        fn resolve(
+            &self,
+            host: &str,
+            port: u16,
+        ) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+            let resolved = match host.parse::<IpAddr>() {
+                Ok(ip) => Ok(vec![SocketAddr::new(ip, port)]),
+                Err(_) => self.0.get(host).cloned().map_or_else(
+                    || Err(io::Error::new(io::ErrorKind::NotFound, host.to_string())),
+                    |ips| {
+                        Ok(ips
+                            .into_iter()
+                            .map(|ip| SocketAddr::new(ip, port))
+                            .collect())
+                    },
+                ),
+            };
+            Box::pin(async move { resolved })
+        }
+    }
+
+    fn resolver(
+        settings: Nat64Settings,
+        names: &[(&'static str, &[&str])],
+        ipv4_route: bool,
+    ) -> Nat64Resolver {
+        let names = names
+            .iter()
+            .map(|(name, ips)| (*name, ips.iter().map(|ip| ip.parse().unwrap()).collect()))
+            .collect();
+        Nat64Resolver::with_probe(settings, Arc::new(MapResolver(names)), move || {
+            ipv4_route.then(|| "192.0.2.10".parse().unwrap())
+        })
+    }
+
+    async fn ips(resolver: &Nat64Resolver, host: &str) -> io::Result<Vec<IpAddr>> {
+        let addrs = resolver.resolve(host, 443).await?;
+        Ok(addrs.iter().map(SocketAddr::ip).collect())
+    }
+
+    #[test]
+    fn test_prefix_embeds_at_every_rfc6052_length() {
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, expected) in [
+            ("64:ff9b::/96", "64:ff9b::c000:221"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+        ] {
+            let prefix: Nat64Prefix = prefix.parse().unwrap();
+            let embedded = prefix.embed(ip);
+            assert_eq!(embedded, expected.parse::<Ipv6Addr>().unwrap(), "{prefix}");
+            assert_eq!(prefix.extract(embedded), Some(ip));
+        }
+        assert_eq!(
+            Nat64Prefix::WELL_KNOWN.extract("2001:db8::1".parse().unwrap()),
+            None
+        );
+        assert!("64:ff9b::/80".parse::<Nat64Prefix>().is_err());
+        assert!("64:ff9b::1/96".parse::<Nat64Prefix>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_only_prefers_aaaa_and_synthesizes_with_the_prefix() {
+        let names: &[(&str, &[&str])] = &[
+            ("v4.test", &["93.184.216.34"]),
+            ("dual.test", &["93.184.216.34", "2001:db8::1"]),
+        ];
+        let settings = Nat64Settings::default().with_prefix(Nat64Prefix::WELL_KNOWN);
+        let ipv6_only = resolver(settings.clone(), names, false);
+        assert_eq!(
+            ips(&ipv6_only, "v4.test").await.unwrap(),
+            ["64:ff9b::5db8:d822".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(
+            ips(&ipv6_only, "dual.test").await.unwrap(),
+            ["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+        let snapshot = ipv6_only.snapshot();
+        assert_eq!(snapshot.strategy, Some(Strategy::Ipv6Only));
+        assert_eq!(snapshot.prefix.as_deref(), Some("64:ff9b::/96"));
+        assert_eq!(snapshot.prefix_source, Some(PrefixSource::Configured));
+        assert_eq!(snapshot.synthesized, 1);
+
+        let dual = resolver(settings, names, true);
+        assert_eq!(ips(&dual, "dual.test").await.unwrap().len(), 2);
+        assert_eq!(dual.snapshot().strategy, Some(Strategy::DualStack));
+        assert_eq!(
+            dual.snapshot().ipv4_source,
+            Some("192.0.2.10".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejects_nat64_addresses_embedding_private_ipv4() {
+        let names: &[(&str, &[&str])] = &[
+            ("intranet.test", &["10.0.0.5"]),
+            ("dns64.test", &["64:ff9b::7f00:1"]),
+        ];
+        let settings = Nat64Settings::default().with_prefix(Nat64Prefix::WELL_KNOWN);
+        let ipv6_only = resolver(settings.clone(), names, false);
+        for host in ["intranet.test", "dns64.test", "64:ff9b::a9fe:a9fe"] {
+            let error = ips(&ipv6_only, host).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied, "{host}");
+        }
+        assert!(ips(&ipv6_only, "64:ff9b::808:808").await.is_ok());
+        assert_eq!(ipv6_only.snapshot().blocked, 3);
+        assert_eq!(
+            ipv6_only.embedded("64:ff9b::808:808".parse().unwrap()),
+            Some(Ipv4Addr::new(8, 8, 8, 8))
+        );
+
+        // The check does not depend on the strategy.
+        let dual = resolver(settings, names, true);
+        assert!(ips(&dual, "dns64.test").await.is_err());
+        assert_eq!(
+            ips(&dual, "intranet.test").await.unwrap(),
+            ["10.0.0.5".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discovers_the_prefix_from_ipv4only_arpa() {
+        let names: &[(&str, &[&str])] = &[
+            (
+                DISCOVERY_NAME,
+                &["2001:db8:64:ff9b::c000:aa", "2001:db8:64:ff9b::c000:ab"],
+            ),
+            ("v4.test", &["93.184.216.34"]),
+        ];
+        let ipv6_only = resolver(Nat64Settings::default(), names, false);
+        assert_eq!(
+            ips(&ipv6_only, "v4.test").await.unwrap(),
+            ["2001:db8:64:ff9b::5db8:d822".parse::<IpAddr>().unwrap()]
+        );
+        let snapshot = ipv6_only.snapshot();
+        assert_eq!(snapshot.prefix.as_deref(), Some("2001:db8:64:ff9b::/96"));
+        assert_eq!(snapshot.prefix_source, Some(PrefixSource::Discovered));
+
+        // Without DNS64 there is nothing to discover.
+        let plain = resolver(
+            Nat64Settings::default(),
+            &[("v4.test", &["93.184.216.34"])],
+            false,
+        );
+        assert_eq!(
+            ips(&plain, "v4.test").await.unwrap(),
+            ["93.184.216.34".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(plain.snapshot().prefix, None);
+    }
+}
@@ -0,0 +1,444 @@
+//! Conexiones con los destinos de las peticiones HTTP reenviadas.
+//!
+//! Las peticiones `http://` van por TCP en claro; las `https://` abren TLS
+//! verificando el certificado del destino con las raíces públicas y ofrecen
+//! `h2` y `http/1.1` por ALPN, así que un destino que soporta HTTP/2 lo
+//! habla y el resto sigue con HTTP/1.1. El cliente puede hablar con el
+//! proxy en cualquiera de las dos: cada lado negocia su versión. Con
+//! `http1_only` solo se ofrece `http/1.1`, para depurar.
+//!
+//! Las sesiones TLS de los últimos `session_cache_size` hosts se guardan en
+//! una [`SessionCache`] para reanudarlas en la siguiente conexión, salvo con
+//! los hosts de `disable_resumption`. Cada saludo se cuenta por host en
+//! [`Metrics`], completo o reanudado y con su duración.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use lru::LruCache;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::{
+    ClientSessionStore, Resumption, Tls12ClientSessionValue, Tls13ClientSessionValue,
+};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, HandshakeKind, NamedGroup, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::dialer::DialConnector;
+use crate::host_pattern::HostPattern;
+use crate::metrics::Metrics;
+
+/// Tickets de TLS 1.3 que se guardan de cada host, los más recientes.
+const TICKETS_PER_HOST: usize = 8;
+
+/// Configuración TLS hacia los destinos: raíces públicas y ALPN con `h2`
+/// salvo con `http1_only`.
+pub fn client_config(http1_only: bool) -> Arc<ClientConfig> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    with_roots(roots, http1_only)
+}
+
+/// Como [`client_config`], confiando solo en `roots`.
+pub fn with_roots(roots: RootCertStore, http1_only: bool) -> Arc<ClientConfig> {
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("versiones de TLS por defecto")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = if http1_only {
+        vec![b"http/1.1".to_vec()]
+    } else {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    };
+    Arc::new(config)
+}
+
+/// Sesiones guardadas de un host.
+#[derive(Default)]
+struct Sessions {
+    kx_hint: Option<NamedGroup>,
+    tls12: Option<Tls12ClientSessionValue>,
+    tls13: VecDeque<Tls13ClientSessionValue>,
+}
+
+/// Ocupación de la caché de sesiones, para `GET /api/upstream-tls`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CacheOccupancy {
+    pub capacity: usize,
+    pub hosts: usize,
+    pub tls12_sessions: usize,
+    pub tls13_tickets: usize,
+}
+
+/// Sesiones TLS de los destinos para reanudarlas, de los `capacity` hosts
+/// usados más recientemente. Hace lo mismo que la caché en memoria de rustls,
+/// pero deja ver cuánto guarda.
+pub struct SessionCache {
+    capacity: usize,
+    hosts: Mutex<LruCache<ServerName<'static>, Sessions>>,
+}
+
+impl std::fmt::Debug for SessionCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCache")
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+impl SessionCache {
+    /// Con `capacity` 0 no se reanuda nada.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            hosts: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN),
+            )),
+        }
+    }
+
+    pub fn occupancy(&self) -> CacheOccupancy {
+        let hosts = self.hosts.lock().expect("caché de sesiones TLS");
+        CacheOccupancy {
+            capacity: self.capacity,
+            hosts: hosts.len(),
+            tls12_sessions: hosts
+                .iter()
+                .filter(|(_, host)| host.tls12.is_some())
+                .count(),
+            tls13_tickets: hosts.iter().map(|(_, host)| host.tls13.len()).sum(),
+        }
+    }
+
+    fn edit(&self, name: ServerName<'static>, edit: impl FnOnce(&mut Sessions)) {
+        let mut hosts = self.hosts.lock().expect("caché de sesiones TLS");
+        edit(hosts.get_or_insert_mut(name, Sessions::default));
+    }
+}
+
+impl ClientSessionStore for SessionCache {
+    fn set_kx_hint(&self, name: ServerName<'static>, group: NamedGroup) {
+        self.edit(name, |host| host.kx_hint = Some(group));
+    }
+
+    fn kx_hint(&self, name: &ServerName<'_>) -> Option<NamedGroup> {
+        let mut hosts = self.hosts.lock().expect("caché de sesiones TLS");
+        hosts.get(&name.to_owned()).and_then(|host| host.kx_hint)
+    }
+
+    fn set_tls12_session(&self, name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.edit(name, |host| host.tls12 = Some(value));
+    }
+
+    fn tls12_session(&self, name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        let mut hosts = self.hosts.lock().expect("caché de sesiones TLS");
+        hosts
+            .get(&name.to_owned())
+            .and_then(|host| host.tls12.clone())
+    }
+
+    fn remove_tls12_session(&self, name: &ServerName<'static>) {
+        let mut hosts = self.hosts.lock().expect("caché de sesiones TLS");
+        if let Some(host) = hosts.peek_mut(name) {
+            host.tls12 = None;
+        }
+    }
+
+    fn insert_tls13_ticket(&self, name: ServerName<'static>, value: Tls13ClientSessionValue) {
+        self.edit(name, |host| {
+            if host.tls13.len() == TICKETS_PER_HOST {
+                host.tls13.pop_front();
+            }
+            host.tls13.push_back(value);
+        });
+    }
+
+    fn take_tls13_ticket(&self, name: &ServerName<'static>) -> Option<Tls13ClientSessionValue> {
+        let mut hosts = self.hosts.lock().expect("caché de sesiones TLS");
+        // Newest first; each ticket is used once.
+        hosts.get_mut(name).and_then(|host| host.tls13.pop_back())
+    }
+}
+
+/// Conector del pool de los destinos: TCP para `http://` y TLS para
+/// `https://`.
+#[derive(Clone)]
+pub struct OriginConnector {
+    inner: DialConnector,
+    tls: TlsConnector,
+    /// Sin reanudación, para los hosts de `no_resumption`.
+    fresh: TlsConnector,
+    no_resumption: Arc<[HostPattern]>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl OriginConnector {
+    pub fn new(inner: DialConnector, tls: Arc<ClientConfig>) -> Self {
+        Self {
+            inner,
+            tls: TlsConnector::from(tls.clone()),
+            fresh: TlsConnector::from(tls),
+            no_resumption: Arc::from([]),
+            metrics: None,
+        }
+    }
+
+    /// Reanuda las sesiones guardadas en `cache`, salvo con los hosts de
+    /// `no_resumption`.
+    pub fn with_sessions(
+        mut self,
+        cache: Arc<SessionCache>,
+        no_resumption: &[HostPattern],
+    ) -> Self {
+        let base = self.tls.config().as_ref().clone();
+        let mut fresh = base.clone();
+        fresh.resumption = Resumption::disabled();
+        let mut resuming = base;
+        resuming.resumption = match cache.capacity {
+            0 => Resumption::disabled(),
+            _ => Resumption::store(cache),
+        };
+        self.tls = TlsConnector::from(Arc::new(resuming));
+        self.fresh = TlsConnector::from(Arc::new(fresh));
+        self.no_resumption = no_resumption.into();
+        self
+    }
+
+    /// Cuenta en `metrics` los saludos TLS por host.
+    pub fn counting(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+impl Service<Uri> for OriginConnector {
+    type Response = OriginStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<OriginStream>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let https = uri.scheme_str() == Some("https");
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let connecting = self.inner.call(uri);
+        let tls = match self.no_resumption.iter().any(|rule| rule.matches(&host)) {
+            true => self.fresh.clone(),
+            false => self.tls.clone(),
+        };
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            if !https {
+                return Ok(OriginStream::Plain(connecting.await?));
+            }
+            let name = ServerName::try_from(host.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let stream = connecting.await?;
+            let started = Instant::now();
+            let stream = tls.connect(name, stream).await?;
+            if let Some(metrics) = metrics {
+                let resumed = stream.get_ref().1.handshake_kind() == Some(HandshakeKind::Resumed);
+                metrics.record_tls_handshake(&host, resumed, started.elapsed());
+            }
+            Ok(OriginStream::Tls(Box::new(stream)))
+        })
+    }
+}
+
+/// Conexión con un destino, en claro o por TLS.
+pub enum OriginStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl OriginStream {
+    /// Si el destino eligió HTTP/2 por ALPN.
+    pub fn negotiated_h2(&self) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            Self::Tls(stream) => stream.get_ref().1.alpn_protocol() == Some(b"h2"),
+        }
+    }
+}
+
+impl Connection for OriginStream {
+    fn connected(&self) -> Connected {
+        if self.negotiated_h2() {
+            Connected::new().negotiated_h2()
+        } else {
+            Connected::new()
+        }
+    }
+}
+
+impl AsyncRead for OriginStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for OriginStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::{Body, Client, Request, Response};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::dialer::{Dialer, SystemResolver};
+    use crate::settings::DialSettings;
+
+    /// Origin that keeps a session cache, as rustls does by default.
+    async fn spawn_origin() -> (u16, RootCertStore) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            certified.signing_key.serialize_der(),
+        ));
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(tls) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let service = service_fn(|_: Request<Body>| async {
+                        Ok::<_, Infallible>(Response::new(Body::from("hola")))
+                    });
+                    let _ = Http::new().serve_connection(tls, service).await;
+                });
+            }
+        });
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        (port, roots)
+    }
+
+    /// Two requests over separate connections, as the pool would open them
+    /// once the first one is closed.
+    async fn two_connections(no_resumption: &[HostPattern]) -> (Arc<Metrics>, Arc<SessionCache>) {
+        let (port, roots) = spawn_origin().await;
+        let dialer = Dialer::new(DialSettings::default(), Arc::new(SystemResolver));
+        let cache = Arc::new(SessionCache::new(4));
+        let metrics = Arc::new(Metrics::default());
+        let connector = OriginConnector::new(
+            DialConnector::new(Arc::new(dialer)),
+            with_roots(roots, true),
+        )
+        .with_sessions(cache.clone(), no_resumption)
+        .counting(metrics.clone());
+        let client = Client::builder()
+            .pool_max_idle_per_host(0)
+            .build::<_, Body>(connector);
+        for _ in 0..2 {
+            let res = client
+                .get(format!("https://localhost:{port}/").parse().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                hyper::body::to_bytes(res.into_body()).await.unwrap(),
+                "hola"
+            );
+        }
+        (metrics, cache)
+    }
+
+    #[tokio::test]
+    async fn test_second_connection_resumes_the_session() {
+        let (metrics, cache) = two_connections(&[]).await;
+        assert_eq!(metrics.tls_handshakes(), (1, 1));
+        let stats = metrics.host_tls_stats();
+        assert_eq!(stats[0].host, "localhost");
+        assert_eq!(stats[0].full_handshakes, 1);
+        assert_eq!(stats[0].resumed_handshakes, 1);
+        assert_eq!(
+            metrics
+                .tls_handshake_duration()
+                .snapshot()
+                .last()
+                .unwrap()
+                .count,
+            2
+        );
+        let occupancy = cache.occupancy();
+        assert_eq!(occupancy.capacity, 4);
+        assert_eq!(occupancy.hosts, 1);
+        assert!(occupancy.tls13_tickets > 0);
+    }
+
+    #[tokio::test]
+    async fn test_hosts_without_resumption_always_do_a_full_handshake() {
+        let (metrics, cache) = two_connections(&["localhost".parse().unwrap()]).await;
+        assert_eq!(metrics.tls_handshakes(), (2, 0));
+        assert_eq!(cache.occupancy().hosts, 0);
+    }
+}
@@ -0,0 +1,398 @@
+//! Descarte de peticiones bajo sobrecarga.
+//!
+//! Con `[overload]` el proxy atiende como mucho `max_in_flight` peticiones a
+//! la vez. Las que llegan por encima esperan en una cola de hasta
+//! `max_queue` el tiempo de su [`ClientClass`]: los clientes de confianza
+//! (un perfil de `trusted_profiles`) esperan más y pasan primero cuando se
+//! libera un hueco, los identificados o autenticados esperan menos y los
+//! anónimos, por defecto, no esperan: son los primeros en quedarse fuera.
+//! Con `max_per_client`, un cliente (usuario o IP) que ya tiene ese número
+//! de peticiones en curso se rechaza sin pasar por la cola.
+//!
+//! Una petición descartada recibe un 503 con `x-proxy-error: overloaded`,
+//! un JSON con el motivo ([`ShedReason`]) y un `Retry-After` calculado con
+//! la cola actual y el ritmo al que se han liberado huecos en los últimos
+//! segundos, con un margen aleatorio para que los clientes no reintenten
+//! todos a la vez y acotado por `max_retry_after`.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::oneshot;
+
+use crate::identity::Identity;
+use crate::settings::{ClientClass, OverloadSettings};
+
+/// Ventana con la que se mide el ritmo de salida de la cola.
+const DRAIN_WINDOW: Duration = Duration::from_secs(10);
+
+/// Salidas recientes que se recuerdan como mucho.
+const MAX_RELEASES: usize = 10_000;
+
+/// Por qué se descartó una petición.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedReason {
+    /// El cliente ya tenía `max_per_client` peticiones en curso.
+    ClientLimit,
+    /// La clase del cliente no espera o la cola estaba llena.
+    QueueFull,
+    /// Esperó en la cola todo lo que le tocaba.
+    QueueTimeout,
+}
+
+impl ShedReason {
+    pub const ALL: [ShedReason; 3] = [
+        ShedReason::ClientLimit,
+        ShedReason::QueueFull,
+        ShedReason::QueueTimeout,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShedReason::ClientLimit => "client_limit",
+            ShedReason::QueueFull => "queue_full",
+            ShedReason::QueueTimeout => "queue_timeout",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Petición descartada.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shed {
+    pub reason: ShedReason,
+    pub class: ClientClass,
+    pub retry_after: Duration,
+}
+
+impl Shed {
+    /// 503 con el motivo y el `Retry-After`.
+    pub fn into_response(self) -> Response<Body> {
+        let secs = self.retry_after.as_secs();
+        let body = json!({
+            "error": "overloaded",
+            "reason": self.reason.as_str(),
+            "class": self.class.as_str(),
+            "retry_after_secs": secs,
+        });
+        let mut response = Response::new(Body::from(body.to_string()));
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert("x-proxy-error", HeaderValue::from_static("overloaded"));
+        headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+        response
+    }
+}
+
+/// Estado de la cola, tal como lo muestra `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OverloadSnapshot {
+    pub in_flight: usize,
+    pub max_in_flight: usize,
+    pub queued: usize,
+    /// Huecos liberados por segundo en la ventana reciente.
+    pub drain_per_sec: f64,
+}
+
+struct Waiter {
+    id: u64,
+    admitted: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    per_client: HashMap<String, usize>,
+    /// One queue per class, in [`ClientClass::ALL`] order.
+    waiting: [VecDeque<Waiter>; 3],
+    next_id: u64,
+    released: VecDeque<Instant>,
+}
+
+impl State {
+    fn queued(&self) -> usize {
+        self.waiting.iter().map(VecDeque::len).sum()
+    }
+
+    fn drain_per_sec(&mut self, now: Instant) -> f64 {
+        while self
+            .released
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > DRAIN_WINDOW)
+        {
+            self.released.pop_front();
+        }
+        self.released.len() as f64 / DRAIN_WINDOW.as_secs_f64()
+    }
+}
+
+/// Límite de peticiones en curso con su cola.
+pub struct Overload {
+    settings: OverloadSettings,
+    state: Mutex<State>,
+}
+
+/// Hueco ocupado por una petición; se libera al soltarlo.
+pub struct Permit {
+    overload: Arc<Overload>,
+    client: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.overload.release(&self.client);
+    }
+}
+
+impl Overload {
+    pub fn new(settings: OverloadSettings) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn settings(&self) -> &OverloadSettings {
+        &self.settings
+    }
+
+    /// Clase del cliente de `req`; `authenticated` si pasó la autenticación
+    /// del proxy o un ticket.
+    pub fn classify(&self, req: &Request<Body>, authenticated: bool) -> ClientClass {
+        match req.extensions().get::<Identity>() {
+            Some(identity)
+                if self
+                    .settings
+                    .trusted_profiles()
+                    .iter()
+                    .any(|profile| profile == identity.profile()) =>
+            {
+                ClientClass::Trusted
+            }
+            Some(_) => ClientClass::Standard,
+            None if authenticated => ClientClass::Standard,
+            None => ClientClass::Anonymous,
+        }
+    }
+
+    /// Espera un hueco para la petición de `client` o la descarta.
+    pub async fn admit(self: &Arc<Self>, class: ClientClass, client: &str) -> Result<Permit, Shed> {
+        let (id, admitted) = {
+            let mut state = self.state.lock().unwrap();
+            let own = state.per_client.get(client).copied().unwrap_or(0);
+            if self.settings.max_per_client().is_some_and(|max| own >= max) {
+                return Err(self.shed(&mut state, ShedReason::ClientLimit, class));
+            }
+            if state.in_flight < self.settings.max_in_flight() {
+                state.in_flight += 1;
+                return Ok(self.permit(&mut state, client));
+            }
+            let timeout = self.settings.queue_timeout(class);
+            if timeout.is_zero() || state.queued() >= self.settings.max_queue() {
+                return Err(self.shed(&mut state, ShedReason::QueueFull, class));
+            }
+            let (tx, rx) = oneshot::channel();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.waiting[class.index()].push_back(Waiter { id, admitted: tx });
+            (id, rx)
+        };
+        let timeout = self.settings.queue_timeout(class);
+        let waited = tokio::time::timeout(timeout, admitted).await;
+        let mut state = self.state.lock().unwrap();
+        if let Ok(Ok(())) = waited {
+            return Ok(self.permit(&mut state, client));
+        }
+        let queue = &mut state.waiting[class.index()];
+        match queue.iter().position(|waiter| waiter.id == id) {
+            Some(position) => {
+                queue.remove(position);
+                Err(self.shed(&mut state, ShedReason::QueueTimeout, class))
+            }
+            // Handed a slot right as the wait ran out.
+            None => Ok(self.permit(&mut state, client)),
+        }
+    }
+
+    pub fn snapshot(&self) -> OverloadSnapshot {
+        let mut state = self.state.lock().unwrap();
+        OverloadSnapshot {
+            in_flight: state.in_flight,
+            max_in_flight: self.settings.max_in_flight(),
+            queued: state.queued(),
+            drain_per_sec: state.drain_per_sec(Instant::now()),
+        }
+    }
+
+    fn permit(self: &Arc<Self>, state: &mut State, client: &str) -> Permit {
+        *state.per_client.entry(client.to_string()).or_default() += 1;
+        Permit {
+            overload: self.clone(),
+            client: client.to_string(),
+        }
+    }
+
+    fn shed(&self, state: &mut State, reason: ShedReason, class: ClientClass) -> Shed {
+        let drain = state.drain_per_sec(Instant::now());
+        let retry_after = retry_after(
+            state.queued(),
+            drain,
+            self.settings.max_retry_after(),
+            rand::random(),
+        );
+        Shed {
+            reason,
+            class,
+            retry_after,
+        }
+    }
+
+    fn release(&self, client: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(own) = state.per_client.get_mut(client) {
+            *own -= 1;
+            if *own == 0 {
+                state.per_client.remove(client);
+            }
+        }
+        if state.released.len() >= MAX_RELEASES {
+            state.released.pop_front();
+        }
+        state.released.push_back(Instant::now());
+        // The slot goes straight to the next waiter, best class first; a
+        // waiter whose request was dropped no longer takes it.
+        for class in ClientClass::ALL {
+            while let Some(waiter) = state.waiting[class.index()].pop_front() {
+                if waiter.admitted.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        state.in_flight -= 1;
+    }
+}
+
+/// `Retry-After` para una cola de `queued` peticiones que se vacía a
+/// `drain_per_sec`: lo que tardaría en llegarle el turno a una petición
+/// nueva, más hasta un 50 % según `jitter` (en `[0, 1)`), entre 1 s y `max`.
+/// Sin salidas recientes no hay estimación y se usa `max`.
+pub fn retry_after(queued: usize, drain_per_sec: f64, max: Duration, jitter: f64) -> Duration {
+    let max = max.as_secs().max(1);
+    if drain_per_sec <= 0.0 {
+        return Duration::from_secs(max);
+    }
+    let wait = (queued + 1) as f64 / drain_per_sec * (1.0 + jitter.clamp(0.0, 1.0) / 2.0);
+    Duration::from_secs((wait.ceil() as u64).clamp(1, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overload(settings: OverloadSettings) -> Arc<Overload> {
+        Arc::new(Overload::new(settings))
+    }
+
+    #[test]
+    fn test_retry_after_follows_the_queue_and_stays_bounded() {
+        let max = Duration::from_secs(30);
+        assert_eq!(retry_after(0, 0.0, max, 0.5), max);
+        assert_eq!(retry_after(0, 100.0, max, 0.0), Duration::from_secs(1));
+        assert_eq!(retry_after(9, 2.0, max, 0.0), Duration::from_secs(5));
+        assert_eq!(retry_after(9, 2.0, max, 0.99), Duration::from_secs(8));
+        assert_eq!(retry_after(1000, 1.0, max, 0.0), max);
+        for jitter in [0.0, 0.25, 0.5, 0.75, 0.999] {
+            let secs = retry_after(9, 2.0, max, jitter).as_secs();
+            assert!((5..=8).contains(&secs), "{secs}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_saturation_sheds_anonymous_first_and_serves_trusted_first() {
+        let settings = OverloadSettings::new(1)
+            .with_max_queue(4)
+            .with_queue_timeout(ClientClass::Standard, Duration::from_millis(100));
+        let overload = overload(settings);
+        let busy = overload.admit(ClientClass::Anonymous, "a").await.unwrap();
+
+        // Anonymous clients do not wait at all.
+        let shed = overload
+            .admit(ClientClass::Anonymous, "b")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            (shed.reason, shed.class),
+            (ShedReason::QueueFull, ClientClass::Anonymous)
+        );
+
+        // A standard client queued first still goes after a trusted one.
+        let standard = tokio::spawn({
+            let overload = overload.clone();
+            async move { overload.admit(ClientClass::Standard, "s").await.map(drop) }
+        });
+        tokio::task::yield_now().await;
+        let trusted = tokio::spawn({
+            let overload = overload.clone();
+            async move { overload.admit(ClientClass::Trusted, "t").await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(overload.snapshot().queued, 2);
+
+        drop(busy);
+        let trusted = trusted.await.unwrap().unwrap();
+        // The standard client times out while the trusted one holds the
+        // slot; the trusted wait is longer.
+        let shed = standard.await.unwrap().err().unwrap();
+        assert_eq!(
+            (shed.reason, shed.class),
+            (ShedReason::QueueTimeout, ClientClass::Standard)
+        );
+        assert!(shed.retry_after >= Duration::from_secs(1));
+        assert!(shed.retry_after <= OverloadSettings::DEFAULT_MAX_RETRY_AFTER);
+        drop(trusted);
+        assert_eq!(overload.snapshot().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_per_client_limit_sheds_without_queueing() {
+        let overload = overload(OverloadSettings::new(10).with_max_per_client(1));
+        let first = overload
+            .admit(ClientClass::Trusted, "10.0.0.1")
+            .await
+            .unwrap();
+        let shed = overload
+            .admit(ClientClass::Trusted, "10.0.0.1")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(shed.reason, ShedReason::ClientLimit);
+        assert!(overload
+            .admit(ClientClass::Trusted, "10.0.0.2")
+            .await
+            .is_ok());
+        drop(first);
+        assert!(overload
+            .admit(ClientClass::Trusted, "10.0.0.1")
+            .await
+            .is_ok());
+
+        let response = shed.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let retry = response.headers()[RETRY_AFTER].to_str().unwrap();
+        assert_eq!(retry, shed.retry_after.as_secs().to_string());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["reason"], "client_limit");
+        assert_eq!(body["class"], "trusted");
+    }
+}
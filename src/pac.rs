@@ -0,0 +1,759 @@
+//! Evaluación de archivos PAC (`FindProxyForURL`).
+//!
+//! No es un motor de JavaScript: entiende el subconjunto que usan los PAC
+//! habituales (funciones, `var`, `if`/`else`, `return`, `&&`, `||`, `!`,
+//! comparaciones, `+` de cadenas, `substring`/`toLowerCase`/`indexOf` y las
+//! funciones auxiliares estándar como `shExpMatch`, `dnsDomainIs` o
+//! `isInNet`). Las construcciones no soportadas (bucles, `new`...) hacen
+//! fallar la carga; una función desconocida solo falla al evaluarse.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use anyhow::{anyhow, bail};
+use hyper::http::uri::Authority;
+
+/// Profundidad máxima de llamadas entre funciones del PAC.
+const MAX_CALL_DEPTH: usize = 32;
+
+/// Una entrada del resultado de `FindProxyForURL`, en orden de preferencia.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacDirective {
+    Direct,
+    Proxy(Authority),
+}
+
+/// Interpreta `"PROXY a:3128; PROXY b:8080; DIRECT"`. Las entradas que el
+/// proxy no sabe usar (`SOCKS`, `HTTPS`...) se ignoran.
+pub fn parse_directives(result: &str) -> Vec<PacDirective> {
+    result
+        .split(';')
+        .filter_map(|entry| {
+            let mut parts = entry.split_whitespace();
+            match parts.next()?.to_ascii_uppercase().as_str() {
+                "DIRECT" => Some(PacDirective::Direct),
+                "PROXY" => parts.next()?.parse().ok().map(PacDirective::Proxy),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Punct(&'static str),
+}
+
+const PUNCTS: [&str; 20] = [
+    "===", "!==", "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ",", ";", ".", "!", "<",
+    ">", "=", "+",
+];
+
+fn tokenize(src: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = src.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+        if c.is_whitespace() {
+            i += 1;
+        } else if rest.starts_with("//") {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if rest.starts_with("/*") {
+            i += 2;
+            while i + 1 < chars.len() && !(chars[i] == '*' && chars[i + 1] == '/') {
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' || c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => bail!("cadena sin cerrar en el PAC"),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(&other) => value.push(other),
+                            None => bail!("cadena sin cerrar en el PAC"),
+                        }
+                    }
+                    Some(&other) => value.push(other),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(value));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(Token::Num(text.parse()?));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let punct = PUNCTS
+                .iter()
+                .find(|p| rest.starts_with(**p))
+                .ok_or_else(|| anyhow!("carácter inesperado en el PAC: `{c}`"))?;
+            i += punct.chars().count();
+            tokens.push(Token::Punct(punct));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Var(String),
+    Call(String, Vec<Expr>),
+    Method(Box<Expr>, String, Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Compare(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Block(Vec<Stmt>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    Return(Expr),
+    Var(String, Expr),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Str(s) => !s.is_empty(),
+            Value::Num(n) => *n != 0.0 && !n.is_nan(),
+            Value::Bool(b) => *b,
+            Value::Null => false,
+        }
+    }
+
+    fn as_string(&self) -> String {
+        match self {
+            Value::Str(s) => s.clone(),
+            Value::Num(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Null => "null".to_string(),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> anyhow::Result<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("el PAC termina de forma inesperada"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if self.peek() == Some(&Token::Punct(punct_static(punct))) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> anyhow::Result<()> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            bail!(
+                "se esperaba `{punct}` en el PAC, se encontró {:?}",
+                self.peek()
+            )
+        }
+    }
+
+    fn ident(&mut self) -> anyhow::Result<String> {
+        match self.next()? {
+            Token::Ident(name) => Ok(name),
+            other => bail!("se esperaba un identificador en el PAC, se encontró {other:?}"),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(name)) if name == keyword)
+    }
+
+    fn function(&mut self) -> anyhow::Result<(String, Vec<String>, Vec<Stmt>)> {
+        if self.ident()? != "function" {
+            bail!("el PAC solo puede contener declaraciones de funciones");
+        }
+        let name = self.ident()?;
+        self.expect("(")?;
+        let mut params = Vec::new();
+        while !self.eat(")") {
+            params.push(self.ident()?);
+            if !self.eat(",") {
+                self.expect(")")?;
+                break;
+            }
+        }
+        let Stmt::Block(body) = self.block()? else {
+            unreachable!("block devuelve un bloque");
+        };
+        Ok((name, params, body))
+    }
+
+    fn block(&mut self) -> anyhow::Result<Stmt> {
+        self.expect("{")?;
+        let mut body = Vec::new();
+        while !self.eat("}") {
+            body.push(self.statement()?);
+        }
+        Ok(Stmt::Block(body))
+    }
+
+    fn statement(&mut self) -> anyhow::Result<Stmt> {
+        if self.peek() == Some(&Token::Punct("{")) {
+            return self.block();
+        }
+        if self.eat(";") {
+            return Ok(Stmt::Block(Vec::new()));
+        }
+        let stmt = if self.is_keyword("if") {
+            self.pos += 1;
+            self.expect("(")?;
+            let cond = self.expr()?;
+            self.expect(")")?;
+            let then = Box::new(self.statement()?);
+            let otherwise = if self.is_keyword("else") {
+                self.pos += 1;
+                Some(Box::new(self.statement()?))
+            } else {
+                None
+            };
+            return Ok(Stmt::If(cond, then, otherwise));
+        } else if self.is_keyword("return") {
+            self.pos += 1;
+            Stmt::Return(self.expr()?)
+        } else if self.is_keyword("var") {
+            self.pos += 1;
+            let name = self.ident()?;
+            self.expect("=")?;
+            Stmt::Var(name, self.expr()?)
+        } else if matches!(self.tokens.get(self.pos + 1), Some(Token::Punct("="))) {
+            let name = self.ident()?;
+            self.expect("=")?;
+            Stmt::Var(name, self.expr()?)
+        } else {
+            Stmt::Expr(self.expr()?)
+        };
+        self.eat(";");
+        Ok(stmt)
+    }
+
+    fn expr(&mut self) -> anyhow::Result<Expr> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> anyhow::Result<Expr> {
+        let mut left = self.compare()?;
+        while self.eat("&&") {
+            left = Expr::And(Box::new(left), Box::new(self.compare()?));
+        }
+        Ok(left)
+    }
+
+    fn compare(&mut self) -> anyhow::Result<Expr> {
+        let left = self.add()?;
+        for op in ["===", "!==", "==", "!=", "<=", ">=", "<", ">"] {
+            if self.eat(op) {
+                let op = punct_static(op);
+                return Ok(Expr::Compare(op, Box::new(left), Box::new(self.add()?)));
+            }
+        }
+        Ok(left)
+    }
+
+    fn add(&mut self) -> anyhow::Result<Expr> {
+        let mut left = self.unary()?;
+        while self.eat("+") {
+            left = Expr::Add(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        let mut expr = self.primary()?;
+        while self.eat(".") {
+            let method = self.ident()?;
+            let args = self.args()?;
+            expr = Expr::Method(Box::new(expr), method, args);
+        }
+        Ok(expr)
+    }
+
+    fn args(&mut self) -> anyhow::Result<Vec<Expr>> {
+        self.expect("(")?;
+        let mut args = Vec::new();
+        while !self.eat(")") {
+            args.push(self.expr()?);
+            if !self.eat(",") {
+                self.expect(")")?;
+                break;
+            }
+        }
+        Ok(args)
+    }
+
+    fn primary(&mut self) -> anyhow::Result<Expr> {
+        match self.next()? {
+            Token::Str(s) => Ok(Expr::Literal(Value::Str(s))),
+            Token::Num(n) => Ok(Expr::Literal(Value::Num(n))),
+            Token::Punct("(") => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Ident(name) => match name.as_str() {
+                "while" | "for" | "do" | "switch" | "function" | "new" | "try" | "with" => {
+                    bail!("construcción no soportada en el PAC: `{name}`")
+                }
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" | "undefined" => Ok(Expr::Literal(Value::Null)),
+                _ if self.peek() == Some(&Token::Punct("(")) => Ok(Expr::Call(name, self.args()?)),
+                _ => Ok(Expr::Var(name)),
+            },
+            other => bail!("expresión inesperada en el PAC: {other:?}"),
+        }
+    }
+}
+
+fn punct_static(punct: &str) -> &'static str {
+    PUNCTS
+        .iter()
+        .find(|p| **p == punct)
+        .copied()
+        .expect("puntuación conocida")
+}
+
+/// Datos de la petición disponibles para las funciones del PAC.
+#[derive(Debug, Clone)]
+pub struct PacRequest<'a> {
+    pub url: &'a str,
+    pub host: &'a str,
+    /// IP de `host`, resuelta antes de evaluar si el PAC usa DNS.
+    pub resolved: Option<IpAddr>,
+    pub my_ip: Option<IpAddr>,
+}
+
+type Function = (Vec<String>, Vec<Stmt>);
+
+/// PAC ya analizado, listo para evaluarse muchas veces.
+#[derive(Debug)]
+pub struct PacScript {
+    functions: HashMap<String, Function>,
+    uses_dns: bool,
+}
+
+impl PacScript {
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        let tokens = tokenize(src)?;
+        let uses_dns = tokens.iter().any(|t| {
+            matches!(t, Token::Ident(name) if ["isInNet", "dnsResolve", "isResolvable"].contains(&name.as_str()))
+        });
+        let mut parser = Parser { tokens, pos: 0 };
+        let mut functions = HashMap::new();
+        while parser.peek().is_some() {
+            let (name, params, body) = parser.function()?;
+            functions.insert(name, (params, body));
+        }
+        if !functions.contains_key("FindProxyForURL") {
+            bail!("el PAC no define FindProxyForURL");
+        }
+        Ok(Self {
+            functions,
+            uses_dns,
+        })
+    }
+
+    /// Indica si conviene resolver el host antes de evaluar.
+    pub fn uses_dns(&self) -> bool {
+        self.uses_dns
+    }
+
+    /// Ejecuta `FindProxyForURL(url, host)` y devuelve la cadena resultante.
+    pub fn find_proxy(&self, req: &PacRequest<'_>) -> anyhow::Result<String> {
+        let args = vec![
+            Value::Str(req.url.to_string()),
+            Value::Str(req.host.to_string()),
+        ];
+        match self.call("FindProxyForURL", args, req, 0)? {
+            Value::Str(result) => Ok(result),
+            other => bail!("FindProxyForURL devolvió {other:?} en lugar de una cadena"),
+        }
+    }
+
+    fn call(
+        &self,
+        name: &str,
+        args: Vec<Value>,
+        req: &PacRequest<'_>,
+        depth: usize,
+    ) -> anyhow::Result<Value> {
+        if depth > MAX_CALL_DEPTH {
+            bail!("el PAC supera la profundidad máxima de llamadas");
+        }
+        if let Some((params, body)) = self.functions.get(name) {
+            let mut vars: HashMap<String, Value> = params
+                .iter()
+                .cloned()
+                .zip(args.into_iter().chain(std::iter::repeat(Value::Null)))
+                .collect();
+            return Ok(self
+                .exec_all(body, &mut vars, req, depth + 1)?
+                .unwrap_or(Value::Null));
+        }
+        builtin(name, &args, req)
+    }
+
+    fn exec_all(
+        &self,
+        body: &[Stmt],
+        vars: &mut HashMap<String, Value>,
+        req: &PacRequest<'_>,
+        depth: usize,
+    ) -> anyhow::Result<Option<Value>> {
+        for stmt in body {
+            if let Some(value) = self.exec(stmt, vars, req, depth)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    fn exec(
+        &self,
+        stmt: &Stmt,
+        vars: &mut HashMap<String, Value>,
+        req: &PacRequest<'_>,
+        depth: usize,
+    ) -> anyhow::Result<Option<Value>> {
+        match stmt {
+            Stmt::Block(body) => self.exec_all(body, vars, req, depth),
+            Stmt::If(cond, then, otherwise) => {
+                if self.eval(cond, vars, req, depth)?.truthy() {
+                    self.exec(then, vars, req, depth)
+                } else if let Some(otherwise) = otherwise {
+                    self.exec(otherwise, vars, req, depth)
+                } else {
+                    Ok(None)
+                }
+            }
+            Stmt::Return(expr) => Ok(Some(self.eval(expr, vars, req, depth)?)),
+            Stmt::Var(name, expr) => {
+                let value = self.eval(expr, vars, req, depth)?;
+                vars.insert(name.clone(), value);
+                Ok(None)
+            }
+            Stmt::Expr(expr) => {
+                self.eval(expr, vars, req, depth)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn eval(
+        &self,
+        expr: &Expr,
+        vars: &HashMap<String, Value>,
+        req: &PacRequest<'_>,
+        depth: usize,
+    ) -> anyhow::Result<Value> {
+        let eval = |e: &Expr| self.eval(e, vars, req, depth);
+        Ok(match expr {
+            Expr::Literal(value) => value.clone(),
+            Expr::Var(name) => vars
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("variable desconocida en el PAC: {name}"))?,
+            Expr::Call(name, args) => {
+                let args = args.iter().map(eval).collect::<anyhow::Result<_>>()?;
+                self.call(name, args, req, depth)?
+            }
+            Expr::Method(target, method, args) => {
+                let target = eval(target)?.as_string();
+                let args: Vec<Value> = args.iter().map(eval).collect::<anyhow::Result<_>>()?;
+                string_method(&target, method, &args)?
+            }
+            Expr::Not(inner) => Value::Bool(!eval(inner)?.truthy()),
+            Expr::And(a, b) => {
+                let a = eval(a)?;
+                if a.truthy() {
+                    eval(b)?
+                } else {
+                    a
+                }
+            }
+            Expr::Or(a, b) => {
+                let a = eval(a)?;
+                if a.truthy() {
+                    a
+                } else {
+                    eval(b)?
+                }
+            }
+            Expr::Add(a, b) => match (eval(a)?, eval(b)?) {
+                (Value::Num(a), Value::Num(b)) => Value::Num(a + b),
+                (a, b) => Value::Str(format!("{}{}", a.as_string(), b.as_string())),
+            },
+            Expr::Compare(op, a, b) => {
+                let (a, b) = (eval(a)?, eval(b)?);
+                let ordering = match (&a, &b) {
+                    (Value::Num(x), Value::Num(y)) => x.partial_cmp(y),
+                    _ => Some(a.as_string().cmp(&b.as_string())),
+                };
+                let result = match *op {
+                    "==" | "===" => a == b,
+                    "!=" | "!==" => a != b,
+                    "<" => ordering == Some(std::cmp::Ordering::Less),
+                    ">" => ordering == Some(std::cmp::Ordering::Greater),
+                    "<=" => matches!(ordering, Some(o) if o.is_le()),
+                    _ => matches!(ordering, Some(o) if o.is_ge()),
+                };
+                Value::Bool(result)
+            }
+        })
+    }
+}
+
+fn string_method(target: &str, method: &str, args: &[Value]) -> anyhow::Result<Value> {
+    let num = |i: usize| match args.get(i) {
+        Some(Value::Num(n)) => Some(n.max(0.0) as usize),
+        _ => None,
+    };
+    let chars: Vec<char> = target.chars().collect();
+    Ok(match method {
+        "toLowerCase" => Value::Str(target.to_lowercase()),
+        "toUpperCase" => Value::Str(target.to_uppercase()),
+        "substring" | "substr" | "slice" => {
+            let start = num(0).unwrap_or(0).min(chars.len());
+            let end = match (method, num(1)) {
+                ("substr", Some(len)) => (start + len).min(chars.len()),
+                (_, Some(end)) => end.min(chars.len()),
+                (_, None) => chars.len(),
+            };
+            let (start, end) = (start.min(end), start.max(end));
+            Value::Str(chars[start..end].iter().collect())
+        }
+        "indexOf" => {
+            let needle = args.first().map(Value::as_string).unwrap_or_default();
+            match target.find(&needle) {
+                Some(byte) => Value::Num(target[..byte].chars().count() as f64),
+                None => Value::Num(-1.0),
+            }
+        }
+        _ => bail!("método no soportado en el PAC: {method}"),
+    })
+}
+
+fn builtin(name: &str, args: &[Value], req: &PacRequest<'_>) -> anyhow::Result<Value> {
+    let arg = |i: usize| args.get(i).map(Value::as_string).unwrap_or_default();
+    let resolve = |host: &str| -> Option<IpAddr> {
+        host.parse()
+            .ok()
+            .or_else(|| req.resolved.filter(|_| host.eq_ignore_ascii_case(req.host)))
+    };
+    Ok(match name {
+        "isPlainHostName" => Value::Bool(!arg(0).contains('.')),
+        "dnsDomainIs" => {
+            let (host, domain) = (arg(0).to_ascii_lowercase(), arg(1).to_ascii_lowercase());
+            Value::Bool(host.ends_with(&domain))
+        }
+        "localHostOrDomainIs" => {
+            let (host, full) = (arg(0).to_ascii_lowercase(), arg(1).to_ascii_lowercase());
+            Value::Bool(
+                host == full || (!host.contains('.') && full.starts_with(&format!("{host}."))),
+            )
+        }
+        "dnsDomainLevels" => Value::Num(arg(0).matches('.').count() as f64),
+        "shExpMatch" => Value::Bool(shell_match(&arg(0), &arg(1))),
+        "isResolvable" => Value::Bool(resolve(&arg(0)).is_some()),
+        "dnsResolve" => resolve(&arg(0)).map_or(Value::Null, |ip| Value::Str(ip.to_string())),
+        "myIpAddress" => Value::Str(
+            req.my_ip
+                .map_or_else(|| "127.0.0.1".to_string(), |ip| ip.to_string()),
+        ),
+        "isInNet" => {
+            let (Some(IpAddr::V4(ip)), Ok(net), Ok(mask)) = (
+                resolve(&arg(0)),
+                arg(1).parse::<std::net::Ipv4Addr>(),
+                arg(2).parse::<std::net::Ipv4Addr>(),
+            ) else {
+                return Ok(Value::Bool(false));
+            };
+            let mask = u32::from(mask);
+            Value::Bool(u32::from(ip) & mask == u32::from(net) & mask)
+        }
+        "alert" => Value::Null,
+        _ => bail!("función no soportada en el PAC: {name}"),
+    })
+}
+
+/// Comodines de shell: `*` cualquier secuencia y `?` un carácter.
+fn shell_match(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            t += 1;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAC: &str = r#"
+        // Typical corporate PAC.
+        function isInternal(host) {
+            return dnsDomainIs(host, ".corp.example") || isPlainHostName(host);
+        }
+
+        function FindProxyForURL(url, host) {
+            var lower = host.toLowerCase();
+            if (isInternal(lower)) return "DIRECT";
+            if (url.substring(0, 5) == "http:" && shExpMatch(lower, "*.legacy.*"))
+                return "PROXY old.example:8080";
+            /* Private ranges go direct too. */
+            if (isInNet(dnsResolve(host), "10.0.0.0", "255.0.0.0")) {
+                return "DIRECT";
+            } else if (lower.indexOf("cdn") >= 0) {
+                return "PROXY cdn.example:3128; DIRECT";
+            }
+            return "PROXY a.example:3128; PROXY b.example:3128; SOCKS s:1080";
+        }
+    "#;
+
+    fn find(url: &str, host: &str, resolved: Option<IpAddr>) -> String {
+        let pac = PacScript::parse(PAC).unwrap();
+        let req = PacRequest {
+            url,
+            host,
+            resolved,
+            my_ip: None,
+        };
+        pac.find_proxy(&req).unwrap()
+    }
+
+    #[test]
+    fn test_find_proxy() {
+        assert_eq!(find("http://intranet/", "intranet", None), "DIRECT");
+        assert_eq!(
+            find("https://wiki.CORP.example/", "wiki.CORP.example", None),
+            "DIRECT"
+        );
+        assert_eq!(
+            find("http://app.legacy.example/", "app.legacy.example", None),
+            "PROXY old.example:8080"
+        );
+        let private = Some("10.1.2.3".parse().unwrap());
+        assert_eq!(find("https://db.example/", "db.example", private), "DIRECT");
+        assert_eq!(
+            find("https://mycdn.example/", "mycdn.example", None),
+            "PROXY cdn.example:3128; DIRECT"
+        );
+        let chain = find("https://www.example/", "www.example", None);
+        assert_eq!(
+            parse_directives(&chain),
+            [
+                PacDirective::Proxy("a.example:3128".parse().unwrap()),
+                PacDirective::Proxy("b.example:3128".parse().unwrap()),
+            ]
+        );
+        assert!(PacScript::parse(PAC).unwrap().uses_dns());
+    }
+
+    #[test]
+    fn test_rejects_unsupported_scripts() {
+        assert!(PacScript::parse("function Other(url, host) { return 'DIRECT'; }").is_err());
+        assert!(PacScript::parse("function FindProxyForURL(url, host) { while (1) {} }").is_err());
+        let pac =
+            PacScript::parse("function FindProxyForURL(url, host) { return weekdayRange('MON'); }")
+                .unwrap();
+        let req = PacRequest {
+            url: "http://a/",
+            host: "a",
+            resolved: None,
+            my_ip: None,
+        };
+        assert!(pac.find_proxy(&req).is_err());
+    }
+
+    #[test]
+    fn test_shell_match() {
+        assert!(shell_match("www.example.com", "*.example.*"));
+        assert!(shell_match("abc", "a?c"));
+        assert!(!shell_match("example.org", "*.example.*"));
+        assert!(shell_match("", "*"));
+    }
+}
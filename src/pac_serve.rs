@@ -0,0 +1,189 @@
+//! `GET /proxy.pac`: un PAC que apunta a este proxy.
+//!
+//! Para configurar los navegadores de una red basta con darles la URL
+//! `http://<proxy>:<puerto>/proxy.pac`. Como `/proxy-info`, solo responde a
+//! una petición en forma de origen (o, en HTTP/2, con la autoridad del propio
+//! listener): una petición a `http://origen/proxy.pac` se reenvía como
+//! cualquier otra. El script manda cada destino a `PROXY host:puerto` (o
+//! `HTTPS host:puerto` si el cliente habla TLS con el proxy) salvo los de
+//! `bypass`, que van en `DIRECT` con la misma sintaxis que `[host_filter]`:
+//! hosts exactos, `*.dominio` y rangos CIDR que solo casan con IPs literales.
+//!
+//! El `host:puerto` anunciado es `advertise` si está configurado; si no, el
+//! `Host` con el que el cliente pidió el PAC, y en último caso la dirección
+//! local de la conexión. Así un listener en `0.0.0.0` no se anuncia como tal.
+
+use std::fmt::Write as _;
+use std::net::IpAddr;
+
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Body, Method, Request, Response, StatusCode, Version};
+
+use crate::connection::{LocalAddr, Protocol};
+use crate::host_filter::HostRule;
+use crate::host_pattern::HostPattern;
+use crate::settings::ServePacSettings;
+
+pub const PATH: &str = "/proxy.pac";
+
+pub const CONTENT_TYPE_PAC: &str = "application/x-ns-proxy-autoconfig";
+
+/// Indica si `req` pide el PAC en lugar de un destino.
+pub fn is_request(req: &Request<Body>) -> bool {
+    if req.method() != Method::GET || req.uri().path() != PATH {
+        return false;
+    }
+    let Some(authority) = req.uri().authority() else {
+        return true;
+    };
+    req.version() == Version::HTTP_2
+        && req
+            .extensions()
+            .get::<LocalAddr>()
+            .is_some_and(|local| authority.port_u16() == Some(local.0.port()))
+}
+
+/// `host:puerto` con el que el cliente debe hablar con el proxy.
+fn advertised(settings: &ServePacSettings, req: &Request<Body>) -> Option<String> {
+    if let Some(advertise) = settings.advertise() {
+        return Some(advertise.to_string());
+    }
+    let local = req.extensions().get::<LocalAddr>().map(|local| local.0);
+    let requested = req
+        .uri()
+        .authority()
+        .cloned()
+        .or_else(|| req.headers().get(HOST)?.to_str().ok()?.parse().ok());
+    match (requested, local) {
+        (Some(authority), _) if authority.port_u16().is_some() => Some(authority.to_string()),
+        (Some(authority), Some(local)) => Some(format!("{}:{}", authority.host(), local.port())),
+        (None, Some(local)) if !local.ip().is_unspecified() => Some(local.to_string()),
+        _ => None,
+    }
+}
+
+/// El PAC para `req`, o un 500 si no hay forma de saber la dirección del
+/// proxy.
+pub fn respond(settings: &ServePacSettings, req: &Request<Body>) -> Response<Body> {
+    let Some(proxy) = advertised(settings, req) else {
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(
+                "No se sabe qué dirección anunciar; configura `advertise` en [serve_pac].\n",
+            ))
+            .expect("respuesta válida");
+    };
+    let tls = req.extensions().get::<Protocol>() == Some(&Protocol::Tls);
+    let proxy = format!("{} {proxy}", if tls { "HTTPS" } else { "PROXY" });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, CONTENT_TYPE_PAC)
+        .body(Body::from(script(settings.bypass(), &proxy)))
+        .expect("respuesta válida")
+}
+
+/// `FindProxyForURL` que devuelve `DIRECT` para `bypass` y `proxy` para lo
+/// demás.
+pub fn script(bypass: &[HostRule], proxy: &str) -> String {
+    let mut js = String::from("function FindProxyForURL(url, host) {\n");
+    js.push_str(
+        "    host = host.toLowerCase().replace(/^\\[|\\]$/g, \"\").replace(/\\.$/, \"\");\n",
+    );
+    for rule in bypass {
+        let _ = writeln!(js, "    if ({}) return \"DIRECT\";", condition(rule));
+    }
+    let _ = writeln!(js, "    return {};", literal(proxy));
+    js.push_str("}\n");
+    js
+}
+
+fn condition(rule: &HostRule) -> String {
+    match rule {
+        HostRule::Host(HostPattern::Any) => "true".to_string(),
+        HostRule::Host(HostPattern::Exact(host)) => format!("host == {}", literal(host)),
+        HostRule::Host(HostPattern::Subdomains(suffix)) => {
+            format!("dnsDomainIs(host, {})", literal(suffix))
+        }
+        // `isInNet` would resolve names; like `[host_filter]`, ranges only
+        // apply to literal IPs.
+        HostRule::Range(range) => match range.network() {
+            IpAddr::V4(network) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(range.prefix()))
+                    .unwrap_or(0);
+                format!(
+                    "/^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host) && isInNet(host, {}, {})",
+                    literal(&network.to_string()),
+                    literal(&std::net::Ipv4Addr::from(mask).to_string())
+                )
+            }
+            IpAddr::V6(_) => format!(
+                "host.indexOf(\":\") >= 0 && typeof isInNetEx == \"function\" && isInNetEx(host, {})",
+                literal(&range.to_string())
+            ),
+        },
+    }
+}
+
+/// Cadena de JavaScript; JSON es un subconjunto válido.
+fn literal(value: &str) -> String {
+    serde_json::to_string(value).expect("una cadena siempre se serializa")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_bypasses_rules_and_returns_the_proxy() {
+        let bypass: Vec<HostRule> = ["intranet.corp", "*.local.corp", "10.0.0.0/8", "fd00::/8"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        let js = script(&bypass, "PROXY proxy.corp:3128");
+
+        assert!(js.starts_with("function FindProxyForURL(url, host) {\n"));
+        assert!(js.contains("if (host == \"intranet.corp\") return \"DIRECT\";"));
+        assert!(js.contains("if (dnsDomainIs(host, \".local.corp\")) return \"DIRECT\";"));
+        assert!(js.contains("isInNet(host, \"10.0.0.0\", \"255.0.0.0\")) return \"DIRECT\";"));
+        assert!(js.contains("isInNetEx(host, \"fd00::/8\")) return \"DIRECT\";"));
+        assert!(js
+            .trim_end()
+            .ends_with("return \"PROXY proxy.corp:3128\";\n}"));
+
+        let everything = script(&["*".parse().unwrap()], "PROXY p:1");
+        assert!(everything.contains("if (true) return \"DIRECT\";"));
+    }
+
+    #[test]
+    fn test_advertised_address_prefers_configuration_then_host() {
+        let local = LocalAddr("0.0.0.0:3128".parse().unwrap());
+        let request = |host: Option<&str>| {
+            let mut builder = Request::get(PATH);
+            if let Some(host) = host {
+                builder = builder.header(HOST, host);
+            }
+            let mut req = builder.body(Body::empty()).unwrap();
+            req.extensions_mut().insert(local);
+            req
+        };
+        let default = ServePacSettings::default();
+        assert_eq!(
+            advertised(&default, &request(Some("proxy.corp:3128"))).as_deref(),
+            Some("proxy.corp:3128")
+        );
+        assert_eq!(
+            advertised(&default, &request(Some("proxy.corp"))).as_deref(),
+            Some("proxy.corp:3128")
+        );
+        // A wildcard listen address is no use to a browser.
+        assert_eq!(advertised(&default, &request(None)), None);
+
+        let configured = default.with_advertise("pac.example:8080".parse().unwrap());
+        assert_eq!(
+            advertised(&configured, &request(Some("proxy.corp:3128"))).as_deref(),
+            Some("pac.example:8080")
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! Exportación de la política efectiva para auditorías.
+//!
+//! `GET /api/policy/export?format=csv|json` aplana en una fila por regla lo
+//! que el proxy aplica en este momento: el filtro de hosts, las reglas de
+//! salida, los destinos de cada perfil, la vía rápida, las zonas y
+//! reescrituras DNS, las filas de los mapas de redirecciones, la lista de
+//! revocación, el script, las IPs que el servicio de reputación marcó y
+//! siguen en caché, y los baneos. Cada
+//! fila sale de las estructuras vivas, no de releer archivos, así que refleja
+//! las recargas en caliente. `source` dice de dónde viene: `config`,
+//! `file:<ruta>`, `feed:<url>` o `runtime`. El body se envía por tandas de
+//! filas.
+
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::stream;
+use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::proxy::ProxyContext;
+use crate::redirect_map::MapAction;
+use crate::settings::DnsRewriteAction;
+
+/// Filas que se codifican en cada trozo del body.
+const BATCH_ROWS: usize = 500;
+
+const COLUMNS: [&str; 8] = [
+    "source", "kind", "pattern", "action", "detail", "expires", "hits", "last_hit",
+];
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Aplicaciones de una regla y la última, en segundos Unix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct HitCount {
+    pub hits: u64,
+    pub last_hit: Option<u64>,
+}
+
+/// Contador de aplicaciones de una regla.
+#[derive(Debug, Default)]
+pub struct RuleHits {
+    count: AtomicU64,
+    /// Unix seconds; zero means never.
+    last: AtomicU64,
+}
+
+impl RuleHits {
+    /// Contador que continúa uno anterior, para conservarlo al recargar.
+    pub fn starting_at(count: HitCount) -> Self {
+        Self {
+            count: AtomicU64::new(count.hits),
+            last: AtomicU64::new(count.last_hit.unwrap_or(0)),
+        }
+    }
+
+    pub fn record(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.last.store(now_secs(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> HitCount {
+        let last = self.last.load(Ordering::Relaxed);
+        HitCount {
+            hits: self.count.load(Ordering::Relaxed),
+            last_hit: (last > 0).then_some(last),
+        }
+    }
+}
+
+/// Formato de la exportación.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    #[default]
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            other => anyhow::bail!("formato desconocido: {other} (csv o json)"),
+        }
+    }
+}
+
+/// Una regla en vigor. `expires` y `last_hit` son segundos Unix; `hits`
+/// vale `None` en las reglas cuyas aplicaciones no se cuentan.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PolicyRow {
+    pub source: String,
+    pub kind: &'static str,
+    pub pattern: String,
+    pub action: String,
+    pub detail: Option<String>,
+    pub expires: Option<u64>,
+    pub hits: Option<u64>,
+    pub last_hit: Option<u64>,
+}
+
+impl PolicyRow {
+    fn new(source: impl Into<String>, kind: &'static str, pattern: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            kind,
+            pattern: pattern.into(),
+            action: String::new(),
+            detail: None,
+            expires: None,
+            hits: None,
+            last_hit: None,
+        }
+    }
+
+    fn action(mut self, action: impl Into<String>) -> Self {
+        self.action = action.into();
+        self
+    }
+
+    fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    fn hits(mut self, count: HitCount) -> Self {
+        self.hits = Some(count.hits);
+        self.last_hit = count.last_hit;
+        self
+    }
+
+    fn csv(&self, out: &mut String) {
+        let fields = [
+            self.source.clone(),
+            self.kind.to_string(),
+            self.pattern.clone(),
+            self.action.clone(),
+            self.detail.clone().unwrap_or_default(),
+            self.expires.map(|v| v.to_string()).unwrap_or_default(),
+            self.hits.map(|v| v.to_string()).unwrap_or_default(),
+            self.last_hit.map(|v| v.to_string()).unwrap_or_default(),
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            csv_field(field, out);
+        }
+        out.push_str("\r\n");
+    }
+}
+
+/// Escribe un campo CSV, entre comillas si hace falta. Los que empiezan
+/// como una fórmula llevan un `'` delante para que la hoja de cálculo no
+/// los evalúe.
+fn csv_field(field: &str, out: &mut String) {
+    let formula = field.starts_with(['=', '+', '-', '@']);
+    if formula || field.contains([',', '"', '\r', '\n']) {
+        out.push('"');
+        if formula {
+            out.push('\'');
+        }
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+/// Reúne las reglas en vigor de todos los subsistemas activos.
+pub fn collect(ctx: &ProxyContext) -> Vec<PolicyRow> {
+    let now = now_secs();
+    let mut rows = Vec::new();
+
+    if let Some(egress) = ctx.egress() {
+        for (rule, count) in egress.rules() {
+            let mut pattern = rule.host().to_string();
+            if let Some(port) = rule.port() {
+                let _ = write!(pattern, ":{port}");
+            }
+            let mut row = PolicyRow::new("config", "egress", pattern)
+                .action("allow")
+                .hits(count);
+            if !rule.path_prefixes().is_empty() {
+                row = row.detail(format!(
+                    "{} (rutas {})",
+                    rule.name(),
+                    rule.path_prefixes().join(" ")
+                ));
+            } else {
+                row = row.detail(rule.name());
+            }
+            rows.push(row);
+        }
+    }
+
+    if let Some(blocklist) = ctx.blocklist() {
+        for entry in blocklist.list() {
+            rows.push(PolicyRow::new("runtime", "blocklist", entry.host).action("deny"));
+        }
+    }
+
+    if let Some(host_filter) = ctx.host_filter() {
+        let settings = host_filter.settings();
+        for (rules, action) in [(settings.deny(), "deny"), (settings.allow(), "allow")] {
+            for rule in rules {
+                rows.push(PolicyRow::new("config", "host_filter", rule.to_string()).action(action));
+            }
+        }
+    }
+
+    if let Some(identity) = ctx.identity() {
+        for profile in identity.profiles() {
+            for host in profile.allow() {
+                rows.push(
+                    PolicyRow::new("config", "profile", host.to_string())
+                        .action("allow")
+                        .detail(format!("perfil {}", profile.name())),
+                );
+            }
+        }
+        if let Some(path) = identity.denylist_path() {
+            let source = format!("file:{}", path.display());
+            for spki in identity.revoked() {
+                rows.push(PolicyRow::new(source.clone(), "denylist", spki).action("revoke"));
+            }
+        }
+    }
+
+    for (host, count) in ctx.fast_path().hosts() {
+        rows.push(
+            PolicyRow::new("config", "fast_path", host.to_string())
+                .action("fast_path")
+                .hits(count),
+        );
+    }
+
+    if let Some(dns) = ctx.dns() {
+        let settings = dns.settings();
+        for (zone, server) in settings.zones() {
+            rows.push(
+                PolicyRow::new("config", "dns_zone", zone.to_string())
+                    .action(format!("resolve {server}")),
+            );
+        }
+        for (host, answer, action) in settings.rewrites() {
+            let action = match action {
+                DnsRewriteAction::Replace(ip) => format!("replace {ip}"),
+                DnsRewriteAction::Requery(server) => format!("requery {server}"),
+            };
+            rows.push(
+                PolicyRow::new("config", "dns_rewrite", host.to_string())
+                    .action(action)
+                    .detail(format!("respuestas en {answer}")),
+            );
+        }
+    }
+
+    if let Some(maps) = ctx.redirect_maps() {
+        for entry in maps.entries() {
+            let action = match entry.action {
+                MapAction::Redirect(status) => format!("redirect {}", status.as_u16()),
+                MapAction::Rewrite => "rewrite".to_string(),
+            };
+            rows.push(
+                PolicyRow::new(
+                    format!("file:{}", entry.file),
+                    "redirect_map",
+                    format!("{}{}", entry.host, entry.prefix),
+                )
+                .action(action)
+                .detail(entry.target)
+                .hits(entry.hits),
+            );
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    if let Some(script) = ctx.script() {
+        rows.push(
+            PolicyRow::new(
+                format!("file:{}", script.settings().path().display()),
+                "script",
+                "*",
+            )
+            .action("filter_route")
+            .detail("las decisiones dependen del código del script"),
+        );
+    }
+
+    if let Some(reputation) = ctx.reputation() {
+        let source = format!("feed:{}", reputation.settings().url());
+        for listed in reputation.listed() {
+            let mut row = PolicyRow::new(source.clone(), "reputation", listed.ip.to_string())
+                .action(listed.action.as_str())
+                .detail(format!("puntuación {}", listed.score));
+            row.expires = Some(now + listed.expires_in.as_secs());
+            rows.push(row);
+        }
+    }
+
+    if let Some(bans) = ctx.bans() {
+        for ban in bans.active() {
+            let mut row = PolicyRow::new("runtime", "ban", ban.ip.to_string())
+                .action("ban")
+                .detail(format!("{} baneos: {}", ban.strikes, ban.reasons.join(" ")));
+            row.expires = Some(now + ban.remaining_secs);
+            rows.push(row);
+        }
+    }
+
+    rows
+}
+
+/// Respuesta con las filas en `format`, enviada por tandas.
+pub fn respond(rows: Vec<PolicyRow>, format: Format) -> Response<Body> {
+    let mut batches = Vec::new();
+    let mut rows = rows.into_iter();
+    loop {
+        let batch: Vec<PolicyRow> = rows.by_ref().take(BATCH_ROWS).collect();
+        if batch.is_empty() {
+            break;
+        }
+        batches.push(batch);
+    }
+
+    let (head, tail) = match format {
+        Format::Csv => (format!("{}\r\n", COLUMNS.join(",")), String::new()),
+        Format::Json => ("[".to_string(), "]\n".to_string()),
+    };
+    let body = stream::iter(
+        std::iter::once(head)
+            .chain(batches.into_iter().enumerate().map(move |(i, batch)| {
+                let mut out = String::new();
+                for (j, row) in batch.iter().enumerate() {
+                    match format {
+                        Format::Csv => row.csv(&mut out),
+                        Format::Json => {
+                            if i > 0 || j > 0 {
+                                out.push(',');
+                            }
+                            out.push('\n');
+                            out.push_str(&serde_json::to_string(row).expect("fila serializable"));
+                        }
+                    }
+                }
+                out
+            }))
+            .chain(std::iter::once(tail))
+            .map(Ok::<_, Infallible>),
+    );
+
+    let mut builder = Response::builder().status(StatusCode::OK);
+    builder = match format {
+        Format::Csv => builder
+            .header(CONTENT_TYPE, "text/csv; charset=utf-8")
+            .header(CONTENT_DISPOSITION, "attachment; filename=\"policy.csv\""),
+        Format::Json => builder.header(CONTENT_TYPE, "application/json"),
+    };
+    builder
+        .body(Body::wrap_stream(body))
+        .expect("respuesta de exportación")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_quotes_separators_and_neutralizes_formulas() {
+        let row = PolicyRow::new("file:/etc/mapa, viejo.csv", "redirect_map", "a.test/x")
+            .action("redirect 301")
+            .detail("=HYPERLINK(\"http://evil\")")
+            .hits(HitCount {
+                hits: 3,
+                last_hit: Some(1_700_000_000),
+            });
+        let mut out = String::new();
+        row.csv(&mut out);
+        assert_eq!(
+            out,
+            "\"file:/etc/mapa, viejo.csv\",redirect_map,a.test/x,redirect 301,\
+             \"'=HYPERLINK(\"\"http://evil\"\")\",,3,1700000000\r\n"
+        );
+    }
+}
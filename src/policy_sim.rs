@@ -0,0 +1,501 @@
+//! Simulación de la política de destinos sobre tráfico grabado.
+//!
+//! `proxy-ia policy simulate --config nueva.toml --input access.log.json`
+//! pasa cada línea del log de acceso, o cada captura de un directorio de
+//! capturas, por el filtro de hosts, las reglas de salida y la lista de
+//! puertos de `CONNECT` de la configuración, sin abrir ninguna conexión. El
+//! informe cuenta cuántas veces decidió cada regla y, frente a una política
+//! de referencia (otra configuración con `--baseline`, o la que aplica el
+//! proxy en marcha si su API de administración responde), cuántas
+//! peticiones pasarían a bloquearse o a permitirse, con ejemplos.
+//!
+//! El log de acceso no guarda la ruta, así que sus líneas solo casan con
+//! reglas de salida sin prefijos de ruta; las capturas sí la tienen. Los
+//! baneos, la reputación, el script, los perfiles, los cupos y los mapas de
+//! redirecciones no se simulan.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::access_log::AccessEntry;
+use crate::capture::{read_bundle, RecordedBundle};
+use crate::egress::EgressPolicy;
+use crate::host_filter::HostFilter;
+use crate::metrics::Policy;
+use crate::proxy::ProxyContext;
+use crate::settings::{AdminToken, EgressMode, ProxySettings};
+
+/// Ejemplos que se guardan de cada cambio.
+pub const MAX_EXAMPLES: usize = 5;
+
+/// Destinos por petición a `POST /api/policy/simulate`.
+pub const LIVE_BATCH: usize = 1000;
+
+/// Nombre de la lista de puertos de `CONNECT` en el informe.
+const CONNECT_PORTS: &str = "connect_ports";
+
+/// Lo que se evalúa de una petición grabada.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    pub method: String,
+    pub host: String,
+    pub port: u16,
+    /// `None` en los túneles y en las líneas del log de acceso.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+impl Target {
+    /// `None` si la petición no traía host.
+    pub fn from_access_entry(entry: &AccessEntry) -> Option<Self> {
+        if entry.host.is_empty() {
+            return None;
+        }
+        let https = entry.method == "CONNECT" || entry.scheme.as_deref() == Some("https");
+        Some(Self {
+            method: entry.method.clone(),
+            host: entry.host.clone(),
+            port: entry.port.unwrap_or(if https { 443 } else { 80 }),
+            path: None,
+        })
+    }
+
+    /// `None` si la URL capturada no tiene host.
+    pub fn from_bundle(bundle: &RecordedBundle) -> Option<Self> {
+        let uri = bundle.request.replay_url().ok()?;
+        let connect = bundle.request.method == "CONNECT";
+        let https = connect || uri.scheme_str() == Some("https");
+        Some(Self {
+            method: bundle.request.method.clone(),
+            host: uri.host()?.to_string(),
+            port: uri.port_u16().unwrap_or(if https { 443 } else { 80 }),
+            path: (!connect).then(|| uri.path().to_string()),
+        })
+    }
+
+    fn is_connect(&self) -> bool {
+        self.method == "CONNECT"
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}:{}", self.method, self.host, self.port)?;
+        if let Some(path) = &self.path {
+            write!(f, "{path}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Peticiones leídas de `--input`.
+#[derive(Debug, Default)]
+pub struct Input {
+    pub targets: Vec<Target>,
+    /// Líneas o capturas ilegibles o sin host.
+    pub skipped: usize,
+}
+
+/// Un directorio se lee como capturas `<id>.json`; un archivo, como log de
+/// acceso en JSON con una línea por petición.
+pub fn read_input(path: &Path) -> anyhow::Result<Input> {
+    let mut input = Input::default();
+    if path.is_dir() {
+        let mut files = std::fs::read_dir(path)
+            .with_context(|| format!("No se pudo leer el directorio {}", path.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        files.retain(|file| file.extension().is_some_and(|ext| ext == "json"));
+        files.sort();
+        for file in files {
+            let target = std::fs::read(&file)
+                .ok()
+                .and_then(|bytes| read_bundle(&bytes).ok())
+                .and_then(|bundle| Target::from_bundle(&bundle));
+            input.push(target);
+        }
+    } else {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("No se pudo leer {}", path.display()))?;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let target = serde_json::from_str::<AccessEntry>(line)
+                .ok()
+                .and_then(|entry| Target::from_access_entry(&entry));
+            input.push(target);
+        }
+    }
+    Ok(input)
+}
+
+impl Input {
+    fn push(&mut self, target: Option<Target>) {
+        match target {
+            Some(target) => self.targets.push(target),
+            None => self.skipped += 1,
+        }
+    }
+}
+
+/// Una regla que decidió sobre una petición.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RuleHit {
+    pub policy: String,
+    /// `None` cuando bloquea la falta de regla: ninguna `allow` casó.
+    pub rule: Option<String>,
+    pub allowed: bool,
+}
+
+/// Resultado de evaluar un destino.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Las reglas que intervinieron, en el orden en que se evalúan; si se
+    /// bloquea, la última es la que bloqueó.
+    pub rules: Vec<RuleHit>,
+}
+
+impl Decision {
+    fn hit(&mut self, policy: &str, rule: Option<String>, allowed: bool) {
+        self.allowed = allowed;
+        self.rules.push(RuleHit {
+            policy: policy.to_string(),
+            rule,
+            allowed,
+        });
+    }
+}
+
+/// Las políticas que se simulan, prestadas de un contexto o de un
+/// [`PolicySet`].
+#[derive(Clone, Copy)]
+pub struct Policies<'a> {
+    host_filter: Option<&'a HostFilter>,
+    egress: Option<&'a EgressPolicy>,
+    /// `None` deja abrir túneles a cualquier puerto.
+    connect_ports: Option<&'a [u16]>,
+}
+
+impl<'a> Policies<'a> {
+    /// Las del proxy en marcha.
+    pub fn of(ctx: &'a ProxyContext) -> Self {
+        Self {
+            host_filter: ctx.host_filter(),
+            egress: ctx.egress(),
+            connect_ports: ctx.connect_ports(),
+        }
+    }
+
+    /// En el orden del proxy: filtro de hosts, reglas de salida y puertos de
+    /// `CONNECT`. No cuenta aplicaciones en las reglas vivas.
+    pub fn evaluate(&self, target: &Target) -> Decision {
+        let mut decision = Decision {
+            allowed: true,
+            rules: Vec::new(),
+        };
+        if let Some(filter) = self.host_filter {
+            let verdict = filter.check(&target.host);
+            let rule = verdict.rule().map(|rule| rule.to_string());
+            if rule.is_some() || !verdict.is_allowed() {
+                decision.hit(Policy::HostFilter.as_str(), rule, verdict.is_allowed());
+            }
+            if !decision.allowed {
+                return decision;
+            }
+        }
+        if let Some(egress) = self.egress {
+            let rule = egress.allowing(&target.host, target.port, target.path.as_deref());
+            decision.hit(
+                Policy::Egress.as_str(),
+                rule.map(|rule| rule.name().to_string()),
+                rule.is_some(),
+            );
+            if !decision.allowed {
+                return decision;
+            }
+        }
+        if let Some(ports) = self.connect_ports.filter(|_| target.is_connect()) {
+            if !ports.contains(&target.port) {
+                decision.hit(CONNECT_PORTS, None, false);
+            }
+        }
+        decision
+    }
+
+    pub fn evaluate_all(&self, targets: &[Target]) -> Vec<Decision> {
+        targets.iter().map(|target| self.evaluate(target)).collect()
+    }
+}
+
+/// Políticas construidas a partir de una configuración, sin proxy.
+pub struct PolicySet {
+    host_filter: Option<HostFilter>,
+    egress: Option<EgressPolicy>,
+    connect_ports: Vec<u16>,
+}
+
+impl PolicySet {
+    /// Como `ProxyServer::new`: las reglas de salida solo se aplican con
+    /// `egress_policy = "deny"`.
+    pub fn from_settings(settings: &ProxySettings) -> Self {
+        Self {
+            host_filter: settings.host_filter().cloned().map(HostFilter::new),
+            egress: (settings.egress().mode() == EgressMode::Deny)
+                .then(|| EgressPolicy::new(settings.egress().clone())),
+            connect_ports: settings.connect_ports().to_vec(),
+        }
+    }
+
+    pub fn policies(&self) -> Policies<'_> {
+        Policies {
+            host_filter: self.host_filter.as_ref(),
+            egress: self.egress.as_ref(),
+            connect_ports: Some(&self.connect_ports),
+        }
+    }
+}
+
+/// Veces que decidió una regla.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RuleCount {
+    #[serde(flatten)]
+    pub hit: RuleHit,
+    pub hits: u64,
+}
+
+/// Peticiones cuya decisión cambia respecto a la referencia.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub count: u64,
+    /// Hasta [`MAX_EXAMPLES`], en el orden de la entrada.
+    pub examples: Vec<Target>,
+}
+
+impl Change {
+    fn add(&mut self, target: &Target) {
+        self.count += 1;
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(target.clone());
+        }
+    }
+}
+
+/// Diferencias con una política de referencia.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Comparison {
+    /// La configuración de `--baseline` o `live`.
+    pub baseline: String,
+    pub newly_blocked: Change,
+    pub newly_allowed: Change,
+}
+
+impl Comparison {
+    /// `candidate` y `baseline` van en el orden de `targets`.
+    pub fn between(
+        baseline: impl Into<String>,
+        targets: &[Target],
+        candidate: &[Decision],
+        reference: &[Decision],
+    ) -> Self {
+        let mut comparison = Self {
+            baseline: baseline.into(),
+            newly_blocked: Change::default(),
+            newly_allowed: Change::default(),
+        };
+        for ((target, new), old) in targets.iter().zip(candidate).zip(reference) {
+            match (old.allowed, new.allowed) {
+                (true, false) => comparison.newly_blocked.add(target),
+                (false, true) => comparison.newly_allowed.add(target),
+                _ => {}
+            }
+        }
+        comparison
+    }
+}
+
+/// Informe de `policy simulate`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub requests: usize,
+    pub skipped: usize,
+    pub allowed: u64,
+    pub blocked: u64,
+    /// Ordenadas por política y regla.
+    pub rules: Vec<RuleCount>,
+    pub comparisons: Vec<Comparison>,
+}
+
+impl Report {
+    pub fn new(input: &Input, decisions: &[Decision]) -> Self {
+        let mut counts: BTreeMap<&RuleHit, u64> = BTreeMap::new();
+        for hit in decisions.iter().flat_map(|decision| &decision.rules) {
+            *counts.entry(hit).or_default() += 1;
+        }
+        let allowed = decisions.iter().filter(|d| d.allowed).count() as u64;
+        Self {
+            requests: input.targets.len(),
+            skipped: input.skipped,
+            allowed,
+            blocked: decisions.len() as u64 - allowed,
+            rules: counts
+                .into_iter()
+                .map(|(hit, hits)| RuleCount {
+                    hit: hit.clone(),
+                    hits,
+                })
+                .collect(),
+            comparisons: Vec::new(),
+        }
+    }
+
+    pub fn with_comparison(mut self, comparison: Comparison) -> Self {
+        self.comparisons.push(comparison);
+        self
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Peticiones: {} ({} permitidas, {} bloqueadas); omitidas: {}",
+            self.requests, self.allowed, self.blocked, self.skipped
+        )?;
+        writeln!(
+            f,
+            "{:<14} {:<40} {:<8} {:>8}",
+            "POLÍTICA", "REGLA", "DECISIÓN", "VECES"
+        )?;
+        for count in &self.rules {
+            writeln!(
+                f,
+                "{:<14} {:<40} {:<8} {:>8}",
+                count.hit.policy,
+                count.hit.rule.as_deref().unwrap_or("(ninguna regla)"),
+                if count.hit.allowed { "allow" } else { "block" },
+                count.hits
+            )?;
+        }
+        for comparison in &self.comparisons {
+            writeln!(f, "Frente a {}:", comparison.baseline)?;
+            for (label, change) in [
+                ("pasan a bloquearse", &comparison.newly_blocked),
+                ("pasan a permitirse", &comparison.newly_allowed),
+            ] {
+                writeln!(f, "  {} {label}", change.count)?;
+                for example in &change.examples {
+                    writeln!(f, "    {example}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct LiveDecisions {
+    decisions: Vec<Decision>,
+}
+
+/// Decisiones de la política que aplica el proxy con la API de
+/// administración en `admin`, por tandas de [`LIVE_BATCH`].
+pub async fn live_decisions(
+    admin: SocketAddr,
+    token: Option<&AdminToken>,
+    targets: &[Target],
+) -> anyhow::Result<Vec<Decision>> {
+    let client = Client::new();
+    let uri: Uri = format!("http://{admin}/api/policy/simulate").parse()?;
+    let mut decisions = Vec::with_capacity(targets.len());
+    for batch in targets.chunks(LIVE_BATCH) {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(uri.clone())
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token.expose()));
+        }
+        let request = request.body(Body::from(json!({ "targets": batch }).to_string()))?;
+        let response = client
+            .request(request)
+            .await
+            .with_context(|| format!("La API de administración en {admin} no responde"))?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if status != StatusCode::OK {
+            anyhow::bail!(
+                "La API de administración respondió {status}: {}",
+                String::from_utf8_lossy(&body)
+            );
+        }
+        let batch_decisions: LiveDecisions = serde_json::from_slice(&body)?;
+        anyhow::ensure!(
+            batch_decisions.decisions.len() == batch.len(),
+            "La API de administración devolvió {} decisiones para {} destinos",
+            batch_decisions.decisions.len(),
+            batch.len()
+        );
+        decisions.extend(batch_decisions.decisions);
+    }
+    Ok(decisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{EgressRule, EgressSettings, HostFilterSettings};
+
+    fn target(method: &str, host: &str, port: u16, path: Option<&str>) -> Target {
+        Target {
+            method: method.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_policies_are_evaluated_in_the_proxy_order() {
+        let filter = HostFilterSettings::default().with_deny("*.ads.test".parse().unwrap());
+        let egress = EgressSettings::default()
+            .with_mode(EgressMode::Deny)
+            .with_rule(
+                EgressRule::new("api.test".parse().unwrap(), None)
+                    .with_name("api")
+                    .with_path_prefix("/v1"),
+            )
+            .with_rule(EgressRule::new("*.test".parse().unwrap(), None).with_name("tunnels"));
+        let settings = ProxySettings::new("127.0.0.1:0".parse().unwrap())
+            .with_host_filter(filter)
+            .with_egress(egress);
+        let set = PolicySet::from_settings(&settings);
+        let policies = set.policies();
+
+        let blocked = policies.evaluate(&target("GET", "x.ads.test", 80, Some("/")));
+        assert!(!blocked.allowed);
+        assert_eq!(blocked.rules.len(), 1);
+        assert_eq!(blocked.rules[0].rule.as_deref(), Some("*.ads.test"));
+
+        let api = policies.evaluate(&target("GET", "api.test", 443, Some("/v1/chat")));
+        assert_eq!(api.rules[0].rule.as_deref(), Some("api"));
+        // Without a path only the rule without prefixes matches.
+        let logged = policies.evaluate(&target("GET", "api.test", 443, None));
+        assert_eq!(logged.rules[0].rule.as_deref(), Some("tunnels"));
+
+        let tunnel = policies.evaluate(&target("CONNECT", "db.test", 5432, None));
+        assert!(!tunnel.allowed);
+        assert_eq!(tunnel.rules.last().unwrap().policy, CONNECT_PORTS);
+        assert!(
+            !policies
+                .evaluate(&target("GET", "other.example", 80, None))
+                .allowed
+        );
+    }
+}
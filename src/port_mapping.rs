@@ -0,0 +1,671 @@
+//! Apertura del puerto del listener en el router.
+//!
+//! Con `--upnp` (o la sección `[upnp]`) el proxy busca al arrancar el router
+//! con UPnP IGD y, si ninguno contesta, prueba NAT-PMP en la puerta de
+//! enlace por defecto. Le pide que redirija un puerto externo al listener
+//! durante `lease_secs` y renueva la redirección a la mitad de lo concedido;
+//! al pararse limpiamente la borra. Ningún fallo impide arrancar: se avisa y
+//! el proxy sigue accesible solo desde la red local. La dirección externa se
+//! registra en el log y se muestra en `GET /api/port-mapping`. Un puerto
+//! abierto así queda expuesto a Internet, así que conviene combinarlo con
+//! `[auth]`.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::settings::PortMappingSettings;
+
+/// Grupo multicast de SSDP.
+const SSDP: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Servicios que redirigen puertos, por orden de preferencia.
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+const NATPMP_PORT: u16 = 5351;
+/// Espera a la primera respuesta NAT-PMP; se dobla en cada reintento.
+const NATPMP_FIRST_WAIT: Duration = Duration::from_millis(250);
+const NATPMP_ATTEMPTS: usize = 4;
+/// Espera tras un fallo antes de volver a pedir la redirección.
+const RETRY: Duration = Duration::from_secs(60);
+const DESCRIPTION: &str = "proxy-ia";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingProtocol {
+    Upnp,
+    NatPmp,
+}
+
+/// Redirección vigente, tal como la muestra `GET /api/port-mapping`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Mapping {
+    pub protocol: MappingProtocol,
+    /// Dirección pública con el puerto que abrió el router.
+    pub external: SocketAddr,
+    pub internal: SocketAddr,
+    /// Duración concedida, que puede ser menor que la pedida.
+    pub lease_secs: u64,
+    /// Segundos Unix de la última renovación.
+    pub renewed_at: u64,
+}
+
+/// Cuándo renovar una redirección concedida por `lease`.
+pub fn renew_after(lease: Duration) -> Duration {
+    lease / 2
+}
+
+/// Un router que sabe redirigir puertos.
+pub enum Gateway {
+    Igd(IgdGateway),
+    NatPmp(NatPmpGateway),
+}
+
+impl Gateway {
+    /// UPnP primero; NAT-PMP en la puerta de enlace por defecto si nadie
+    /// contesta a la búsqueda.
+    pub async fn discover() -> anyhow::Result<Self> {
+        match ssdp_search().await {
+            Ok(location) => match IgdGateway::from_location(location).await {
+                Ok(igd) => return Ok(Gateway::Igd(igd)),
+                Err(e) => debug!(error = %e, "Router UPnP inutilizable; se prueba NAT-PMP"),
+            },
+            Err(e) => debug!(error = %e, "Sin router UPnP; se prueba NAT-PMP"),
+        }
+        let gateway = default_gateway()?;
+        Ok(Gateway::NatPmp(NatPmpGateway::new(SocketAddr::new(
+            gateway.into(),
+            NATPMP_PORT,
+        ))))
+    }
+
+    pub fn protocol(&self) -> MappingProtocol {
+        match self {
+            Gateway::Igd(_) => MappingProtocol::Upnp,
+            Gateway::NatPmp(_) => MappingProtocol::NatPmp,
+        }
+    }
+
+    fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Gateway::Igd(igd) => igd.addr(),
+            Gateway::NatPmp(natpmp) => Some(natpmp.gateway),
+        }
+    }
+
+    /// Pide la redirección; devuelve la dirección externa y lo concedido.
+    async fn add(
+        &self,
+        internal: SocketAddr,
+        external_port: u16,
+        lease: Duration,
+    ) -> anyhow::Result<(SocketAddr, Duration)> {
+        match self {
+            Gateway::Igd(igd) => igd.add(internal, external_port, lease).await,
+            Gateway::NatPmp(natpmp) => natpmp.add(internal, external_port, lease).await,
+        }
+    }
+
+    async fn remove(&self, mapping: &Mapping) -> anyhow::Result<()> {
+        match self {
+            Gateway::Igd(igd) => igd.remove(mapping.external.port()).await,
+            Gateway::NatPmp(natpmp) => natpmp.remove(mapping.internal.port()).await,
+        }
+    }
+}
+
+/// Router UPnP IGD, controlado con SOAP sobre HTTP.
+pub struct IgdGateway {
+    control: Uri,
+    service: String,
+}
+
+impl IgdGateway {
+    /// Lee la descripción del router de `location`, la URL que anuncia en
+    /// SSDP.
+    pub async fn from_location(location: Uri) -> anyhow::Result<Self> {
+        let response = Client::new()
+            .get(location.clone())
+            .await
+            .with_context(|| format!("No se pudo leer la descripción UPnP de {location}"))?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let description = String::from_utf8_lossy(&body);
+        let Some((service, control)) = wan_service(&description) else {
+            bail!("El router de {location} no ofrece redirección de puertos");
+        };
+        let control = match control.parse::<Uri>() {
+            Ok(uri) if uri.scheme().is_some() => uri,
+            _ => {
+                let authority = location
+                    .authority()
+                    .context("URL de descripción sin host")?;
+                let path = control.trim_start_matches('/');
+                format!("http://{authority}/{path}").parse()?
+            }
+        };
+        Ok(Self { control, service })
+    }
+
+    fn addr(&self) -> Option<SocketAddr> {
+        let ip = self.control.host()?.parse::<IpAddr>().ok()?;
+        Some(SocketAddr::new(ip, self.control.port_u16().unwrap_or(80)))
+    }
+
+    async fn add(
+        &self,
+        internal: SocketAddr,
+        external_port: u16,
+        lease: Duration,
+    ) -> anyhow::Result<(SocketAddr, Duration)> {
+        self.soap(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", "TCP".to_string()),
+                ("NewInternalPort", internal.port().to_string()),
+                ("NewInternalClient", internal.ip().to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", DESCRIPTION.to_string()),
+                ("NewLeaseDuration", lease.as_secs().to_string()),
+            ],
+        )
+        .await?;
+        let response = self.soap("GetExternalIPAddress", &[]).await?;
+        let ip = element(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .context("El router no informó de su dirección externa")?;
+        Ok((SocketAddr::new(ip, external_port), lease))
+    }
+
+    async fn remove(&self, external_port: u16) -> anyhow::Result<()> {
+        self.soap(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", "TCP".to_string()),
+            ],
+        )
+        .await
+        .map(drop)
+    }
+
+    /// Llama a `action` y devuelve el cuerpo de la respuesta.
+    async fn soap(&self, action: &str, args: &[(&str, String)]) -> anyhow::Result<String> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+             </s:Envelope>",
+            service = self.service
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.control.clone())
+            .header(CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+            .header("soapaction", format!("\"{}#{action}\"", self.service))
+            .body(Body::from(body))?;
+        // Renewals are far apart; no pooled connection is worth keeping.
+        let response = Client::new()
+            .request(request)
+            .await
+            .with_context(|| format!("El router no contesta a {action}"))?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+        if status != StatusCode::OK {
+            let reason = element(&body, "errorDescription").unwrap_or("sin detalle");
+            bail!("El router rechazó {action} ({status}): {reason}");
+        }
+        Ok(body)
+    }
+}
+
+/// Router NAT-PMP (RFC 6886), que contesta por UDP en la puerta de enlace.
+pub struct NatPmpGateway {
+    gateway: SocketAddr,
+}
+
+impl NatPmpGateway {
+    pub fn new(gateway: SocketAddr) -> Self {
+        Self { gateway }
+    }
+
+    async fn add(
+        &self,
+        internal: SocketAddr,
+        external_port: u16,
+        lease: Duration,
+    ) -> anyhow::Result<(SocketAddr, Duration)> {
+        let address = self.request(&[0, 0], 12).await?;
+        let ip = Ipv4Addr::new(address[8], address[9], address[10], address[11]);
+        let mapped = self
+            .request(&map_request(internal.port(), external_port, lease), 16)
+            .await?;
+        let port = u16::from_be_bytes([mapped[10], mapped[11]]);
+        let granted = u32::from_be_bytes([mapped[12], mapped[13], mapped[14], mapped[15]]);
+        Ok((
+            SocketAddr::new(ip.into(), port),
+            Duration::from_secs(granted.into()),
+        ))
+    }
+
+    /// Una petición con duración y puerto externo a cero borra la
+    /// redirección del puerto interno.
+    async fn remove(&self, internal_port: u16) -> anyhow::Result<()> {
+        self.request(&map_request(internal_port, 0, Duration::ZERO), 16)
+            .await
+            .map(drop)
+    }
+
+    /// Envía `request` con reintentos y comprueba la cabecera de la
+    /// respuesta, que debe tener al menos `len` bytes.
+    async fn request(&self, request: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(self.gateway).await?;
+        let mut wait = NATPMP_FIRST_WAIT;
+        let mut buf = [0u8; 16];
+        for _ in 0..NATPMP_ATTEMPTS {
+            socket.send(request).await?;
+            if let Ok(received) = tokio::time::timeout(wait, socket.recv(&mut buf)).await {
+                let response = &buf[..received?];
+                if response.len() < len || response[0] != 0 || response[1] != request[1] + 128 {
+                    bail!("Respuesta NAT-PMP inválida de {}", self.gateway);
+                }
+                let result = u16::from_be_bytes([response[2], response[3]]);
+                if result != 0 {
+                    bail!("El router rechazó la petición NAT-PMP (código {result})");
+                }
+                return Ok(response.to_vec());
+            }
+            wait *= 2;
+        }
+        bail!("El router {} no contesta por NAT-PMP", self.gateway)
+    }
+}
+
+fn map_request(internal_port: u16, external_port: u16, lease: Duration) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    // Version 0, opcode 2: map TCP.
+    request[1] = 2;
+    request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    let lifetime = u32::try_from(lease.as_secs()).unwrap_or(u32::MAX);
+    request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+#[derive(Default)]
+struct Session {
+    gateway: Option<Gateway>,
+    /// La parada ya borró la redirección: no se vuelve a pedir.
+    closed: bool,
+}
+
+/// Mantiene la redirección del listener mientras el proxy está en marcha.
+pub struct PortMapper {
+    settings: PortMappingSettings,
+    session: tokio::sync::Mutex<Session>,
+    current: Mutex<Option<Mapping>>,
+}
+
+impl PortMapper {
+    pub fn new(settings: PortMappingSettings) -> Self {
+        Self {
+            settings,
+            session: tokio::sync::Mutex::default(),
+            current: Mutex::default(),
+        }
+    }
+
+    pub fn current(&self) -> Option<Mapping> {
+        self.current.lock().expect("lock de la redirección").clone()
+    }
+
+    /// Busca el router y mantiene la redirección hasta que se suelta la
+    /// tarea o la parada la borra.
+    pub async fn run(&self, listener: SocketAddr) {
+        match Gateway::discover().await {
+            Ok(gateway) => self.maintain(gateway, listener).await,
+            Err(e) => warn!(error = %e, "No se encontró un router al que pedir el puerto"),
+        }
+    }
+
+    /// Como [`PortMapper::run`], con un router ya encontrado.
+    pub async fn maintain(&self, gateway: Gateway, listener: SocketAddr) {
+        let internal = match internal_addr(&gateway, listener).await {
+            Ok(internal) => internal,
+            Err(e) => {
+                warn!(error = %e, "No se pudo saber con qué dirección ve el router al proxy");
+                return;
+            }
+        };
+        let external_port = self.settings.external_port().unwrap_or(listener.port());
+        let protocol = gateway.protocol();
+        self.session.lock().await.gateway = Some(gateway);
+        loop {
+            let session = self.session.lock().await;
+            let Some(gateway) = session.gateway.as_ref().filter(|_| !session.closed) else {
+                return;
+            };
+            let wait = match gateway
+                .add(internal, external_port, self.settings.lease())
+                .await
+            {
+                Ok((external, granted)) => {
+                    let mapping = Mapping {
+                        protocol,
+                        external,
+                        internal,
+                        lease_secs: granted.as_secs(),
+                        renewed_at: SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs(),
+                    };
+                    let previous = self
+                        .current
+                        .lock()
+                        .expect("lock de la redirección")
+                        .replace(mapping);
+                    if previous.is_none_or(|previous| previous.external != external) {
+                        info!(%external, %internal, ?protocol, lease_secs = granted.as_secs(), "Puerto abierto en el router");
+                    } else {
+                        debug!(%external, "Redirección del router renovada");
+                    }
+                    renew_after(granted).max(Duration::from_secs(1))
+                }
+                Err(e) => {
+                    warn!(error = %e, ?protocol, "No se pudo abrir el puerto en el router");
+                    RETRY
+                }
+            };
+            drop(session);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Borra la redirección; la llama la parada ordenada.
+    pub async fn remove(&self) {
+        let mut session = self.session.lock().await;
+        session.closed = true;
+        let mapping = self.current.lock().expect("lock de la redirección").take();
+        let (Some(gateway), Some(mapping)) = (session.gateway.as_ref(), mapping) else {
+            return;
+        };
+        match gateway.remove(&mapping).await {
+            Ok(()) => info!(external = %mapping.external, "Puerto del router cerrado"),
+            Err(e) => warn!(error = %e, "No se pudo cerrar el puerto en el router"),
+        }
+    }
+}
+
+/// La dirección del listener tal como la alcanza el router: con un
+/// listener en todas las interfaces, la de la interfaz que da al router.
+async fn internal_addr(gateway: &Gateway, listener: SocketAddr) -> anyhow::Result<SocketAddr> {
+    if !listener.ip().is_unspecified() {
+        return Ok(listener);
+    }
+    let towards = gateway
+        .addr()
+        .context("El router no tiene una IP literal")?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(towards).await?;
+    Ok(SocketAddr::new(socket.local_addr()?.ip(), listener.port()))
+}
+
+async fn ssdp_search() -> anyhow::Result<Uri> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {IGD_DEVICE}\r\n\r\n"
+    );
+    socket.send_to(request.as_bytes(), SSDP).await?;
+    let deadline = tokio::time::Instant::now() + DISCOVERY_TIMEOUT;
+    let mut buf = [0u8; 2048];
+    loop {
+        let (len, _) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf))
+            .await
+            .context("Ningún router contestó a la búsqueda UPnP")??;
+        if let Some(location) = ssdp_location(&buf[..len]) {
+            return Ok(location);
+        }
+    }
+}
+
+fn ssdp_location(response: &[u8]) -> Option<Uri> {
+    std::str::from_utf8(response)
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if !name.trim().eq_ignore_ascii_case("location") {
+                return None;
+            }
+            value.trim().parse().ok()
+        })
+}
+
+/// Tipo y URL de control del servicio preferido de la descripción.
+fn wan_service(description: &str) -> Option<(String, String)> {
+    let services: Vec<(&str, &str)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|block| {
+            Some((
+                element(block, "serviceType")?,
+                element(block, "controlURL")?,
+            ))
+        })
+        .collect();
+    WAN_SERVICES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(kind, _)| kind == wanted)
+            .map(|(kind, control)| (kind.to_string(), control.to_string()))
+    })
+}
+
+/// Texto del primer `<tag>…</tag>` de `xml`.
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{tag}>");
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(xml[start..end].trim())
+}
+
+fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route")
+        .context("No se pudo leer la tabla de rutas para buscar el router")?;
+    parse_default_gateway(&routes).context("No hay puerta de enlace por defecto")
+}
+
+/// La ruta con destino `00000000` de `/proc/net/route`.
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // The kernel prints addresses in host byte order.
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        (gateway != 0).then(|| Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+
+    use super::*;
+
+    const DESCRIPTION_XML: &str = "<root><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/ctl/L3F</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL></service>\
+        </serviceList></device></root>";
+
+    /// IGD that records the SOAP actions it receives.
+    async fn mock_igd() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let recorded = actions.clone();
+        let make = make_service_fn(move |_| {
+            let actions = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let actions = actions.clone();
+                    async move {
+                        if req.uri().path() == "/desc.xml" {
+                            return Ok::<_, Infallible>(Response::new(Body::from(DESCRIPTION_XML)));
+                        }
+                        let action = req.headers()["soapaction"].to_str().unwrap().to_string();
+                        let action = action
+                            .trim_matches('"')
+                            .rsplit('#')
+                            .next()
+                            .unwrap()
+                            .to_string();
+                        let body = match action.as_str() {
+                            "GetExternalIPAddress" => {
+                                "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>"
+                            }
+                            _ => "",
+                        };
+                        actions.lock().unwrap().push(action);
+                        Ok(Response::new(Body::from(body)))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, actions)
+    }
+
+    fn count(actions: &Mutex<Vec<String>>, action: &str) -> usize {
+        actions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|a| *a == action)
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_igd_mapping_is_renewed_at_half_lease_and_removed_on_shutdown() {
+        assert_eq!(
+            renew_after(Duration::from_secs(3600)),
+            Duration::from_secs(1800)
+        );
+        let (addr, actions) = mock_igd().await;
+        let location = format!("http://{addr}/desc.xml").parse().unwrap();
+        let igd = IgdGateway::from_location(location).await.unwrap();
+        assert_eq!(igd.control.path(), "/ctl/IPConn");
+
+        let settings = PortMappingSettings::default()
+            .with_external_port(18080)
+            .with_lease(Duration::from_secs(2));
+        let mapper = Arc::new(PortMapper::new(settings));
+        let task = {
+            let mapper = mapper.clone();
+            tokio::spawn(async move {
+                mapper
+                    .maintain(Gateway::Igd(igd), "192.168.1.20:8888".parse().unwrap())
+                    .await
+            })
+        };
+        while mapper.current().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mapping = mapper.current().unwrap();
+        assert_eq!(mapping.external, "203.0.113.7:18080".parse().unwrap());
+        assert_eq!(mapping.protocol, MappingProtocol::Upnp);
+        assert_eq!(count(&actions, "AddPortMapping"), 1);
+
+        tokio::time::sleep(Duration::from_millis(1300)).await;
+        assert_eq!(count(&actions, "AddPortMapping"), 2);
+
+        mapper.remove().await;
+        assert_eq!(actions.lock().unwrap().last().unwrap(), "DeletePortMapping");
+        assert_eq!(mapper.current(), None);
+        // The renewal loop stops instead of opening the port again.
+        tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(count(&actions, "AddPortMapping"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_natpmp_uses_the_granted_lease_and_removes_with_zero_lifetime() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = socket.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut lifetimes = Vec::new();
+            let mut buf = [0u8; 12];
+            while lifetimes.len() < 2 {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let mut response = vec![0, buf[1] + 128, 0, 0, 0, 0, 0, 1];
+                if buf[1] == 0 {
+                    response.extend([198, 51, 100, 4]);
+                } else {
+                    assert_eq!(len, 12);
+                    lifetimes.push(u32::from_be_bytes(buf[8..12].try_into().unwrap()));
+                    response.extend(&buf[4..6]);
+                    response.extend(40000u16.to_be_bytes());
+                    response.extend(120u32.min(lifetimes[lifetimes.len() - 1]).to_be_bytes());
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+            lifetimes
+        });
+
+        let natpmp = Gateway::NatPmp(NatPmpGateway::new(gateway));
+        let internal: SocketAddr = "192.168.1.20:8888".parse().unwrap();
+        let (external, granted) = natpmp
+            .add(internal, 8888, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(external, "198.51.100.4:40000".parse().unwrap());
+        // The router may grant less than asked; renewal follows it.
+        assert_eq!(renew_after(granted), Duration::from_secs(60));
+
+        let mapping = Mapping {
+            protocol: MappingProtocol::NatPmp,
+            external,
+            internal,
+            lease_secs: granted.as_secs(),
+            renewed_at: 0,
+        };
+        natpmp.remove(&mapping).await.unwrap();
+        assert_eq!(server.await.unwrap(), [3600, 0]);
+        assert_eq!(
+            parse_default_gateway(
+                "Iface\tDestination\tGateway\nwlan0\t0000A8C0\t00000000\nwlan0\t00000000\t0101A8C0\n"
+            ),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+    }
+}
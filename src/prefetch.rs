@@ -0,0 +1,433 @@
+//! Precarga de los recursos enlazados desde el HTML que entrega el proxy.
+//!
+//! Con `[prefetch]` (que requiere `[response_cache]`) el body de cada página
+//! HTML que llega del destino se lee según pasa hacia el cliente, como mucho
+//! `max_html_bytes`, y al terminar se buscan en él las hojas de estilo
+//! (`<link rel=stylesheet href>`), los scripts (`<script src>`) y las
+//! imágenes (`<img src>`). Los primeros `max_per_page` del mismo origen que
+//! la página (esquema, host y puerto) se piden en segundo plano por la cola
+//! `prefetch` del planificador, cuya simultaneidad es el tope global, para
+//! dejarlos en la caché de respuestas si sus cabeceras lo permiten.
+//!
+//! Solo se leen las páginas pedidas con `GET` sin `Authorization` ni
+//! `Cookie`, y las precargas salen sin credenciales del cliente: nunca se
+//! guarda una variante autenticada. Tampoco se precarga hacia los hosts con
+//! `[credentials]`. Las precargas no pasan por la autenticación, los cupos
+//! ni el log de acceso de los clientes; dejan su propio log con
+//! `prefetch = true`.
+
+use futures_util::TryStreamExt;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+
+use crate::settings::PrefetchSettings;
+
+/// Marca de las peticiones que lanza la precarga.
+#[derive(Debug, Clone, Copy)]
+pub struct Prefetch;
+
+pub struct Prefetcher {
+    settings: PrefetchSettings,
+}
+
+impl Prefetcher {
+    pub fn new(settings: PrefetchSettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn settings(&self) -> &PrefetchSettings {
+        &self.settings
+    }
+
+    /// Si la respuesta a `req` puede traer enlaces que precargar.
+    pub fn wants(req: &Request<Body>) -> bool {
+        let headers = req.headers();
+        req.method() == Method::GET
+            && !headers.contains_key(AUTHORIZATION)
+            && !headers.contains_key(COOKIE)
+            && req.extensions().get::<Prefetch>().is_none()
+    }
+
+    /// Copia lo que pasa de una página HTML hacia el cliente y, al soltarse
+    /// el body, entrega a `found` los enlaces de `page` que hay que
+    /// precargar. Las demás respuestas pasan intactas.
+    pub fn watch(
+        &self,
+        page: Uri,
+        response: Response<Body>,
+        found: impl FnOnce(Vec<Uri>) + Send + 'static,
+    ) -> Response<Body> {
+        if response.status() != StatusCode::OK || !is_html(&response) {
+            return response;
+        }
+        let mut seen = Seen {
+            html: Vec::new(),
+            limit: self.settings.max_html_bytes(),
+            max_links: self.settings.max_per_page(),
+            page,
+            found: Some(found),
+        };
+        response.map(|body| {
+            Body::wrap_stream(body.inspect_ok(move |chunk| {
+                let room = seen.limit - seen.html.len();
+                seen.html.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            }))
+        })
+    }
+}
+
+/// HTML leído de una página; al soltarse busca los enlaces.
+struct Seen<F: FnOnce(Vec<Uri>)> {
+    html: Vec<u8>,
+    limit: usize,
+    max_links: usize,
+    page: Uri,
+    found: Option<F>,
+}
+
+impl<F: FnOnce(Vec<Uri>)> Drop for Seen<F> {
+    fn drop(&mut self) {
+        let links = links(&self.html, &self.page, self.max_links);
+        if let Some(found) = self.found.take().filter(|_| !links.is_empty()) {
+            found(links);
+        }
+    }
+}
+
+fn is_html(response: &Response<Body>) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case("text/html"))
+}
+
+/// Hasta `max` recursos de `html` del mismo origen que `page`, sin repetir
+/// y en el orden en que aparecen.
+pub fn links(html: &[u8], page: &Uri, max: usize) -> Vec<Uri> {
+    let mut found: Vec<Uri> = Vec::new();
+    for tag in Tags::new(html) {
+        if found.len() >= max {
+            break;
+        }
+        let reference = match tag.name.as_str() {
+            "link"
+                if tag.attr("rel").is_some_and(|rel| {
+                    rel.split_ascii_whitespace()
+                        .any(|token| token.eq_ignore_ascii_case("stylesheet"))
+                }) =>
+            {
+                tag.attr("href")
+            }
+            "script" | "img" => tag.attr("src"),
+            _ => None,
+        };
+        let Some(uri) = reference.and_then(|reference| resolve(page, reference)) else {
+            continue;
+        };
+        if same_origin(page, &uri) && uri != *page && !found.contains(&uri) {
+            found.push(uri);
+        }
+    }
+    found
+}
+
+/// Resuelve `reference` contra `page` (RFC 3986 §5.2), sin el fragmento.
+/// `None` si no es una URL `http`/`https` o no se puede leer.
+fn resolve(page: &Uri, reference: &str) -> Option<Uri> {
+    let reference = reference.trim();
+    let reference = reference.split('#').next().unwrap_or_default();
+    if reference.is_empty() {
+        return None;
+    }
+    let scheme = page.scheme_str()?;
+    let authority = page.authority()?.as_str();
+    let resolved = if let Some(rest) = reference.strip_prefix("//") {
+        format!("{scheme}://{rest}")
+    } else if has_scheme(reference) {
+        reference.to_string()
+    } else if reference.starts_with('/') {
+        let (path, query) = split_query(reference);
+        format!("{scheme}://{authority}{}{query}", remove_dots(path))
+    } else if reference.starts_with('?') {
+        format!("{scheme}://{authority}{}{reference}", page.path())
+    } else {
+        let base = page.path();
+        let directory = &base[..base.rfind('/').map_or(0, |slash| slash + 1)];
+        let (path, query) = split_query(reference);
+        let joined = format!("{directory}{path}");
+        format!("{scheme}://{authority}{}{query}", remove_dots(&joined))
+    };
+    let uri: Uri = resolved.parse().ok()?;
+    matches!(uri.scheme_str(), Some("http" | "https")).then_some(uri)
+}
+
+fn has_scheme(reference: &str) -> bool {
+    let Some((scheme, _)) = reference.split_once(':') else {
+        return false;
+    };
+    let mut chars = scheme.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+fn split_query(reference: &str) -> (&str, &str) {
+    match reference.find('?') {
+        Some(at) => reference.split_at(at),
+        None => (reference, ""),
+    }
+}
+
+/// Quita los segmentos `.` y `..` de una ruta absoluta.
+fn remove_dots(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path.split('/').skip(1).peekable();
+    while let Some(segment) = parts.next() {
+        let last = parts.peek().is_none();
+        match segment {
+            "." | ".." => {
+                if segment == ".." {
+                    segments.pop();
+                }
+                if last {
+                    segments.push("");
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+fn same_origin(page: &Uri, uri: &Uri) -> bool {
+    let port = |uri: &Uri| {
+        uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        })
+    };
+    page.scheme() == uri.scheme()
+        && page
+            .host()
+            .zip(uri.host())
+            .is_some_and(|(a, b)| a.eq_ignore_ascii_case(b))
+        && port(page) == port(uri)
+}
+
+/// Etiqueta de apertura con sus atributos, nombres en minúsculas.
+struct Tag {
+    name: String,
+    attrs: Vec<(String, String)>,
+}
+
+impl Tag {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Etiquetas de apertura de un HTML, saltando comentarios y el contenido de
+/// `<script>` y `<style>`. Tolera HTML cortado: la última etiqueta a medias
+/// no se devuelve.
+struct Tags<'a> {
+    html: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Tags<'a> {
+    fn new(html: &'a [u8]) -> Self {
+        Self { html, at: 0 }
+    }
+
+    fn skip_past(&mut self, needle: &[u8]) {
+        let rest = &self.html[self.at..];
+        self.at = match find(rest, needle) {
+            Some(at) => self.at + at + needle.len(),
+            None => self.html.len(),
+        };
+    }
+}
+
+impl Iterator for Tags<'_> {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        loop {
+            let start = self.at + self.html[self.at..].iter().position(|b| *b == b'<')?;
+            self.at = start + 1;
+            if self.html[self.at..].starts_with(b"!--") {
+                self.skip_past(b"-->");
+                continue;
+            }
+            let name_len = self.html[self.at..]
+                .iter()
+                .take_while(|b| b.is_ascii_alphanumeric())
+                .count();
+            if name_len == 0 {
+                continue;
+            }
+            let name = String::from_utf8_lossy(&self.html[self.at..self.at + name_len])
+                .to_ascii_lowercase();
+            self.at += name_len;
+            let attrs = self.attributes()?;
+            // Script and style bodies are not markup.
+            if name == "script" {
+                self.skip_past(b"</script");
+            } else if name == "style" {
+                self.skip_past(b"</style");
+            }
+            return Some(Tag { name, attrs });
+        }
+    }
+}
+
+impl Tags<'_> {
+    /// Atributos hasta el `>` que cierra la etiqueta; `None` si no llega.
+    fn attributes(&mut self) -> Option<Vec<(String, String)>> {
+        let html = self.html;
+        let mut attrs = Vec::new();
+        loop {
+            while html
+                .get(self.at)
+                .is_some_and(|b| b.is_ascii_whitespace() || *b == b'/')
+            {
+                self.at += 1;
+            }
+            if *html.get(self.at)? == b'>' {
+                self.at += 1;
+                return Some(attrs);
+            }
+            let name_start = self.at;
+            while html
+                .get(self.at)
+                .is_some_and(|b| !b.is_ascii_whitespace() && !matches!(b, b'=' | b'>' | b'/'))
+            {
+                self.at += 1;
+            }
+            let name = String::from_utf8_lossy(&html[name_start..self.at]).to_ascii_lowercase();
+            while html.get(self.at).is_some_and(|b| b.is_ascii_whitespace()) {
+                self.at += 1;
+            }
+            let mut value = String::new();
+            if html.get(self.at) == Some(&b'=') {
+                self.at += 1;
+                while html.get(self.at).is_some_and(|b| b.is_ascii_whitespace()) {
+                    self.at += 1;
+                }
+                let (start, end) = match html.get(self.at)? {
+                    quote @ (b'"' | b'\'') => {
+                        let start = self.at + 1;
+                        let len = html[start..].iter().position(|b| b == quote)?;
+                        self.at = start + len + 1;
+                        (start, start + len)
+                    }
+                    _ => {
+                        let start = self.at;
+                        while html
+                            .get(self.at)
+                            .is_some_and(|b| !b.is_ascii_whitespace() && *b != b'>')
+                        {
+                            self.at += 1;
+                        }
+                        (start, self.at)
+                    }
+                };
+                value = String::from_utf8_lossy(&html[start..end]).replace("&amp;", "&");
+            }
+            if !name.is_empty() {
+                attrs.push((name, value));
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<!doctype html>
+<html>
+<head>
+  <LINK REL="stylesheet" HREF="/css/site.css">
+  <link rel="preload stylesheet" href=theme.css?v=2>
+  <link rel="icon" href="/favicon.ico">
+  <link rel="stylesheet" href="https://cdn.example/lib.css">
+  <!-- <script src="/comentado.js"></script> -->
+  <script src="../js/app.js#main" defer></script>
+  <script>document.write('<img src="/escrito.png">')</script>
+  <style>body { background: url("<img src='/estilo.png'>") }</style>
+</head>
+<body>
+  <img alt="logo" src='//portal.test/img/logo.png'>
+  <img src="http://portal.test:8080/otro-puerto.png">
+  <img src="https://portal.test/otro-esquema.png">
+  <img src="data:image/png;base64,AAAA">
+  <img src="/css/site.css">
+  <img src="fotos/a.jpg?x=1&amp;y=2">
+</body>
+</html>"#;
+
+    fn strings(links: Vec<Uri>) -> Vec<String> {
+        links.into_iter().map(|uri| uri.to_string()).collect()
+    }
+
+    #[test]
+    fn test_links_keeps_same_origin_resources_in_order() {
+        let page: Uri = "http://portal.test/docs/index.html".parse().unwrap();
+        assert_eq!(
+            strings(links(PAGE.as_bytes(), &page, 10)),
+            [
+                "http://portal.test/css/site.css",
+                "http://portal.test/docs/theme.css?v=2",
+                "http://portal.test/js/app.js",
+                "http://portal.test/img/logo.png",
+                "http://portal.test/docs/fotos/a.jpg?x=1&y=2",
+            ]
+        );
+        assert_eq!(
+            strings(links(PAGE.as_bytes(), &page, 2)),
+            [
+                "http://portal.test/css/site.css",
+                "http://portal.test/docs/theme.css?v=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_links_tolerates_cut_html() {
+        let page: Uri = "http://portal.test/".parse().unwrap();
+        let cut = &PAGE.as_bytes()[..PAGE.find("theme.css").unwrap()];
+        assert_eq!(
+            strings(links(cut, &page, 10)),
+            ["http://portal.test/css/site.css"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_follows_rfc3986() {
+        let page: Uri = "http://a.test/b/c/d?q".parse().unwrap();
+        let resolved = |reference: &str| resolve(&page, reference).map(|uri| uri.to_string());
+        assert_eq!(resolved("g").as_deref(), Some("http://a.test/b/c/g"));
+        assert_eq!(resolved("./g/").as_deref(), Some("http://a.test/b/c/g/"));
+        assert_eq!(resolved("../g").as_deref(), Some("http://a.test/b/g"));
+        assert_eq!(resolved("../..").as_deref(), Some("http://a.test/"));
+        assert_eq!(resolved("/./g").as_deref(), Some("http://a.test/g"));
+        assert_eq!(resolved("?y").as_deref(), Some("http://a.test/b/c/d?y"));
+        assert_eq!(resolved("//g.test/x").as_deref(), Some("http://g.test/x"));
+        assert_eq!(resolved("#s"), None);
+        assert_eq!(resolved("mailto:a@a.test"), None);
+        assert_eq!(resolved("javascript:void(0)"), None);
+    }
+}
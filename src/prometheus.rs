@@ -0,0 +1,362 @@
+//! Métricas en formato de texto de Prometheus.
+//!
+//! Con `metrics_listen` (o `--metrics-listen`) el proxy sirve `GET /metrics`
+//! en un listener propio, sin autenticación, pensado para loopback o una red
+//! de monitorización. Los valores salen de los contadores de [`Metrics`],
+//! los mismos que alimentan la API de administración, así que el scrape no
+//! añade trabajo en el camino de las peticiones. Sin dirección no se abre
+//! nada.
+
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tracing::{debug, error};
+
+use crate::concurrency::ConcurrencyLimit;
+use crate::connection::CloseReason;
+use crate::error::ProxyError;
+use crate::metrics::{Metrics, REQUEST_METHODS, STATUS_CLASSES};
+use crate::overload::ShedReason;
+use crate::response_cache::StaleReason;
+use crate::settings::ClientClass;
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Atiende el listener de métricas hasta que se suelta la tarea.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(error = %e, "Error aceptando conexión de métricas");
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(handle(&metrics, &req)) }
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                debug!(%remote_addr, error = %e, "Conexión de métricas terminó con error");
+            }
+        });
+    }
+}
+
+fn handle(metrics: &Metrics, req: &Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("Solo se sirve GET /metrics\n"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+    let mut response = Response::new(Body::from(render(metrics)));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_TEXT));
+    response
+}
+
+/// Texto de la exposición.
+pub fn render(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    header(
+        &mut out,
+        "proxy_requests_total",
+        "counter",
+        "Peticiones terminadas por método y clase de código.",
+    );
+    for method in REQUEST_METHODS {
+        for status in STATUS_CLASSES {
+            let count = metrics.finished_requests(method, status);
+            if count > 0 {
+                let _ = writeln!(
+                    out,
+                    "proxy_requests_total{{method=\"{method}\",status=\"{status}\"}} {count}"
+                );
+            }
+        }
+    }
+
+    let duration = metrics.request_duration();
+    header(
+        &mut out,
+        "proxy_request_duration_seconds",
+        "histogram",
+        "Duración de las peticiones; un CONNECT termina al abrir el túnel.",
+    );
+    let buckets = duration.snapshot();
+    for bucket in &buckets {
+        let _ = writeln!(
+            out,
+            "proxy_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+            bucket.le, bucket.count
+        );
+    }
+    let count = buckets.last().map_or(0, |bucket| bucket.count);
+    let _ = writeln!(out, "proxy_request_duration_seconds_sum {}", duration.sum());
+    let _ = writeln!(out, "proxy_request_duration_seconds_count {count}");
+
+    header(
+        &mut out,
+        "proxy_tunnels_active",
+        "gauge",
+        "Túneles CONNECT abiertos.",
+    );
+    let _ = writeln!(out, "proxy_tunnels_active {}", metrics.tunnels_open());
+
+    header(
+        &mut out,
+        "proxy_tunnels_terminated_total",
+        "counter",
+        "Túneles cerrados desde la API de administración.",
+    );
+    let _ = writeln!(
+        out,
+        "proxy_tunnels_terminated_total {}",
+        metrics.tunnels_terminated()
+    );
+
+    header(
+        &mut out,
+        "proxy_connections_terminated_total",
+        "counter",
+        "Conexiones HTTP y túneles interceptados cerrados desde la API de administración.",
+    );
+    let _ = writeln!(
+        out,
+        "proxy_connections_terminated_total {}",
+        metrics.connection_closes(CloseReason::Admin)
+    );
+
+    let (sent, received) = metrics.tunnel_bytes();
+    header(
+        &mut out,
+        "proxy_tunnel_bytes_total",
+        "counter",
+        "Bytes copiados por los túneles cerrados, por sentido.",
+    );
+    let _ = writeln!(
+        out,
+        "proxy_tunnel_bytes_total{{direction=\"client_to_upstream\"}} {sent}"
+    );
+    let _ = writeln!(
+        out,
+        "proxy_tunnel_bytes_total{{direction=\"upstream_to_client\"}} {received}"
+    );
+
+    header(
+        &mut out,
+        "proxy_upstream_errors_total",
+        "counter",
+        "Fallos hacia el destino por categoría.",
+    );
+    for kind in ProxyError::ALL {
+        let _ = writeln!(
+            out,
+            "proxy_upstream_errors_total{{category=\"{}\"}} {}",
+            kind.category(),
+            metrics.upstream_errors(kind)
+        );
+    }
+
+    header(
+        &mut out,
+        "proxy_upstream_retries_total",
+        "counter",
+        "Reenvíos de peticiones idempotentes tras un fallo del destino.",
+    );
+    let _ = writeln!(
+        out,
+        "proxy_upstream_retries_total {}",
+        metrics.upstream_retries()
+    );
+
+    header(
+        &mut out,
+        "proxy_upstream_connections_opened_total",
+        "counter",
+        "Conexiones abiertas por el pool de las peticiones HTTP hacia los destinos.",
+    );
+    let _ = writeln!(
+        out,
+        "proxy_upstream_connections_opened_total {}",
+        metrics.upstream_connections()
+    );
+
+    let (full, resumed) = metrics.tls_handshakes();
+    header(
+        &mut out,
+        "proxy_upstream_tls_handshakes_total",
+        "counter",
+        "Saludos TLS del pool con los destinos https://, completos o reanudados.",
+    );
+    let _ = writeln!(
+        out,
+        "proxy_upstream_tls_handshakes_total{{kind=\"full\"}} {full}"
+    );
+    let _ = writeln!(
+        out,
+        "proxy_upstream_tls_handshakes_total{{kind=\"resumed\"}} {resumed}"
+    );
+
+    let duration = metrics.tls_handshake_duration();
+    header(
+        &mut out,
+        "proxy_upstream_tls_handshake_duration_seconds",
+        "histogram",
+        "Duración de los saludos TLS del pool con los destinos.",
+    );
+    let buckets = duration.snapshot();
+    for bucket in &buckets {
+        let _ = writeln!(
+            out,
+            "proxy_upstream_tls_handshake_duration_seconds_bucket{{le=\"{}\"}} {}",
+            bucket.le, bucket.count
+        );
+    }
+    let count = buckets.last().map_or(0, |bucket| bucket.count);
+    let _ = writeln!(
+        out,
+        "proxy_upstream_tls_handshake_duration_seconds_sum {}",
+        duration.sum()
+    );
+    let _ = writeln!(
+        out,
+        "proxy_upstream_tls_handshake_duration_seconds_count {count}"
+    );
+
+    header(
+        &mut out,
+        "proxy_upstream_tls_requests_total",
+        "counter",
+        "Peticiones https:// enviadas por el pool, por conexión nueva o reutilizada.",
+    );
+    let _ = writeln!(
+        out,
+        "proxy_upstream_tls_requests_total {}",
+        metrics.tls_requests()
+    );
+
+    header(
+        &mut out,
+        "proxy_request_body_rejections_total",
+        "counter",
+        "Peticiones rechazadas con 413 por superar max_request_body.",
+    );
+    let _ = writeln!(
+        out,
+        "proxy_request_body_rejections_total {}",
+        metrics.request_body_rejections()
+    );
+
+    header(
+        &mut out,
+        "proxy_rate_limited_total",
+        "counter",
+        "Peticiones rechazadas con 429 por el cupo por IP.",
+    );
+    let _ = writeln!(out, "proxy_rate_limited_total {}", metrics.rate_limited());
+
+    header(
+        &mut out,
+        "proxy_response_cache_stale_served_total",
+        "counter",
+        "Copias caducadas servidas por la caché de respuestas, por motivo.",
+    );
+    for reason in StaleReason::ALL {
+        let _ = writeln!(
+            out,
+            "proxy_response_cache_stale_served_total{{reason=\"{}\"}} {}",
+            reason.as_str(),
+            metrics.stale_served(reason)
+        );
+    }
+
+    let (queued, dropped) = metrics.prefetches();
+    header(
+        &mut out,
+        "proxy_prefetch_total",
+        "counter",
+        "Precargas de recursos enlazados desde HTML, encoladas o descartadas.",
+    );
+    let _ = writeln!(out, "proxy_prefetch_total{{outcome=\"queued\"}} {queued}");
+    let _ = writeln!(out, "proxy_prefetch_total{{outcome=\"dropped\"}} {dropped}");
+    header(
+        &mut out,
+        "proxy_prefetch_hits_total",
+        "counter",
+        "Recursos precargados que luego se sirvieron desde la caché.",
+    );
+    let _ = writeln!(out, "proxy_prefetch_hits_total {}", metrics.prefetch_hits());
+
+    header(
+        &mut out,
+        "proxy_concurrency_rejected_total",
+        "counter",
+        "Peticiones y túneles rechazados por max_connections o max_tunnels.",
+    );
+    for limit in ConcurrencyLimit::ALL {
+        let _ = writeln!(
+            out,
+            "proxy_concurrency_rejected_total{{limit=\"{}\"}} {}",
+            limit.as_str(),
+            metrics.concurrency_rejected(limit)
+        );
+    }
+
+    header(
+        &mut out,
+        "proxy_overload_shed_total",
+        "counter",
+        "Peticiones descartadas por sobrecarga, por motivo y clase de cliente.",
+    );
+    for reason in ShedReason::ALL {
+        for class in ClientClass::ALL {
+            let _ = writeln!(
+                out,
+                "proxy_overload_shed_total{{reason=\"{}\",class=\"{}\"}} {}",
+                reason.as_str(),
+                class.as_str(),
+                metrics.overload_shed(reason, class)
+            );
+        }
+    }
+
+    let retry_after = metrics.overload_retry_after();
+    header(
+        &mut out,
+        "proxy_overload_retry_after_seconds",
+        "histogram",
+        "Retry-After enviado con los descartes por sobrecarga.",
+    );
+    let buckets = retry_after.snapshot();
+    for bucket in &buckets {
+        let _ = writeln!(
+            out,
+            "proxy_overload_retry_after_seconds_bucket{{le=\"{}\"}} {}",
+            bucket.le, bucket.count
+        );
+    }
+    let count = buckets.last().map_or(0, |bucket| bucket.count);
+    let _ = writeln!(
+        out,
+        "proxy_overload_retry_after_seconds_sum {}",
+        retry_after.sum()
+    );
+    let _ = writeln!(out, "proxy_overload_retry_after_seconds_count {count}");
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
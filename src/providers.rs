@@ -0,0 +1,232 @@
+//! Claves de los proveedores de IA que los clientes nunca tienen.
+//!
+//! Cada entrada de `[[providers]]` asocia un patrón de host (`api.openai.com`)
+//! con una clave que sale de una variable de entorno o de un archivo. En las
+//! peticiones HTTP hacia ese host el proxy quita el `Authorization` y el
+//! `x-api-key` que mande el cliente y pone la clave configurada, así que un
+//! portátil puede usar la API sin guardar el secreto. Las claves de archivo
+//! se releen cuando el archivo cambia, para rotarlas sin reiniciar; si la
+//! nueva versión no se puede leer se sigue con la anterior. La clave nunca
+//! aparece en los logs: el valor se marca como sensible y los volcados de
+//! cabeceras lo redactan.
+
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use hyper::HeaderMap;
+use tracing::{debug, info, warn};
+
+use crate::host_pattern::HostPattern;
+use crate::settings::{KeySource, ProviderSettings};
+
+/// Cabeceras de clave que el cliente no puede mandar a un proveedor.
+const CLIENT_KEY_HEADERS: [HeaderName; 2] = [AUTHORIZATION, HeaderName::from_static("x-api-key")];
+
+struct Provider {
+    host: HostPattern,
+    header: HeaderName,
+    source: KeySource,
+    value: RwLock<HeaderValue>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Provider {
+    /// Valor de la cabecera para `key`; el error nunca incluye la clave.
+    fn value(&self, key: &str) -> anyhow::Result<HeaderValue> {
+        header_value(&self.host, &self.header, key)
+    }
+
+    fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let KeySource::File(path) = &self.source else {
+            return Ok(false);
+        };
+        let current = file_modified(path);
+        if current == *self.modified.lock().expect("lock de mtime") {
+            return Ok(false);
+        }
+        // Remembered even on failure, so a broken file is reported once.
+        *self.modified.lock().expect("lock de mtime") = current;
+        let value = self.value(&read_key(path)?)?;
+        *self.value.write().expect("lock de la clave") = value;
+        info!(host = %self.host, path = %path.display(), "Clave del proveedor recargada");
+        Ok(true)
+    }
+}
+
+fn header_value(host: &HostPattern, header: &HeaderName, key: &str) -> anyhow::Result<HeaderValue> {
+    let value = if header == AUTHORIZATION {
+        format!("Bearer {key}")
+    } else {
+        key.to_string()
+    };
+    let mut value = HeaderValue::from_str(&value).map_err(|_| {
+        anyhow::anyhow!("la clave del proveedor {host} no es un valor de cabecera válido")
+    })?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+fn read_key(path: &Path) -> anyhow::Result<String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("no se pudo leer {}: {e}", path.display()))?;
+    let key = text.trim();
+    anyhow::ensure!(!key.is_empty(), "{} está vacío", path.display());
+    Ok(key.to_string())
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub struct ProviderKeys {
+    providers: Vec<Provider>,
+}
+
+impl ProviderKeys {
+    /// Lee las claves de `env` y de sus archivos; falla si falta alguna.
+    pub fn new(
+        settings: &[ProviderSettings],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<Self> {
+        let mut providers = Vec::with_capacity(settings.len());
+        for provider in settings {
+            let (key, modified) = match provider.key() {
+                KeySource::Env(name) => {
+                    let key = env(name).filter(|key| !key.trim().is_empty());
+                    let key = key.ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "la variable de entorno {name} del proveedor {} no está definida",
+                            provider.host()
+                        )
+                    })?;
+                    (key.trim().to_string(), None)
+                }
+                KeySource::File(path) => (read_key(path)?, file_modified(path)),
+            };
+            providers.push(Provider {
+                host: provider.host().clone(),
+                header: provider.header().clone(),
+                source: provider.key().clone(),
+                value: RwLock::new(header_value(provider.host(), provider.header(), &key)?),
+                modified: Mutex::new(modified),
+            });
+        }
+        Ok(Self { providers })
+    }
+
+    /// Si alguna clave sale de un archivo que hay que vigilar.
+    pub fn watches_files(&self) -> bool {
+        self.providers
+            .iter()
+            .any(|provider| matches!(provider.source, KeySource::File(_)))
+    }
+
+    /// Relee las claves cuyos archivos cambiaron.
+    pub fn reload_if_changed(&self) {
+        for provider in &self.providers {
+            if let Err(e) = provider.reload_if_changed() {
+                warn!(host = %provider.host, error = %e, "No se pudo recargar la clave del proveedor; se mantiene la anterior");
+            }
+        }
+    }
+
+    /// Pone la clave del primer proveedor de `host` en `headers`, quitando
+    /// antes las del cliente. Devuelve si `host` es de algún proveedor.
+    pub fn apply(&self, host: &str, headers: &mut HeaderMap) -> bool {
+        let Some(provider) = self
+            .providers
+            .iter()
+            .find(|provider| provider.host.matches(host))
+        else {
+            return false;
+        };
+        for name in CLIENT_KEY_HEADERS.iter().chain([&provider.header]) {
+            headers.remove(name);
+        }
+        let value = provider.value.read().expect("lock de la clave").clone();
+        headers.insert(provider.header.clone(), value);
+        debug!(%host, header = %provider.header, "Clave del proveedor inyectada");
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replaces_client_keys_and_rereads_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("anthropic.key");
+        std::fs::write(&path, "sk-ant-1\n").unwrap();
+        let settings = [
+            ProviderSettings::new(
+                "api.openai.test".parse().unwrap(),
+                KeySource::Env("OPENAI_KEY".into()),
+            ),
+            ProviderSettings::new(
+                "*.anthropic.test".parse().unwrap(),
+                KeySource::File(path.clone()),
+            )
+            .with_header(HeaderName::from_static("x-api-key")),
+        ];
+        let keys = ProviderKeys::new(&settings, |name| {
+            (name == "OPENAI_KEY").then(|| "sk-oa".to_string())
+        })
+        .unwrap();
+        assert!(keys.watches_files());
+
+        let client_headers = || {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, "Bearer del-cliente".parse().unwrap());
+            headers.insert("x-api-key", "del-cliente".parse().unwrap());
+            headers
+        };
+        let mut headers = client_headers();
+        assert!(keys.apply("api.openai.test", &mut headers));
+        assert_eq!(headers[AUTHORIZATION], "Bearer sk-oa");
+        assert!(!headers.contains_key("x-api-key"));
+        // Header dumps never show it.
+        assert!(headers[AUTHORIZATION].is_sensitive());
+        assert!(!format!("{headers:?}").contains("sk-oa"));
+
+        let mut headers = client_headers();
+        assert!(keys.apply("api.anthropic.test", &mut headers));
+        assert_eq!(headers["x-api-key"], "sk-ant-1");
+        assert!(!headers.contains_key(AUTHORIZATION));
+
+        let mut headers = client_headers();
+        assert!(!keys.apply("example.test", &mut headers));
+        assert_eq!(headers, client_headers());
+
+        // A rotated file is picked up; an emptied one keeps the last key.
+        std::fs::write(&path, "sk-ant-2").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        keys.reload_if_changed();
+        let mut headers = HeaderMap::new();
+        keys.apply("api.anthropic.test", &mut headers);
+        assert_eq!(headers["x-api-key"], "sk-ant-2");
+        std::fs::write(&path, "").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later + std::time::Duration::from_secs(5))
+            .unwrap();
+        keys.reload_if_changed();
+        let mut headers = HeaderMap::new();
+        keys.apply("api.anthropic.test", &mut headers);
+        assert_eq!(headers["x-api-key"], "sk-ant-2");
+
+        let missing = ProviderKeys::new(&settings[..1], |_| None).err().unwrap();
+        assert!(missing.to_string().contains("OPENAI_KEY"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Autenticación de clientes con `Proxy-Authorization: Basic`.
+//!
+//! Con `[auth]` el proxy no reenvía nada, ni peticiones HTTP ni túneles
+//! `CONNECT`, sin credenciales de un usuario configurado: responde `407`
+//! con `Proxy-Authenticate: Basic realm="..."` para que el cliente las pida.
+//! Una contraseña incorrecta cuenta como infracción para el baneo; la
+//! ausencia de credenciales no, porque es el primer paso normal de
+//! cualquier navegador. La cabecera no llega nunca al destino:
+//! `sanitize_headers` la quita con las demás de salto a salto.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderMap, HeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use hyper::{Body, Response, StatusCode};
+
+use crate::settings::AuthSettings;
+
+/// Motivo por el que se pide autenticación.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// Sin `Proxy-Authorization`, o con otro esquema que Basic.
+    Missing,
+    /// Usuario desconocido, contraseña incorrecta o credenciales mal
+    /// codificadas.
+    Invalid,
+}
+
+#[derive(Debug)]
+pub struct ProxyAuth {
+    settings: AuthSettings,
+    challenge: HeaderValue,
+}
+
+impl ProxyAuth {
+    pub fn new(settings: AuthSettings) -> anyhow::Result<Self> {
+        let challenge = HeaderValue::from_str(&format!("Basic realm=\"{}\"", settings.realm()))
+            .map_err(|_| anyhow::anyhow!("realm de [auth] inválido: {}", settings.realm()))?;
+        Ok(Self {
+            settings,
+            challenge,
+        })
+    }
+
+    /// Usuario de las credenciales de `headers`.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<&str, AuthError> {
+        let value = headers
+            .get(PROXY_AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(AuthError::Missing)?;
+        let (scheme, encoded) = value.trim().split_once(' ').ok_or(AuthError::Missing)?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return Err(AuthError::Missing);
+        }
+        let decoded = STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| AuthError::Invalid)?;
+        let colon = decoded
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or(AuthError::Invalid)?;
+        let (user, password) = (&decoded[..colon], &decoded[colon + 1..]);
+        self.settings
+            .users()
+            .iter()
+            .find(|(name, _)| name.as_bytes() == user)
+            .filter(|(_, expected)| expected.matches(password))
+            .map(|(name, _)| name.as_str())
+            .ok_or(AuthError::Invalid)
+    }
+
+    /// 407 que pide credenciales Basic.
+    pub fn challenge(&self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header(PROXY_AUTHENTICATE, self.challenge.clone())
+            .body(Body::from("Se requiere autenticación en el proxy"))
+            .expect("respuesta 407")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = format!("Basic {}", STANDARD.encode(credentials));
+        headers.insert(PROXY_AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_authenticate_checks_user_and_password() {
+        let auth = ProxyAuth::new(
+            AuthSettings::default()
+                .with_user("ana", "s3cr:eto")
+                .with_user("luis", "otra"),
+        )
+        .unwrap();
+        assert_eq!(auth.authenticate(&basic("ana:s3cr:eto")), Ok("ana"));
+        assert_eq!(auth.authenticate(&basic("luis:otra")), Ok("luis"));
+        assert_eq!(
+            auth.authenticate(&basic("ana:otra")),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authenticate(&basic("nadie:otra")),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authenticate(&HeaderMap::new()),
+            Err(AuthError::Missing)
+        );
+        let mut bearer = HeaderMap::new();
+        bearer.insert(PROXY_AUTHORIZATION, "Bearer abc".parse().unwrap());
+        assert_eq!(auth.authenticate(&bearer), Err(AuthError::Missing));
+
+        let challenge = auth.challenge();
+        assert_eq!(
+            challenge.status(),
+            StatusCode::PROXY_AUTHENTICATION_REQUIRED
+        );
+        assert_eq!(
+            challenge.headers()[PROXY_AUTHENTICATE],
+            "Basic realm=\"proxy-ia\""
+        );
+    }
+}
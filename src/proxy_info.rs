@@ -0,0 +1,79 @@
+//! `GET /proxy-info`: lo que el proxy ve de la conexión del propio cliente.
+//!
+//! Soporte suele necesitar saber con qué habla un cliente ("¿llega siquiera
+//! por HTTP/2?"). Una petición en forma de origen a `/proxy-info` (la que
+//! hace un navegador al abrir `http://proxy:puerto/proxy-info`) no se
+//! reenvía; en HTTP/2, donde toda petición lleva `:authority`, vale
+//! cualquier nombre con el puerto del propio listener. El proxy responde con
+//! la versión HTTP, el protocolo detectado en el puerto, la dirección del
+//! cliente, su identidad y las features que se le aplicarían. Solo describe
+//! la conexión que pregunta. Se desactiva con `proxy_info = false`.
+
+use std::net::SocketAddr;
+
+use hyper::{Body, Method, Request, Response, StatusCode, Version};
+use serde_json::json;
+
+use crate::admin::json_response;
+use crate::connection::LocalAddr;
+use crate::identity::Identity;
+
+pub const PATH: &str = "/proxy-info";
+
+/// Indica si `req` pide el informe en lugar de un destino.
+pub fn is_request(req: &Request<Body>) -> bool {
+    if req.method() != Method::GET || req.uri().path() != PATH {
+        return false;
+    }
+    let Some(authority) = req.uri().authority() else {
+        return true;
+    };
+    req.version() == Version::HTTP_2
+        && req
+            .extensions()
+            .get::<LocalAddr>()
+            .is_some_and(|local| authority.port_u16() == Some(local.0.port()))
+}
+
+fn version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "desconocida",
+    }
+}
+
+/// Informe en JSON; `protocol` es el detectado en el puerto y `features`
+/// las que se aplicarían a las peticiones de este cliente.
+pub fn respond(
+    req: &Request<Body>,
+    remote_addr: SocketAddr,
+    protocol: &str,
+    features: &[&str],
+) -> Response<Body> {
+    let identity = req.extensions().get::<Identity>().map(|identity| {
+        json!({
+            "user": identity.user(),
+            "profile": identity.profile(),
+        })
+    });
+    json_response(
+        StatusCode::OK,
+        json!({
+            "http_version": version(req.version()),
+            "protocol": protocol,
+            // Session details are not kept per request; `protocol` already
+            // says whether the connection is TLS.
+            "tls": null,
+            "client": {
+                "ip": remote_addr.ip().to_string(),
+                "port": remote_addr.port(),
+            },
+            "identity": identity,
+            "features": features,
+        }),
+    )
+}
@@ -0,0 +1,235 @@
+//! Cupo de peticiones por IP de cliente.
+//!
+//! Con `--rate-limit` cada IP tiene un token bucket propio: gasta un token
+//! por petición (un `CONNECT` también cuenta) y recupera `per_sec` por
+//! segundo hasta `burst`. Sin tokens, la petición recibe un 429 con
+//! `Retry-After` antes de que se reenvíe nada. Los buckets se comparten entre
+//! todas las conexiones; los que se han vuelto a llenar equivalen a no tener
+//! bucket y se olvidan, así que la memoria solo crece con las IPs que están
+//! gastando su cupo, hasta [`MAX_TRACKED`].
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::log_throttle::LogThrottle;
+use crate::settings::RateLimit;
+
+/// IPs con bucket propio como mucho; al llegar, se olvidan las que tienen
+/// el bucket lleno y, si aun así no caben, la octava parte que lleva más
+/// tiempo sin pedir nada.
+pub const MAX_TRACKED: usize = 65_536;
+
+/// Cada cuánto se olvidan los buckets llenos.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Token bucket de una IP.
+pub(crate) struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub(crate) fn full(burst: u32, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(burst),
+            updated: now,
+        }
+    }
+
+    pub(crate) fn refill(&mut self, per_sec: f64, burst: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(f64::from(burst));
+        self.updated = now;
+    }
+
+    pub(crate) fn is_full(&self, burst: u32) -> bool {
+        self.tokens >= f64::from(burst)
+    }
+
+    /// Gasta un token si lo hay.
+    pub(crate) fn take(&mut self) -> bool {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Estado del limitador, tal como lo muestra `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RateLimitSnapshot {
+    pub per_sec: f64,
+    pub burst: u32,
+    /// IPs con el bucket a medio gastar.
+    pub tracked_clients: usize,
+    pub rejected: u64,
+}
+
+/// Buckets de todas las IPs, compartidos entre conexiones.
+pub struct RateLimiter {
+    rate: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    rejected: AtomicU64,
+    rejection_log: LogThrottle,
+}
+
+impl RateLimiter {
+    pub fn new(rate: RateLimit) -> Self {
+        Self {
+            rate,
+            buckets: Mutex::default(),
+            rejected: AtomicU64::new(0),
+            rejection_log: LogThrottle::new(Duration::from_secs(10)),
+        }
+    }
+
+    pub fn rate(&self) -> RateLimit {
+        self.rate
+    }
+
+    /// Limita el log de los rechazos: un cliente que insiste generaría uno
+    /// por petición.
+    pub fn rejection_log(&self) -> &LogThrottle {
+        &self.rejection_log
+    }
+
+    /// Gasta un token de `ip`; sin tokens, devuelve cuánto falta para el
+    /// siguiente.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let (per_sec, burst) = (self.rate.per_sec(), self.rate.burst());
+        let mut buckets = self.buckets.lock().expect("lock de cupos");
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&ip) {
+            self.sweep_locked(&mut buckets, now);
+            if buckets.len() >= MAX_TRACKED {
+                // Everyone is spending: forget the eighth idle the longest,
+                // so the scan is paid once every few thousand new clients.
+                let mut updated: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+                let (_, cut, _) = updated.select_nth_unstable(MAX_TRACKED / 8);
+                let cut = *cut;
+                buckets.retain(|_, bucket| bucket.updated > cut);
+            }
+        }
+        let bucket = buckets
+            .entry(ip)
+            .or_insert_with(|| Bucket::full(burst, now));
+        bucket.refill(per_sec, burst, now);
+        if bucket.take() {
+            return Ok(());
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+    }
+
+    /// Olvida los buckets que se han vuelto a llenar.
+    pub fn sweep(&self) {
+        let mut buckets = self.buckets.lock().expect("lock de cupos");
+        self.sweep_locked(&mut buckets, Instant::now());
+    }
+
+    fn sweep_locked(&self, buckets: &mut HashMap<IpAddr, Bucket>, now: Instant) {
+        let (per_sec, burst) = (self.rate.per_sec(), self.rate.burst());
+        buckets.retain(|_, bucket| {
+            bucket.refill(per_sec, burst, now);
+            !bucket.is_full(burst)
+        });
+    }
+
+    /// Barre los buckets llenos cada [`SWEEP_INTERVAL`].
+    pub async fn run(self: std::sync::Arc<Self>) {
+        // Ends once a reload replaces the limiter and no request holds it.
+        let limiter = std::sync::Arc::downgrade(&self);
+        drop(self);
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match limiter.upgrade() {
+                Some(limiter) => limiter.sweep(),
+                None => return,
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> RateLimitSnapshot {
+        RateLimitSnapshot {
+            per_sec: self.rate.per_sec(),
+            burst: self.rate.burst(),
+            tracked_clients: self.buckets.lock().expect("lock de cupos").len(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 429 para un cliente sin cupo; `Retry-After` en segundos enteros, como
+/// mínimo 1.
+pub fn too_many_requests(retry_after: Duration) -> Response<Body> {
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = Response::new(Body::from(format!(
+        "Demasiadas peticiones desde esta IP; reintenta en {secs} s\n"
+    )));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    headers.insert("x-proxy-error", HeaderValue::from_static("rate_limited"));
+    headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills_at_the_rate_and_full_buckets_are_forgotten() {
+        let limiter = RateLimiter::new(RateLimit::new(4.0, 2));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let t0 = Instant::now();
+        assert!(limiter.check_at(ip, t0).is_ok());
+        assert!(limiter.check_at(ip, t0).is_ok());
+        let wait = limiter.check_at(ip, t0).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(250));
+        // A quarter of a second brings back exactly one token.
+        let t1 = t0 + Duration::from_millis(250);
+        assert!(limiter.check_at(ip, t1).is_ok());
+        assert!(limiter.check_at(ip, t1).is_err());
+        assert_eq!(limiter.snapshot().rejected, 2);
+
+        let mut buckets = limiter.buckets.lock().unwrap();
+        limiter.sweep_locked(&mut buckets, t1 + Duration::from_millis(100));
+        assert_eq!(buckets.len(), 1);
+        limiter.sweep_locked(&mut buckets, t1 + Duration::from_millis(500));
+        assert!(buckets.is_empty());
+    }
+
+    #[test]
+    fn test_client_churn_never_grows_past_the_cap() {
+        let limiter = RateLimiter::new(RateLimit::new(0.001, 1));
+        let now = Instant::now();
+        for n in 0..(MAX_TRACKED as u32 * 2) {
+            let ip = IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + n));
+            let at = now + Duration::from_micros(u64::from(n));
+            assert!(limiter.check_at(ip, at).is_ok());
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_TRACKED);
+        }
+        // The newest clients are the ones still tracked.
+        let newest = IpAddr::from(std::net::Ipv4Addr::from(
+            0x0a00_0000 + MAX_TRACKED as u32 * 2 - 1,
+        ));
+        assert!(limiter.buckets.lock().unwrap().contains_key(&newest));
+    }
+}
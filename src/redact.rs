@@ -0,0 +1,184 @@
+//! Reglas de redacción de secretos para todo lo que el proxy guarda o expone
+//! fuera del propio tráfico.
+
+use hyper::{HeaderMap, Uri};
+
+pub const REDACTED: &str = "[redacted]";
+
+const SENSITIVE_HEADERS: [&str; 7] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "api-key",
+    "x-auth-token",
+];
+
+const SENSITIVE_PARAMS: [&str; 9] = [
+    "token",
+    "access_token",
+    "api_key",
+    "apikey",
+    "key",
+    "password",
+    "secret",
+    "signature",
+    "sig",
+];
+
+pub fn is_sensitive_header(name: &str) -> bool {
+    SENSITIVE_HEADERS
+        .iter()
+        .any(|sensitive| sensitive.eq_ignore_ascii_case(name))
+}
+
+/// Copia las cabeceras reemplazando el valor de las sensibles, por nombre o
+/// porque el proxy las marcó así al inyectarlas.
+pub fn redact_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_sensitive_header(name.as_str()) || value.is_sensitive() {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.as_str().to_string(), value)
+        })
+        .collect()
+}
+
+/// Devuelve la URL sin credenciales de usuario y con los parámetros de query
+/// sensibles redactados.
+pub fn redact_url(uri: &Uri) -> String {
+    let mut out = String::new();
+    if let Some(scheme) = uri.scheme_str() {
+        out.push_str(scheme);
+        out.push_str("://");
+    }
+    if let Some(authority) = uri.authority() {
+        let authority = authority.as_str();
+        match authority.rsplit_once('@') {
+            Some((_, host)) => {
+                out.push_str(REDACTED);
+                out.push('@');
+                out.push_str(host);
+            }
+            None => out.push_str(authority),
+        }
+    }
+    out.push_str(uri.path());
+    if let Some(query) = uri.query() {
+        out.push('?');
+        let params: Vec<String> = query
+            .split('&')
+            .map(|param| match param.split_once('=') {
+                Some((name, _))
+                    if SENSITIVE_PARAMS
+                        .iter()
+                        .any(|sensitive| sensitive.eq_ignore_ascii_case(name)) =>
+                {
+                    format!("{name}={REDACTED}")
+                }
+                _ => param.to_string(),
+            })
+            .collect();
+        out.push_str(&params.join("&"));
+    }
+    out
+}
+
+/// Redacta los valores de parámetros sensibles en texto libre (`token=...`,
+/// `"password": "..."`) y los tokens `Bearer`. Pensado para registrar bodies
+/// de error sin filtrar secretos; no entiende el formato del texto.
+pub fn redact_text(text: &str) -> String {
+    // ASCII lowercasing keeps byte offsets, so matches index into `text`.
+    let lower = text.to_ascii_lowercase();
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while i < lower.len() {
+        match secret_at(lower.as_bytes(), i) {
+            Some((start, end)) => {
+                out.push_str(&text[copied..start]);
+                out.push_str(REDACTED);
+                copied = end;
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+    out.push_str(&text[copied..]);
+    out
+}
+
+/// Value span of a secret whose name starts at `i`, if any.
+fn secret_at(lower: &[u8], i: usize) -> Option<(usize, usize)> {
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'-';
+    if i > 0 && is_word(lower[i - 1]) {
+        return None;
+    }
+    let rest = &lower[i..];
+    let skip = |mut j: usize, set: &[u8]| {
+        while j < lower.len() && set.contains(&lower[j]) {
+            j += 1;
+        }
+        j
+    };
+    let start = if rest.starts_with(b"bearer ") {
+        skip(i + "bearer ".len(), b" ")
+    } else {
+        let name = SENSITIVE_PARAMS
+            .iter()
+            .find(|name| rest.starts_with(name.as_bytes()))?;
+        let j = skip(i + name.len(), b"\"' ");
+        if !matches!(lower.get(j), Some(b'=' | b':')) {
+            return None;
+        }
+        skip(j + 1, b"\"' ")
+    };
+    let end = lower[start..]
+        .iter()
+        .position(|b| b.is_ascii_whitespace() || b"\"'&,;}<".contains(b))
+        .map_or(lower.len(), |len| start + len);
+    (end > start).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacts_headers_and_url() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", "Bearer sk-secreto".parse().unwrap());
+        headers.insert("accept", "text/plain".parse().unwrap());
+        let mut injected: hyper::header::HeaderValue = "sk-propia".parse().unwrap();
+        injected.set_sensitive(true);
+        headers.insert("x-goog-api-key", injected);
+        let redacted = redact_headers(&headers);
+        assert!(redacted.contains(&("authorization".into(), REDACTED.into())));
+        assert!(redacted.contains(&("x-goog-api-key".into(), REDACTED.into())));
+        assert!(redacted.contains(&("accept".into(), "text/plain".into())));
+
+        let uri: Uri = "http://user:pw@api.test/v1?q=gato&api_key=abc&Token=x"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            redact_url(&uri),
+            "http://[redacted]@api.test/v1?q=gato&api_key=[redacted]&Token=[redacted]"
+        );
+    }
+
+    #[test]
+    fn test_redacts_secrets_in_free_text() {
+        let text = r#"{"password": "hunter2", "user": "ana"} token=abc&q=1
+Authorization: Bearer sk-123 at Handler.java:42 monkey=ok"#;
+        assert_eq!(
+            redact_text(text),
+            r#"{"password": "[redacted]", "user": "ana"} token=[redacted]&q=1
+Authorization: Bearer [redacted] at Handler.java:42 monkey=ok"#
+        );
+    }
+}
@@ -0,0 +1,468 @@
+//! Mapas de redirecciones y reescrituras cargados desde CSV.
+//!
+//! Las migraciones mantienen miles de filas `match_host,match_prefix,target,
+//! status` en una hoja de cálculo. Cada archivo de `[[redirect_maps]]` se
+//! compila al cargarlo en una tabla por host con los prefijos indexados por
+//! longitud, así que una búsqueda prueba como mucho un prefijo por longitud
+//! distinta y gana el más largo. Los destinos repetidos se guardan una sola
+//! vez.
+//!
+//! `status` es un código de redirección (301 por defecto, 302, 303, 307 o
+//! 308), que se responde con `Location: target` seguido del resto de la ruta
+//! y la query, o `rewrite`, que reenvía la petición a esa URL (solo
+//! `http://`). Los archivos se releen cuando cambian; una versión con errores
+//! se rechaza entera, con el número de línea de cada fila inválida, y se
+//! sigue usando la anterior. Entre archivos manda el primero que tiene una
+//! fila para la petición.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use hyper::{StatusCode, Uri};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::policy_export::{HitCount, RuleHits};
+
+const COLUMNS: [&str; 4] = ["match_host", "match_prefix", "target", "status"];
+
+/// Errores de fila que se muestran al rechazar un archivo.
+const MAX_REPORTED: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapAction {
+    Redirect(StatusCode),
+    Rewrite,
+}
+
+/// Resultado de una búsqueda: la URL final y qué hacer con ella.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapDecision {
+    pub target: String,
+    pub action: MapAction,
+}
+
+struct Entry {
+    target: Arc<str>,
+    action: MapAction,
+    hits: RuleHits,
+}
+
+#[derive(Default)]
+struct HostTable {
+    prefixes: HashMap<Box<str>, Entry>,
+    /// Distinct prefix lengths, longest first.
+    lengths: Vec<usize>,
+}
+
+impl HostTable {
+    fn longest(&self, path: &str) -> Option<(usize, &Entry)> {
+        self.lengths.iter().find_map(|&len| {
+            let prefix = path.get(..len)?;
+            self.prefixes.get(prefix).map(|entry| (len, entry))
+        })
+    }
+}
+
+#[derive(Default)]
+struct Compiled {
+    hosts: HashMap<Box<str>, HostTable>,
+    rows: usize,
+}
+
+impl Compiled {
+    fn parse(path: &Path, text: &str) -> anyhow::Result<Self> {
+        let file = path.display();
+        let mut lines = text.lines().enumerate().map(|(n, line)| (n + 1, line));
+        let header = lines
+            .by_ref()
+            .find(|(_, line)| !line.trim().is_empty())
+            .ok_or_else(|| anyhow::anyhow!("{file}: el archivo está vacío"))?;
+        let names = split_row(header.1).map_err(|e| anyhow::anyhow!("{file}:{}: {e}", header.0))?;
+        let mut index = [0; COLUMNS.len()];
+        for (slot, column) in index.iter_mut().zip(COLUMNS) {
+            *slot = names
+                .iter()
+                .position(|name| name.trim().eq_ignore_ascii_case(column))
+                .ok_or_else(|| anyhow::anyhow!("{file}:{}: falta la columna {column}", header.0))?;
+        }
+
+        let mut compiled = Compiled::default();
+        let mut targets: HashSet<Arc<str>> = HashSet::new();
+        let mut seen: HashMap<(String, String), usize> = HashMap::new();
+        let mut errors = Vec::new();
+        for (number, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let row = split_row(line).and_then(|fields| {
+                let field = |i: usize| fields.get(index[i]).map_or("", |f| f.trim());
+                parse_row(field(0), field(1), field(2), field(3))
+            });
+            let (host, prefix, target, action) = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    errors.push(format!("{file}:{number}: {e}"));
+                    continue;
+                }
+            };
+            if let Some(first) = seen.insert((host.clone(), prefix.clone()), number) {
+                errors.push(format!(
+                    "{file}:{number}: {host}{prefix} ya está en la línea {first}"
+                ));
+                continue;
+            }
+            let target = match targets.get(target.as_str()) {
+                Some(interned) => interned.clone(),
+                None => {
+                    let interned: Arc<str> = target.into();
+                    targets.insert(interned.clone());
+                    interned
+                }
+            };
+            compiled
+                .hosts
+                .entry(host.into())
+                .or_default()
+                .prefixes
+                .insert(
+                    prefix.into(),
+                    Entry {
+                        target,
+                        action,
+                        hits: RuleHits::default(),
+                    },
+                );
+            compiled.rows += 1;
+        }
+        if !errors.is_empty() {
+            let more = errors.len().saturating_sub(MAX_REPORTED);
+            errors.truncate(MAX_REPORTED);
+            if more > 0 {
+                errors.push(format!("y {more} errores más"));
+            }
+            anyhow::bail!("mapa de redirecciones inválido:\n{}", errors.join("\n"));
+        }
+        for table in compiled.hosts.values_mut() {
+            let lengths: BTreeSet<usize> = table.prefixes.keys().map(|p| p.len()).collect();
+            table.lengths = lengths.into_iter().rev().collect();
+        }
+        Ok(compiled)
+    }
+}
+
+fn parse_row(
+    host: &str,
+    prefix: &str,
+    target: &str,
+    status: &str,
+) -> anyhow::Result<(String, String, String, MapAction)> {
+    anyhow::ensure!(!host.is_empty(), "match_host vacío");
+    anyhow::ensure!(
+        prefix.starts_with('/'),
+        "match_prefix debe empezar por /: {prefix:?}"
+    );
+    let uri: Uri = target
+        .parse()
+        .map_err(|_| anyhow::anyhow!("target no es una URL: {target:?}"))?;
+    anyhow::ensure!(
+        uri.scheme().is_some() && uri.host().is_some(),
+        "target debe ser una URL absoluta: {target:?}"
+    );
+    let action = match status {
+        "" => MapAction::Redirect(StatusCode::MOVED_PERMANENTLY),
+        "rewrite" => {
+            anyhow::ensure!(
+                uri.scheme_str() == Some("http"),
+                "rewrite solo admite destinos http://: {target:?}"
+            );
+            MapAction::Rewrite
+        }
+        code => match code.parse::<u16>() {
+            Ok(code @ (301 | 302 | 303 | 307 | 308)) => {
+                MapAction::Redirect(StatusCode::from_u16(code).expect("código de redirección"))
+            }
+            _ => anyhow::bail!("status debe ser 301, 302, 303, 307, 308 o rewrite: {code:?}"),
+        },
+    };
+    Ok((
+        host.to_ascii_lowercase(),
+        prefix.to_string(),
+        target.to_string(),
+        action,
+    ))
+}
+
+/// Separa una fila CSV; admite campos entre comillas con `""` dentro.
+fn split_row(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    anyhow::ensure!(!quoted, "comillas sin cerrar");
+    fields.push(field);
+    Ok(fields)
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Estado de un archivo, tal como lo lista la API de administración.
+#[derive(Debug, Clone, Serialize)]
+pub struct MapSnapshot {
+    pub file: String,
+    pub rows: usize,
+    pub hits: u64,
+}
+
+/// Una fila de un mapa con sus aplicaciones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapEntry {
+    pub file: String,
+    pub host: String,
+    pub prefix: String,
+    pub target: String,
+    pub action: MapAction,
+    pub hits: HitCount,
+}
+
+struct MapFile {
+    path: PathBuf,
+    compiled: RwLock<Arc<Compiled>>,
+    modified: Mutex<Option<SystemTime>>,
+    hits: AtomicU64,
+}
+
+impl MapFile {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let modified = file_modified(path);
+        let compiled = read(path)?;
+        info!(path = %path.display(), rows = compiled.rows, "Mapa de redirecciones cargado");
+        Ok(Self {
+            path: path.to_path_buf(),
+            compiled: RwLock::new(Arc::new(compiled)),
+            modified: Mutex::new(modified),
+            hits: AtomicU64::new(0),
+        })
+    }
+
+    fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let current = file_modified(&self.path);
+        if current == *self.modified.lock().expect("lock de mtime") {
+            return Ok(false);
+        }
+        // Remembered even on failure, so a broken file is reported once.
+        *self.modified.lock().expect("lock de mtime") = current;
+        let compiled = read(&self.path)?;
+        info!(path = %self.path.display(), rows = compiled.rows, "Mapa de redirecciones recargado");
+        *self.compiled.write().expect("lock del mapa") = Arc::new(compiled);
+        Ok(true)
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Compiled> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("no se pudo leer {}: {e}", path.display()))?;
+    Compiled::parse(path, &text)
+}
+
+pub struct RedirectMaps {
+    files: Vec<MapFile>,
+}
+
+impl RedirectMaps {
+    /// Carga los archivos; falla con la primera que no se pueda leer o que
+    /// tenga filas inválidas.
+    pub fn load(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let files = paths
+            .iter()
+            .map(|path| MapFile::load(path))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { files })
+    }
+
+    /// Fila con el prefijo más largo para `uri`, en el primer archivo que
+    /// tenga alguna.
+    pub fn lookup(&self, uri: &Uri) -> Option<MapDecision> {
+        let host = uri.host()?.to_ascii_lowercase();
+        let path = uri.path();
+        self.files.iter().find_map(|file| {
+            let compiled = file.compiled.read().expect("lock del mapa").clone();
+            let (len, entry) = compiled.hosts.get(host.as_str())?.longest(path)?;
+            file.hits.fetch_add(1, Ordering::Relaxed);
+            entry.hits.record();
+            let mut target = format!("{}{}", entry.target, &path[len..]);
+            if let Some(query) = uri.query() {
+                target.push('?');
+                target.push_str(query);
+            }
+            Some(MapDecision {
+                target,
+                action: entry.action,
+            })
+        })
+    }
+
+    /// Todas las filas cargadas. Sus cuentas empiezan de cero cuando el
+    /// archivo se recarga; la del archivo entero se conserva.
+    pub fn entries(&self) -> Vec<MapEntry> {
+        let mut entries = Vec::new();
+        for file in &self.files {
+            let compiled = file.compiled.read().expect("lock del mapa").clone();
+            let mut rows: Vec<MapEntry> = compiled
+                .hosts
+                .iter()
+                .flat_map(|(host, table)| {
+                    table.prefixes.iter().map(|(prefix, entry)| MapEntry {
+                        file: file.path.display().to_string(),
+                        host: host.to_string(),
+                        prefix: prefix.to_string(),
+                        target: entry.target.to_string(),
+                        action: entry.action,
+                        hits: entry.hits.get(),
+                    })
+                })
+                .collect();
+            rows.sort_by(|a, b| (&a.host, &a.prefix).cmp(&(&b.host, &b.prefix)));
+            entries.extend(rows);
+        }
+        entries
+    }
+
+    pub fn list(&self) -> Vec<MapSnapshot> {
+        self.files
+            .iter()
+            .map(|file| MapSnapshot {
+                file: file.path.display().to_string(),
+                rows: file.compiled.read().expect("lock del mapa").rows,
+                hits: file.hits.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Relee los archivos que cambiaron en disco; los que ya no cargan
+    /// conservan la versión anterior.
+    pub fn reload_if_changed(&self) {
+        for file in &self.files {
+            if let Err(e) = file.reload_if_changed() {
+                warn!(path = %file.path.display(), error = %e, "No se pudo recargar el mapa de redirecciones");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(file: &tempfile::NamedTempFile, text: &str) {
+        std::fs::write(file.path(), text).unwrap();
+    }
+
+    #[test]
+    fn test_longest_prefix_wins_and_reload_swaps() {
+        let csv = tempfile::NamedTempFile::new().unwrap();
+        write(
+            &csv,
+            "match_host,match_prefix,target,status\n\
+             viejo.example,/,https://nuevo.example/,301\n\
+             viejo.example,/docs,https://docs.example/manual,308\n\
+             viejo.example,/docs/api/,http://api.interno/v2/,rewrite\n\
+             \"Viejo.Example\",/docs/api/v1,\"https://docs.example/legacy,v1\",\n",
+        );
+        let maps = RedirectMaps::load(&[csv.path().to_path_buf()]).unwrap();
+        let lookup = |url: &str| maps.lookup(&url.parse().unwrap());
+
+        assert_eq!(
+            lookup("http://viejo.example/docs/api/users?id=3"),
+            Some(MapDecision {
+                target: "http://api.interno/v2/users?id=3".into(),
+                action: MapAction::Rewrite,
+            })
+        );
+        assert_eq!(
+            lookup("http://VIEJO.example/docs/guia").unwrap(),
+            MapDecision {
+                target: "https://docs.example/manual/guia".into(),
+                action: MapAction::Redirect(StatusCode::PERMANENT_REDIRECT),
+            }
+        );
+        assert_eq!(
+            lookup("http://viejo.example/docs/api/v1/x").unwrap().target,
+            "https://docs.example/legacy,v1/x"
+        );
+        assert_eq!(
+            lookup("http://viejo.example/precios").unwrap().target,
+            "https://nuevo.example/precios"
+        );
+        assert_eq!(lookup("http://otro.example/docs"), None);
+        assert_eq!(maps.list()[0].rows, 4);
+        assert_eq!(maps.list()[0].hits, 4);
+
+        // A broken version is rejected whole and the loaded one stays.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        write(
+            &csv,
+            "match_prefix,match_host,target,status\n/docs,viejo.example,nuevo.example/docs,\n/a,,http://b/,999\n",
+        );
+        let error = maps.files[0].reload_if_changed().unwrap_err().to_string();
+        assert!(error.contains(":2: target"), "{error}");
+        assert!(error.contains(":3: match_host vacío"), "{error}");
+        assert!(lookup("http://viejo.example/docs").is_some());
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        write(
+            &csv,
+            "match_prefix,match_host,target,status\n/docs,viejo.example,https://wiki.example/,302\n",
+        );
+        assert!(maps.files[0].reload_if_changed().unwrap());
+        assert_eq!(
+            lookup("http://viejo.example/docs/x").unwrap(),
+            MapDecision {
+                target: "https://wiki.example//x".into(),
+                action: MapAction::Redirect(StatusCode::FOUND),
+            }
+        );
+        assert_eq!(lookup("http://viejo.example/precios"), None);
+    }
+
+    #[test]
+    fn test_rejects_duplicates_and_bad_rows_by_line() {
+        let text = "match_host,match_prefix,target,status\n\
+                    a.example,/x,http://b.example/,rewrite\n\
+                    \n\
+                    a.example,/x,http://c.example/,\n\
+                    a.example,y,http://c.example/,\n\
+                    a.example,/z,https://c.example/,rewrite\n";
+        let error = Compiled::parse(Path::new("m.csv"), text)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            error.contains("m.csv:4: a.example/x ya está en la línea 2"),
+            "{error}"
+        );
+        assert!(error.contains("m.csv:5: match_prefix"), "{error}");
+        assert!(error.contains("m.csv:6: rewrite solo admite"), "{error}");
+        assert!(Compiled::parse(Path::new("m.csv"), "host,target\n").is_err());
+    }
+}
@@ -0,0 +1,576 @@
+//! Reproducción de capturas contra otro backend LLM, para evaluarlo sin
+//! tocar producción.
+//!
+//! `proxy-ia llm replay` lee las capturas en el orden en que se guardaron,
+//! reescribe el campo `model` del body según `--model-map` y envía cada
+//! petición al destino nuevo al ritmo configurado. Lo redactado al capturar
+//! sigue redactado: las cabeceras con credenciales se envían sin ellas y las
+//! respuestas nuevas se guardan también redactadas. Cada resultado se escribe
+//! junto al original y al final un informe compara latencia, tokens y
+//! diferencias de texto.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use hyper::{Body, Client, HeaderMap, Method, Request, Uri};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::capture::{read_bundle, RecordedBundle};
+use crate::redact::{redact_headers, REDACTED};
+use crate::settings::ReplaySettings;
+
+/// Tokens informados por el backend en `usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    /// Entiende `usage` al estilo OpenAI (`prompt_tokens`) y Anthropic
+    /// (`input_tokens`).
+    pub(crate) fn of(body: &Value) -> Option<Self> {
+        let usage = body.get("usage")?;
+        let field = |names: [&str; 2]| names.iter().find_map(|name| usage.get(name)?.as_u64());
+        Some(Self {
+            prompt_tokens: field(["prompt_tokens", "input_tokens"])?,
+            completion_tokens: field(["completion_tokens", "output_tokens"]).unwrap_or(0),
+        })
+    }
+}
+
+/// Palabras en común entre la respuesta original y la nueva, sin importar
+/// el orden.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DiffStats {
+    pub shared_words: usize,
+    pub removed_words: usize,
+    pub added_words: usize,
+    /// `2 × shared / (original + nuevas)`; 1 si son idénticas.
+    pub similarity: f64,
+}
+
+impl DiffStats {
+    fn between(original: &str, replay: &str) -> Self {
+        let mut counts: HashMap<&str, i64> = HashMap::new();
+        for word in original.split_whitespace() {
+            *counts.entry(word).or_default() += 1;
+        }
+        let mut added = 0;
+        for word in replay.split_whitespace() {
+            match counts.get_mut(word) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => added += 1,
+            }
+        }
+        let removed = counts.values().sum::<i64>() as usize;
+        let total_original = original.split_whitespace().count();
+        let shared = total_original - removed;
+        let total = total_original + shared + added;
+        Self {
+            shared_words: shared,
+            removed_words: removed,
+            added_words: added,
+            similarity: if total == 0 {
+                1.0
+            } else {
+                2.0 * shared as f64 / total as f64
+            },
+        }
+    }
+}
+
+/// Resultado de una captura reproducida.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryReport {
+    pub request_id: String,
+    pub model: Option<String>,
+    pub replay_model: Option<String>,
+    pub original_status: u16,
+    pub original_latency_ms: Option<f64>,
+    pub original_usage: Option<Usage>,
+    pub status: Option<u16>,
+    pub latency_ms: Option<f64>,
+    pub usage: Option<Usage>,
+    /// Sin body original (capturas de la versión 1) no hay comparación.
+    pub diff: Option<DiffStats>,
+    pub error: Option<String>,
+}
+
+/// Capturas que no se enviaron, por motivo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Skipped {
+    /// Túneles `CONNECT`: no hay petición que repetir.
+    pub tunnels: usize,
+    /// Body capturado a medias.
+    pub truncated: usize,
+    /// Con marcas de redacción y `--skip-redacted`.
+    pub redacted: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub replayed: usize,
+    pub failed: usize,
+    pub skipped: Skipped,
+    /// Medias sobre las peticiones que tienen el dato.
+    pub original_latency_ms: Option<f64>,
+    pub latency_ms: Option<f64>,
+    pub original_tokens: Usage,
+    pub tokens: Usage,
+    pub mean_similarity: Option<f64>,
+    pub identical: usize,
+    pub entries: Vec<EntryReport>,
+}
+
+impl Report {
+    fn summarize(&mut self) {
+        fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+            let values: Vec<f64> = values.collect();
+            (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+        }
+        let entries = &self.entries;
+        self.original_latency_ms = mean(entries.iter().filter_map(|e| e.original_latency_ms));
+        self.latency_ms = mean(entries.iter().filter_map(|e| e.latency_ms));
+        self.mean_similarity = mean(entries.iter().filter_map(|e| e.diff.map(|d| d.similarity)));
+        self.identical = entries
+            .iter()
+            .filter(|e| e.diff.is_some_and(|d| d.similarity == 1.0))
+            .count();
+        for entry in entries {
+            for (total, usage) in [
+                (&mut self.original_tokens, entry.original_usage),
+                (&mut self.tokens, entry.usage),
+            ] {
+                if let Some(usage) = usage {
+                    total.prompt_tokens += usage.prompt_tokens;
+                    total.completion_tokens += usage.completion_tokens;
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |value: Option<f64>| value.map_or("-".to_string(), |v| format!("{v:.0} ms"));
+        writeln!(
+            f,
+            "Reproducidas: {} ({} fallidas); omitidas: {} túneles, {} truncadas, {} redactadas",
+            self.replayed,
+            self.failed,
+            self.skipped.tunnels,
+            self.skipped.truncated,
+            self.skipped.redacted
+        )?;
+        writeln!(
+            f,
+            "Latencia media: {} original, {} nueva",
+            ms(self.original_latency_ms),
+            ms(self.latency_ms)
+        )?;
+        writeln!(
+            f,
+            "Tokens (prompt/respuesta): {}/{} original, {}/{} nueva",
+            self.original_tokens.prompt_tokens,
+            self.original_tokens.completion_tokens,
+            self.tokens.prompt_tokens,
+            self.tokens.completion_tokens
+        )?;
+        match self.mean_similarity {
+            Some(similarity) => write!(
+                f,
+                "Similitud media: {similarity:.2} ({} idénticas)",
+                self.identical
+            ),
+            None => write!(f, "Similitud media: - (capturas sin body de respuesta)"),
+        }
+    }
+}
+
+/// Reproduce las capturas de `settings.from()` y escribe cada resultado y
+/// `report.json` en `settings.out()`.
+pub async fn replay(settings: &ReplaySettings) -> anyhow::Result<Report> {
+    if settings.target().scheme_str() != Some("http") || settings.target().host().is_none() {
+        anyhow::bail!("El destino debe ser una URL http://host[:puerto]");
+    }
+    let bundles = load(settings.from())?;
+    std::fs::create_dir_all(settings.out())
+        .with_context(|| format!("No se pudo crear {}", settings.out().display()))?;
+
+    let client = Client::new();
+    let mut pace = tokio::time::interval(Duration::from_secs_f64(1.0 / settings.rate_per_sec()));
+    let mut report = Report::default();
+    for bundle in bundles {
+        let request = &bundle.request;
+        if request.method == Method::CONNECT.as_str() {
+            report.skipped.tunnels += 1;
+            continue;
+        }
+        if request.body_truncated {
+            report.skipped.truncated += 1;
+            continue;
+        }
+        if settings.skip_redacted()
+            && (request.url.contains(REDACTED) || request.body.contains(REDACTED))
+        {
+            report.skipped.redacted += 1;
+            continue;
+        }
+        pace.tick().await;
+        let entry = replay_one(&client, settings, &bundle).await?;
+        if entry.error.is_some() {
+            report.failed += 1;
+        } else {
+            report.replayed += 1;
+        }
+        report.entries.push(entry);
+    }
+    report.summarize();
+
+    let path = settings.out().join("report.json");
+    std::fs::write(&path, serde_json::to_vec_pretty(&report)?)
+        .with_context(|| format!("No se pudo escribir {}", path.display()))?;
+    info!(
+        replayed = report.replayed,
+        failed = report.failed,
+        "Reproducción terminada"
+    );
+    Ok(report)
+}
+
+/// Capturas del directorio, de la más antigua a la más reciente.
+fn load(dir: &Path) -> anyhow::Result<Vec<RecordedBundle>> {
+    let mut bundles = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("No se pudo leer {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let bytes = std::fs::read(&path)?;
+            bundles.push(read_bundle(&bytes).with_context(|| path.display().to_string())?);
+        }
+    }
+    bundles
+        .sort_by(|a, b| (a.captured_at_ms, &a.request_id).cmp(&(b.captured_at_ms, &b.request_id)));
+    Ok(bundles)
+}
+
+/// Envía una captura y guarda el resultado en `<request_id>.json`. Solo
+/// falla si no puede escribir; los errores del destino quedan en la entrada.
+async fn replay_one(
+    client: &Client<hyper::client::HttpConnector>,
+    settings: &ReplaySettings,
+    bundle: &RecordedBundle,
+) -> anyhow::Result<EntryReport> {
+    let request = &bundle.request;
+    let mut body = request.body.clone();
+    let mut json_body: Option<Value> = serde_json::from_str(&body).ok();
+    let model = json_body
+        .as_ref()
+        .and_then(|b| b.get("model")?.as_str())
+        .map(str::to_string);
+    let replay_model = model
+        .as_deref()
+        .map(|model| settings.model(model).unwrap_or(model).to_string());
+    if let (Some(Value::Object(fields)), Some(replay_model)) = (&mut json_body, &replay_model) {
+        if model.as_ref() != Some(replay_model) {
+            fields.insert("model".into(), Value::String(replay_model.clone()));
+            body = Value::Object(fields.clone()).to_string();
+        }
+    }
+
+    let original_body: Option<Value> = serde_json::from_str(&bundle.response.body).ok();
+    let mut entry = EntryReport {
+        request_id: bundle.request_id.clone(),
+        model,
+        replay_model,
+        original_status: bundle.response.status,
+        original_latency_ms: bundle.stage_ms("upstream"),
+        original_usage: original_body.as_ref().and_then(Usage::of),
+        status: None,
+        latency_ms: None,
+        usage: None,
+        diff: None,
+        error: None,
+    };
+
+    let started = Instant::now();
+    let outcome = match build_request(settings.target(), request, body) {
+        Ok(req) => send(client, req).await,
+        Err(e) => Err(e),
+    };
+    let replayed = match outcome {
+        Ok((status, headers, response_body)) => {
+            let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+            let parsed: Option<Value> = serde_json::from_str(&response_body).ok();
+            entry.status = Some(status);
+            entry.latency_ms = Some(latency_ms);
+            entry.usage = parsed.as_ref().and_then(Usage::of);
+            if !bundle.response.body.is_empty() {
+                entry.diff = Some(DiffStats::between(
+                    &completion_text(original_body.as_ref(), &bundle.response.body),
+                    &completion_text(parsed.as_ref(), &response_body),
+                ));
+            }
+            json!({
+                "status": status,
+                "latency_ms": latency_ms,
+                "headers": redact_headers(&headers),
+                "body": response_body,
+            })
+        }
+        Err(e) => {
+            warn!(request_id = %bundle.request_id, error = %e, "No se pudo reproducir la captura");
+            entry.error = Some(format!("{e:#}"));
+            json!({ "error": entry.error })
+        }
+    };
+
+    let record = json!({
+        "original": bundle,
+        "replay": replayed,
+        "diff": entry.diff,
+    });
+    let path = settings.out().join(format!("{}.json", bundle.request_id));
+    std::fs::write(&path, serde_json::to_vec_pretty(&record)?)
+        .with_context(|| format!("No se pudo escribir {}", path.display()))?;
+    Ok(entry)
+}
+
+/// La petición capturada, dirigida a `target` y sin las cabeceras que no
+/// tienen sentido repetir ni las que se redactaron.
+fn build_request(
+    target: &Uri,
+    request: &crate::capture::CapturedRequest,
+    body: String,
+) -> anyhow::Result<Request<Body>> {
+    let original = request.replay_url()?;
+    let path = original.path_and_query().map_or("/", |pq| pq.as_str());
+    let authority = target.authority().expect("destino validado");
+    let uri: Uri = format!("http://{authority}{path}").parse()?;
+    let headers = request.replay_headers();
+
+    let mut req = Request::builder()
+        .method(request.method.as_str())
+        .uri(uri)
+        .body(Body::from(body))?;
+    *req.headers_mut() = headers;
+    Ok(req)
+}
+
+async fn send(
+    client: &Client<hyper::client::HttpConnector>,
+    req: Request<Body>,
+) -> anyhow::Result<(u16, HeaderMap, String)> {
+    let response = client.request(req).await?;
+    let status = response.status().as_u16();
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    Ok((
+        status,
+        parts.headers,
+        String::from_utf8_lossy(&body).into_owned(),
+    ))
+}
+
+/// Texto generado de una respuesta de chat o de completions; si no tiene
+/// esa forma se compara el body entero.
+fn completion_text(parsed: Option<&Value>, raw: &str) -> String {
+    let choice = parsed.and_then(|body| body.get("choices")?.get(0));
+    choice
+        .and_then(|choice| {
+            choice
+                .pointer("/message/content")
+                .or_else(|| choice.get("text"))?
+                .as_str()
+        })
+        .unwrap_or(raw)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+
+    fn chat_request(model: &str, url: &str) -> Value {
+        json!({
+            "method": "POST",
+            "url": url,
+            "headers": [
+                ["content-type", "application/json"],
+                ["authorization", REDACTED],
+                ["host", "api.openai.test"]
+            ],
+            "body": json!({ "model": model, "messages": [] }).to_string(),
+            "body_truncated": false
+        })
+    }
+
+    fn write_fixture(dir: &Path) {
+        let chat = "http://api.openai.test/v1/chat/completions";
+        let bundles = [
+            // Version 1: no format_version and no response body.
+            json!({
+                "request_id": "100-0",
+                "captured_at_ms": 100,
+                "client": "10.0.0.1:5000",
+                "request": chat_request("gpt-4o", chat),
+                "timeline": [{ "stage": "upstream", "offset_ms": 0.0, "duration_ms": 120.0, "error": null }],
+                "error_category": null,
+                "response": { "status": 502, "headers": [] }
+            }),
+            json!({
+                "format_version": 2,
+                "request_id": "200-1",
+                "captured_at_ms": 200,
+                "client": "10.0.0.1:5001",
+                "request": chat_request("gpt-4o", chat),
+                "timeline": [{ "stage": "upstream", "offset_ms": 0.0, "duration_ms": 80.0, "error": null }],
+                "error_category": null,
+                "response": {
+                    "status": 200,
+                    "headers": [],
+                    "body": json!({
+                        "choices": [{ "message": { "content": "el gato duerme" } }],
+                        "usage": { "prompt_tokens": 10, "completion_tokens": 3 }
+                    }).to_string(),
+                    "body_truncated": false
+                }
+            }),
+            json!({
+                "format_version": 2,
+                "request_id": "300-2",
+                "captured_at_ms": 300,
+                "client": "10.0.0.1:5002",
+                "request": chat_request("gpt-4o", &format!("{chat}?api_key={REDACTED}")),
+                "timeline": [],
+                "error_category": null,
+                "response": { "status": 200, "headers": [], "body": "", "body_truncated": false }
+            }),
+            json!({
+                "format_version": 2,
+                "request_id": "400-3",
+                "captured_at_ms": 400,
+                "client": "10.0.0.1:5003",
+                "request": {
+                    "method": "CONNECT", "url": "api.openai.test:443", "headers": [],
+                    "body": "", "body_truncated": false
+                },
+                "timeline": [],
+                "error_category": null,
+                "response": { "status": 502, "headers": [], "body": "", "body_truncated": false }
+            }),
+        ];
+        for bundle in bundles {
+            let id = bundle["request_id"].as_str().unwrap();
+            std::fs::write(dir.join(format!("{id}.json")), bundle.to_string()).unwrap();
+        }
+    }
+
+    /// Backend que responde con el modelo recibido y anota qué cabeceras llegaron.
+    async fn mock_llm(seen: Arc<Mutex<Vec<(String, bool)>>>) -> std::net::SocketAddr {
+        let make = make_service_fn(move |_| {
+            let seen = seen.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let seen = seen.clone();
+                    async move {
+                        let authorized = req.headers().contains_key("authorization");
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body: Value = serde_json::from_slice(&body).unwrap();
+                        let model = body["model"].as_str().unwrap().to_string();
+                        seen.lock().unwrap().push((model.clone(), authorized));
+                        let answer = json!({
+                            "model": model,
+                            "choices": [{ "message": { "content": "el gato duerme mucho" } }],
+                            "usage": { "prompt_tokens": 10, "completion_tokens": 4 }
+                        });
+                        Ok::<_, Infallible>(Response::new(Body::from(answer.to_string())))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_replays_fixture_and_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let addr = mock_llm(seen.clone()).await;
+
+        let settings = ReplaySettings::new(dir.path(), format!("http://{addr}").parse().unwrap())
+            .with_model("gpt-4o", "llama-70b")
+            .with_rate_per_sec(1000.0)
+            .with_skip_redacted(true);
+        let report = replay(&settings).await.unwrap();
+
+        assert_eq!(report.replayed, 2);
+        assert_eq!(report.failed, 0);
+        assert_eq!(
+            report.skipped,
+            Skipped {
+                tunnels: 1,
+                truncated: 0,
+                redacted: 1
+            }
+        );
+        // The redacted credential is never sent and the model is remapped.
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                ("llama-70b".to_string(), false),
+                ("llama-70b".to_string(), false)
+            ]
+        );
+        assert_eq!(report.entries[0].request_id, "100-0");
+        assert_eq!(report.entries[0].replay_model.as_deref(), Some("llama-70b"));
+        assert_eq!(report.entries[0].diff, None);
+        assert_eq!(report.original_latency_ms, Some(100.0));
+        assert_eq!(
+            report.original_tokens,
+            Usage {
+                prompt_tokens: 10,
+                completion_tokens: 3
+            }
+        );
+        assert_eq!(
+            report.tokens,
+            Usage {
+                prompt_tokens: 20,
+                completion_tokens: 8
+            }
+        );
+        let diff = report.entries[1].diff.unwrap();
+        assert_eq!(
+            (diff.shared_words, diff.removed_words, diff.added_words),
+            (3, 0, 1)
+        );
+        assert!((report.mean_similarity.unwrap() - 6.0 / 7.0).abs() < 1e-9);
+        assert_eq!(report.identical, 0);
+
+        let out = dir.path().join("replay");
+        let saved: Value =
+            serde_json::from_slice(&std::fs::read(out.join("report.json")).unwrap()).unwrap();
+        assert_eq!(saved["replayed"], 2);
+        let record: Value =
+            serde_json::from_slice(&std::fs::read(out.join("200-1.json")).unwrap()).unwrap();
+        assert_eq!(record["original"]["request"]["headers"][1][1], REDACTED);
+        assert_eq!(record["replay"]["status"], 200);
+    }
+}
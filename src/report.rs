@@ -0,0 +1,349 @@
+//! Informes de uso en HTML generados a partir de [`crate::stats`].
+//!
+//! Se generan según una programación semanal o diaria (`mon 08:00`,
+//! `daily 06:30`, en UTC) o bajo demanda desde la API de administración, y se
+//! entregan por webhook y/o escribiéndolos en un directorio. La plantilla
+//! embebida se puede reemplazar por un archivo con los mismos marcadores
+//! `{{nombre}}`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use tracing::{error, info, warn};
+
+use crate::jobs::{self, JobScheduler};
+use crate::settings::ReportSettings;
+use crate::stats::{format_day, today, DayStats, StatsStore};
+
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="es">
+<head><meta charset="utf-8"><title>Informe de tráfico {{period}}</title></head>
+<body>
+<h1>Informe de tráfico</h1>
+<p>Periodo: {{period}}</p>
+<table>
+<tr><th>Peticiones</th><td>{{requests}}</td></tr>
+<tr><th>Bytes transferidos</th><td>{{bytes}}</td></tr>
+<tr><th>Errores</th><td>{{errors}} ({{errors_trend}} frente a {{errors_previous}} del periodo anterior)</td></tr>
+</table>
+<h2>Clientes principales</h2>
+<table>{{top_clients}}</table>
+<h2>Destinos principales</h2>
+<table>{{top_hosts}}</table>
+<h2>Bloqueos por categoría</h2>
+<table>{{blocks}}</table>
+</body>
+</html>
+"#;
+
+/// Momento de generación programada, en UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// 0 = lunes; `None` = todos los días.
+    weekday: Option<u8>,
+    minute_of_day: u32,
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+impl Schedule {
+    /// Segundos que faltan desde `now` (segundos Unix) hasta la siguiente
+    /// ejecución; nunca devuelve cero para no repetir la misma.
+    pub fn secs_until_next(&self, now: u64) -> u64 {
+        let day = now / 86_400;
+        let target = u64::from(self.minute_of_day) * 60;
+        (0..=7)
+            .map(|offset| (day + offset) * 86_400 + target)
+            .find(|at| {
+                let weekday = ((at / 86_400 + 3) % 7) as u8;
+                *at > now && self.weekday.is_none_or(|w| w == weekday)
+            })
+            .map(|at| at - now)
+            .unwrap_or(86_400)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("programación inválida `{s}` (p. ej. `mon 08:00`)");
+        let (day, time) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let weekday = match day.to_ascii_lowercase().as_str() {
+            "daily" => None,
+            day => Some(
+                WEEKDAYS
+                    .iter()
+                    .position(|w| *w == day)
+                    .ok_or_else(invalid)? as u8,
+            ),
+        };
+        let (hour, minute) = time.trim().split_once(':').ok_or_else(invalid)?;
+        let hour: u32 = hour.parse().map_err(|_| invalid())?;
+        let minute: u32 = minute.parse().map_err(|_| invalid())?;
+        if hour > 23 || minute > 59 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            weekday,
+            minute_of_day: hour * 60 + minute,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let day = self.weekday.map_or("daily", |w| WEEKDAYS[w as usize]);
+        let (hour, minute) = (self.minute_of_day / 60, self.minute_of_day % 60);
+        write!(f, "{day} {hour:02}:{minute:02}")
+    }
+}
+
+pub struct Reporter {
+    settings: ReportSettings,
+    stats: Arc<StatsStore>,
+    client: Client<HttpConnector>,
+    webhook: Option<Uri>,
+}
+
+impl Reporter {
+    pub fn new(settings: ReportSettings, stats: Arc<StatsStore>) -> anyhow::Result<Self> {
+        let webhook = match settings.webhook() {
+            Some(url) => {
+                let url: Uri = url.parse()?;
+                if url.scheme_str() != Some("http") {
+                    anyhow::bail!("el webhook de informes debe usar una URL http://: {url}");
+                }
+                Some(url)
+            }
+            None => None,
+        };
+        Ok(Self {
+            settings,
+            stats,
+            client: Client::new(),
+            webhook,
+        })
+    }
+
+    pub fn settings(&self) -> &ReportSettings {
+        &self.settings
+    }
+
+    /// Informe de los `period_days` días que terminan en `end_day` (excluido),
+    /// comparado con el periodo anterior de la misma duración.
+    pub fn render(&self, end_day: u64) -> anyhow::Result<String> {
+        let template = match self.settings.template() {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("No se pudo leer la plantilla {}", path.display()))?,
+            None => DEFAULT_TEMPLATE.to_string(),
+        };
+        let days = self.settings.period_days();
+        let start = end_day.saturating_sub(days);
+        let current = self.stats.range(start, end_day);
+        let previous = self.stats.range(start.saturating_sub(days), start);
+        let top = self.settings.top();
+
+        let trend = match (current.errors, previous.errors) {
+            (_, 0) => "sin datos previos".to_string(),
+            (now, before) => {
+                let change = (now as f64 - before as f64) / before as f64 * 100.0;
+                format!("{change:+.0}%")
+            }
+        };
+        let period = format!(
+            "{} – {}",
+            format_day(start),
+            format_day(end_day.saturating_sub(1))
+        );
+        let values = [
+            ("period", period),
+            ("requests", current.requests.to_string()),
+            ("bytes", current.bytes.to_string()),
+            ("errors", current.errors.to_string()),
+            ("errors_previous", previous.errors.to_string()),
+            ("errors_trend", trend),
+            ("top_clients", rows(&DayStats::top(&current.clients, top))),
+            ("top_hosts", rows(&DayStats::top(&current.hosts, top))),
+            ("blocks", rows(&DayStats::top(&current.blocks, usize::MAX))),
+        ];
+        Ok(values.iter().fold(template, |html, (key, value)| {
+            html.replace(&format!("{{{{{key}}}}}"), value)
+        }))
+    }
+
+    /// Envía el informe por webhook y/o lo guarda en el directorio
+    /// configurado. Devuelve los destinos a los que se entregó.
+    pub async fn deliver(&self, html: &str, end_day: u64) -> anyhow::Result<Vec<String>> {
+        let mut delivered = Vec::new();
+        if let Some(dir) = self.settings.dir() {
+            std::fs::create_dir_all(dir)?;
+            let last_day = format_day(end_day.saturating_sub(1));
+            let path = dir.join(format!("report-{last_day}.html"));
+            std::fs::write(&path, html)
+                .with_context(|| format!("No se pudo escribir {}", path.display()))?;
+            delivered.push(path.display().to_string());
+        }
+        if let Some(url) = &self.webhook {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(url.clone())
+                .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(html.to_string()))?;
+            let res = self.client.request(req).await?;
+            if !res.status().is_success() {
+                anyhow::bail!("el webhook de informes respondió {}", res.status());
+            }
+            delivered.push(url.to_string());
+        }
+        Ok(delivered)
+    }
+
+    /// Genera y entrega el informe que termina hoy (incluido).
+    pub async fn generate(&self) -> anyhow::Result<(String, Vec<String>)> {
+        let end_day = today() + 1;
+        let html = self.render(end_day)?;
+        let delivered = self.deliver(&html, end_day).await?;
+        Ok((html, delivered))
+    }
+
+    /// Ejecuta la programación hasta que se cancele la tarea; cada informe
+    /// se genera como trabajo de la cola `reports`.
+    pub async fn run_schedule(self: Arc<Self>, schedule: Schedule, jobs: Arc<JobScheduler>) {
+        loop {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            tokio::time::sleep(Duration::from_secs(schedule.secs_until_next(now))).await;
+            let reporter = self.clone();
+            match jobs
+                .run(jobs::REPORTS, async move { reporter.generate().await })
+                .await
+            {
+                Some(Ok((_, delivered))) => info!(?delivered, "Informe de tráfico generado"),
+                Some(Err(e)) => error!(error = %e, "No se pudo generar el informe de tráfico"),
+                None => warn!("La cola de informes estaba llena; se omitió el informe programado"),
+            }
+        }
+    }
+}
+
+fn rows(entries: &[(String, u64)]) -> String {
+    entries
+        .iter()
+        .map(|(key, count)| format!("<tr><td>{}</td><td>{count}</td></tr>", escape(key)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::settings::StatsSettings;
+
+    fn fixture(dir: &std::path::Path) -> Arc<StatsStore> {
+        let store = StatsStore::open(&StatsSettings::new(dir.join("stats.json"))).unwrap();
+        let counts = |pairs: &[(&str, u64)]| -> HashMap<String, u64> {
+            pairs.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+        };
+        // Previous week: 4 errors. Current week (days 107..114): 6 errors.
+        store.insert_day(
+            101,
+            DayStats {
+                requests: 50,
+                errors: 4,
+                ..Default::default()
+            },
+        );
+        store.insert_day(
+            108,
+            DayStats {
+                requests: 700,
+                bytes: 1_000_000,
+                errors: 2,
+                clients: counts(&[("10.0.0.7", 600), ("10.0.0.9", 100)]),
+                hosts: counts(&[("api.example", 650), ("<script>", 50)]),
+                blocks: counts(&[("reputation", 12)]),
+                ..Default::default()
+            },
+        );
+        store.insert_day(
+            113,
+            DayStats {
+                requests: 300,
+                bytes: 234_567,
+                errors: 4,
+                clients: counts(&[("10.0.0.9", 300)]),
+                hosts: counts(&[("api.example", 300)]),
+                blocks: counts(&[("reputation", 3), ("profile", 5)]),
+                ..Default::default()
+            },
+        );
+        Arc::new(store)
+    }
+
+    #[test]
+    fn test_render_from_fixture() {
+        let dir = tempfile::tempdir().unwrap();
+        let reporter =
+            Reporter::new(ReportSettings::new().with_top(1), fixture(dir.path())).unwrap();
+        let html = reporter.render(114).unwrap();
+
+        assert!(html.contains("1970-04-18 – 1970-04-24"));
+        assert!(html.contains("<th>Peticiones</th><td>1000</td>"));
+        assert!(html.contains("<td>1234567</td>"));
+        assert!(html.contains("6 (+50% frente a 4 del periodo anterior)"));
+        assert!(html.contains("<tr><td>api.example</td><td>950</td></tr>"));
+        // Only the top client; 10.0.0.9 has 400 against 600.
+        assert!(html.contains("<tr><td>10.0.0.7</td><td>600</td></tr>"));
+        assert!(!html.contains("10.0.0.9"));
+        assert!(html.contains("<tr><td>reputation</td><td>15</td></tr>"));
+        assert!(html.contains("<tr><td>profile</td><td>5</td></tr>"));
+        assert!(!html.contains("{{"));
+    }
+
+    #[tokio::test]
+    async fn test_custom_template_written_to_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("plantilla.html");
+        std::fs::write(&template, "bloqueos:{{blocks}} hosts:{{top_hosts}}").unwrap();
+        let settings = ReportSettings::new()
+            .with_template(&template)
+            .with_dir(dir.path().join("informes"));
+        let reporter = Reporter::new(settings, fixture(dir.path())).unwrap();
+        let html = reporter.render(114).unwrap();
+        assert!(html.starts_with("bloqueos:"));
+        assert!(html.contains("&lt;script&gt;"));
+
+        let delivered = reporter.deliver(&html, 114).await.unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(std::fs::read_to_string(&delivered[0]).unwrap(), html);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let monday: Schedule = "mon 08:00".parse().unwrap();
+        assert_eq!(monday.to_string(), "mon 08:00");
+        // 1970-01-01 was a Thursday; the next Monday is day 4.
+        assert_eq!(monday.secs_until_next(0), 4 * 86_400 + 8 * 3_600);
+        let daily: Schedule = "daily 00:30".parse().unwrap();
+        assert_eq!(daily.secs_until_next(3_600), 86_400 - 1_800);
+        assert!("mon 25:00".parse::<Schedule>().is_err());
+        assert!("lunes 08:00".parse::<Schedule>().is_err());
+    }
+}
@@ -0,0 +1,510 @@
+//! Reputación de IPs contra un servicio externo.
+//!
+//! Las consultas pasan por una caché LRU con TTL y, cuando faltan, se agrupan
+//! durante una ventana corta para enviar un solo lote al proveedor. Si el
+//! proveedor no responde a tiempo, el veredicto depende de `fail_open`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::future::BoxFuture;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use lru::LruCache;
+use serde::Deserialize;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+use crate::log_throttle::LogThrottle;
+use crate::settings::{ReputationAction, ReputationSettings};
+
+/// Fuente de puntuaciones de riesgo por IP. Cada proveedor adapta la API de
+/// su servicio a esta interfaz.
+pub trait ReputationProvider: Send + Sync + 'static {
+    /// Consulta un lote de IPs. Las IPs ausentes de la respuesta se
+    /// consideran sin antecedentes.
+    fn lookup(&self, ips: Vec<IpAddr>) -> BoxFuture<'static, anyhow::Result<HashMap<IpAddr, f64>>>;
+}
+
+/// Proveedor HTTP genérico: envía `POST {"ips": [...]}` y espera
+/// `{"scores": {"<ip>": <puntuación>}}`. Solo admite URLs `http://`.
+pub struct HttpReputationProvider {
+    client: Client<HttpConnector>,
+    url: Uri,
+}
+
+impl HttpReputationProvider {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let url: Uri = url.parse()?;
+        if url.scheme_str() != Some("http") {
+            anyhow::bail!("el servicio de reputación debe usar una URL http://: {url}");
+        }
+        Ok(Self {
+            client: Client::new(),
+            url,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    scores: HashMap<IpAddr, f64>,
+}
+
+impl ReputationProvider for HttpReputationProvider {
+    fn lookup(&self, ips: Vec<IpAddr>) -> BoxFuture<'static, anyhow::Result<HashMap<IpAddr, f64>>> {
+        let client = self.client.clone();
+        let url = self.url.clone();
+        Box::pin(async move {
+            let body = serde_json::to_vec(&serde_json::json!({ "ips": ips }))?;
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))?;
+            let res = client.request(req).await?;
+            if !res.status().is_success() {
+                anyhow::bail!("el servicio de reputación respondió {}", res.status());
+            }
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            let parsed: LookupResponse = serde_json::from_slice(&body)?;
+            Ok(parsed.scores)
+        })
+    }
+}
+
+/// De dónde salió un veredicto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerdictSource {
+    Feed,
+    Cache,
+    /// El proveedor falló o no respondió a tiempo.
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Verdict {
+    pub ip: IpAddr,
+    /// `None` si la IP no tiene antecedentes o el proveedor no respondió.
+    pub score: Option<f64>,
+    pub action: ReputationAction,
+    pub source: VerdictSource,
+}
+
+/// IP con una puntuación en caché que no la deja pasar libremente.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListedIp {
+    pub ip: IpAddr,
+    pub score: f64,
+    pub action: ReputationAction,
+    /// Lo que le queda en la caché.
+    pub expires_in: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Unavailable;
+
+type LookupResult = Result<Option<f64>, Unavailable>;
+
+struct CachedScore {
+    score: Option<f64>,
+    expires: Instant,
+}
+
+/// Aplica la reputación a IPs concretas. Debe crearse dentro de un runtime
+/// de Tokio, ya que lanza la tarea que agrupa las consultas.
+pub struct ReputationChecker {
+    settings: ReputationSettings,
+    cache: Mutex<LruCache<IpAddr, CachedScore>>,
+    queue: mpsc::UnboundedSender<(IpAddr, oneshot::Sender<LookupResult>)>,
+    unavailable_log: LogThrottle,
+}
+
+impl ReputationChecker {
+    pub fn new(settings: ReputationSettings, provider: Arc<dyn ReputationProvider>) -> Self {
+        let (queue, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_batcher(
+            rx,
+            provider,
+            settings.batch_window(),
+            settings.max_batch(),
+            settings.timeout(),
+        ));
+        let size = NonZeroUsize::new(settings.cache_size()).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            settings,
+            cache: Mutex::new(LruCache::new(size)),
+            queue,
+            unavailable_log: LogThrottle::new(Duration::from_secs(10)),
+        }
+    }
+
+    pub fn settings(&self) -> &ReputationSettings {
+        &self.settings
+    }
+
+    /// IPs en caché cuya puntuación lleva a una acción distinta de `allow`,
+    /// de la más a la menos puntuada.
+    pub fn listed(&self) -> Vec<ListedIp> {
+        let now = Instant::now();
+        let cache = self.cache.lock().expect("lock de caché de reputación");
+        let mut listed: Vec<ListedIp> = cache
+            .iter()
+            .filter(|(_, cached)| cached.expires > now)
+            .filter_map(|(ip, cached)| {
+                let score = cached.score?;
+                let action = self.settings.action_for(score);
+                (action != ReputationAction::Allow).then_some(ListedIp {
+                    ip: *ip,
+                    score,
+                    action,
+                    expires_in: cached.expires - now,
+                })
+            })
+            .collect();
+        listed.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.ip.cmp(&b.ip)));
+        listed
+    }
+
+    /// Devuelve un veredicto por cada IP, en el mismo orden.
+    pub async fn check(&self, ips: &[IpAddr]) -> Vec<Verdict> {
+        let mut verdicts = Vec::with_capacity(ips.len());
+        let mut pending = Vec::new();
+        {
+            let now = Instant::now();
+            let mut cache = self.cache.lock().expect("lock de caché de reputación");
+            for &ip in ips {
+                match cache.get(&ip) {
+                    Some(cached) if cached.expires > now => {
+                        verdicts.push(Some(self.verdict(ip, cached.score, VerdictSource::Cache)));
+                    }
+                    _ => {
+                        let (tx, rx) = oneshot::channel();
+                        // The batcher lives as long as the checker, so the
+                        // send only fails during shutdown.
+                        let _ = self.queue.send((ip, tx));
+                        pending.push((verdicts.len(), ip, rx));
+                        verdicts.push(None);
+                    }
+                }
+            }
+        }
+
+        for (slot, ip, rx) in pending {
+            let verdict = match rx.await.unwrap_or(Err(Unavailable)) {
+                Ok(score) => {
+                    self.cache.lock().expect("lock de caché de reputación").put(
+                        ip,
+                        CachedScore {
+                            score,
+                            expires: Instant::now() + self.settings.cache_ttl(),
+                        },
+                    );
+                    self.verdict(ip, score, VerdictSource::Feed)
+                }
+                Err(Unavailable) => self.unavailable(ip),
+            };
+            verdicts[slot] = Some(verdict);
+        }
+        verdicts.into_iter().flatten().collect()
+    }
+
+    fn verdict(&self, ip: IpAddr, score: Option<f64>, source: VerdictSource) -> Verdict {
+        let action = score
+            .map(|score| self.settings.action_for(score))
+            .unwrap_or(ReputationAction::Allow);
+        Verdict {
+            ip,
+            score,
+            action,
+            source,
+        }
+    }
+
+    fn unavailable(&self, ip: IpAddr) -> Verdict {
+        let action = if self.settings.fail_open() {
+            ReputationAction::Allow
+        } else {
+            ReputationAction::Block
+        };
+        if let Some(suppressed) = self.unavailable_log.should_log() {
+            warn!(
+                %ip,
+                action = action.as_str(),
+                suppressed,
+                "Servicio de reputación no disponible"
+            );
+        }
+        Verdict {
+            ip,
+            score: None,
+            action,
+            source: VerdictSource::Unavailable,
+        }
+    }
+}
+
+/// Junta las IPs pedidas durante `window` (o hasta `max_batch` distintas) y
+/// las consulta en un solo lote.
+async fn run_batcher(
+    mut rx: mpsc::UnboundedReceiver<(IpAddr, oneshot::Sender<LookupResult>)>,
+    provider: Arc<dyn ReputationProvider>,
+    window: Duration,
+    max_batch: usize,
+    timeout: Duration,
+) {
+    while let Some((ip, tx)) = rx.recv().await {
+        let mut waiters: HashMap<IpAddr, Vec<oneshot::Sender<LookupResult>>> = HashMap::new();
+        waiters.entry(ip).or_default().push(tx);
+
+        let deadline = tokio::time::sleep(window);
+        tokio::pin!(deadline);
+        while waiters.len() < max_batch {
+            tokio::select! {
+                _ = &mut deadline => break,
+                next = rx.recv() => match next {
+                    Some((ip, tx)) => waiters.entry(ip).or_default().push(tx),
+                    None => break,
+                },
+            }
+        }
+
+        let ips: Vec<IpAddr> = waiters.keys().copied().collect();
+        let lookup = tokio::time::timeout(timeout, provider.lookup(ips));
+        // Slow batches must not hold back the next window.
+        tokio::spawn(async move {
+            let scores = match lookup.await {
+                Ok(Ok(scores)) => Ok(scores),
+                Ok(Err(e)) => {
+                    debug!(error = %e, "Consulta de reputación fallida");
+                    Err(Unavailable)
+                }
+                Err(_) => {
+                    debug!(?timeout, "Consulta de reputación sin respuesta a tiempo");
+                    Err(Unavailable)
+                }
+            };
+            for (ip, senders) in waiters {
+                let result = scores
+                    .as_ref()
+                    .map(|scores| scores.get(&ip).copied())
+                    .map_err(|_| Unavailable);
+                for tx in senders {
+                    let _ = tx.send(result);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    #[derive(Default)]
+    struct MockProvider {
+        scores: HashMap<IpAddr, f64>,
+        delay: Option<Duration>,
+        fail: bool,
+        calls: Mutex<Vec<Vec<IpAddr>>>,
+    }
+
+    impl MockProvider {
+        fn with_scores(scores: &[(&str, f64)]) -> Self {
+            Self {
+                scores: scores
+                    .iter()
+                    .map(|(ip, score)| (ip.parse().unwrap(), *score))
+                    .collect(),
+                ..Self::default()
+            }
+        }
+
+        fn calls(&self) -> Vec<Vec<IpAddr>> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl ReputationProvider for MockProvider {
+        fn lookup(
+            &self,
+            mut ips: Vec<IpAddr>,
+        ) -> BoxFuture<'static, anyhow::Result<HashMap<IpAddr, f64>>> {
+            ips.sort();
+            self.calls.lock().unwrap().push(ips.clone());
+            let scores = self.scores.clone();
+            let delay = self.delay;
+            let fail = self.fail;
+            Box::pin(async move {
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                }
+                if fail {
+                    anyhow::bail!("feed caído");
+                }
+                Ok(ips
+                    .into_iter()
+                    .filter_map(|ip| scores.get(&ip).map(|s| (ip, *s)))
+                    .collect())
+            })
+        }
+    }
+
+    fn settings() -> ReputationSettings {
+        ReputationSettings::new("http://reputation.test/")
+            .with_batch_window(Duration::from_millis(20))
+            .with_threshold(50.0, ReputationAction::Log)
+            .with_threshold(80.0, ReputationAction::Block)
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_thresholds_map_scores_to_actions() {
+        let provider = Arc::new(MockProvider::with_scores(&[
+            ("10.0.0.1", 10.0),
+            ("10.0.0.2", 50.0),
+            ("10.0.0.3", 95.0),
+        ]));
+        let checker = ReputationChecker::new(settings(), provider);
+        let ips = [
+            ip("10.0.0.1"),
+            ip("10.0.0.2"),
+            ip("10.0.0.3"),
+            ip("10.0.0.4"),
+        ];
+        let actions: Vec<_> = checker
+            .check(&ips)
+            .await
+            .into_iter()
+            .map(|v| v.action)
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ReputationAction::Allow,
+                ReputationAction::Log,
+                ReputationAction::Block,
+                ReputationAction::Allow,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_and_expiry() {
+        let provider = Arc::new(MockProvider::with_scores(&[("10.0.0.1", 90.0)]));
+        let checker = ReputationChecker::new(
+            settings().with_cache_ttl(Duration::from_millis(150)),
+            provider.clone(),
+        );
+
+        let first = checker.check(&[ip("10.0.0.1")]).await[0];
+        assert_eq!(first.source, VerdictSource::Feed);
+        let second = checker.check(&[ip("10.0.0.1")]).await[0];
+        assert_eq!(second.source, VerdictSource::Cache);
+        assert_eq!(second.action, ReputationAction::Block);
+        assert_eq!(provider.calls().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let third = checker.check(&[ip("10.0.0.1")]).await[0];
+        assert_eq!(third.source, VerdictSource::Feed);
+        assert_eq!(provider.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_checks_are_batched() {
+        let provider = Arc::new(MockProvider::default());
+        let checker = ReputationChecker::new(settings(), provider.clone());
+
+        let (first, second, third) = (
+            [ip("10.0.0.1")],
+            [ip("10.0.0.2"), ip("10.0.0.1")],
+            [ip("10.0.0.3")],
+        );
+        let (a, b, c) = tokio::join!(
+            checker.check(&first),
+            checker.check(&second),
+            checker.check(&third),
+        );
+        assert_eq!((a.len(), b.len(), c.len()), (1, 2, 1));
+        assert_eq!(
+            provider.calls(),
+            [vec![ip("10.0.0.1"), ip("10.0.0.2"), ip("10.0.0.3")]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_batch_splits_lookups() {
+        let provider = Arc::new(MockProvider::default());
+        let checker = ReputationChecker::new(settings().with_max_batch(2), provider.clone());
+        checker
+            .check(&[ip("10.0.0.1"), ip("10.0.0.2"), ip("10.0.0.3")])
+            .await;
+        assert_eq!(provider.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_feed_fails_open_or_closed() {
+        let provider = Arc::new(MockProvider {
+            fail: true,
+            ..MockProvider::default()
+        });
+        let open = ReputationChecker::new(settings(), provider.clone());
+        let verdict = open.check(&[ip("10.0.0.1")]).await[0];
+        assert_eq!(verdict.source, VerdictSource::Unavailable);
+        assert_eq!(verdict.action, ReputationAction::Allow);
+
+        let closed = ReputationChecker::new(settings().with_fail_open(false), provider.clone());
+        let verdict = closed.check(&[ip("10.0.0.1")]).await[0];
+        assert_eq!(verdict.action, ReputationAction::Block);
+
+        // Failures are not cached, so the feed is asked again.
+        open.check(&[ip("10.0.0.1")]).await;
+        assert_eq!(provider.calls().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_slow_feed_times_out() {
+        let provider = Arc::new(MockProvider {
+            delay: Some(Duration::from_secs(5)),
+            ..MockProvider::with_scores(&[("10.0.0.1", 99.0)])
+        });
+        let checker =
+            ReputationChecker::new(settings().with_timeout(Duration::from_millis(50)), provider);
+        let started = Instant::now();
+        let verdict = checker.check(&[ip("10.0.0.1")]).await[0];
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(verdict.source, VerdictSource::Unavailable);
+        assert_eq!(verdict.action, ReputationAction::Allow);
+    }
+
+    #[tokio::test]
+    async fn test_http_provider_parses_scores() {
+        use hyper::service::{make_service_fn, service_fn};
+
+        let make = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(request["ips"], serde_json::json!(["192.0.2.7"]));
+                Ok::<_, Infallible>(hyper::Response::new(Body::from(
+                    r#"{"scores": {"192.0.2.7": 73.5}}"#,
+                )))
+            }))
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let provider = HttpReputationProvider::new(&format!("http://{addr}/v1/lookup")).unwrap();
+        let scores = provider.lookup(vec![ip("192.0.2.7")]).await.unwrap();
+        assert_eq!(scores[&ip("192.0.2.7")], 73.5);
+        assert!(HttpReputationProvider::new("https://reputation.test/").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Id de cada petición, para seguirla del cliente al destino.
+//!
+//! Al entrar en `handle_request` cada petición recibe un id: el que trae en
+//! `X-Request-Id` (o la cabecera de `[request_id]`) si es válido y
+//! `trust_incoming` lo permite, o un UUID v4 nuevo. El id va en el span de
+//! la petición, así que lo llevan todas sus líneas de log (también las del
+//! túnel de un `CONNECT`), sale hacia el destino en esa misma cabecera y
+//! vuelve al cliente en la respuesta, incluidas las de error que genera el
+//! proxy.
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response};
+
+use crate::settings::RequestIdSettings;
+
+/// Mayor id del cliente que se acepta.
+const MAX_LEN: usize = 128;
+
+/// El id asignado, en las extensiones de la petición.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+pub struct RequestIds {
+    settings: RequestIdSettings,
+}
+
+impl RequestIds {
+    pub fn new(settings: RequestIdSettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn header(&self) -> &HeaderName {
+        self.settings.header()
+    }
+
+    /// Da un id a `req`: lo deja en su cabecera, para el destino, y en sus
+    /// extensiones.
+    pub fn assign(&self, req: &mut Request<Body>) -> String {
+        let header = self.settings.header();
+        let id = req
+            .headers()
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| self.settings.trust_incoming() && well_formed(id))
+            .map(str::to_string)
+            .unwrap_or_else(new_id);
+        req.headers_mut().insert(
+            header.clone(),
+            HeaderValue::from_str(&id).expect("id de petición válido"),
+        );
+        req.extensions_mut().insert(RequestId(id.clone()));
+        id
+    }
+
+    /// Devuelve el id al cliente.
+    pub fn annotate(&self, id: &str, response: &mut Response<Body>) {
+        response.headers_mut().insert(
+            self.settings.header().clone(),
+            HeaderValue::from_str(id).expect("id de petición válido"),
+        );
+    }
+}
+
+/// Si `id` sirve como id de petición: de 1 a 128 caracteres entre letras,
+/// dígitos y `-_.:`.
+pub fn well_formed(id: &str) -> bool {
+    (1..=MAX_LEN).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// UUID v4 aleatorio (RFC 9562).
+pub fn new_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incoming_ids_are_kept_only_when_trusted_and_well_formed() {
+        let id = new_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!("89ab".contains(&id[19..20]));
+        assert_ne!(new_id(), id);
+
+        let request = |id: &str| {
+            Request::get("http://example.test/")
+                .header("x-request-id", id)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let ids = RequestIds::new(RequestIdSettings::default());
+        let mut req = request("pedido-42");
+        assert_eq!(ids.assign(&mut req), "pedido-42");
+        assert_eq!(
+            req.extensions().get::<RequestId>(),
+            Some(&RequestId("pedido-42".to_string()))
+        );
+        let mut req = request("no vale");
+        let id = ids.assign(&mut req);
+        assert_eq!(id.len(), 36);
+        assert_eq!(req.headers()["x-request-id"], id.as_str());
+
+        let ids = RequestIds::new(RequestIdSettings::default().with_trust_incoming(false));
+        assert_ne!(ids.assign(&mut request("pedido-42")), "pedido-42");
+    }
+}
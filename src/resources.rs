@@ -0,0 +1,283 @@
+//! Límite de descriptores del proceso.
+//!
+//! Los contenedores arrancan a menudo con `ulimit -n 1024`, que se queda
+//! corto en cuanto hay unos cientos de clientes: cada conexión ocupa un
+//! descriptor del cliente y otro del destino. Al arrancar se lee
+//! `RLIMIT_NOFILE` y se compara con lo que pide la configuración
+//! (`expected_connections` de `[resources]`, los listeners y una reserva
+//! para logs, DNS y archivos); si no alcanza, un `warn` muestra la cuenta.
+//! Con `--raise-nofile` (o `raise_nofile = true`) el límite blando sube
+//! antes hasta el duro. `GET /api/stats` muestra los límites y los
+//! descriptores abiertos. Un `EMFILE` al aceptar pausa la aceptación un
+//! momento en lugar de reintentar en bucle, y se cuenta aparte.
+
+use std::fmt;
+use std::io;
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::settings::ResourceSettings;
+
+/// Descriptores que se reservan para logs, DNS, archivos y demás.
+pub const RESERVED_FDS: usize = 64;
+
+/// Descriptores de una conexión de cliente: el suyo y el del destino.
+pub const FDS_PER_CONNECTION: usize = 2;
+
+/// `RLIMIT_NOFILE`; `None` donde es ilimitado.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NofileLimit {
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+#[cfg(target_os = "linux")]
+fn from_rlim(value: libc::rlim_t) -> Option<u64> {
+    (value != libc::RLIM_INFINITY).then_some(value)
+}
+
+/// El límite actual; `None` si la plataforma no permite leerlo.
+pub fn nofile_limit() -> Option<NofileLimit> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: `limit` is a live local the kernel fills in.
+        let result = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+        (result == 0).then(|| NofileLimit {
+            soft: from_rlim(limit.rlim_cur),
+            hard: from_rlim(limit.rlim_max),
+        })
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Sube el límite blando hasta el duro y devuelve el nuevo límite.
+pub fn raise_nofile() -> io::Result<NofileLimit> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: both calls only read or write `limit`, a live local.
+        let result = unsafe {
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 {
+                limit.rlim_cur = limit.rlim_max;
+                libc::setrlimit(libc::RLIMIT_NOFILE, &limit)
+            } else {
+                -1
+            }
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NofileLimit {
+            soft: from_rlim(limit.rlim_cur),
+            hard: from_rlim(limit.rlim_max),
+        })
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "RLIMIT_NOFILE solo se ajusta en Linux",
+        ))
+    }
+}
+
+/// Descriptores abiertos por el proceso (solo Linux).
+pub fn open_fds() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|entries| entries.count())
+}
+
+/// Si `error` es el de quedarse sin descriptores, del proceso o del sistema.
+pub fn is_fd_exhaustion(error: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = error;
+        false
+    }
+}
+
+/// Lo que la configuración va a necesitar abrir a la vez.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    pub connections: usize,
+    pub listeners: usize,
+}
+
+impl Capacity {
+    pub fn required(&self) -> usize {
+        self.connections * FDS_PER_CONNECTION + self.listeners + RESERVED_FDS
+    }
+
+    /// Conexiones simultáneas que caben en `limit` descriptores.
+    pub fn connections_within(limit: u64, listeners: usize) -> u64 {
+        limit.saturating_sub((listeners + RESERVED_FDS) as u64) / FDS_PER_CONNECTION as u64
+    }
+}
+
+/// Un límite que no alcanza para `capacity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shortfall {
+    pub capacity: Capacity,
+    pub limit: u64,
+}
+
+impl fmt::Display for Shortfall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Capacity {
+            connections,
+            listeners,
+        } = self.capacity;
+        write!(
+            f,
+            "{connections} conexiones × {FDS_PER_CONNECTION} + {listeners} listeners + \
+             {RESERVED_FDS} de reserva = {} descriptores, y el límite es {}",
+            self.capacity.required(),
+            self.limit
+        )
+    }
+}
+
+/// `Err` si `limit` (`None` es ilimitado) no alcanza para `capacity`.
+pub fn check(capacity: Capacity, limit: Option<u64>) -> Result<(), Shortfall> {
+    match limit {
+        Some(limit) if (capacity.required() as u64) > limit => Err(Shortfall { capacity, limit }),
+        _ => Ok(()),
+    }
+}
+
+/// Límites y uso actuales, tal como los muestra `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResourceSnapshot {
+    pub nofile: Option<NofileLimit>,
+    pub open_fds: Option<usize>,
+    pub expected_connections: Option<usize>,
+    /// Descriptores que pide la configuración, con `expected_connections`.
+    pub required_fds: Option<usize>,
+    /// Si el límite blando se subió al arrancar.
+    pub raised: bool,
+}
+
+pub struct ResourceMonitor {
+    settings: ResourceSettings,
+    listeners: usize,
+    raised: bool,
+}
+
+impl ResourceMonitor {
+    /// Sube el límite si se pidió y avisa si no alcanza para la
+    /// configuración; se llama antes de abrir nada.
+    pub fn start(settings: ResourceSettings, listeners: usize) -> Self {
+        let mut limit = nofile_limit();
+        let mut raised = false;
+        if settings.raise_nofile() {
+            match limit {
+                Some(current) if current.soft == current.hard => {}
+                _ => match raise_nofile() {
+                    Ok(new) => {
+                        info!(
+                            from = limit.and_then(|limit| limit.soft),
+                            to = new.soft,
+                            "Límite blando de descriptores elevado hasta el duro"
+                        );
+                        limit = Some(new);
+                        raised = true;
+                    }
+                    Err(e) => warn!(error = %e, "No se pudo elevar el límite de descriptores"),
+                },
+            }
+        }
+        let monitor = Self {
+            settings,
+            listeners,
+            raised,
+        };
+        let Some(limit) = limit else {
+            return monitor;
+        };
+        if let Some(connections) = monitor.settings.expected_connections() {
+            let capacity = Capacity {
+                connections,
+                listeners,
+            };
+            if let Err(shortfall) = check(capacity, limit.soft) {
+                // The soft limit never exceeds the hard one.
+                let hint = if limit.soft != limit.hard {
+                    "suba `ulimit -n` o arranque con --raise-nofile"
+                } else {
+                    "suba el límite duro del contenedor o del servicio"
+                };
+                warn!(
+                    soft = limit.soft,
+                    hard = limit.hard,
+                    "Límite de descriptores insuficiente: {shortfall}; {hint}"
+                );
+                return monitor;
+            }
+        }
+        info!(
+            soft = limit.soft,
+            hard = limit.hard,
+            connections = limit
+                .soft
+                .map(|soft| Capacity::connections_within(soft, listeners)),
+            "Límite de descriptores"
+        );
+        monitor
+    }
+
+    pub fn snapshot(&self) -> ResourceSnapshot {
+        let expected = self.settings.expected_connections();
+        ResourceSnapshot {
+            nofile: nofile_limit(),
+            open_fds: open_fds(),
+            expected_connections: expected,
+            required_fds: expected.map(|connections| {
+                Capacity {
+                    connections,
+                    listeners: self.listeners,
+                }
+                .required()
+            }),
+            raised: self.raised,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_shows_the_math_of_the_shortfall() {
+        let capacity = Capacity {
+            connections: 500,
+            listeners: 2,
+        };
+        assert_eq!(capacity.required(), 1066);
+        assert_eq!(check(capacity, Some(1066)), Ok(()));
+        assert_eq!(check(capacity, None), Ok(()));
+        let shortfall = check(capacity, Some(1024)).unwrap_err();
+        assert_eq!(
+            shortfall.to_string(),
+            "500 conexiones × 2 + 2 listeners + 64 de reserva = 1066 descriptores, y el límite es 1024"
+        );
+        assert_eq!(Capacity::connections_within(1024, 2), 479);
+        assert_eq!(Capacity::connections_within(10, 2), 0);
+    }
+}
@@ -0,0 +1,535 @@
+//! Caché en memoria de respuestas `GET`/`HEAD` según `Cache-Control`.
+//!
+//! Con `[response_cache]` las respuestas 200 que el destino declara
+//! cacheables (`max-age` o `s-maxage` mayor que cero) se guardan por método y
+//! URI y se sirven desde memoria hasta que caducan, con `X-Cache: HIT` y
+//! `Age`; las que pasan por el destino llevan `X-Cache: MISS`. No se guarda
+//! nada con `no-store`, `private`, `no-cache`, `Set-Cookie` o `Vary` (la
+//! clave no distingue variantes), ni las respuestas que generó el propio
+//! proxy. Las peticiones con `Authorization` no pasan por la caché; un
+//! `Cache-Control: no-cache` del cliente la salta y refresca la entrada.
+//!
+//! Para saber el tamaño hay que leer el body: las respuestas que superan
+//! `max_body_bytes` se entregan según llegan y no se guardan. Al llenarse
+//! `max_entries` se descarta la menos usada.
+//!
+//! Con `stale-while-revalidate` o `stale-if-error` (RFC 5861), del destino o
+//! por defecto para su host, la entrada se conserva esa ventana más tras
+//! caducar. Dentro de la primera se sirve al momento con `X-Cache: STALE` y
+//! `Warning: 110` mientras una sola revalidación por entrada, por la cola
+//! `revalidate` del planificador y con un cupo por host, la refresca; dentro
+//! de la segunda se sirve con `Warning: 111` si el destino contesta un 5xx o
+//! no contesta. `must-revalidate` y `proxy-revalidate` las anulan.
+
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, SET_COOKIE, VARY, WARNING};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use lru::LruCache;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::capture::buffer_prefix;
+use crate::metrics::{CacheKind, Metrics};
+use crate::prefetch::Prefetch;
+use crate::settings::{ResponseCacheSettings, StaleDefaults};
+use crate::streaming;
+
+pub const X_CACHE: &str = "x-cache";
+
+/// Motivo por el que se sirvió una copia caducada.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleReason {
+    /// Dentro de `stale-while-revalidate`, mientras se refresca.
+    WhileRevalidate,
+    /// Dentro de `stale-if-error`, porque el destino falló.
+    IfError,
+}
+
+impl StaleReason {
+    pub const ALL: [StaleReason; 2] = [StaleReason::WhileRevalidate, StaleReason::IfError];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StaleReason::WhileRevalidate => "while_revalidate",
+            StaleReason::IfError => "if_error",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// Códigos de aviso de RFC 7234 §5.5.
+    fn warning(&self) -> &'static str {
+        match self {
+            StaleReason::WhileRevalidate => "110 - \"Response is Stale\"",
+            StaleReason::IfError => "111 - \"Revalidation Failed\"",
+        }
+    }
+}
+
+/// Marca de las peticiones que revalidan una entrada: van siempre al
+/// destino.
+#[derive(Debug, Clone, Copy)]
+pub struct Revalidating;
+
+/// Respuesta guardada.
+struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+    /// `Age` que ya traía del destino.
+    age: Duration,
+    while_revalidate: Duration,
+    if_error: Duration,
+    /// Guardada por una precarga y aún no servida a ningún cliente.
+    prefetched: AtomicBool,
+}
+
+impl Stored {
+    fn response(&self, stale: Option<StaleReason>) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        let age = (self.age + self.stored.elapsed()).as_secs();
+        let headers = response.headers_mut();
+        headers.insert(AGE, HeaderValue::from(age));
+        match stale {
+            Some(reason) => {
+                headers.insert(X_CACHE, HeaderValue::from_static("STALE"));
+                headers.append(WARNING, HeaderValue::from_static(reason.warning()));
+            }
+            None => {
+                headers.insert(X_CACHE, HeaderValue::from_static("HIT"));
+            }
+        }
+        response
+    }
+
+    /// Hasta cuándo puede servirse de algún modo.
+    fn usable_until(&self) -> Instant {
+        self.expires + self.while_revalidate.max(self.if_error)
+    }
+}
+
+/// Resultado de buscar una petición en la caché.
+pub enum Lookup {
+    /// Copia vigente.
+    Fresh(Response<Body>),
+    /// Copia caducada dentro de `stale-while-revalidate`, que se sirve ya.
+    /// Con `Some`, a esta petición le toca lanzar la revalidación.
+    Stale(Response<Body>, Option<Revalidation>),
+    /// Hay que ir al destino; con `Some`, la copia que se sirve si falla.
+    Miss(Option<Fallback>),
+}
+
+/// Copia caducada dentro de `stale-if-error`.
+pub struct Fallback(Arc<Stored>);
+
+impl Fallback {
+    pub fn serve(self, metrics: &Metrics) -> Response<Body> {
+        metrics.record_stale_served(StaleReason::IfError);
+        self.0.response(Some(StaleReason::IfError))
+    }
+}
+
+/// Revalidaciones en curso, por entrada y por host.
+#[derive(Default)]
+struct InFlight {
+    keys: HashSet<String>,
+    hosts: HashMap<String, usize>,
+}
+
+/// Turno de revalidar una entrada; lo devuelve al soltarse.
+pub struct Revalidation {
+    in_flight: Arc<Mutex<InFlight>>,
+    key: String,
+    host: String,
+}
+
+impl Drop for Revalidation {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().expect("lock de revalidaciones");
+        in_flight.keys.remove(&self.key);
+        if let Some(running) = in_flight.hosts.get_mut(&self.host) {
+            *running -= 1;
+            if *running == 0 {
+                in_flight.hosts.remove(&self.host);
+            }
+        }
+    }
+}
+
+/// Estado de la caché para `GET /api/stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CacheSnapshot {
+    pub entries: usize,
+    pub revalidating: usize,
+    pub max_revalidations_per_host: usize,
+}
+
+pub struct ResponseCache {
+    settings: ResponseCacheSettings,
+    entries: Mutex<LruCache<String, Arc<Stored>>>,
+    in_flight: Arc<Mutex<InFlight>>,
+}
+
+impl ResponseCache {
+    pub fn new(settings: ResponseCacheSettings) -> Self {
+        let size = NonZeroUsize::new(settings.max_entries()).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            settings,
+            entries: Mutex::new(LruCache::new(size)),
+            in_flight: Arc::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot {
+            entries: self
+                .entries
+                .lock()
+                .expect("lock de la caché de respuestas")
+                .len(),
+            revalidating: self
+                .in_flight
+                .lock()
+                .expect("lock de revalidaciones")
+                .keys
+                .len(),
+            max_revalidations_per_host: self.settings.max_revalidations_per_host(),
+        }
+    }
+
+    /// Clave de `req` si la petición puede pasar por la caché.
+    pub fn key(&self, req: &Request<Body>) -> Option<String> {
+        let method = req.method();
+        if (method != Method::GET && method != Method::HEAD)
+            || req.headers().contains_key(AUTHORIZATION)
+            || directives(req.headers()).any(|d| d == "no-store")
+        {
+            return None;
+        }
+        Some(format!("{method} {}", req.uri()))
+    }
+
+    /// Busca `key`, salvo que el cliente pida refrescarla o la petición sea
+    /// ella misma una revalidación.
+    pub fn lookup(&self, key: &str, req: &Request<Body>, metrics: &Metrics) -> Lookup {
+        if directives(req.headers()).any(|d| d == "no-cache")
+            || req.extensions().get::<Revalidating>().is_some()
+        {
+            return Lookup::Miss(None);
+        }
+        let now = Instant::now();
+        let stored = {
+            let mut entries = self.entries.lock().expect("lock de la caché de respuestas");
+            match entries.get(key) {
+                Some(stored) if stored.usable_until() > now => Some(stored.clone()),
+                Some(_) => {
+                    entries.pop(key);
+                    None
+                }
+                None => None,
+            }
+        };
+        let lookup = match stored {
+            Some(stored) if stored.expires > now => {
+                if req.extensions().get::<Prefetch>().is_none()
+                    && stored.prefetched.swap(false, Ordering::Relaxed)
+                {
+                    metrics.record_prefetch_hit();
+                }
+                Lookup::Fresh(stored.response(None))
+            }
+            Some(stored) if stored.expires + stored.while_revalidate > now => {
+                let host = req.uri().host().unwrap_or_default();
+                let revalidation = self.revalidation(key, host);
+                if revalidation.is_none() {
+                    debug!(%key, "Copia caducada servida sin revalidar: ya se revalida o el host está en su cupo");
+                }
+                metrics.record_stale_served(StaleReason::WhileRevalidate);
+                Lookup::Stale(
+                    stored.response(Some(StaleReason::WhileRevalidate)),
+                    revalidation,
+                )
+            }
+            Some(stored) if stored.expires + stored.if_error > now => {
+                Lookup::Miss(Some(Fallback(stored)))
+            }
+            _ => Lookup::Miss(None),
+        };
+        metrics.record_cache_lookup(CacheKind::Response, !matches!(lookup, Lookup::Miss(_)));
+        lookup
+    }
+
+    /// Turno para revalidar `key`, si nadie la está revalidando ya y `host`
+    /// no tiene su cupo lleno.
+    fn revalidation(&self, key: &str, host: &str) -> Option<Revalidation> {
+        let mut in_flight = self.in_flight.lock().expect("lock de revalidaciones");
+        let running = in_flight.hosts.get(host).copied().unwrap_or(0);
+        if in_flight.keys.contains(key) || running >= self.settings.max_revalidations_per_host() {
+            return None;
+        }
+        in_flight.keys.insert(key.to_string());
+        in_flight.hosts.insert(host.to_string(), running + 1);
+        Some(Revalidation {
+            in_flight: self.in_flight.clone(),
+            key: key.to_string(),
+            host: host.to_string(),
+        })
+    }
+
+    /// Marca `response` como `MISS` y la guarda si el destino lo permite.
+    /// `prefetched` indica que la pidió una precarga y no un cliente.
+    pub async fn store(
+        &self,
+        key: String,
+        host: &str,
+        prefetched: bool,
+        mut response: Response<Body>,
+    ) -> Response<Body> {
+        response
+            .headers_mut()
+            .insert(X_CACHE, HeaderValue::from_static("MISS"));
+        // Retaining a streamed body would hold it back from the client.
+        let defaults = self.settings.stale_for(host);
+        let Some(freshness) =
+            freshness(&response, defaults).filter(|_| !streaming::is_streamed(&response))
+        else {
+            return response;
+        };
+        let (parts, body) = response.into_parts();
+        let (bytes, truncated, body) = buffer_prefix(body, self.settings.max_body()).await;
+        // A body cut short by an error has no exact size: not worth keeping.
+        let complete = body.size_hint().exact() == Some(bytes.len() as u64);
+        if truncated || !complete {
+            debug!(%key, "Respuesta no guardada en caché: body demasiado grande o incompleto");
+            return Response::from_parts(parts, body);
+        }
+        let age = Duration::from_secs(header_secs(parts.headers.get(AGE)).unwrap_or(0));
+        if let Some(left) = freshness
+            .ttl
+            .checked_sub(age)
+            .filter(|left| !left.is_zero())
+        {
+            let mut headers = parts.headers.clone();
+            headers.remove(X_CACHE);
+            let stored = Instant::now();
+            self.entries
+                .lock()
+                .expect("lock de la caché de respuestas")
+                .put(
+                    key,
+                    Arc::new(Stored {
+                        status: parts.status,
+                        headers,
+                        body: Bytes::from(bytes),
+                        stored,
+                        expires: stored + left,
+                        age,
+                        while_revalidate: freshness.while_revalidate,
+                        if_error: freshness.if_error,
+                        prefetched: AtomicBool::new(prefetched),
+                    }),
+                );
+        }
+        Response::from_parts(parts, body)
+    }
+}
+
+/// Cuánto puede servirse una respuesta desde la caché.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Freshness {
+    ttl: Duration,
+    while_revalidate: Duration,
+    if_error: Duration,
+}
+
+/// Tiempo que `response` puede servirse desde la caché, si puede, y sus
+/// ventanas de RFC 5861; las que no declara salen de `defaults`.
+fn freshness(response: &Response<Body>, defaults: Option<&StaleDefaults>) -> Option<Freshness> {
+    let headers = response.headers();
+    if response.status() != StatusCode::OK
+        || headers.contains_key(SET_COOKIE)
+        || headers.contains_key(VARY)
+        || headers.contains_key("x-proxy-error")
+    {
+        return None;
+    }
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    let mut while_revalidate = None;
+    let mut if_error = None;
+    let mut must_revalidate = false;
+    for directive in directives(headers) {
+        let secs = |value: &str| value.trim_matches('"').parse().ok();
+        match directive.split_once('=') {
+            Some(("max-age", value)) => max_age = secs(value),
+            Some(("s-maxage", value)) => shared_max_age = secs(value),
+            Some(("stale-while-revalidate", value)) => while_revalidate = secs(value),
+            Some(("stale-if-error", value)) => if_error = secs(value),
+            _ if matches!(directive.as_str(), "no-store" | "private" | "no-cache") => return None,
+            _ if matches!(directive.as_str(), "must-revalidate" | "proxy-revalidate") => {
+                must_revalidate = true
+            }
+            _ => {}
+        }
+    }
+    // The proxy is a shared cache: `s-maxage` wins (RFC 9111 §5.2.2.10).
+    let ttl = shared_max_age
+        .or(max_age)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)?;
+    let window = |declared: Option<u64>, default: fn(&StaleDefaults) -> Duration| match (
+        must_revalidate,
+        declared,
+    ) {
+        (true, _) => Duration::ZERO,
+        (false, Some(secs)) => Duration::from_secs(secs),
+        (false, None) => defaults.map_or(Duration::ZERO, default),
+    };
+    Some(Freshness {
+        ttl,
+        while_revalidate: window(while_revalidate, StaleDefaults::while_revalidate),
+        if_error: window(if_error, StaleDefaults::if_error),
+    })
+}
+
+/// Directivas de `Cache-Control`, en minúsculas y sin espacios.
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .filter(|directive| !directive.is_empty())
+}
+
+fn header_secs(value: Option<&HeaderValue>) -> Option<u64> {
+    value?.to_str().ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)]) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        for (name, value) in headers {
+            response.headers_mut().append(
+                hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        response
+    }
+
+    #[test]
+    fn test_freshness_follows_cache_control() {
+        let secs = |headers: &[(&str, &str)]| {
+            freshness(&response(headers), None).map(|fresh| fresh.ttl.as_secs())
+        };
+        assert_eq!(secs(&[("cache-control", "public, max-age=60")]), Some(60));
+        assert_eq!(
+            secs(&[
+                ("cache-control", "max-age=60"),
+                ("cache-control", "s-maxage=5")
+            ]),
+            Some(5)
+        );
+        assert_eq!(secs(&[]), None);
+        assert_eq!(secs(&[("cache-control", "max-age=0")]), None);
+        assert_eq!(secs(&[("cache-control", "max-age=60, No-Store")]), None);
+        assert_eq!(secs(&[("cache-control", "private, max-age=60")]), None);
+        assert_eq!(
+            secs(&[("cache-control", "max-age=60"), ("set-cookie", "s=1")]),
+            None
+        );
+        assert_eq!(
+            secs(&[("cache-control", "max-age=60"), ("vary", "accept-encoding")]),
+            None
+        );
+        let mut not_found = response(&[("cache-control", "max-age=60")]);
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        assert_eq!(freshness(&not_found, None), None);
+
+        let cache = ResponseCache::new(ResponseCacheSettings::default());
+        let req = |method: Method, header: Option<(&str, &str)>| {
+            let mut req = Request::builder().method(method).uri("http://a.test/x.js");
+            if let Some((name, value)) = header {
+                req = req.header(name, value);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        assert_eq!(
+            cache.key(&req(Method::GET, None)).as_deref(),
+            Some("GET http://a.test/x.js")
+        );
+        assert!(cache.key(&req(Method::POST, None)).is_none());
+        assert!(cache
+            .key(&req(Method::GET, Some(("authorization", "Bearer x"))))
+            .is_none());
+    }
+
+    #[test]
+    fn test_stale_windows_come_from_the_origin_or_the_host_default() {
+        let windows = |headers: &[(&str, &str)], defaults: Option<&StaleDefaults>| {
+            freshness(&response(headers), defaults)
+                .map(|fresh| (fresh.while_revalidate.as_secs(), fresh.if_error.as_secs()))
+        };
+        let defaults = StaleDefaults::new("a.test".parse().unwrap())
+            .with_while_revalidate(Duration::from_secs(10))
+            .with_if_error(Duration::from_secs(300));
+        assert_eq!(
+            windows(&[("cache-control", "max-age=60")], None),
+            Some((0, 0))
+        );
+        assert_eq!(
+            windows(&[("cache-control", "max-age=60")], Some(&defaults)),
+            Some((10, 300))
+        );
+        assert_eq!(
+            windows(
+                &[("cache-control", "max-age=60, stale-while-revalidate=5")],
+                Some(&defaults)
+            ),
+            Some((5, 300))
+        );
+        assert_eq!(
+            windows(
+                &[("cache-control", "max-age=60, stale-if-error=\"20\"")],
+                None
+            ),
+            Some((0, 20))
+        );
+        assert_eq!(
+            windows(
+                &[(
+                    "cache-control",
+                    "max-age=60, proxy-revalidate, stale-if-error=20"
+                )],
+                Some(&defaults)
+            ),
+            Some((0, 0))
+        );
+        // Without freshness there is nothing to serve stale.
+        assert_eq!(
+            windows(&[("cache-control", "stale-while-revalidate=5")], None),
+            None
+        );
+
+        let settings = ResponseCacheSettings::default().with_stale(defaults.clone());
+        assert_eq!(settings.stale_for("a.test"), Some(&defaults));
+        assert_eq!(settings.stale_for("b.test"), None);
+    }
+}
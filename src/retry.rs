@@ -0,0 +1,151 @@
+//! Reintentos de las peticiones idempotentes cuando el destino falla.
+//!
+//! Con `--retry-attempts` mayor que 1, una petición `GET`, `HEAD` u
+//! `OPTIONS` que no llega a conectar, cuya conexión se corta antes de la
+//! respuesta o que recibe un 502, 503 o 504 se vuelve a enviar, hasta ese
+//! número de intentos en total. Entre uno y otro se espera `base_delay`,
+//! doblándolo en cada reintento (con un tope de [`MAX_DELAY`]) y con una
+//! variación aleatoria para que los clientes no reintenten a la vez. Agotados
+//! los intentos el cliente recibe la última respuesta del destino, o un 502
+//! que dice cuántas veces se intentó.
+//!
+//! Los demás métodos no se reintentan nunca. Para reenviar una petición hace
+//! falta su body entero: se guarda uno de hasta [`MAX_REPLAY_BODY`] bytes; uno
+//! mayor se envía una sola vez.
+
+use std::time::Duration;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, HeaderMap, Method, Request, StatusCode, Uri, Version};
+
+use crate::capture::buffer_prefix;
+use crate::error::ProxyError;
+use crate::settings::RetrySettings;
+
+/// Mayor body que se guarda para poder reenviar la petición.
+pub const MAX_REPLAY_BODY: usize = 64 * 1024;
+
+/// Espera máxima entre dos intentos.
+pub const MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Si una petición con `method` puede reintentarse.
+pub fn applies(settings: &RetrySettings, method: &Method) -> bool {
+    settings.attempts() > 1 && matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Espera antes del reintento número `retry` (desde 1); `jitter` en `[0, 1)`
+/// la deja entre la mitad y el total.
+pub fn backoff(base: Duration, retry: u32, jitter: f64) -> Duration {
+    let full = base
+        .saturating_mul(1 << retry.saturating_sub(1).min(16))
+        .min(MAX_DELAY);
+    full.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+pub fn retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Fallos en los que el destino no llegó a responder.
+pub fn retryable_error(kind: ProxyError) -> bool {
+    matches!(kind, ProxyError::Connect | ProxyError::Reset)
+}
+
+/// Copia de una petición para volver a enviarla.
+pub struct Replay {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Replay {
+    /// Guarda `req` si su body cabe en [`MAX_REPLAY_BODY`]; la petición que
+    /// devuelve lleva el mismo body que la original.
+    pub async fn capture(req: Request<Body>) -> (Request<Body>, Option<Replay>) {
+        let (parts, body) = req.into_parts();
+        if body.is_end_stream() {
+            let replay = Replay::new(&parts, Bytes::new());
+            return (Request::from_parts(parts, body), Some(replay));
+        }
+        let (bytes, truncated, body) = buffer_prefix(body, MAX_REPLAY_BODY).await;
+        // A body cut short by an error cannot be sent again as it was.
+        let complete = body.size_hint().exact() == Some(bytes.len() as u64);
+        let replay = (!truncated && complete).then(|| Replay::new(&parts, Bytes::from(bytes)));
+        (Request::from_parts(parts, body), replay)
+    }
+
+    fn new(parts: &hyper::http::request::Parts, body: Bytes) -> Self {
+        Self {
+            method: parts.method.clone(),
+            uri: parts.uri.clone(),
+            version: parts.version,
+            headers: parts.headers.clone(),
+            body,
+        }
+    }
+
+    /// Petición nueva igual a la original.
+    pub fn request(&self) -> Request<Body> {
+        let mut req = Request::new(Body::from(self.body.clone()));
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = self.headers.clone();
+        req
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_with_jitter_and_caps() {
+        let base = Duration::from_millis(100);
+        assert_eq!(backoff(base, 1, 1.0), Duration::from_millis(100));
+        assert_eq!(backoff(base, 1, 0.0), Duration::from_millis(50));
+        assert_eq!(backoff(base, 3, 1.0), Duration::from_millis(400));
+        assert_eq!(backoff(base, 40, 1.0), MAX_DELAY);
+
+        let settings = RetrySettings::default();
+        assert!(!applies(&settings, &Method::GET), "one attempt by default");
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_small_bodies_only() {
+        let req = |body: Vec<u8>| {
+            Request::builder()
+                .method(Method::GET)
+                .uri("http://a.test/x")
+                .header("x-a", "1")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let (req_back, replay) = Replay::capture(req(b"hola".to_vec())).await;
+        let replay = replay.expect("small body");
+        assert_eq!(
+            hyper::body::to_bytes(req_back.into_body()).await.unwrap(),
+            "hola"
+        );
+        let again = replay.request();
+        assert_eq!(again.uri(), "http://a.test/x");
+        assert_eq!(again.headers()["x-a"], "1");
+        assert_eq!(
+            hyper::body::to_bytes(again.into_body()).await.unwrap(),
+            "hola"
+        );
+
+        let large = vec![7u8; MAX_REPLAY_BODY + 1];
+        let (req_back, replay) = Replay::capture(req(large.clone())).await;
+        assert!(replay.is_none());
+        assert_eq!(
+            hyper::body::to_bytes(req_back.into_body()).await.unwrap(),
+            large
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Activación gradual de features por cliente.
+//!
+//! Cada cliente se reparte en 10 000 buckets con un hash estable de la
+//! feature y su sujeto (el usuario de [`Identity`] o, si no hay, la IP). Una
+//! feature al 10 % queda activa para los buckets `0..1000`, de modo que el
+//! mismo cliente obtiene siempre la misma decisión y al ampliar el rollout
+//! nadie la pierde. La configuración se reemplaza en caliente con
+//! [`Rollouts::reload`].
+
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use hyper::{Body, Request};
+use tracing::{debug, info};
+
+use crate::identity::Identity;
+use crate::metrics::Metrics;
+use crate::settings::{Feature, RolloutSettings};
+
+const BUCKETS: u64 = 10_000;
+
+pub struct Rollouts {
+    settings: RwLock<RolloutSettings>,
+}
+
+impl Rollouts {
+    pub fn new(settings: RolloutSettings) -> Self {
+        Self {
+            settings: RwLock::new(settings),
+        }
+    }
+
+    pub fn reload(&self, settings: RolloutSettings) {
+        let mut current = self.settings.write().expect("rollouts");
+        if *current != settings {
+            info!("Rollouts actualizados");
+            *current = settings;
+        }
+    }
+
+    /// Decide si `feature` se aplica a `subject` y lo cuenta en `metrics`.
+    pub fn enabled(&self, feature: Feature, subject: &str, metrics: &Metrics) -> bool {
+        let enabled = self.decide(feature, subject);
+        metrics.record_feature_exposure(feature, enabled);
+        enabled
+    }
+
+    /// Igual que [`Rollouts::enabled`], sin contar la decisión.
+    pub fn decide(&self, feature: Feature, subject: &str) -> bool {
+        let settings = self.settings.read().expect("rollouts");
+        match settings.get(feature) {
+            None => true,
+            Some(rollout) => {
+                let bucket = bucket(feature, subject);
+                let enabled = rollout.include().iter().any(|s| s == subject)
+                    || (bucket as f64) < rollout.percent() * (BUCKETS as f64) / 100.0;
+                debug!(
+                    feature = feature.as_str(),
+                    subject,
+                    bucket,
+                    percent = rollout.percent(),
+                    enabled,
+                    "Decisión de rollout"
+                );
+                enabled
+            }
+        }
+    }
+
+    /// Igual que [`Rollouts::enabled`], tomando el sujeto de la petición.
+    pub fn enabled_for(
+        &self,
+        feature: Feature,
+        req: &Request<Body>,
+        client: IpAddr,
+        metrics: &Metrics,
+    ) -> bool {
+        match req.extensions().get::<Identity>() {
+            Some(identity) => self.enabled(feature, identity.user(), metrics),
+            None => self.enabled(feature, &client.to_string(), metrics),
+        }
+    }
+
+    /// Igual que [`Rollouts::enabled_for`], sin contar la decisión: para
+    /// informar al cliente sin alterar las exposiciones.
+    pub fn preview_for(&self, feature: Feature, req: &Request<Body>, client: IpAddr) -> bool {
+        match req.extensions().get::<Identity>() {
+            Some(identity) => self.decide(feature, identity.user()),
+            None => self.decide(feature, &client.to_string()),
+        }
+    }
+}
+
+/// FNV-1a: a hash that must not change between builds or Rust releases,
+/// otherwise every client would be reshuffled on upgrade.
+fn bucket(feature: Feature, subject: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let input = feature.as_str().bytes().chain([0]).chain(subject.bytes());
+    for byte in input {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash % BUCKETS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::FeatureRollout;
+
+    fn rollouts(percent: f64) -> Rollouts {
+        Rollouts::new(RolloutSettings::default().with_feature(
+            Feature::Capture,
+            FeatureRollout::new(percent).with_include("qa"),
+        ))
+    }
+
+    #[test]
+    fn test_decision_is_sticky() {
+        let metrics = Metrics::default();
+        let rollouts = rollouts(50.0);
+        for i in 0..200 {
+            let subject = format!("10.1.{}.{}", i / 256, i % 256);
+            let first = rollouts.enabled(Feature::Capture, &subject, &metrics);
+            for _ in 0..5 {
+                assert_eq!(
+                    rollouts.enabled(Feature::Capture, &subject, &metrics),
+                    first
+                );
+            }
+        }
+        assert!(rollouts.enabled(Feature::Capture, "qa", &metrics));
+        // Features without a rollout are on for everyone.
+        assert!(rollouts.enabled(Feature::Script, "10.1.0.1", &metrics));
+    }
+
+    #[test]
+    fn test_population_split_and_widening() {
+        let metrics = Metrics::default();
+        let rollouts = rollouts(10.0);
+        let clients: Vec<String> = (0..20_000)
+            .map(|i| format!("10.{}.{}.{}", i / 65_536, (i / 256) % 256, i % 256))
+            .collect();
+        let enabled: Vec<bool> = clients
+            .iter()
+            .map(|c| rollouts.enabled(Feature::Capture, c, &metrics))
+            .collect();
+        let share = enabled.iter().filter(|e| **e).count() as f64 / clients.len() as f64;
+        assert!((0.09..0.11).contains(&share), "{share}");
+        let (on, off) = metrics.feature_exposures(Feature::Capture);
+        assert_eq!(on + off, clients.len() as u64);
+
+        // Widening the rollout keeps everyone who already had the feature.
+        rollouts.reload(
+            RolloutSettings::default().with_feature(Feature::Capture, FeatureRollout::new(30.0)),
+        );
+        let mut widened = 0;
+        for (client, before) in clients.iter().zip(&enabled) {
+            let now = rollouts.enabled(Feature::Capture, client, &metrics);
+            assert!(now || !before, "{client} perdió la feature");
+            widened += usize::from(now);
+        }
+        let share = widened as f64 / clients.len() as f64;
+        assert!((0.28..0.32).contains(&share), "{share}");
+    }
+}
@@ -0,0 +1,457 @@
+//! Scripts Rhai para decisiones de enrutamiento y filtrado.
+//!
+//! El script puede definir dos funciones, ambas opcionales:
+//!
+//! - `filter(req)` devuelve `"allow"`, `"deny"` o `#{ action: "deny", reason: "..." }`.
+//! - `route(req)` devuelve `"direct"` o `#{ upstream: "host:puerto" }` para
+//!   conectar con otro destino manteniendo el `Host` original.
+//!
+//! `req` es un mapa de solo lectura con `method`, `host`, `port`, `path`,
+//! `client_ip`, `headers` (nombres en minúscula) y `time`
+//! (`unix`, `hour`, `minute`, `weekday` con 0 = domingo, todo en UTC).
+//!
+//! Cada llamada está limitada en operaciones y en tiempo; si se supera el
+//! límite o el script falla, la petición se deja pasar o se bloquea según
+//! `fail_closed`.
+
+use std::cell::Cell;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use hyper::http::uri::Authority;
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use tracing::{info, warn};
+
+use crate::log_throttle::LogThrottle;
+use crate::meta::RequestMeta;
+use crate::settings::ScriptSettings;
+
+/// Resultado de `filter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Allow,
+    Deny { reason: Option<String> },
+}
+
+/// Resultado de `route`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteDecision {
+    Direct,
+    Upstream(Authority),
+    /// Solo se produce cuando el script falla con `fail_closed` activo.
+    Block,
+}
+
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Script compilado y listo para evaluarse desde varias conexiones.
+pub struct ScriptHost {
+    settings: ScriptSettings,
+    engine: Engine,
+    ast: RwLock<Arc<AST>>,
+    modified: Mutex<Option<SystemTime>>,
+    errors: LogThrottle,
+}
+
+impl ScriptHost {
+    /// Compila el script; un error de sintaxis impide arrancar.
+    pub fn load(settings: ScriptSettings) -> anyhow::Result<Self> {
+        let engine = build_engine(&settings);
+        let (ast, modified) = compile(&engine, &settings)?;
+        info!(path = %settings.path().display(), "Script cargado");
+        Ok(Self {
+            settings,
+            engine,
+            ast: RwLock::new(Arc::new(ast)),
+            modified: Mutex::new(modified),
+            errors: LogThrottle::new(Duration::from_secs(10)),
+        })
+    }
+
+    pub fn settings(&self) -> &ScriptSettings {
+        &self.settings
+    }
+
+    /// Recompila el script si el archivo cambió desde la última carga. Si la
+    /// nueva versión no compila se conserva la anterior.
+    pub fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let current = file_modified(&self.settings);
+        if current == *self.modified.lock().expect("lock de mtime") {
+            return Ok(false);
+        }
+        let (ast, modified) = compile(&self.engine, &self.settings)?;
+        *self.ast.write().expect("lock del script") = Arc::new(ast);
+        *self.modified.lock().expect("lock de mtime") = modified;
+        info!(path = %self.settings.path().display(), "Script recargado");
+        Ok(true)
+    }
+
+    pub fn filter(&self, meta: &RequestMeta) -> FilterDecision {
+        match self.call("filter", meta).and_then(parse_filter) {
+            Ok(Some(decision)) => decision,
+            Ok(None) => FilterDecision::Allow,
+            Err(e) => {
+                self.log_error("filter", &e);
+                if self.settings.fail_closed() {
+                    FilterDecision::Deny {
+                        reason: Some("error en el script".to_string()),
+                    }
+                } else {
+                    FilterDecision::Allow
+                }
+            }
+        }
+    }
+
+    pub fn route(&self, meta: &RequestMeta) -> RouteDecision {
+        match self.call("route", meta).and_then(parse_route) {
+            Ok(Some(decision)) => decision,
+            Ok(None) => RouteDecision::Direct,
+            Err(e) => {
+                self.log_error("route", &e);
+                if self.settings.fail_closed() {
+                    RouteDecision::Block
+                } else {
+                    RouteDecision::Direct
+                }
+            }
+        }
+    }
+
+    fn call(&self, entry: &str, meta: &RequestMeta) -> anyhow::Result<Option<Dynamic>> {
+        let ast = self.ast.read().expect("lock del script").clone();
+        if !ast.iter_functions().any(|f| f.name == entry) {
+            return Ok(None);
+        }
+
+        let deadline = Instant::now() + self.settings.timeout();
+        DEADLINE.with(|d| d.set(Some(deadline)));
+        let mut scope = Scope::new();
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new().eval_ast(false),
+            &mut scope,
+            &ast,
+            entry,
+            (Dynamic::from_map(request_map(meta)),),
+        );
+        DEADLINE.with(|d| d.set(None));
+
+        result.map(Some).map_err(|e| anyhow!("{e}"))
+    }
+
+    fn log_error(&self, entry: &str, err: &anyhow::Error) {
+        if let Some(suppressed) = self.errors.should_log() {
+            warn!(
+                entry,
+                error = %err,
+                suppressed,
+                fail_closed = self.settings.fail_closed(),
+                "Error ejecutando script"
+            );
+        }
+    }
+}
+
+fn build_engine(settings: &ScriptSettings) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(settings.max_operations());
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(64 * 1024);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+    engine.on_print(|text| tracing::debug!(target: "script", "{text}"));
+    engine.on_debug(|text, _, _| tracing::debug!(target: "script", "{text}"));
+    engine.on_progress(|ops| {
+        // Reading the clock on every operation would dominate small scripts.
+        if ops % 256 != 0 {
+            return None;
+        }
+        let expired = DEADLINE.with(|d| d.get().is_some_and(|dl| Instant::now() > dl));
+        expired.then(|| Dynamic::from("tiempo de ejecución agotado"))
+    });
+    engine
+}
+
+fn compile(
+    engine: &Engine,
+    settings: &ScriptSettings,
+) -> anyhow::Result<(AST, Option<SystemTime>)> {
+    let modified = file_modified(settings);
+    let source = std::fs::read_to_string(settings.path())
+        .with_context(|| format!("No se pudo leer {}", settings.path().display()))?;
+    let ast = engine
+        .compile(source)
+        .map_err(|e| anyhow!("Script inválido {}: {e}", settings.path().display()))?;
+    Ok((ast, modified))
+}
+
+fn file_modified(settings: &ScriptSettings) -> Option<SystemTime> {
+    std::fs::metadata(settings.path())
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+fn request_map(meta: &RequestMeta) -> Map {
+    let mut headers = Map::new();
+    for (name, value) in &meta.headers {
+        headers.insert(name.as_str().into(), value.clone().into());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let mut time = Map::new();
+    time.insert("unix".into(), now.into());
+    time.insert("hour".into(), ((now / 3600) % 24).into());
+    time.insert("minute".into(), ((now / 60) % 60).into());
+    // 1970-01-01 was a Thursday.
+    time.insert("weekday".into(), ((now / 86_400 + 4) % 7).into());
+
+    let mut map = Map::new();
+    map.insert("method".into(), meta.method.clone().into());
+    map.insert("host".into(), meta.host.clone().into());
+    map.insert(
+        "port".into(),
+        meta.port
+            .map(|p| Dynamic::from(p as i64))
+            .unwrap_or(Dynamic::UNIT),
+    );
+    map.insert("path".into(), meta.path.clone().into());
+    map.insert("client_ip".into(), meta.client_ip.to_string().into());
+    map.insert("headers".into(), Dynamic::from_map(headers));
+    map.insert("time".into(), Dynamic::from_map(time));
+    map
+}
+
+fn parse_filter(value: Option<Dynamic>) -> anyhow::Result<Option<FilterDecision>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let (action, map) = action_of(value)?;
+    match action.as_str() {
+        "allow" => Ok(Some(FilterDecision::Allow)),
+        "deny" => {
+            let reason = map
+                .and_then(|m| m.get("reason").cloned())
+                .and_then(|r| r.into_string().ok());
+            Ok(Some(FilterDecision::Deny { reason }))
+        }
+        other => Err(anyhow!("filter devolvió una acción desconocida: {other}")),
+    }
+}
+
+fn parse_route(value: Option<Dynamic>) -> anyhow::Result<Option<RouteDecision>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    if value.is_map() {
+        let map = value.cast::<Map>();
+        if let Some(upstream) = map.get("upstream") {
+            let upstream = upstream
+                .clone()
+                .into_string()
+                .map_err(|t| anyhow!("upstream debe ser texto, no {t}"))?;
+            let authority = upstream
+                .parse::<Authority>()
+                .map_err(|e| anyhow!("upstream inválido {upstream:?}: {e}"))?;
+            return Ok(Some(RouteDecision::Upstream(authority)));
+        }
+        return parse_route_action(&map_action(&map)?);
+    }
+    let action = value
+        .into_string()
+        .map_err(|t| anyhow!("route debe devolver texto o mapa, no {t}"))?;
+    parse_route_action(&action)
+}
+
+fn parse_route_action(action: &str) -> anyhow::Result<Option<RouteDecision>> {
+    match action {
+        "direct" => Ok(Some(RouteDecision::Direct)),
+        other => Err(anyhow!("route devolvió una acción desconocida: {other}")),
+    }
+}
+
+fn action_of(value: Dynamic) -> anyhow::Result<(String, Option<Map>)> {
+    if value.is_map() {
+        let map = value.cast::<Map>();
+        let action = map_action(&map)?;
+        Ok((action, Some(map)))
+    } else {
+        let action = value
+            .into_string()
+            .map_err(|t| anyhow!("se esperaba texto o mapa, no {t}"))?;
+        Ok((action, None))
+    }
+}
+
+fn map_action(map: &Map) -> anyhow::Result<String> {
+    map.get("action")
+        .cloned()
+        .ok_or_else(|| anyhow!("el mapa devuelto no tiene `action`"))?
+        .into_string()
+        .map_err(|t| anyhow!("`action` debe ser texto, no {t}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    struct TempScript {
+        file: tempfile::NamedTempFile,
+        path: std::path::PathBuf,
+        bumps: Cell<u64>,
+    }
+
+    impl TempScript {
+        fn rewrite(&self, source: &str) {
+            let mut file = self.file.reopen().unwrap();
+            file.set_len(0).unwrap();
+            file.write_all(source.as_bytes()).unwrap();
+            // Move the mtime forward explicitly so coarse filesystem
+            // timestamps can't hide a change.
+            self.bumps.set(self.bumps.get() + 1);
+            file.set_modified(SystemTime::now() + Duration::from_secs(self.bumps.get()))
+                .unwrap();
+        }
+    }
+
+    fn write_script(source: &str) -> TempScript {
+        let file = tempfile::Builder::new().suffix(".rhai").tempfile().unwrap();
+        let path = file.path().to_path_buf();
+        let script = TempScript {
+            file,
+            path,
+            bumps: Cell::new(0),
+        };
+        script.rewrite(source);
+        script
+    }
+
+    fn meta(host: &str, path: &str) -> RequestMeta {
+        RequestMeta {
+            method: "GET".into(),
+            host: host.into(),
+            port: None,
+            path: path.into(),
+            client_ip: "10.0.0.7".parse().unwrap(),
+            headers: vec![("x-team".into(), "blue".into())],
+        }
+    }
+
+    #[test]
+    fn test_routing_script() {
+        let script = write_script(
+            r#"
+            fn route(req) {
+                if req.path.contains("/api/") && req.headers["x-team"] == "blue" {
+                    return #{ upstream: "127.0.0.1:9000" };
+                }
+                "direct"
+            }
+            "#,
+        );
+        let host = ScriptHost::load(ScriptSettings::new(&script.path)).unwrap();
+        assert_eq!(
+            host.route(&meta("example.com", "/api/users")),
+            RouteDecision::Upstream("127.0.0.1:9000".parse().unwrap())
+        );
+        assert_eq!(
+            host.route(&meta("example.com", "/static/app.js")),
+            RouteDecision::Direct
+        );
+        // No filter() defined: everything is allowed.
+        assert_eq!(
+            host.filter(&meta("example.com", "/")),
+            FilterDecision::Allow
+        );
+    }
+
+    #[test]
+    fn test_filter_script() {
+        let script = write_script(
+            r#"
+            fn filter(req) {
+                if req.host.ends_with(".bloqueado.test") {
+                    return #{ action: "deny", reason: "dominio bloqueado" };
+                }
+                if req.client_ip == "10.0.0.7" && req.time.hour >= 0 {
+                    return "allow";
+                }
+                "deny"
+            }
+            "#,
+        );
+        let host = ScriptHost::load(ScriptSettings::new(&script.path)).unwrap();
+        assert_eq!(
+            host.filter(&meta("ads.bloqueado.test", "/")),
+            FilterDecision::Deny {
+                reason: Some("dominio bloqueado".into())
+            }
+        );
+        assert_eq!(
+            host.filter(&meta("example.com", "/")),
+            FilterDecision::Allow
+        );
+    }
+
+    #[test]
+    fn test_instruction_limit_aborts() {
+        let script = write_script("fn filter(req) { loop { } }");
+        let open =
+            ScriptHost::load(ScriptSettings::new(&script.path).with_max_operations(1_000)).unwrap();
+        assert_eq!(open.filter(&meta("a.test", "/")), FilterDecision::Allow);
+
+        let closed = ScriptHost::load(
+            ScriptSettings::new(&script.path)
+                .with_max_operations(1_000)
+                .with_fail_closed(true),
+        )
+        .unwrap();
+        assert!(matches!(
+            closed.filter(&meta("a.test", "/")),
+            FilterDecision::Deny { .. }
+        ));
+    }
+
+    #[test]
+    fn test_time_limit_aborts() {
+        let script = write_script("fn route(req) { loop { } }");
+        let host = ScriptHost::load(
+            ScriptSettings::new(&script.path)
+                .with_max_operations(0)
+                .with_timeout(Duration::from_millis(20))
+                .with_fail_closed(true),
+        )
+        .unwrap();
+        let started = Instant::now();
+        assert_eq!(host.route(&meta("a.test", "/")), RouteDecision::Block);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_reload_keeps_previous_on_error() {
+        let script = write_script(r#"fn filter(req) { "deny" }"#);
+        let host = ScriptHost::load(ScriptSettings::new(&script.path)).unwrap();
+        assert!(matches!(
+            host.filter(&meta("a.test", "/")),
+            FilterDecision::Deny { .. }
+        ));
+
+        script.rewrite("fn filter(req) { ");
+        assert!(host.reload_if_changed().is_err());
+        assert!(matches!(
+            host.filter(&meta("a.test", "/")),
+            FilterDecision::Deny { .. }
+        ));
+
+        script.rewrite(r#"fn filter(req) { "allow" }"#);
+        assert!(host.reload_if_changed().unwrap());
+        assert_eq!(host.filter(&meta("a.test", "/")), FilterDecision::Allow);
+    }
+}
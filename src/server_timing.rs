@@ -0,0 +1,39 @@
+//! Cabecera `Server-Timing` con los tiempos del proxy.
+//!
+//! Para los hosts y clientes de `[server_timing]`, la respuesta lleva una
+//! cabecera `Server-Timing` más con las etapas de la petición: `dns` y
+//! `connect` hacia el destino, `ttfb` hasta recibir sus cabeceras y `total`
+//! desde que llegó la petición. Los valores salen de la misma [`Timeline`]
+//! que guardan las capturas; una etapa que se repite (por ejemplo, la
+//! resolución de la reputación y la del dialer) se suma. Las cabeceras
+//! `Server-Timing` del destino se conservan delante de la del proxy.
+
+use std::collections::BTreeMap;
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+use crate::capture::Timeline;
+
+pub const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+/// Métrica de `Server-Timing` para cada etapa de la timeline que se expone.
+const METRICS: [(&str, &str); 3] = [("dns", "dns"), ("connect", "connect"), ("upstream", "ttfb")];
+
+/// Añade la cabecera del proxy con las etapas anotadas hasta ahora.
+pub fn append(timeline: &Timeline, headers: &mut HeaderMap) {
+    let mut durations: BTreeMap<usize, f64> = BTreeMap::new();
+    for event in timeline.events() {
+        if let Some(order) = METRICS.iter().position(|(stage, _)| *stage == event.stage) {
+            *durations.entry(order).or_default() += event.duration_ms;
+        }
+    }
+    let mut metrics: Vec<String> = durations
+        .into_iter()
+        .map(|(order, ms)| format!("{};dur={ms:.1}", METRICS[order].1))
+        .collect();
+    let total = timeline.elapsed().as_secs_f64() * 1000.0;
+    metrics.push(format!("total;dur={total:.1}"));
+    let value = HeaderValue::from_str(&metrics.join(", ")).expect("métricas ASCII");
+    headers.append(SERVER_TIMING, value);
+}
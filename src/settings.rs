@@ -1,16 +1,6027 @@
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cidr::Cidr;
+use crate::host_filter::HostRule;
+use crate::host_pattern::HostPattern;
+use crate::nat64::Nat64Prefix;
+use crate::report::Schedule;
 
 #[derive(Debug, Clone)]
 pub struct ProxySettings {
     listen: SocketAddr,
+    listen_uds: Option<UnixListenSettings>,
+    script: Option<ScriptSettings>,
+    connection_limits: ConnectionLimits,
+    protocols: ListenerProtocols,
+    hardening: ListenerHardening,
+    reputation: Option<ReputationSettings>,
+    admin_listen: Option<SocketAddr>,
+    admin_token: Option<AdminToken>,
+    file_config: Option<serde_json::Value>,
+    blocklist_file: Option<PathBuf>,
+    metrics_listen: Option<SocketAddr>,
+    tls: Option<TlsSettings>,
+    mitm: Option<MitmSettings>,
+    cert_expiry: CertExpirySettings,
+    port_mapping: Option<PortMappingSettings>,
+    access_log: Option<AccessLogTarget>,
+    capture: Option<CaptureSettings>,
+    archive: Option<ArchiveSettings>,
+    dial: DialSettings,
+    client_pool: ClientPoolSettings,
+    credentials: Vec<CredentialSettings>,
+    providers: Vec<ProviderSettings>,
+    signing: Vec<SigningSettings>,
+    identity: Option<IdentitySettings>,
+    affinity: Option<AffinitySettings>,
+    stats: Option<StatsSettings>,
+    report: Option<ReportSettings>,
+    pac: Option<PacSettings>,
+    upstream_proxy: Option<UpstreamProxySettings>,
+    upstream_socks5: Option<Socks5UpstreamSettings>,
+    canary: Option<CanarySettings>,
+    data_saver: Option<DataSaverSettings>,
+    rollout: RolloutSettings,
+    expect_continue: ExpectContinue,
+    dns: DnsSettings,
+    ban: Option<BanSettings>,
+    overload: Option<OverloadSettings>,
+    rate_limit: Option<RateLimit>,
+    concurrency: Option<ConcurrencyLimits>,
+    trailer_fallback: TrailerFallback,
+    bandwidth: Option<BandwidthSettings>,
+    egress: EgressSettings,
+    llm: Option<LlmSettings>,
+    ai_router: Option<AiRouterSettings>,
+    token_budget: Option<TokenBudgetSettings>,
+    debug_headers: bool,
+    content_filters: Option<ContentFilterSettings>,
+    request_id: RequestIdSettings,
+    sniff: Option<SniffSettings>,
+    idempotency: Option<IdempotencySettings>,
+    response_cache: Option<ResponseCacheSettings>,
+    prefetch: Option<PrefetchSettings>,
+    jobs: JobSettings,
+    fast_path: Vec<HostPattern>,
+    error_pages: Option<ErrorPageSettings>,
+    server_timing: Option<ServerTimingSettings>,
+    metadata_echo: Option<MetadataEchoSettings>,
+    redirect_maps: Vec<PathBuf>,
+    proxy_info: bool,
+    serve_pac: Option<ServePacSettings>,
+    transparent: Option<TransparentSettings>,
+    anonymous: bool,
+    log_profile: LogProfile,
+    nat64: Option<Nat64Settings>,
+    tunnel_quality: Option<TunnelQualitySettings>,
+    verify_sni: Option<VerifySniSettings>,
+    header_limits: Option<HeaderLimits>,
+    auth: Option<AuthSettings>,
+    tickets: Option<TicketSettings>,
+    events: Option<EventSettings>,
+    host_filter: Option<HostFilterSettings>,
+    header_rules: Vec<HeaderRule>,
+    resources: ResourceSettings,
+    drain: DrainSettings,
+    drain_timeout: Duration,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    max_request_body: Option<u64>,
+    max_response_body: Option<u64>,
+    http1_only: bool,
+    upstream_tls: UpstreamTlsSettings,
+    connect_ports: Vec<u16>,
+    throttle: ThrottleSettings,
+    retry: RetrySettings,
+    ssrf: SsrfSettings,
 }
 
 impl ProxySettings {
+    pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+    pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+    pub const DEFAULT_CONNECT_PORTS: [u16; 1] = [443];
+
     pub fn new(listen: SocketAddr) -> Self {
-        Self { listen }
+        Self {
+            listen,
+            listen_uds: None,
+            script: None,
+            connection_limits: ConnectionLimits::default(),
+            protocols: ListenerProtocols::default(),
+            hardening: ListenerHardening::default(),
+            reputation: None,
+            admin_listen: None,
+            metrics_listen: None,
+            tls: None,
+            mitm: None,
+            cert_expiry: CertExpirySettings::default(),
+            port_mapping: None,
+            access_log: None,
+            admin_token: None,
+            file_config: None,
+            blocklist_file: None,
+            capture: None,
+            archive: None,
+            dial: DialSettings::default(),
+            client_pool: ClientPoolSettings::default(),
+            credentials: Vec::new(),
+            providers: Vec::new(),
+            signing: Vec::new(),
+            identity: None,
+            affinity: None,
+            stats: None,
+            report: None,
+            pac: None,
+            upstream_proxy: None,
+            upstream_socks5: None,
+            canary: None,
+            data_saver: None,
+            rollout: RolloutSettings::default(),
+            expect_continue: ExpectContinue::default(),
+            dns: DnsSettings::default(),
+            ban: None,
+            overload: None,
+            rate_limit: None,
+            concurrency: None,
+            trailer_fallback: TrailerFallback::default(),
+            bandwidth: None,
+            egress: EgressSettings::default(),
+            llm: None,
+            ai_router: None,
+            token_budget: None,
+            debug_headers: false,
+            content_filters: None,
+            request_id: RequestIdSettings::default(),
+            sniff: None,
+            idempotency: None,
+            response_cache: None,
+            prefetch: None,
+            jobs: JobSettings::default(),
+            fast_path: Vec::new(),
+            error_pages: None,
+            server_timing: None,
+            metadata_echo: None,
+            redirect_maps: Vec::new(),
+            proxy_info: true,
+            serve_pac: None,
+            transparent: None,
+            anonymous: false,
+            log_profile: LogProfile::default(),
+            nat64: None,
+            tunnel_quality: None,
+            verify_sni: None,
+            header_limits: None,
+            auth: None,
+            tickets: None,
+            events: None,
+            host_filter: None,
+            header_rules: Vec::new(),
+            resources: ResourceSettings::default(),
+            drain: DrainSettings::default(),
+            drain_timeout: Self::DEFAULT_DRAIN_TIMEOUT,
+            connect_timeout: Some(Self::DEFAULT_CONNECT_TIMEOUT),
+            max_request_body: None,
+            max_response_body: None,
+            http1_only: false,
+            upstream_tls: UpstreamTlsSettings::default(),
+            request_timeout: Some(Self::DEFAULT_REQUEST_TIMEOUT),
+            connect_ports: Self::DEFAULT_CONNECT_PORTS.to_vec(),
+            throttle: ThrottleSettings::default(),
+            retry: RetrySettings::default(),
+            ssrf: SsrfSettings::default(),
+        }
+    }
+
+    pub fn with_listen(mut self, listen: SocketAddr) -> Self {
+        self.listen = listen;
+        self
     }
 
     pub fn listen(&self) -> SocketAddr {
         self.listen
     }
+
+    /// Socket UNIX en el que se escucha además de `listen`.
+    pub fn with_listen_uds(mut self, uds: UnixListenSettings) -> Self {
+        self.listen_uds = Some(uds);
+        self
+    }
+
+    pub fn listen_uds(&self) -> Option<&UnixListenSettings> {
+        self.listen_uds.as_ref()
+    }
+
+    pub fn with_script(mut self, script: ScriptSettings) -> Self {
+        self.script = Some(script);
+        self
+    }
+
+    pub fn script(&self) -> Option<&ScriptSettings> {
+        self.script.as_ref()
+    }
+
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = limits;
+        self
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        self.connection_limits
+    }
+
+    pub fn with_protocols(mut self, protocols: ListenerProtocols) -> Self {
+        self.protocols = protocols;
+        self
+    }
+
+    pub fn protocols(&self) -> ListenerProtocols {
+        self.protocols
+    }
+
+    pub fn with_hardening(mut self, hardening: ListenerHardening) -> Self {
+        self.hardening = hardening;
+        self
+    }
+
+    pub fn hardening(&self) -> ListenerHardening {
+        self.hardening
+    }
+
+    pub fn with_reputation(mut self, reputation: ReputationSettings) -> Self {
+        self.reputation = Some(reputation);
+        self
+    }
+
+    pub fn reputation(&self) -> Option<&ReputationSettings> {
+        self.reputation.as_ref()
+    }
+
+    /// Dirección del listener de administración, separado del proxy.
+    pub fn with_admin_listen(mut self, addr: SocketAddr) -> Self {
+        self.admin_listen = Some(addr);
+        self
+    }
+
+    pub fn admin_listen(&self) -> Option<SocketAddr> {
+        self.admin_listen
+    }
+
+    /// Dirección del listener que sirve `GET /metrics` en formato de
+    /// Prometheus.
+    pub fn with_metrics_listen(mut self, addr: SocketAddr) -> Self {
+        self.metrics_listen = Some(addr);
+        self
+    }
+
+    pub fn metrics_listen(&self) -> Option<SocketAddr> {
+        self.metrics_listen
+    }
+
+    /// Atiende a los clientes sobre TLS; ver [`crate::tls`].
+    pub fn with_tls(mut self, tls: TlsSettings) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn tls(&self) -> Option<&TlsSettings> {
+        self.tls.as_ref()
+    }
+
+    /// Descifra los túneles `CONNECT`; ver [`crate::mitm`].
+    pub fn with_mitm(mut self, mitm: MitmSettings) -> Self {
+        self.mitm = Some(mitm);
+        self
+    }
+
+    pub fn mitm(&self) -> Option<&MitmSettings> {
+        self.mitm.as_ref()
+    }
+
+    /// Umbrales y webhook del aviso de caducidad; ver [`crate::cert_expiry`].
+    pub fn with_cert_expiry(mut self, cert_expiry: CertExpirySettings) -> Self {
+        self.cert_expiry = cert_expiry;
+        self
+    }
+
+    pub fn cert_expiry(&self) -> &CertExpirySettings {
+        &self.cert_expiry
+    }
+
+    /// Pide al router que abra el puerto del listener; ver
+    /// [`crate::port_mapping`].
+    pub fn with_port_mapping(mut self, port_mapping: PortMappingSettings) -> Self {
+        self.port_mapping = Some(port_mapping);
+        self
+    }
+
+    pub fn port_mapping(&self) -> Option<&PortMappingSettings> {
+        self.port_mapping.as_ref()
+    }
+
+    /// Destino del log de acceso en JSON; ver [`crate::access_log`].
+    pub fn with_access_log(mut self, target: AccessLogTarget) -> Self {
+        self.access_log = Some(target);
+        self
+    }
+
+    pub fn access_log(&self) -> Option<&AccessLogTarget> {
+        self.access_log.as_ref()
+    }
+
+    /// Token que exige la API de administración en `Authorization: Bearer`.
+    pub fn with_admin_token(mut self, token: AdminToken) -> Self {
+        self.admin_token = Some(token);
+        self
+    }
+
+    pub fn admin_token(&self) -> Option<&AdminToken> {
+        self.admin_token.as_ref()
+    }
+
+    /// Configuración de archivo combinada, ya redactada, que muestra
+    /// `GET /api/config`.
+    pub fn with_file_config(mut self, config: serde_json::Value) -> Self {
+        self.file_config = Some(config);
+        self
+    }
+
+    pub fn file_config(&self) -> Option<&serde_json::Value> {
+        self.file_config.as_ref()
+    }
+
+    /// JSON donde se guarda la lista de bloqueo de la API de administración;
+    /// ver [`crate::blocklist`].
+    pub fn with_blocklist_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.blocklist_file = Some(path.into());
+        self
+    }
+
+    pub fn blocklist_file(&self) -> Option<&Path> {
+        self.blocklist_file.as_deref()
+    }
+
+    pub fn with_capture(mut self, capture: CaptureSettings) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn capture(&self) -> Option<&CaptureSettings> {
+        self.capture.as_ref()
+    }
+
+    pub fn with_archive(mut self, archive: ArchiveSettings) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    pub fn archive(&self) -> Option<&ArchiveSettings> {
+        self.archive.as_ref()
+    }
+
+    pub fn with_dial(mut self, dial: DialSettings) -> Self {
+        self.dial = dial;
+        self
+    }
+
+    pub fn dial(&self) -> &DialSettings {
+        &self.dial
+    }
+
+    pub fn with_client_pool(mut self, pool: ClientPoolSettings) -> Self {
+        self.client_pool = pool;
+        self
+    }
+
+    pub fn client_pool(&self) -> &ClientPoolSettings {
+        &self.client_pool
+    }
+
+    pub fn with_credential(mut self, credential: CredentialSettings) -> Self {
+        self.credentials.push(credential);
+        self
+    }
+
+    pub fn credentials(&self) -> &[CredentialSettings] {
+        &self.credentials
+    }
+
+    /// Clave de un proveedor de IA que los clientes no tienen.
+    pub fn with_provider(mut self, provider: ProviderSettings) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    pub fn providers(&self) -> &[ProviderSettings] {
+        &self.providers
+    }
+
+    pub fn with_signing(mut self, signing: SigningSettings) -> Self {
+        self.signing.push(signing);
+        self
+    }
+
+    pub fn signing(&self) -> &[SigningSettings] {
+        &self.signing
+    }
+
+    pub fn with_identity(mut self, identity: IdentitySettings) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    pub fn identity(&self) -> Option<&IdentitySettings> {
+        self.identity.as_ref()
+    }
+
+    pub fn with_affinity(mut self, affinity: AffinitySettings) -> Self {
+        self.affinity = Some(affinity);
+        self
+    }
+
+    pub fn affinity(&self) -> Option<&AffinitySettings> {
+        self.affinity.as_ref()
+    }
+
+    pub fn with_stats(mut self, stats: StatsSettings) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn stats(&self) -> Option<&StatsSettings> {
+        self.stats.as_ref()
+    }
+
+    /// Requiere estadísticas persistentes (`with_stats`).
+    pub fn with_report(mut self, report: ReportSettings) -> Self {
+        self.report = Some(report);
+        self
+    }
+
+    pub fn report(&self) -> Option<&ReportSettings> {
+        self.report.as_ref()
+    }
+
+    pub fn with_pac(mut self, pac: PacSettings) -> Self {
+        self.pac = Some(pac);
+        self
+    }
+
+    pub fn pac(&self) -> Option<&PacSettings> {
+        self.pac.as_ref()
+    }
+
+    /// Encamina todo el tráfico por un proxy padre fijo.
+    pub fn with_upstream_proxy(mut self, upstream: UpstreamProxySettings) -> Self {
+        self.upstream_proxy = Some(upstream);
+        self
+    }
+
+    pub fn upstream_proxy(&self) -> Option<&UpstreamProxySettings> {
+        self.upstream_proxy.as_ref()
+    }
+
+    /// Abre las conexiones directas con los destinos a través de un
+    /// servidor SOCKS5.
+    pub fn with_upstream_socks5(mut self, socks5: Socks5UpstreamSettings) -> Self {
+        self.upstream_socks5 = Some(socks5);
+        self
+    }
+
+    pub fn upstream_socks5(&self) -> Option<&Socks5UpstreamSettings> {
+        self.upstream_socks5.as_ref()
+    }
+
+    /// Manda una parte del tráfico a un proxy padre en pruebas.
+    pub fn with_canary(mut self, canary: CanarySettings) -> Self {
+        self.canary = Some(canary);
+        self
+    }
+
+    pub fn canary(&self) -> Option<&CanarySettings> {
+        self.canary.as_ref()
+    }
+
+    /// Requiere la feature `transcoding`.
+    pub fn with_data_saver(mut self, data_saver: DataSaverSettings) -> Self {
+        self.data_saver = Some(data_saver);
+        self
+    }
+
+    pub fn data_saver(&self) -> Option<&DataSaverSettings> {
+        self.data_saver.as_ref()
+    }
+
+    pub fn with_rollout(mut self, rollout: RolloutSettings) -> Self {
+        self.rollout = rollout;
+        self
+    }
+
+    pub fn rollout(&self) -> &RolloutSettings {
+        &self.rollout
+    }
+
+    pub fn with_expect_continue(mut self, expect: ExpectContinue) -> Self {
+        self.expect_continue = expect;
+        self
+    }
+
+    pub fn expect_continue(&self) -> ExpectContinue {
+        self.expect_continue
+    }
+
+    pub fn with_dns(mut self, dns: DnsSettings) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    pub fn dns(&self) -> &DnsSettings {
+        &self.dns
+    }
+
+    pub fn with_ban(mut self, ban: BanSettings) -> Self {
+        self.ban = Some(ban);
+        self
+    }
+
+    pub fn ban(&self) -> Option<&BanSettings> {
+        self.ban.as_ref()
+    }
+
+    pub fn with_overload(mut self, overload: OverloadSettings) -> Self {
+        self.overload = Some(overload);
+        self
+    }
+
+    pub fn overload(&self) -> Option<&OverloadSettings> {
+        self.overload.as_ref()
+    }
+
+    /// Cupo de peticiones por IP de cliente; ver [`crate::rate_limit`].
+    pub fn with_rate_limit(mut self, rate: RateLimit) -> Self {
+        self.rate_limit = Some(rate);
+        self
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
+
+    pub fn with_concurrency(mut self, limits: ConcurrencyLimits) -> Self {
+        self.concurrency = Some(limits);
+        self
+    }
+
+    pub fn concurrency(&self) -> Option<ConcurrencyLimits> {
+        self.concurrency
+    }
+
+    pub fn with_trailer_fallback(mut self, fallback: TrailerFallback) -> Self {
+        self.trailer_fallback = fallback;
+        self
+    }
+
+    pub fn trailer_fallback(&self) -> TrailerFallback {
+        self.trailer_fallback
+    }
+
+    pub fn with_bandwidth(mut self, bandwidth: BandwidthSettings) -> Self {
+        self.bandwidth = Some(bandwidth);
+        self
+    }
+
+    pub fn bandwidth(&self) -> Option<&BandwidthSettings> {
+        self.bandwidth.as_ref()
+    }
+
+    pub fn with_egress(mut self, egress: EgressSettings) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    pub fn egress(&self) -> &EgressSettings {
+        &self.egress
+    }
+
+    pub fn with_llm(mut self, llm: LlmSettings) -> Self {
+        self.llm = Some(llm);
+        self
+    }
+
+    pub fn llm(&self) -> Option<&LlmSettings> {
+        self.llm.as_ref()
+    }
+
+    /// Envía las peticiones de la API de OpenAI que llegan al propio proxy
+    /// al backend de su `model`.
+    pub fn with_ai_router(mut self, router: AiRouterSettings) -> Self {
+        self.ai_router = Some(router);
+        self
+    }
+
+    pub fn ai_router(&self) -> Option<&AiRouterSettings> {
+        self.ai_router.as_ref()
+    }
+
+    /// Tokens que cada cliente puede gastar a través del router IA.
+    pub fn with_token_budget(mut self, budget: TokenBudgetSettings) -> Self {
+        self.token_budget = Some(budget);
+        self
+    }
+
+    pub fn token_budget(&self) -> Option<&TokenBudgetSettings> {
+        self.token_budget.as_ref()
+    }
+
+    /// Cabeceras de depuración en las respuestas, como el `X-Upstream` del
+    /// router IA.
+    pub fn with_debug_headers(mut self, debug_headers: bool) -> Self {
+        self.debug_headers = debug_headers;
+        self
+    }
+
+    pub fn debug_headers(&self) -> bool {
+        self.debug_headers
+    }
+
+    pub fn with_content_filters(mut self, filters: ContentFilterSettings) -> Self {
+        self.content_filters = Some(filters);
+        self
+    }
+
+    pub fn content_filters(&self) -> Option<&ContentFilterSettings> {
+        self.content_filters.as_ref()
+    }
+
+    pub fn with_request_id(mut self, request_id: RequestIdSettings) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn request_id(&self) -> &RequestIdSettings {
+        &self.request_id
+    }
+
+    pub fn with_sniff(mut self, sniff: SniffSettings) -> Self {
+        self.sniff = Some(sniff);
+        self
+    }
+
+    pub fn sniff(&self) -> Option<&SniffSettings> {
+        self.sniff.as_ref()
+    }
+
+    pub fn with_idempotency(mut self, idempotency: IdempotencySettings) -> Self {
+        self.idempotency = Some(idempotency);
+        self
+    }
+
+    pub fn idempotency(&self) -> Option<&IdempotencySettings> {
+        self.idempotency.as_ref()
+    }
+
+    pub fn with_response_cache(mut self, cache: ResponseCacheSettings) -> Self {
+        self.response_cache = Some(cache);
+        self
+    }
+
+    pub fn response_cache(&self) -> Option<&ResponseCacheSettings> {
+        self.response_cache.as_ref()
+    }
+
+    pub fn with_prefetch(mut self, prefetch: PrefetchSettings) -> Self {
+        self.prefetch = Some(prefetch);
+        self
+    }
+
+    pub fn prefetch(&self) -> Option<&PrefetchSettings> {
+        self.prefetch.as_ref()
+    }
+
+    pub fn with_jobs(mut self, jobs: JobSettings) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    pub fn jobs(&self) -> &JobSettings {
+        &self.jobs
+    }
+
+    /// Hosts de confianza que se reenvían sin inspección.
+    pub fn with_fast_path(mut self, hosts: Vec<HostPattern>) -> Self {
+        self.fast_path = hosts;
+        self
+    }
+
+    pub fn fast_path(&self) -> &[HostPattern] {
+        &self.fast_path
+    }
+
+    pub fn with_error_pages(mut self, error_pages: ErrorPageSettings) -> Self {
+        self.error_pages = Some(error_pages);
+        self
+    }
+
+    pub fn error_pages(&self) -> Option<&ErrorPageSettings> {
+        self.error_pages.as_ref()
+    }
+
+    pub fn with_server_timing(mut self, server_timing: ServerTimingSettings) -> Self {
+        self.server_timing = Some(server_timing);
+        self
+    }
+
+    pub fn server_timing(&self) -> Option<&ServerTimingSettings> {
+        self.server_timing.as_ref()
+    }
+
+    pub fn with_metadata_echo(mut self, echo: MetadataEchoSettings) -> Self {
+        self.metadata_echo = Some(echo);
+        self
+    }
+
+    pub fn metadata_echo(&self) -> Option<&MetadataEchoSettings> {
+        self.metadata_echo.as_ref()
+    }
+
+    /// Añade un CSV de redirecciones; ante una misma petición manda el
+    /// primero que tenga una fila para ella.
+    pub fn with_redirect_map(mut self, path: impl Into<PathBuf>) -> Self {
+        self.redirect_maps.push(path.into());
+        self
+    }
+
+    pub fn redirect_maps(&self) -> &[PathBuf] {
+        &self.redirect_maps
+    }
+
+    /// Sirve `GET /proxy-info` a los clientes; activo por defecto.
+    pub fn with_proxy_info(mut self, enabled: bool) -> Self {
+        self.proxy_info = enabled;
+        self
+    }
+
+    pub fn proxy_info(&self) -> bool {
+        self.proxy_info
+    }
+
+    /// Sirve un PAC que apunta a este proxy en `GET /proxy.pac`.
+    pub fn with_serve_pac(mut self, serve_pac: ServePacSettings) -> Self {
+        self.serve_pac = Some(serve_pac);
+        self
+    }
+
+    pub fn serve_pac(&self) -> Option<&ServePacSettings> {
+        self.serve_pac.as_ref()
+    }
+
+    /// Acepta peticiones con URI relativa y las manda al destino de su
+    /// `Host`.
+    pub fn with_transparent(mut self, transparent: TransparentSettings) -> Self {
+        self.transparent = Some(transparent);
+        self
+    }
+
+    pub fn transparent(&self) -> Option<&TransparentSettings> {
+        self.transparent.as_ref()
+    }
+
+    /// Modo anónimo: sin `Via` ni `X-Forwarded-*` propias y sin las que
+    /// mande el cliente.
+    pub fn with_anonymous(mut self, anonymous: bool) -> Self {
+        self.anonymous = anonymous;
+        self
+    }
+
+    pub fn anonymous(&self) -> bool {
+        self.anonymous
+    }
+
+    pub fn with_log_profile(mut self, profile: LogProfile) -> Self {
+        self.log_profile = profile;
+        self
+    }
+
+    pub fn log_profile(&self) -> LogProfile {
+        self.log_profile
+    }
+
+    pub fn with_nat64(mut self, nat64: Nat64Settings) -> Self {
+        self.nat64 = Some(nat64);
+        self
+    }
+
+    pub fn nat64(&self) -> Option<&Nat64Settings> {
+        self.nat64.as_ref()
+    }
+
+    pub fn with_tunnel_quality(mut self, quality: TunnelQualitySettings) -> Self {
+        self.tunnel_quality = Some(quality);
+        self
+    }
+
+    pub fn tunnel_quality(&self) -> Option<&TunnelQualitySettings> {
+        self.tunnel_quality.as_ref()
+    }
+
+    /// Comprueba el SNI del saludo TLS de cada túnel `CONNECT`.
+    pub fn with_verify_sni(mut self, verify_sni: VerifySniSettings) -> Self {
+        self.verify_sni = Some(verify_sni);
+        self
+    }
+
+    pub fn verify_sni(&self) -> Option<&VerifySniSettings> {
+        self.verify_sni.as_ref()
+    }
+
+    pub fn with_header_limits(mut self, limits: HeaderLimits) -> Self {
+        self.header_limits = Some(limits);
+        self
+    }
+
+    pub fn header_limits(&self) -> Option<&HeaderLimits> {
+        self.header_limits.as_ref()
+    }
+
+    /// Exige `Proxy-Authorization` a los clientes.
+    pub fn with_auth(mut self, auth: AuthSettings) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn auth(&self) -> Option<&AuthSettings> {
+        self.auth.as_ref()
+    }
+
+    /// Acepta tickets de preaprobación en `Proxy-Authorization: Ticket`.
+    pub fn with_tickets(mut self, tickets: TicketSettings) -> Self {
+        self.tickets = Some(tickets);
+        self
+    }
+
+    pub fn tickets(&self) -> Option<&TicketSettings> {
+        self.tickets.as_ref()
+    }
+
+    /// Sirve el flujo de eventos a los agentes locales.
+    pub fn with_events(mut self, events: EventSettings) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn events(&self) -> Option<&EventSettings> {
+        self.events.as_ref()
+    }
+
+    /// Limita los destinos a las listas `allow` y `deny`.
+    pub fn with_host_filter(mut self, host_filter: HostFilterSettings) -> Self {
+        self.host_filter = Some(host_filter);
+        self
+    }
+
+    pub fn host_filter(&self) -> Option<&HostFilterSettings> {
+        self.host_filter.as_ref()
+    }
+
+    /// Añade una regla de reescritura de cabeceras; se aplican en orden.
+    pub fn with_header_rule(mut self, rule: HeaderRule) -> Self {
+        self.header_rules.push(rule);
+        self
+    }
+
+    pub fn header_rules(&self) -> &[HeaderRule] {
+        &self.header_rules
+    }
+
+    pub fn with_resources(mut self, resources: ResourceSettings) -> Self {
+        self.resources = resources;
+        self
+    }
+
+    pub fn resources(&self) -> &ResourceSettings {
+        &self.resources
+    }
+
+    pub fn with_drain(mut self, drain: DrainSettings) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    pub fn drain(&self) -> &DrainSettings {
+        &self.drain
+    }
+
+    /// Tiempo que la parada espera a las conexiones abiertas antes de
+    /// abortarlas.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// Espera máxima para abrir el túnel de un `CONNECT`; cero la quita.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// Bytes como mucho en el body de una petición HTTP; sin límite por
+    /// defecto.
+    pub fn with_max_request_body(mut self, max: u64) -> Self {
+        self.max_request_body = Some(max);
+        self
+    }
+
+    pub fn max_request_body(&self) -> Option<u64> {
+        self.max_request_body
+    }
+
+    /// Bytes como mucho en el body de una respuesta del destino; sin límite
+    /// por defecto.
+    pub fn with_max_response_body(mut self, max: u64) -> Self {
+        self.max_response_body = Some(max);
+        self
+    }
+
+    pub fn max_response_body(&self) -> Option<u64> {
+        self.max_response_body
+    }
+
+    /// Habla solo HTTP/1.1 con los destinos `https://`, sin ofrecer `h2`.
+    pub fn with_http1_only(mut self, http1_only: bool) -> Self {
+        self.http1_only = http1_only;
+        self
+    }
+
+    pub fn http1_only(&self) -> bool {
+        self.http1_only
+    }
+
+    /// Caché de sesiones TLS hacia los destinos; ver [`crate::origin_tls`].
+    pub fn with_upstream_tls(mut self, upstream_tls: UpstreamTlsSettings) -> Self {
+        self.upstream_tls = upstream_tls;
+        self
+    }
+
+    pub fn upstream_tls(&self) -> &UpstreamTlsSettings {
+        &self.upstream_tls
+    }
+
+    /// Espera máxima hasta las cabeceras de la respuesta del destino en una
+    /// petición HTTP; cero la quita.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    /// Puertos de destino a los que se puede abrir un túnel `CONNECT`; el
+    /// resto recibe un 403.
+    pub fn with_connect_ports(mut self, ports: Vec<u16>) -> Self {
+        self.connect_ports = ports;
+        self
+    }
+
+    pub fn connect_ports(&self) -> &[u16] {
+        &self.connect_ports
+    }
+
+    pub fn with_throttle(mut self, throttle: ThrottleSettings) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn throttle(&self) -> &ThrottleSettings {
+        &self.throttle
+    }
+
+    /// Intentos por petición idempotente, el primero incluido; 1 (o 0) no
+    /// reintenta.
+    pub fn with_retry_attempts(mut self, attempts: u32) -> Self {
+        self.retry.attempts = attempts.max(1);
+        self
+    }
+
+    /// Espera antes del primer reintento; se dobla en cada uno.
+    pub fn with_retry_base_delay(mut self, delay: Duration) -> Self {
+        self.retry.base_delay = delay;
+        self
+    }
+
+    pub fn retry(&self) -> RetrySettings {
+        self.retry
+    }
+
+    pub fn with_ssrf(mut self, ssrf: SsrfSettings) -> Self {
+        self.ssrf = ssrf;
+        self
+    }
+
+    pub fn ssrf(&self) -> &SsrfSettings {
+        &self.ssrf
+    }
+}
+
+/// Redes con las que el proxy no conecta en nombre de un cliente.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SsrfSettings {
+    block_private_networks: bool,
+    blocked_networks: Vec<Cidr>,
+}
+
+impl SsrfSettings {
+    /// Loopback, link-local, privadas y sin especificar.
+    pub fn with_block_private_networks(mut self, block: bool) -> Self {
+        self.block_private_networks = block;
+        self
+    }
+
+    /// Rango bloqueado además de las redes privadas.
+    pub fn with_blocked_network(mut self, range: Cidr) -> Self {
+        self.blocked_networks.push(range);
+        self
+    }
+
+    pub fn block_private_networks(&self) -> bool {
+        self.block_private_networks
+    }
+
+    pub fn blocked_networks(&self) -> &[Cidr] {
+        &self.blocked_networks
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.block_private_networks || !self.blocked_networks.is_empty()
+    }
+}
+
+/// Reintentos de las peticiones idempotentes cuando el destino falla.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetrySettings {
+    attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetrySettings {
+    pub const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(100);
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn base_delay(&self) -> Duration {
+        self.base_delay
+    }
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            base_delay: Self::DEFAULT_BASE_DELAY,
+        }
+    }
+}
+
+/// Caudal máximo de cada conexión de cliente, por sentido, en bytes por
+/// segundo; sin valor no hay límite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThrottleSettings {
+    down: Option<u64>,
+    up: Option<u64>,
+}
+
+impl ThrottleSettings {
+    /// Del destino hacia el cliente; cero quita el límite.
+    pub fn with_down(mut self, bytes_per_sec: u64) -> Self {
+        self.down = Some(bytes_per_sec).filter(|rate| *rate > 0);
+        self
+    }
+
+    /// Del cliente hacia el destino; cero quita el límite.
+    pub fn with_up(mut self, bytes_per_sec: u64) -> Self {
+        self.up = Some(bytes_per_sec).filter(|rate| *rate > 0);
+        self
+    }
+
+    pub fn down(&self) -> Option<u64> {
+        self.down
+    }
+
+    pub fn up(&self) -> Option<u64> {
+        self.up
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.down.is_none() && self.up.is_none()
+    }
+}
+
+/// Qué se hace con los trailers de una respuesta cuando el cliente no envió
+/// `TE: trailers`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrailerFallback {
+    /// Se descartan con un log `debug`.
+    #[default]
+    Drop,
+    /// Se retiene el body (hasta `max_body` bytes) para agregar los trailers
+    /// como cabeceras. Si el body es mayor se envía tal cual y se descartan.
+    Headers { max_body: usize },
+}
+
+impl TrailerFallback {
+    pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+}
+
+/// Tratamiento de las peticiones con `Expect: 100-continue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectContinue {
+    /// Reenvía `Expect` al destino y solo pide el body al cliente cuando el
+    /// destino responde `100 Continue` (o pasa `timeout` sin respuesta). Un
+    /// rechazo anticipado llega al cliente sin que suba el body.
+    Forward { timeout: Duration },
+    /// Responde `100 Continue` en el proxy y reenvía sin `Expect`.
+    Local,
+}
+
+impl ExpectContinue {
+    /// Los clientes suelen enviar el body tras un segundo sin respuesta.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+}
+
+impl Default for ExpectContinue {
+    fn default() -> Self {
+        ExpectContinue::Forward {
+            timeout: Self::DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// Límites de las conexiones keep-alive de los clientes. `None` desactiva
+/// cada límite; por defecto no hay ninguno.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    max_requests: Option<u64>,
+}
+
+impl ConnectionLimits {
+    /// Cierra conexiones sin peticiones en curso durante más de `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Fuerza a reconectar pasado `lifetime`, siempre entre peticiones.
+    pub fn with_max_lifetime(mut self, lifetime: Duration) -> Self {
+        self.max_lifetime = Some(lifetime).filter(|t| !t.is_zero());
+        self
+    }
+
+    pub fn with_max_requests(mut self, max: u64) -> Self {
+        self.max_requests = Some(max).filter(|m| *m > 0);
+        self
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime
+    }
+
+    pub fn max_requests(&self) -> Option<u64> {
+        self.max_requests
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.idle_timeout.is_none() && self.max_lifetime.is_none()
+    }
+
+    /// Cada cuánto se revisan los temporizadores de una conexión.
+    pub fn check_interval(&self) -> Duration {
+        let shortest = [self.idle_timeout, self.max_lifetime]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(Duration::from_secs(1));
+        (shortest / 4).clamp(Duration::from_millis(10), Duration::from_secs(1))
+    }
+}
+
+/// Certificado y clave PEM con los que el puerto de escucha habla TLS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsSettings {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl TlsSettings {
+    /// `cert` puede llevar la cadena de intermedios tras el certificado.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+        }
+    }
+
+    pub fn cert(&self) -> &Path {
+        &self.cert
+    }
+
+    pub fn key(&self) -> &Path {
+        &self.key
+    }
+}
+
+/// CA con la que se firman los certificados de los túneles interceptados.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MitmSettings {
+    ca_cert: PathBuf,
+    ca_key: PathBuf,
+    passthrough: Vec<HostPattern>,
+    upstream_ca: Option<PathBuf>,
+}
+
+impl MitmSettings {
+    /// `ca_key` debe ser PKCS#8, como la que genera `openssl genpkey`.
+    pub fn new(ca_cert: impl Into<PathBuf>, ca_key: impl Into<PathBuf>) -> Self {
+        Self {
+            ca_cert: ca_cert.into(),
+            ca_key: ca_key.into(),
+            passthrough: Vec::new(),
+            upstream_ca: None,
+        }
+    }
+
+    /// Destinos que siguen siendo túneles ciegos, p. ej. los que fijan su
+    /// certificado.
+    pub fn with_passthrough(mut self, passthrough: Vec<HostPattern>) -> Self {
+        self.passthrough = passthrough;
+        self
+    }
+
+    /// Raíces PEM con las que se verifican los destinos además de las
+    /// públicas, para orígenes internos con su propia CA.
+    pub fn with_upstream_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.upstream_ca = Some(path.into());
+        self
+    }
+
+    pub fn ca_cert(&self) -> &Path {
+        &self.ca_cert
+    }
+
+    pub fn ca_key(&self) -> &Path {
+        &self.ca_key
+    }
+
+    pub fn passthrough(&self) -> &[HostPattern] {
+        &self.passthrough
+    }
+
+    pub fn upstream_ca(&self) -> Option<&Path> {
+        self.upstream_ca.as_deref()
+    }
+}
+
+/// Reanudación de las sesiones TLS con los destinos `https://`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamTlsSettings {
+    session_cache_size: usize,
+    no_resumption: Vec<HostPattern>,
+}
+
+impl Default for UpstreamTlsSettings {
+    fn default() -> Self {
+        Self {
+            session_cache_size: 256,
+            no_resumption: Vec::new(),
+        }
+    }
+}
+
+impl UpstreamTlsSettings {
+    /// Hosts cuyas sesiones se guardan; 0 no reanuda ninguna.
+    pub fn with_session_cache_size(mut self, hosts: usize) -> Self {
+        self.session_cache_size = hosts;
+        self
+    }
+
+    /// Destinos con los que siempre se hace el saludo completo, p. ej. detrás
+    /// de equipos que rompen la reanudación.
+    pub fn with_no_resumption(mut self, hosts: Vec<HostPattern>) -> Self {
+        self.no_resumption = hosts;
+        self
+    }
+
+    pub fn session_cache_size(&self) -> usize {
+        self.session_cache_size
+    }
+
+    pub fn no_resumption(&self) -> &[HostPattern] {
+        &self.no_resumption
+    }
+}
+
+/// Cuándo avisar de que caduca un certificado que sirve el proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertExpirySettings {
+    thresholds_days: Vec<u64>,
+    check_interval: Duration,
+    webhook: Option<String>,
+}
+
+impl Default for CertExpirySettings {
+    fn default() -> Self {
+        Self {
+            thresholds_days: vec![30, 7, 1],
+            check_interval: Duration::from_secs(3600),
+            webhook: None,
+        }
+    }
+}
+
+impl CertExpirySettings {
+    /// Días antes del `not_after` en los que se avisa; el orden no importa.
+    pub fn with_thresholds_days(mut self, mut days: Vec<u64>) -> Self {
+        days.sort_unstable_by(|a, b| b.cmp(a));
+        days.dedup();
+        self.thresholds_days = days;
+        self
+    }
+
+    pub fn with_check_interval(mut self, interval: Duration) -> Self {
+        self.check_interval = interval;
+        self
+    }
+
+    /// POST `http://` con cada aviso.
+    pub fn with_webhook(mut self, url: impl Into<String>) -> Self {
+        self.webhook = Some(url.into());
+        self
+    }
+
+    /// De mayor a menor.
+    pub fn thresholds_days(&self) -> &[u64] {
+        &self.thresholds_days
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        self.check_interval
+    }
+
+    pub fn webhook(&self) -> Option<&str> {
+        self.webhook.as_deref()
+    }
+}
+
+/// Redirección del puerto del listener en el router por UPnP IGD o NAT-PMP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMappingSettings {
+    external_port: Option<u16>,
+    lease: Duration,
+}
+
+impl Default for PortMappingSettings {
+    fn default() -> Self {
+        Self {
+            external_port: None,
+            lease: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl PortMappingSettings {
+    /// Puerto que se pide en el router; por defecto, el del listener.
+    pub fn with_external_port(mut self, port: u16) -> Self {
+        self.external_port = Some(port);
+        self
+    }
+
+    /// Duración de la redirección; se renueva a la mitad. Nunca es infinita,
+    /// para que un proxy que muere sin avisar no deje el puerto abierto.
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = lease.max(Duration::from_secs(1));
+        self
+    }
+
+    pub fn external_port(&self) -> Option<u16> {
+        self.external_port
+    }
+
+    pub fn lease(&self) -> Duration {
+        self.lease
+    }
+}
+
+/// Protocolos que atiende el puerto de escucha. Por defecto solo HTTP; al
+/// activar otro, cada conexión se clasifica por sus primeros bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerProtocols {
+    http: bool,
+    socks5: bool,
+    detect_timeout: Duration,
+}
+
+impl Default for ListenerProtocols {
+    fn default() -> Self {
+        Self {
+            http: true,
+            socks5: false,
+            detect_timeout: Self::DEFAULT_DETECT_TIMEOUT,
+        }
+    }
+}
+
+impl ListenerProtocols {
+    pub const DEFAULT_DETECT_TIMEOUT: Duration = Duration::from_millis(200);
+
+    pub fn with_http(mut self, enabled: bool) -> Self {
+        self.http = enabled;
+        self
+    }
+
+    pub fn with_socks5(mut self, enabled: bool) -> Self {
+        self.socks5 = enabled;
+        self
+    }
+
+    /// Espera máxima al primer byte; un cliente que calla más se trata como
+    /// HTTP.
+    pub fn with_detect_timeout(mut self, timeout: Duration) -> Self {
+        self.detect_timeout = timeout;
+        self
+    }
+
+    pub fn http(&self) -> bool {
+        self.http
+    }
+
+    pub fn socks5(&self) -> bool {
+        self.socks5
+    }
+
+    pub fn detect_timeout(&self) -> Duration {
+        self.detect_timeout
+    }
+
+    /// Si hay que mirar los primeros bytes antes de atender la conexión.
+    pub fn detects(&self) -> bool {
+        self.socks5 || !self.http
+    }
+}
+
+/// Conexiones nuevas que admite cada IP: `per_sec` de media con ráfagas de
+/// hasta `burst`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AcceptRate {
+    per_sec: f64,
+    burst: u32,
+}
+
+impl AcceptRate {
+    pub fn new(per_sec: f64, burst: u32) -> Self {
+        Self { per_sec, burst }
+    }
+
+    pub fn per_sec(&self) -> f64 {
+        self.per_sec
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+}
+
+/// Peticiones que admite cada IP de cliente: `per_sec` de media con
+/// ráfagas de hasta `burst`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    per_sec: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(per_sec: f64, burst: u32) -> Self {
+        Self { per_sec, burst }
+    }
+
+    pub fn per_sec(&self) -> f64 {
+        self.per_sec
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+}
+
+/// Peticiones y túneles simultáneos; ver [`crate::concurrency`]. Sin
+/// límites, no se cuenta nada.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConcurrencyLimits {
+    max_connections: Option<usize>,
+    max_tunnels: Option<usize>,
+}
+
+impl ConcurrencyLimits {
+    /// Peticiones y túneles en curso en todo el proxy.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max.max(1));
+        self
+    }
+
+    /// Túneles `CONNECT` abiertos por cada IP de cliente.
+    pub fn with_max_tunnels(mut self, max: usize) -> Self {
+        self.max_tunnels = Some(max.max(1));
+        self
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn max_tunnels(&self) -> Option<usize> {
+        self.max_tunnels
+    }
+}
+
+/// Socket UNIX del proxy: quién puede usarlo lo deciden los permisos del
+/// archivo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnixListenSettings {
+    path: PathBuf,
+    mode: u32,
+}
+
+impl UnixListenSettings {
+    pub const DEFAULT_MODE: u32 = 0o660;
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            mode: Self::DEFAULT_MODE,
+        }
+    }
+
+    /// Permisos del archivo del socket, como los de `chmod`.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode & 0o777;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+}
+
+/// Defensas del listener frente a avalanchas de conexiones. Todo se aplica
+/// en el bucle de aceptación, antes de leer nada del cliente.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListenerHardening {
+    backlog: u32,
+    defer_accept: Option<Duration>,
+    accept_rate: Option<AcceptRate>,
+    fd_pause_percent: Option<u8>,
+}
+
+impl Default for ListenerHardening {
+    fn default() -> Self {
+        Self {
+            backlog: Self::DEFAULT_BACKLOG,
+            defer_accept: None,
+            accept_rate: None,
+            fd_pause_percent: None,
+        }
+    }
+}
+
+impl ListenerHardening {
+    pub const DEFAULT_BACKLOG: u32 = 1024;
+
+    /// Conexiones completadas que el kernel encola antes de `accept`.
+    pub fn with_backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// `TCP_DEFER_ACCEPT` (solo Linux): el kernel entrega la conexión cuando
+    /// llegan datos o pasa `timeout`.
+    pub fn with_defer_accept(mut self, timeout: Duration) -> Self {
+        self.defer_accept = Some(timeout);
+        self
+    }
+
+    pub fn with_accept_rate(mut self, rate: AcceptRate) -> Self {
+        self.accept_rate = Some(rate);
+        self
+    }
+
+    /// Deja de aceptar mientras los descriptores abiertos superan este
+    /// porcentaje del límite del proceso (solo Linux).
+    pub fn with_fd_pause_percent(mut self, percent: u8) -> Self {
+        self.fd_pause_percent = Some(percent);
+        self
+    }
+
+    pub fn backlog(&self) -> u32 {
+        self.backlog
+    }
+
+    pub fn defer_accept(&self) -> Option<Duration> {
+        self.defer_accept
+    }
+
+    pub fn accept_rate(&self) -> Option<AcceptRate> {
+        self.accept_rate
+    }
+
+    pub fn fd_pause_percent(&self) -> Option<u8> {
+        self.fd_pause_percent
+    }
+}
+
+/// Configuración del script Rhai de enrutamiento y filtrado.
+#[derive(Debug, Clone)]
+pub struct ScriptSettings {
+    path: PathBuf,
+    fail_closed: bool,
+    max_operations: u64,
+    timeout: Duration,
+}
+
+impl ScriptSettings {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            fail_closed: false,
+            max_operations: 100_000,
+            timeout: Duration::from_millis(50),
+        }
+    }
+
+    /// Si es `true`, un error del script bloquea la petición en lugar de
+    /// dejarla pasar.
+    pub fn with_fail_closed(mut self, fail_closed: bool) -> Self {
+        self.fail_closed = fail_closed;
+        self
+    }
+
+    pub fn with_max_operations(mut self, max_operations: u64) -> Self {
+        self.max_operations = max_operations;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn fail_closed(&self) -> bool {
+        self.fail_closed
+    }
+
+    pub fn max_operations(&self) -> u64 {
+        self.max_operations
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Acción asociada a un rango de puntuación de reputación.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReputationAction {
+    Allow,
+    Log,
+    Block,
+}
+
+impl ReputationAction {
+    pub const ALL: [ReputationAction; 3] = [
+        ReputationAction::Allow,
+        ReputationAction::Log,
+        ReputationAction::Block,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReputationAction::Allow => "allow",
+            ReputationAction::Log => "log",
+            ReputationAction::Block => "block",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl std::str::FromStr for ReputationAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|action| action.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("acción de reputación desconocida: {s}"))
+    }
+}
+
+/// Consulta de reputación de IPs contra un servicio HTTP externo.
+///
+/// Las puntuaciones son de riesgo: cuanto más alta, peor. Cada umbral aplica
+/// su acción a partir de `min_score`; gana el umbral más alto alcanzado y por
+/// debajo de todos se permite la conexión.
+#[derive(Debug, Clone)]
+pub struct ReputationSettings {
+    url: String,
+    timeout: Duration,
+    cache_size: usize,
+    cache_ttl: Duration,
+    batch_window: Duration,
+    max_batch: usize,
+    check_clients: bool,
+    fail_open: bool,
+    thresholds: Vec<(f64, ReputationAction)>,
+}
+
+impl ReputationSettings {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            timeout: Duration::from_millis(200),
+            cache_size: 10_000,
+            cache_ttl: Duration::from_secs(600),
+            batch_window: Duration::from_millis(5),
+            max_batch: 100,
+            check_clients: false,
+            fail_open: true,
+            thresholds: Vec::new(),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_cache_size(mut self, size: usize) -> Self {
+        self.cache_size = size.max(1);
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Tiempo que se esperan más IPs antes de enviar una consulta agrupada.
+    pub fn with_batch_window(mut self, window: Duration) -> Self {
+        self.batch_window = window;
+        self
+    }
+
+    pub fn with_max_batch(mut self, max: usize) -> Self {
+        self.max_batch = max.max(1);
+        self
+    }
+
+    /// Comprueba también la IP de cada cliente.
+    pub fn with_check_clients(mut self, check: bool) -> Self {
+        self.check_clients = check;
+        self
+    }
+
+    /// Si es `true` (por defecto), una caída del servicio deja pasar las
+    /// conexiones con un aviso en el log.
+    pub fn with_fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+
+    pub fn with_threshold(mut self, min_score: f64, action: ReputationAction) -> Self {
+        self.thresholds.push((min_score, action));
+        self.thresholds.sort_by(|a, b| b.0.total_cmp(&a.0));
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.cache_size
+    }
+
+    pub fn cache_ttl(&self) -> Duration {
+        self.cache_ttl
+    }
+
+    pub fn batch_window(&self) -> Duration {
+        self.batch_window
+    }
+
+    pub fn max_batch(&self) -> usize {
+        self.max_batch
+    }
+
+    pub fn check_clients(&self) -> bool {
+        self.check_clients
+    }
+
+    pub fn fail_open(&self) -> bool {
+        self.fail_open
+    }
+
+    /// Acción que corresponde a `score`.
+    pub fn action_for(&self, score: f64) -> ReputationAction {
+        self.thresholds
+            .iter()
+            .find(|(min, _)| score >= *min)
+            .map(|(_, action)| *action)
+            .unwrap_or(ReputationAction::Allow)
+    }
+}
+
+/// Captura a disco de las peticiones que terminan en 5xx.
+#[derive(Debug, Clone)]
+pub struct CaptureSettings {
+    dir: PathBuf,
+    max_total_bytes: u64,
+    max_body_bytes: usize,
+    sample_rate: f64,
+    hosts: Vec<String>,
+    all_responses: bool,
+}
+
+impl CaptureSettings {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_total_bytes: 100 * 1024 * 1024,
+            max_body_bytes: 64 * 1024,
+            sample_rate: 1.0,
+            hosts: Vec::new(),
+            all_responses: false,
+        }
+    }
+
+    /// Espacio máximo del directorio; al superarlo se borran los más antiguos.
+    pub fn with_max_total_bytes(mut self, max: u64) -> Self {
+        self.max_total_bytes = max;
+        self
+    }
+
+    pub fn with_max_body_bytes(mut self, max: usize) -> Self {
+        self.max_body_bytes = max;
+        self
+    }
+
+    /// Fracción de peticiones (0.0 a 1.0) que se preparan para capturar.
+    pub fn with_sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Limita la captura a estos hosts y sus subdominios.
+    pub fn with_hosts(mut self, hosts: Vec<String>) -> Self {
+        self.hosts = hosts.into_iter().map(|h| h.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Guarda todas las respuestas muestreadas, no solo los 5xx.
+    pub fn with_all_responses(mut self, all: bool) -> Self {
+        self.all_responses = all;
+        self
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    pub fn all_responses(&self) -> bool {
+        self.all_responses
+    }
+
+    pub fn max_total_bytes(&self) -> u64 {
+        self.max_total_bytes
+    }
+
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    pub fn matches_host(&self, host: &str) -> bool {
+        self.hosts.is_empty()
+            || self.hosts.iter().any(|h| {
+                host.eq_ignore_ascii_case(h)
+                    || host
+                        .to_ascii_lowercase()
+                        .strip_suffix(h.as_str())
+                        .is_some_and(|rest| rest.ends_with('.'))
+            })
+    }
+}
+
+/// Secreto (el token de la API de administración o una contraseña de
+/// `[auth]`); no se muestra en los logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    /// El secreto en claro, para enviarlo a quien lo espera.
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Compara en tiempo constante respecto al contenido.
+    pub fn matches(&self, presented: &[u8]) -> bool {
+        let expected = self.0.as_bytes();
+        expected.len() == presented.len()
+            && expected
+                .iter()
+                .zip(presented)
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl std::fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AdminToken([redacted])")
+    }
+}
+
+/// Usuarios que pueden usar el proxy con `Proxy-Authorization: Basic`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthSettings {
+    realm: String,
+    users: Vec<(String, AdminToken)>,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            realm: "proxy-ia".to_string(),
+            users: Vec::new(),
+        }
+    }
+}
+
+impl AuthSettings {
+    /// Realm del desafío `Proxy-Authenticate`.
+    pub fn with_realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    pub fn with_user(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.users.push((user.into(), AdminToken::new(password)));
+        self
+    }
+
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    /// Usuarios con su contraseña.
+    pub fn users(&self) -> &[(String, AdminToken)] {
+        &self.users
+    }
+}
+
+/// Tickets firmados que autorizan a un dispositivo hacia unos destinos
+/// hasta que caducan, sin consultar ningún usuario.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TicketSettings {
+    key: AdminToken,
+    max_ttl: Duration,
+    revoked: Vec<String>,
+}
+
+impl TicketSettings {
+    pub const DEFAULT_MAX_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+
+    /// `key` firma y verifica los tickets con HMAC-SHA256.
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: AdminToken::new(key),
+            max_ttl: Self::DEFAULT_MAX_TTL,
+            revoked: Vec::new(),
+        }
+    }
+
+    /// Vigencia máxima de un ticket emitido por la API.
+    pub fn with_max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// Id de un ticket que ya no se acepta aunque no haya caducado.
+    pub fn with_revoked(mut self, id: impl Into<String>) -> Self {
+        self.revoked.push(id.into());
+        self
+    }
+
+    pub fn key(&self) -> &AdminToken {
+        &self.key
+    }
+
+    pub fn max_ttl(&self) -> Duration {
+        self.max_ttl
+    }
+
+    pub fn revoked(&self) -> &[String] {
+        &self.revoked
+    }
+}
+
+impl std::fmt::Debug for TicketSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketSettings")
+            .field("max_ttl", &self.max_ttl)
+            .field("revoked", &self.revoked)
+            .finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Debug for AuthSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let users: Vec<&str> = self.users.iter().map(|(user, _)| user.as_str()).collect();
+        f.debug_struct("AuthSettings")
+            .field("realm", &self.realm)
+            .field("users", &users)
+            .finish()
+    }
+}
+
+/// Clave AES-256 con la que se cifran los bodies archivados.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ArchiveKey([u8; 32]);
+
+impl ArchiveKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for ArchiveKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ArchiveKey([redacted])")
+    }
+}
+
+/// Archivo consultable de los intercambios HTTP que atraviesan el proxy.
+#[derive(Debug, Clone)]
+pub struct ArchiveSettings {
+    dir: PathBuf,
+    key: Option<ArchiveKey>,
+    max_age: Duration,
+    max_total_bytes: u64,
+    max_body_bytes: usize,
+}
+
+impl ArchiveSettings {
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            key: None,
+            max_age: Self::DEFAULT_MAX_AGE,
+            max_total_bytes: 512 * 1024 * 1024,
+            max_body_bytes: 64 * 1024,
+        }
+    }
+
+    /// Sin clave solo se archivan los metadatos, nunca los bodies.
+    pub fn with_key(mut self, key: ArchiveKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Espacio máximo en disco; al superarlo se borran los segmentos más
+    /// antiguos.
+    pub fn with_max_total_bytes(mut self, max: u64) -> Self {
+        self.max_total_bytes = max;
+        self
+    }
+
+    pub fn with_max_body_bytes(mut self, max: usize) -> Self {
+        self.max_body_bytes = max;
+        self
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    pub fn key(&self) -> Option<&ArchiveKey> {
+        self.key.as_ref()
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    pub fn max_total_bytes(&self) -> u64 {
+        self.max_total_bytes
+    }
+
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+}
+
+/// Familia de direcciones que se prueba primero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+impl AddressFamily {
+    pub fn contains(&self, addr: &std::net::SocketAddr) -> bool {
+        match self {
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+        }
+    }
+}
+
+impl std::str::FromStr for AddressFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv6" => Ok(AddressFamily::Ipv6),
+            "ipv4" => Ok(AddressFamily::Ipv4),
+            other => anyhow::bail!("familia de direcciones desconocida: {other}"),
+        }
+    }
+}
+
+/// Conexión con destinos que resuelven a varias direcciones.
+#[derive(Debug, Clone)]
+pub struct DialSettings {
+    race_width: usize,
+    stagger: Duration,
+    score_half_life: Duration,
+    max_scored_hosts: usize,
+    prefer_family: AddressFamily,
+}
+
+impl Default for DialSettings {
+    fn default() -> Self {
+        Self {
+            race_width: 2,
+            stagger: Duration::from_millis(250),
+            score_half_life: Duration::from_secs(300),
+            max_scored_hosts: 1024,
+            prefer_family: AddressFamily::default(),
+        }
+    }
+}
+
+impl DialSettings {
+    /// Intentos simultáneos como máximo; 1 prueba las direcciones de una en una.
+    pub fn with_race_width(mut self, width: usize) -> Self {
+        self.race_width = width.max(1);
+        self
+    }
+
+    /// Espera antes de lanzar el siguiente intento si el anterior no terminó.
+    pub fn with_stagger(mut self, stagger: Duration) -> Self {
+        self.stagger = stagger;
+        self
+    }
+
+    /// Tiempo en que una latencia recordada pierde la mitad de su peso.
+    pub fn with_score_half_life(mut self, half_life: Duration) -> Self {
+        self.score_half_life = half_life;
+        self
+    }
+
+    pub fn with_max_scored_hosts(mut self, max: usize) -> Self {
+        self.max_scored_hosts = max.max(1);
+        self
+    }
+
+    /// Familia con la que se empieza; los intentos alternan entre las dos.
+    pub fn with_prefer_family(mut self, family: AddressFamily) -> Self {
+        self.prefer_family = family;
+        self
+    }
+
+    pub fn race_width(&self) -> usize {
+        self.race_width
+    }
+
+    pub fn stagger(&self) -> Duration {
+        self.stagger
+    }
+
+    pub fn score_half_life(&self) -> Duration {
+        self.score_half_life
+    }
+
+    pub fn max_scored_hosts(&self) -> usize {
+        self.max_scored_hosts
+    }
+
+    pub fn prefer_family(&self) -> AddressFamily {
+        self.prefer_family
+    }
+}
+
+/// Pool de conexiones hacia los destinos de las peticiones HTTP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientPoolSettings {
+    enabled: bool,
+    max_idle_per_host: Option<usize>,
+    idle_timeout: Duration,
+    http1_keep_alive: bool,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
+}
+
+impl Default for ClientPoolSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_idle_per_host: None,
+            idle_timeout: Self::DEFAULT_IDLE_TIMEOUT,
+            http1_keep_alive: true,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+        }
+    }
+}
+
+impl ClientPoolSettings {
+    pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+    /// Con `false` cada petición abre su propia conexión, para depurar.
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Conexiones ociosas que se guardan por destino; sin límite por
+    /// defecto.
+    pub fn with_max_idle_per_host(mut self, max: usize) -> Self {
+        self.max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Tiempo que una conexión ociosa espera a la siguiente petición.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Con `false` las peticiones HTTP/1.1 llevan `Connection: close` y el
+    /// destino cierra la conexión tras responder.
+    pub fn with_http1_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.http1_keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// Sondas TCP keepalive tras `idle` sin tráfico.
+    pub fn with_tcp_keepalive(mut self, idle: Duration) -> Self {
+        self.tcp_keepalive = Some(idle);
+        self
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn max_idle_per_host(&self) -> Option<usize> {
+        self.max_idle_per_host
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn http1_keep_alive(&self) -> bool {
+        self.http1_keep_alive
+    }
+
+    pub fn tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay
+    }
+
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        self.tcp_keepalive
+    }
+}
+
+/// Muestreo de la calidad de los túneles largos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelQualitySettings {
+    interval: Duration,
+    min_age: Duration,
+}
+
+impl Default for TunnelQualitySettings {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            min_age: Duration::from_secs(30),
+        }
+    }
+}
+
+impl TunnelQualitySettings {
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Los túneles más cortos no se muestrean.
+    pub fn with_min_age(mut self, min_age: Duration) -> Self {
+        self.min_age = min_age;
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn min_age(&self) -> Duration {
+        self.min_age
+    }
+}
+
+/// Qué se hace con un túnel cuyo cliente no empieza con un saludo TLS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonTlsAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+impl std::str::FromStr for NonTlsAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(NonTlsAction::Allow),
+            "deny" => Ok(NonTlsAction::Deny),
+            other => anyhow::bail!("acción para túneles sin TLS desconocida: {other}"),
+        }
+    }
+}
+
+/// Comprobación del SNI de los túneles `CONNECT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifySniSettings {
+    non_tls: NonTlsAction,
+    hello_timeout: Duration,
+}
+
+impl Default for VerifySniSettings {
+    fn default() -> Self {
+        Self {
+            non_tls: NonTlsAction::default(),
+            hello_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl VerifySniSettings {
+    pub fn with_non_tls(mut self, non_tls: NonTlsAction) -> Self {
+        self.non_tls = non_tls;
+        self
+    }
+
+    /// Espera máxima del primer registro TLS del cliente.
+    pub fn with_hello_timeout(mut self, timeout: Duration) -> Self {
+        self.hello_timeout = timeout;
+        self
+    }
+
+    pub fn non_tls(&self) -> NonTlsAction {
+        self.non_tls
+    }
+
+    pub fn hello_timeout(&self) -> Duration {
+        self.hello_timeout
+    }
+}
+
+/// Dónde escuchan los suscriptores del flujo de eventos.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventEndpoint {
+    Unix(PathBuf),
+    /// Solo direcciones de loopback.
+    Tcp(SocketAddr),
+}
+
+/// Flujo de eventos en JSON por líneas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventSettings {
+    endpoint: EventEndpoint,
+    queue: usize,
+}
+
+impl EventSettings {
+    pub fn new(endpoint: EventEndpoint) -> Self {
+        Self {
+            endpoint,
+            queue: 1024,
+        }
+    }
+
+    /// Eventos pendientes por suscriptor; los que no caben se descartan.
+    pub fn with_queue(mut self, queue: usize) -> Self {
+        self.queue = queue.max(1);
+        self
+    }
+
+    pub fn endpoint(&self) -> &EventEndpoint {
+        &self.endpoint
+    }
+
+    pub fn queue(&self) -> usize {
+        self.queue
+    }
+}
+
+/// Límites de las cabeceras de las peticiones y de las respuestas del
+/// destino. Los tamaños cuentan cada cabecera como en el cable: nombre,
+/// `: `, valor y fin de línea.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderLimits {
+    max_header_bytes: usize,
+    max_total_bytes: usize,
+    max_count: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 8 * 1024,
+            max_total_bytes: 32 * 1024,
+            max_count: 100,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Cabeceras que hyper acepta en HTTP/1; más allá responde él mismo con
+    /// un 431 sin motivo.
+    pub const MAX_COUNT: usize = 100;
+
+    /// Tamaño de una sola cabecera.
+    pub fn with_max_header_bytes(mut self, max: usize) -> Self {
+        self.max_header_bytes = max;
+        self
+    }
+
+    /// Tamaño de todas las cabeceras juntas.
+    pub fn with_max_total_bytes(mut self, max: usize) -> Self {
+        self.max_total_bytes = max;
+        self
+    }
+
+    pub fn with_max_count(mut self, max: usize) -> Self {
+        self.max_count = max;
+        self
+    }
+
+    pub fn max_header_bytes(&self) -> usize {
+        self.max_header_bytes
+    }
+
+    pub fn max_total_bytes(&self) -> usize {
+        self.max_total_bytes
+    }
+
+    pub fn max_count(&self) -> usize {
+        self.max_count
+    }
+}
+
+/// Comprobación del límite de descriptores al arrancar.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceSettings {
+    expected_connections: Option<usize>,
+    raise_nofile: bool,
+}
+
+impl ResourceSettings {
+    /// Conexiones de clientes simultáneas previstas; solo se usan para
+    /// comprobar que el límite de descriptores alcanza.
+    pub fn with_expected_connections(mut self, connections: usize) -> Self {
+        self.expected_connections = Some(connections);
+        self
+    }
+
+    /// Sube el límite blando de descriptores hasta el duro al arrancar.
+    pub fn with_raise_nofile(mut self, raise: bool) -> Self {
+        self.raise_nofile = raise;
+        self
+    }
+
+    pub fn expected_connections(&self) -> Option<usize> {
+        self.expected_connections
+    }
+
+    pub fn raise_nofile(&self) -> bool {
+        self.raise_nofile
+    }
+}
+
+/// Modo drenaje de `POST /api/drain`.
+#[derive(Debug, Clone)]
+pub struct DrainSettings {
+    close_keepalive: bool,
+    connect_grace: Duration,
+}
+
+impl Default for DrainSettings {
+    fn default() -> Self {
+        Self {
+            close_keepalive: true,
+            connect_grace: Duration::from_secs(30),
+        }
+    }
+}
+
+impl DrainSettings {
+    /// Responde con `Connection: close` mientras dura el drenaje.
+    pub fn with_close_keepalive(mut self, close: bool) -> Self {
+        self.close_keepalive = close;
+        self
+    }
+
+    /// Tiempo desde el inicio del drenaje tras el que se rechazan los
+    /// `CONNECT` nuevos.
+    pub fn with_connect_grace(mut self, grace: Duration) -> Self {
+        self.connect_grace = grace;
+        self
+    }
+
+    pub fn close_keepalive(&self) -> bool {
+        self.close_keepalive
+    }
+
+    pub fn connect_grace(&self) -> Duration {
+        self.connect_grace
+    }
+}
+
+/// Salida en redes solo IPv6: prefijo NAT64 y cada cuánto se comprueba si
+/// hay ruta IPv4.
+#[derive(Debug, Clone)]
+pub struct Nat64Settings {
+    prefix: Option<Nat64Prefix>,
+    probe_interval: Duration,
+}
+
+impl Default for Nat64Settings {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            probe_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Nat64Settings {
+    /// Prefijo con el que sintetizar; sin él se descubre con
+    /// `ipv4only.arpa`.
+    pub fn with_prefix(mut self, prefix: Nat64Prefix) -> Self {
+        self.prefix = Some(prefix);
+        self
+    }
+
+    pub fn with_probe_interval(mut self, interval: Duration) -> Self {
+        self.probe_interval = interval;
+        self
+    }
+
+    pub fn prefix(&self) -> Option<Nat64Prefix> {
+        self.prefix
+    }
+
+    pub fn probe_interval(&self) -> Duration {
+        self.probe_interval
+    }
+}
+
+/// Cabecera que se inyecta y de qué variables de entorno sale su valor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CredentialKind {
+    Bearer {
+        token_env: String,
+    },
+    Basic {
+        username_env: String,
+        password_env: String,
+    },
+    Header {
+        name: String,
+        value_env: String,
+    },
+}
+
+/// Credencial que el proxy añade a las peticiones HTTP hacia `host`.
+#[derive(Debug, Clone)]
+pub struct CredentialSettings {
+    host: HostPattern,
+    kind: CredentialKind,
+    replace_client: bool,
+}
+
+impl CredentialSettings {
+    pub fn new(host: HostPattern, kind: CredentialKind) -> Self {
+        Self {
+            host,
+            kind,
+            replace_client: true,
+        }
+    }
+
+    /// Si es `true` (por defecto), el valor que envíe el cliente en la misma
+    /// cabecera se descarta; si no, se respeta y no se inyecta nada.
+    pub fn with_replace_client(mut self, replace: bool) -> Self {
+        self.replace_client = replace;
+        self
+    }
+
+    pub fn host(&self) -> &HostPattern {
+        &self.host
+    }
+
+    pub fn kind(&self) -> &CredentialKind {
+        &self.kind
+    }
+
+    pub fn replace_client(&self) -> bool {
+        self.replace_client
+    }
+}
+
+/// De dónde sale la clave de un proveedor de IA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// Variable de entorno, leída al arrancar.
+    Env(String),
+    /// Archivo con la clave, que se relee cuando cambia.
+    File(PathBuf),
+}
+
+/// Clave de un proveedor de IA que el proxy pone en las peticiones HTTP
+/// hacia `host`, en lugar de la que mande el cliente.
+#[derive(Debug, Clone)]
+pub struct ProviderSettings {
+    host: HostPattern,
+    key: KeySource,
+    header: hyper::header::HeaderName,
+}
+
+impl ProviderSettings {
+    pub fn new(host: HostPattern, key: KeySource) -> Self {
+        Self {
+            host,
+            key,
+            header: hyper::header::AUTHORIZATION,
+        }
+    }
+
+    /// Cabecera que lleva la clave; con `Authorization` (por defecto) va
+    /// como `Bearer <clave>` y con cualquier otra, tal cual.
+    pub fn with_header(mut self, header: hyper::header::HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn host(&self) -> &HostPattern {
+        &self.host
+    }
+
+    pub fn key(&self) -> &KeySource {
+        &self.key
+    }
+
+    pub fn header(&self) -> &hyper::header::HeaderName {
+        &self.header
+    }
+}
+
+/// Cómo se firma una petición hacia un servicio interno.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigningAlgorithm {
+    /// HMAC-SHA256 con la clave tal cual sobre la cadena canónica, con
+    /// cabeceras `X-Signature-Date` y `X-Content-Sha256`.
+    HmacSha256,
+    /// AWS Signature Version 4, con la clave derivada por fecha, región y
+    /// servicio.
+    AwsSigV4 { region: String, service: String },
+}
+
+/// Firma de las peticiones HTTP hacia `host` (y, con `path_prefix`, solo
+/// las de esas rutas) con la clave de la variable de entorno `secret_env`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigningSettings {
+    name: String,
+    host: HostPattern,
+    path_prefix: Option<String>,
+    algorithm: SigningAlgorithm,
+    key_id: String,
+    secret_env: String,
+    signed_headers: Vec<String>,
+    max_body: usize,
+    skew_tolerance: Duration,
+}
+
+impl SigningSettings {
+    pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+    pub const DEFAULT_SKEW_TOLERANCE: Duration = Duration::from_secs(30);
+
+    /// El nombre por defecto es el patrón de host.
+    pub fn new(
+        host: HostPattern,
+        algorithm: SigningAlgorithm,
+        key_id: impl Into<String>,
+        secret_env: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: host.to_string(),
+            host,
+            path_prefix: None,
+            algorithm,
+            key_id: key_id.into(),
+            secret_env: secret_env.into(),
+            signed_headers: Vec::new(),
+            max_body: Self::DEFAULT_MAX_BODY,
+            skew_tolerance: Self::DEFAULT_SKEW_TOLERANCE,
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn with_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Cabecera firmada además de `host` y la de fecha, que siempre lo están.
+    pub fn with_signed_header(mut self, name: impl Into<String>) -> Self {
+        self.signed_headers.push(name.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Body máximo que se retiene para calcular su hash; uno mayor se
+    /// rechaza con 413.
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    /// Desfase entre el reloj propio y el `Date` de las respuestas del
+    /// servicio a partir del cual se corrige la hora de las firmas.
+    pub fn with_skew_tolerance(mut self, tolerance: Duration) -> Self {
+        self.skew_tolerance = tolerance;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn host(&self) -> &HostPattern {
+        &self.host
+    }
+
+    pub fn path_prefix(&self) -> Option<&str> {
+        self.path_prefix.as_deref()
+    }
+
+    pub fn algorithm(&self) -> &SigningAlgorithm {
+        &self.algorithm
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn secret_env(&self) -> &str {
+        &self.secret_env
+    }
+
+    pub fn signed_headers(&self) -> &[String] {
+        &self.signed_headers
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    pub fn skew_tolerance(&self) -> Duration {
+        self.skew_tolerance
+    }
+}
+
+/// Atributo del certificado de cliente con el que se compara una regla.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertMatcher {
+    /// Cualquier entrada DNS, email o URI del SAN.
+    San(String),
+    CommonName(String),
+    /// SHA-256 de la SubjectPublicKeyInfo en hexadecimal.
+    Spki(String),
+}
+
+/// Qué hacer con un certificado válido que ninguna regla reconoce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnknownCertPolicy {
+    Reject,
+    Profile(String),
+}
+
+/// Perfil de acceso: destinos permitidos (vacío = todos).
+#[derive(Debug, Clone)]
+pub struct ProfileSettings {
+    name: String,
+    allow: Vec<HostPattern>,
+    data_saver: bool,
+}
+
+impl ProfileSettings {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            allow: Vec::new(),
+            data_saver: false,
+        }
+    }
+
+    pub fn with_allow(mut self, host: HostPattern) -> Self {
+        self.allow.push(host);
+        self
+    }
+
+    /// Activa el modo de ahorro de datos para los clientes del perfil.
+    pub fn with_data_saver(mut self, enabled: bool) -> Self {
+        self.data_saver = enabled;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn data_saver(&self) -> bool {
+        self.data_saver
+    }
+
+    pub fn allow(&self) -> &[HostPattern] {
+        &self.allow
+    }
+}
+
+/// Identidad de los clientes a partir de su certificado mTLS.
+#[derive(Debug, Clone)]
+pub struct IdentitySettings {
+    rules: Vec<(CertMatcher, String, String)>,
+    profiles: Vec<ProfileSettings>,
+    unknown: UnknownCertPolicy,
+    denylist: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+}
+
+impl Default for IdentitySettings {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            profiles: Vec::new(),
+            unknown: UnknownCertPolicy::Reject,
+            denylist: None,
+            client_ca: None,
+        }
+    }
+}
+
+impl IdentitySettings {
+    /// Asocia el certificado que cumpla `matcher` al usuario `user` con el
+    /// perfil `profile`. Gana la primera regla que coincide.
+    pub fn with_rule(
+        mut self,
+        matcher: CertMatcher,
+        user: impl Into<String>,
+        profile: impl Into<String>,
+    ) -> Self {
+        self.rules.push((matcher, user.into(), profile.into()));
+        self
+    }
+
+    pub fn with_profile(mut self, profile: ProfileSettings) -> Self {
+        self.profiles.push(profile);
+        self
+    }
+
+    pub fn with_unknown(mut self, policy: UnknownCertPolicy) -> Self {
+        self.unknown = policy;
+        self
+    }
+
+    /// Archivo con un hash SPKI revocado por línea; se puede recargar.
+    pub fn with_denylist(mut self, path: impl Into<PathBuf>) -> Self {
+        self.denylist = Some(path.into());
+        self
+    }
+
+    /// CAs en PEM que firman los certificados de cliente; el listener TLS
+    /// los pide y valida contra ellas.
+    pub fn with_client_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.client_ca = Some(path.into());
+        self
+    }
+
+    pub fn rules(&self) -> &[(CertMatcher, String, String)] {
+        &self.rules
+    }
+
+    pub fn profiles(&self) -> &[ProfileSettings] {
+        &self.profiles
+    }
+
+    pub fn unknown(&self) -> &UnknownCertPolicy {
+        &self.unknown
+    }
+
+    pub fn denylist(&self) -> Option<&PathBuf> {
+        self.denylist.as_ref()
+    }
+
+    pub fn client_ca(&self) -> Option<&PathBuf> {
+        self.client_ca.as_ref()
+    }
+}
+
+/// Destinos cuyas conexiones quedan ligadas a la conexión del cliente, para
+/// autenticación por conexión como NTLM o Negotiate.
+#[derive(Debug, Clone)]
+pub struct AffinitySettings {
+    hosts: Vec<HostPattern>,
+    max_connections_per_client: usize,
+    auto_detect: bool,
+}
+
+impl Default for AffinitySettings {
+    fn default() -> Self {
+        Self {
+            hosts: Vec::new(),
+            max_connections_per_client: 4,
+            auto_detect: true,
+        }
+    }
+}
+
+impl AffinitySettings {
+    pub fn with_host(mut self, host: HostPattern) -> Self {
+        self.hosts.push(host);
+        self
+    }
+
+    /// Conexiones simultáneas como máximo por cliente hacia estos destinos.
+    pub fn with_max_connections_per_client(mut self, max: usize) -> Self {
+        self.max_connections_per_client = max.max(1);
+        self
+    }
+
+    /// Añade los destinos que responden `WWW-Authenticate: NTLM|Negotiate`.
+    pub fn with_auto_detect(mut self, auto_detect: bool) -> Self {
+        self.auto_detect = auto_detect;
+        self
+    }
+
+    pub fn hosts(&self) -> &[HostPattern] {
+        &self.hosts
+    }
+
+    pub fn max_connections_per_client(&self) -> usize {
+        self.max_connections_per_client
+    }
+
+    pub fn auto_detect(&self) -> bool {
+        self.auto_detect
+    }
+}
+
+/// Archivo de estadísticas diarias persistentes.
+#[derive(Debug, Clone)]
+pub struct StatsSettings {
+    path: PathBuf,
+    retention_days: u64,
+    flush_interval: Duration,
+}
+
+impl StatsSettings {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            retention_days: 90,
+            flush_interval: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_retention_days(mut self, days: u64) -> Self {
+        self.retention_days = days.max(1);
+        self
+    }
+
+    /// Cada cuánto se vuelcan a disco; lo no volcado se pierde si el proceso
+    /// muere.
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    pub fn retention_days(&self) -> u64 {
+        self.retention_days
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+}
+
+/// Informes de tráfico: cuándo se generan, qué periodo cubren y adónde van.
+#[derive(Debug, Clone)]
+pub struct ReportSettings {
+    schedule: Option<Schedule>,
+    period_days: u64,
+    top: usize,
+    template: Option<PathBuf>,
+    webhook: Option<String>,
+    dir: Option<PathBuf>,
+}
+
+impl Default for ReportSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReportSettings {
+    pub fn new() -> Self {
+        Self {
+            schedule: None,
+            period_days: 7,
+            top: 10,
+            template: None,
+            webhook: None,
+            dir: None,
+        }
+    }
+
+    /// Sin programación, solo se generan bajo demanda.
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    pub fn with_period_days(mut self, days: u64) -> Self {
+        self.period_days = days.max(1);
+        self
+    }
+
+    /// Filas de las tablas de clientes y destinos principales.
+    pub fn with_top(mut self, top: usize) -> Self {
+        self.top = top;
+        self
+    }
+
+    pub fn with_template(mut self, path: impl Into<PathBuf>) -> Self {
+        self.template = Some(path.into());
+        self
+    }
+
+    pub fn with_webhook(mut self, url: impl Into<String>) -> Self {
+        self.webhook = Some(url.into());
+        self
+    }
+
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    pub fn schedule(&self) -> Option<Schedule> {
+        self.schedule
+    }
+
+    pub fn period_days(&self) -> u64 {
+        self.period_days
+    }
+
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    pub fn template(&self) -> Option<&PathBuf> {
+        self.template.as_ref()
+    }
+
+    pub fn webhook(&self) -> Option<&str> {
+        self.webhook.as_deref()
+    }
+
+    pub fn dir(&self) -> Option<&PathBuf> {
+        self.dir.as_ref()
+    }
+}
+
+/// Dónde se resuelve el nombre de un destino que sale por el proxy padre.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParentResolve {
+    /// El padre recibe el nombre y lo resuelve con su vista del DNS; no se
+    /// aplican las comprobaciones por IP.
+    #[default]
+    Remote,
+    /// El proxy resuelve, aplica las comprobaciones por IP y pide al padre
+    /// el túnel hacia la IP.
+    Local,
+}
+
+/// PAC del que se obtiene el proxy padre de cada destino.
+#[derive(Debug, Clone)]
+pub struct PacSettings {
+    url: String,
+    refresh: Duration,
+    fallback: Option<hyper::http::uri::Authority>,
+    cache_size: usize,
+    resolve: ParentResolve,
+}
+
+impl PacSettings {
+    /// `url` es una URL `http://` o una ruta local (con o sin `file://`).
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            refresh: Duration::from_secs(300),
+            fallback: None,
+            cache_size: 1024,
+            resolve: ParentResolve::default(),
+        }
+    }
+
+    pub fn with_refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Proxy a usar si el PAC no está disponible o falla; sin él, directo.
+    pub fn with_fallback(mut self, parent: hyper::http::uri::Authority) -> Self {
+        self.fallback = Some(parent);
+        self
+    }
+
+    pub fn with_cache_size(mut self, size: usize) -> Self {
+        self.cache_size = size;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn refresh(&self) -> Duration {
+        self.refresh
+    }
+
+    pub fn fallback(&self) -> Option<&hyper::http::uri::Authority> {
+        self.fallback.as_ref()
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.cache_size
+    }
+
+    /// Resolución de los destinos que el PAC manda al proxy padre; los
+    /// directos siempre se resuelven aquí.
+    pub fn with_resolve(mut self, resolve: ParentResolve) -> Self {
+        self.resolve = resolve;
+        self
+    }
+
+    pub fn resolve(&self) -> ParentResolve {
+        self.resolve
+    }
+}
+
+/// Proxy padre fijo: las peticiones HTTP se le envían en forma absoluta y
+/// los túneles se le piden con su propio `CONNECT`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UpstreamProxySettings {
+    parent: hyper::http::uri::Authority,
+    credentials: Option<(String, AdminToken)>,
+}
+
+impl UpstreamProxySettings {
+    pub fn new(parent: hyper::http::uri::Authority) -> Self {
+        Self {
+            parent,
+            credentials: None,
+        }
+    }
+
+    /// Credenciales `Basic` para el padre; solo viajan en el salto hacia él.
+    pub fn with_credentials(
+        mut self,
+        user: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some((user.into(), AdminToken::new(password)));
+        self
+    }
+
+    pub fn parent(&self) -> &hyper::http::uri::Authority {
+        &self.parent
+    }
+
+    pub fn credentials(&self) -> Option<&(String, AdminToken)> {
+        self.credentials.as_ref()
+    }
+}
+
+impl std::fmt::Debug for UpstreamProxySettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamProxySettings")
+            .field("parent", &self.parent)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+/// Servidor SOCKS5 de salida, como `host:puerto`.
+#[derive(Clone, PartialEq)]
+pub struct Socks5UpstreamSettings {
+    server: hyper::http::uri::Authority,
+    credentials: Option<(String, AdminToken)>,
+}
+
+impl Socks5UpstreamSettings {
+    pub fn new(server: hyper::http::uri::Authority) -> Self {
+        Self {
+            server,
+            credentials: None,
+        }
+    }
+
+    /// Usuario y contraseña (RFC 1929), si el servidor los pide.
+    pub fn with_credentials(
+        mut self,
+        user: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some((user.into(), AdminToken::new(password)));
+        self
+    }
+
+    pub fn server(&self) -> &hyper::http::uri::Authority {
+        &self.server
+    }
+
+    pub fn credentials(&self) -> Option<&(String, AdminToken)> {
+        self.credentials.as_ref()
+    }
+}
+
+impl std::fmt::Debug for Socks5UpstreamSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Socks5UpstreamSettings")
+            .field("server", &self.server)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+/// PAC servido en `GET /proxy.pac`: `advertise` es el `host:puerto` que
+/// reciben los navegadores (sin él, el de la petición) y `bypass` los
+/// destinos que van en `DIRECT`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServePacSettings {
+    advertise: Option<hyper::http::uri::Authority>,
+    bypass: Vec<HostRule>,
+}
+
+impl ServePacSettings {
+    pub fn with_advertise(mut self, advertise: hyper::http::uri::Authority) -> Self {
+        self.advertise = Some(advertise);
+        self
+    }
+
+    pub fn with_bypass(mut self, rule: HostRule) -> Self {
+        self.bypass.push(rule);
+        self
+    }
+
+    pub fn advertise(&self) -> Option<&hyper::http::uri::Authority> {
+        self.advertise.as_ref()
+    }
+
+    pub fn bypass(&self) -> &[HostRule] {
+        &self.bypass
+    }
+}
+
+/// Modo transparente: `scheme` es el esquema de los destinos que se
+/// reconstruyen a partir de `Host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransparentSettings {
+    scheme: hyper::http::uri::Scheme,
+}
+
+impl Default for TransparentSettings {
+    fn default() -> Self {
+        Self {
+            scheme: hyper::http::uri::Scheme::HTTP,
+        }
+    }
+}
+
+impl TransparentSettings {
+    pub fn with_scheme(mut self, scheme: hyper::http::uri::Scheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    pub fn scheme(&self) -> &hyper::http::uri::Scheme {
+        &self.scheme
+    }
+}
+
+/// Canary hacia un proxy padre nuevo: `weight` % del tráfico que casa con
+/// `hosts` va por `parent` y el resto sigue su camino de siempre. Si en la
+/// ventana el canary supera al estable en tasa de errores o en p95 por más
+/// de los márgenes, su peso baja a cero hasta que se restablezca a mano.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanarySettings {
+    parent: UpstreamProxySettings,
+    weight: f64,
+    hosts: Vec<HostRule>,
+    window: Duration,
+    min_requests: usize,
+    error_margin: f64,
+    p95_margin: Duration,
+    webhook: Option<String>,
+}
+
+impl CanarySettings {
+    pub const DEFAULT_WINDOW: Duration = Duration::from_secs(300);
+    pub const DEFAULT_MIN_REQUESTS: usize = 20;
+    pub const DEFAULT_ERROR_MARGIN: f64 = 0.05;
+    pub const DEFAULT_P95_MARGIN: Duration = Duration::from_millis(500);
+
+    pub fn new(parent: UpstreamProxySettings, weight: f64) -> Self {
+        Self {
+            parent,
+            weight: weight.clamp(0.0, 100.0),
+            hosts: Vec::new(),
+            window: Self::DEFAULT_WINDOW,
+            min_requests: Self::DEFAULT_MIN_REQUESTS,
+            error_margin: Self::DEFAULT_ERROR_MARGIN,
+            p95_margin: Self::DEFAULT_P95_MARGIN,
+            webhook: None,
+        }
+    }
+
+    /// Sin reglas, el canary se reparte todo el tráfico.
+    pub fn with_host(mut self, rule: HostRule) -> Self {
+        self.hosts.push(rule);
+        self
+    }
+
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Peticiones que necesita cada lado en la ventana para compararlos.
+    pub fn with_min_requests(mut self, min: usize) -> Self {
+        self.min_requests = min.max(1);
+        self
+    }
+
+    /// Diferencia tolerada en la tasa de errores, entre 0 y 1.
+    pub fn with_error_margin(mut self, margin: f64) -> Self {
+        self.error_margin = margin.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_p95_margin(mut self, margin: Duration) -> Self {
+        self.p95_margin = margin;
+        self
+    }
+
+    /// URL `http://` que recibe un POST con el motivo de cada vuelta atrás.
+    pub fn with_webhook(mut self, url: impl Into<String>) -> Self {
+        self.webhook = Some(url.into());
+        self
+    }
+
+    pub fn parent(&self) -> &UpstreamProxySettings {
+        &self.parent
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    pub fn hosts(&self) -> &[HostRule] {
+        &self.hosts
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn min_requests(&self) -> usize {
+        self.min_requests
+    }
+
+    pub fn error_margin(&self) -> f64 {
+        self.error_margin
+    }
+
+    pub fn p95_margin(&self) -> Duration {
+        self.p95_margin
+    }
+
+    pub fn webhook(&self) -> Option<&str> {
+        self.webhook.as_deref()
+    }
+}
+
+/// Modo de ahorro de datos: recompresión de imágenes grandes para los
+/// clientes que lo piden (`Save-Data: on`) o cuyo perfil lo activa.
+#[derive(Debug, Clone)]
+pub struct DataSaverSettings {
+    min_bytes: usize,
+    max_input_bytes: usize,
+    jpeg_quality: u8,
+    max_dimension: u32,
+    timeout: Duration,
+    max_concurrent: usize,
+    strip_video_preload: bool,
+}
+
+impl Default for DataSaverSettings {
+    fn default() -> Self {
+        Self {
+            min_bytes: 32 * 1024,
+            max_input_bytes: 10 * 1024 * 1024,
+            jpeg_quality: 70,
+            max_dimension: 1280,
+            timeout: Duration::from_secs(2),
+            max_concurrent: 4,
+            strip_video_preload: false,
+        }
+    }
+}
+
+impl DataSaverSettings {
+    /// Las imágenes más pequeñas se entregan sin tocar.
+    pub fn with_min_bytes(mut self, bytes: usize) -> Self {
+        self.min_bytes = bytes;
+        self
+    }
+
+    /// Las imágenes más grandes tampoco se tocan, para acotar la memoria.
+    pub fn with_max_input_bytes(mut self, bytes: usize) -> Self {
+        self.max_input_bytes = bytes;
+        self
+    }
+
+    pub fn with_jpeg_quality(mut self, quality: u8) -> Self {
+        self.jpeg_quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// Lado máximo en píxeles; se conserva la proporción.
+    pub fn with_max_dimension(mut self, pixels: u32) -> Self {
+        self.max_dimension = pixels.max(1);
+        self
+    }
+
+    /// Pasado este tiempo se entrega la imagen original.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Recompresiones simultáneas en todo el proxy; por encima se entrega la
+    /// imagen original.
+    pub fn with_max_concurrent(mut self, max: usize) -> Self {
+        self.max_concurrent = max.max(1);
+        self
+    }
+
+    /// Quita las cabeceras `Link` que precargan vídeo.
+    pub fn with_strip_video_preload(mut self, strip: bool) -> Self {
+        self.strip_video_preload = strip;
+        self
+    }
+
+    pub fn min_bytes(&self) -> usize {
+        self.min_bytes
+    }
+
+    pub fn max_input_bytes(&self) -> usize {
+        self.max_input_bytes
+    }
+
+    pub fn jpeg_quality(&self) -> u8 {
+        self.jpeg_quality
+    }
+
+    pub fn max_dimension(&self) -> u32 {
+        self.max_dimension
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    pub fn strip_video_preload(&self) -> bool {
+        self.strip_video_preload
+    }
+}
+
+/// Subsistemas que se pueden activar gradualmente con un rollout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    Capture,
+    Script,
+    Credentials,
+    UpstreamPac,
+    DataSaver,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 5] = [
+        Feature::Capture,
+        Feature::Script,
+        Feature::Credentials,
+        Feature::UpstreamPac,
+        Feature::DataSaver,
+    ];
+
+    /// Nombre de la sección de configuración del subsistema.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Feature::Capture => "capture",
+            Feature::Script => "script",
+            Feature::Credentials => "credentials",
+            Feature::UpstreamPac => "upstream_pac",
+            Feature::DataSaver => "data_saver",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl std::str::FromStr for Feature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|feature| feature.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("feature de rollout desconocida: {s}"))
+    }
+}
+
+/// Porcentaje de clientes que reciben una feature. Cada cliente (usuario o
+/// IP) cae siempre en el mismo bucket, así que subir el porcentaje solo suma
+/// clientes nuevos. Los sujetos de `include` la reciben siempre.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureRollout {
+    percent: f64,
+    include: Vec<String>,
+}
+
+impl FeatureRollout {
+    pub fn new(percent: f64) -> Self {
+        Self {
+            percent: percent.clamp(0.0, 100.0),
+            include: Vec::new(),
+        }
+    }
+
+    /// Usuario o IP de cliente que siempre recibe la feature.
+    pub fn with_include(mut self, subject: impl Into<String>) -> Self {
+        self.include.push(subject.into());
+        self
+    }
+
+    pub fn percent(&self) -> f64 {
+        self.percent
+    }
+
+    pub fn include(&self) -> &[String] {
+        &self.include
+    }
+}
+
+/// Rollouts por feature. Las features sin rollout están activas para todos.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RolloutSettings {
+    features: Vec<(Feature, FeatureRollout)>,
+}
+
+impl RolloutSettings {
+    pub fn with_feature(mut self, feature: Feature, rollout: FeatureRollout) -> Self {
+        self.features.retain(|(f, _)| *f != feature);
+        self.features.push((feature, rollout));
+        self
+    }
+
+    pub fn get(&self, feature: Feature) -> Option<&FeatureRollout> {
+        self.features
+            .iter()
+            .find(|(f, _)| *f == feature)
+            .map(|(_, rollout)| rollout)
+    }
+}
+
+/// Qué hacer con una respuesta DNS que cae en el rango de una reescritura.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsRewriteAction {
+    /// Sustituye la dirección, conservando el puerto.
+    Replace(IpAddr),
+    /// Vuelve a preguntar el nombre a otro servidor DNS y usa su respuesta.
+    Requery(SocketAddr),
+}
+
+/// Reescrituras de respuestas DNS para redes con horizonte dividido. Las
+/// zonas envían ciertos nombres a otro servidor; las reescrituras cambian
+/// las respuestas de un nombre que caen en un rango. En ambos casos gana la
+/// primera regla que coincide.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DnsSettings {
+    zones: Vec<(HostPattern, SocketAddr)>,
+    rewrites: Vec<(HostPattern, Cidr, DnsRewriteAction)>,
+}
+
+impl DnsSettings {
+    /// Resuelve los nombres de `zone` con `server` en lugar del DNS del sistema.
+    pub fn with_zone(mut self, zone: HostPattern, server: SocketAddr) -> Self {
+        self.zones.push((zone, server));
+        self
+    }
+
+    /// Aplica `action` a las respuestas de `host` que caen en `answer`.
+    pub fn with_rewrite(
+        mut self,
+        host: HostPattern,
+        answer: Cidr,
+        action: DnsRewriteAction,
+    ) -> Self {
+        self.rewrites.push((host, answer, action));
+        self
+    }
+
+    pub fn zones(&self) -> &[(HostPattern, SocketAddr)] {
+        &self.zones
+    }
+
+    pub fn rewrites(&self) -> &[(HostPattern, Cidr, DnsRewriteAction)] {
+        &self.rewrites
+    }
+}
+
+/// Qué recibe un cliente baneado al conectar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanAction {
+    /// Cierra el socket sin leer nada.
+    Drop,
+    /// Responde un 403 mínimo y cierra.
+    Forbidden,
+}
+
+impl BanAction {
+    pub const ALL: [BanAction; 2] = [BanAction::Drop, BanAction::Forbidden];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BanAction::Drop => "drop",
+            BanAction::Forbidden => "forbidden",
+        }
+    }
+}
+
+impl std::str::FromStr for BanAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|action| action.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("acción de baneo desconocida: {s}"))
+    }
+}
+
+/// Baneo temporal de clientes que acumulan infracciones (destinos
+/// bloqueados, framing ambiguo, fallos de autenticación...). Cada baneo
+/// repetido dura el doble que el anterior, hasta `max_duration`.
+#[derive(Debug, Clone)]
+pub struct BanSettings {
+    threshold: usize,
+    window: Duration,
+    duration: Duration,
+    max_duration: Duration,
+    action: BanAction,
+    trusted: Vec<Cidr>,
+    path: Option<PathBuf>,
+}
+
+impl Default for BanSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 10,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(600),
+            max_duration: Duration::from_secs(24 * 3600),
+            action: BanAction::Forbidden,
+            trusted: Vec::new(),
+            path: None,
+        }
+    }
+}
+
+impl BanSettings {
+    /// Infracciones dentro de `window` que disparan el baneo.
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold.max(1);
+        self
+    }
+
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Duración del primer baneo.
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn with_max_duration(mut self, max: Duration) -> Self {
+        self.max_duration = max;
+        self
+    }
+
+    pub fn with_action(mut self, action: BanAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Rango de clientes que nunca se banea.
+    pub fn with_trusted(mut self, range: Cidr) -> Self {
+        self.trusted.push(range);
+        self
+    }
+
+    /// Archivo donde se guardan los baneos para que sobrevivan a reinicios.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn max_duration(&self) -> Duration {
+        self.max_duration
+    }
+
+    pub fn action(&self) -> BanAction {
+        self.action
+    }
+
+    pub fn trusted(&self) -> &[Cidr] {
+        &self.trusted
+    }
+
+    pub fn path(&self) -> Option<&std::path::Path> {
+        self.path.as_deref()
+    }
+}
+
+/// Dónde se escribe el log de acceso.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessLogTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+impl std::str::FromStr for AccessLogTarget {
+    type Err = anyhow::Error;
+
+    /// `-` es la salida estándar; cualquier otra cosa, una ruta.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => anyhow::bail!("la ruta del log de acceso está vacía"),
+            "-" => Ok(AccessLogTarget::Stdout),
+            path => Ok(AccessLogTarget::File(PathBuf::from(path))),
+        }
+    }
+}
+
+/// Cómo quedan las IPs de los clientes y las URLs en lo que el proxy
+/// registra o guarda; ver [`crate::privacy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogProfile {
+    #[default]
+    Full,
+    /// IPs sin el último octeto (o los últimos 64 bits) y URLs recortadas.
+    Truncate,
+    /// HMAC con una clave que cambia cada día.
+    Hashed,
+}
+
+impl LogProfile {
+    pub const ALL: [LogProfile; 3] = [LogProfile::Full, LogProfile::Truncate, LogProfile::Hashed];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogProfile::Full => "full",
+            LogProfile::Truncate => "truncate",
+            LogProfile::Hashed => "hashed",
+        }
+    }
+}
+
+impl std::str::FromStr for LogProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|profile| profile.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("perfil de logs desconocido: {s}"))
+    }
+}
+
+/// Clase de un cliente frente a la sobrecarga: cuánto espera en la cola
+/// antes de recibir un 503.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientClass {
+    /// Identidad con un perfil de `trusted_profiles`.
+    Trusted,
+    /// Cualquier otro cliente identificado o autenticado.
+    Standard,
+    Anonymous,
+}
+
+impl ClientClass {
+    /// En orden de prioridad en la cola.
+    pub const ALL: [ClientClass; 3] = [
+        ClientClass::Trusted,
+        ClientClass::Standard,
+        ClientClass::Anonymous,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientClass::Trusted => "trusted",
+            ClientClass::Standard => "standard",
+            ClientClass::Anonymous => "anonymous",
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Límite de peticiones en curso. Por encima de `max_in_flight` las
+/// peticiones esperan en una cola (primero las de los clientes de
+/// confianza) hasta la espera de su clase; la de los anónimos es 0 por
+/// defecto, así que son los primeros en recibir el 503.
+#[derive(Debug, Clone)]
+pub struct OverloadSettings {
+    max_in_flight: usize,
+    max_queue: usize,
+    max_per_client: Option<usize>,
+    trusted_profiles: Vec<String>,
+    queue_timeouts: [Duration; 3],
+    max_retry_after: Duration,
+}
+
+impl OverloadSettings {
+    pub const DEFAULT_MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+    /// La cola admite tantas peticiones como `max_in_flight`.
+    pub fn new(max_in_flight: usize) -> Self {
+        let max_in_flight = max_in_flight.max(1);
+        Self {
+            max_in_flight,
+            max_queue: max_in_flight,
+            max_per_client: None,
+            trusted_profiles: Vec::new(),
+            queue_timeouts: [
+                Duration::from_secs(2),
+                Duration::from_millis(500),
+                Duration::ZERO,
+            ],
+            max_retry_after: Self::DEFAULT_MAX_RETRY_AFTER,
+        }
+    }
+
+    pub fn with_max_queue(mut self, max: usize) -> Self {
+        self.max_queue = max;
+        self
+    }
+
+    /// Peticiones en curso de un mismo cliente (usuario o IP); las que
+    /// pasan se rechazan sin esperar.
+    pub fn with_max_per_client(mut self, max: usize) -> Self {
+        self.max_per_client = Some(max).filter(|max| *max > 0);
+        self
+    }
+
+    pub fn with_trusted_profile(mut self, profile: impl Into<String>) -> Self {
+        self.trusted_profiles.push(profile.into());
+        self
+    }
+
+    /// Espera máxima en la cola de los clientes de `class`; 0 no espera.
+    pub fn with_queue_timeout(mut self, class: ClientClass, timeout: Duration) -> Self {
+        self.queue_timeouts[class.index()] = timeout;
+        self
+    }
+
+    /// Tope del `Retry-After` calculado.
+    pub fn with_max_retry_after(mut self, max: Duration) -> Self {
+        self.max_retry_after = max.max(Duration::from_secs(1));
+        self
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    pub fn max_queue(&self) -> usize {
+        self.max_queue
+    }
+
+    pub fn max_per_client(&self) -> Option<usize> {
+        self.max_per_client
+    }
+
+    pub fn trusted_profiles(&self) -> &[String] {
+        &self.trusted_profiles
+    }
+
+    pub fn queue_timeout(&self, class: ClientClass) -> Duration {
+        self.queue_timeouts[class.index()]
+    }
+
+    pub fn max_retry_after(&self) -> Duration {
+        self.max_retry_after
+    }
+}
+
+/// Política de salida hacia los destinos.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EgressMode {
+    /// Se permite todo lo que no bloqueen la reputación, el script o el
+    /// perfil.
+    #[default]
+    Allow,
+    /// Solo se permiten los destinos de alguna regla [`EgressRule`]; las
+    /// listas de bloqueo no se consultan.
+    Deny,
+}
+
+impl EgressMode {
+    pub const ALL: [EgressMode; 2] = [EgressMode::Allow, EgressMode::Deny];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EgressMode::Allow => "allow",
+            EgressMode::Deny => "deny",
+        }
+    }
+}
+
+impl std::str::FromStr for EgressMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("política de salida desconocida: {s}"))
+    }
+}
+
+/// Destino permitido en modo [`EgressMode::Deny`]. Sin puerto vale
+/// cualquiera; con prefijos de ruta solo casa con peticiones HTTP cuya ruta
+/// empiece por alguno, nunca con túneles `CONNECT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EgressRule {
+    name: String,
+    host: HostPattern,
+    port: Option<u16>,
+    path_prefixes: Vec<String>,
+}
+
+impl EgressRule {
+    /// El nombre por defecto es `host:puerto`, con `*` si no hay puerto.
+    pub fn new(host: HostPattern, port: Option<u16>) -> Self {
+        let name = match port {
+            Some(port) => format!("{host}:{port}"),
+            None => format!("{host}:*"),
+        };
+        Self {
+            name,
+            host,
+            port,
+            path_prefixes: Vec::new(),
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn with_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefixes.push(prefix.into());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn host(&self) -> &HostPattern {
+        &self.host
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    pub fn path_prefixes(&self) -> &[String] {
+        &self.path_prefixes
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EgressSettings {
+    mode: EgressMode,
+    rules: Vec<EgressRule>,
+    contact: Option<String>,
+}
+
+impl EgressSettings {
+    pub fn with_mode(mut self, mode: EgressMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_rule(mut self, rule: EgressRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Indicación para pedir un destino nuevo que acompaña al 403, p. ej. la
+    /// URL del formulario de solicitudes.
+    pub fn with_contact(mut self, contact: impl Into<String>) -> Self {
+        self.contact = Some(contact.into());
+        self
+    }
+
+    pub fn mode(&self) -> EgressMode {
+        self.mode
+    }
+
+    pub fn rules(&self) -> &[EgressRule] {
+        &self.rules
+    }
+
+    pub fn contact(&self) -> Option<&str> {
+        self.contact.as_deref()
+    }
+}
+
+/// Destinos permitidos y bloqueados; ver [`crate::host_filter`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostFilterSettings {
+    allow: Vec<HostRule>,
+    deny: Vec<HostRule>,
+}
+
+impl HostFilterSettings {
+    pub fn with_allow(mut self, rule: HostRule) -> Self {
+        self.allow.push(rule);
+        self
+    }
+
+    pub fn with_deny(mut self, rule: HostRule) -> Self {
+        self.deny.push(rule);
+        self
+    }
+
+    pub fn allow(&self) -> &[HostRule] {
+        &self.allow
+    }
+
+    pub fn deny(&self) -> &[HostRule] {
+        &self.deny
+    }
+}
+
+/// Sentido de las cabeceras que reescribe una [`HeaderRule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderDirection {
+    /// Las que el proxy envía al destino.
+    Request,
+    /// Las que el proxy devuelve al cliente.
+    Response,
+}
+
+impl HeaderDirection {
+    pub const ALL: [HeaderDirection; 2] = [HeaderDirection::Request, HeaderDirection::Response];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeaderDirection::Request => "request",
+            HeaderDirection::Response => "response",
+        }
+    }
+}
+
+impl std::str::FromStr for HeaderDirection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|direction| direction.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("sentido de cabeceras desconocido: {s}"))
+    }
+}
+
+/// Qué hace una [`HeaderRule`] con su cabecera.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderOp {
+    /// Quita todos los valores.
+    Remove,
+    /// Sustituye los valores que haya por este.
+    Set(hyper::header::HeaderValue),
+    /// Añade este valor a los que haya.
+    Append(hyper::header::HeaderValue),
+}
+
+/// Reescritura de una cabecera en un sentido, para todos los destinos o
+/// solo para los que casan con `host`. El nombre y el valor se validan al
+/// construirla, así que una configuración inválida falla al cargar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderRule {
+    direction: HeaderDirection,
+    name: hyper::header::HeaderName,
+    op: HeaderOp,
+    host: Option<HostPattern>,
+}
+
+impl HeaderRule {
+    pub fn remove(direction: HeaderDirection, name: &str) -> anyhow::Result<Self> {
+        Self::new(direction, name, HeaderOp::Remove)
+    }
+
+    pub fn set(direction: HeaderDirection, name: &str, value: &str) -> anyhow::Result<Self> {
+        let value = Self::value(name, value)?;
+        Self::new(direction, name, HeaderOp::Set(value))
+    }
+
+    pub fn append(direction: HeaderDirection, name: &str, value: &str) -> anyhow::Result<Self> {
+        let value = Self::value(name, value)?;
+        Self::new(direction, name, HeaderOp::Append(value))
+    }
+
+    fn new(direction: HeaderDirection, name: &str, op: HeaderOp) -> anyhow::Result<Self> {
+        let name = hyper::header::HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| anyhow::anyhow!("nombre de cabecera inválido: `{name}`"))?;
+        // The proxy owns the framing and connection management headers.
+        const RESERVED: [&str; 9] = [
+            "connection",
+            "content-length",
+            "keep-alive",
+            "proxy-authenticate",
+            "proxy-authorization",
+            "te",
+            "trailers",
+            "transfer-encoding",
+            "upgrade",
+        ];
+        if RESERVED.contains(&name.as_str()) {
+            anyhow::bail!("la cabecera `{name}` no se puede reescribir");
+        }
+        Ok(Self {
+            direction,
+            name,
+            op,
+            host: None,
+        })
+    }
+
+    fn value(name: &str, value: &str) -> anyhow::Result<hyper::header::HeaderValue> {
+        hyper::header::HeaderValue::from_str(value)
+            .map_err(|_| anyhow::anyhow!("valor inválido para la cabecera `{name}`"))
+    }
+
+    /// Limita la regla a los destinos que casan con `host`.
+    pub fn with_host(mut self, host: HostPattern) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    pub fn direction(&self) -> HeaderDirection {
+        self.direction
+    }
+
+    pub fn name(&self) -> &hyper::header::HeaderName {
+        &self.name
+    }
+
+    pub fn op(&self) -> &HeaderOp {
+        &self.op
+    }
+
+    pub fn host(&self) -> Option<&HostPattern> {
+        self.host.as_ref()
+    }
+}
+
+/// Límite global de ancho de banda repartido entre los clientes activos.
+///
+/// Cada cliente (su usuario de [`Identity`](crate::identity::Identity) o su
+/// IP) recibe en cada ronda `quantum` bytes por unidad de peso; el peso sale
+/// de su perfil y es 1 si no tiene uno configurado.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandwidthSettings {
+    bytes_per_sec: u64,
+    quantum: usize,
+    weights: HashMap<String, u32>,
+}
+
+impl BandwidthSettings {
+    pub const DEFAULT_QUANTUM: usize = 16 * 1024;
+
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            quantum: Self::DEFAULT_QUANTUM,
+            weights: HashMap::new(),
+        }
+    }
+
+    /// Bytes por ronda y por unidad de peso; también es el mayor trozo que
+    /// se envía de una vez.
+    pub fn with_quantum(mut self, quantum: usize) -> Self {
+        self.quantum = quantum.max(1);
+        self
+    }
+
+    pub fn with_profile_weight(mut self, profile: impl Into<String>, weight: u32) -> Self {
+        self.weights.insert(profile.into(), weight.max(1));
+        self
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    pub fn quantum(&self) -> usize {
+        self.quantum
+    }
+
+    pub fn weight(&self, profile: Option<&str>) -> u32 {
+        profile
+            .and_then(|profile| self.profile_weight(profile))
+            .unwrap_or(1)
+    }
+
+    /// Peso propio de `profile`, si la configuración le da uno.
+    pub fn profile_weight(&self, profile: &str) -> Option<u32> {
+        self.weights.get(profile).copied()
+    }
+}
+
+/// Precio de un modelo en dólares por millón de tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input_per_mtok: f64,
+    pub output_per_mtok: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, input_tokens: u64, output_tokens: u64) -> f64 {
+        (input_tokens as f64 * self.input_per_mtok + output_tokens as f64 * self.output_per_mtok)
+            / 1_000_000.0
+    }
+}
+
+/// Qué hacer con las respuestas de un host cuyo body no casa con su
+/// `Content-Type`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SniffAction {
+    /// Agrega `X-Detected-Content-Type` con el tipo detectado.
+    #[default]
+    Detect,
+    /// Reemplaza el `Content-Type` cuando es claramente incorrecto.
+    Override,
+    /// Agrega `X-Content-Type-Options: nosniff` sin leer el body.
+    Nosniff,
+}
+
+impl SniffAction {
+    pub const ALL: [SniffAction; 3] = [
+        SniffAction::Detect,
+        SniffAction::Override,
+        SniffAction::Nosniff,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SniffAction::Detect => "detect",
+            SniffAction::Override => "override",
+            SniffAction::Nosniff => "nosniff",
+        }
+    }
+}
+
+impl std::str::FromStr for SniffAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|action| action.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("acción de detección de tipo desconocida: {s}"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniffRule {
+    host: HostPattern,
+    action: SniffAction,
+}
+
+impl SniffRule {
+    pub fn new(host: HostPattern, action: SniffAction) -> Self {
+        Self { host, action }
+    }
+
+    pub fn host(&self) -> &HostPattern {
+        &self.host
+    }
+
+    pub fn action(&self) -> SniffAction {
+        self.action
+    }
+}
+
+/// Corrección del `Content-Type` de las respuestas HTTP por host. Manda la
+/// primera regla que casa.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniffSettings {
+    rules: Vec<SniffRule>,
+    max_bytes: usize,
+}
+
+impl Default for SniffSettings {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            max_bytes: Self::DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl SniffSettings {
+    pub const DEFAULT_MAX_BYTES: usize = 512;
+
+    pub fn with_rule(mut self, rule: SniffRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Bytes del principio del body que se examinan.
+    pub fn with_max_bytes(mut self, max: usize) -> Self {
+        self.max_bytes = max.max(1);
+        self
+    }
+
+    pub fn rules(&self) -> &[SniffRule] {
+        &self.rules
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+}
+
+/// Peticiones HTTP hacia `host` (y, con `path_prefix`, solo las de esas
+/// rutas) en las que se respeta `Idempotency-Key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencyRoute {
+    host: HostPattern,
+    path_prefix: Option<String>,
+}
+
+impl IdempotencyRoute {
+    pub fn new(host: HostPattern) -> Self {
+        Self {
+            host,
+            path_prefix: None,
+        }
+    }
+
+    pub fn with_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn host(&self) -> &HostPattern {
+        &self.host
+    }
+
+    pub fn path_prefix(&self) -> Option<&str> {
+        self.path_prefix.as_deref()
+    }
+
+    pub fn matches(&self, host: &str, path: &str) -> bool {
+        self.host.matches(host)
+            && self
+                .path_prefix
+                .as_deref()
+                .is_none_or(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// Respuestas guardadas por `Idempotency-Key` para devolverlas a los
+/// reintentos en vez de reenviarlos.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencySettings {
+    routes: Vec<IdempotencyRoute>,
+    ttl: Duration,
+    max_entries: usize,
+    max_body: usize,
+}
+
+impl Default for IdempotencySettings {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            ttl: Self::DEFAULT_TTL,
+            max_entries: Self::DEFAULT_MAX_ENTRIES,
+            max_body: Self::DEFAULT_MAX_BODY,
+        }
+    }
+}
+
+impl IdempotencySettings {
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+    pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+    pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+    pub fn with_route(mut self, route: IdempotencyRoute) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Tiempo que se guarda cada respuesta.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Claves guardadas a la vez; al llenarse se descarta la menos usada.
+    pub fn with_max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max.max(1);
+        self
+    }
+
+    /// Body máximo de la petición (para su hash) y de la respuesta (para
+    /// guardarla).
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    pub fn routes(&self) -> &[IdempotencyRoute] {
+        &self.routes
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+}
+
+/// Caché en memoria de las respuestas `GET`/`HEAD` cacheables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseCacheSettings {
+    max_entries: usize,
+    max_body: usize,
+    stale: Vec<StaleDefaults>,
+    max_revalidations_per_host: usize,
+}
+
+impl Default for ResponseCacheSettings {
+    fn default() -> Self {
+        Self {
+            max_entries: Self::DEFAULT_MAX_ENTRIES,
+            max_body: Self::DEFAULT_MAX_BODY,
+            stale: Vec::new(),
+            max_revalidations_per_host: Self::DEFAULT_MAX_REVALIDATIONS_PER_HOST,
+        }
+    }
+}
+
+impl ResponseCacheSettings {
+    pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+    pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+    pub const DEFAULT_MAX_REVALIDATIONS_PER_HOST: usize = 2;
+
+    /// Respuestas guardadas a la vez; al llenarse se descarta la menos usada.
+    pub fn with_max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max.max(1);
+        self
+    }
+
+    /// Body máximo de una respuesta guardada; las mayores pasan sin guardarse.
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    /// `stale-while-revalidate` y `stale-if-error` para las respuestas de
+    /// los hosts de `defaults` que no los declaran. Gana la primera regla
+    /// que coincide.
+    pub fn with_stale(mut self, defaults: StaleDefaults) -> Self {
+        self.stale.push(defaults);
+        self
+    }
+
+    /// Revalidaciones en segundo plano a la vez hacia un mismo host; con
+    /// el cupo lleno se sirve la copia caducada sin revalidarla.
+    pub fn with_max_revalidations_per_host(mut self, max: usize) -> Self {
+        self.max_revalidations_per_host = max.max(1);
+        self
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    pub fn stale(&self) -> &[StaleDefaults] {
+        &self.stale
+    }
+
+    /// Valores por defecto para `host`, si alguna regla lo nombra.
+    pub fn stale_for(&self, host: &str) -> Option<&StaleDefaults> {
+        self.stale.iter().find(|rule| rule.host.matches(host))
+    }
+
+    pub fn max_revalidations_per_host(&self) -> usize {
+        self.max_revalidations_per_host
+    }
+}
+
+/// Precarga de los recursos enlazados desde las páginas HTML servidas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefetchSettings {
+    max_per_page: usize,
+    max_html_bytes: usize,
+}
+
+impl Default for PrefetchSettings {
+    fn default() -> Self {
+        Self {
+            max_per_page: Self::DEFAULT_MAX_PER_PAGE,
+            max_html_bytes: Self::DEFAULT_MAX_HTML_BYTES,
+        }
+    }
+}
+
+impl PrefetchSettings {
+    pub const DEFAULT_MAX_PER_PAGE: usize = 8;
+    pub const DEFAULT_MAX_HTML_BYTES: usize = 256 * 1024;
+
+    /// Recursos precargados como mucho por cada página.
+    pub fn with_max_per_page(mut self, max: usize) -> Self {
+        self.max_per_page = max;
+        self
+    }
+
+    /// Bytes del principio de cada página en los que se buscan enlaces.
+    pub fn with_max_html_bytes(mut self, max: usize) -> Self {
+        self.max_html_bytes = max;
+        self
+    }
+
+    pub fn max_per_page(&self) -> usize {
+        self.max_per_page
+    }
+
+    pub fn max_html_bytes(&self) -> usize {
+        self.max_html_bytes
+    }
+}
+
+/// Ventanas de RFC 5861 por defecto para los hosts de `host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaleDefaults {
+    host: HostPattern,
+    while_revalidate: Duration,
+    if_error: Duration,
+}
+
+impl StaleDefaults {
+    pub fn new(host: HostPattern) -> Self {
+        Self {
+            host,
+            while_revalidate: Duration::ZERO,
+            if_error: Duration::ZERO,
+        }
+    }
+
+    /// Tiempo tras caducar en que se sirve la copia mientras se revalida.
+    pub fn with_while_revalidate(mut self, window: Duration) -> Self {
+        self.while_revalidate = window;
+        self
+    }
+
+    /// Tiempo tras caducar en que se sirve la copia si el destino falla.
+    pub fn with_if_error(mut self, window: Duration) -> Self {
+        self.if_error = window;
+        self
+    }
+
+    pub fn host(&self) -> &HostPattern {
+        &self.host
+    }
+
+    pub fn while_revalidate(&self) -> Duration {
+        self.while_revalidate
+    }
+
+    pub fn if_error(&self) -> Duration {
+        self.if_error
+    }
+}
+
+/// Quién recibe la cabecera `Server-Timing`: las peticiones a `hosts` y las
+/// de los clientes de `clients`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerTimingSettings {
+    hosts: Vec<HostPattern>,
+    clients: Vec<Cidr>,
+}
+
+impl ServerTimingSettings {
+    pub fn with_host(mut self, host: HostPattern) -> Self {
+        self.hosts.push(host);
+        self
+    }
+
+    pub fn with_client(mut self, range: Cidr) -> Self {
+        self.clients.push(range);
+        self
+    }
+
+    pub fn hosts(&self) -> &[HostPattern] {
+        &self.hosts
+    }
+
+    pub fn clients(&self) -> &[Cidr] {
+        &self.clients
+    }
+
+    pub fn applies(&self, host: &str, client: IpAddr) -> bool {
+        self.hosts.iter().any(|pattern| pattern.matches(host))
+            || self.clients.iter().any(|range| range.contains(client))
+    }
+}
+
+/// Quién recibe las cabeceras `X-ProxyIA-*`: los clientes de `clients` y los
+/// autenticados con un perfil de `profiles`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataEchoSettings {
+    clients: Vec<Cidr>,
+    profiles: Vec<String>,
+    max_bytes: usize,
+    cacheable: EchoCacheable,
+}
+
+impl MetadataEchoSettings {
+    pub const DEFAULT_MAX_BYTES: usize = 512;
+
+    pub fn with_client(mut self, range: Cidr) -> Self {
+        self.clients.push(range);
+        self
+    }
+
+    pub fn with_profile(mut self, profile: impl Into<String>) -> Self {
+        self.profiles.push(profile.into());
+        self
+    }
+
+    /// Tope de lo que ocupan las cabeceras, nombres incluidos.
+    pub fn with_max_bytes(mut self, max: usize) -> Self {
+        self.max_bytes = max;
+        self
+    }
+
+    pub fn with_cacheable(mut self, cacheable: EchoCacheable) -> Self {
+        self.cacheable = cacheable;
+        self
+    }
+
+    pub fn clients(&self) -> &[Cidr] {
+        &self.clients
+    }
+
+    pub fn profiles(&self) -> &[String] {
+        &self.profiles
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub fn cacheable(&self) -> EchoCacheable {
+        self.cacheable
+    }
+}
+
+impl Default for MetadataEchoSettings {
+    fn default() -> Self {
+        Self {
+            clients: Vec::new(),
+            profiles: Vec::new(),
+            max_bytes: Self::DEFAULT_MAX_BYTES,
+            cacheable: EchoCacheable::default(),
+        }
+    }
+}
+
+/// Qué se hace con las cabeceras `X-ProxyIA-*` en una respuesta que una
+/// caché compartida podría guardar.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EchoCacheable {
+    /// Se añaden y la respuesta pasa a `Cache-Control: private`.
+    #[default]
+    Private,
+    /// No se añaden.
+    Skip,
+}
+
+impl EchoCacheable {
+    pub const ALL: [EchoCacheable; 2] = [EchoCacheable::Private, EchoCacheable::Skip];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EchoCacheable::Private => "private",
+            EchoCacheable::Skip => "skip",
+        }
+    }
+}
+
+impl std::str::FromStr for EchoCacheable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("tratamiento de respuestas cacheables desconocido: {s}"))
+    }
+}
+
+/// Códigos de estado de `from` a `to`, ambos incluidos: `500-599` o `404`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusRange {
+    from: u16,
+    to: u16,
+}
+
+impl StatusRange {
+    pub const SERVER_ERRORS: StatusRange = StatusRange { from: 500, to: 599 };
+
+    pub fn contains(&self, status: u16) -> bool {
+        (self.from..=self.to).contains(&status)
+    }
+}
+
+impl std::str::FromStr for StatusRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s.split_once('-').unwrap_or((s, s));
+        let parse = |code: &str| {
+            code.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|code| (100..=599).contains(code))
+                .ok_or_else(|| anyhow::anyhow!("rango de códigos de estado inválido: {s}"))
+        };
+        let (from, to) = (parse(from)?, parse(to)?);
+        anyhow::ensure!(from <= to, "rango de códigos de estado inválido: {s}");
+        Ok(Self { from, to })
+    }
+}
+
+/// Respuestas de error de `host` (y, con `path_prefix`, solo de esas rutas)
+/// que se reemplazan por la página de error del proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorPageRule {
+    host: HostPattern,
+    path_prefix: Option<String>,
+    statuses: Vec<StatusRange>,
+    status: Option<u16>,
+    debug_token_env: Option<String>,
+}
+
+impl ErrorPageRule {
+    /// Por defecto reemplaza los 5xx y conserva el código original.
+    pub fn new(host: HostPattern) -> Self {
+        Self {
+            host,
+            path_prefix: None,
+            statuses: vec![StatusRange::SERVER_ERRORS],
+            status: None,
+            debug_token_env: None,
+        }
+    }
+
+    pub fn with_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Reemplaza los códigos que se interceptan.
+    pub fn with_statuses(mut self, statuses: Vec<StatusRange>) -> Self {
+        self.statuses = statuses;
+        self
+    }
+
+    /// Código con el que se responde la página en vez del original.
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Variable de entorno con el token que, en `X-Debug-Errors`, deja pasar
+    /// la respuesta original.
+    pub fn with_debug_token_env(mut self, name: impl Into<String>) -> Self {
+        self.debug_token_env = Some(name.into());
+        self
+    }
+
+    pub fn host(&self) -> &HostPattern {
+        &self.host
+    }
+
+    pub fn path_prefix(&self) -> Option<&str> {
+        self.path_prefix.as_deref()
+    }
+
+    pub fn statuses(&self) -> &[StatusRange] {
+        &self.statuses
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    pub fn debug_token_env(&self) -> Option<&str> {
+        self.debug_token_env.as_deref()
+    }
+
+    pub fn matches(&self, host: &str, path: &str) -> bool {
+        self.host.matches(host)
+            && self
+                .path_prefix
+                .as_deref()
+                .is_none_or(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// Páginas de error propias en lugar de las del destino. Manda la primera
+/// regla que casa.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorPageSettings {
+    rules: Vec<ErrorPageRule>,
+    template: Option<PathBuf>,
+    max_log_bytes: usize,
+}
+
+impl Default for ErrorPageSettings {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            template: None,
+            max_log_bytes: Self::DEFAULT_MAX_LOG_BYTES,
+        }
+    }
+}
+
+impl ErrorPageSettings {
+    pub const DEFAULT_MAX_LOG_BYTES: usize = 2048;
+
+    pub fn with_rule(mut self, rule: ErrorPageRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Plantilla HTML con los marcadores `{{status}}` y `{{reason}}`.
+    pub fn with_template(mut self, path: impl Into<PathBuf>) -> Self {
+        self.template = Some(path.into());
+        self
+    }
+
+    /// Bytes del body original que se registran, ya redactados.
+    pub fn with_max_log_bytes(mut self, max: usize) -> Self {
+        self.max_log_bytes = max;
+        self
+    }
+
+    pub fn rules(&self) -> &[ErrorPageRule] {
+        &self.rules
+    }
+
+    pub fn template(&self) -> Option<&Path> {
+        self.template.as_deref()
+    }
+
+    pub fn max_log_bytes(&self) -> usize {
+        self.max_log_bytes
+    }
+}
+
+/// Prioridad de una cola de trabajo interno.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobPriority {
+    /// Se ejecuta aunque el proxy esté ocupado.
+    High,
+    /// Espera mientras el proxy está ocupado y comparte el cupo de trabajo
+    /// en segundo plano con las demás colas de baja prioridad.
+    Low,
+}
+
+impl JobPriority {
+    pub const ALL: [JobPriority; 2] = [JobPriority::High, JobPriority::Low];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobPriority::High => "high",
+            JobPriority::Low => "low",
+        }
+    }
+}
+
+impl std::str::FromStr for JobPriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|priority| priority.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("prioridad de cola desconocida: {s}"))
+    }
+}
+
+/// Una cola de trabajo interno: prioridad, trabajos a la vez y trabajos en
+/// espera antes de descartar los nuevos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueSettings {
+    priority: JobPriority,
+    concurrency: usize,
+    capacity: usize,
+}
+
+impl QueueSettings {
+    pub fn new(priority: JobPriority, concurrency: usize, capacity: usize) -> Self {
+        Self {
+            priority,
+            concurrency: concurrency.max(1),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn priority(&self) -> JobPriority {
+        self.priority
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Planificador del trabajo interno en segundo plano (escritura del archivo,
+/// estadísticas, informes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobSettings {
+    busy_requests: usize,
+    background_concurrency: usize,
+    queues: BTreeMap<String, QueueSettings>,
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        Self {
+            busy_requests: Self::DEFAULT_BUSY_REQUESTS,
+            background_concurrency: Self::DEFAULT_BACKGROUND_CONCURRENCY,
+            queues: BTreeMap::new(),
+        }
+    }
+}
+
+impl JobSettings {
+    pub const DEFAULT_BUSY_REQUESTS: usize = 256;
+    pub const DEFAULT_BACKGROUND_CONCURRENCY: usize = 2;
+
+    /// Peticiones en curso a partir de las cuales el proxy se considera
+    /// ocupado y las colas de baja prioridad esperan.
+    pub fn with_busy_requests(mut self, requests: usize) -> Self {
+        self.busy_requests = requests.max(1);
+        self
+    }
+
+    /// Trabajos de baja prioridad a la vez, sumando todas las colas.
+    pub fn with_background_concurrency(mut self, jobs: usize) -> Self {
+        self.background_concurrency = jobs.max(1);
+        self
+    }
+
+    /// Reemplaza la configuración por defecto de una cola.
+    pub fn with_queue(mut self, name: impl Into<String>, queue: QueueSettings) -> Self {
+        self.queues.insert(name.into(), queue);
+        self
+    }
+
+    pub fn busy_requests(&self) -> usize {
+        self.busy_requests
+    }
+
+    pub fn background_concurrency(&self) -> usize {
+        self.background_concurrency
+    }
+
+    pub fn queues(&self) -> &BTreeMap<String, QueueSettings> {
+        &self.queues
+    }
+}
+
+/// Rutas de gateway LLM: peticiones HTTP hacia `hosts` en las que se
+/// respetan los presupuestos `X-LLM-Max-Cost` y `X-LLM-Max-Latency`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlmSettings {
+    hosts: Vec<HostPattern>,
+    prices: HashMap<String, ModelPrice>,
+    default_max_tokens: u64,
+    max_body: usize,
+}
+
+impl Default for LlmSettings {
+    fn default() -> Self {
+        Self {
+            hosts: Vec::new(),
+            prices: HashMap::new(),
+            default_max_tokens: Self::DEFAULT_MAX_TOKENS,
+            max_body: Self::DEFAULT_MAX_BODY,
+        }
+    }
+}
+
+impl LlmSettings {
+    pub const DEFAULT_MAX_TOKENS: u64 = 4096;
+    pub const DEFAULT_MAX_BODY: usize = 4 * 1024 * 1024;
+
+    pub fn with_host(mut self, host: HostPattern) -> Self {
+        self.hosts.push(host);
+        self
+    }
+
+    /// Precio de `model` y de sus versiones (`gpt-4o` cubre
+    /// `gpt-4o-2024-08-06` si no tiene precio propio).
+    pub fn with_price(mut self, model: impl Into<String>, price: ModelPrice) -> Self {
+        self.prices.insert(model.into(), price);
+        self
+    }
+
+    /// Tokens de salida que se suponen si la petición no fija `max_tokens`.
+    pub fn with_default_max_tokens(mut self, tokens: u64) -> Self {
+        self.default_max_tokens = tokens;
+        self
+    }
+
+    /// Body máximo que se retiene para estimar el coste.
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    pub fn hosts(&self) -> &[HostPattern] {
+        &self.hosts
+    }
+
+    /// Precio exacto o, si no, el del prefijo más largo.
+    pub fn price(&self, model: &str) -> Option<ModelPrice> {
+        self.prices.get(model).copied().or_else(|| {
+            self.prices
+                .iter()
+                .filter(|(name, _)| model.starts_with(name.as_str()))
+                .max_by_key(|(name, _)| name.len())
+                .map(|(_, price)| *price)
+        })
+    }
+
+    pub fn default_max_tokens(&self) -> u64 {
+        self.default_max_tokens
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+}
+
+/// Backends compatibles con la API de OpenAI por modelo, para el router IA.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AiRouterSettings {
+    default_upstream: hyper::Uri,
+    models: BTreeMap<String, UpstreamPool>,
+    paths: Vec<String>,
+    max_body: usize,
+    cache: Option<LlmCacheSettings>,
+    retry: Option<AiRetrySettings>,
+    health: AiHealthSettings,
+}
+
+impl AiRouterSettings {
+    pub const DEFAULT_PATHS: [&str; 3] =
+        ["/v1/chat/completions", "/v1/completions", "/v1/embeddings"];
+    pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+    /// `default_upstream` recibe los modelos sin backend propio y las
+    /// peticiones cuyo modelo no se puede leer.
+    pub fn new(default_upstream: hyper::Uri) -> Self {
+        Self {
+            default_upstream,
+            models: BTreeMap::new(),
+            paths: Self::DEFAULT_PATHS.map(String::from).to_vec(),
+            max_body: Self::DEFAULT_MAX_BODY,
+            cache: None,
+            retry: None,
+            health: AiHealthSettings::default(),
+        }
+    }
+
+    /// Backend de `model` y de sus versiones (`gpt-4o` cubre
+    /// `gpt-4o-2024-08-06` si no tiene uno propio). Repetirlo con el mismo
+    /// modelo añade backends, que se reparten según [`Self::with_balance`].
+    pub fn with_model(self, model: impl Into<String>, upstream: hyper::Uri) -> Self {
+        self.with_weighted_model(model, upstream, 1)
+    }
+
+    /// Como [`Self::with_model`], con el peso del backend para
+    /// [`BalanceStrategy::Weighted`].
+    pub fn with_weighted_model(
+        mut self,
+        model: impl Into<String>,
+        upstream: hyper::Uri,
+        weight: u32,
+    ) -> Self {
+        let pool = self.models.entry(model.into()).or_default();
+        pool.upstreams.push((upstream, weight.max(1)));
+        self
+    }
+
+    /// Cómo se reparten las peticiones de `model` entre sus backends.
+    pub fn with_balance(mut self, model: impl Into<String>, strategy: BalanceStrategy) -> Self {
+        self.models.entry(model.into()).or_default().strategy = strategy;
+        self
+    }
+
+    /// Rutas cuyos `POST` se enrutan; las demás no se tocan.
+    pub fn with_paths(mut self, paths: Vec<String>) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    /// Body máximo que se retiene para leer el modelo; uno mayor va al
+    /// backend por defecto.
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    pub fn default_upstream(&self) -> &hyper::Uri {
+        &self.default_upstream
+    }
+
+    pub fn models(&self) -> &BTreeMap<String, UpstreamPool> {
+        &self.models
+    }
+
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    /// Sirve desde la caché las peticiones repetidas.
+    pub fn with_cache(mut self, cache: LlmCacheSettings) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn cache(&self) -> Option<&LlmCacheSettings> {
+        self.cache.as_ref()
+    }
+
+    /// Reintenta las respuestas 429 y 529 de los backends.
+    pub fn with_retry(mut self, retry: AiRetrySettings) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn retry(&self) -> Option<&AiRetrySettings> {
+        self.retry.as_ref()
+    }
+
+    /// Cuándo un backend deja de recibir peticiones y cuándo se vuelve a
+    /// probar.
+    pub fn with_health(mut self, health: AiHealthSettings) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn health(&self) -> AiHealthSettings {
+        self.health
+    }
+
+    /// Nombre configurado y backends de `model`: el exacto o, si no, el del
+    /// prefijo más largo.
+    pub fn pool(&self, model: &str) -> Option<(&str, &UpstreamPool)> {
+        self.models
+            .get_key_value(model)
+            .or_else(|| {
+                self.models
+                    .iter()
+                    .filter(|(name, _)| model.starts_with(name.as_str()))
+                    .max_by_key(|(name, _)| name.len())
+            })
+            .map(|(name, pool)| (name.as_str(), pool))
+    }
+}
+
+/// Reparto de las peticiones de un modelo entre sus backends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Siempre el primero que esté sano; los demás son de reserva.
+    #[default]
+    Failover,
+    RoundRobin,
+    /// El que tiene menos peticiones en curso.
+    LeastActive,
+    /// Por turnos, en proporción al peso de cada uno.
+    Weighted,
+}
+
+impl BalanceStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BalanceStrategy::Failover => "failover",
+            BalanceStrategy::RoundRobin => "round_robin",
+            BalanceStrategy::LeastActive => "least_active",
+            BalanceStrategy::Weighted => "weighted",
+        }
+    }
+}
+
+/// Backends de un modelo, con su peso, y cómo se reparten.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpstreamPool {
+    upstreams: Vec<(hyper::Uri, u32)>,
+    strategy: BalanceStrategy,
+}
+
+impl UpstreamPool {
+    pub fn upstreams(&self) -> &[(hyper::Uri, u32)] {
+        &self.upstreams
+    }
+
+    pub fn strategy(&self) -> BalanceStrategy {
+        self.strategy
+    }
+}
+
+/// Salud de los backends del router IA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AiHealthSettings {
+    unhealthy_after: u32,
+    cooldown: Duration,
+}
+
+impl Default for AiHealthSettings {
+    fn default() -> Self {
+        Self {
+            unhealthy_after: Self::DEFAULT_UNHEALTHY_AFTER,
+            cooldown: Self::DEFAULT_COOLDOWN,
+        }
+    }
+}
+
+impl AiHealthSettings {
+    pub const DEFAULT_UNHEALTHY_AFTER: u32 = 3;
+    pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+    /// Fallos de conexión o 5xx seguidos que apartan a un backend.
+    pub fn with_unhealthy_after(mut self, failures: u32) -> Self {
+        self.unhealthy_after = failures.max(1);
+        self
+    }
+
+    /// Espera antes de cada sonda a un backend apartado.
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    pub fn unhealthy_after(&self) -> u32 {
+        self.unhealthy_after
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+}
+
+/// Qué hacer cuando un backend del router IA contesta 429 o 529.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AiRetrySettings {
+    attempts: u32,
+    max_wait: Duration,
+    failover: bool,
+}
+
+impl Default for AiRetrySettings {
+    fn default() -> Self {
+        Self {
+            attempts: Self::DEFAULT_ATTEMPTS,
+            max_wait: Self::DEFAULT_MAX_WAIT,
+            failover: true,
+        }
+    }
+}
+
+impl AiRetrySettings {
+    pub const DEFAULT_ATTEMPTS: u32 = 3;
+    pub const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(10);
+
+    /// Intentos en total, contando el primero.
+    pub fn with_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Tope de la espera que pide `Retry-After`.
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// `false` espera y repite en el mismo backend aunque el modelo tenga
+    /// otros.
+    pub fn with_failover(mut self, failover: bool) -> Self {
+        self.failover = failover;
+        self
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    pub fn failover(&self) -> bool {
+        self.failover
+    }
+}
+
+/// Caché de las respuestas del router IA por contenido de la petición.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LlmCacheSettings {
+    ttl: Duration,
+    max_entries: usize,
+    dir: Option<PathBuf>,
+    sampled: bool,
+    streams: bool,
+}
+
+impl Default for LlmCacheSettings {
+    fn default() -> Self {
+        Self {
+            ttl: Self::DEFAULT_TTL,
+            max_entries: Self::DEFAULT_MAX_ENTRIES,
+            dir: None,
+            sampled: false,
+            streams: false,
+        }
+    }
+}
+
+impl LlmCacheSettings {
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(3600);
+    pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Respuestas guardadas a la vez; al llenarse se descarta la menos usada.
+    pub fn with_max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max.max(1);
+        self
+    }
+
+    /// Directorio donde las entradas sobreviven a un reinicio.
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Guarda también las peticiones con `temperature` mayor que cero.
+    pub fn with_sampled(mut self, sampled: bool) -> Self {
+        self.sampled = sampled;
+        self
+    }
+
+    /// Guarda también las peticiones con `stream: true`, repitiendo el
+    /// stream entero de una vez.
+    pub fn with_streams(mut self, streams: bool) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.sampled
+    }
+
+    pub fn streams(&self) -> bool {
+        self.streams
+    }
+}
+
+/// Tokens por cliente en cada minuto y en cada día; `None` no limita.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenBudgetSettings {
+    per_minute: Option<u64>,
+    per_day: Option<u64>,
+}
+
+impl TokenBudgetSettings {
+    pub fn with_per_minute(mut self, tokens: u64) -> Self {
+        self.per_minute = Some(tokens);
+        self
+    }
+
+    pub fn with_per_day(mut self, tokens: u64) -> Self {
+        self.per_day = Some(tokens);
+        self
+    }
+
+    pub fn per_minute(&self) -> Option<u64> {
+        self.per_minute
+    }
+
+    pub fn per_day(&self) -> Option<u64> {
+        self.per_day
+    }
+}
+
+/// Id de cada petición, en sus logs, hacia el destino y en la respuesta.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestIdSettings {
+    enabled: bool,
+    header: hyper::header::HeaderName,
+    trust_incoming: bool,
+}
+
+impl Default for RequestIdSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            header: hyper::header::HeaderName::from_static("x-request-id"),
+            trust_incoming: true,
+        }
+    }
+}
+
+impl RequestIdSettings {
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_header(mut self, header: hyper::header::HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Con `true` (por defecto) se conserva el id que mande el cliente si
+    /// es válido; con `false` siempre se genera uno.
+    pub fn with_trust_incoming(mut self, trust: bool) -> Self {
+        self.trust_incoming = trust;
+        self
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn header(&self) -> &hyper::header::HeaderName {
+        &self.header
+    }
+
+    pub fn trust_incoming(&self) -> bool {
+        self.trust_incoming
+    }
+}
+
+/// Qué hacer con una petición cuyo body casa con una regla de contenido.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentAction {
+    /// Rechaza la petición con un 403.
+    Block,
+    /// Reemplaza cada coincidencia por `[REDACTED]` y la reenvía.
+    Redact,
+}
+
+impl ContentAction {
+    pub const ALL: [ContentAction; 2] = [ContentAction::Block, ContentAction::Redact];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentAction::Block => "block",
+            ContentAction::Redact => "redact",
+        }
+    }
+}
+
+impl std::str::FromStr for ContentAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|action| action.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("acción de filtro de contenido desconocida: {s}"))
+    }
+}
+
+/// Expresión regular que se busca en los bodies de las peticiones.
+#[derive(Debug, Clone)]
+pub struct ContentRule {
+    id: String,
+    pattern: regex::bytes::Regex,
+    action: ContentAction,
+}
+
+impl ContentRule {
+    /// Falla si `pattern` no es una expresión regular válida.
+    pub fn new(
+        id: impl Into<String>,
+        pattern: &str,
+        action: ContentAction,
+    ) -> Result<Self, regex::Error> {
+        Ok(Self {
+            id: id.into(),
+            pattern: regex::bytes::Regex::new(pattern)?,
+            action,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn pattern(&self) -> &regex::bytes::Regex {
+        &self.pattern
+    }
+
+    pub fn action(&self) -> ContentAction {
+        self.action
+    }
+}
+
+/// Reglas de contenido para los bodies de las peticiones del router IA y,
+/// con `all_text_bodies`, de cualquier petición de texto o JSON.
+#[derive(Debug, Clone)]
+pub struct ContentFilterSettings {
+    rules: Vec<ContentRule>,
+    all_text_bodies: bool,
+    max_body: usize,
+}
+
+impl Default for ContentFilterSettings {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            all_text_bodies: false,
+            max_body: Self::DEFAULT_MAX_BODY,
+        }
+    }
+}
+
+impl ContentFilterSettings {
+    pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+    pub fn with_rule(mut self, rule: ContentRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn with_all_text_bodies(mut self, all: bool) -> Self {
+        self.all_text_bodies = all;
+        self
+    }
+
+    /// Bytes como mucho que se retienen para revisar un body; uno mayor
+    /// pasa sin revisar.
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    pub fn rules(&self) -> &[ContentRule] {
+        &self.rules
+    }
+
+    pub fn all_text_bodies(&self) -> bool {
+        self.all_text_bodies
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+}
+
+/// Reproducción de capturas contra otro destino (`proxy-ia llm replay`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplaySettings {
+    from: PathBuf,
+    target: hyper::Uri,
+    out: PathBuf,
+    models: HashMap<String, String>,
+    rate_per_sec: f64,
+    skip_redacted: bool,
+}
+
+impl ReplaySettings {
+    pub const DEFAULT_RATE_PER_SEC: f64 = 5.0;
+
+    /// Los resultados van por defecto a `replay/` dentro de `from`.
+    pub fn new(from: impl Into<PathBuf>, target: hyper::Uri) -> Self {
+        let from = from.into();
+        Self {
+            out: from.join("replay"),
+            from,
+            target,
+            models: HashMap::new(),
+            rate_per_sec: Self::DEFAULT_RATE_PER_SEC,
+            skip_redacted: false,
+        }
+    }
+
+    pub fn with_out(mut self, out: impl Into<PathBuf>) -> Self {
+        self.out = out.into();
+        self
+    }
+
+    /// Reemplaza el campo `model` del body cuando vale `from`.
+    pub fn with_model(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.models.insert(from.into(), to.into());
+        self
+    }
+
+    /// Peticiones por segundo hacia el destino.
+    pub fn with_rate_per_sec(mut self, rate: f64) -> Self {
+        self.rate_per_sec = rate.max(0.001);
+        self
+    }
+
+    /// Omite las peticiones cuya URL o body contienen marcas de redacción.
+    pub fn with_skip_redacted(mut self, skip: bool) -> Self {
+        self.skip_redacted = skip;
+        self
+    }
+
+    pub fn from(&self) -> &PathBuf {
+        &self.from
+    }
+
+    pub fn target(&self) -> &hyper::Uri {
+        &self.target
+    }
+
+    pub fn out(&self) -> &PathBuf {
+        &self.out
+    }
+
+    pub fn model(&self, model: &str) -> Option<&str> {
+        self.models.get(model).map(String::as_str)
+    }
+
+    pub fn rate_per_sec(&self) -> f64 {
+        self.rate_per_sec
+    }
+
+    pub fn skip_redacted(&self) -> bool {
+        self.skip_redacted
+    }
+}
+
+/// Banco de carga (`proxy-ia bench`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchSettings {
+    target: BenchTarget,
+    mode: BenchMode,
+    duration: Duration,
+    connections: usize,
+    checkpoint: Option<Duration>,
+    load_threads: usize,
+    body_bytes: usize,
+    admin_token: Option<AdminToken>,
+}
+
+impl BenchSettings {
+    pub const DEFAULT_DURATION: Duration = Duration::from_secs(60);
+    pub const DEFAULT_SOAK_DURATION: Duration = Duration::from_secs(4 * 3600);
+    pub const DEFAULT_CHECKPOINT: Duration = Duration::from_secs(300);
+    pub const DEFAULT_CONNECTIONS: usize = 50;
+    pub const DEFAULT_LOAD_THREADS: usize = 2;
+    pub const DEFAULT_BODY_BYTES: usize = 1024;
+
+    pub fn new(target: BenchTarget, mode: BenchMode) -> Self {
+        Self {
+            target,
+            mode,
+            duration: Self::DEFAULT_DURATION,
+            connections: Self::DEFAULT_CONNECTIONS,
+            checkpoint: None,
+            load_threads: Self::DEFAULT_LOAD_THREADS,
+            body_bytes: Self::DEFAULT_BODY_BYTES,
+            admin_token: None,
+        }
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Conexiones de cliente simultáneas.
+    pub fn with_connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    /// Cada cuánto se informa del intervalo transcurrido.
+    pub fn with_checkpoint(mut self, every: Duration) -> Self {
+        self.checkpoint = Some(every.max(Duration::from_secs(1)));
+        self
+    }
+
+    /// Hilos del runtime que genera la carga, aparte de los del proxy.
+    pub fn with_load_threads(mut self, threads: usize) -> Self {
+        self.load_threads = threads.max(1);
+        self
+    }
+
+    /// Tamaño del body que sirve el origen interno.
+    pub fn with_body_bytes(mut self, bytes: usize) -> Self {
+        self.body_bytes = bytes;
+        self
+    }
+
+    /// Token con el que se consulta la API de administración del proxy.
+    pub fn with_admin_token(mut self, token: AdminToken) -> Self {
+        self.admin_token = Some(token);
+        self
+    }
+
+    pub fn target(&self) -> &BenchTarget {
+        &self.target
+    }
+
+    pub fn mode(&self) -> BenchMode {
+        self.mode
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    pub fn checkpoint(&self) -> Option<Duration> {
+        self.checkpoint
+    }
+
+    pub fn load_threads(&self) -> usize {
+        self.load_threads
+    }
+
+    pub fn body_bytes(&self) -> usize {
+        self.body_bytes
+    }
+
+    pub fn admin_token(&self) -> Option<&AdminToken> {
+        self.admin_token.as_ref()
+    }
+}
+
+/// A quién van las peticiones del banco de carga.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BenchTarget {
+    /// Un origen que levanta el propio banco (`self`).
+    Internal,
+    /// Un origen HTTP ya en marcha, como `host:puerto`.
+    Origin(String),
+}
+
+impl std::str::FromStr for BenchTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "self" {
+            return Ok(BenchTarget::Internal);
+        }
+        let authority: hyper::http::uri::Authority = s
+            .parse()
+            .map_err(|_| anyhow::anyhow!("se esperaba `self` o `host:puerto`: {s}"))?;
+        let port = authority.port_u16().unwrap_or(80);
+        Ok(BenchTarget::Origin(format!("{}:{port}", authority.host())))
+    }
+}
+
+/// Qué tráfico genera el banco de carga.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BenchMode {
+    /// Peticiones HTTP en conexiones persistentes.
+    #[default]
+    Http,
+    /// Un túnel `CONNECT` nuevo por petición.
+    Connect,
+    /// La mitad de las conexiones de cada tipo.
+    Mixed,
+}
+
+impl BenchMode {
+    pub const ALL: [BenchMode; 3] = [BenchMode::Http, BenchMode::Connect, BenchMode::Mixed];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BenchMode::Http => "http",
+            BenchMode::Connect => "connect",
+            BenchMode::Mixed => "mixed",
+        }
+    }
+}
+
+impl std::str::FromStr for BenchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("modo de carga desconocido: {s}"))
+    }
 }
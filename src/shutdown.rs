@@ -0,0 +1,338 @@
+//! Parada ordenada del proxy y su resultado.
+//!
+//! Una señal (`SIGTERM`, `SIGINT`), `POST /api/shutdown` o un error fatal
+//! disparan la parada: se deja de aceptar conexiones, las abiertas terminan
+//! su intercambio en curso y cierran, y los túneles siguen hasta que el
+//! cliente o el destino cortan. Lo que siga abierto al vencer
+//! `drain_timeout` se aborta. El resultado se resume en un [`ShutdownReport`]
+//! que también se registra en el log y decide el código de salida.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::metrics::Metrics;
+
+/// Código de salida de una configuración que no se pudo cargar o no es
+/// válida; el proxy no llegó a arrancar.
+pub const EXIT_CONFIG: u8 = 3;
+
+/// Qué disparó la parada.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShutdownTrigger {
+    /// Nombre de la señal (`SIGTERM`, `SIGINT`).
+    Signal(&'static str),
+    /// `POST /api/shutdown` o [`ProxyHandle::shutdown`](crate::proxy::ProxyHandle::shutdown).
+    Admin,
+    Fatal(String),
+}
+
+impl ShutdownTrigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownTrigger::Signal(_) => "signal",
+            ShutdownTrigger::Admin => "admin",
+            ShutdownTrigger::Fatal(_) => "fatal",
+        }
+    }
+}
+
+impl fmt::Display for ShutdownTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownTrigger::Signal(name) => write!(f, "señal {name}"),
+            ShutdownTrigger::Admin => f.write_str("petición de administración"),
+            ShutdownTrigger::Fatal(error) => write!(f, "error fatal: {error}"),
+        }
+    }
+}
+
+/// Cómo terminó el proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownOutcome {
+    /// Todas las conexiones cerraron dentro del plazo.
+    Clean,
+    /// Alguna conexión se abortó al vencer `drain_timeout`.
+    DrainTimeout,
+    /// Un error fatal detuvo el proxy, aunque haya drenado bien.
+    Fatal,
+}
+
+impl ShutdownOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownOutcome::Clean => "clean",
+            ShutdownOutcome::DrainTimeout => "drain_timeout",
+            ShutdownOutcome::Fatal => "fatal",
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ShutdownOutcome::Clean => 0,
+            ShutdownOutcome::Fatal => 1,
+            ShutdownOutcome::DrainTimeout => 2,
+        }
+    }
+}
+
+/// Resumen final de una ejecución.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub outcome: ShutdownOutcome,
+    pub trigger: ShutdownTrigger,
+    pub uptime: Duration,
+    pub requests: u64,
+    /// Conexiones y túneles abiertos al empezar la parada que cerraron solos.
+    pub drained: usize,
+    /// Los que se cortaron al vencer el plazo.
+    pub aborted: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    Draining,
+    Aborting,
+}
+
+/// Coordinación de la parada, compartida por el listener, las conexiones y
+/// los túneles.
+#[derive(Debug)]
+pub struct Shutdown {
+    started: Instant,
+    accepting: AtomicBool,
+    trigger: Mutex<Option<ShutdownTrigger>>,
+    phase: watch::Sender<Phase>,
+    active: watch::Sender<usize>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            accepting: AtomicBool::new(false),
+            trigger: Mutex::new(None),
+            phase: watch::Sender::new(Phase::Running),
+            active: watch::Sender::new(0),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Empieza la parada; si ya había empezado se conserva el primer motivo.
+    pub fn trigger(&self, trigger: ShutdownTrigger) {
+        let mut current = self.trigger.lock().expect("motivo de parada");
+        if current.is_some() {
+            return;
+        }
+        info!(trigger = %trigger, "Iniciando parada del proxy");
+        *current = Some(trigger);
+        self.phase.send_replace(Phase::Draining);
+    }
+
+    /// Anota que el bucle de aceptación ya está en marcha.
+    pub(crate) fn mark_accepting(&self) {
+        self.accepting.store(true, Ordering::Relaxed);
+    }
+
+    /// Si el proxy ya acepta clientes; no dice nada de la parada.
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    /// Tiempo desde que arrancó el proceso.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Si la parada ya empezó.
+    pub fn is_stopping(&self) -> bool {
+        *self.phase.borrow() != Phase::Running
+    }
+
+    /// Se completa cuando empieza la parada.
+    pub async fn draining(&self) {
+        self.reached(Phase::Draining).await;
+    }
+
+    /// Se completa al vencer el plazo de drenaje; quien siga abierto corta.
+    pub async fn aborting(&self) {
+        self.reached(Phase::Aborting).await;
+    }
+
+    async fn reached(&self, phase: Phase) {
+        let mut rx = self.phase.subscribe();
+        // The sender lives as long as `self`, so this cannot fail.
+        let _ = rx.wait_for(|current| *current >= phase).await;
+    }
+
+    /// Cuenta una conexión o túnel abierto mientras viva el guard.
+    pub(crate) fn track(self: &Arc<Self>) -> Tracked {
+        self.active.send_modify(|active| *active += 1);
+        Tracked(self.clone())
+    }
+
+    /// Conexiones y túneles abiertos ahora.
+    pub fn active(&self) -> usize {
+        *self.active.borrow()
+    }
+
+    async fn idle(&self) {
+        let mut rx = self.active.subscribe();
+        let _ = rx.wait_for(|active| *active == 0).await;
+    }
+
+    /// Espera a que empiece la parada, drena hasta `timeout`, aborta el resto
+    /// y registra el resumen.
+    pub async fn finish(&self, timeout: Duration, metrics: &Metrics) -> ShutdownReport {
+        self.draining().await;
+        let trigger = self
+            .trigger
+            .lock()
+            .expect("motivo de parada")
+            .clone()
+            .expect("la parada tiene motivo");
+        let open = self.active();
+        let aborted = match tokio::time::timeout(timeout, self.idle()).await {
+            Ok(()) => 0,
+            Err(_) => {
+                let aborted = self.active();
+                self.phase.send_replace(Phase::Aborting);
+                // Aborted tasks only need to notice and drop their sockets.
+                let _ = tokio::time::timeout(Duration::from_secs(1), self.idle()).await;
+                aborted
+            }
+        };
+        let outcome = match &trigger {
+            ShutdownTrigger::Fatal(_) => ShutdownOutcome::Fatal,
+            _ if aborted > 0 => ShutdownOutcome::DrainTimeout,
+            _ => ShutdownOutcome::Clean,
+        };
+        let report = ShutdownReport {
+            outcome,
+            trigger,
+            uptime: self.started.elapsed(),
+            requests: metrics.requests(),
+            drained: open.saturating_sub(aborted),
+            aborted,
+        };
+        report.log();
+        report
+    }
+}
+
+impl ShutdownReport {
+    fn log(&self) {
+        let signal = match &self.trigger {
+            ShutdownTrigger::Signal(name) => Some(*name),
+            _ => None,
+        };
+        let fatal = match &self.trigger {
+            ShutdownTrigger::Fatal(error) => Some(error.as_str()),
+            _ => None,
+        };
+        macro_rules! report {
+            ($level:ident) => {
+                $level!(
+                    outcome = self.outcome.as_str(),
+                    exit_code = self.outcome.exit_code(),
+                    trigger = self.trigger.as_str(),
+                    signal,
+                    error = fatal,
+                    uptime_secs = self.uptime.as_secs(),
+                    requests = self.requests,
+                    drained = self.drained,
+                    aborted = self.aborted,
+                    "Proxy detenido"
+                )
+            };
+        }
+        match self.outcome {
+            ShutdownOutcome::Clean => report!(info),
+            ShutdownOutcome::DrainTimeout => report!(warn),
+            ShutdownOutcome::Fatal => report!(error),
+        }
+    }
+}
+
+/// Mantiene contada una conexión o túnel en [`Shutdown::active`].
+pub(crate) struct Tracked(Arc<Shutdown>);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.active.send_modify(|active| *active -= 1);
+    }
+}
+
+/// Dispara la parada con la primera `SIGTERM` o `SIGINT`.
+pub async fn on_signal(shutdown: Arc<Shutdown>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (Ok(mut term), Ok(mut int)) = (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) else {
+            warn!("No se pudieron instalar los manejadores de señales");
+            return;
+        };
+        let name = tokio::select! {
+            _ = term.recv() => "SIGTERM",
+            _ = int.recv() => "SIGINT",
+        };
+        shutdown.trigger(ShutdownTrigger::Signal(name));
+    }
+    #[cfg(not(unix))]
+    if tokio::signal::ctrl_c().await.is_ok() {
+        shutdown.trigger(ShutdownTrigger::Signal("SIGINT"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_counts_and_outcomes() {
+        let metrics = Metrics::default();
+        let shutdown = Arc::new(Shutdown::default());
+        let quick = shutdown.track();
+        let stuck = shutdown.track();
+        let stuck_task = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                shutdown.aborting().await;
+                drop(stuck);
+            })
+        };
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(quick);
+        });
+
+        shutdown.trigger(ShutdownTrigger::Signal("SIGTERM"));
+        // A later trigger does not replace the first one.
+        shutdown.trigger(ShutdownTrigger::Admin);
+        let report = shutdown.finish(Duration::from_secs(5), &metrics).await;
+        assert_eq!(report.outcome, ShutdownOutcome::DrainTimeout);
+        assert_eq!(report.outcome.exit_code(), 2);
+        assert_eq!(report.trigger, ShutdownTrigger::Signal("SIGTERM"));
+        assert_eq!((report.drained, report.aborted), (1, 1));
+        stuck_task.await.unwrap();
+        assert_eq!(shutdown.active(), 0);
+
+        let fatal = Shutdown::default();
+        fatal.trigger(ShutdownTrigger::Fatal("listener caído".into()));
+        let report = fatal.finish(Duration::from_secs(5), &metrics).await;
+        assert_eq!(report.outcome, ShutdownOutcome::Fatal);
+        assert_eq!(report.outcome.exit_code(), 1);
+        assert_eq!((report.drained, report.aborted), (0, 0));
+    }
+}
@@ -0,0 +1,195 @@
+//! Comprobación del SNI de los túneles `CONNECT`.
+//!
+//! Nada obliga al cliente de un túnel a hablar con el host que pidió: puede
+//! pedir `CONNECT` a una IP permitida y saludar después por TLS a otro
+//! nombre, o hacer domain fronting. Con `[verify_sni]`, tras el 200 el proxy
+//! lee el primer registro que manda el cliente, antes de copiar nada, y saca
+//! el `server_name` de su ClientHello. El túnel se cierra si ese nombre no es
+//! el host del `CONNECT` o, si se pidió una IP, si el nombre no pasa la
+//! lista de bloqueo, el filtro de hosts o la lista de salida. Lo leído se
+//! entrega al destino antes de que empiece la copia, así que el saludo le
+//! llega entero.
+//!
+//! Un ClientHello sin SNI pasa: el destino es el del `CONNECT`, que ya se
+//! comprobó. Uno que no cabe en el primer registro no se puede leer y cierra
+//! el túnel. Si lo primero no es un registro TLS, o si el destino habla
+//! antes que el cliente (SSH, SMTP), decide `non_tls`.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Mayor registro TLS que se espera (RFC 8446 §5.2).
+const MAX_RECORD: usize = (1 << 14) + 2048;
+
+/// Lo que empieza a mandar el cliente de un túnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hello {
+    /// Un ClientHello entero en el primer registro, con su SNI si lo trae.
+    Tls(Option<String>),
+    /// Un registro de handshake que no es un ClientHello entero.
+    Unreadable,
+    /// Cualquier otra cosa.
+    NotTls,
+}
+
+/// Qué manda el cliente que empieza con `bytes`; `None` mientras falten
+/// bytes para saberlo.
+pub fn classify(bytes: &[u8]) -> Option<Hello> {
+    // Handshake record, major version 3.
+    match bytes {
+        [] | [0x16] => return None,
+        [0x16, 0x03, ..] => {}
+        _ => return Some(Hello::NotTls),
+    }
+    let header = bytes.get(..5)?;
+    let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+    if len > MAX_RECORD {
+        return Some(Hello::Unreadable);
+    }
+    let record = bytes.get(5..5 + len)?;
+    Some(client_hello(record).map_or(Hello::Unreadable, Hello::Tls))
+}
+
+/// SNI del ClientHello que ocupa `handshake`, si es uno entero.
+fn client_hello(handshake: &[u8]) -> Option<Option<String>> {
+    struct Cursor<'a>(&'a [u8]);
+
+    impl<'a> Cursor<'a> {
+        fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+            let (head, rest) = (self.0.get(..n)?, self.0.get(n..)?);
+            self.0 = rest;
+            Some(head)
+        }
+
+        /// Entero de `width` bytes.
+        fn uint(&mut self, width: usize) -> Option<usize> {
+            let bytes = self.take(width)?;
+            Some(bytes.iter().fold(0, |n, &b| n << 8 | usize::from(b)))
+        }
+
+        /// Bloque precedido de su longitud en `width` bytes.
+        fn block(&mut self, width: usize) -> Option<Cursor<'a>> {
+            let len = self.uint(width)?;
+            self.take(len).map(Cursor)
+        }
+    }
+
+    let mut message = Cursor(handshake);
+    if message.uint(1)? != 0x01 {
+        return None;
+    }
+    // A message split across records doesn't fit in this one.
+    let mut hello = message.block(3)?;
+    // Version and random, then session id, cipher suites and compression.
+    hello.take(2 + 32)?;
+    hello.block(1)?;
+    hello.block(2)?;
+    hello.block(1)?;
+    if hello.0.is_empty() {
+        return Some(None);
+    }
+    let mut extensions = hello.block(2)?;
+    while let Some(kind) = extensions.uint(2) {
+        let mut extension = extensions.block(2)?;
+        if kind != 0 {
+            continue;
+        }
+        let mut names = extension.block(2)?;
+        while let Some(name_type) = names.uint(1) {
+            let name = names.block(2)?;
+            if name_type == 0 {
+                return std::str::from_utf8(name.0)
+                    .ok()
+                    .map(|name| Some(name.to_string()));
+            }
+        }
+    }
+    Some(None)
+}
+
+/// Lee del cliente hasta saber qué manda. Lo leído queda en `buf`, también
+/// si se abandona la lectura a medias.
+pub async fn read_hello<R: AsyncRead + Unpin>(
+    client: &mut R,
+    buf: &mut Vec<u8>,
+) -> io::Result<Hello> {
+    loop {
+        if let Some(hello) = classify(buf) {
+            return Ok(hello);
+        }
+        if client.read_buf(buf).await? == 0 {
+            return Ok(if buf.is_empty() {
+                Hello::NotTls
+            } else {
+                Hello::Unreadable
+            });
+        }
+    }
+}
+
+/// Si `sni` nombra a `host`, sin distinguir mayúsculas ni el punto final.
+pub fn matches(host: &str, sni: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let sni = sni.strip_suffix('.').unwrap_or(sni);
+    host.eq_ignore_ascii_case(sni)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ClientHello mínimo con `server_name` tras otra extensión.
+    fn client_hello(host: &str) -> Vec<u8> {
+        let name = host.as_bytes();
+        let mut server_name = vec![0x00, 0x00];
+        let list_len = 3 + name.len();
+        server_name.extend((list_len as u16 + 2).to_be_bytes());
+        server_name.extend((list_len as u16).to_be_bytes());
+        server_name.push(0);
+        server_name.extend((name.len() as u16).to_be_bytes());
+        server_name.extend(name);
+        // supported_versions comes first to exercise the skip.
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend(server_name);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0u8; 32]);
+        body.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend((body.len() as u16).to_be_bytes());
+        handshake.extend(body);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn test_classify_reads_the_server_name_of_a_whole_hello() {
+        let hello = client_hello("api.ejemplo.test");
+        assert_eq!(
+            classify(&hello),
+            Some(Hello::Tls(Some("api.ejemplo.test".to_string())))
+        );
+        // Trailing bytes belong to later records.
+        let mut more = hello.clone();
+        more.extend([0x17, 0x03, 0x03]);
+        assert_eq!(classify(&more), classify(&hello));
+        for cut in [0, 1, 4, hello.len() - 4] {
+            assert_eq!(classify(&hello[..cut]), None, "{cut}");
+        }
+        assert_eq!(classify(b"SSH-2.0-OpenSSH_9.6\r\n"), Some(Hello::NotTls));
+        assert_eq!(classify(b"\x16GET"), Some(Hello::NotTls));
+
+        // The same hello split in two records: the first one is all we see.
+        let mut split = hello[..5 + 40].to_vec();
+        split[3..5].copy_from_slice(&40u16.to_be_bytes());
+        assert_eq!(classify(&split), Some(Hello::Unreadable));
+
+        assert!(matches("API.ejemplo.test.", "api.ejemplo.test"));
+        assert!(!matches("api.ejemplo.test", "otro.ejemplo.test"));
+    }
+}
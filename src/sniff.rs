@@ -0,0 +1,375 @@
+//! Corrección del `Content-Type` de las respuestas por host.
+//!
+//! Hay destinos que declaran `text/plain` para todo, lo que confunde a los
+//! filtros que se guían por el tipo y a los navegadores. Para los hosts de
+//! `[sniff]` se examinan los primeros `max_bytes` del body buscando firmas
+//! (HTML, JSON, PDF, PNG, JPEG, GIF, WebP, ZIP) y, según la regla, se informa
+//! del tipo detectado, se corrige el declarado cuando es claramente
+//! incorrecto o se pide al navegador que no adivine. Los bytes leídos se
+//! vuelven a entregar delante del resto, así que el body sigue en streaming;
+//! la respuesta espera como mucho a esos primeros bytes o al final del body.
+//! Los bodies comprimidos y los `text/event-stream` no se examinan.
+
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::{Body, Response};
+use tracing::debug;
+
+use crate::capture::buffer_prefix;
+use crate::settings::{SniffAction, SniffSettings};
+
+pub const DETECTED: &str = "x-detected-content-type";
+pub const OPTIONS: &str = "x-content-type-options";
+
+/// Firmas binarias: con una de estas el tipo declarado no importa.
+const MAGIC: [(&[u8], &str); 8] = [
+    (b"%PDF-", "application/pdf"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"PK\x05\x06", "application/zip"),
+    (b"PK\x07\x08", "application/zip"),
+];
+
+/// Etiquetas con las que empieza un documento HTML (WHATWG MIME Sniffing
+/// §7.1), seguidas de espacio o `>`.
+const HTML_TAGS: [&[u8]; 17] = [
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<script",
+    b"<iframe",
+    b"<h1",
+    b"<div",
+    b"<font",
+    b"<table",
+    b"<a",
+    b"<style",
+    b"<title",
+    b"<b",
+    b"<body",
+    b"<br",
+    b"<p",
+    b"<!--",
+];
+
+/// Tipos que no dicen nada del contenido y que una detección por texto
+/// puede reemplazar.
+const GENERIC: [&str; 3] = [
+    "text/plain",
+    "application/octet-stream",
+    "binary/octet-stream",
+];
+
+/// Tipo detectado y si salió de una firma binaria, más fiable que el texto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Detected {
+    pub mime: &'static str,
+    pub magic: bool,
+}
+
+/// Tipo del contenido según sus primeros bytes. `complete` indica que
+/// `prefix` es el body entero.
+pub fn detect(prefix: &[u8], complete: bool) -> Option<Detected> {
+    let magic = |mime| Some(Detected { mime, magic: true });
+    if let Some((_, mime)) = MAGIC.iter().find(|(sig, _)| prefix.starts_with(sig)) {
+        return magic(*mime);
+    }
+    if prefix.len() >= 12 && prefix.starts_with(b"RIFF") && &prefix[8..12] == b"WEBP" {
+        return magic("image/webp");
+    }
+    let text = |mime| Some(Detected { mime, magic: false });
+    let trimmed = trim_start(prefix);
+    if starts_html(trimmed) {
+        return text("text/html");
+    }
+    if looks_json(trimmed, complete) {
+        return text("application/json");
+    }
+    None
+}
+
+fn trim_start(bytes: &[u8]) -> &[u8] {
+    let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn starts_html(bytes: &[u8]) -> bool {
+    HTML_TAGS.iter().any(|tag| {
+        bytes.len() > tag.len()
+            && bytes[..tag.len()].eq_ignore_ascii_case(tag)
+            && (tag.ends_with(b"--") || matches!(bytes[tag.len()], b' ' | b'>'))
+    })
+}
+
+/// Un objeto o array JSON válido, o el principio de uno si el body sigue.
+fn looks_json(bytes: &[u8], complete: bool) -> bool {
+    if !bytes.starts_with(b"{") && !bytes.starts_with(b"[") {
+        return false;
+    }
+    // A prefix may end halfway through a UTF-8 sequence.
+    let bytes = match std::str::from_utf8(bytes) {
+        Ok(_) => bytes,
+        Err(e) if e.error_len().is_none() && !complete => &bytes[..e.valid_up_to()],
+        Err(_) => return false,
+    };
+    match serde_json::from_slice::<serde::de::IgnoredAny>(bytes) {
+        Ok(_) => true,
+        Err(e) => e.is_eof() && !complete,
+    }
+}
+
+/// Tipo sin parámetros y en minúsculas.
+fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Si el tipo declarado es aceptable para lo detectado: el mismo, o una
+/// variante como `application/problem+json`.
+fn compatible(declared: &str, detected: &str) -> bool {
+    declared == detected
+        || (detected == "application/json"
+            && (declared.ends_with("+json") || declared == "text/json"))
+        || (detected == "text/html" && declared == "application/xhtml+xml")
+        || (detected == "application/zip" && declared.ends_with("+zip"))
+}
+
+pub struct ContentSniffer {
+    settings: SniffSettings,
+}
+
+impl ContentSniffer {
+    pub fn new(settings: SniffSettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn settings(&self) -> &SniffSettings {
+        &self.settings
+    }
+
+    /// Acción de la primera regla que casa con `host`.
+    pub fn action(&self, host: &str) -> Option<SniffAction> {
+        self.settings
+            .rules()
+            .iter()
+            .find(|rule| rule.host().matches(host))
+            .map(|rule| rule.action())
+    }
+
+    pub async fn apply(&self, host: &str, response: Response<Body>) -> Response<Body> {
+        let Some(action) = self.action(host) else {
+            return response;
+        };
+        let mut response = response;
+        if action == SniffAction::Nosniff {
+            response
+                .headers_mut()
+                .insert(OPTIONS, HeaderValue::from_static("nosniff"));
+            return response;
+        }
+        let headers = response.headers();
+        let encoded = headers
+            .get(CONTENT_ENCODING)
+            .is_some_and(|value| value.as_bytes() != b"identity");
+        let declared = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        if encoded || declared.as_deref().map(essence).as_deref() == Some("text/event-stream") {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let (prefix, truncated, body) = buffer_prefix(body, self.settings.max_bytes()).await;
+        let Some(detected) = detect(&prefix, !truncated) else {
+            return Response::from_parts(parts, body);
+        };
+        if action == SniffAction::Detect {
+            parts
+                .headers
+                .insert(DETECTED, HeaderValue::from_static(detected.mime));
+            return Response::from_parts(parts, body);
+        }
+        let declared_essence = declared.as_deref().map(essence).unwrap_or_default();
+        let wrong = !compatible(&declared_essence, detected.mime)
+            && (detected.magic
+                || declared.is_none()
+                || GENERIC.contains(&declared_essence.as_str()));
+        if wrong {
+            debug!(%host, declared = declared.as_deref().unwrap_or(""), detected = detected.mime, "Content-Type corregido");
+            parts
+                .headers
+                .insert(CONTENT_TYPE, corrected(declared.as_deref(), detected.mime));
+        }
+        Response::from_parts(parts, body)
+    }
+}
+
+/// Tipo corregido; los tipos de texto conservan el `charset` declarado.
+fn corrected(declared: Option<&str>, detected: &'static str) -> HeaderValue {
+    let charset = declared
+        .and_then(|declared| {
+            declared.split(';').skip(1).map(str::trim).find(|param| {
+                param
+                    .get(..8)
+                    .is_some_and(|name| name.eq_ignore_ascii_case("charset="))
+            })
+        })
+        .filter(|_| detected.starts_with("text/") || detected == "application/json");
+    match charset {
+        Some(charset) => HeaderValue::from_str(&format!("{detected}; {charset}"))
+            .unwrap_or(HeaderValue::from_static(detected)),
+        None => HeaderValue::from_static(detected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::SniffRule;
+    use hyper::body::to_bytes;
+
+    fn sniffer(action: SniffAction) -> ContentSniffer {
+        ContentSniffer::new(
+            SniffSettings::default()
+                .with_max_bytes(16)
+                .with_rule(SniffRule::new("*.origin.test".parse().unwrap(), action)),
+        )
+    }
+
+    fn response(content_type: &str, body: &'static [u8]) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_detects_signatures() {
+        let pdf = detect(b"%PDF-1.7\n", true).unwrap();
+        assert_eq!(
+            pdf,
+            Detected {
+                mime: "application/pdf",
+                magic: true
+            }
+        );
+        assert_eq!(
+            detect(b"\x89PNG\r\n\x1a\n....", true).unwrap().mime,
+            "image/png"
+        );
+        assert_eq!(
+            detect(b"RIFF\0\0\0\0WEBPVP8 ", true).unwrap().mime,
+            "image/webp"
+        );
+        assert_eq!(
+            detect(b"PK\x03\x04\x14\0", false).unwrap().mime,
+            "application/zip"
+        );
+        assert_eq!(
+            detect(b"\xef\xbb\xbf\n  <!DOCTYPE HTML><p>", true)
+                .unwrap()
+                .mime,
+            "text/html"
+        );
+        // `<abbr` is not `<a` followed by a tag end.
+        assert_eq!(detect(b"<abbr>x</abbr>", true), None);
+        assert_eq!(
+            detect(br#"{"a": [1, 2"#, false).unwrap().mime,
+            "application/json"
+        );
+        assert_eq!(detect(br#"{"a": [1, 2"#, true), None);
+        // Cut in the middle of "ñ".
+        assert_eq!(detect(b"[\"\xc3", false).unwrap().mime, "application/json");
+        assert_eq!(detect(b"42", true), None);
+        assert_eq!(detect(b"hola", true), None);
+    }
+
+    #[tokio::test]
+    async fn test_detect_adds_header_and_replays_body() {
+        let body: &[u8] = b"<html><body>escrito como texto, no como HTML</body></html>";
+        let res = sniffer(SniffAction::Detect)
+            .apply("www.origin.test", response("text/plain", body))
+            .await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(res.headers()[DETECTED], "text/html");
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), body);
+
+        let res = sniffer(SniffAction::Detect)
+            .apply("otro.test", response("text/plain", body))
+            .await;
+        assert!(!res.headers().contains_key(DETECTED));
+    }
+
+    #[tokio::test]
+    async fn test_override_corrects_only_clearly_wrong_types() {
+        let sniffer = sniffer(SniffAction::Override);
+        let res = sniffer
+            .apply(
+                "api.origin.test",
+                response("text/plain; charset=utf-8", br#"{"ok": true}"#),
+            )
+            .await;
+        assert_eq!(
+            res.headers()[CONTENT_TYPE],
+            "application/json; charset=utf-8"
+        );
+
+        let res = sniffer
+            .apply(
+                "cdn.origin.test",
+                response("text/html", b"%PDF-1.4\n%binario"),
+            )
+            .await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/pdf");
+        assert_eq!(
+            to_bytes(res.into_body()).await.unwrap(),
+            &b"%PDF-1.4\n%binario"[..]
+        );
+
+        // Text detection never overrides a specific declared type.
+        let res = sniffer
+            .apply("cdn.origin.test", response("text/markdown", b"<p>hola</p>"))
+            .await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/markdown");
+        let res = sniffer
+            .apply(
+                "api.origin.test",
+                response("application/problem+json", br#"{"title":"x"}"#),
+            )
+            .await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/problem+json");
+    }
+
+    #[tokio::test]
+    async fn test_nosniff_leaves_type_and_compressed_bodies_alone() {
+        let res = sniffer(SniffAction::Nosniff)
+            .apply(
+                "a.origin.test",
+                response("text/plain", b"\x89PNG\r\n\x1a\n"),
+            )
+            .await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(res.headers()[OPTIONS], "nosniff");
+        assert!(!res.headers().contains_key(DETECTED));
+
+        let mut gzipped = response("text/plain", b"%PDF-1.4");
+        gzipped
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let res = sniffer(SniffAction::Override)
+            .apply("a.origin.test", gzipped)
+            .await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+    }
+}
@@ -0,0 +1,293 @@
+//! Clientes SOCKS5 (RFC 1928) sobre el mismo camino que `CONNECT`.
+//!
+//! Tras el saludo, el destino pedido se convierte en un `CONNECT host:puerto`
+//! que hyper atiende como cualquier otro, así que baneos, reputación, scripts,
+//! perfiles y estadísticas se aplican igual. [`SocksBridge`] le entrega a
+//! hyper esa petición sintética y traduce la cabecera de su respuesta a la
+//! respuesta SOCKS; después el túnel pasa los bytes sin tocarlos. Solo se
+//! admite `CONNECT` sin autenticación.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll, Waker};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+pub(crate) const VERSION: u8 = 0x05;
+pub(crate) const NO_AUTH: u8 = 0x00;
+pub(crate) const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+pub(crate) const CMD_CONNECT: u8 = 0x01;
+/// Tiempo máximo para completar el saludo y la petición.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Cabecera de respuesta de hyper que se acepta leer como mucho.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Códigos de respuesta de RFC 1928 §6.
+pub(crate) mod reply {
+    pub const SUCCEEDED: u8 = 0x00;
+    pub const GENERAL_FAILURE: u8 = 0x01;
+    pub const NOT_ALLOWED: u8 = 0x02;
+    pub const NETWORK_UNREACHABLE: u8 = 0x03;
+    pub const HOST_UNREACHABLE: u8 = 0x04;
+    pub const CONNECTION_REFUSED: u8 = 0x05;
+    pub const TTL_EXPIRED: u8 = 0x06;
+    pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+    pub const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+    pub fn describe(code: u8) -> &'static str {
+        match code {
+            SUCCEEDED => "éxito",
+            GENERAL_FAILURE => "fallo general",
+            NOT_ALLOWED => "no permitido por las reglas del servidor",
+            NETWORK_UNREACHABLE => "red inalcanzable",
+            HOST_UNREACHABLE => "host inalcanzable",
+            CONNECTION_REFUSED => "conexión rechazada",
+            TTL_EXPIRED => "TTL agotado",
+            COMMAND_NOT_SUPPORTED => "comando no soportado",
+            ADDRESS_TYPE_NOT_SUPPORTED => "tipo de dirección no soportado",
+            _ => "código desconocido",
+        }
+    }
+}
+
+/// Lee el saludo y la petición, y devuelve el destino como `host:puerto`.
+/// Si la petición no se puede atender responde el error SOCKS antes de
+/// fallar.
+pub async fn handshake<S>(stream: &mut S) -> anyhow::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, negotiate(stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("saludo SOCKS5 incompleto"))?
+}
+
+async fn negotiate<S>(stream: &mut S) -> anyhow::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [version, methods] = read_array(stream).await?;
+    anyhow::ensure!(version == VERSION, "versión SOCKS {version} no soportada");
+    let mut offered = vec![0u8; methods.into()];
+    stream.read_exact(&mut offered).await?;
+    if !offered.contains(&NO_AUTH) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        anyhow::bail!("el cliente SOCKS5 solo ofrece métodos con autenticación");
+    }
+    stream.write_all(&[VERSION, NO_AUTH]).await?;
+
+    let [version, command, _reserved, address_type] = read_array(stream).await?;
+    anyhow::ensure!(version == VERSION, "versión SOCKS {version} no soportada");
+    let host = match address_type {
+        0x01 => Ipv4Addr::from(read_array::<_, 4>(stream).await?).to_string(),
+        0x03 => {
+            let [len] = read_array(stream).await?;
+            let mut name = vec![0u8; len.into()];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| anyhow::anyhow!("nombre de host no UTF-8"))?
+        }
+        0x04 => format!("[{}]", Ipv6Addr::from(read_array::<_, 16>(stream).await?)),
+        other => {
+            stream
+                .write_all(&reply_bytes(reply::ADDRESS_TYPE_NOT_SUPPORTED))
+                .await?;
+            anyhow::bail!("tipo de dirección SOCKS5 {other:#04x} no soportado");
+        }
+    };
+    let port = u16::from_be_bytes(read_array(stream).await?);
+    if command != CMD_CONNECT {
+        stream
+            .write_all(&reply_bytes(reply::COMMAND_NOT_SUPPORTED))
+            .await?;
+        anyhow::bail!("comando SOCKS5 {command:#04x} no soportado");
+    }
+    Ok(format!("{host}:{port}"))
+}
+
+async fn read_array<S: AsyncRead + Unpin, const N: usize>(stream: &mut S) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Respuesta sin dirección ligada; los clientes no la usan para `CONNECT`.
+fn reply_bytes(code: u8) -> [u8; 10] {
+    [VERSION, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+}
+
+/// Respuesta SOCKS equivalente a la que el proxy dio al `CONNECT`.
+fn reply_for(head: &[u8]) -> u8 {
+    let status = head
+        .split(|b| *b == b' ')
+        .nth(1)
+        .and_then(|code| std::str::from_utf8(code).ok()?.parse::<u16>().ok());
+    match status {
+        Some(200..=299) => reply::SUCCEEDED,
+        Some(403) => reply::NOT_ALLOWED,
+        Some(502) => reply::CONNECTION_REFUSED,
+        Some(504) => reply::TTL_EXPIRED,
+        _ => reply::GENERAL_FAILURE,
+    }
+}
+
+enum Phase {
+    /// Acumulando la cabecera de la respuesta al `CONNECT`.
+    Head(Vec<u8>),
+    Tunnel,
+    /// El `CONNECT` falló: lo que hyper escriba se descarta y las lecturas
+    /// terminan para que cierre la conexión.
+    Refused,
+}
+
+/// Conexión SOCKS5 ya negociada, vista por hyper como un cliente que envió
+/// `CONNECT` al destino.
+pub struct SocksBridge<S> {
+    inner: S,
+    request: Vec<u8>,
+    read: usize,
+    phase: Phase,
+    /// Respuesta SOCKS pendiente de escribir.
+    pending: Vec<u8>,
+    /// Lector esperando al socket, a despertar si el `CONNECT` falla.
+    reader: Option<Waker>,
+}
+
+impl<S> SocksBridge<S> {
+    pub fn new(inner: S, target: &str) -> Self {
+        Self {
+            inner,
+            request: format!("CONNECT {target} HTTP/1.1\r\nhost: {target}\r\n\r\n").into_bytes(),
+            read: 0,
+            phase: Phase::Head(Vec::new()),
+            pending: Vec::new(),
+            reader: None,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SocksBridge<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SocksBridge<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.read < this.request.len() {
+            let n = buf.remaining().min(this.request.len() - this.read);
+            buf.put_slice(&this.request[this.read..this.read + n]);
+            this.read += n;
+            return Poll::Ready(Ok(()));
+        }
+        if let Phase::Refused = this.phase {
+            return Poll::Ready(Ok(()));
+        }
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if poll.is_pending() {
+            this.reader = Some(cx.waker().clone());
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SocksBridge<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_pending(cx))?;
+        let head = match &mut this.phase {
+            Phase::Tunnel => return Pin::new(&mut this.inner).poll_write(cx, buf),
+            Phase::Refused => return Poll::Ready(Ok(buf.len())),
+            Phase::Head(head) => head,
+        };
+        let before = head.len();
+        head.extend_from_slice(buf);
+        let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if head.len() > MAX_HEAD {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cabecera de respuesta demasiado larga",
+                )));
+            }
+            return Poll::Ready(Ok(buf.len()));
+        };
+        // Only the head is consumed; hyper writes whatever follows again.
+        let consumed = end + 4 - before;
+        let code = reply_for(&head[..end]);
+        this.pending = reply_bytes(code).to_vec();
+        if code == reply::SUCCEEDED {
+            this.phase = Phase::Tunnel;
+        } else {
+            this.phase = Phase::Refused;
+            if let Some(reader) = this.reader.take() {
+                reader.wake();
+            }
+        }
+        Poll::Ready(Ok(consumed))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_parses_targets_and_rejects_others() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        client.write_all(&[5, 1, 0, 5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"example.com\x01\xbb").await.unwrap();
+        assert_eq!(handshake(&mut server).await.unwrap(), "example.com:443");
+        let mut chosen = [0u8; 2];
+        client.read_exact(&mut chosen).await.unwrap();
+        assert_eq!(chosen, [5, NO_AUTH]);
+
+        // BIND is refused with its own reply code.
+        let (mut client, mut server) = tokio::io::duplex(256);
+        client
+            .write_all(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        assert!(handshake(&mut server).await.is_err());
+        let mut replies = [0u8; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], reply::COMMAND_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn test_reply_follows_connect_status() {
+        assert_eq!(reply_for(b"HTTP/1.1 200 OK"), reply::SUCCEEDED);
+        assert_eq!(reply_for(b"HTTP/1.1 403 Forbidden"), reply::NOT_ALLOWED);
+        assert_eq!(
+            reply_for(b"HTTP/1.1 502 Bad Gateway"),
+            reply::CONNECTION_REFUSED
+        );
+        assert_eq!(reply_for(b"basura"), reply::GENERAL_FAILURE);
+    }
+}
@@ -0,0 +1,241 @@
+//! Salida por un servidor SOCKS5 (RFC 1928).
+//!
+//! Con `--upstream-socks5` (o `[upstream_socks5]`), las conexiones con los
+//! destinos que piden los clientes, tanto los túneles `CONNECT` como las
+//! peticiones HTTP directas, se abren a través del servidor SOCKS5 en lugar
+//! de con un `connect` propio. El nombre del destino viaja tal cual y lo
+//! resuelve el servidor; las IPs literales van como IPs. Si el servidor pide
+//! usuario y contraseña se usan los de la configuración (RFC 1929). Los
+//! proxies padre, el PAC y demás servicios configurados se siguen conectando
+//! directamente.
+//!
+//! Un saludo fallido o una respuesta distinta de "éxito" es un error de
+//! conexión: el cliente recibe un 502 y el log lleva el código SOCKS.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use hyper::http::uri::Authority;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::settings::{AdminToken, Socks5UpstreamSettings};
+use crate::socks::{reply, CMD_CONNECT, NO_ACCEPTABLE_METHODS, NO_AUTH, VERSION};
+
+/// Puerto del servidor cuando la dirección no lo indica.
+pub const DEFAULT_SOCKS_PORT: u16 = 1080;
+
+/// Tiempo máximo para completar el saludo y la petición.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const USER_PASSWORD: u8 = 0x02;
+/// Versión de la subnegociación de RFC 1929.
+const AUTH_VERSION: u8 = 0x01;
+
+/// El servidor respondió a la petición con un código de error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocksRefused {
+    pub reply: u8,
+}
+
+impl fmt::Display for SocksRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "el servidor SOCKS5 respondió {:#04x} ({})",
+            self.reply,
+            reply::describe(self.reply)
+        )
+    }
+}
+
+impl std::error::Error for SocksRefused {}
+
+pub struct Socks5Upstream {
+    server: Authority,
+    credentials: Option<(String, AdminToken)>,
+}
+
+impl Socks5Upstream {
+    pub fn new(settings: &Socks5UpstreamSettings) -> Self {
+        Self {
+            server: settings.server().clone(),
+            credentials: settings.credentials().cloned(),
+        }
+    }
+
+    pub fn server(&self) -> &Authority {
+        &self.server
+    }
+
+    pub fn port(&self) -> u16 {
+        self.server.port_u16().unwrap_or(DEFAULT_SOCKS_PORT)
+    }
+
+    /// Pide al servidor, ya conectado en `stream`, una conexión con
+    /// `host:port`, y devuelve `stream` listo para copiar bytes.
+    pub async fn connect<S>(&self, mut stream: S, host: &str, port: u16) -> io::Result<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let negotiated =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, self.negotiate(&mut stream, host, port))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "saludo SOCKS5 incompleto",
+                    ))
+                });
+        if let Err(e) = &negotiated {
+            let reply = e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<SocksRefused>())
+                .map(|refused| refused.reply);
+            warn!(server = %self.server, %host, port, ?reply, error = %e, "Fallo en el saludo SOCKS5");
+        }
+        negotiated.map(|()| stream)
+    }
+
+    async fn negotiate<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[VERSION, 2, NO_AUTH, USER_PASSWORD],
+            None => &[VERSION, 1, NO_AUTH],
+        };
+        stream.write_all(greeting).await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        match choice {
+            [VERSION, NO_AUTH] => {}
+            [VERSION, USER_PASSWORD] => match &self.credentials {
+                Some((user, password)) => authenticate(stream, user, password).await?,
+                None => return Err(protocol("el servidor SOCKS5 eligió un método no ofrecido")),
+            },
+            [VERSION, NO_ACCEPTABLE_METHODS] => {
+                return Err(protocol(
+                    "el servidor SOCKS5 no acepta ninguno de los métodos de autenticación",
+                ))
+            }
+            _ => return Err(protocol("respuesta SOCKS5 inválida al saludo")),
+        }
+
+        let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+        match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let name = u8::try_from(host.len())
+                    .map_err(|_| protocol("nombre de host demasiado largo para SOCKS5"))?;
+                request.extend_from_slice(&[0x03, name]);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        let [version, code, _reserved, address_type] = head;
+        if version != VERSION {
+            return Err(protocol("respuesta SOCKS5 inválida a la petición"));
+        }
+        if code != reply::SUCCEEDED {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                SocksRefused { reply: code },
+            ));
+        }
+        // The bound address is of no use for CONNECT, but must be consumed.
+        let bound = match address_type {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => usize::from(stream.read_u8().await?),
+            _ => {
+                return Err(protocol(
+                    "tipo de dirección SOCKS5 inválido en la respuesta",
+                ))
+            }
+        };
+        let mut skip = vec![0u8; bound + 2];
+        stream.read_exact(&mut skip).await?;
+        Ok(())
+    }
+}
+
+async fn authenticate<S>(stream: &mut S, user: &str, password: &AdminToken) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let password = password.expose();
+    let (Ok(user_len), Ok(password_len)) = (u8::try_from(user.len()), u8::try_from(password.len()))
+    else {
+        return Err(protocol("usuario o contraseña SOCKS5 de más de 255 bytes"));
+    };
+    let mut request = vec![AUTH_VERSION, user_len];
+    request.extend_from_slice(user.as_bytes());
+    request.push(password_len);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await?;
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await?;
+    match status {
+        [AUTH_VERSION, 0x00] => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "el servidor SOCKS5 rechazó el usuario y la contraseña",
+        )),
+    }
+}
+
+fn protocol(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_credentials_are_sent_when_the_server_asks() {
+        let server = "socks.test:1080".parse().unwrap();
+        let upstream = Socks5Upstream::new(
+            &Socks5UpstreamSettings::new(server).with_credentials("ana", "s3creta"),
+        );
+        let (client, mut socks) = tokio::io::duplex(1024);
+        let serve = async move {
+            let mut greeting = [0u8; 4];
+            socks.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [VERSION, 2, NO_AUTH, USER_PASSWORD]);
+            socks.write_all(&[VERSION, USER_PASSWORD]).await.unwrap();
+            let mut auth = [0u8; 13];
+            socks.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x03ana\x07s3creta");
+            socks.write_all(&[AUTH_VERSION, 0]).await.unwrap();
+            let mut request = [0u8; 18];
+            socks.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..5], [VERSION, CMD_CONNECT, 0, 3, 11]);
+            assert_eq!(&request[5..16], b"example.com");
+            assert_eq!(u16::from_be_bytes([request[16], request[17]]), 443);
+            socks
+                .write_all(&[VERSION, reply::HOST_UNREACHABLE, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        };
+        let (result, ()) = tokio::join!(upstream.connect(client, "example.com", 443), serve);
+        let err = result.err().unwrap();
+        let refused = err.get_ref().unwrap().downcast_ref::<SocksRefused>();
+        assert_eq!(refused, Some(&SocksRefused { reply: 0x04 }));
+        assert!(err.to_string().contains("host inalcanzable"));
+    }
+}
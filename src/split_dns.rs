@@ -0,0 +1,429 @@
+//! Resolución con horizonte dividido.
+//!
+//! [`SplitHorizonResolver`] envuelve la resolución del sistema: los nombres
+//! de una zona configurada se preguntan a su propio servidor DNS y las
+//! respuestas que caen en el rango de una reescritura se sustituyen por otra
+//! dirección o se vuelven a preguntar a otro servidor. Como el [`Dialer`]
+//! resuelve a través de él, las reglas valen igual para las peticiones HTTP
+//! y para los túneles CONNECT. Las reglas se reemplazan en caliente con
+//! [`SplitHorizonResolver::reload`].
+//!
+//! [`Dialer`]: crate::dialer::Dialer
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+use crate::dialer::Resolve;
+use crate::settings::{DnsRewriteAction, DnsSettings};
+
+/// Espera máxima de la respuesta de un servidor DNS configurado.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+type ServerFactory = dyn Fn(SocketAddr) -> Arc<dyn Resolve> + Send + Sync;
+
+/// Resultado de resolver un nombre con todas las reglas, tal como lo muestra
+/// la API de administración.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResolveTrace {
+    pub host: String,
+    /// Servidor de la zona; `None` es el DNS del sistema.
+    pub server: Option<SocketAddr>,
+    pub answers: Vec<IpAddr>,
+    /// Direcciones finales tras las reescrituras.
+    pub resolved: Vec<IpAddr>,
+}
+
+pub struct SplitHorizonResolver {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    system: Arc<dyn Resolve>,
+    connect: Box<ServerFactory>,
+    servers: Mutex<HashMap<SocketAddr, Arc<dyn Resolve>>>,
+    settings: RwLock<DnsSettings>,
+}
+
+impl SplitHorizonResolver {
+    /// Los servidores de zonas y reescrituras se consultan por UDP.
+    pub fn new(settings: DnsSettings, system: Arc<dyn Resolve>) -> Self {
+        Self::with_servers(settings, system, |server| {
+            Arc::new(UdpResolver::new(server))
+        })
+    }
+
+    /// Como [`SplitHorizonResolver::new`], eligiendo cómo se consulta cada
+    /// servidor; lo usan las pruebas.
+    pub fn with_servers(
+        settings: DnsSettings,
+        system: Arc<dyn Resolve>,
+        connect: impl Fn(SocketAddr) -> Arc<dyn Resolve> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                system,
+                connect: Box::new(connect),
+                servers: Mutex::new(HashMap::new()),
+                settings: RwLock::new(settings),
+            }),
+        }
+    }
+
+    pub fn reload(&self, settings: DnsSettings) {
+        let mut current = self.inner.settings.write().expect("reglas DNS");
+        if *current != settings {
+            info!(
+                zones = settings.zones().len(),
+                rewrites = settings.rewrites().len(),
+                "Reglas DNS actualizadas"
+            );
+            *current = settings;
+        }
+    }
+
+    /// Reglas en vigor.
+    pub fn settings(&self) -> DnsSettings {
+        self.inner.settings.read().expect("reglas DNS").clone()
+    }
+
+    /// Resuelve `host` aplicando las reglas y cuenta qué pasó en cada paso.
+    pub async fn explain(&self, host: &str) -> io::Result<ResolveTrace> {
+        self.inner.clone().explain(host.to_string()).await
+    }
+}
+
+impl Inner {
+    fn server(&self, addr: SocketAddr) -> Arc<dyn Resolve> {
+        self.servers
+            .lock()
+            .expect("servidores DNS")
+            .entry(addr)
+            .or_insert_with(|| (self.connect)(addr))
+            .clone()
+    }
+
+    async fn explain(self: Arc<Self>, host: String) -> io::Result<ResolveTrace> {
+        let settings = self.settings.read().expect("reglas DNS").clone();
+        let server = settings
+            .zones()
+            .iter()
+            .find(|(zone, _)| zone.matches(&host))
+            .map(|(_, server)| *server);
+        let upstream = match server {
+            Some(server) => self.server(server),
+            None => self.system.clone(),
+        };
+        let answers: Vec<IpAddr> = upstream
+            .resolve(&host, 0)
+            .await?
+            .into_iter()
+            .map(|addr| addr.ip())
+            .collect();
+
+        let mut resolved = Vec::with_capacity(answers.len());
+        for ip in &answers {
+            let rule = settings
+                .rewrites()
+                .iter()
+                .find(|(pattern, range, _)| pattern.matches(&host) && range.contains(*ip));
+            let rewritten = match rule {
+                None => vec![*ip],
+                Some((_, _, DnsRewriteAction::Replace(with))) => vec![*with],
+                Some((_, _, DnsRewriteAction::Requery(server))) => self
+                    .server(*server)
+                    .resolve(&host, 0)
+                    .await?
+                    .into_iter()
+                    .map(|addr| addr.ip())
+                    .collect(),
+            };
+            if rule.is_some() {
+                debug!(%host, before = %ip, after = ?rewritten, "Respuesta DNS reescrita");
+            }
+            for ip in rewritten {
+                if !resolved.contains(&ip) {
+                    resolved.push(ip);
+                }
+            }
+        }
+        Ok(ResolveTrace {
+            host,
+            server,
+            answers,
+            resolved,
+        })
+    }
+}
+
+impl Resolve for SplitHorizonResolver {
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        if host.parse::<IpAddr>().is_ok() {
+            return self.inner.system.resolve(host, port);
+        }
+        let inner = self.inner.clone();
+        let host = host.to_ascii_lowercase();
+        Box::pin(async move {
+            let trace = inner.explain(host).await?;
+            Ok(trace
+                .resolved
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect())
+        })
+    }
+}
+
+/// Cliente DNS mínimo: pregunta A y AAAA por UDP a un servidor recursivo.
+/// No sigue respuestas truncadas ni valida DNSSEC.
+pub struct UdpResolver {
+    server: SocketAddr,
+}
+
+impl UdpResolver {
+    pub fn new(server: SocketAddr) -> Self {
+        Self { server }
+    }
+}
+
+impl Resolve for UdpResolver {
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        let server = self.server;
+        let host = host.to_string();
+        Box::pin(async move {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![SocketAddr::new(ip, port)]);
+            }
+            let (v4, v6) = tokio::join!(
+                query(server, &host, TYPE_A),
+                query(server, &host, TYPE_AAAA)
+            );
+            let mut ips = match (v4, v6) {
+                (Err(e), Err(_)) => return Err(e),
+                (v4, v6) => [v4.unwrap_or_default(), v6.unwrap_or_default()].concat(),
+            };
+            ips.dedup();
+            Ok(ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect())
+        })
+    }
+}
+
+async fn query(server: SocketAddr, host: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+    let bind: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    let id: u16 = rand::random();
+    socket.send(&encode_query(id, host, qtype)?).await?;
+    let mut buf = [0u8; 1500];
+    let n = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{server} no respondió")))??;
+    decode_answers(&buf[..n], id)
+}
+
+fn encode_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(18 + host.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("nombre DNS inválido: {host}"),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    Ok(packet)
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "respuesta DNS inválida")
+}
+
+fn decode_answers(packet: &[u8], id: u16) -> io::Result<Vec<IpAddr>> {
+    if packet.len() < 12 || packet[..2] != id.to_be_bytes() {
+        return Err(invalid());
+    }
+    match packet[3] & 0x0f {
+        0 => {}
+        // NXDOMAIN: the name simply has no addresses.
+        3 => return Ok(Vec::new()),
+        rcode => {
+            return Err(io::Error::other(format!(
+                "el servidor DNS respondió con el código {rcode}"
+            )))
+        }
+    }
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let answers = u16::from_be_bytes([packet[6], packet[7]]);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(packet, pos)?;
+        let header = packet.get(pos..pos + 10).ok_or_else(invalid)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = usize::from(u16::from_be_bytes([header[8], header[9]]));
+        let data = packet.get(pos + 10..pos + 10 + len).ok_or_else(invalid)?;
+        if let Ok(v4) = <[u8; 4]>::try_from(data).map(IpAddr::from) {
+            if rtype == TYPE_A {
+                ips.push(v4);
+            }
+        } else if let Ok(v6) = <[u8; 16]>::try_from(data).map(IpAddr::from) {
+            if rtype == TYPE_AAAA {
+                ips.push(v6);
+            }
+        }
+        pos += 10 + len;
+    }
+    Ok(ips)
+}
+
+/// Salta un nombre (etiquetas o puntero de compresión) y devuelve la
+/// posición siguiente.
+fn skip_name(packet: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *packet.get(pos).ok_or_else(invalid)?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + usize::from(len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::DnsRewriteAction;
+
+    /// Fixed answers per name, standing in for one DNS server.
+    struct Canned(Vec<(&'static str, &'static str)>);
+
+    impl Resolve for Canned {
+        fn resolve(
+            &self,
+            host: &str,
+            port: u16,
+        ) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+            let addrs = self
+                .0
+                .iter()
+                .filter(|(name, _)| *name == host)
+                .map(|(_, ip)| SocketAddr::new(ip.parse().unwrap(), port))
+                .collect();
+            Box::pin(async move { Ok(addrs) })
+        }
+    }
+
+    fn resolver(settings: DnsSettings) -> SplitHorizonResolver {
+        let system = Arc::new(Canned(vec![
+            ("app.corp.test", "203.0.113.10"),
+            ("app.corp.test", "198.51.100.7"),
+            ("www.public.test", "203.0.113.20"),
+        ]));
+        SplitHorizonResolver::with_servers(settings, system, |server| {
+            let answers = match server.port() {
+                5301 => vec![("db.corp.test", "10.0.0.5"), ("app.corp.test", "10.0.0.9")],
+                _ => vec![("app.corp.test", "10.9.9.9")],
+            };
+            Arc::new(Canned(answers))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_zone_selects_server() {
+        let corp: SocketAddr = "127.0.0.1:5301".parse().unwrap();
+        let dns = resolver(DnsSettings::default().with_zone("*.corp.test".parse().unwrap(), corp));
+
+        let trace = dns.explain("db.corp.test").await.unwrap();
+        assert_eq!(trace.server, Some(corp));
+        assert_eq!(trace.resolved, ["10.0.0.5".parse::<IpAddr>().unwrap()]);
+
+        let trace = dns.explain("www.public.test").await.unwrap();
+        assert_eq!(trace.server, None);
+        assert_eq!(trace.resolved, ["203.0.113.20".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_only_matching_answers() {
+        let settings = DnsSettings::default().with_rewrite(
+            "*.corp.test".parse().unwrap(),
+            "203.0.113.0/24".parse().unwrap(),
+            DnsRewriteAction::Replace("10.1.2.3".parse().unwrap()),
+        );
+        let dns = resolver(settings);
+        let addrs = dns.resolve("app.corp.test", 443).await.unwrap();
+        let expected: Vec<SocketAddr> = vec![
+            "10.1.2.3:443".parse().unwrap(),
+            "198.51.100.7:443".parse().unwrap(),
+        ];
+        assert_eq!(addrs, expected);
+        // Same range, but the host pattern does not match.
+        let trace = dns.explain("www.public.test").await.unwrap();
+        assert_eq!(trace.resolved, trace.answers);
+
+        // Requery against another server, hot-reloaded.
+        dns.reload(DnsSettings::default().with_rewrite(
+            "app.corp.test".parse().unwrap(),
+            "203.0.113.0/24".parse().unwrap(),
+            DnsRewriteAction::Requery("127.0.0.1:5399".parse().unwrap()),
+        ));
+        let trace = dns.explain("app.corp.test").await.unwrap();
+        let expected: Vec<IpAddr> =
+            vec!["10.9.9.9".parse().unwrap(), "198.51.100.7".parse().unwrap()];
+        assert_eq!(trace.resolved, expected);
+    }
+
+    #[tokio::test]
+    async fn test_udp_query_roundtrip() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = server.recv_from(&mut buf).await {
+                let query = &buf[..n];
+                let qtype = u16::from_be_bytes([query[n - 4], query[n - 3]]);
+                let mut reply = query.to_vec();
+                reply[2] = 0x81;
+                reply[3] = 0x80;
+                if qtype == TYPE_A {
+                    reply[7] = 1;
+                    // Compressed pointer to the question name.
+                    reply
+                        .extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 7]);
+                }
+                let _ = server.send_to(&reply, peer).await;
+            }
+        });
+        let addrs = UdpResolver::new(addr)
+            .resolve("db.corp.test", 80)
+            .await
+            .unwrap();
+        assert_eq!(addrs, ["10.0.0.7:80".parse::<SocketAddr>().unwrap()]);
+    }
+}
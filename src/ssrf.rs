@@ -0,0 +1,184 @@
+//! Protección contra SSRF: destinos en redes privadas.
+//!
+//! Sin ella, un cliente puede usar el proxy para llegar a `127.0.0.1`, a
+//! `169.254.169.254` o a los servicios internos de la red del proxy. Con
+//! `--ssrf-protect` (o `block_private_networks = true`) el [`Dialer`] se niega
+//! a conectar con un destino si alguna de sus direcciones es de loopback,
+//! link-local, privada (RFC 1918, `100.64.0.0/10`, `fc00::/7`) o sin
+//! especificar, o cae en `blocked_networks`. La comprobación se hace sobre
+//! las direcciones que el dialer acaba de resolver y a las que va a
+//! conectar, así que un DNS que cambie de respuesta entre una consulta y otra
+//! no la esquiva. Las IPs literales pasan por el mismo camino, y las
+//! direcciones NAT64 se juzgan por la IPv4 que representan.
+//!
+//! El fallo llega a los handlers como un error de conexión que lleva un
+//! [`Blocked`] dentro ([`blocked`]); el cliente recibe un 403. Las conexiones
+//! con los proxies padre, el PAC y demás servicios configurados no pasan por
+//! esta comprobación: solo los destinos que pide el cliente.
+//!
+//! [`Dialer`]: crate::dialer::Dialer
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use crate::nat64::Nat64Resolver;
+use crate::settings::SsrfSettings;
+
+/// Un destino resolvió a una dirección bloqueada.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blocked {
+    pub host: String,
+    pub ip: IpAddr,
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} resuelve a {}, en una red bloqueada",
+            self.host, self.ip
+        )
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+/// El [`Blocked`] que lleva `err` o alguna de sus causas.
+pub fn blocked<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a Blocked> {
+    let mut source = Some(err);
+    while let Some(cause) = source {
+        if let Some(blocked) = cause.downcast_ref::<Blocked>() {
+            return Some(blocked);
+        }
+        if let Some(inner) = cause
+            .downcast_ref::<io::Error>()
+            .and_then(|io| io.get_ref())
+            .and_then(|inner| inner.downcast_ref::<Blocked>())
+        {
+            return Some(inner);
+        }
+        source = cause.source();
+    }
+    None
+}
+
+pub struct NetworkGuard {
+    settings: SsrfSettings,
+    nat64: Option<Arc<Nat64Resolver>>,
+}
+
+impl NetworkGuard {
+    pub fn new(settings: SsrfSettings) -> Self {
+        Self {
+            settings,
+            nat64: None,
+        }
+    }
+
+    pub fn with_nat64(mut self, nat64: Option<Arc<Nat64Resolver>>) -> Self {
+        self.nat64 = nat64;
+        self
+    }
+
+    pub fn blocks(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        if let Some(v4) = self.nat64.as_deref().and_then(|nat64| nat64.embedded(ip)) {
+            return self.blocks(IpAddr::V4(v4));
+        }
+        (self.settings.block_private_networks() && is_private(ip))
+            || self
+                .settings
+                .blocked_networks()
+                .iter()
+                .any(|range| range.contains(ip))
+    }
+
+    /// Error con un [`Blocked`] si alguna de `addrs` está bloqueada.
+    pub fn check(&self, host: &str, addrs: &[SocketAddr]) -> io::Result<()> {
+        match addrs.iter().find(|addr| self.blocks(addr.ip())) {
+            Some(addr) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                Blocked {
+                    host: host.to_string(),
+                    ip: addr.ip(),
+                },
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || a == 0
+        // Shared address space (RFC 6598), used by carrier-grade NAT.
+        || (a == 100 && (64..128).contains(&b))
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_private_ranges_and_extra_networks_are_blocked() {
+        let guard = NetworkGuard::new(
+            SsrfSettings::default()
+                .with_block_private_networks(true)
+                .with_blocked_network("198.51.100.0/24".parse().unwrap()),
+        );
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "198.51.100.7",
+        ] {
+            assert!(guard.blocks(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["93.184.216.34", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!guard.blocks(ip.parse().unwrap()), "{ip}");
+        }
+
+        // One bad address is enough, and the error says which.
+        let addrs = ["93.184.216.34:80", "10.0.0.1:80"].map(|a| a.parse().unwrap());
+        let err = guard.check("mixto.test", &addrs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let found = blocked(&err).unwrap();
+        assert_eq!(found.ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(found.host, "mixto.test");
+    }
+}
@@ -0,0 +1,205 @@
+//! Estadísticas de tráfico por día persistidas en disco.
+//!
+//! A diferencia de [`crate::metrics::Metrics`], que vive en memoria, estos
+//! agregados sobreviven a los reinicios: los informes semanales se calculan a
+//! partir de ellos. Se guardan como JSON, reemplazando el archivo de forma
+//! atómica en cada volcado.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::settings::StatsSettings;
+
+/// Agregados de un día (o de un periodo cuando se combinan varios).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DayStats {
+    pub requests: u64,
+    pub bytes: u64,
+    /// Respuestas 5xx y fallos hacia el destino.
+    pub errors: u64,
+    /// Peticiones por IP de cliente.
+    pub clients: HashMap<String, u64>,
+    /// Peticiones por host de destino.
+    pub hosts: HashMap<String, u64>,
+    /// Bloqueos por categoría (`reputation`, `profile`, `script`...).
+    pub blocks: HashMap<String, u64>,
+    /// Túneles por IP de destino: la del socket conectado, no la del DNS.
+    pub destinations: HashMap<String, u64>,
+}
+
+impl DayStats {
+    fn merge(&mut self, other: &DayStats) {
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+        self.errors += other.errors;
+        for (dst, src) in [
+            (&mut self.clients, &other.clients),
+            (&mut self.hosts, &other.hosts),
+            (&mut self.blocks, &other.blocks),
+            (&mut self.destinations, &other.destinations),
+        ] {
+            for (key, count) in src {
+                *dst.entry(key.clone()).or_default() += count;
+            }
+        }
+    }
+
+    /// Las `n` entradas con más peticiones, de mayor a menor.
+    pub fn top(map: &HashMap<String, u64>, n: usize) -> Vec<(String, u64)> {
+        let mut entries: Vec<_> = map.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        entries.truncate(n);
+        entries
+    }
+}
+
+/// Día UTC actual contado desde la época Unix.
+pub fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86_400
+}
+
+/// Fecha `AAAA-MM-DD` de un día desde la época (algoritmo de Howard Hinnant).
+pub fn format_day(day: u64) -> String {
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+pub struct StatsStore {
+    path: PathBuf,
+    retention_days: u64,
+    days: Mutex<BTreeMap<u64, DayStats>>,
+}
+
+impl StatsStore {
+    /// Abre el archivo de estadísticas; si no existe se empieza de cero.
+    pub fn open(settings: &StatsSettings) -> anyhow::Result<Self> {
+        let days = match std::fs::read(settings.path()) {
+            Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| {
+                format!("Estadísticas corruptas en {}", settings.path().display())
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("No se pudo leer {}", settings.path().display()))
+            }
+        };
+        Ok(Self {
+            path: settings.path().to_path_buf(),
+            retention_days: settings.retention_days(),
+            days: Mutex::new(days),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn with_today(&self, update: impl FnOnce(&mut DayStats)) {
+        let mut days = self.days.lock().expect("estadísticas");
+        update(days.entry(today()).or_default());
+    }
+
+    pub fn record_request(&self, client: &str, host: &str, server_error: bool) {
+        self.with_today(|day| {
+            day.requests += 1;
+            day.errors += u64::from(server_error);
+            *day.clients.entry(client.to_string()).or_default() += 1;
+            *day.hosts.entry(host.to_string()).or_default() += 1;
+        });
+    }
+
+    pub fn record_bytes(&self, bytes: u64) {
+        self.with_today(|day| day.bytes += bytes);
+    }
+
+    pub fn record_block(&self, category: &str) {
+        self.with_today(|day| *day.blocks.entry(category.to_string()).or_default() += 1);
+    }
+
+    pub fn record_destination(&self, ip: &str) {
+        self.with_today(|day| *day.destinations.entry(ip.to_string()).or_default() += 1);
+    }
+
+    /// Combina los días `[from, to)`.
+    pub fn range(&self, from: u64, to: u64) -> DayStats {
+        let days = self.days.lock().expect("estadísticas");
+        let mut total = DayStats::default();
+        for stats in days.range(from..to).map(|(_, stats)| stats) {
+            total.merge(stats);
+        }
+        total
+    }
+
+    /// Reemplaza los agregados de `day`; lo usan las pruebas y las
+    /// importaciones de datos históricos.
+    pub fn insert_day(&self, day: u64, stats: DayStats) {
+        self.days.lock().expect("estadísticas").insert(day, stats);
+    }
+
+    /// Escribe el archivo descartando los días fuera de la retención.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let bytes = {
+            let mut days = self.days.lock().expect("estadísticas");
+            let oldest = today().saturating_sub(self.retention_days);
+            days.retain(|day, _| *day >= oldest);
+            serde_json::to_vec(&*days)?
+        };
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, bytes)
+            .with_context(|| format!("No se pudo escribir {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("No se pudo reemplazar {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = StatsSettings::new(dir.path().join("stats.json"));
+        let store = StatsStore::open(&settings).unwrap();
+        store.record_request("10.0.0.1", "a.test", false);
+        store.record_request("10.0.0.1", "b.test", true);
+        store.record_bytes(512);
+        store.record_block("reputation");
+        store.record_destination("192.0.2.7");
+        store.flush().unwrap();
+
+        let reopened = StatsStore::open(&settings).unwrap();
+        let stats = reopened.range(today(), today() + 1);
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.bytes, 512);
+        assert_eq!(stats.clients["10.0.0.1"], 2);
+        assert_eq!(stats.blocks["reputation"], 1);
+        assert_eq!(stats.destinations["192.0.2.7"], 1);
+    }
+
+    #[test]
+    fn test_format_day() {
+        assert_eq!(format_day(0), "1970-01-01");
+        assert_eq!(format_day(19_782), "2024-02-29");
+    }
+}
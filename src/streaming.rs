@@ -0,0 +1,74 @@
+//! Respuestas que se entregan a medida que llegan.
+//!
+//! Una respuesta `text/event-stream` (los chats en streaming de los
+//! gateways LLM) o con `Transfer-Encoding: chunked` sin `Content-Length`
+//! se marca con [`Streamed`] al llegar del destino. Las etapas que leen el
+//! body por adelantado (la caché de respuestas, el registro de `usage` de
+//! `[llm]`, la idempotencia y las capturas) no lo hacen con ellas, así que
+//! cada chunk sale hacia el cliente en cuanto el destino lo envía. Las de
+//! eventos tampoco se retienen para pasar los trailers a cabeceras. El
+//! `request_timeout` solo cubre la espera de la cabecera de la respuesta:
+//! una generación larga no se corta a mitad.
+
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+use hyper::{HeaderMap, Response};
+
+/// Extensión de las respuestas que se reenvían sin retener el body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Streamed;
+
+/// Si el body es un stream de eventos.
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Si la respuesta con estas cabeceras se debe entregar chunk a chunk.
+pub fn detect(headers: &HeaderMap) -> bool {
+    let chunked = !headers.contains_key(CONTENT_LENGTH)
+        && headers
+            .get_all(TRANSFER_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+    chunked || is_event_stream(headers)
+}
+
+/// Si `response` se marcó como [`Streamed`].
+pub fn is_streamed<B>(response: &Response<B>) -> bool {
+    response.extensions().get::<Streamed>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_streams_and_chunked_bodies_are_streamed() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        assert!(detect(&headers(&[(
+            "content-type",
+            "Text/Event-Stream; charset=utf-8"
+        )])));
+        assert!(detect(&headers(&[("transfer-encoding", "gzip, chunked")])));
+        assert!(!detect(&headers(&[
+            ("transfer-encoding", "chunked"),
+            ("content-length", "5")
+        ])));
+        assert!(!detect(&headers(&[("content-type", "application/json")])));
+        assert!(!is_event_stream(&headers(&[(
+            "transfer-encoding",
+            "chunked"
+        )])));
+    }
+}
@@ -0,0 +1,215 @@
+//! Límite de caudal por conexión, para simular redes lentas.
+//!
+//! Con `--max-rate-down` y `--max-rate-up` (bytes por segundo) cada conexión
+//! de cliente recibe un [`ConnectionThrottle`] con un cubo por sentido: el
+//! body de las respuestas y lo que el destino manda por un túnel gastan el de
+//! bajada, y el body de las peticiones y lo que el cliente manda por un
+//! túnel, el de subida. Cada trozo se paga antes de pasar, así que una
+//! transferencia de `n` bytes tarda al menos `n / tasa` segundos. Sin límite
+//! (0 o ausente) la conexión no lleva cubo y los datos van por el camino de
+//! siempre.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::settings::ThrottleSettings;
+
+/// Mayor trozo que se paga de una vez.
+const MAX_PIECE: usize = 16 * 1024;
+
+/// Trozos por segundo como mínimo, para que el ritmo no vaya a saltos.
+const PIECES_PER_SEC: u64 = 20;
+
+/// Caudal de un sentido de una conexión.
+pub struct Bucket {
+    bytes_per_sec: u64,
+    /// Momento a partir del cual lo reservado ya está pagado.
+    next: Mutex<Instant>,
+}
+
+impl Bucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Mayor trozo que conviene pagar de una vez a esta tasa.
+    pub fn piece(&self) -> usize {
+        (self.bytes_per_sec / PIECES_PER_SEC).clamp(1, MAX_PIECE as u64) as usize
+    }
+
+    /// Reserva `bytes` y devuelve cuándo quedan pagados.
+    fn reserve(&self, bytes: usize) -> Instant {
+        let mut next = self.next.lock().expect("lock del límite de caudal");
+        // Idle time does not pile up into a burst.
+        *next = (*next).max(Instant::now()) + self.cost(bytes);
+        *next
+    }
+
+    fn cost(&self, bytes: usize) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64)
+    }
+
+    /// Espera a que `bytes` estén pagados.
+    pub async fn acquire(&self, bytes: usize) {
+        tokio::time::sleep_until(self.reserve(bytes)).await;
+    }
+}
+
+/// Cubos de una conexión de cliente.
+pub struct ConnectionThrottle {
+    down: Option<Arc<Bucket>>,
+    up: Option<Arc<Bucket>>,
+}
+
+impl ConnectionThrottle {
+    /// `None` si ningún sentido tiene límite.
+    pub fn new(settings: &ThrottleSettings) -> Option<Self> {
+        if settings.is_unlimited() {
+            return None;
+        }
+        Some(Self {
+            down: settings.down().map(|rate| Arc::new(Bucket::new(rate))),
+            up: settings.up().map(|rate| Arc::new(Bucket::new(rate))),
+        })
+    }
+
+    /// Del destino hacia el cliente.
+    pub fn down(&self) -> Option<Arc<Bucket>> {
+        self.down.clone()
+    }
+
+    /// Del cliente hacia el destino.
+    pub fn up(&self) -> Option<Arc<Bucket>> {
+        self.up.clone()
+    }
+}
+
+/// Entrega `body` al ritmo de `bucket`.
+pub fn throttle_body(body: Body, bucket: Arc<Bucket>) -> Body {
+    let chunks = futures_util::stream::unfold(
+        (body, Bytes::new(), bucket),
+        |(mut body, mut rest, bucket)| async move {
+            if rest.is_empty() {
+                match body.data().await? {
+                    Ok(chunk) => rest = chunk,
+                    Err(e) => return Some((Err(e), (body, rest, bucket))),
+                }
+            }
+            let piece = rest.split_to(rest.len().min(bucket.piece()));
+            bucket.acquire(piece.len()).await;
+            Some((Ok::<_, hyper::Error>(piece), (body, rest, bucket)))
+        },
+    );
+    Body::wrap_stream(chunks)
+}
+
+/// Flujo cuyas lecturas se entregan cuando `bucket` las ha pagado; las
+/// escrituras pasan tal cual.
+pub struct Throttled<S> {
+    inner: S,
+    bucket: Option<Arc<Bucket>>,
+    /// Lo leído que aún no se ha entregado, desde `start`, y su plazo.
+    held: Vec<u8>,
+    start: usize,
+    paid: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, bucket: Option<Arc<Bucket>>) -> Self {
+        Self {
+            inner,
+            bucket,
+            held: Vec::new(),
+            start: 0,
+            paid: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(bucket) = &this.bucket else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        if this.start == this.held.len() {
+            // Paid from when the bytes arrive, so idle time buys nothing.
+            this.held.resize(bucket.piece(), 0);
+            let mut read = ReadBuf::new(&mut this.held);
+            let polled = Pin::new(&mut this.inner).poll_read(cx, &mut read);
+            let n = read.filled().len();
+            this.held.truncate(n);
+            this.start = 0;
+            ready!(polled)?;
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            this.paid = Some(Box::pin(tokio::time::sleep_until(bucket.reserve(n))));
+        }
+        if let Some(paid) = &mut this.paid {
+            ready!(paid.as_mut().poll(cx));
+            this.paid = None;
+        }
+        let n = buf.remaining().min(this.held.len() - this.start);
+        buf.put_slice(&this.held[this.start..this.start + n]);
+        this.start += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_body_takes_at_least_size_over_rate() {
+        let bucket = Arc::new(Bucket::new(10_000));
+        assert_eq!(bucket.piece(), 500);
+        let started = Instant::now();
+        let body = throttle_body(Body::from(vec![7u8; 25_000]), bucket);
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(bytes.len(), 25_000);
+        let elapsed = started.elapsed();
+        assert!(
+            (Duration::from_millis(2500)..Duration::from_millis(2600)).contains(&elapsed),
+            "{elapsed:?}"
+        );
+
+        // Unlimited in both directions means no throttle at all.
+        assert!(ConnectionThrottle::new(&ThrottleSettings::default()).is_none());
+    }
+}
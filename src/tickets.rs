@@ -0,0 +1,358 @@
+//! Tickets de preaprobación para dispositivos que no pueden responder a un
+//! `407`, como los quioscos.
+//!
+//! Un administrador emite el ticket con `POST /api/tickets` y el
+//! dispositivo lo presenta en cada petición como `Proxy-Authorization:
+//! Ticket <token>`. El token lleva dentro el id del ticket, el del
+//! dispositivo, los destinos permitidos (las mismas reglas que
+//! `[host_filter]`) y la caducidad, firmados con HMAC-SHA256 con la clave de
+//! `[tickets]`; validarlo no consulta ningún usuario ni estado compartido,
+//! así que cualquier instancia con la misma clave lo acepta. Un ticket se
+//! revoca por id: la lista de revocados sale de `revoked` en la
+//! configuración y de `DELETE /api/tickets/{id}`, y es lo único que hay que
+//! repartir entre instancias.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::header::{HeaderMap, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use hyper::{Body, Response, StatusCode};
+use rand::Rng;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::host_filter::HostRule;
+use crate::settings::TicketSettings;
+
+/// Lo que firma un ticket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ticket {
+    pub id: String,
+    pub device: String,
+    pub destinations: Vec<HostRule>,
+    /// Segundos desde la época.
+    pub expires_at: u64,
+}
+
+impl Ticket {
+    pub fn allows(&self, host: &str) -> bool {
+        self.destinations.iter().any(|rule| rule.matches(host))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    id: String,
+    device: String,
+    destinations: Vec<String>,
+    exp: u64,
+}
+
+/// Por qué no se acepta un ticket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TicketError {
+    /// No tiene la forma `datos.firma`.
+    Malformed,
+    /// La firma no corresponde a los datos: se manipuló o es de otra clave.
+    Tampered,
+    Expired,
+    Revoked,
+    /// Válido pero no para este destino.
+    OutOfScope,
+}
+
+impl TicketError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TicketError::Malformed => "malformed",
+            TicketError::Tampered => "tampered",
+            TicketError::Expired => "expired",
+            TicketError::Revoked => "revoked",
+            TicketError::OutOfScope => "out_of_scope",
+        }
+    }
+}
+
+impl fmt::Display for TicketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TicketError::Malformed => "ticket mal formado",
+            TicketError::Tampered => "firma del ticket inválida",
+            TicketError::Expired => "ticket caducado",
+            TicketError::Revoked => "ticket revocado",
+            TicketError::OutOfScope => "el ticket no cubre este destino",
+        })
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub struct TicketAuthority {
+    key: hmac::Key,
+    max_ttl: Duration,
+    revoked: Mutex<HashSet<String>>,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl TicketAuthority {
+    pub fn new(settings: &TicketSettings) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, settings.key().expose().as_bytes()),
+            max_ttl: settings.max_ttl(),
+            revoked: Mutex::new(settings.revoked().iter().cloned().collect()),
+            accepted: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Emite un ticket de `device` hacia `destinations` que vence en `ttl`
+    /// desde `now`. Devuelve el ticket y su token.
+    pub fn mint(
+        &self,
+        device: &str,
+        destinations: Vec<HostRule>,
+        ttl: Duration,
+        now: SystemTime,
+    ) -> anyhow::Result<(Ticket, String)> {
+        anyhow::ensure!(!device.is_empty(), "falta el id del dispositivo");
+        anyhow::ensure!(
+            !destinations.is_empty(),
+            "el ticket necesita al menos un destino"
+        );
+        anyhow::ensure!(
+            !ttl.is_zero() && ttl <= self.max_ttl,
+            "la vigencia debe estar entre 1 y {} segundos",
+            self.max_ttl.as_secs()
+        );
+        let id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let ticket = Ticket {
+            id,
+            device: device.to_string(),
+            destinations,
+            expires_at: unix_secs(now) + ttl.as_secs(),
+        };
+        let claims = Claims {
+            id: ticket.id.clone(),
+            device: ticket.device.clone(),
+            destinations: ticket.destinations.iter().map(|r| r.to_string()).collect(),
+            exp: ticket.expires_at,
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?);
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(&self.key, payload.as_bytes()));
+        Ok((ticket, format!("{payload}.{signature}")))
+    }
+
+    /// Comprueba firma, revocación y caducidad; el destino lo comprueba
+    /// [`TicketAuthority::authorize`].
+    pub fn verify(&self, token: &str, now: SystemTime) -> Result<Ticket, TicketError> {
+        let (payload, signature) = token.trim().split_once('.').ok_or(TicketError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TicketError::Malformed)?;
+        hmac::verify(&self.key, payload.as_bytes(), &signature)
+            .map_err(|_| TicketError::Tampered)?;
+        // Signed by this key: anything unreadable past here is our bug, not
+        // an attack, but it is still not a ticket.
+        let claims: Claims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(TicketError::Malformed)?;
+        let destinations = claims
+            .destinations
+            .iter()
+            .map(|rule| rule.parse())
+            .collect::<Result<Vec<HostRule>, _>>()
+            .map_err(|_| TicketError::Malformed)?;
+        if self.is_revoked(&claims.id) {
+            return Err(TicketError::Revoked);
+        }
+        if unix_secs(now) >= claims.exp {
+            return Err(TicketError::Expired);
+        }
+        Ok(Ticket {
+            id: claims.id,
+            device: claims.device,
+            destinations,
+            expires_at: claims.exp,
+        })
+    }
+
+    /// El ticket de `headers` si lo hay: `None` sin `Proxy-Authorization:
+    /// Ticket`, y si no, si vale para `host` ahora.
+    pub fn authorize(
+        &self,
+        headers: &HeaderMap,
+        host: &str,
+    ) -> Option<Result<Ticket, (TicketError, Option<Ticket>)>> {
+        let value = headers.get(PROXY_AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, token) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("ticket") {
+            return None;
+        }
+        let verdict = match self.verify(token, SystemTime::now()) {
+            Ok(ticket) if ticket.allows(host) => Ok(ticket),
+            Ok(ticket) => Err((TicketError::OutOfScope, Some(ticket))),
+            Err(error) => Err((error, None)),
+        };
+        let counter = match verdict {
+            Ok(_) => &self.accepted,
+            Err(_) => &self.rejected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Some(verdict)
+    }
+
+    /// Deja de aceptar el ticket `id`; `false` si ya estaba revocado.
+    pub fn revoke(&self, id: &str) -> bool {
+        self.revoked
+            .lock()
+            .expect("lock de tickets revocados")
+            .insert(id.to_string())
+    }
+
+    pub fn is_revoked(&self, id: &str) -> bool {
+        self.revoked
+            .lock()
+            .expect("lock de tickets revocados")
+            .contains(id)
+    }
+
+    /// Ids revocados, ordenados.
+    pub fn revoked(&self) -> Vec<String> {
+        let mut revoked: Vec<String> = self
+            .revoked
+            .lock()
+            .expect("lock de tickets revocados")
+            .iter()
+            .cloned()
+            .collect();
+        revoked.sort();
+        revoked
+    }
+
+    pub fn max_ttl(&self) -> Duration {
+        self.max_ttl
+    }
+
+    /// Peticiones aceptadas y rechazadas con ticket.
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.accepted.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+        )
+    }
+
+    /// 407 para un ticket que no vale; sin detalles para quien lo presentó.
+    pub fn challenge(&self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header(PROXY_AUTHENTICATE, "Ticket realm=\"proxy-ia\"")
+            .body(Body::from("Se requiere un ticket válido"))
+            .expect("respuesta 407")
+    }
+
+    /// 403 para un ticket válido hacia un destino que no cubre.
+    pub fn out_of_scope(&self, host: &str) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header("x-proxy-ticket", "out_of_scope")
+            .body(Body::from(format!(
+                "El ticket no cubre el destino {host}.\n"
+            )))
+            .expect("respuesta de ticket fuera de alcance")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticket_header(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = format!("Ticket {token}");
+        headers.insert(PROXY_AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    fn rejection(
+        verdict: Option<Result<Ticket, (TicketError, Option<Ticket>)>>,
+    ) -> Option<TicketError> {
+        verdict.unwrap().err().map(|(error, _)| error)
+    }
+
+    #[test]
+    fn test_tickets_enforce_scope_expiry_revocation_and_signature() {
+        let authority = TicketAuthority::new(&TicketSettings::new("clave-de-prueba"));
+        let now = SystemTime::now();
+        let destinations = vec![
+            "*.kiosk.example".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ];
+        let (ticket, token) = authority
+            .mint("quiosco-7", destinations, Duration::from_secs(60), now)
+            .unwrap();
+        let headers = ticket_header(&token);
+
+        let accepted = authority.authorize(&headers, "api.kiosk.example");
+        assert_eq!(accepted, Some(Ok(ticket.clone())));
+        assert!(authority.authorize(&headers, "10.1.2.3").unwrap().is_ok());
+        assert_eq!(
+            rejection(authority.authorize(&headers, "example.com")),
+            Some(TicketError::OutOfScope)
+        );
+
+        // Offline validation against the clock, and only with the same key.
+        let later = now + Duration::from_secs(61);
+        assert_eq!(authority.verify(&token, later), Err(TicketError::Expired));
+        let other = TicketAuthority::new(&TicketSettings::new("otra-clave"));
+        assert_eq!(other.verify(&token, now), Err(TicketError::Tampered));
+
+        // Re-encoding the claims with a wider scope breaks the signature.
+        let (payload, signature) = token.split_once('.').unwrap();
+        let mut claims: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+        claims["destinations"] = serde_json::json!(["*"]);
+        let forged = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let forged = ticket_header(&format!("{forged}.{signature}"));
+        assert_eq!(
+            rejection(authority.authorize(&forged, "example.com")),
+            Some(TicketError::Tampered)
+        );
+        assert_eq!(
+            rejection(authority.authorize(&ticket_header("basura"), "x")),
+            Some(TicketError::Malformed)
+        );
+
+        assert!(authority.revoke(&ticket.id));
+        assert!(!authority.revoke(&ticket.id));
+        assert_eq!(
+            rejection(authority.authorize(&headers, "api.kiosk.example")),
+            Some(TicketError::Revoked)
+        );
+        assert_eq!(authority.counts(), (2, 4));
+
+        // Basic credentials are not a ticket.
+        let mut basic = HeaderMap::new();
+        basic.insert(PROXY_AUTHORIZATION, "Basic YTpi".parse().unwrap());
+        assert_eq!(authority.authorize(&basic, "api.kiosk.example"), None);
+        assert!(authority
+            .mint("quiosco-7", vec![], Duration::from_secs(60), now)
+            .is_err());
+        let too_long = TicketSettings::DEFAULT_MAX_TTL + Duration::from_secs(1);
+        let destinations = vec!["a.example".parse().unwrap()];
+        assert!(authority
+            .mint("quiosco-7", destinations, too_long, now)
+            .is_err());
+    }
+}
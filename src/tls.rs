@@ -0,0 +1,92 @@
+//! TLS entre el cliente y el proxy.
+//!
+//! Con `--tls-cert` y `--tls-key` el puerto de escucha es un proxy HTTPS:
+//! cada conexión aceptada pasa por el saludo TLS antes de llegar a hyper, y
+//! tanto las peticiones HTTP como los túneles `CONNECT` viajan dentro de la
+//! sesión. El certificado y la clave se leen al crear el servidor, así que un
+//! fichero ausente o que no casa impide arrancar en lugar de fallar con el
+//! primer cliente.
+//!
+//! Con `[identity]` el saludo pide además un certificado de cliente firmado
+//! por `client_ca`. Es opcional: quien no lo presenta sigue pudiendo
+//! autenticarse con `Proxy-Authorization`, y quien presenta uno que no valida
+//! no completa el saludo.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::settings::{IdentitySettings, TlsSettings};
+
+/// Espera máxima al saludo TLS de un cliente.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Carga el certificado y la clave de `settings` y, con `identity`, las CAs
+/// de los certificados de cliente.
+pub fn acceptor(
+    settings: &TlsSettings,
+    identity: Option<&IdentitySettings>,
+) -> anyhow::Result<TlsAcceptor> {
+    let certs = certificates(settings.cert())?;
+    let key = private_key(settings.key())?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Versiones de TLS no disponibles")?;
+    let builder = match identity {
+        Some(identity) => {
+            let Some(path) = identity.client_ca() else {
+                bail!("[identity] requiere `client_ca` con las CAs de los certificados de cliente");
+            };
+            let mut roots = RootCertStore::empty();
+            for cert in certificates(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("CA de cliente inválida en {}", path.display()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .with_context(|| format!("CAs de cliente inutilizables en {}", path.display()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(certs, key).with_context(|| {
+        format!(
+            "El certificado {} y la clave {} no forman un par válido",
+            settings.cert().display(),
+            settings.key().display()
+        )
+    })?;
+    // hyper serves HTTP/1.1 only on this port.
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub(crate) fn certificates(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("No se pudo leer el certificado TLS {}", path.display()))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Certificado TLS ilegible en {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("{} no contiene ningún certificado PEM", path.display());
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("No se pudo leer la clave TLS {}", path.display()))?;
+    PrivateKeyDer::from_pem_slice(&pem)
+        .with_context(|| format!("{} no contiene una clave privada PEM", path.display()))
+}
@@ -0,0 +1,285 @@
+//! Presupuesto de tokens por cliente para el router IA.
+//!
+//! Contar peticiones no basta con un LLM: una sola puede gastar cien mil
+//! tokens. Con `[token_budget]`, cada cliente (su `X-Client-Id` o, sin él,
+//! su IP según el perfil de logs) tiene un cupo de tokens por minuto y otro
+//! por día, en ventanas fijas que empiezan con su primera petición. Antes de
+//! reenviar se reservan los tokens del prompt estimados con
+//! [`estimate_prompt_tokens`]; si no caben, la petición recibe un 429 con un
+//! error en el formato de OpenAI. Al terminar la respuesta la reserva se
+//! corrige con el `usage` real que cuenta [`crate::token_usage`]. Las
+//! respuestas llevan el cupo que queda en `X-RateLimit-Remaining-Tokens`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+use serde_json::json;
+use tracing::debug;
+
+use crate::rate_limit::MAX_TRACKED;
+use crate::settings::TokenBudgetSettings;
+use crate::token_usage::TokenCount;
+
+/// Tokens que le quedan al cliente en la ventana más justa.
+pub const REMAINING_TOKENS: &str = "x-ratelimit-remaining-tokens";
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Tokens de un prompt a razón de cuatro caracteres por token.
+pub fn estimate_prompt_tokens(body: &[u8]) -> u64 {
+    let chars = String::from_utf8_lossy(body).chars().count() as u64;
+    chars.div_ceil(4)
+}
+
+/// Gasto de un cliente en una ventana.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    started: Instant,
+    used: u64,
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            used: 0,
+        }
+    }
+
+    fn roll(&mut self, length: Duration, now: Instant) {
+        if now.duration_since(self.started) >= length {
+            *self = Self::new(now);
+        }
+    }
+}
+
+struct Windows {
+    minute: Window,
+    day: Window,
+}
+
+pub struct TokenBudgets {
+    settings: TokenBudgetSettings,
+    clients: Mutex<HashMap<String, Windows>>,
+}
+
+impl TokenBudgets {
+    pub fn new(settings: TokenBudgetSettings) -> Self {
+        Self {
+            settings,
+            clients: Mutex::default(),
+        }
+    }
+
+    /// Reserva `estimate` tokens para `client`, si le caben.
+    pub fn reserve(
+        self: &Arc<Self>,
+        client: &str,
+        estimate: u64,
+    ) -> Result<Reservation, Exhausted> {
+        self.reserve_at(client, estimate, Instant::now())
+    }
+
+    fn reserve_at(
+        self: &Arc<Self>,
+        client: &str,
+        estimate: u64,
+        now: Instant,
+    ) -> Result<Reservation, Exhausted> {
+        let mut clients = self.clients.lock().expect("lock de presupuestos");
+        if clients.len() >= MAX_TRACKED && !clients.contains_key(client) {
+            // Clients whose windows have all expired owe nothing.
+            clients.retain(|_, windows| {
+                now.duration_since(windows.minute.started) < MINUTE
+                    || (self.settings.per_day().is_some()
+                        && now.duration_since(windows.day.started) < DAY)
+            });
+            if clients.len() >= MAX_TRACKED {
+                // Same trade-off as the request limiter: forget the eighth
+                // that started spending the longest ago.
+                let mut started: Vec<Instant> =
+                    clients.values().map(|w| w.minute.started).collect();
+                let (_, cut, _) = started.select_nth_unstable(MAX_TRACKED / 8);
+                let cut = *cut;
+                clients.retain(|_, windows| windows.minute.started > cut);
+            }
+        }
+        let windows = clients.entry(client.to_string()).or_insert(Windows {
+            minute: Window::new(now),
+            day: Window::new(now),
+        });
+        windows.minute.roll(MINUTE, now);
+        windows.day.roll(DAY, now);
+        let limits = [
+            (self.settings.per_minute(), windows.minute, MINUTE),
+            (self.settings.per_day(), windows.day, DAY),
+        ];
+        let mut remaining = u64::MAX;
+        for (limit, window, length) in limits {
+            let Some(limit) = limit else { continue };
+            let left = limit.saturating_sub(window.used);
+            if estimate > left {
+                let retry_after = length.saturating_sub(now.duration_since(window.started));
+                debug!(%client, estimate, left, "Presupuesto de tokens agotado");
+                return Err(Exhausted {
+                    remaining: left,
+                    retry_after,
+                });
+            }
+            remaining = remaining.min(left - estimate);
+        }
+        windows.minute.used += estimate;
+        windows.day.used += estimate;
+        Ok(Reservation {
+            budgets: self.clone(),
+            client: client.to_string(),
+            estimate,
+            minute: windows.minute.started,
+            day: windows.day.started,
+            remaining,
+        })
+    }
+
+    /// Cambia lo reservado por lo gastado, en las ventanas que siguen
+    /// abiertas.
+    fn reconcile(&self, reservation: &Reservation, spent: u64) {
+        let mut clients = self.clients.lock().expect("lock de presupuestos");
+        let Some(windows) = clients.get_mut(&reservation.client) else {
+            return;
+        };
+        for (window, started) in [
+            (&mut windows.minute, reservation.minute),
+            (&mut windows.day, reservation.day),
+        ] {
+            if window.started == started {
+                window.used = (window.used + spent).saturating_sub(reservation.estimate);
+            }
+        }
+    }
+}
+
+/// Tokens reservados para una petición.
+pub struct Reservation {
+    budgets: Arc<TokenBudgets>,
+    client: String,
+    estimate: u64,
+    minute: Instant,
+    day: Instant,
+    remaining: u64,
+}
+
+impl Reservation {
+    /// Tokens que le quedaban al cliente tras reservar.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Corrige la reserva con el consumo de la respuesta. Un stream sin
+    /// `usage` solo cuenta la salida, así que el prompt sigue estimado.
+    pub fn settle(self, count: &TokenCount) {
+        let spent = match count.estimated {
+            true => self.estimate + count.completion_tokens,
+            false => count.total_tokens,
+        };
+        debug!(client = %self.client, estimate = self.estimate, spent, "Reserva de tokens corregida");
+        self.budgets.reconcile(&self, spent);
+    }
+}
+
+/// Una petición que no cabe en el presupuesto del cliente.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exhausted {
+    /// Tokens que quedan, menos que los estimados.
+    pub remaining: u64,
+    /// Hasta que se abre la ventana agotada.
+    pub retry_after: Duration,
+}
+
+/// 429 con el error en el formato de la API de OpenAI.
+pub fn too_many_tokens(exhausted: Exhausted) -> Response<Body> {
+    let Exhausted {
+        remaining,
+        retry_after,
+    } = exhausted;
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let error = json!({
+        "error": {
+            "message": format!(
+                "Presupuesto de tokens agotado para este cliente; reintenta en {secs} s"
+            ),
+            "type": "tokens",
+            "param": null,
+            "code": "rate_limit_exceeded",
+        }
+    });
+    let mut response = Response::new(Body::from(error.to_string()));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert("x-proxy-error", HeaderValue::from_static("rate_limited"));
+    headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+    headers.insert(REMAINING_TOKENS, HeaderValue::from(remaining));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(total_tokens: u64) -> TokenCount {
+        TokenCount {
+            total_tokens,
+            ..TokenCount::default()
+        }
+    }
+
+    #[test]
+    fn test_reservations_are_settled_with_the_real_usage() {
+        assert_eq!(estimate_prompt_tokens(b"hola"), 1);
+        assert_eq!(estimate_prompt_tokens("¿qué tal?".as_bytes()), 3);
+
+        let budgets = Arc::new(TokenBudgets::new(
+            TokenBudgetSettings::default()
+                .with_per_minute(100)
+                .with_per_day(150),
+        ));
+        let t0 = Instant::now();
+        let first = budgets.reserve_at("a", 40, t0).unwrap();
+        assert_eq!(first.remaining(), 60);
+        // The estimate was high: the real usage frees part of it.
+        first.settle(&count(10));
+        let second = budgets.reserve_at("a", 80, t0).unwrap();
+        assert_eq!(second.remaining(), 10);
+        let Err(exhausted) = budgets.reserve_at("a", 20, t0) else {
+            panic!("el minuto ya no admite 20 tokens");
+        };
+        assert_eq!(
+            exhausted,
+            Exhausted {
+                remaining: 10,
+                retry_after: MINUTE
+            }
+        );
+        let rejected = too_many_tokens(exhausted);
+        assert_eq!(rejected.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(rejected.headers()[REMAINING_TOKENS], "10");
+        assert_eq!(rejected.headers()[RETRY_AFTER], "60");
+        assert!(budgets.reserve_at("b", 20, t0).is_ok());
+
+        // A new minute, but the day still remembers the 90 spent.
+        let t1 = t0 + MINUTE;
+        second.settle(&count(80));
+        let third = budgets.reserve_at("a", 50, t1).unwrap();
+        assert_eq!(third.remaining(), 10);
+        // Settling after the minute rolled only touches the day.
+        let stale = budgets.reserve_at("a", 5, t1).unwrap();
+        let t2 = t1 + MINUTE;
+        assert!(budgets.reserve_at("a", 1, t2).is_ok());
+        stale.settle(&count(0));
+        assert_eq!(budgets.reserve_at("a", 9, t2).unwrap().remaining(), 0);
+    }
+}
@@ -0,0 +1,427 @@
+//! Tokens consumidos por cada cliente a través del router IA.
+//!
+//! Las respuestas de las peticiones que enruta `[ai_router]` se leen a la
+//! vez que se entregan: cada chunk sale hacia el cliente sin esperar y el
+//! proxy se queda con una copia. Al terminar el body se suma su `usage`
+//! (`prompt_tokens`, `completion_tokens`, `total_tokens`) a los contadores
+//! del cliente, que es su `X-Client-Id` o, sin él, su IP según el perfil de
+//! logs. En un stream de eventos se usa el `usage` del último `data:` que lo
+//! trae (el de `stream_options.include_usage`); si ninguno lo trae, cada
+//! evento cuenta como un token de salida y el registro se marca como
+//! estimado. Los contadores salen en `GET /api/stats` y el consumo de cada
+//! petición en su línea del log de acceso.
+//!
+//! Un body con `Content-Encoding: gzip` o `deflate` se lee descomprimido y
+//! llega al cliente tal cual; un JSON que descomprimido pasa de `max_body`
+//! no se cuenta. Con otra codificación la respuesta no se cuenta y queda un
+//! aviso en el log.
+
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+
+use futures_util::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, HeaderMap, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::decompress::{DecodeError, Decoder, Encoding};
+use crate::replay::Usage;
+use crate::streaming;
+
+/// Cabecera con la que un cliente se identifica para el reparto de costes.
+pub const CLIENT_ID: &str = "x-client-id";
+
+/// Tokens de una respuesta.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenCount {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// Contado por eventos porque el stream no traía `usage`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+}
+
+impl TokenCount {
+    fn of(body: &Value) -> Option<Self> {
+        let usage = Usage::of(body)?;
+        let sum = usage.prompt_tokens + usage.completion_tokens;
+        Some(Self {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: body["usage"]["total_tokens"].as_u64().unwrap_or(sum),
+            estimated: false,
+        })
+    }
+}
+
+/// Consumo acumulado de un cliente.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ClientTokens {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// Respuestas cuyo consumo es una estimación.
+    pub estimated_requests: u64,
+}
+
+/// Contadores por cliente desde el arranque.
+#[derive(Default)]
+pub struct TokenLedger {
+    clients: Mutex<BTreeMap<String, ClientTokens>>,
+}
+
+impl TokenLedger {
+    pub fn record(&self, client: &str, count: &TokenCount) {
+        let mut clients = self.clients.lock().expect("lock de los tokens");
+        let tokens = clients.entry(client.to_string()).or_default();
+        tokens.requests += 1;
+        tokens.prompt_tokens += count.prompt_tokens;
+        tokens.completion_tokens += count.completion_tokens;
+        tokens.total_tokens += count.total_tokens;
+        tokens.estimated_requests += u64::from(count.estimated);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, ClientTokens> {
+        self.clients.lock().expect("lock de los tokens").clone()
+    }
+}
+
+/// Extensión de la respuesta donde queda su consumo al terminar el body,
+/// para el log de acceso.
+#[derive(Debug, Clone, Default)]
+pub struct UsageSlot(Arc<OnceLock<TokenCount>>);
+
+impl UsageSlot {
+    pub fn get(&self) -> Option<TokenCount> {
+        self.0.get().copied()
+    }
+}
+
+/// El `X-Client-Id` de la petición, si trae uno.
+pub fn client_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CLIENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+}
+
+/// Envuelve el body de `response` para sumar su `usage` a `client` al
+/// terminar y pasárselo a `then`. Un JSON mayor que `max_body` no se cuenta.
+pub fn meter(
+    ledger: Arc<TokenLedger>,
+    client: String,
+    response: &mut Response<Body>,
+    max_body: usize,
+    then: impl FnOnce(&TokenCount) + Send + 'static,
+) {
+    let reader = if streaming::is_event_stream(response.headers()) {
+        Reader::Events {
+            line: Vec::new(),
+            events: 0,
+            usage: None,
+        }
+    } else if response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("json"))
+    {
+        Reader::Json(Vec::new())
+    } else {
+        return;
+    };
+    let decoder = match Encoding::of(response.headers()) {
+        // Events are read line by line, so only lines need a bound.
+        Ok(encoding) => encoding.map(|encoding| match reader {
+            Reader::Json(_) => Decoder::new(encoding, max_body),
+            Reader::Events { .. } => Decoder::new(encoding, usize::MAX),
+        }),
+        Err(unsupported) => {
+            warn!(%client, encoding = %unsupported, "Respuesta comprimida sin contar sus tokens");
+            return;
+        }
+    };
+    let slot = UsageSlot::default();
+    response.extensions_mut().insert(slot.clone());
+    let body = Meter {
+        body: std::mem::take(response.body_mut()),
+        reader,
+        decoder,
+        max_body,
+        done: Some(Box::new(move |count| {
+            debug!(%client, ?count, "Tokens de la respuesta");
+            ledger.record(&client, &count);
+            then(&count);
+            let _ = slot.0.set(count);
+        })),
+    };
+    *response.body_mut() = Body::wrap_stream(body);
+}
+
+enum Reader {
+    /// El body entero, hasta `max_body`.
+    Json(Vec<u8>),
+    /// La línea en curso, los eventos vistos y el último `usage`.
+    Events {
+        line: Vec<u8>,
+        events: u64,
+        usage: Option<TokenCount>,
+    },
+}
+
+impl Reader {
+    fn feed(&mut self, chunk: &[u8], max_body: usize) {
+        match self {
+            // Past the limit the copy is useless: stop growing it.
+            Reader::Json(buf) if buf.len() <= max_body => buf.extend_from_slice(chunk),
+            Reader::Json(_) => {}
+            Reader::Events {
+                line,
+                events,
+                usage,
+            } => {
+                for &byte in chunk {
+                    if byte != b'\n' {
+                        if line.len() <= max_body {
+                            line.push(byte);
+                        }
+                        continue;
+                    }
+                    if let Some(data) = line.strip_prefix(b"data:") {
+                        let data = data.trim_ascii();
+                        if data != b"[DONE]" {
+                            *events += 1;
+                            let parsed = serde_json::from_slice::<Value>(data).ok();
+                            if let Some(count) = parsed.as_ref().and_then(TokenCount::of) {
+                                *usage = Some(count);
+                            }
+                        }
+                    }
+                    line.clear();
+                }
+            }
+        }
+    }
+
+    fn finish(&self, max_body: usize) -> Option<TokenCount> {
+        match self {
+            Reader::Json(buf) if buf.len() <= max_body => {
+                TokenCount::of(&serde_json::from_slice(buf).ok()?)
+            }
+            Reader::Json(_) => None,
+            Reader::Events {
+                usage: Some(usage), ..
+            } => Some(*usage),
+            Reader::Events { events, .. } => Some(TokenCount {
+                prompt_tokens: 0,
+                completion_tokens: *events,
+                total_tokens: *events,
+                estimated: true,
+            }),
+        }
+    }
+}
+
+struct Meter {
+    body: Body,
+    reader: Reader,
+    /// Con el body comprimido.
+    decoder: Option<Decoder>,
+    max_body: usize,
+    done: Option<Box<dyn FnOnce(TokenCount) + Send>>,
+}
+
+impl Meter {
+    /// Pasa `chunk` al lector, descomprimido si hace falta; `None` es el
+    /// final del body.
+    fn read(&mut self, chunk: Option<&[u8]>) {
+        if self.done.is_none() {
+            return;
+        }
+        let max_body = self.max_body;
+        let plain = match (&mut self.decoder, chunk) {
+            (None, Some(chunk)) => return self.reader.feed(chunk, max_body),
+            (None, None) => return,
+            (Some(decoder), Some(chunk)) => decoder.feed(chunk),
+            (Some(decoder), None) => decoder.finish(),
+        };
+        match plain {
+            Ok(plain) => self.reader.feed(&plain, max_body),
+            // Same as a plain JSON past `max_body`: not counted.
+            Err(DecodeError::TooLarge) => self.done = None,
+            Err(error) => {
+                warn!(%error, "Tokens de la respuesta sin contar");
+                self.done = None;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(done) = self.done.take() {
+            if let Some(count) = self.reader.finish(self.max_body) {
+                done(count);
+            }
+        }
+    }
+}
+
+impl Stream for Meter {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = ready!(Pin::new(&mut self.body).poll_data(cx));
+        match &next {
+            Some(Ok(chunk)) => self.read(Some(chunk)),
+            Some(Err(_)) => {}
+            None => {
+                self.read(None);
+                self.finish();
+            }
+        }
+        Poll::Ready(next)
+    }
+}
+
+impl Drop for Meter {
+    /// Un stream cortado por el cliente también consumió lo que llegó a
+    /// generar.
+    fn drop(&mut self) {
+        if matches!(self.reader, Reader::Events { .. }) {
+            self.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn metered(content_type: &str, chunks: Vec<&'static str>) -> (Bytes, Option<TokenCount>) {
+        let body = futures_util::stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>));
+        let response = Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::wrap_stream(body))
+            .unwrap();
+        metered_response(response).await
+    }
+
+    async fn metered_response(mut response: Response<Body>) -> (Bytes, Option<TokenCount>) {
+        let ledger = Arc::new(TokenLedger::default());
+        meter(
+            ledger.clone(),
+            "equipo-a".into(),
+            &mut response,
+            1024,
+            |_| {},
+        );
+        let slot = response.extensions().get::<UsageSlot>().cloned();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let count = slot.and_then(|slot| slot.get());
+        if let Some(count) = count {
+            assert_eq!(
+                ledger.snapshot()["equipo-a"].total_tokens,
+                count.total_tokens
+            );
+        }
+        (body, count)
+    }
+
+    #[tokio::test]
+    async fn test_usage_from_json_and_event_streams() {
+        let json = [
+            r#"{"id":"x","usage":{"prompt_tokens":9,"#,
+            r#""completion_tokens":3,"total_tokens":12}}"#,
+        ];
+        let (body, count) = metered("application/json", json.to_vec()).await;
+        assert_eq!(body, json.concat());
+        assert_eq!(
+            count,
+            Some(TokenCount {
+                prompt_tokens: 9,
+                completion_tokens: 3,
+                total_tokens: 12,
+                estimated: false,
+            })
+        );
+
+        // include_usage: the last data chunk carries the totals.
+        let (_, count) = metered(
+            "text/event-stream",
+            vec![
+                "data: {\"choices\":[{\"delta\":{\"content\":\"Ho\"}}],\"usage\":null}\n\n",
+                "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":5,\"completion_tokens\":2,\"total_tokens\":7}}\n",
+                "\ndata: [DONE]\n\n",
+            ],
+        )
+        .await;
+        assert_eq!(
+            count.map(|count| (count.total_tokens, count.estimated)),
+            Some((7, false))
+        );
+
+        let (_, count) = metered(
+            "text/event-stream",
+            vec!["data: {\"a\":1}\n\ndata: {\"a\"", ":2}\n\ndata: [DONE]\n\n"],
+        )
+        .await;
+        assert_eq!(
+            count,
+            Some(TokenCount {
+                prompt_tokens: 0,
+                completion_tokens: 2,
+                total_tokens: 2,
+                estimated: true,
+            })
+        );
+
+        let (_, count) = metered("text/plain", vec!["hola"]).await;
+        assert_eq!(count, None);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_bodies_are_counted_decompressed_and_forwarded_as_is() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use hyper::header::CONTENT_ENCODING;
+        use std::io::Write;
+
+        let gzip = |plain: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(plain).unwrap();
+            Bytes::from(encoder.finish().unwrap())
+        };
+        let response = |body: Bytes| {
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let usage =
+            gzip(br#"{"usage":{"prompt_tokens":9,"completion_tokens":3,"total_tokens":12}}"#);
+        let (body, count) = metered_response(response(usage.clone())).await;
+        assert_eq!(body, usage);
+        assert_eq!(count.map(|count| count.total_tokens), Some(12));
+
+        // Past the 1024-byte cap once decompressed: delivered, not counted.
+        let padding = " ".repeat(4096);
+        let large = gzip(
+            format!(r#"{{"pad":"{padding}","usage":{{"prompt_tokens":1,"completion_tokens":1}}}}"#)
+                .as_bytes(),
+        );
+        assert!(large.len() < 1024);
+        let (body, count) = metered_response(response(large.clone())).await;
+        assert_eq!(body, large);
+        assert_eq!(count, None);
+    }
+}
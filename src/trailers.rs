@@ -0,0 +1,599 @@
+//! Trailers de respuestas chunked (gRPC-web, `Server-Timing`...).
+//!
+//! hyper 0.14 descarta los trailers HTTP/1: al leer una respuesta chunked se
+//! los salta y al escribir una nunca los emite. Para reenviarlos, las
+//! peticiones que pueden recibirlos viajan por un pool propio cuyo socket
+//! observa las respuestas del destino ([`TrailerTap`]) y guarda los trailers
+//! de cada body chunked; del lado del cliente, [`TrailerWriter`] los inserta
+//! en el chunk final que escribe hyper. El socket del destino solo se
+//! observa, nunca se modifica.
+
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use futures_util::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::connect::{Connected, Connection};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, TE, TRAILER, TRANSFER_ENCODING,
+};
+use hyper::service::Service;
+use hyper::{Body, Response, Uri};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::dialer::DialConnector;
+use crate::settings::TrailerFallback;
+use crate::streaming;
+
+/// Tamaño máximo de una cabecera o bloque de trailers que se analiza; más
+/// allá el socket deja de observarse.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Chunk final sin trailers tal como lo escribe hyper.
+const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+/// Campos que nunca se copian de un trailer a las cabeceras porque cambian
+/// el framing o el enrutado del mensaje.
+const NOT_FOLDABLE: [HeaderName; 4] = [CONTENT_LENGTH, TRANSFER_ENCODING, TRAILER, TE];
+
+/// Trailers pendientes de una conexión: los del último body chunked del
+/// destino o los que esperan al chunk final hacia el cliente.
+#[derive(Debug, Clone, Default)]
+pub struct TrailerSlot(Arc<Mutex<Option<HeaderMap>>>);
+
+impl TrailerSlot {
+    fn put(&self, trailers: HeaderMap) {
+        *self.0.lock().expect("trailers") = Some(trailers);
+    }
+
+    fn take(&self) -> Option<HeaderMap> {
+        self.0.lock().expect("trailers").take()
+    }
+}
+
+/// Indica si el cliente declaró con `TE` que acepta trailers.
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            coding
+                .split(';')
+                .next()
+                .is_some_and(|c| c.trim().eq_ignore_ascii_case("trailers"))
+        })
+}
+
+fn is_chunked(headers: &HeaderMap) -> bool {
+    !headers.contains_key(CONTENT_LENGTH)
+        && headers
+            .get_all(TRANSFER_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .last()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Deja de observar los trailers de una respuesta que no es chunked. Va
+/// antes de quitar las cabeceras hop-by-hop, `Transfer-Encoding` incluida.
+pub fn check_framing(response: &mut Response<Body>) {
+    if !is_chunked(response.headers()) {
+        response.extensions_mut().remove::<TrailerSlot>();
+    }
+}
+
+/// Entrega los trailers de `response` según lo que acepte el cliente:
+/// `client` es la conexión del cliente si envió `TE: trailers`. La respuesta
+/// ya pasó por [`check_framing`].
+pub async fn relay(
+    response: Response<Body>,
+    client: Option<TrailerSlot>,
+    fallback: TrailerFallback,
+    remote: IpAddr,
+) -> Response<Body> {
+    // Only the tapped pool sees trailers; elsewhere they are lost.
+    let Some(origin) = response.extensions().get::<TrailerSlot>().cloned() else {
+        return drop_declared(response, remote);
+    };
+    if let Some(client) = client {
+        let (parts, body) = response.into_parts();
+        let body = Relay {
+            body,
+            on_end: Some(Box::new(move || {
+                if let Some(trailers) = origin.take() {
+                    client.put(trailers);
+                }
+            })),
+        };
+        return Response::from_parts(parts, Body::wrap_stream(body));
+    }
+    match fallback {
+        // Holding an event stream back would stall it until it ends.
+        TrailerFallback::Headers { max_body }
+            if !streaming::is_event_stream(response.headers()) =>
+        {
+            fold(response, origin, max_body, remote).await
+        }
+        _ => {
+            let (mut parts, body) = response.into_parts();
+            parts.headers.remove(TRAILER);
+            let body = Relay {
+                body,
+                on_end: Some(Box::new(move || {
+                    if let Some(trailers) = origin.take() {
+                        log_dropped(&trailers, remote);
+                    }
+                })),
+            };
+            Response::from_parts(parts, Body::wrap_stream(body))
+        }
+    }
+}
+
+/// Respuestas sin trailers observables: no se anuncia lo que no llegará.
+fn drop_declared(mut response: Response<Body>, remote: IpAddr) -> Response<Body> {
+    if let Some(declared) = response.headers_mut().remove(TRAILER) {
+        debug!(%remote, trailer = ?declared, "Trailers anunciados que no se reenvían");
+    }
+    response
+}
+
+fn log_dropped(trailers: &HeaderMap, remote: IpAddr) {
+    let names: Vec<&str> = trailers.keys().map(|name| name.as_str()).collect();
+    debug!(%remote, ?names, "Trailers descartados: el cliente no los acepta");
+}
+
+/// Retiene el body para agregar los trailers como cabeceras.
+async fn fold(
+    response: Response<Body>,
+    origin: TrailerSlot,
+    max_body: usize,
+    remote: IpAddr,
+) -> Response<Body> {
+    let (mut parts, mut body) = response.into_parts();
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut size = 0;
+    while size <= max_body {
+        match body.data().await {
+            Some(Ok(chunk)) => {
+                size += chunk.len();
+                chunks.push(chunk);
+            }
+            Some(Err(e)) => {
+                let chunks = chunks.into_iter().map(Ok).chain([Err(e)]);
+                let body = futures_util::stream::iter(chunks);
+                return Response::from_parts(parts, Body::wrap_stream(body));
+            }
+            None => {
+                if let Some(trailers) = origin.take() {
+                    let mut name = None;
+                    for (key, value) in trailers {
+                        name = key.or(name);
+                        match &name {
+                            Some(name) if !NOT_FOLDABLE.contains(name) => {
+                                parts.headers.append(name.clone(), value);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                let body = chunks.concat();
+                parts.headers.remove(TRAILER);
+                parts.headers.remove(TRANSFER_ENCODING);
+                parts
+                    .headers
+                    .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                return Response::from_parts(parts, Body::from(body));
+            }
+        }
+    }
+    debug!(%remote, size, "Body demasiado grande para agregar los trailers como cabeceras");
+    parts.headers.remove(TRAILER);
+    let rest = Relay {
+        body,
+        on_end: Some(Box::new(move || {
+            if let Some(trailers) = origin.take() {
+                log_dropped(&trailers, remote);
+            }
+        })),
+    };
+    let body = futures_util::StreamExt::chain(
+        futures_util::stream::iter(chunks.into_iter().map(Ok)),
+        rest,
+    );
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// Body que ejecuta `on_end` justo antes de terminar, cuando los trailers del
+/// destino ya se leyeron y hyper todavía no escribió el chunk final.
+struct Relay {
+    body: Body,
+    on_end: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Stream for Relay {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = ready!(Pin::new(&mut self.body).poll_data(cx));
+        if next.is_none() {
+            if let Some(on_end) = self.on_end.take() {
+                on_end();
+            }
+        }
+        Poll::Ready(next)
+    }
+}
+
+/// Conector del pool que observa los trailers del destino.
+#[derive(Clone)]
+pub struct TrailerConnector {
+    inner: DialConnector,
+}
+
+impl TrailerConnector {
+    pub fn new(inner: DialConnector) -> Self {
+        Self { inner }
+    }
+}
+
+impl Service<Uri> for TrailerConnector {
+    type Response = TrailerTap;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn std::future::Future<Output = io::Result<TrailerTap>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        Box::pin(async move { Ok(TrailerTap::new(connecting.await?)) })
+    }
+}
+
+/// Estado del análisis de las respuestas que llegan por el socket.
+#[derive(Debug)]
+enum Scan {
+    Head(Vec<u8>),
+    Body(u64),
+    ChunkSize(Vec<u8>),
+    ChunkData(u64),
+    /// Fin de línea tras los datos de un chunk.
+    ChunkEnd,
+    Trailers(Vec<u8>),
+    /// Body hasta el cierre o framing que no se sigue: se deja de observar.
+    Done,
+}
+
+/// Socket hacia el destino que guarda en su [`TrailerSlot`] los trailers de
+/// cada respuesta chunked. El slot viaja en las extensiones de la respuesta.
+pub struct TrailerTap {
+    inner: TcpStream,
+    scan: Scan,
+    slot: TrailerSlot,
+}
+
+impl TrailerTap {
+    fn new(inner: TcpStream) -> Self {
+        Self {
+            inner,
+            scan: Scan::Head(Vec::new()),
+            slot: TrailerSlot::default(),
+        }
+    }
+
+    fn feed(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let is_line = matches!(self.scan, Scan::ChunkSize(_));
+            let taken = match &mut self.scan {
+                Scan::Done => return,
+                Scan::Head(buf) | Scan::Trailers(buf) | Scan::ChunkSize(buf) => {
+                    let (taken, complete) = take_block(buf, bytes, is_line);
+                    if complete {
+                        let block = std::mem::take(buf);
+                        self.scan = self.next_after(block);
+                    } else if buf.len() > MAX_HEAD {
+                        self.scan = Scan::Done;
+                    }
+                    taken
+                }
+                Scan::Body(left) | Scan::ChunkData(left) => {
+                    let taken = bytes.len().min(*left as usize);
+                    *left -= taken as u64;
+                    if *left == 0 {
+                        self.scan = match self.scan {
+                            Scan::Body(_) => Scan::Head(Vec::new()),
+                            _ => Scan::ChunkEnd,
+                        };
+                    }
+                    taken
+                }
+                Scan::ChunkEnd => match bytes.iter().position(|b| *b == b'\n') {
+                    Some(end) => {
+                        self.scan = Scan::ChunkSize(Vec::new());
+                        end + 1
+                    }
+                    None => bytes.len(),
+                },
+            };
+            bytes = &bytes[taken..];
+        }
+    }
+
+    /// Estado siguiente a una cabecera, línea de chunk o bloque de trailers.
+    fn next_after(&mut self, block: Vec<u8>) -> Scan {
+        match &self.scan {
+            Scan::Head(_) => self.after_head(&block),
+            Scan::ChunkSize(_) => {
+                let line = String::from_utf8_lossy(&block);
+                let size = line.split(';').next().unwrap_or_default().trim();
+                match u64::from_str_radix(size, 16) {
+                    Ok(0) => Scan::Trailers(Vec::new()),
+                    Ok(size) => Scan::ChunkData(size),
+                    Err(_) => Scan::Done,
+                }
+            }
+            _ => {
+                let trailers = parse_fields(&block);
+                if !trailers.is_empty() {
+                    self.slot.put(trailers);
+                }
+                Scan::Head(Vec::new())
+            }
+        }
+    }
+
+    fn after_head(&mut self, head: &[u8]) -> Scan {
+        // A new response: whatever was left belongs to an earlier one.
+        self.slot.take();
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.lines();
+        let status: Option<u16> = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok());
+        let headers = parse_fields(
+            head.split_once('\n')
+                .map_or("", |(_, rest)| rest)
+                .as_bytes(),
+        );
+        match status {
+            Some(101) | None => Scan::Done,
+            Some(100..=199 | 204 | 304) => Scan::Head(Vec::new()),
+            Some(_) if headers.contains_key(TRANSFER_ENCODING) => match is_chunked(&headers) {
+                true => Scan::ChunkSize(Vec::new()),
+                false => Scan::Done,
+            },
+            Some(_) => match headers
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+            {
+                Some(0) => Scan::Head(Vec::new()),
+                Some(len) => Scan::Body(len),
+                None => Scan::Done,
+            },
+        }
+    }
+}
+
+/// Copia bytes a `buf` hasta completar una línea (`line`) o un bloque que
+/// termina en línea vacía. Devuelve los bytes consumidos y si se completó.
+fn take_block(buf: &mut Vec<u8>, bytes: &[u8], line: bool) -> (usize, bool) {
+    for (i, byte) in bytes.iter().enumerate() {
+        buf.push(*byte);
+        if *byte != b'\n' {
+            continue;
+        }
+        let complete = line
+            || buf == b"\r\n"
+            || buf == b"\n"
+            || buf.ends_with(b"\n\r\n")
+            || buf.ends_with(b"\n\n");
+        if complete {
+            return (i + 1, true);
+        }
+    }
+    (bytes.len(), false)
+}
+
+fn parse_fields(block: &[u8]) -> HeaderMap {
+    let mut fields = HeaderMap::new();
+    for line in String::from_utf8_lossy(block).lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.trim().as_bytes()),
+            HeaderValue::from_str(value.trim()),
+        ) {
+            fields.append(name, value);
+        }
+    }
+    fields
+}
+
+impl Connection for TrailerTap {
+    fn connected(&self) -> Connected {
+        self.inner.connected().extra(self.slot.clone())
+    }
+}
+
+impl AsyncRead for TrailerTap {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.feed(&buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TrailerTap {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Socket del cliente que agrega al chunk final de la respuesta en curso los
+/// trailers que dejó [`relay`] en su slot. No anuncia escrituras vectoriales
+/// para que hyper entregue el chunk final al final de un único buffer.
+pub struct TrailerWriter<T> {
+    inner: T,
+    slot: TrailerSlot,
+    outgoing: Vec<u8>,
+    written: usize,
+}
+
+impl<T> TrailerWriter<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            slot: TrailerSlot::default(),
+            outgoing: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Slot que [`relay`] debe recibir para las respuestas de esta conexión.
+    pub fn slot(&self) -> TrailerSlot {
+        self.slot.clone()
+    }
+}
+
+impl<T: AsyncWrite + Unpin> TrailerWriter<T> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.outgoing.len() {
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.outgoing.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for TrailerWriter<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TrailerWriter<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+        if buf.ends_with(LAST_CHUNK) {
+            if let Some(trailers) = this.slot.take() {
+                let mut out = buf[..buf.len() - LAST_CHUNK.len()].to_vec();
+                out.extend_from_slice(b"0\r\n");
+                for (name, value) in &trailers {
+                    out.extend_from_slice(name.as_str().as_bytes());
+                    out.extend_from_slice(b": ");
+                    out.extend_from_slice(value.as_bytes());
+                    out.extend_from_slice(b"\r\n");
+                }
+                out.extend_from_slice(b"\r\n");
+                this.outgoing = out;
+                // Whatever is left goes out on the next write or flush.
+                if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+                    return Poll::Ready(Err(e));
+                }
+                return Poll::Ready(Ok(buf.len()));
+            }
+        }
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn tap() -> TrailerTap {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        TrailerTap::new(stream)
+    }
+
+    #[tokio::test]
+    async fn test_tap_follows_framing_across_reads() {
+        let mut tap = tap().await;
+        let stream: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n0\r\n\r\n\
+            HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ntrailer: grpc-status\r\n\r\n\
+            5;ext=1\r\n0\r\n\r\n\r\n0\r\ngrpc-status: 0\r\ngrpc-message: ok\r\n\r\n";
+        // Split everywhere, including inside the chunk size and trailers.
+        for piece in stream.chunks(3) {
+            tap.feed(piece);
+        }
+        let trailers = tap.slot.take().expect("trailers");
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["grpc-message"], "ok");
+        assert!(matches!(tap.scan, Scan::Head(ref buf) if buf.is_empty()));
+
+        // The next response head clears anything nobody collected.
+        tap.slot.put(trailers);
+        tap.feed(b"HTTP/1.1 204 No Content\r\n\r\n");
+        assert!(tap.slot.take().is_none());
+    }
+
+    #[test]
+    fn test_accepts_trailers() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_trailers(&headers));
+        headers.insert(TE, HeaderValue::from_static("gzip;q=0.5, Trailers"));
+        assert!(accepts_trailers(&headers));
+        headers.insert(TE, HeaderValue::from_static("gzip"));
+        assert!(!accepts_trailers(&headers));
+    }
+}
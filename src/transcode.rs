@@ -0,0 +1,439 @@
+//! Modo de ahorro de datos: recompresión de imágenes en las respuestas.
+//!
+//! Solo se aplica a clientes que lo piden con `Save-Data: on` o cuyo perfil
+//! lo activa. Las imágenes JPEG, PNG y WebP por encima del umbral se
+//! reescalan y se recodifican como JPEG (o PNG si tienen transparencia),
+//! siempre que el `Accept` del cliente admita el formato elegido. Nunca se
+//! produce WebP. El trabajo corre en el pool de hilos bloqueantes; ante
+//! cualquier error, timeout o falta de capacidad se entrega la original.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use futures_util::StreamExt;
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, LINK,
+};
+use hyper::{Body, Request, Response};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilter, PngEncoder};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::identity::Identity;
+use crate::metrics::Metrics;
+use crate::settings::DataSaverSettings;
+
+/// Formatos de entrada que se recomprimen.
+const INPUT_TYPES: [(&str, ImageFormat); 3] = [
+    ("image/jpeg", ImageFormat::Jpeg),
+    ("image/png", ImageFormat::Png),
+    ("image/webp", ImageFormat::WebP),
+];
+
+/// Indica si la petición pide el modo de ahorro de datos.
+pub fn wants_data_saver(req: &Request<Body>) -> bool {
+    let header = req
+        .headers()
+        .get("save-data")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("on"));
+    header
+        || req
+            .extensions()
+            .get::<Identity>()
+            .is_some_and(Identity::data_saver)
+}
+
+pub struct Transcoder {
+    settings: DataSaverSettings,
+    permits: Arc<Semaphore>,
+    saved: Mutex<HashMap<IpAddr, u64>>,
+}
+
+impl Transcoder {
+    pub fn new(settings: DataSaverSettings) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(settings.max_concurrent())),
+            settings,
+            saved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bytes ahorrados por cliente desde el arranque.
+    pub fn saved_by_client(&self) -> HashMap<IpAddr, u64> {
+        self.saved.lock().expect("ahorro por cliente").clone()
+    }
+
+    /// Recomprime `response` si procede. `accept` es el `Accept` de la
+    /// petición original.
+    pub async fn apply(
+        &self,
+        client: IpAddr,
+        accept: Option<HeaderValue>,
+        metrics: &Metrics,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        let (mut parts, body) = response.into_parts();
+        if self.settings.strip_video_preload() {
+            strip_video_preload(&mut parts.headers);
+        }
+        let content_type = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase());
+        let format = INPUT_TYPES
+            .iter()
+            .find(|(mime, _)| content_type.as_deref() == Some(*mime))
+            .map(|(_, format)| *format);
+        let declared_len = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        let eligible = format.is_some()
+            && !parts.headers.contains_key(CONTENT_ENCODING)
+            && declared_len.is_none_or(|len| {
+                len >= self.settings.min_bytes() && len <= self.settings.max_input_bytes()
+            });
+        let (Some(format), true) = (format, eligible) else {
+            return Response::from_parts(parts, body);
+        };
+        let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+            debug!("Recompresión saturada; se entrega la imagen original");
+            metrics.record_transcode_passthrough();
+            return Response::from_parts(parts, body);
+        };
+
+        let (original, body) = match buffer(body, self.settings.max_input_bytes()).await {
+            Buffered::Complete(bytes) => (bytes, None),
+            Buffered::TooLarge(body) => (Bytes::new(), Some(body)),
+        };
+        if let Some(body) = body {
+            return Response::from_parts(parts, body);
+        }
+        if original.len() < self.settings.min_bytes() {
+            return Response::from_parts(parts, Body::from(original));
+        }
+
+        let accept = AcceptList::parse(accept.as_ref());
+        let settings = self.settings.clone();
+        let input = original.clone();
+        let job = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            transcode(&input, format, &settings, &accept)
+        });
+        let output = match tokio::time::timeout(self.settings.timeout(), job).await {
+            Ok(Ok(Ok(Some(output)))) if output.0.len() < original.len() => Some(output),
+            Ok(Ok(Ok(_))) => None,
+            Ok(Ok(Err(e))) => {
+                debug!(error = %e, "No se pudo recomprimir la imagen");
+                None
+            }
+            Ok(Err(_)) | Err(_) => None,
+        };
+        let Some((bytes, mime)) = output else {
+            metrics.record_transcode_passthrough();
+            return Response::from_parts(parts, Body::from(original));
+        };
+
+        let saved = (original.len() - bytes.len()) as u64;
+        metrics.record_transcode_saved(saved);
+        *self
+            .saved
+            .lock()
+            .expect("ahorro por cliente")
+            .entry(client)
+            .or_default() += saved;
+        parts
+            .headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static(mime));
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+        // A different representation must not be revalidated against the
+        // original's validator.
+        parts.headers.remove(ETAG);
+        Response::from_parts(parts, Body::from(bytes))
+    }
+}
+
+enum Buffered {
+    Complete(Bytes),
+    /// Se pasó del límite; el body reconstruido continúa el stream original.
+    TooLarge(Body),
+}
+
+async fn buffer(mut body: Body, limit: usize) -> Buffered {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut total = 0;
+    while let Some(chunk) = body.next().await {
+        let Ok(chunk) = chunk else {
+            // Replay what arrived and let the error surface downstream.
+            let replay = futures_util::stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>))
+                .chain(futures_util::stream::iter([chunk]));
+            return Buffered::TooLarge(Body::wrap_stream(replay));
+        };
+        total += chunk.len();
+        chunks.push(chunk);
+        if total > limit {
+            let replay = futures_util::stream::iter(chunks.into_iter().map(Ok)).chain(body);
+            return Buffered::TooLarge(Body::wrap_stream(replay));
+        }
+    }
+    Buffered::Complete(chunks.concat().into())
+}
+
+/// Tipos admitidos por el cliente según su `Accept`; sin cabecera, todos.
+struct AcceptList(Option<Vec<String>>);
+
+impl AcceptList {
+    fn parse(header: Option<&HeaderValue>) -> Self {
+        let Some(header) = header.and_then(|v| v.to_str().ok()) else {
+            return Self(None);
+        };
+        let types = header
+            .split(',')
+            .filter_map(|entry| {
+                let mut params = entry.split(';');
+                let mime = params.next()?.trim().to_ascii_lowercase();
+                let rejected = params.any(|p| {
+                    p.trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+                (!rejected).then_some(mime)
+            })
+            .collect();
+        Self(Some(types))
+    }
+
+    fn accepts(&self, mime: &str) -> bool {
+        let Some(types) = &self.0 else {
+            return true;
+        };
+        types
+            .iter()
+            .any(|t| t == mime || t == "image/*" || t == "*/*")
+    }
+}
+
+fn transcode(
+    input: &[u8],
+    format: ImageFormat,
+    settings: &DataSaverSettings,
+    accept: &AcceptList,
+) -> anyhow::Result<Option<(Vec<u8>, &'static str)>> {
+    let mut image = image::load_from_memory_with_format(input, format)?;
+    let max = settings.max_dimension();
+    if image.width() > max || image.height() > max {
+        image = image.resize(max, max, FilterType::Triangle);
+    }
+    let mut out = Vec::new();
+    if !image.color().has_alpha() && accept.accepts("image/jpeg") {
+        let rgb = DynamicImage::ImageRgb8(image.to_rgb8());
+        let encoder = JpegEncoder::new_with_quality(&mut out, settings.jpeg_quality());
+        rgb.write_with_encoder(encoder)?;
+        return Ok(Some((out, "image/jpeg")));
+    }
+    if accept.accepts("image/png") {
+        let encoder = PngEncoder::new_with_quality(
+            Cursor::new(&mut out),
+            CompressionType::Best,
+            PngFilter::Adaptive,
+        );
+        image.write_with_encoder(encoder)?;
+        return Ok(Some((out, "image/png")));
+    }
+    Ok(None)
+}
+
+/// Quita de las cabeceras `Link` las entradas `rel=preload; as=video`.
+fn strip_video_preload(headers: &mut HeaderMap) {
+    let values: Vec<HeaderValue> = headers.get_all(LINK).iter().cloned().collect();
+    if values.is_empty() {
+        return;
+    }
+    headers.remove(LINK);
+    for value in values {
+        let Ok(text) = value.to_str() else {
+            headers.append(LINK, value);
+            continue;
+        };
+        let kept: Vec<&str> = text
+            .split(',')
+            .filter(|link| {
+                let params: Vec<String> = link
+                    .split(';')
+                    .skip(1)
+                    .map(|p| p.trim().to_ascii_lowercase().replace('"', ""))
+                    .collect();
+                !(params.iter().any(|p| p == "rel=preload")
+                    && params.iter().any(|p| p == "as=video"))
+            })
+            .collect();
+        if let Ok(value) = HeaderValue::from_str(&kept.join(",")) {
+            if !kept.is_empty() {
+                headers.append(LINK, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::body::to_bytes;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    /// Photo-like fixture: smooth gradients plus noise compress badly as PNG.
+    fn photo_png(width: u32, height: u32) -> Vec<u8> {
+        let mut seed = 7u32;
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let noise = (seed >> 24) as u8 / 8;
+            Rgb([
+                (x * 255 / width) as u8 ^ noise,
+                (y * 255 / height) as u8,
+                ((x + y) % 256) as u8 ^ noise,
+            ])
+        });
+        let mut out = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+            .unwrap();
+        out
+    }
+
+    fn response(mime: &str, body: Vec<u8>) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_TYPE, mime)
+            .header(CONTENT_LENGTH, body.len())
+            .header(ETAG, "\"v1\"")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn run(
+        transcoder: &Transcoder,
+        accept: Option<&str>,
+        res: Response<Body>,
+    ) -> (HeaderMap, Vec<u8>) {
+        let accept = accept.map(|a| HeaderValue::from_str(a).unwrap());
+        let client = "10.0.0.5".parse().unwrap();
+        let res = transcoder
+            .apply(client, accept, &Metrics::default(), res)
+            .await;
+        let (parts, body) = res.into_parts();
+        (parts.headers, to_bytes(body).await.unwrap().to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_large_photo_becomes_smaller_jpeg() {
+        let transcoder = Transcoder::new(DataSaverSettings::default().with_max_dimension(400));
+        let original = photo_png(1000, 800);
+        let (headers, body) = run(
+            &transcoder,
+            Some("image/avif,image/webp,*/*"),
+            response("image/png", original.clone()),
+        )
+        .await;
+
+        assert_eq!(headers[CONTENT_TYPE], "image/jpeg");
+        assert!(
+            body.len() < original.len() / 4,
+            "{} vs {}",
+            body.len(),
+            original.len()
+        );
+        assert_eq!(headers[CONTENT_LENGTH], body.len().to_string().as_str());
+        assert!(!headers.contains_key(ETAG));
+        let decoded = image::load_from_memory(&body).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (400, 320));
+        let saved = transcoder.saved_by_client()[&"10.0.0.5".parse::<IpAddr>().unwrap()];
+        assert_eq!(saved, (original.len() - body.len()) as u64);
+    }
+
+    #[tokio::test]
+    async fn test_format_respects_alpha_and_accept() {
+        let transcoder = Transcoder::new(
+            DataSaverSettings::default()
+                .with_min_bytes(1)
+                .with_max_dimension(64),
+        );
+        // Transparency keeps PNG.
+        let alpha = RgbaImage::from_fn(300, 300, |x, y| {
+            Rgba([x as u8, y as u8, 0, (x % 200) as u8])
+        });
+        let mut png = Vec::new();
+        alpha
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let (headers, body) = run(&transcoder, None, response("image/png", png.clone())).await;
+        assert_eq!(headers[CONTENT_TYPE], "image/png");
+        assert!(body.len() < png.len());
+
+        // JPEG explicitly refused: stays PNG.
+        let photo = photo_png(300, 300);
+        let (headers, _) = run(
+            &transcoder,
+            Some("image/png, image/jpeg;q=0"),
+            response("image/png", photo),
+        )
+        .await;
+        assert_eq!(headers[CONTENT_TYPE], "image/png");
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_on_error_timeout_and_small_images() {
+        let transcoder = Transcoder::new(DataSaverSettings::default().with_min_bytes(1));
+        let corrupt = vec![0xFFu8; 4096];
+        let (headers, body) = run(&transcoder, None, response("image/jpeg", corrupt.clone())).await;
+        assert_eq!(body, corrupt);
+        assert_eq!(headers[ETAG], "\"v1\"");
+
+        let slow = Transcoder::new(
+            DataSaverSettings::default()
+                .with_min_bytes(1)
+                .with_timeout(Duration::ZERO),
+        );
+        let photo = photo_png(600, 600);
+        let (headers, body) = run(&slow, None, response("image/png", photo.clone())).await;
+        assert_eq!(headers[CONTENT_TYPE], "image/png");
+        assert_eq!(body, photo);
+
+        let small = Transcoder::new(DataSaverSettings::default());
+        let png = photo_png(16, 16);
+        let (_, body) = run(&small, None, response("image/png", png.clone())).await;
+        assert_eq!(body, png);
+    }
+
+    #[test]
+    fn test_strip_video_preload() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LINK,
+            "</v.mp4>; rel=preload; as=video, </app.css>; rel=preload; as=style"
+                .parse()
+                .unwrap(),
+        );
+        headers.append(
+            LINK,
+            "</i.mp4>; rel=\"preload\"; as=\"video\"".parse().unwrap(),
+        );
+        strip_video_preload(&mut headers);
+        let links: Vec<_> = headers.get_all(LINK).iter().collect();
+        assert_eq!(links, [" </app.css>; rel=preload; as=style"]);
+    }
+}
@@ -0,0 +1,157 @@
+//! Modo transparente: clientes que no saben que pasan por un proxy.
+//!
+//! Con el DNS o un `REDIRECT` de iptables apuntando al proxy, los clientes
+//! mandan peticiones en forma de origen (`GET /ruta` con `Host`). En modo
+//! transparente la URI se reconstruye con el esquema configurado y el `Host`
+//! de la petición antes de cualquier otra comprobación, así que el control
+//! de acceso, la lista de bloqueo y los filtros ven ese destino. Sin `Host`
+//! la respuesta sigue siendo un 400.
+//!
+//! Un `Host` que apunta al propio proxy haría que se reenviara la petición a
+//! sí mismo sin fin; si el destino resuelve a la dirección en la que llegó
+//! la conexión, el cliente recibe un 508. Por lo mismo, las páginas propias
+//! del proxy (`/proxy-info`, `/proxy.pac`) solo responden cuando el `Host`
+//! es el del proxy: con el de cualquier otro sitio, la petición se reenvía.
+
+use std::net::SocketAddr;
+
+use hyper::header::HOST;
+use hyper::http::uri::{Authority, PathAndQuery};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+
+use crate::settings::TransparentSettings;
+
+pub struct Transparent {
+    settings: TransparentSettings,
+}
+
+impl Transparent {
+    pub fn new(settings: TransparentSettings) -> Self {
+        Self { settings }
+    }
+
+    /// Si `req` llega en forma de origen y hay que reconstruir su destino.
+    pub fn applies(&self, req: &Request<Body>) -> bool {
+        req.method() != Method::CONNECT && req.uri().authority().is_none()
+    }
+
+    /// Pone en `req` la URI absoluta de su `Host`.
+    pub fn absolutize(&self, req: &mut Request<Body>) -> Result<(), InvalidHost> {
+        *req.uri_mut() = self.absolute_uri(req)?;
+        Ok(())
+    }
+
+    /// La URI absoluta que corresponde al `Host` de `req`.
+    pub fn absolute_uri(&self, req: &Request<Body>) -> Result<Uri, InvalidHost> {
+        let authority = req
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+            .filter(|authority| !authority.as_str().contains('@'))
+            .ok_or(InvalidHost)?;
+        let path = req
+            .uri()
+            .path_and_query()
+            .cloned()
+            .unwrap_or_else(|| PathAndQuery::from_static("/"));
+        Uri::builder()
+            .scheme(self.settings.scheme().clone())
+            .authority(authority)
+            .path_and_query(path)
+            .build()
+            .map_err(|_| InvalidHost)
+    }
+}
+
+/// Si alguna de `addrs` es la dirección `local` de la conexión en la que
+/// llegó la petición. Con el mismo puerto, las de loopback también cuentan:
+/// un listener en todas las interfaces las atiende.
+pub fn loops_back(addrs: &[SocketAddr], local: SocketAddr) -> bool {
+    addrs.iter().any(|addr| {
+        addr.port() == local.port()
+            && (addr.ip() == local.ip() || addr.ip().is_loopback() || addr.ip().is_unspecified())
+    })
+}
+
+/// 508 para una petición que volvería al propio proxy.
+pub fn loop_detected() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::LOOP_DETECTED)
+        .header("x-proxy-error", "loop_detected")
+        .body(Body::from("El destino es el propio proxy"))
+        .expect("respuesta loop detected")
+}
+
+/// Una petición en forma de origen sin `Host` que sirva como destino.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidHost;
+
+impl InvalidHost {
+    /// 400, como para cualquier petición sin destino.
+    pub fn into_response(self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(
+                "Petición sin URI absoluta ni cabecera Host válida",
+            ))
+            .expect("respuesta bad request")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_becomes_the_authority_of_relative_requests() {
+        let transparent = Transparent::new(TransparentSettings::default());
+        let mut req = Request::get("/v1/items?page=2")
+            .header(HOST, "api.example.com:8080")
+            .body(Body::empty())
+            .unwrap();
+        assert!(transparent.applies(&req));
+        transparent.absolutize(&mut req).unwrap();
+        assert_eq!(req.uri(), "http://api.example.com:8080/v1/items?page=2");
+        assert!(!transparent.applies(&req));
+
+        let https = Transparent::new(
+            TransparentSettings::default().with_scheme(hyper::http::uri::Scheme::HTTPS),
+        );
+        let mut req = Request::get("/")
+            .header(HOST, "api.example.com")
+            .body(Body::empty())
+            .unwrap();
+        https.absolutize(&mut req).unwrap();
+        assert_eq!(req.uri(), "https://api.example.com/");
+
+        for host in [None, Some("user@api.example.com"), Some("no valido")] {
+            let mut req = Request::get("/").body(Body::empty()).unwrap();
+            if let Some(host) = host {
+                req.headers_mut().insert(HOST, host.parse().unwrap());
+            }
+            assert_eq!(
+                transparent.absolutize(&mut req),
+                Err(InvalidHost),
+                "{host:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_loops_back_to_the_listener_address() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let local = addr("127.0.0.1:8888");
+        assert!(loops_back(&[addr("127.0.0.1:8888")], local));
+        assert!(loops_back(&[addr("[::1]:8888")], local));
+        assert!(!loops_back(&[addr("127.0.0.1:80")], local));
+        assert!(!loops_back(&[addr("93.184.216.34:8888")], local));
+        let lan = addr("10.0.0.5:8888");
+        assert!(loops_back(
+            &[addr("10.0.0.6:80"), addr("10.0.0.5:8888")],
+            lan
+        ));
+        assert!(loops_back(&[addr("0.0.0.0:8888")], lan));
+        assert!(!loops_back(&[addr("10.0.0.6:8888")], lan));
+    }
+}
@@ -0,0 +1,311 @@
+//! Calidad de conexión de los túneles largos.
+//!
+//! Los bytes copiados no dicen nada de una llamada VoIP o un escritorio
+//! remoto que va por un `CONNECT`. Con `[tunnel_quality]`, cada túnel que
+//! dura más de `min_age` se muestrea cada `interval`: en Linux se lee
+//! `TCP_INFO` de los dos sockets (RTT, su variación, retransmisiones y
+//! ventana de congestión) y en cualquier plataforma se calcula el caudal en
+//! cada sentido. La última muestra aparece en `GET /api/tunnels`, cada una
+//! se registra en el log y las RTT y retransmisiones se acumulan en los
+//! histogramas de `GET /api/stats`. Donde no hay `TCP_INFO` las muestras
+//! solo llevan el caudal.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tracing::info;
+
+use crate::metrics::{Metrics, TunnelLabels};
+use crate::settings::TunnelQualitySettings;
+use crate::tunnels::TunnelRecord;
+
+/// Intervalo mínimo entre muestras.
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Límites superiores de los buckets de RTT, en milisegundos.
+const RTT_BUCKETS_MS: [f64; 9] = [1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+/// Límites superiores de los buckets de retransmisiones por intervalo.
+const RETRANSMIT_BUCKETS: [f64; 6] = [0.0, 1.0, 5.0, 10.0, 50.0, 100.0];
+
+/// Lo que el kernel sabe de un socket TCP.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TcpInfo {
+    pub rtt_ms: f64,
+    pub rtt_var_ms: f64,
+    /// Segmentos retransmitidos desde que se abrió el socket.
+    pub retransmits: u32,
+    /// Ventana de congestión, en segmentos.
+    pub cwnd: u32,
+}
+
+/// Socket de un lado del túnel que se puede consultar mientras vive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketProbe {
+    #[cfg(target_os = "linux")]
+    fd: Option<std::os::fd::RawFd>,
+}
+
+impl SocketProbe {
+    pub fn new(stream: &TcpStream) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            Self {
+                fd: Some(stream.as_raw_fd()),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = stream;
+            Self {}
+        }
+    }
+
+    /// Sin socket que consultar: las muestras solo llevarán el caudal.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// `None` si la plataforma no tiene `TCP_INFO` o la consulta falla.
+    /// Solo debe llamarse mientras el socket sigue abierto.
+    pub fn sample(&self) -> Option<TcpInfo> {
+        #[cfg(target_os = "linux")]
+        {
+            self.fd.and_then(tcp_info)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_info(fd: std::os::fd::RawFd) -> Option<TcpInfo> {
+    // SAFETY: `tcp_info` is plain old data, so all zeroes is a valid value.
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: the kernel writes at most `len` bytes into `info`, a live local
+    // of that size. A descriptor that is no longer a TCP socket only makes
+    // the call fail.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            (&mut info as *mut libc::tcp_info).cast(),
+            &mut len,
+        )
+    };
+    (result == 0).then(|| TcpInfo {
+        rtt_ms: f64::from(info.tcpi_rtt) / 1000.0,
+        rtt_var_ms: f64::from(info.tcpi_rttvar) / 1000.0,
+        retransmits: info.tcpi_total_retrans,
+        cwnd: info.tcpi_snd_cwnd,
+    })
+}
+
+/// Muestra de un túnel. Los caudales son bytes por segundo desde la muestra
+/// anterior (o desde que se cumplió `min_age`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QualitySample {
+    pub client: Option<TcpInfo>,
+    pub upstream: Option<TcpInfo>,
+    /// Del cliente hacia el destino.
+    pub sent_per_sec: f64,
+    /// Del destino hacia el cliente.
+    pub received_per_sec: f64,
+}
+
+/// Bucket acumulado de un histograma.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Bucket {
+    /// Límite superior, o `+Inf`.
+    pub le: String,
+    pub count: u64,
+}
+
+/// Histograma acumulado con buckets fijos.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// One per bound plus the overflow bucket; not cumulative.
+    counts: Box<[AtomicU64]>,
+    /// Bits of the `f64` sum of the observed values.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    fn rtt_ms() -> Self {
+        Self::new(&RTT_BUCKETS_MS)
+    }
+
+    fn retransmits() -> Self {
+        Self::new(&RETRANSMIT_BUCKETS)
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    /// Suma de los valores observados.
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// Cuentas acumuladas por límite superior, con `+Inf` al final.
+    pub fn snapshot(&self) -> Vec<Bucket> {
+        let mut total = 0;
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                total += count.load(Ordering::Relaxed);
+                let le = match self.bounds.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                Bucket { le, count: total }
+            })
+            .collect()
+    }
+}
+
+/// Histogramas de las muestras de todos los túneles.
+#[derive(Debug)]
+pub struct QualityHistograms {
+    pub rtt_ms: Histogram,
+    /// Retransmisiones de un lado del túnel entre dos muestras.
+    pub retransmits: Histogram,
+}
+
+impl Default for QualityHistograms {
+    fn default() -> Self {
+        Self {
+            rtt_ms: Histogram::rtt_ms(),
+            retransmits: Histogram::retransmits(),
+        }
+    }
+}
+
+/// Muestrea un túnel hasta que se suelta el future; se ejecuta junto a la
+/// copia de bytes, que es la que mantiene abiertos los dos sockets.
+pub struct QualitySampler {
+    pub settings: TunnelQualitySettings,
+    pub record: Arc<TunnelRecord>,
+    pub client: SocketProbe,
+    pub upstream: SocketProbe,
+    pub labels: TunnelLabels,
+    pub metrics: Arc<Metrics>,
+}
+
+impl QualitySampler {
+    pub async fn run(self) {
+        tokio::time::sleep(self.settings.min_age()).await;
+        let mut last_at = Instant::now();
+        let mut last_bytes = self.record.bytes();
+        let mut retransmits = (None, None);
+        let mut ticks = tokio::time::interval(self.settings.interval().max(MIN_INTERVAL));
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let (at, bytes) = (Instant::now(), self.record.bytes());
+            let secs = at.duration_since(last_at).as_secs_f64().max(f64::EPSILON);
+            let sample = QualitySample {
+                client: self.client.sample(),
+                upstream: self.upstream.sample(),
+                sent_per_sec: (bytes.0 - last_bytes.0) as f64 / secs,
+                received_per_sec: (bytes.1 - last_bytes.1) as f64 / secs,
+            };
+            (last_at, last_bytes) = (at, bytes);
+            let delta = |info: Option<TcpInfo>, previous: &mut Option<u32>| {
+                let info = info?;
+                let delta = info.retransmits.saturating_sub(previous.unwrap_or(0));
+                *previous = Some(info.retransmits);
+                Some(delta)
+            };
+            let client_retransmits = delta(sample.client, &mut retransmits.0);
+            let upstream_retransmits = delta(sample.upstream, &mut retransmits.1);
+            info!(
+                tunnel = self.record.id(),
+                host = %self.labels.host,
+                client_rtt_ms = sample.client.map(|info| info.rtt_ms),
+                client_retransmits,
+                client_cwnd = sample.client.map(|info| info.cwnd),
+                upstream_rtt_ms = sample.upstream.map(|info| info.rtt_ms),
+                upstream_retransmits,
+                upstream_cwnd = sample.upstream.map(|info| info.cwnd),
+                sent_per_sec = sample.sent_per_sec.round(),
+                received_per_sec = sample.received_per_sec.round(),
+                "Calidad del túnel"
+            );
+            self.metrics.record_tunnel_quality(
+                &self.labels,
+                &sample,
+                [client_retransmits, upstream_retransmits],
+            );
+            self.record.set_quality(sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_histogram_is_cumulative() {
+        let histogram = QualityHistograms::default().rtt_ms;
+        for value in [0.4, 3.0, 3.0, 4000.0] {
+            histogram.observe(value);
+        }
+        let snapshot = histogram.snapshot();
+        let counts: Vec<(&str, u64)> = snapshot
+            .iter()
+            .map(|bucket| (bucket.le.as_str(), bucket.count))
+            .collect();
+        assert_eq!(counts[..2], [("1", 1), ("5", 3)]);
+        assert_eq!(counts.last(), Some(&("+Inf", 4)));
+    }
+
+    #[tokio::test]
+    async fn test_loopback_socket_reports_tcp_info_on_linux() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let _server = listener.accept().await.unwrap();
+        let sample = SocketProbe::new(&client).sample();
+        if cfg!(target_os = "linux") {
+            let info = sample.expect("TCP_INFO en Linux");
+            assert!(info.cwnd > 0);
+            assert!(info.rtt_ms >= 0.0);
+        } else {
+            assert_eq!(sample, None);
+        }
+        assert_eq!(SocketProbe::none().sample(), None);
+    }
+}
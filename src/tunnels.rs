@@ -0,0 +1,295 @@
+//! Túneles `CONNECT` abiertos y la dirección a la que se conectó cada uno.
+//!
+//! La IP sale del socket ya conectado: es la que eligió el dialer entre las
+//! resueltas (con reputación de destinos, entre las ya comprobadas), y el
+//! log, las estadísticas y `GET /api/tunnels` muestran la misma durante todo
+//! el túnel aunque el DNS cambie mientras dura. Si el túnel va por un proxy
+//! padre, la dirección es la del padre. Si lo primero que envía el cliente
+//! es un ClientHello de TLS, su SNI se guarda junto al túnel. La API de
+//! administración puede cerrar un túnel con [`TunnelRegistry::terminate`]: el
+//! motivo queda en el registro y la tarea que copia los bytes lo recibe al
+//! momento.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::connections::Termination;
+use crate::sni_check::{self, Hello};
+use crate::tunnel_quality::QualitySample;
+
+/// Túnel abierto, tal como lo lista la API de administración.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TunnelSnapshot {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub host: String,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub via_parent: bool,
+    pub started_at_ms: u64,
+    pub age_secs: f64,
+    /// Del cliente hacia el destino.
+    pub bytes_sent: u64,
+    /// Del destino hacia el cliente.
+    pub bytes_received: u64,
+    /// Última muestra de `[tunnel_quality]`, si ya se tomó alguna.
+    pub quality: Option<QualitySample>,
+    /// SNI del ClientHello, si el túnel lleva TLS.
+    pub sni: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct TunnelRecord {
+    id: u64,
+    client: SocketAddr,
+    host: String,
+    upstream: SocketAddr,
+    via_parent: bool,
+    started_at_ms: u64,
+    started: Instant,
+    sent: AtomicU64,
+    received: AtomicU64,
+    quality: Mutex<Option<QualitySample>>,
+    sni: OnceLock<Option<String>>,
+    /// Motivo con el que se pidió cerrarlo.
+    termination: Termination,
+}
+
+impl TunnelRecord {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn upstream(&self) -> SocketAddr {
+        self.upstream
+    }
+
+    pub fn client(&self) -> SocketAddr {
+        self.client
+    }
+
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// SNI del ClientHello; `None` también mientras el cliente no envió nada.
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.get().and_then(|sni| sni.as_deref())
+    }
+
+    /// Bytes `(enviados, recibidos)` hasta ahora.
+    pub fn bytes(&self) -> (u64, u64) {
+        (
+            self.sent.load(Ordering::Relaxed),
+            self.received.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn set_quality(&self, sample: QualitySample) {
+        *self.quality.lock().expect("lock de calidad") = Some(sample);
+    }
+
+    /// Pide cerrar el túnel; `false` si ya se había pedido.
+    pub fn terminate(&self, reason: &str) -> bool {
+        self.termination.request(reason)
+    }
+
+    /// Motivo del cierre pedido, si se pidió.
+    pub fn termination(&self) -> Option<String> {
+        self.termination.reason()
+    }
+
+    /// Termina cuando se pide cerrar el túnel.
+    pub async fn terminated(&self) {
+        self.termination.requested().await
+    }
+
+    fn snapshot(&self) -> TunnelSnapshot {
+        TunnelSnapshot {
+            id: self.id,
+            client: self.client,
+            host: self.host.clone(),
+            ip: self.upstream.ip(),
+            port: self.upstream.port(),
+            via_parent: self.via_parent,
+            started_at_ms: self.started_at_ms,
+            age_secs: self.started.elapsed().as_secs_f64(),
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+            bytes_received: self.received.load(Ordering::Relaxed),
+            quality: self.quality.lock().expect("lock de calidad").clone(),
+            sni: self.sni().map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TunnelRegistry {
+    next_id: AtomicU64,
+    open: Mutex<BTreeMap<u64, Arc<TunnelRecord>>>,
+}
+
+impl TunnelRegistry {
+    /// Registra un túnel ya conectado con `upstream`; sale de la lista cuando
+    /// se suelta el guard.
+    pub fn open(
+        self: &Arc<Self>,
+        client: SocketAddr,
+        host: &str,
+        upstream: SocketAddr,
+        via_parent: bool,
+    ) -> OpenTunnel {
+        let record = Arc::new(TunnelRecord {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            client,
+            host: host.to_string(),
+            upstream,
+            via_parent,
+            started_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            started: Instant::now(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            quality: Mutex::new(None),
+            sni: OnceLock::new(),
+            termination: Termination::default(),
+        });
+        self.open
+            .lock()
+            .expect("lock de túneles")
+            .insert(record.id, record.clone());
+        OpenTunnel {
+            registry: self.clone(),
+            record,
+        }
+    }
+
+    /// Túneles abiertos, del más antiguo al más reciente.
+    pub fn list(&self) -> Vec<TunnelSnapshot> {
+        self.open
+            .lock()
+            .expect("lock de túneles")
+            .values()
+            .map(|record| record.snapshot())
+            .collect()
+    }
+
+    /// Pide cerrar el túnel `id`. `None` si no está abierto o ya se pidió.
+    pub fn terminate(&self, id: u64, reason: &str) -> Option<TunnelSnapshot> {
+        let open = self.open.lock().expect("lock de túneles");
+        let record = open.get(&id)?;
+        record.terminate(reason).then(|| record.snapshot())
+    }
+
+    /// Pide cerrar todos los túneles abiertos de la IP `client`.
+    pub fn terminate_client(&self, client: IpAddr, reason: &str) -> Vec<TunnelSnapshot> {
+        self.open
+            .lock()
+            .expect("lock de túneles")
+            .values()
+            .filter(|record| record.client.ip() == client && record.terminate(reason))
+            .map(|record| record.snapshot())
+            .collect()
+    }
+}
+
+/// Mantiene un túnel en [`TunnelRegistry::list`] mientras vive.
+pub struct OpenTunnel {
+    registry: Arc<TunnelRegistry>,
+    record: Arc<TunnelRecord>,
+}
+
+impl OpenTunnel {
+    pub fn record(&self) -> &Arc<TunnelRecord> {
+        &self.record
+    }
+
+    /// Socket hacia el destino que cuenta los bytes de este túnel.
+    pub fn count<S>(&self, inner: S) -> Counted<S> {
+        Counted {
+            inner,
+            record: self.record.clone(),
+        }
+    }
+}
+
+impl Drop for OpenTunnel {
+    fn drop(&mut self) {
+        self.registry
+            .open
+            .lock()
+            .expect("lock de túneles")
+            .remove(&self.record.id);
+    }
+}
+
+/// Lado del destino de un túnel: lo leído llega del destino y lo escrito va
+/// hacia él.
+pub struct Counted<S> {
+    inner: S,
+    record: Arc<TunnelRecord>,
+}
+
+impl<S> Counted<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.record
+            .received
+            .fetch_add(read as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.record.sni.get().is_none() {
+            // The first write carries the client's first bytes.
+            let sni = match sni_check::classify(buf) {
+                Some(Hello::Tls(sni)) => sni,
+                _ => None,
+            };
+            let _ = self.record.sni.set(sni);
+        }
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &poll {
+            self.record
+                .sent
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,71 @@
+//! WebSocket y demás `Upgrade` en peticiones HTTP en claro.
+//!
+//! Una petición HTTP/1.1 con `Connection: upgrade` y `Upgrade: <protocolo>`
+//! hacia un destino `http://` conserva esas dos cabeceras al reenviarse (el
+//! resto de hop-by-hop se quita como siempre). Si el destino contesta
+//! `101 Switching Protocols`, la respuesta llega al cliente y las dos
+//! conexiones quedan unidas byte a byte, como un túnel `CONNECT`, hasta que
+//! uno de los lados cierra. Cualquier otra respuesta se entrega tal cual.
+
+use std::sync::Arc;
+
+use hyper::header::{HeaderMap, HeaderValue, CONNECTION, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use tokio::io::copy_bidirectional;
+use tracing::{debug, info};
+
+use crate::metrics::HostBytes;
+
+/// El `Upgrade` que pide una petición cuyo `Connection` incluye `upgrade`.
+pub fn requested(headers: &HeaderMap) -> Option<HeaderValue> {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    headers.get(UPGRADE).filter(|_| listed).cloned()
+}
+
+/// Une la conexión del cliente con la del destino cuando las dos han
+/// cambiado de protocolo, y anota los bytes de cada sentido en `traffic`.
+pub async fn bridge(
+    client: OnUpgrade,
+    origin: OnUpgrade,
+    target: String,
+    protocol: String,
+    traffic: Arc<HostBytes>,
+) {
+    let (mut client, mut origin) = match tokio::try_join!(client, origin) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            debug!(%target, %protocol, error = %e, "Upgrade HTTP falló");
+            return;
+        }
+    };
+    // A side that closes gets its peer's write half shut down.
+    match copy_bidirectional(&mut client, &mut origin).await {
+        Ok((sent, received)) => {
+            traffic.add_sent(sent);
+            traffic.add_received(received);
+            info!(%target, %protocol, bytes_sent = sent, bytes_received = received, "Conexión con upgrade cerrada");
+        }
+        Err(e) => debug!(%target, %protocol, error = %e, "Conexión con upgrade cortada"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_needs_the_connection_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        assert_eq!(requested(&headers), None);
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        assert_eq!(requested(&headers).unwrap(), "websocket");
+        headers.remove(UPGRADE);
+        assert_eq!(requested(&headers), None);
+    }
+}
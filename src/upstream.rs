@@ -0,0 +1,119 @@
+//! Encadenamiento con un proxy padre: peticiones HTTP en forma absoluta y
+//! túneles CONNECT sobre una conexión ya establecida con el padre. El padre
+//! lo elige el PAC de cada destino o, sin PAC, es el fijo de
+//! `[upstream_proxy]`, que puede llevar sus propias credenciales.
+
+use std::io;
+
+use base64::Engine;
+use hyper::header::{HeaderValue, PROXY_AUTHORIZATION};
+use hyper::http::uri::Authority;
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::settings::UpstreamProxySettings;
+
+/// Tamaño máximo de la cabecera de respuesta a un CONNECT.
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+/// Puerto del padre cuando la URL no lo indica.
+pub const DEFAULT_PARENT_PORT: u16 = 8080;
+
+/// El proxy padre fijo, con el `Proxy-Authorization` que se le presenta.
+pub struct UpstreamProxy {
+    parent: Authority,
+    authorization: Option<HeaderValue>,
+}
+
+impl UpstreamProxy {
+    pub fn new(settings: &UpstreamProxySettings) -> Self {
+        let authorization = settings.credentials().map(|(user, password)| {
+            let encoded = base64::engine::general_purpose::STANDARD
+                .encode(format!("{user}:{}", password.expose()));
+            let mut value =
+                HeaderValue::try_from(format!("Basic {encoded}")).expect("base64 es ASCII");
+            value.set_sensitive(true);
+            value
+        });
+        Self {
+            parent: settings.parent().clone(),
+            authorization,
+        }
+    }
+
+    pub fn parent(&self) -> &Authority {
+        &self.parent
+    }
+
+    pub fn port(&self) -> u16 {
+        self.parent.port_u16().unwrap_or(DEFAULT_PARENT_PORT)
+    }
+
+    pub fn authorization(&self) -> Option<&HeaderValue> {
+        self.authorization.as_ref()
+    }
+}
+
+/// Pone `authorization` en una petición que va al padre; solo ese salto la
+/// lleva, la del cliente ya se quitó con las cabeceras de salto.
+pub fn authorize(req: &mut Request<Body>, authorization: Option<&HeaderValue>) {
+    if let Some(authorization) = authorization {
+        req.headers_mut()
+            .insert(PROXY_AUTHORIZATION, authorization.clone());
+    }
+}
+
+/// Envía `req` al proxy padre conectado en `stream`. La URI se escribe tal
+/// cual, así que debe ser absoluta.
+pub async fn send_http(
+    stream: TcpStream,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            debug!(error = %e, "Conexión con el proxy padre terminó con error");
+        }
+    });
+    sender.send_request(req).await
+}
+
+/// Pide al proxy padre un túnel hacia `target` y devuelve el socket listo
+/// para copiar bytes.
+pub async fn open_tunnel(
+    mut stream: TcpStream,
+    target: &Authority,
+    authorization: Option<&HeaderValue>,
+) -> io::Result<TcpStream> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n").into_bytes();
+    if let Some(authorization) = authorization {
+        request.extend_from_slice(b"Proxy-Authorization: ");
+        request.extend_from_slice(authorization.as_bytes());
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"\r\n");
+    stream.write_all(&request).await?;
+
+    // Byte by byte so nothing the target sends after the head is consumed.
+    let mut head = Vec::with_capacity(128);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_CONNECT_RESPONSE {
+            return Err(io::Error::other(
+                "respuesta CONNECT del proxy padre demasiado grande",
+            ));
+        }
+        let byte = stream.read_u8().await?;
+        head.push(byte);
+    }
+    let status_line = head.split(|b| *b == b'\n').next().unwrap_or_default();
+    let status = String::from_utf8_lossy(status_line);
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(stream),
+        _ => Err(io::Error::other(format!(
+            "el proxy padre rechazó el CONNECT: {}",
+            status.trim()
+        ))),
+    }
+}
@@ -0,0 +1,111 @@
+//! Aceptación con el límite de descriptores agotado: el binario arranca con
+//! un `RLIMIT_NOFILE` artificialmente bajo y se le abren más conexiones de
+//! las que caben.
+#![cfg(target_os = "linux")]
+
+use std::net::{SocketAddr, TcpListener as StdListener};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const BIN: &str = env!("CARGO_BIN_EXE_prueba_codex_proxy_ia");
+
+/// Descriptores del proceso del proxy; deja sitio para arrancar y poco más.
+const NOFILE: libc::rlim_t = 48;
+
+fn free_addr() -> SocketAddr {
+    StdListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn spawn_limited(listen: SocketAddr, admin: SocketAddr) -> Child {
+    let mut command = Command::new(BIN);
+    command
+        .args(["--listen", &listen.to_string()])
+        .args(["--admin-listen", &admin.to_string()])
+        .arg("--quiet")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: the closure runs in the child between fork and exec and only
+    // makes one async-signal-safe call on a local.
+    unsafe {
+        command.pre_exec(|| {
+            let limit = libc::rlimit {
+                rlim_cur: NOFILE,
+                rlim_max: NOFILE,
+            };
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        });
+    }
+    command.spawn().unwrap()
+}
+
+/// `GET /api/stats`, reintentando mientras la aceptación está pausada.
+async fn stats(admin: SocketAddr) -> serde_json::Value {
+    for _ in 0..100 {
+        if let Ok(mut stream) = TcpStream::connect(admin).await {
+            let req = "GET /api/stats HTTP/1.1\r\nhost: admin\r\nconnection: close\r\n\r\n";
+            let mut response = Vec::new();
+            let read = tokio::time::timeout(Duration::from_secs(2), async {
+                stream.write_all(req.as_bytes()).await?;
+                stream.read_to_end(&mut response).await
+            })
+            .await;
+            let text = String::from_utf8_lossy(&response);
+            if let (Ok(Ok(_)), Some((_, body))) = (read, text.split_once("\r\n\r\n")) {
+                if let Ok(stats) = serde_json::from_str(body) {
+                    return stats;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("la API de administración no respondió");
+}
+
+#[tokio::test]
+async fn test_emfile_pauses_accept_and_counts_it() {
+    let (listen, admin) = (free_addr(), free_addr());
+    let mut child = spawn_limited(listen, admin);
+    let before = stats(admin).await;
+    assert_eq!(before["resources"]["nofile"]["soft"], NOFILE);
+    assert_eq!(before["accept"]["fd_exhausted"], 0);
+
+    // They wait in the backlog once the proxy runs out of descriptors.
+    let mut held = Vec::new();
+    for _ in 0..NOFILE * 2 {
+        held.push(TcpStream::connect(listen).await.unwrap());
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    drop(held);
+
+    let after = stats(admin).await;
+    let exhausted = after["accept"]["fd_exhausted"].as_u64().unwrap();
+    // One per pause, not one per retry of a hot loop.
+    assert!((1..=10).contains(&exhausted), "{exhausted}");
+
+    // Once descriptors are free again the proxy accepts as before.
+    let mut client = TcpStream::connect(listen).await.unwrap();
+    client
+        .write_all(b"GET /proxy-info HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
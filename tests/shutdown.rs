@@ -0,0 +1,149 @@
+//! Códigos de salida del binario ante una configuración inválida, una parada
+//! limpia, un túnel que termina durante el drenaje y uno que lo hace vencer.
+#![cfg(unix)]
+
+use std::net::{SocketAddr, TcpListener as StdListener};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const BIN: &str = env!("CARGO_BIN_EXE_prueba_codex_proxy_ia");
+
+fn free_addr() -> SocketAddr {
+    StdListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Arranca el proxy y espera a que acepte conexiones.
+async fn spawn_proxy(addr: SocketAddr, drain_timeout: u64, connect_port: u16) -> Child {
+    let mut child = Command::new(BIN)
+        .args(["--listen", &addr.to_string(), "--quiet"])
+        .args(["--drain-timeout", &drain_timeout.to_string()])
+        .args(["--connect-ports", &connect_port.to_string()])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return child;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    panic!("el proxy no arrancó");
+}
+
+fn sigterm(child: &Child) {
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+async fn exit_code(mut child: Child) -> i32 {
+    for _ in 0..200 {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status.code().expect("terminó por señal");
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    panic!("el proxy no terminó");
+}
+
+#[test]
+fn test_bad_config_exits_with_config_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("proxy.toml");
+    std::fs::write(&path, "campo_desconocido = 1\n").unwrap();
+    let output = Command::new(BIN)
+        .arg("--config")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("configuración"));
+}
+
+#[tokio::test]
+async fn test_sigterm_shuts_down_cleanly() {
+    let addr = free_addr();
+    let child = spawn_proxy(addr, 5, 443).await;
+    // An idle keep-alive connection does not hold the drain back.
+    let mut idle = TcpStream::connect(addr).await.unwrap();
+    sigterm(&child);
+    assert_eq!(exit_code(child).await, 0);
+    let mut buf = [0u8; 1];
+    assert_eq!(idle.read(&mut buf).await.unwrap_or(0), 0);
+}
+
+#[tokio::test]
+async fn test_tunnel_finishes_its_transfer_during_the_drain() {
+    // The target answers the first byte with a slow download, then closes.
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let mut start = [0u8; 1];
+        stream.read_exact(&mut start).await.unwrap();
+        for i in 0..5u8 {
+            stream.write_all(&[i; 1024]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    });
+
+    let addr = free_addr();
+    let child = spawn_proxy(addr, 5, target_addr.port()).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let connect = format!("CONNECT {target_addr} HTTP/1.1\r\nhost: {target_addr}\r\n\r\n");
+    client.write_all(connect.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(client.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 200"));
+
+    client.write_all(b"x").await.unwrap();
+    sigterm(&child);
+    // New connections are refused while the tunnel drains.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+    let mut body = Vec::new();
+    client.read_to_end(&mut body).await.unwrap();
+    assert_eq!(body.len(), 5 * 1024);
+    assert!(body.ends_with(&[4; 1024]));
+    drop(client);
+    assert_eq!(exit_code(child).await, 0);
+}
+
+#[tokio::test]
+async fn test_long_lived_tunnel_exceeds_drain_timeout() {
+    // The target accepts and keeps the tunnel open without ever closing it.
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = target.accept().await {
+            held.push(stream);
+        }
+    });
+
+    let addr = free_addr();
+    let child = spawn_proxy(addr, 1, target_addr.port()).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let connect = format!("CONNECT {target_addr} HTTP/1.1\r\nhost: {target_addr}\r\n\r\n");
+    client.write_all(connect.as_bytes()).await.unwrap();
+    let mut head = [0u8; 12];
+    client.read_exact(&mut head).await.unwrap();
+    assert_eq!(&head, b"HTTP/1.1 200");
+
+    sigterm(&child);
+    assert_eq!(exit_code(child).await, 2);
+}